@@ -0,0 +1,561 @@
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::ffi::OsString;
+#[cfg(unix)]
+use std::os::unix::fs::DirBuilderExt;
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::extract::{IntoResponse, Json};
+use crate::faults::{FaultFilter, FaultKind};
+use crate::headers::HeaderRule;
+use crate::json::{self, Value};
+use crate::log::{self, Level};
+use crate::request::{Limits, Request};
+use crate::response::{reason_phrase, Response};
+use crate::rewrite::Action;
+use crate::PoolHandle;
+
+/// How long an admin client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most worker threads a pool can be resized to
+const MAX_WORKERS: usize = 1024;
+
+/// Where the admin endpoint listens
+///
+/// The endpoint speaks HTTP, so besides the `ctl` subcommand it can be
+/// used with e.g. `curl --unix-socket /run/server.sock localhost/status`.
+/// It answers `GET /status`, `GET /config`, `GET /log-level`,
+/// `PUT /log-level` with a level such as `warn` as the body,
+/// `GET /maintenance`, `PUT /maintenance` with `on` or `off` as the body,
+/// `POST /pool` with a body such as `{"size": 16}` or
+/// `{"pool": "reports", "size": 2}`, `POST /drain` and `POST /shutdown`.
+#[derive(Clone, Debug)]
+pub enum AdminAddress {
+    /// A Unix domain socket, which only the user running the server can
+    /// connect to; Unix only
+    Unix(PathBuf),
+    /// A TCP address, which has to be a loopback one such as
+    /// `127.0.0.1:7879`, so only local users can connect to it
+    ///
+    /// Requests have to name the endpoint in their Host header, as
+    /// `localhost`, `127.0.0.1` or `[::1]` with its port, and must not
+    /// have an Origin header, so web pages open in a local browser
+    /// cannot reach it, not even through DNS rebinding.
+    Tcp(String),
+}
+
+/// What the admin endpoint acts on, implemented by the server
+pub(crate) trait Control: Send + Sync {
+    /// The current state of the server, for `GET /status`
+    fn status(&self) -> Value;
+    /// The pool of a name, `default` being the one connections are
+    /// handled in
+    fn pool(&self, name: &str) -> Option<PoolHandle>;
+    /// Close every connection after its current request from now on, so
+    /// clients move to other instances
+    fn drain(&self);
+    /// Whether the server is in maintenance, for `GET /maintenance`
+    fn maintenance(&self) -> bool;
+    /// Turn maintenance on or off, see `maintenance::Maintenance`
+    fn set_maintenance(&self, on: bool);
+    /// Stop accepting connections, so `Server::serve` returns once the
+    /// open ones have finished
+    fn shutdown(&self);
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// A bound admin endpoint, not accepting yet
+pub(crate) struct AdminListener {
+    listener: Listener,
+    /// The Host headers requests over TCP may have, which Unix socket
+    /// requests are not checked for
+    hosts: Vec<String>,
+    config: Value,
+}
+
+impl AdminListener {
+    /// Bind the admin endpoint, which has to happen before privileges
+    /// are dropped if the socket lives in a directory only root can
+    /// write to
+    ///
+    /// A stale socket file left behind by a previous run is replaced. The
+    /// socket is bound in a directory only the user running the server
+    /// can enter and moved into place once it is private, so no one else
+    /// can connect to it in the meantime.
+    ///
+    /// # Arguments
+    ///
+    /// address - Where to listen.
+    /// config - The configuration of the server, shown by `GET /config`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the address cannot be bound, a TCP address is
+    /// not a loopback one, or a Unix socket is asked for on a platform
+    /// without them.
+    pub(crate) fn bind(address: &AdminAddress, config: &Config) -> io::Result<AdminListener> {
+        let mut hosts = Vec::new();
+        let listener = match address {
+            AdminAddress::Tcp(address) => {
+                let listener = TcpListener::bind(address)?;
+                let local = listener.local_addr()?;
+                if !local.ip().is_loopback() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "The admin endpoint must listen on a loopback address.",
+                    ));
+                }
+                hosts = ["localhost", "127.0.0.1", "[::1]"].iter().map(|host| format!("{}:{}", host, local.port())).collect();
+                hosts.push(local.to_string());
+                Listener::Tcp(listener)
+            }
+            #[cfg(unix)]
+            AdminAddress::Unix(path) => Listener::Unix(bind_private(path)?),
+            #[cfg(not(unix))]
+            AdminAddress::Unix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Unix domain sockets are not supported on this platform.",
+                ));
+            }
+        };
+        Ok(AdminListener { listener, hosts, config: describe(config) })
+    }
+
+    /// Answer admin requests on a thread of its own, so the endpoint keeps
+    /// working when every worker is busy
+    pub(crate) fn spawn(self, control: Arc<dyn Control>, limits: Limits) {
+        thread::spawn(move || loop {
+            let result = match &self.listener {
+                Listener::Tcp(listener) => listener.accept().and_then(|(stream, _)| {
+                    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+                    serve(&stream, &stream, &*control, &self.config, &limits, Some(&self.hosts));
+                    Ok(())
+                }),
+                #[cfg(unix)]
+                Listener::Unix(listener) => listener.accept().and_then(|(stream, _)| {
+                    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+                    serve(&stream, &stream, &*control, &self.config, &limits, None);
+                    Ok(())
+                }),
+            };
+            if let Err(e) = result {
+                log::error(&format!("Failed to accept admin connection: {}", e));
+            }
+        });
+    }
+}
+
+/// Bind a Unix socket readable and writable by the user running the
+/// server only
+///
+/// The socket is created in a fresh directory no one else can enter,
+/// made private and then renamed to its path.
+#[cfg(unix)]
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "The admin socket path has no file name."))?;
+    let mut staging_name = OsString::from(".");
+    staging_name.push(name);
+    staging_name.push(format!(".{}", process::id()));
+    let staging = path.with_file_name(staging_name);
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join(name);
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&staging);
+    bound
+}
+
+/// Answer one request and close the connection
+///
+/// # Arguments
+///
+/// hosts - The Host headers accepted, or None to accept any.
+fn serve<R: Read, W: Write>(
+    reader: R,
+    mut writer: W,
+    control: &dyn Control,
+    config: &Value,
+    limits: &Limits,
+    hosts: Option<&[String]>,
+) {
+    let mut response = match Request::parse_with_limits(&mut BufReader::new(reader), limits) {
+        Ok(request) if !is_local(&request, hosts) => {
+            log::warn(&format!("Refused admin request for {} from a browser or a foreign host", request.path()));
+            Response::text(403, reason_phrase(403))
+        }
+        Ok(request) => respond(&request, control, config),
+        Err(e) => Response::text(e.status(), reason_phrase(e.status())),
+    };
+    response.set_header("Connection", "close");
+    if let Err(e) = response.write_to(&mut writer) {
+        log::warn(&format!("Failed to answer admin request: {}", e));
+    }
+}
+
+/// Whether a request comes from a local client rather than from a web
+/// page, which any site a local browser has open could make send a
+/// request to a loopback address, or by DNS rebinding
+///
+/// Browsers send an Origin header with cross-origin requests, and the
+/// Host header of a rebound name is not one of the loopback names.
+fn is_local(request: &Request, hosts: Option<&[String]>) -> bool {
+    if request.header("Origin").is_some() {
+        return false;
+    }
+    match hosts {
+        Some(hosts) => request.header("Host").is_some_and(|host| hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host.trim()))),
+        None => true,
+    }
+}
+
+fn respond(request: &Request, control: &dyn Control, config: &Value) -> Response {
+    let allowed = match request.path() {
+        "/status" | "/config" => "GET",
+        "/log-level" | "/maintenance" => "GET, PUT",
+        "/pool" | "/drain" | "/shutdown" => "POST",
+        _ => return Response::text(404, reason_phrase(404)),
+    };
+    if !allowed.split(", ").any(|method| method == request.method()) {
+        return Response::text(405, reason_phrase(405)).with_header("Allow", allowed);
+    }
+
+    match (request.method(), request.path()) {
+        ("GET", "/status") => Json(control.status()).into_response(),
+        ("GET", "/config") => Json(config.clone()).into_response(),
+        ("GET", "/log-level") => Json(Value::object().with("level", log::level().as_str())).into_response(),
+        ("PUT", "/log-level") => {
+            let name = String::from_utf8_lossy(request.body());
+            match Level::parse(&name) {
+                Some(level) => {
+                    log::set_level(level);
+                    log::info(&format!("Log level changed to {} through the admin endpoint", level.as_str()));
+                    Json(Value::object().with("level", level.as_str())).into_response()
+                }
+                None => Response::text(400, "Expected a level of debug, info, warn or error"),
+            }
+        }
+        ("GET", "/maintenance") => Json(Value::object().with("maintenance", control.maintenance())).into_response(),
+        ("PUT", "/maintenance") => {
+            let on = match String::from_utf8_lossy(request.body()).trim() {
+                "on" => true,
+                "off" => false,
+                _ => return Response::text(400, "Expected on or off"),
+            };
+            control.set_maintenance(on);
+            log::info(&format!("Maintenance turned {} through the admin endpoint", if on { "on" } else { "off" }));
+            Json(Value::object().with("maintenance", on)).into_response()
+        }
+        ("POST", "/pool") => resize(request, control),
+        ("POST", "/drain") => {
+            log::info("Draining connections as asked through the admin endpoint");
+            control.drain();
+            Json(Value::object().with("draining", true)).into_response()
+        }
+        _ => {
+            log::info("Shutting down as asked through the admin endpoint");
+            control.shutdown();
+            Json(Value::object().with("shutting_down", true)).into_response().with_status(202)
+        }
+    }
+}
+
+/// Resize a pool as asked by `POST /pool`, answering with the previous
+/// and new size and the jobs queued
+fn resize(request: &Request, control: &dyn Control) -> Response {
+    let body = match std::str::from_utf8(request.body()).map(Value::parse) {
+        Ok(Ok(body)) => body,
+        _ => return Response::text(400, "Expected a JSON object such as {\"size\": 16}"),
+    };
+    let size: usize = match json::field(&body, "size") {
+        Ok(size) if (1..=MAX_WORKERS).contains(&size) => size,
+        _ => return Response::text(400, format!("Expected a size between 1 and {}", MAX_WORKERS)),
+    };
+    let name: Option<String> = match json::field(&body, "pool") {
+        Ok(name) => name,
+        Err(_) => return Response::text(400, "Expected the pool as a string"),
+    };
+    let name = name.unwrap_or_else(|| String::from("default"));
+    let pool = match control.pool(&name) {
+        Some(pool) => pool,
+        None => return Response::text(404, format!("No pool named {}", name)),
+    };
+
+    match pool.resize(size) {
+        Ok(previous) => {
+            log::info(&format!("Pool {} resized from {} to {} workers through the admin endpoint", name, previous, size));
+            let answer = Value::object()
+                .with("pool", name.as_str())
+                .with("previous", previous)
+                .with("size", size)
+                .with("queued", pool.monitor().queued_jobs());
+            Json(answer).into_response()
+        }
+        Err(e) => Response::text(409, e.to_string()),
+    }
+}
+
+/// The configuration as shown by `GET /config`, leaving out hooks and
+/// anything that cannot be shown as JSON
+fn describe(config: &Config) -> Value {
+    let pools: Vec<Value> = config
+        .pools
+        .iter()
+        .map(|(name, size)| Value::object().with("name", name.as_str()).with("size", *size))
+        .collect();
+    let limits = Value::object()
+        .with("max_request_line", config.limits.max_request_line)
+        .with("max_headers", config.limits.max_headers)
+        .with("max_head_size", config.limits.max_head_size)
+        .with("max_buffered", config.limits.max_buffered)
+        .with("max_decompressed_body", config.limits.max_decompressed_body)
+        .with("strictness", format!("{:?}", config.limits.strictness).to_ascii_lowercase());
+    let socket = Value::object()
+        .with("nodelay", config.socket.nodelay)
+        .with("keepalive", config.socket.keepalive.is_some())
+        .with("backlog", config.socket.backlog)
+        .with("send_buffer_size", config.socket.send_buffer_size)
+        .with("recv_buffer_size", config.socket.recv_buffer_size)
+        .with("linger_ms", config.socket.linger.map(|linger| linger.as_millis() as u64))
+        .with("ipv6_only", config.socket.ipv6_only);
+    let shedding = config.shedding.as_ref().map(|shedding| {
+        Value::object()
+            .with("max_queued", shedding.max_queued)
+            .with("max_in_flight", shedding.max_in_flight)
+            .with("retry_after_secs", shedding.retry_after.as_secs_f64())
+            .with("overflow", format!("{:?}", shedding.overflow).to_ascii_lowercase())
+    });
+    let maintenance = Value::object()
+        .with("enabled", config.maintenance.enabled)
+        .with("page", config.maintenance.page.as_ref().map(|page| page.display().to_string()))
+        .with("retry_after_secs", config.maintenance.retry_after.as_secs_f64())
+        .with("allowed", config.maintenance.allowed.iter().map(|allowed| Value::String(allowed.clone())).collect::<Vec<Value>>());
+    let autoscale = config.autoscale.as_ref().map(|autoscale| {
+        Value::object()
+            .with("min", autoscale.min)
+            .with("max", autoscale.max)
+            .with("interval_secs", autoscale.interval.as_secs_f64())
+            .with("max_queued", autoscale.max_queued)
+            .with("max_queue_wait_ms", autoscale.max_queue_wait.as_secs_f64() * 1000.0)
+            .with("grow_after", autoscale.grow_after)
+            .with("shrink_after_secs", autoscale.shrink_after.as_secs_f64())
+            .with("shrink_below", autoscale.shrink_below)
+    });
+    let client_limit = config.client_limit.as_ref().map(|limit| {
+        Value::object()
+            .with("max_in_flight", limit.max_in_flight)
+            .with("max_wait_ms", limit.max_wait.as_secs_f64() * 1000.0)
+            .with("retry_after_secs", limit.retry_after.as_secs_f64())
+            .with("ipv6_prefix", limit.ipv6_prefix)
+            .with("exempt", limit.exempt.iter().map(ToString::to_string).collect::<Vec<_>>())
+    });
+    let https_redirect = config.https_redirect.as_ref().map(|redirect| {
+        Value::object()
+            .with("address", redirect.address.as_str())
+            .with("https_port", redirect.https_port)
+            .with("acme_webroot", redirect.acme_webroot.as_ref().map(|path| path.display().to_string()))
+    });
+    let response_headers: Vec<Value> = config
+        .response_headers
+        .iter()
+        .map(|rule| {
+            let (rule, name, value) = match rule {
+                HeaderRule::Add(name, value) => ("add", name, Some(value.as_str())),
+                HeaderRule::Default(name, value) => ("default", name, Some(value.as_str())),
+                HeaderRule::Set(name, value) => ("set", name, Some(value.as_str())),
+                HeaderRule::Replace(name, value) => ("replace", name, Some(value.as_str())),
+                HeaderRule::Remove(name) => ("remove", name, None),
+            };
+            Value::object().with("rule", rule).with("name", name.as_str()).with("value", value)
+        })
+        .collect();
+    let faults: Vec<Value> = config
+        .faults
+        .iter()
+        .map(|fault| {
+            let kind = match &fault.kind {
+                FaultKind::Delay(delay) => format!("delay {}ms", delay.as_millis()),
+                FaultKind::Status(status) => format!("status {}", status),
+                FaultKind::Drop => String::from("drop"),
+                FaultKind::Truncate(sent) => format!("truncate {}", sent),
+            };
+            let filters: Vec<Value> = fault
+                .filters
+                .iter()
+                .map(|filter| match filter {
+                    FaultFilter::PathPrefix(prefix) => Value::String(format!("path {}*", prefix)),
+                    FaultFilter::Method(method) => Value::String(format!("method {}", method)),
+                    FaultFilter::Header(name, Some(value)) => Value::String(format!("header {}: {}", name, value)),
+                    FaultFilter::Header(name, None) => Value::String(format!("header {}", name)),
+                    FaultFilter::ClientIp(ip) => Value::String(format!("client {}", ip)),
+                })
+                .collect();
+            Value::object().with("kind", kind).with("percent", fault.percent).with("filters", filters)
+        })
+        .collect();
+    let rewrites: Vec<Value> = config
+        .rewrites
+        .iter()
+        .map(|rule| {
+            let action = match &rule.action {
+                Action::Rewrite => String::from("rewrite"),
+                Action::Redirect(status) => format!("redirect {}", status),
+                Action::Alias(root) => format!("alias {}", root.display()),
+            };
+            Value::object()
+                .with("pattern", rule.pattern.as_str())
+                .with("replacement", rule.replacement.as_str())
+                .with("action", action)
+                .with("flag", format!("{:?}", rule.flag).to_ascii_lowercase())
+        })
+        .collect();
+    let debug_dumps = config.debug_dumps.as_ref().map(|dumps| {
+        Value::object()
+            .with("path", dumps.path.display().to_string())
+            .with("sample_rate", dumps.sample_rate)
+            .with("filters", dumps.filters.len())
+            .with("max_body", dumps.max_body)
+    });
+    let recording = config.recording.as_ref().map(|recording| {
+        Value::object()
+            .with("path", recording.path.display().to_string())
+            .with("responses", recording.responses)
+    });
+    let path = |path: &Option<PathBuf>| path.as_ref().map(|path| path.display().to_string());
+    let admin = config.admin.as_ref().map(|admin| match admin {
+        AdminAddress::Unix(path) => format!("unix:{}", path.display()),
+        AdminAddress::Tcp(address) => address.clone(),
+    });
+
+    Value::object()
+        .with("address", config.address.as_str())
+        .with("workers", config.workers)
+        .with("autoscale", autoscale)
+        .with("acceptors", config.acceptors)
+        .with("pools", pools)
+        .with("metrics_path", config.metrics_path.as_deref())
+        .with("server_name", config.server_name.as_deref())
+        .with("response_headers", response_headers)
+        .with("limits", limits)
+        .with("socket", socket)
+        .with("shedding", shedding)
+        .with("client_limit", client_limit)
+        .with("maintenance", maintenance)
+        .with("event_driven", config.event_driven)
+        .with("keep_alive_timeout_secs", config.keep_alive_timeout.as_secs_f64())
+        .with("deadline_header", config.deadline_header.as_deref())
+        .with("header_casing", format!("{:?}", config.header_casing).to_ascii_lowercase())
+        .with("max_requests_per_connection", config.max_requests_per_connection)
+        .with("proxy_protocol", config.proxy_protocol)
+        .with("require_client_cert", config.require_client_cert)
+        .with("trusted_proxies", config.trusted_proxies.iter().map(ToString::to_string).collect::<Vec<_>>())
+        .with("forwarded_header", config.forwarded_header.name())
+        .with("allowed_hosts", &config.allowed_hosts)
+        .with("rewrites", rewrites)
+        .with("https_redirect", https_redirect)
+        .with("user", config.user.as_deref())
+        .with("group", config.group.as_deref())
+        .with("chroot", path(&config.chroot))
+        .with("daemonize", config.daemonize)
+        .with("pid_file", path(&config.pid_file))
+        .with("log_file", path(&config.log_file))
+        .with("log_format", format!("{:?}", config.log_format).to_ascii_lowercase())
+        .with("log_level", config.log_level.as_str())
+        .with("slow_request_threshold_secs", config.slow_request_threshold.map(|threshold| threshold.as_secs_f64()))
+        .with("debug_dumps", debug_dumps)
+        .with("faults", faults)
+        .with("recording", recording)
+        .with("hot_restart", config.hot_restart)
+        .with("admin", admin)
+}
+
+/// Send a command to the admin endpoint of a running server and return
+/// the body of its answer, as the `ctl` subcommand does
+///
+/// # Arguments
+///
+/// address - Where the endpoint listens.
+/// method - The method of the request, such as `GET` or `POST`.
+/// path - The command, such as `/status`.
+/// body - The body of the request, such as the level for `/log-level`.
+///
+/// # Errors
+///
+/// Returns an error if the endpoint cannot be reached or answers with
+/// anything but a success status, with the body of the answer as the
+/// message.
+pub fn send(address: &AdminAddress, method: &str, path: &str, body: &str) -> io::Result<String> {
+    let host = match address {
+        AdminAddress::Tcp(address) => address.as_str(),
+        AdminAddress::Unix(_) => "localhost",
+    };
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        path,
+        host,
+        body.len(),
+        body
+    );
+    let mut answer = Vec::new();
+    match address {
+        AdminAddress::Tcp(address) => {
+            let mut stream = std::net::TcpStream::connect(address)?;
+            stream.write_all(request.as_bytes())?;
+            stream.read_to_end(&mut answer)?;
+        }
+        #[cfg(unix)]
+        AdminAddress::Unix(path) => {
+            let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+            stream.write_all(request.as_bytes())?;
+            stream.read_to_end(&mut answer)?;
+        }
+        #[cfg(not(unix))]
+        AdminAddress::Unix(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix domain sockets are not supported on this platform.",
+            ));
+        }
+    }
+
+    let answer = String::from_utf8_lossy(&answer);
+    let (head, body) = answer
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Malformed answer from the admin endpoint."))?;
+    let success = head.split(' ').nth(1).is_some_and(|status| status.starts_with('2'));
+    if !success {
+        return Err(io::Error::other(body.trim_end().to_string()));
+    }
+    Ok(String::from(body))
+}
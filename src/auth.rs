@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::base64;
+use crate::md5;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+use crate::sha256;
+use crate::trace_context;
+
+/// Looks up the passwords of users for the authentication middleware
+///
+/// Closures of the form `|user: &str| -> Option<String>` implement it,
+/// so users can be looked up anywhere, and `Users` holds them in memory.
+pub trait CredentialStore: Send + Sync + 'static {
+    /// The password of a user, or None if there is no such user
+    fn password(&self, user: &str) -> Option<String>;
+}
+
+impl<F> CredentialStore for F
+where
+    F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+{
+    fn password(&self, user: &str) -> Option<String> {
+        self(user)
+    }
+}
+
+/// Users and their passwords held in memory
+#[derive(Clone, Default)]
+pub struct Users {
+    passwords: HashMap<String, String>,
+}
+
+impl Users {
+    pub fn new() -> Users {
+        Users::default()
+    }
+
+    /// Add a user, replacing its password if it was already added
+    pub fn with_user(mut self, name: &str, password: &str) -> Users {
+        self.passwords.insert(String::from(name), String::from(password));
+        self
+    }
+}
+
+impl CredentialStore for Users {
+    fn password(&self, user: &str) -> Option<String> {
+        self.passwords.get(user).cloned()
+    }
+}
+
+/// The user a request was authenticated as, which the authentication
+/// middleware puts into the request extensions
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct User {
+    pub name: String,
+}
+
+/// A middleware requiring HTTP Basic authentication, as specified in
+/// RFC 7617
+///
+/// Requests without valid credentials are answered with a 401 asking
+/// for them. Basic authentication sends the password itself, so it must
+/// only be used over TLS; `DigestAuth` does not.
+///
+/// ```no_run
+/// use server::auth::{BasicAuth, User, Users};
+/// use server::request::Request;
+/// use server::router::Router;
+///
+/// let mut router = Router::new();
+/// router.wrap(BasicAuth::new("admin", Users::new().with_user("ada", "secret")));
+/// router.get("/", |request: Request| {
+///     format!("Hello, {}", request.extensions().get::<User>().map_or("", |user| user.name.as_str()))
+/// });
+/// ```
+pub struct BasicAuth {
+    realm: String,
+    store: Box<dyn CredentialStore>,
+}
+
+impl BasicAuth {
+    /// Create the middleware
+    ///
+    /// # Arguments
+    ///
+    /// realm - Tells users which of their passwords to enter.
+    /// store - Where the passwords of users are looked up.
+    pub fn new<S: CredentialStore>(realm: &str, store: S) -> BasicAuth {
+        BasicAuth { realm: String::from(realm), store: Box::new(store) }
+    }
+
+    /// The user whose credentials a request carries, if they are valid
+    fn authenticate(&self, request: &Request) -> Option<String> {
+        let (scheme, credentials) = request.header("Authorization")?.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return None;
+        }
+        let decoded = String::from_utf8(base64::decode(credentials.trim())?).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        let expected = self.store.password(user)?;
+        // Comparing hashes takes the same time whatever the passwords
+        // have in common, or how long they are
+        if constant_time_eq(&sha256::digest(expected.as_bytes()), &sha256::digest(password.as_bytes())) {
+            Some(String::from(user))
+        } else {
+            None
+        }
+    }
+}
+
+impl Middleware for BasicAuth {
+    fn handle(&self, mut request: Request, next: &Next) -> Response {
+        match self.authenticate(&request) {
+            Some(name) => {
+                request.extensions_mut().insert(User { name });
+                next.run(request)
+            }
+            None => Response::text(401, "Unauthorized")
+                .with_header("WWW-Authenticate", &format!("Basic realm={}, charset=\"UTF-8\"", quote(&self.realm))),
+        }
+    }
+}
+
+/// A digest algorithm of Digest authentication
+#[derive(Clone, Copy)]
+enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    fn from_name(name: &str) -> Option<Algorithm> {
+        if name.eq_ignore_ascii_case("MD5") {
+            Some(Algorithm::Md5)
+        } else if name.eq_ignore_ascii_case("SHA-256") {
+            Some(Algorithm::Sha256)
+        } else {
+            None
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha256 => "SHA-256",
+        }
+    }
+
+    /// The hash of some text in hexadecimal
+    fn hash(self, text: &str) -> String {
+        match self {
+            Algorithm::Md5 => sha256::hex(&md5::digest(text.as_bytes())),
+            Algorithm::Sha256 => sha256::hex(&sha256::digest(text.as_bytes())),
+        }
+    }
+}
+
+/// Why a request failed Digest authentication
+enum Rejection {
+    /// The credentials are missing or wrong
+    Invalid,
+    /// The credentials are right but the nonce has expired, so the client
+    /// can retry with a new one without asking the user again
+    Stale,
+}
+
+/// A middleware requiring HTTP Digest authentication, as specified in
+/// RFC 7616
+///
+/// Unlike Basic authentication, the password is never sent, so Digest
+/// authentication can protect deployments without TLS, although the
+/// rest of the exchange is still readable. Requests without valid
+/// credentials are answered with a 401 offering SHA-256 and, for older
+/// clients, MD5, with `qop=auth`.
+///
+/// Nonces carry the time they were issued and a MAC keyed with a secret
+/// made when the middleware is created, so no state is kept for them
+/// until they are used. They expire after the nonce lifetime, after which
+/// clients are told to retry with a new one. The nonce count of each
+/// nonce has to increase from request to request, so a captured request
+/// cannot be replayed. The opaque value is made along with the secret
+/// and has to be returned unchanged.
+///
+/// The store is the same kind as for `BasicAuth`, and it has to give
+/// the plain password, as Digest authentication needs it to compute the
+/// expected response.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use server::auth::{DigestAuth, Users};
+/// use server::router::Router;
+///
+/// let users = Users::new().with_user("ada", "secret");
+/// let mut router = Router::new();
+/// router.wrap(DigestAuth::new("admin", users).with_nonce_lifetime(Duration::from_secs(60)));
+/// ```
+pub struct DigestAuth {
+    realm: String,
+    store: Box<dyn CredentialStore>,
+    nonce_lifetime: Duration,
+    secret: [u8; 32],
+    opaque: String,
+    /// The last nonce count of each used nonce that has not expired
+    counts: Mutex<HashMap<String, u32>>,
+}
+
+impl DigestAuth {
+    /// Create the middleware, with nonces expiring after 5 minutes
+    ///
+    /// # Arguments
+    ///
+    /// realm - Tells users which of their passwords to enter, and is
+    /// part of what is hashed.
+    /// store - Where the passwords of users are looked up.
+    pub fn new<S: CredentialStore>(realm: &str, store: S) -> DigestAuth {
+        let secret = random_secret();
+        DigestAuth {
+            realm: String::from(realm),
+            store: Box::new(store),
+            nonce_lifetime: Duration::from_secs(5 * 60),
+            opaque: sha256::hex(&sha256::hmac(&secret, b"opaque")[..16]),
+            secret,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Set how long a nonce can be used after it was issued
+    pub fn with_nonce_lifetime(mut self, lifetime: Duration) -> DigestAuth {
+        self.nonce_lifetime = lifetime;
+        self
+    }
+
+    /// Issue a nonce: the time and a random id, followed by their MAC
+    fn nonce(&self) -> String {
+        let issued = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        let value = format!("{:016x}{}", issued, sha256::hex(&trace_context::random_id()));
+        format!("{}{}", value, self.mac(&value))
+    }
+
+    fn mac(&self, value: &str) -> String {
+        sha256::hex(&sha256::hmac(&self.secret, value.as_bytes())[..16])
+    }
+
+    /// How long ago a nonce was issued, or None if it was not issued by
+    /// this middleware
+    fn nonce_age(&self, nonce: &str) -> Option<Duration> {
+        if nonce.len() != 64 || !nonce.is_ascii() {
+            return None;
+        }
+        let (value, mac) = nonce.split_at(32);
+        if !constant_time_eq(mac.as_bytes(), self.mac(value).as_bytes()) {
+            return None;
+        }
+        let issued = u64::from_str_radix(&value[..16], 16).ok()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        Some(Duration::from_secs(now.saturating_sub(issued)))
+    }
+
+    /// The user whose credentials a request carries, if they are valid
+    fn authenticate(&self, request: &Request) -> Result<String, Rejection> {
+        let header = request.header("Authorization").ok_or(Rejection::Invalid)?.trim();
+        let (scheme, params) = header.split_once(' ').ok_or(Rejection::Invalid)?;
+        if !scheme.eq_ignore_ascii_case("Digest") {
+            return Err(Rejection::Invalid);
+        }
+        let params = auth_params(params);
+        let param = |name: &str| {
+            params.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+        };
+        let required = |name: &str| param(name).ok_or(Rejection::Invalid);
+
+        let user = required("username")?;
+        let nonce = required("nonce")?;
+        let uri = required("uri")?;
+        let count = required("nc")?;
+        let cnonce = required("cnonce")?;
+        let algorithm = Algorithm::from_name(param("algorithm").unwrap_or("MD5")).ok_or(Rejection::Invalid)?;
+        let matches_target = uri == request.target() || uri == request.origin_target();
+        if required("realm")? != self.realm
+            || required("qop")? != "auth"
+            || param("opaque") != Some(self.opaque.as_str())
+            || !matches_target
+        {
+            return Err(Rejection::Invalid);
+        }
+        let age = self.nonce_age(nonce).ok_or(Rejection::Invalid)?;
+        let count_value = u32::from_str_radix(count, 16).map_err(|_| Rejection::Invalid)?;
+
+        let password = self.store.password(user).ok_or(Rejection::Invalid)?;
+        let secret = algorithm.hash(&format!("{}:{}:{}", user, self.realm, password));
+        let target = algorithm.hash(&format!("{}:{}", request.method(), uri));
+        let expected = algorithm.hash(&format!("{}:{}:{}:{}:auth:{}", secret, nonce, count, cnonce, target));
+        if !constant_time_eq(expected.as_bytes(), required("response")?.to_ascii_lowercase().as_bytes()) {
+            return Err(Rejection::Invalid);
+        }
+        if age > self.nonce_lifetime {
+            return Err(Rejection::Stale);
+        }
+
+        let mut counts = self.counts.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !counts.contains_key(nonce) {
+            counts.retain(|used, _| self.nonce_age(used).is_some_and(|age| age <= self.nonce_lifetime));
+        }
+        let last = counts.entry(String::from(nonce)).or_insert(0);
+        if count_value <= *last {
+            return Err(Rejection::Invalid);
+        }
+        *last = count_value;
+        Ok(String::from(user))
+    }
+
+    /// A 401 challenging the client to authenticate
+    fn challenge(&self, stale: bool) -> Response {
+        let nonce = self.nonce();
+        let mut response = Response::text(401, "Unauthorized");
+        for algorithm in [Algorithm::Sha256, Algorithm::Md5].iter() {
+            let mut challenge = format!(
+                "Digest realm={}, qop=\"auth\", algorithm={}, nonce=\"{}\", opaque=\"{}\"",
+                quote(&self.realm),
+                algorithm.name(),
+                nonce,
+                self.opaque
+            );
+            if stale {
+                challenge.push_str(", stale=true");
+            }
+            response = response.with_header("WWW-Authenticate", &challenge);
+        }
+        response
+    }
+}
+
+impl Middleware for DigestAuth {
+    fn handle(&self, mut request: Request, next: &Next) -> Response {
+        match self.authenticate(&request) {
+            Ok(name) => {
+                request.extensions_mut().insert(User { name });
+                next.run(request)
+            }
+            Err(Rejection::Stale) => self.challenge(true),
+            Err(Rejection::Invalid) => self.challenge(false),
+        }
+    }
+}
+
+/// Parse the comma-separated `name=value` parameters of credentials,
+/// unquoting quoted values
+fn auth_params(input: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut rest = input;
+    while let Some((name, after)) = rest.split_once('=') {
+        let name = name.trim().trim_start_matches(',').trim();
+        let after = after.trim_start();
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let end = loop {
+                    match chars.next() {
+                        Some((_, '\\')) => value.extend(chars.next().map(|(_, c)| c)),
+                        Some((i, '"')) => break i + 1,
+                        Some((_, c)) => value.push(c),
+                        None => break quoted.len(),
+                    }
+                };
+                (value, &quoted[end..])
+            }
+            None => {
+                let end = after.find(',').unwrap_or(after.len());
+                (String::from(after[..end].trim()), &after[end..])
+            }
+        };
+        params.push((String::from(name), value));
+        rest = match after.split_once(',') {
+            Some((_, rest)) => rest,
+            None => break,
+        };
+    }
+    params
+}
+
+/// A value as a quoted string
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Compare two values in time that only depends on their length
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+/// A key no one else can guess, from the system's random source if it
+/// has one
+pub(crate) fn random_secret() -> [u8; 32] {
+    let mut secret = [0; 32];
+    let read = File::open("/dev/urandom").and_then(|mut random| random.read_exact(&mut secret));
+    if read.is_err() {
+        for piece in secret.chunks_exact_mut(8) {
+            piece.copy_from_slice(&trace_context::random_id());
+        }
+    }
+    secret
+}
@@ -0,0 +1,158 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::log;
+use crate::PoolHandle;
+
+/// When a pool started with `ThreadPool::with_autoscaling` grows and
+/// shrinks with its load
+///
+/// The load is sampled every interval. A sample is overloaded when more
+/// jobs wait for a worker than `max_queued`, or the jobs started since
+/// the last one waited longer than `max_queue_wait` on average. The pool
+/// grows by half after `grow_after` overloaded samples in a row, and
+/// shrinks by a quarter once it went `shrink_after` without one while
+/// no sample found more than `shrink_below` of its workers busy. Every
+/// change starts both counts over, so the pool settles instead of
+/// flapping between sizes.
+///
+/// ```
+/// use std::time::Duration;
+/// use server::autoscale::Autoscale;
+/// use server::ThreadPool;
+///
+/// let pool = ThreadPool::new(4).unwrap().with_autoscaling(Autoscale {
+///     min: 2,
+///     max: 32,
+///     shrink_after: Duration::from_secs(600),
+///     ..Autoscale::default()
+/// });
+/// ```
+#[derive(Clone, Debug)]
+pub struct Autoscale {
+    /// Fewest workers the pool shrinks to
+    pub min: usize,
+    /// Most workers the pool grows to
+    pub max: usize,
+    /// How often the load is sampled
+    pub interval: Duration,
+    /// Jobs waiting for a worker beyond which a sample is overloaded
+    pub max_queued: usize,
+    /// Average wait of the jobs started since the last sample beyond
+    /// which a sample is overloaded
+    pub max_queue_wait: Duration,
+    /// Overloaded samples in a row after which the pool grows
+    pub grow_after: u32,
+    /// How long the pool has to be lightly loaded before it shrinks
+    pub shrink_after: Duration,
+    /// Share of the workers busy at a sample beyond which the pool is
+    /// not lightly loaded
+    pub shrink_below: f64,
+}
+
+impl Default for Autoscale {
+    fn default() -> Autoscale {
+        Autoscale {
+            min: 1,
+            max: 64,
+            interval: Duration::from_secs(1),
+            max_queued: 16,
+            max_queue_wait: Duration::from_millis(50),
+            grow_after: 2,
+            shrink_after: Duration::from_secs(300),
+            shrink_below: 0.5,
+        }
+    }
+}
+
+impl Autoscale {
+    /// The size the pool should have after a sample, or None to keep
+    /// its size
+    fn target(&self, size: usize, trend: &Trend) -> Option<usize> {
+        let target = if trend.overloaded >= self.grow_after.max(1) {
+            size + (size / 2).max(1)
+        } else if trend.calm_since.elapsed() >= self.shrink_after && trend.peak_active as f64 <= size as f64 * self.shrink_below {
+            size - (size / 4).max(1)
+        } else {
+            size
+        };
+        Some(target.clamp(self.min, self.max)).filter(|&target| target != size)
+    }
+}
+
+/// The waits of the jobs started since the last sample
+#[derive(Default)]
+struct Waits {
+    total_nanos: AtomicU64,
+    jobs: AtomicU64,
+}
+
+/// What the samples since the pool last changed size found
+struct Trend {
+    /// Overloaded samples in a row
+    overloaded: u32,
+    /// When the last overloaded sample or change was
+    calm_since: Instant,
+    /// Most workers busy at a sample since then
+    peak_active: usize,
+}
+
+impl Trend {
+    fn new() -> Trend {
+        Trend { overloaded: 0, calm_since: Instant::now(), peak_active: 0 }
+    }
+}
+
+/// Resize a pool with its load on a thread of its own, which stops once
+/// the pool is dropped
+pub(crate) fn spawn(pool: PoolHandle, config: Autoscale) {
+    let waits = Arc::new(Waits::default());
+    let counted = Arc::clone(&waits);
+    pool.on_job_started(move |job| {
+        counted.total_nanos.fetch_add(job.queue_wait.as_nanos().min(u64::MAX as u128) as u64, Ordering::Relaxed);
+        counted.jobs.fetch_add(1, Ordering::Relaxed);
+    });
+    thread::spawn(move || {
+        let mut trend = Trend::new();
+        loop {
+            thread::sleep(config.interval);
+            if pool.is_dropped() {
+                return;
+            }
+            let monitor = pool.monitor();
+            let (size, queued, active) = (monitor.size(), monitor.queued_jobs(), monitor.active_jobs());
+            let jobs = waits.jobs.swap(0, Ordering::Relaxed);
+            let total = waits.total_nanos.swap(0, Ordering::Relaxed);
+            let wait = Duration::from_nanos(total.checked_div(jobs).unwrap_or(0));
+            if queued > config.max_queued || wait > config.max_queue_wait {
+                trend.overloaded += 1;
+                trend.calm_since = Instant::now();
+                trend.peak_active = 0;
+            } else {
+                trend.overloaded = 0;
+                trend.peak_active = trend.peak_active.max(active);
+            }
+
+            let target = match config.target(size, &trend) {
+                Some(target) => target,
+                None => continue,
+            };
+            match pool.resize(target) {
+                Ok(_) => log::info(&format!(
+                    "Pool resized from {} to {} workers, with {} jobs queued and {}ms waited on average",
+                    size,
+                    target,
+                    queued,
+                    wait.as_millis()
+                )),
+                Err(e) => {
+                    log::error(&format!("Failed to resize pool: {}", e));
+                    return;
+                }
+            }
+            trend = Trend::new();
+        }
+    });
+}
@@ -0,0 +1,67 @@
+// Base64 encoding as specified in RFC 4648, with the standard alphabet
+// and, for decoding, the URL-safe one
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+const URL_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Encode bytes with padding
+///
+/// ```
+/// assert_eq!(server::base64::encode(b"hello"), "aGVsbG8=");
+/// ```
+pub fn encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let bytes = [group[0], *group.get(1).unwrap_or(&0), *group.get(2).unwrap_or(&0)];
+        let bits = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+        for i in 0..4 {
+            if i <= group.len() {
+                encoded.push(char::from(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decode text in the standard alphabet, with or without padding, or
+/// None if it is not valid base64
+///
+/// ```
+/// assert_eq!(server::base64::decode("aGVsbG8=").as_deref(), Some(&b"hello"[..]));
+/// assert_eq!(server::base64::decode("aGVsbG8").as_deref(), Some(&b"hello"[..]));
+/// assert_eq!(server::base64::decode("aGVsbG=8"), None);
+/// ```
+pub fn decode(encoded: &str) -> Option<Vec<u8>> {
+    decode_with(encoded, ALPHABET)
+}
+
+/// Decode text in the URL-safe alphabet, with or without padding, or
+/// None if it is not valid base64url
+///
+/// ```
+/// assert_eq!(server::base64::decode_url("-_8").as_deref(), Some(&[0xfb, 0xff][..]));
+/// assert_eq!(server::base64::decode_url("+/8="), None);
+/// ```
+pub fn decode_url(encoded: &str) -> Option<Vec<u8>> {
+    decode_with(encoded, URL_ALPHABET)
+}
+
+fn decode_with(encoded: &str, alphabet: &[u8; 64]) -> Option<Vec<u8>> {
+    let unpadded = encoded.strip_suffix("==").or_else(|| encoded.strip_suffix('=')).unwrap_or(encoded);
+    if (encoded.len() != unpadded.len() && !encoded.len().is_multiple_of(4)) || unpadded.len() % 4 == 1 {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(unpadded.len() / 4 * 3 + 2);
+    for group in unpadded.as_bytes().chunks(4) {
+        let mut bits = 0u32;
+        for (i, byte) in group.iter().enumerate() {
+            let value = alphabet.iter().position(|b| b == byte)? as u32;
+            bits |= value << (18 - 6 * i);
+        }
+        decoded.extend_from_slice(&bits.to_be_bytes()[1..group.len()]);
+    }
+    Some(decoded)
+}
@@ -1,41 +1,190 @@
-use std::io::prelude::*;
-use std::net::TcpListener;
-use std::net::TcpStream;
-use std::fs;
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
 
-use server::ThreadPool;
+use server::admin::{self, AdminAddress};
+use server::cache::ResponseCache;
+use server::config::Config;
+use server::json::Value;
+use server::loadgen::{self, LoadConfig};
+use server::record::Replay;
+use server::response::Response;
+use server::router::Router;
+use server::server::Server;
+use server::template::Context;
+
+/// Where the admin endpoint of the server listens, and `ctl` connects to
+/// unless told otherwise
+const ADMIN_ADDRESS: &str = "127.0.0.1:7879";
 
 fn main() {
-    println!("Opening web server in 127.0.0.1:7878...");
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4).unwrap();
-
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("bench") => {
+            bench(&args[1..]);
+            return;
+        }
+        Some("ctl") => {
+            ctl(&args[1..]);
+            return;
+        }
+        Some("replay") => {
+            replay(&args[1..]);
+            return;
+        }
+        _ => {}
     }
 
+    let config = Config {
+        metrics_path: Some(String::from("/metrics")),
+        admin: Some(AdminAddress::Tcp(String::from(ADMIN_ADDRESS))),
+        ..Config::default()
+    };
+
+    println!("Opening web server in {}...", config.address);
+    let server = Server::new(config).unwrap();
+
+    server.serve(router());
+
     println!("Shutting down.");
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    let mut buffer = [0; 512];
-    stream.read(&mut buffer).unwrap();
+/// The routes of the site
+fn router() -> Router {
+    let mut router = Router::new();
+    router.wrap(ResponseCache::new(Duration::from_secs(10)));
+    router.get("/", |_| Response::render("index.html", &Context::new()));
+    router.fallback(|_| {
+        let response = Response::render("404.html", &Context::new());
+        if response.status() == 200 {
+            response.with_status(404)
+        } else {
+            response
+        }
+    });
+    router
+}
+
+/// Generate load against a server and print how it held up
+///
+/// Usage: `bench [-c connections] [-d seconds] [-m method] [-H "Name: value"] url`
+fn bench(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!("Usage: bench [-c connections] [-d seconds] [-m method] [-H \"Name: value\"] url");
+        process::exit(2);
+    };
+
+    let mut config = LoadConfig::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" => config.connections = args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage()),
+            "-d" => {
+                let seconds = args.next().and_then(|n| n.parse().ok());
+                config.duration = seconds
+                    .and_then(|s| Duration::try_from_secs_f64(s).ok())
+                    .unwrap_or_else(|| usage());
+            }
+            "-m" => config.method = args.next().cloned().unwrap_or_else(|| usage()),
+            "-H" => {
+                let header = args.next().and_then(|h| h.split_once(':')).unwrap_or_else(|| usage());
+                config.headers.push((String::from(header.0.trim()), String::from(header.1.trim())));
+            }
+            url if !url.starts_with('-') => config.url = String::from(url),
+            _ => usage(),
+        }
+    }
+
+    println!(
+        "Sending {} {} over {} connections for {:.1}s...",
+        config.method,
+        config.url,
+        config.connections,
+        config.duration.as_secs_f64()
+    );
+    match loadgen::run(&config) {
+        Ok(report) => println!("{}", report),
+        Err(e) => {
+            eprintln!("Load test failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Send a command to the admin endpoint of a running server and print
+/// its answer
+///
+/// Usage: `ctl [-s socket | -a address] status|config|drain|shutdown|log-level [level]|maintenance [on|off]|pool [name] size`
+fn ctl(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!("Usage: ctl [-s socket | -a address] status|config|drain|shutdown|log-level [level]|maintenance [on|off]|pool [name] size");
+        process::exit(2);
+    };
+
+    let mut address = AdminAddress::Tcp(String::from(ADMIN_ADDRESS));
+    let mut command = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-s" => address = AdminAddress::Unix(args.next().map(PathBuf::from).unwrap_or_else(|| usage())),
+            "-a" => address = AdminAddress::Tcp(args.next().cloned().unwrap_or_else(|| usage())),
+            word if !word.starts_with('-') => command.push(word),
+            _ => usage(),
+        }
+    }
+
+    let (method, path, body) = match command.as_slice() {
+        ["status"] => ("GET", "/status", String::new()),
+        ["config"] => ("GET", "/config", String::new()),
+        ["drain"] => ("POST", "/drain", String::new()),
+        ["shutdown"] => ("POST", "/shutdown", String::new()),
+        ["log-level"] => ("GET", "/log-level", String::new()),
+        ["log-level", level] => ("PUT", "/log-level", String::from(*level)),
+        ["maintenance"] => ("GET", "/maintenance", String::new()),
+        ["maintenance", on] => ("PUT", "/maintenance", String::from(*on)),
+        ["pool", size] => ("POST", "/pool", format!("{{\"size\": {}}}", size)),
+        ["pool", name, size] => ("POST", "/pool", format!("{{\"pool\": {}, \"size\": {}}}", Value::String(String::from(*name)), size)),
+        _ => usage(),
+    };
+    match admin::send(&address, method, path, &body) {
+        Ok(answer) => println!("{}", answer.trim_end()),
+        Err(e) => {
+            eprintln!("Command failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
 
-    let get = b"GET / HTTP/1.1\r\n";
-    
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK\r\n\r\n", "index.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "404.html")
+/// Feed recorded traffic through the routes of the site and print how
+/// the answers compare to the recorded ones
+///
+/// Usage: `replay [-s speed] [-c concurrency] file`
+fn replay(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!("Usage: replay [-s speed] [-c concurrency] file");
+        process::exit(2);
     };
-    
-    let contents = fs::read_to_string(filename).unwrap();
-    let response = format!("{}{}", status_line, contents);
 
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+    let mut file = None;
+    let mut speed = 1.0;
+    let mut concurrency = 16;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-s" => speed = args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage()),
+            "-c" => concurrency = args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage()),
+            path if !path.starts_with('-') => file = Some(PathBuf::from(path)),
+            _ => usage(),
+        }
+    }
+    let file = file.unwrap_or_else(|| usage());
+
+    match Replay::new(file).with_speed(speed).with_concurrency(concurrency).run(&router()) {
+        Ok(report) => println!("{}", report),
+        Err(e) => {
+            eprintln!("Replay failed: {}", e);
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,113 @@
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+
+/// The body of a request, read in chunks as it arrives
+///
+/// Taken from a request with `Request::take_body`. For a route set to
+/// `Route::stream_body`, the chunks are read from the connection while
+/// the handler consumes them, with only a few of them buffered in
+/// between, so a large upload can be hashed or written to storage
+/// without holding it in memory. Otherwise the body was already read
+/// and is yielded as a single chunk.
+///
+/// It can be iterated over, or read from like any other reader:
+///
+/// ```no_run
+/// use std::fs::File;
+/// use std::io;
+/// use server::request::Request;
+/// use server::response::Response;
+/// use server::router::Router;
+///
+/// fn upload(mut request: Request) -> Response {
+///     let stored = File::create("upload.bin").and_then(|mut file| io::copy(&mut request.take_body(), &mut file));
+///     match stored {
+///         Ok(size) => Response::text(201, format!("Stored {} bytes", size)),
+///         Err(e) => Response::text(400, e.to_string()),
+///     }
+/// }
+///
+/// let mut router = Router::new();
+/// router.post("/upload", upload).stream_body();
+/// ```
+///
+/// A body that is dropped before the end is still read from the
+/// connection in full, but discarded. A failure to read it, including a
+/// body larger than `Limits::max_streamed_body`, is returned as an error
+/// by the iterator and the reader, and the connection is closed once
+/// the response has been sent.
+pub struct Body {
+    source: Source,
+    /// A chunk only partially read by `Read::read`
+    current: Vec<u8>,
+    position: usize,
+}
+
+enum Source {
+    Buffered(Option<Vec<u8>>),
+    Streaming(Receiver<io::Result<Vec<u8>>>),
+}
+
+impl Body {
+    /// A body that was read into memory
+    pub(crate) fn buffered(body: Vec<u8>) -> Body {
+        let body = if body.is_empty() { None } else { Some(body) };
+        Body { source: Source::Buffered(body), current: Vec::new(), position: 0 }
+    }
+
+    /// A body streamed in through the returned sender, which holds up to
+    /// `capacity` chunks the body has not taken yet
+    pub(crate) fn channel(capacity: usize) -> (SyncSender<io::Result<Vec<u8>>>, Body) {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        (sender, Body { source: Source::Streaming(receiver), current: Vec::new(), position: 0 })
+    }
+
+    /// Read the rest of the body into memory
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the body cannot be read in full.
+    pub fn read_all(self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        for chunk in self {
+            body.extend_from_slice(&chunk?);
+        }
+        Ok(body)
+    }
+}
+
+impl Iterator for Body {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.position < self.current.len() {
+            let rest = self.current.split_off(self.position);
+            self.position = 0;
+            self.current.clear();
+            return Some(Ok(rest));
+        }
+        match &mut self.source {
+            Source::Buffered(body) => body.take().map(Ok),
+            // The sender is dropped once the whole body has been sent
+            Source::Streaming(receiver) => receiver.recv().ok(),
+        }
+    }
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.current.len() {
+            match self.next() {
+                Some(chunk) => {
+                    self.current = chunk?;
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let size = buf.len().min(self.current.len() - self.position);
+        buf[..size].copy_from_slice(&self.current[self.position..self.position + size]);
+        self.position += size;
+        Ok(size)
+    }
+}
@@ -0,0 +1,454 @@
+// Brotli as specified in RFC 7932, without the static dictionary and
+// with a single block type and prefix code per category, which keeps the
+// encoder small at some cost in compression over the reference one
+
+use std::io::{self, Write};
+
+use crate::huffman::{self, BitWriter};
+use crate::lz77::{Matcher, Params, Token};
+
+/// Input compressed into one meta-block
+const BLOCK_SIZE: usize = 1 << 16;
+
+/// The window size is 2^WINDOW_BITS - 16 bytes; the format allows up to
+/// 2^24, but every stream being compressed keeps a window in memory
+const WINDOW_BITS: u32 = 18;
+
+/// How long a chain of earlier positions each quality searches for
+/// matches
+const CHAIN_LENGTHS: [usize; 12] = [1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024, 4096];
+
+/// Insert lengths at which each insert length code starts, and their
+/// extra bits
+const INSERT_BASE: [u32; 24] =
+    [0, 1, 2, 3, 4, 5, 6, 8, 10, 14, 18, 26, 34, 50, 66, 98, 130, 194, 322, 578, 1090, 2114, 6210, 22594];
+const INSERT_EXTRA: [u32; 24] = [0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 7, 8, 9, 10, 12, 14, 24];
+
+/// Copy lengths at which each copy length code starts, and their extra
+/// bits
+const COPY_BASE: [u32; 24] =
+    [2, 3, 4, 5, 6, 7, 8, 9, 10, 12, 14, 18, 22, 30, 38, 54, 70, 102, 134, 198, 326, 582, 1094, 2118];
+const COPY_EXTRA: [u32; 24] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 7, 8, 9, 10, 24];
+
+/// The order code length code lengths are sent in
+const CODE_LENGTH_ORDER: [usize; 18] = [1, 2, 3, 4, 0, 5, 17, 6, 16, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+/// The static code code length code lengths are sent with, as the
+/// bit-reversed code and its length of each length from 0 to 5
+const CODE_LENGTH_CODES: [(u64, u32); 6] = [(0, 2), (7, 4), (3, 3), (2, 2), (1, 2), (15, 4)];
+
+const LITERAL_ALPHABET: usize = 256;
+const COMMAND_ALPHABET: usize = 704;
+/// 16 short codes and 48 long ones, with no postfix bits or direct codes
+const DISTANCE_ALPHABET: usize = 64;
+
+/// Compresses what is written to it into Brotli written to another
+/// writer
+///
+/// Compressed data is written in meta-blocks as enough input arrives.
+/// `flush` writes out everything written so far so it can be
+/// decompressed right away, at some cost in compression, and `finish`
+/// ends the stream; an encoder dropped without `finish` leaves it
+/// truncated.
+///
+/// ```
+/// use std::io::Write;
+/// use server::brotli::BrotliEncoder;
+///
+/// let mut encoder = BrotliEncoder::new(Vec::new(), 5);
+/// encoder.write_all("hello ".repeat(1000).as_bytes()).unwrap();
+/// let compressed = encoder.finish().unwrap();
+/// assert!(compressed.len() < 100);
+/// ```
+pub struct BrotliEncoder<W: Write> {
+    inner: W,
+    matcher: Matcher,
+    bits: BitWriter,
+    /// The distance of the last copy that did not reuse it
+    last_distance: u32,
+}
+
+impl<W: Write> BrotliEncoder<W> {
+    /// Create an encoder
+    ///
+    /// # Arguments
+    ///
+    /// inner - The writer the compressed stream is written to.
+    /// quality - From 0, the fastest, to 11, searching hardest for the
+    /// smallest output; larger qualities mean 11.
+    pub fn new(inner: W, quality: u32) -> BrotliEncoder<W> {
+        let quality = quality.min(11);
+        let params = Params {
+            window: (1 << WINDOW_BITS) - 16,
+            min_length: 4,
+            max_length: 1 << 16,
+            max_chain: CHAIN_LENGTHS[quality as usize],
+            lazy: quality >= 4,
+        };
+        let mut bits = BitWriter::default();
+        bits.write(1, 1);
+        bits.write(u64::from(WINDOW_BITS - 17), 3);
+        BrotliEncoder { inner, matcher: Matcher::new(params), bits, last_distance: 4 }
+    }
+
+    /// The writer the compressed stream is written to
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// The writer the compressed stream is written to, which must not
+    /// be written to directly
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Compress the rest of the input and end the stream, returning the
+    /// underlying writer
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing to the underlying writer fails.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.matcher.pending() > 0 {
+            self.meta_block();
+        }
+        // An empty last meta-block
+        self.bits.write(1, 1);
+        self.bits.write(1, 1);
+        self.bits.align();
+        self.bits.drain_to(&mut self.inner)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Compress up to a block of pending input, storing it uncompressed
+    /// if it does not get smaller
+    fn meta_block(&mut self) {
+        let tokens = self.matcher.parse(BLOCK_SIZE);
+        let raw = self.matcher.block();
+        let mut compressed = self.bits.scratch();
+        let last_distance = write_compressed(&mut compressed, &tokens, raw, self.last_distance);
+        // An uncompressed meta-block takes the header, up to a byte of
+        // padding and the data
+        if compressed.len() < 40 + raw.len() as u64 * 8 {
+            self.bits.append(compressed);
+            self.last_distance = last_distance;
+        } else {
+            write_header(&mut self.bits, raw.len());
+            self.bits.write(1, 1);
+            self.bits.align();
+            self.bits.write_bytes(raw);
+        }
+    }
+}
+
+impl<W: Write> Write for BrotliEncoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        for piece in data.chunks(BLOCK_SIZE) {
+            self.matcher.push(piece);
+            while self.matcher.pending() >= BLOCK_SIZE {
+                self.meta_block();
+            }
+        }
+        self.bits.drain_to(&mut self.inner)?;
+        Ok(data.len())
+    }
+
+    /// Compress what is pending and align the output to a byte with an
+    /// empty metadata block, so a decompressor gets all of the input
+    fn flush(&mut self) -> io::Result<()> {
+        if self.matcher.pending() > 0 {
+            self.meta_block();
+        }
+        self.bits.write(0, 1);
+        self.bits.write(3, 2);
+        self.bits.write(0, 1);
+        self.bits.write(0, 2);
+        self.bits.align();
+        self.bits.drain_to(&mut self.inner)?;
+        self.inner.flush()
+    }
+}
+
+/// A command: literals to insert, then a copy of earlier output
+struct Command {
+    symbol: usize,
+    insert_extra: (u64, u32),
+    copy_extra: (u64, u32),
+    /// The number of literals
+    insert: usize,
+    /// The number of bytes copied
+    copy: usize,
+    /// The distance code and its extra bits, None if the command
+    /// repeats the last distance or has no copy
+    distance: Option<(usize, u64, u32)>,
+}
+
+/// The header of a meta-block that is not the last one
+fn write_header(bits: &mut BitWriter, length: usize) {
+    let nibbles = match length - 1 {
+        0..=0xffff => 4,
+        0x10000..=0xfffff => 5,
+        _ => 6,
+    };
+    bits.write(0, 1);
+    bits.write(nibbles - 4, 2);
+    bits.write(length as u64 - 1, nibbles as u32 * 4);
+}
+
+/// Write a compressed meta-block, returning the last distance after it
+fn write_compressed(bits: &mut BitWriter, tokens: &[Token], raw: &[u8], mut last_distance: u32) -> u32 {
+    let mut commands = Vec::new();
+    let mut inserted = 0;
+    for token in tokens {
+        match *token {
+            Token::Literal(_) => inserted += 1,
+            Token::Match { length, distance } => {
+                let reuse = distance == last_distance;
+                if !reuse {
+                    last_distance = distance;
+                }
+                commands.push(command(inserted, Some((length, distance)), reuse));
+                inserted = 0;
+            }
+        }
+    }
+    if inserted > 0 {
+        commands.push(command(inserted, None, false));
+    }
+
+    let mut literal_frequencies = [0u32; LITERAL_ALPHABET];
+    let mut command_frequencies = [0u32; COMMAND_ALPHABET];
+    let mut distance_frequencies = [0u32; DISTANCE_ALPHABET];
+    for token in tokens {
+        if let Token::Literal(byte) = *token {
+            literal_frequencies[usize::from(byte)] += 1;
+        }
+    }
+    for command in &commands {
+        command_frequencies[command.symbol] += 1;
+        if let Some((code, _, _)) = command.distance {
+            distance_frequencies[code] += 1;
+        }
+    }
+
+    write_header(bits, raw.len());
+    bits.write(0, 1);
+    // One block type each for literals, commands and distances
+    bits.write(0, 1);
+    bits.write(0, 1);
+    bits.write(0, 1);
+    // No postfix bits or direct distance codes, and the literal context
+    // mode, which does not matter with a single literal code
+    bits.write(0, 2);
+    bits.write(0, 4);
+    bits.write(0, 2);
+    // One literal and one distance code, so no context maps
+    bits.write(0, 1);
+    bits.write(0, 1);
+    let literals = write_prefix_code(bits, &literal_frequencies, 8);
+    let command_code = write_prefix_code(bits, &command_frequencies, 10);
+    let distances = write_prefix_code(bits, &distance_frequencies, 6);
+
+    let mut position = 0;
+    for command in &commands {
+        command_code.write(bits, command.symbol);
+        bits.write(command.insert_extra.0, command.insert_extra.1);
+        bits.write(command.copy_extra.0, command.copy_extra.1);
+        for &byte in &raw[position..position + command.insert] {
+            literals.write(bits, usize::from(byte));
+        }
+        if let Some((code, extra, extra_bits)) = command.distance {
+            distances.write(bits, code);
+            bits.write(extra, extra_bits);
+        }
+        position += command.insert + command.copy;
+    }
+    last_distance
+}
+
+/// Work out the command symbol and extra bits of a run of literals
+/// followed by a copy, or by nothing at the end of a meta-block
+///
+/// # Arguments
+///
+/// insert - The number of literals.
+/// copy - The length and distance of the copy.
+/// reuse - Whether the copy has the distance of the last one.
+fn command(insert: usize, copy: Option<(u32, u32)>, reuse: bool) -> Command {
+    let insert_code = INSERT_BASE.partition_point(|&base| base as usize <= insert) - 1;
+    let length = copy.map_or(2, |(length, _)| length);
+    let copy_code = COPY_BASE.partition_point(|&base| base <= length) - 1;
+    // The copy length is ignored after the last literals of the
+    // meta-block, and so is the distance, which is never read
+    let implicit = (copy.is_none() || reuse) && insert_code < 8 && copy_code < 16;
+    let cell = match (insert_code / 8, copy_code / 8, implicit) {
+        (0, 0, true) => 0,
+        (0, 1, true) => 64,
+        (0, 0, _) => 128,
+        (0, 1, _) => 192,
+        (1, 0, _) => 256,
+        (1, 1, _) => 320,
+        (0, 2, _) => 384,
+        (2, 0, _) => 448,
+        (1, 2, _) => 512,
+        (2, 1, _) => 576,
+        _ => 640,
+    };
+    let distance = match copy {
+        Some(_) if implicit => None,
+        Some(_) if reuse => Some((0, 0, 0)),
+        Some((_, distance)) => Some(distance_code(distance)),
+        None => None,
+    };
+    Command {
+        symbol: cell + (insert_code % 8) * 8 + copy_code % 8,
+        insert_extra: ((insert - INSERT_BASE[insert_code] as usize) as u64, INSERT_EXTRA[insert_code]),
+        copy_extra: (u64::from(length - COPY_BASE[copy_code]), COPY_EXTRA[copy_code]),
+        insert,
+        copy: copy.map_or(0, |(length, _)| length as usize),
+        distance,
+    }
+}
+
+/// The long distance code for a distance, with its extra bits
+fn distance_code(distance: u32) -> (usize, u64, u32) {
+    let offset = distance + 3;
+    let extra_bits = 31 - offset.leading_zeros() - 1;
+    let prefix = (offset >> extra_bits) & 1;
+    let code = 16 + 2 * (extra_bits - 1) + prefix;
+    (code as usize, u64::from(offset - ((2 + prefix) << extra_bits)), extra_bits)
+}
+
+/// A prefix code as the lengths and bit-reversed codes of its symbols
+struct Code {
+    lengths: Vec<u8>,
+    codes: Vec<u32>,
+}
+
+impl Code {
+    fn write(&self, bits: &mut BitWriter, symbol: usize) {
+        bits.write(u64::from(self.codes[symbol]), u32::from(self.lengths[symbol]));
+    }
+}
+
+/// Write the prefix code for symbols with the given frequencies, and
+/// return it
+///
+/// # Arguments
+///
+/// bits - Where the code is written.
+/// frequencies - How often each symbol of the alphabet is used.
+/// alphabet_bits - The number of bits a symbol of the alphabet takes.
+fn write_prefix_code(bits: &mut BitWriter, frequencies: &[u32], alphabet_bits: u32) -> Code {
+    let used: Vec<usize> = (0..frequencies.len()).filter(|&symbol| frequencies[symbol] > 0).collect();
+    if used.len() < 2 {
+        // A simple code of one symbol, which takes no bits at all
+        bits.write(1, 2);
+        bits.write(0, 2);
+        bits.write(used.first().copied().unwrap_or(0) as u64, alphabet_bits);
+        return Code { lengths: vec![0; frequencies.len()], codes: vec![0; frequencies.len()] };
+    }
+
+    let lengths = huffman::lengths(frequencies, 15);
+    let runs = run_lengths(&lengths);
+    let mut code_length_frequencies = [0u32; 18];
+    for &(symbol, _) in &runs {
+        code_length_frequencies[usize::from(symbol)] += 1;
+    }
+    // The code length code needs two symbols to be complete
+    if code_length_frequencies.iter().filter(|&&frequency| frequency > 0).count() < 2 {
+        let unused = code_length_frequencies.iter().position(|&frequency| frequency == 0).unwrap_or(0);
+        code_length_frequencies[unused] = 1;
+    }
+    let code_length_lengths = huffman::lengths(&code_length_frequencies, 5);
+    let code_length_codes = huffman::codes(&code_length_lengths);
+    let count = CODE_LENGTH_ORDER.iter().rposition(|&symbol| code_length_lengths[symbol] > 0).map_or(0, |i| i + 1);
+
+    bits.write(0, 2);
+    for &symbol in &CODE_LENGTH_ORDER[..count] {
+        let (code, length) = CODE_LENGTH_CODES[usize::from(code_length_lengths[symbol])];
+        bits.write(code, length);
+    }
+    for &(symbol, extra) in &runs {
+        let symbol = usize::from(symbol);
+        bits.write(u64::from(code_length_codes[symbol]), u32::from(code_length_lengths[symbol]));
+        match symbol {
+            16 => bits.write(u64::from(extra), 2),
+            17 => bits.write(u64::from(extra), 3),
+            _ => {}
+        }
+    }
+    Code { codes: huffman::codes(&lengths), lengths }
+}
+
+/// Run-length encode code lengths up to the last used one, as the
+/// reference encoder does: 16 repeats the previous non-zero length and
+/// 17 a zero, and runs of them multiply their counts
+fn run_lengths(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let end = lengths.iter().rposition(|&length| length > 0).map_or(0, |i| i + 1);
+    let mut runs = Vec::new();
+    // The length 16 repeats before any is sent
+    let mut previous = 8;
+    let mut i = 0;
+    while i < end {
+        let length = lengths[i];
+        let run = lengths[i..end].iter().take_while(|&&l| l == length).count();
+        if length == 0 {
+            repeat_zeros(&mut runs, run);
+        } else {
+            repeat_length(&mut runs, previous, length, run);
+            previous = length;
+        }
+        i += run;
+    }
+    runs
+}
+
+fn repeat_zeros(runs: &mut Vec<(u8, u8)>, mut run: usize) {
+    if run == 11 {
+        runs.push((0, 0));
+        run -= 1;
+    }
+    if run < 3 {
+        runs.extend(std::iter::repeat_n((0, 0), run));
+        return;
+    }
+    let start = runs.len();
+    run -= 3;
+    loop {
+        runs.push((17, (run & 7) as u8));
+        run >>= 3;
+        if run == 0 {
+            break;
+        }
+        run -= 1;
+    }
+    runs[start..].reverse();
+}
+
+fn repeat_length(runs: &mut Vec<(u8, u8)>, previous: u8, length: u8, mut run: usize) {
+    if previous != length {
+        runs.push((length, 0));
+        run -= 1;
+    }
+    if run == 7 {
+        runs.push((length, 0));
+        run -= 1;
+    }
+    if run < 3 {
+        runs.extend(std::iter::repeat_n((length, 0), run));
+        return;
+    }
+    let start = runs.len();
+    run -= 3;
+    loop {
+        runs.push((16, (run & 3) as u8));
+        run >>= 2;
+        if run == 0 {
+            break;
+        }
+        run -= 1;
+    }
+    runs[start..].reverse();
+}
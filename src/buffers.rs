@@ -0,0 +1,151 @@
+use std::io::{self, BufRead, Read};
+use std::sync::Mutex;
+
+/// How much larger than the pool's buffer size a returned buffer may
+/// have grown and still be kept, so one huge response does not pin its
+/// memory for good
+const MAX_GROWTH: usize = 4;
+
+/// Byte buffers that connections check out when they are accepted and
+/// return when they close, so the buffers requests are read into and
+/// responses written from are not allocated anew for every connection
+///
+/// ```
+/// use server::buffers::BufferPool;
+///
+/// let pool = BufferPool::new(8192, 16);
+/// let buffer = pool.take();
+/// assert!(buffer.capacity() >= 8192);
+/// pool.give(buffer);
+/// assert_eq!(pool.idle(), 1);
+/// ```
+pub struct BufferPool {
+    idle: Mutex<Vec<Vec<u8>>>,
+    buffer_size: usize,
+    capacity: usize,
+}
+
+impl BufferPool {
+    /// Create an empty pool
+    ///
+    /// # Arguments
+    ///
+    /// buffer_size - The number of bytes the buffers have room for.
+    /// capacity - The most buffers kept for reuse; ones returned beyond
+    /// it are dropped.
+    pub fn new(buffer_size: usize, capacity: usize) -> BufferPool {
+        BufferPool { idle: Mutex::new(Vec::new()), buffer_size, capacity }
+    }
+
+    /// The number of bytes the buffers have room for
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Check out an empty buffer with room for at least `buffer_size`
+    /// bytes, reusing a returned one if there is any
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool mutex is in a poisoned state.
+    pub fn take(&self) -> Vec<u8> {
+        match self.idle.lock().unwrap().pop() {
+            Some(buffer) => buffer,
+            None => Vec::with_capacity(self.buffer_size),
+        }
+    }
+
+    /// Return a buffer for reuse
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool mutex is in a poisoned state.
+    pub fn give(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() < self.buffer_size || buffer.capacity() > self.buffer_size * MAX_GROWTH {
+            return;
+        }
+        buffer.clear();
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.capacity {
+            idle.push(buffer);
+        }
+    }
+
+    /// The number of buffers waiting to be checked out
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool mutex is in a poisoned state.
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+}
+
+/// A buffered reader like `std::io::BufReader` whose buffer can be
+/// taken back out to return it to a pool
+pub(crate) struct PooledReader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    /// The unread bytes of the buffer
+    start: usize,
+    end: usize,
+}
+
+impl<R: Read> PooledReader<R> {
+    /// Read through a buffer, using all of its capacity
+    pub(crate) fn new(inner: R, mut buffer: Vec<u8>) -> PooledReader<R> {
+        buffer.resize(buffer.capacity(), 0);
+        PooledReader { inner, buffer, start: 0, end: 0 }
+    }
+
+    /// The bytes read ahead and not yet consumed
+    pub(crate) fn buffer(&self) -> &[u8] {
+        &self.buffer[self.start..self.end]
+    }
+
+    pub(crate) fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Take the buffer out, dropping what it holds, after which the
+    /// reader reads unbuffered
+    pub(crate) fn take_buffer(&mut self) -> Vec<u8> {
+        self.start = 0;
+        self.end = 0;
+        std::mem::take(&mut self.buffer)
+    }
+}
+
+impl<R: Read> Read for PooledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Reads at least as large as the buffer skip it, as
+        // BufReader's do
+        if self.start == self.end && buf.len() >= self.buffer.len() {
+            return self.inner.read(buf);
+        }
+        let read = {
+            let mut available = self.fill_buf()?;
+            available.read(buf)?
+        };
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl<R: Read> BufRead for PooledReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.start == self.end {
+            self.start = 0;
+            self.end = self.inner.read(&mut self.buffer)?;
+        }
+        Ok(&self.buffer[self.start..self.end])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.start = (self.start + amount).min(self.end);
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+
+/// A middleware keeping successful responses in memory and serving
+/// repeated requests from there without running the handler
+///
+/// Responses are stored per method, target and Accept-Encoding of the
+/// request. Only complete 200 responses to GET requests without
+/// credentials are stored, and only if their Cache-Control allows it.
+/// A `max-age` or `s-maxage` directive replaces the default lifetime.
+/// When the cache is full, the least recently used responses go first.
+pub struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    max_size: usize,
+    state: Mutex<State>,
+}
+
+#[derive(PartialEq, Eq, Hash, Clone)]
+struct Key {
+    method: String,
+    /// Responses for different hosts must not be mixed up, whatever
+    /// host a client claims
+    host: String,
+    target: String,
+    encoding: String,
+}
+
+struct Entry {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    stored: Instant,
+    expires: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<Key, Entry>,
+    size: usize,
+    clock: u64,
+}
+
+impl ResponseCache {
+    /// Create a cache keeping responses for the given time by default,
+    /// holding at most 1000 responses and 16 MiB of bodies
+    pub fn new(ttl: Duration) -> ResponseCache {
+        ResponseCache {
+            ttl,
+            max_entries: 1000,
+            max_size: 16 * 1024 * 1024,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Set the maximum number of stored responses
+    pub fn with_max_entries(mut self, max_entries: usize) -> ResponseCache {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Set the maximum combined size of the stored bodies in bytes
+    pub fn with_max_size(mut self, max_size: usize) -> ResponseCache {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Remove all stored responses
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.size = 0;
+    }
+
+    /// Number of stored responses
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    /// Whether no responses are stored
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lookup(&self, key: &Key) -> Option<Response> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        let now = Instant::now();
+
+        let expired = match state.entries.get_mut(key) {
+            Some(entry) if entry.expires > now => {
+                entry.last_used = clock;
+                let mut response = Response::new(entry.status);
+                for (name, value) in &entry.headers {
+                    response = response.with_header(name, value);
+                }
+                let age = now.duration_since(entry.stored).as_secs();
+                return Some(response.with_header("Age", &age.to_string()).with_body(entry.body.clone()));
+            }
+            Some(_) => true,
+            None => false,
+        };
+        if expired {
+            state.remove(key);
+        }
+        None
+    }
+
+    fn store(&self, key: Key, response: &Response, ttl: Duration) {
+        let body = match response.body().as_bytes() {
+            Some(body) if body.len() <= self.max_size => body.to_vec(),
+            _ => return,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        state.remove(&key);
+
+        let now = Instant::now();
+        state.entries.retain(|_, entry| entry.expires > now);
+        state.size = state.entries.values().map(|e| e.body.len()).sum();
+        while state.entries.len() >= self.max_entries || state.size + body.len() > self.max_size {
+            let oldest = state.entries.iter().min_by_key(|(_, e)| e.last_used).map(|(k, _)| k.clone());
+            match oldest {
+                Some(oldest) => state.remove(&oldest),
+                None => break,
+            }
+        }
+        if self.max_entries == 0 {
+            return;
+        }
+
+        state.size += body.len();
+        let last_used = state.clock;
+        state.entries.insert(key, Entry {
+            status: response.status(),
+            headers: response.headers().to_vec(),
+            body,
+            stored: now,
+            expires: now + ttl,
+            last_used,
+        });
+    }
+
+    /// How long a response may be stored, or None if it may not be
+    fn lifetime(&self, response: &Response) -> Option<Duration> {
+        if response.status() != 200 || response.header("Set-Cookie").is_some() {
+            return None;
+        }
+        // Only the encoding is part of the key, so responses varying on
+        // anything else cannot be told apart
+        if let Some(vary) = response.header("Vary") {
+            if vary.split(',').any(|h| !h.trim().eq_ignore_ascii_case("Accept-Encoding")) {
+                return None;
+            }
+        }
+
+        let mut ttl = self.ttl;
+        let mut shared_ttl = None;
+        if let Some(cache_control) = response.header("Cache-Control") {
+            for directive in cache_control.split(',').map(|d| d.trim().to_ascii_lowercase()) {
+                match directive.split_once('=') {
+                    Some(("max-age", seconds)) => ttl = Duration::from_secs(seconds.trim_matches('"').parse().ok()?),
+                    Some(("s-maxage", seconds)) => {
+                        shared_ttl = Some(Duration::from_secs(seconds.trim_matches('"').parse().ok()?))
+                    }
+                    None if directive == "no-store" || directive == "no-cache" || directive == "private" => {
+                        return None
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let ttl = shared_ttl.unwrap_or(ttl);
+        if ttl.is_zero() {
+            None
+        } else {
+            Some(ttl)
+        }
+    }
+}
+
+impl State {
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.size -= entry.body.len();
+        }
+    }
+}
+
+impl Middleware for ResponseCache {
+    fn handle(&self, request: Request, next: &Next) -> Response {
+        let cacheable = (request.method() == "GET" || request.method() == "HEAD")
+            && request.header("Authorization").is_none()
+            && request.header("Cookie").is_none();
+        if !cacheable {
+            return next.run(request);
+        }
+
+        // HEAD requests are answered from the stored GET response
+        let key = Key {
+            method: String::from("GET"),
+            host: request.header("Host").unwrap_or_default().to_ascii_lowercase(),
+            target: String::from(request.target()),
+            encoding: request
+                .header("Accept-Encoding")
+                .map(|v| v.to_ascii_lowercase().replace(' ', ""))
+                .unwrap_or_default(),
+        };
+        let bypass = request
+            .header("Cache-Control")
+            .is_some_and(|v| v.split(',').any(|d| d.trim().eq_ignore_ascii_case("no-cache")));
+        if !bypass {
+            if let Some(response) = self.lookup(&key) {
+                return response;
+            }
+        }
+
+        let is_get = request.method() == "GET";
+        let response = next.run(request);
+        if is_get {
+            if let Some(ttl) = self.lifetime(&response) {
+                self.store(key, &response, ttl);
+            }
+        }
+        response
+    }
+}
@@ -0,0 +1,39 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Tells a handler that its response is no longer wanted, such as when
+/// it ran out of time, so it can stop working early
+///
+/// Every request carries one, see `Request::cancellation`. Handlers
+/// cannot be interrupted, so one doing long work should check the
+/// token between steps; clones share their state.
+///
+/// ```
+/// use server::cancel::CancellationToken;
+///
+/// let token = CancellationToken::new();
+/// let handler_side = token.clone();
+/// token.cancel();
+/// assert!(handler_side.is_cancelled());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// Create a token that has not been cancelled
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Cancel the work the token was handed to
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the work was cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
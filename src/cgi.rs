@@ -0,0 +1,336 @@
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::log;
+use crate::request::Request;
+use crate::response::Response;
+use crate::static_files::{error_response, forbidden, sanitize};
+use crate::trace_context;
+
+/// A handler running CGI scripts from a directory
+///
+/// A request for `<prefix>/script/extra` runs the executable `script`
+/// under the directory, with `/extra` as its PATH_INFO. The request
+/// body is piped to the script and its output is parsed into the
+/// response, as described in RFC 3875.
+pub struct Cgi {
+    root: PathBuf,
+    prefix: String,
+    timeout: Duration,
+    max_output: u64,
+}
+
+impl Cgi {
+    /// Create a handler for the scripts under a directory
+    ///
+    /// # Arguments
+    ///
+    /// root - The directory holding the scripts. Nothing outside of it is run.
+    /// prefix - The request path the directory is mounted at, e.g. /cgi-bin.
+    pub fn new<P: AsRef<Path>>(root: P, prefix: &str) -> Cgi {
+        Cgi {
+            root: root.as_ref().to_path_buf(),
+            prefix: String::from(prefix.trim_end_matches('/')),
+            timeout: Duration::from_secs(30),
+            max_output: 16 * 1024 * 1024,
+        }
+    }
+
+    /// Set how long a script may run before it is killed and answered
+    /// with 504, 30 seconds by default
+    ///
+    /// The time covers the output as well, which processes the script
+    /// started may keep open after it exited.
+    pub fn with_timeout(mut self, timeout: Duration) -> Cgi {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the most bytes of output a script may write, headers
+    /// included, 16 MiB by default; a script writing more is killed and
+    /// answered with 502
+    pub fn with_max_output(mut self, bytes: u64) -> Cgi {
+        self.max_output = bytes;
+        self
+    }
+
+    /// Run the script matching the request path
+    pub fn handle(&self, request: &Request) -> Response {
+        let rest = match request.path().strip_prefix(&self.prefix) {
+            Some(rest) if rest.starts_with('/') => rest,
+            _ => return Response::text(404, "Not Found"),
+        };
+        let (script, script_name, path_info) = match self.resolve(rest) {
+            Ok(found) => found,
+            Err(response) => return response,
+        };
+
+        match self.run(request, &script, &script_name, &path_info) {
+            Ok(response) => response,
+            Err(response) => response,
+        }
+    }
+
+    /// Find the script a path names, returning its location, its
+    /// SCRIPT_NAME and the remaining PATH_INFO
+    fn resolve(&self, path: &str) -> Result<(PathBuf, String, String), Response> {
+        let relative = sanitize(path)?;
+        let root = fs::canonicalize(&self.root).map_err(|e| error_response(&e))?;
+
+        let mut candidate = root.clone();
+        let mut script_name = self.prefix.clone();
+        let mut segments = path.split('/').filter(|s| !s.is_empty() && *s != ".");
+        for part in relative.iter() {
+            candidate.push(part);
+            script_name.push('/');
+            script_name.push_str(segments.next().unwrap_or(""));
+
+            let metadata = fs::metadata(&candidate).map_err(|e| error_response(&e))?;
+            if metadata.is_dir() {
+                continue;
+            }
+            let script = fs::canonicalize(&candidate).map_err(|e| error_response(&e))?;
+            if !script.starts_with(&root) || !is_executable(&metadata) {
+                return Err(forbidden());
+            }
+            let path_info: Vec<&str> = segments.collect();
+            let path_info = if path_info.is_empty() {
+                String::new()
+            } else {
+                format!("/{}", path_info.join("/"))
+            };
+            return Ok((script, script_name, path_info));
+        }
+        Err(forbidden())
+    }
+
+    fn run(&self, request: &Request, script: &Path, script_name: &str, path_info: &str) -> Result<Response, Response> {
+        let mut command = Command::new(script);
+        command
+            .env_clear()
+            .envs(environment(request, script, script_name, path_info))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        if let Some(dir) = script.parent() {
+            command.current_dir(dir);
+        }
+        // In a group of its own, so the processes it starts are killed
+        // along with it
+        #[cfg(unix)]
+        command.process_group(0);
+
+        let mut child = command.spawn().map_err(|e| {
+            log::error(&format!("Failed to start CGI script {}: {}", script.display(), e));
+            Response::text(502, "Bad Gateway")
+        })?;
+
+        // Feed stdin and drain stdout on their own threads, so a script
+        // filling one pipe while the other is full cannot deadlock us
+        let mut stdin = child.stdin.take().unwrap();
+        let body = request.body().to_vec();
+        let writer = thread::spawn(move || {
+            let _ = stdin.write_all(&body);
+        });
+        let stdout = child.stdout.take().unwrap();
+        let limit = self.max_output;
+        let (sender, output) = mpsc::channel();
+        thread::spawn(move || {
+            // A byte more than allowed tells output that is too long
+            let mut read = Vec::new();
+            let _ = sender.send(stdout.take(limit.saturating_add(1)).read_to_end(&mut read).map(|_| read));
+        });
+
+        // The output only ends once every process holding the pipe has
+        // closed it, so the deadline has to cover it as well
+        let deadline = Instant::now() + self.timeout;
+        let output = match output.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Ok(output)) if output.len() as u64 <= limit => output,
+            Ok(Ok(_)) => {
+                log::error(&format!("CGI script {} wrote more than {} bytes", script.display(), limit));
+                stop(&mut child);
+                return Err(Response::text(502, "Bad Gateway"));
+            }
+            Ok(Err(_)) | Err(RecvTimeoutError::Disconnected) => {
+                stop(&mut child);
+                return Err(Response::text(502, "Bad Gateway"));
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                log::error(&format!("CGI script {} timed out", script.display()));
+                stop(&mut child);
+                return Err(Response::text(504, "Gateway Timeout"));
+            }
+        };
+        loop {
+            match child.try_wait() {
+                Ok(Some(_)) => break,
+                Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                Ok(None) => {
+                    log::error(&format!("CGI script {} timed out", script.display()));
+                    stop(&mut child);
+                    return Err(Response::text(504, "Gateway Timeout"));
+                }
+                Err(e) => {
+                    log::error(&format!("Failed to wait for CGI script {}: {}", script.display(), e));
+                    return Err(Response::text(502, "Bad Gateway"));
+                }
+            }
+        }
+
+        let _ = writer.join();
+        parse_output(&output).ok_or_else(|| {
+            log::error(&format!("CGI script {} produced malformed output", script.display()));
+            Response::text(502, "Bad Gateway")
+        })
+    }
+}
+
+/// Kill a script together with the processes it started, and reap it
+fn stop(child: &mut Child) {
+    #[cfg(unix)]
+    sys::kill_group(child.id());
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// The meta-variables passed to a script
+pub(crate) fn environment(request: &Request, script: &Path, script_name: &str, path_info: &str) -> Vec<(String, String)> {
+    let mut env = vec![
+        (String::from("GATEWAY_INTERFACE"), String::from("CGI/1.1")),
+        (String::from("SERVER_PROTOCOL"), String::from(request.version())),
+        (String::from("SERVER_SOFTWARE"), format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))),
+        (String::from("REQUEST_METHOD"), String::from(request.method())),
+        (String::from("QUERY_STRING"), String::from(request.query().unwrap_or(""))),
+        (String::from("SCRIPT_NAME"), String::from(script_name)),
+        (String::from("SCRIPT_FILENAME"), script.display().to_string()),
+        (String::from("PATH_INFO"), String::from(path_info)),
+        (String::from("PATH"), String::from("/usr/local/bin:/usr/bin:/bin")),
+    ];
+    if !request.body().is_empty() {
+        env.push((String::from("CONTENT_LENGTH"), request.body().len().to_string()));
+    }
+    if let Some(content_type) = request.header("Content-Type") {
+        env.push((String::from("CONTENT_TYPE"), String::from(content_type)));
+    }
+    if let Some(addr) = request.peer_addr() {
+        env.push((String::from("REMOTE_ADDR"), addr.ip().to_string()));
+        env.push((String::from("REMOTE_PORT"), addr.port().to_string()));
+    }
+    if let Some(host) = request.header("Host") {
+        let (name, port) = match host.rfind(':') {
+            Some(i) if !host[i..].contains(']') => (&host[..i], &host[i + 1..]),
+            _ => (host, "80"),
+        };
+        env.push((String::from("SERVER_NAME"), String::from(name)));
+        env.push((String::from("SERVER_PORT"), String::from(port)));
+    }
+
+    // The script is called as part of the request's span, so it gets a
+    // span of its own below it rather than the caller's
+    let trace = trace_context::current().map(|trace| trace.child());
+    if let Some(trace) = &trace {
+        for (name, value) in trace.headers() {
+            env.push((format!("HTTP_{}", name.to_ascii_uppercase()), value));
+        }
+    }
+
+    for (name, value) in request.headers() {
+        let name = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        // Passed as CONTENT_* above; HTTP_PROXY would be mistaken for
+        // the proxy setting by many HTTP libraries (httpoxy)
+        if name == "HTTP_CONTENT_TYPE" || name == "HTTP_CONTENT_LENGTH" || name == "HTTP_PROXY" {
+            continue;
+        }
+        if trace.is_some() && (name == "HTTP_TRACEPARENT" || name == "HTTP_TRACESTATE") {
+            continue;
+        }
+        match env.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+            None => env.push((name, String::from(value))),
+        }
+    }
+    env
+}
+
+/// Parse the header section and body a script wrote to its output
+pub(crate) fn parse_output(output: &[u8]) -> Option<Response> {
+    let (head_end, body_start) = match output.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) if !output[..i].windows(2).any(|w| w == b"\n\n") => (i, i + 4),
+        _ => {
+            let i = output.windows(2).position(|w| w == b"\n\n")?;
+            (i, i + 2)
+        }
+    };
+    let head = std::str::from_utf8(&output[..head_end]).ok()?;
+
+    let mut status = None;
+    let mut headers = Vec::new();
+    for line in head.lines() {
+        let colon = line.find(':')?;
+        let name = line[..colon].trim();
+        let value = line[colon + 1..].trim();
+        if name.eq_ignore_ascii_case("Status") {
+            status = Some(value.split(' ').next()?.parse::<u16>().ok()?);
+        } else if !is_hop_by_hop(name) {
+            headers.push((name, value));
+        }
+    }
+    if headers.is_empty() && status.is_none() {
+        return None;
+    }
+
+    let redirect = headers.iter().any(|(n, _)| n.eq_ignore_ascii_case("Location"));
+    let status = status.unwrap_or(if redirect { 302 } else { 200 });
+    let mut response = Response::new(status);
+    for (name, value) in headers {
+        response = response.with_header(name, value);
+    }
+    Some(response.with_body(&output[body_start..]))
+}
+
+/// Whether the server frames the response itself, so a header from the
+/// script would conflict with it
+fn is_hop_by_hop(name: &str) -> bool {
+    ["Connection", "Content-Length", "Transfer-Encoding", "Keep-Alive"]
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(name))
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    true
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::os::raw::c_int;
+
+    const SIGKILL: c_int = 9;
+
+    extern "C" {
+        fn kill(pid: c_int, signal: c_int) -> c_int;
+    }
+
+    /// Kill every process in the group a script leads
+    pub fn kill_group(leader: u32) {
+        unsafe {
+            kill(-(leader as c_int), SIGKILL);
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::forwarded::Cidr;
+use crate::response::{reason_phrase, Response};
+
+/// How many requests of a single client are handled at once, so one
+/// client opening many connections cannot take every worker
+///
+/// Clients are told apart by `Request::client_ip`, which is the address
+/// `Config::trusted_proxies` forwards requests for when they come
+/// through a trusted proxy; without any configured, every client behind
+/// a balancer counts as the balancer. A request beyond the limit waits
+/// for one of its client's to finish for up to `max_wait`, holding its
+/// worker meanwhile, and is answered with 429 and a Retry-After header
+/// if none does, closing the connection.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use server::client_limit::ClientLimit;
+/// use server::config::Config;
+/// use server::forwarded::Cidr;
+/// use server::server::Server;
+///
+/// let client_limit = ClientLimit {
+///     max_in_flight: 8,
+///     max_wait: Duration::from_millis(100),
+///     exempt: vec![Cidr::parse("10.0.0.0/8").unwrap()],
+///     ..ClientLimit::default()
+/// };
+/// let server = Server::new(Config { client_limit: Some(client_limit), ..Config::default() }).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct ClientLimit {
+    /// Requests of a client handled at once, beyond which further ones
+    /// wait or are refused
+    pub max_in_flight: usize,
+    /// How long a request beyond the limit waits for its turn before it
+    /// is refused, zero refusing it right away
+    pub max_wait: Duration,
+    /// How long refused clients are asked to wait before trying again,
+    /// sent as a Retry-After header
+    pub retry_after: Duration,
+    /// The prefix length IPv6 addresses are counted together by, see
+    /// `Cidr::client`
+    pub ipv6_prefix: u8,
+    /// Clients without a limit, such as monitoring
+    pub exempt: Vec<Cidr>,
+}
+
+impl Default for ClientLimit {
+    fn default() -> ClientLimit {
+        ClientLimit {
+            max_in_flight: 16,
+            max_wait: Duration::ZERO,
+            retry_after: Duration::from_secs(1),
+            ipv6_prefix: 64,
+            exempt: Vec::new(),
+        }
+    }
+}
+
+/// Counts the requests each client has in flight
+pub(crate) struct ClientLimiter {
+    config: ClientLimit,
+    in_flight: Mutex<HashMap<Cidr, usize>>,
+    /// Signalled whenever a request finishes
+    finished: Condvar,
+}
+
+/// A request counted against the limit of its client until it is
+/// dropped
+pub(crate) struct ClientSlot {
+    limiter: Arc<ClientLimiter>,
+    client: Cidr,
+}
+
+impl ClientLimiter {
+    pub(crate) fn new(config: ClientLimit) -> ClientLimiter {
+        ClientLimiter { config, in_flight: Mutex::new(HashMap::new()), finished: Condvar::new() }
+    }
+
+    /// Count a request of a client, waiting for its turn if the client
+    /// is at its limit
+    ///
+    /// Returns None if the request is refused, and Some(None) for a
+    /// client without a limit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the mutex is in a poisoned state.
+    pub(crate) fn acquire(limiter: &Arc<ClientLimiter>, ip: IpAddr) -> Option<Option<ClientSlot>> {
+        if limiter.config.exempt.iter().any(|cidr| cidr.contains(ip)) {
+            return Some(None);
+        }
+        let client = Cidr::client(ip, limiter.config.ipv6_prefix);
+        let deadline = Instant::now() + limiter.config.max_wait;
+        let mut in_flight = limiter.in_flight.lock().unwrap();
+        loop {
+            let count = in_flight.entry(client).or_insert(0);
+            if *count < limiter.config.max_in_flight {
+                *count += 1;
+                return Some(Some(ClientSlot { limiter: Arc::clone(limiter), client }));
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return None;
+            }
+            in_flight = limiter.finished.wait_timeout(in_flight, left).unwrap().0;
+        }
+    }
+
+    /// The response sent to refused requests
+    pub(crate) fn response(&self) -> Response {
+        // Retry-After counts whole seconds, and zero would invite an
+        // immediate retry
+        let seconds = self.config.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        Response::text(429, reason_phrase(429))
+            .with_header("Retry-After", &seconds.to_string())
+            .with_header("Connection", "close")
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let mut in_flight = self.limiter.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.client);
+            }
+        }
+        drop(in_flight);
+        self.limiter.finished.notify_all();
+    }
+}
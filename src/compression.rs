@@ -0,0 +1,289 @@
+use std::io::{self, Read, Write};
+
+#[cfg(feature = "brotli")]
+use crate::brotli::BrotliEncoder;
+use crate::etag;
+use crate::gzip::GzipEncoder;
+use crate::middleware::{Middleware, Next};
+use crate::negotiate;
+use crate::request::Request;
+use crate::response::{Body, Response};
+
+/// How much of a streamed body is read at a time to be compressed
+const READ_SIZE: usize = 1 << 16;
+
+/// The media types compressed unless configured otherwise
+const DEFAULT_TYPES: [&str; 6] =
+    ["text/*", "application/json", "application/javascript", "application/xml", "application/wasm", "image/svg+xml"];
+
+/// Whether bodies of a media type, without its parameters, are
+/// compressed unless configured otherwise
+pub(crate) fn compresses_by_default(essence: &str) -> bool {
+    let essence = essence.to_ascii_lowercase();
+    DEFAULT_TYPES.iter().any(|media_type| type_matches(media_type, &essence))
+}
+
+/// Whether a lowercase media type is one of a configured list, where a
+/// trailing `*` matches anything
+fn type_matches(media_type: &str, essence: &str) -> bool {
+    match media_type.strip_suffix('*') {
+        Some(prefix) => essence.starts_with(prefix),
+        None => media_type == essence,
+    }
+}
+
+/// A middleware compressing response bodies with the coding the client
+/// prefers
+///
+/// gzip is always available, and with the `brotli` feature so is `br`,
+/// which wins ties in the q-values of Accept-Encoding. Only responses
+/// with a compressible Content-Type and a body of at least the minimum
+/// size are compressed; bodies of unknown length are compressed as they
+/// are streamed, and sent chunked. Responses that are partial, already
+/// have a Content-Encoding, ask for `Cache-Control: no-transform` or are
+/// written by a function are left alone, the last because they usually
+/// stream events whose delivery compression would hold up.
+///
+/// Compressible responses get `Vary: Accept-Encoding`, whether they are
+/// compressed or not. Compressed ones lose their Accept-Ranges and
+/// digest headers, which describe the unencoded body.
+///
+/// ```no_run
+/// use server::compression::Compression;
+/// use server::router::Router;
+///
+/// let mut router = Router::new();
+/// router.wrap(Compression::new().with_min_size(512).with_gzip_level(4));
+/// ```
+pub struct Compression {
+    min_size: u64,
+    content_types: Vec<String>,
+    gzip_level: u32,
+    #[cfg(feature = "brotli")]
+    brotli_quality: u32,
+}
+
+impl Compression {
+    /// Compress bodies of common text types of at least 1 KiB, with
+    /// gzip level 6 and Brotli quality 4
+    pub fn new() -> Compression {
+        Compression {
+            min_size: 1024,
+            content_types: DEFAULT_TYPES.iter().map(|media_type| String::from(*media_type)).collect(),
+            gzip_level: 6,
+            #[cfg(feature = "brotli")]
+            brotli_quality: 4,
+        }
+    }
+
+    /// Set the size below which bodies are sent as they are, since
+    /// compressing them saves less than it costs
+    pub fn with_min_size(mut self, bytes: u64) -> Compression {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Set the media types that are compressed
+    ///
+    /// # Arguments
+    ///
+    /// content_types - Media types compared with the Content-Type
+    /// without its parameters, or `type/*` for all subtypes of a type.
+    pub fn with_content_types(mut self, content_types: &[&str]) -> Compression {
+        self.content_types = content_types.iter().map(|media_type| media_type.to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// Set the gzip level, from 1, the fastest, to 9, the smallest
+    pub fn with_gzip_level(mut self, level: u32) -> Compression {
+        self.gzip_level = level.clamp(1, 9);
+        self
+    }
+
+    /// Set the Brotli quality, from 0, the fastest, to 11, the smallest
+    #[cfg(feature = "brotli")]
+    pub fn with_brotli_quality(mut self, quality: u32) -> Compression {
+        self.brotli_quality = quality.min(11);
+        self
+    }
+
+    /// Whether a response is one compression applies to
+    fn is_compressible(&self, response: &Response) -> bool {
+        let status = response.status();
+        if status < 200 || status == 204 || status == 206 || status == 304 {
+            return false;
+        }
+        if response.header("Content-Encoding").is_some() {
+            return false;
+        }
+        let no_transform = response.header("Cache-Control").is_some_and(|value| {
+            value.split(',').any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"))
+        });
+        if no_transform {
+            return false;
+        }
+        let essence = match response.content_type() {
+            Some(content_type) => content_type.to_ascii_lowercase(),
+            None => return false,
+        };
+        self.content_types.iter().any(|media_type| type_matches(media_type, &essence))
+    }
+
+    /// The encoder for a coding
+    fn encoder(&self, coding: &str) -> Encoder {
+        match coding {
+            #[cfg(feature = "brotli")]
+            "br" => Encoder::Brotli(BrotliEncoder::new(Vec::new(), self.brotli_quality)),
+            _ => Encoder::Gzip(GzipEncoder::new(Vec::new(), self.gzip_level)),
+        }
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::new()
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, request: Request, next: &Next) -> Response {
+        let accept_encoding = request.header("Accept-Encoding").map(String::from);
+        let mut response = next.run(request);
+        if !self.is_compressible(&response) {
+            return response;
+        }
+        add_vary(&mut response);
+
+        let body = response.body();
+        let small = body.len().is_some_and(|length| length < self.min_size);
+        if small || matches!(body, Body::Writer(_)) {
+            return response;
+        }
+        let available: &[&str] = if cfg!(feature = "brotli") { &["br", "gzip"] } else { &["gzip"] };
+        let coding = match accept_encoding.and_then(|accept| negotiate::preferred_encoding(&accept, available)) {
+            Some(coding) => coding,
+            None => return response,
+        };
+
+        let mut encoder = self.encoder(coding);
+        let mut response = match response.take_body() {
+            Body::Bytes(bytes) => {
+                let compressed = encoder.write_all(&bytes).and_then(|_| encoder.finish());
+                match compressed {
+                    Ok(compressed) => {
+                        response.set_header("Content-Length", &compressed.len().to_string());
+                        response.with_body(compressed)
+                    }
+                    Err(_) => unreachable!("writing to a Vec cannot fail"),
+                }
+            }
+            Body::Reader(reader, _) => compressed_stream(response, reader, encoder),
+            Body::File(file, length) => compressed_stream(response, Box::new(file.take(length)), encoder),
+            Body::Writer(_) => unreachable!(),
+        };
+        response.set_header("Content-Encoding", coding);
+        if let Some(tag) = response.header("ETag").map(etag::weaken) {
+            response.set_header("ETag", &tag);
+        }
+        for name in ["Accept-Ranges", "Content-Digest", "Repr-Digest"].iter() {
+            response.remove_header(name);
+        }
+        response
+    }
+}
+
+/// Replace the body of a response with one compressing a reader as it
+/// is sent
+fn compressed_stream(mut response: Response, source: Box<dyn Read + Send>, encoder: Encoder) -> Response {
+    response.remove_header("Content-Length");
+    response.with_stream(
+        Compressing { source, input: vec![0; READ_SIZE], encoder: Some(encoder), output: Vec::new(), position: 0 },
+        None,
+    )
+}
+
+/// Appends Accept-Encoding to the Vary header unless it already names it
+fn add_vary(response: &mut Response) {
+    let vary = response.header("Vary").unwrap_or("").trim();
+    if vary.split(',').map(str::trim).any(|name| name == "*" || name.eq_ignore_ascii_case("Accept-Encoding")) {
+        return;
+    }
+    let vary = match vary {
+        "" => String::from("Accept-Encoding"),
+        vary => format!("{}, Accept-Encoding", vary),
+    };
+    response.set_header("Vary", &vary);
+}
+
+/// An encoder for one of the supported codings, writing to memory
+enum Encoder {
+    Gzip(GzipEncoder<Vec<u8>>),
+    #[cfg(feature = "brotli")]
+    Brotli(BrotliEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write_all(data),
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(encoder) => encoder.write_all(data),
+        }
+    }
+
+    /// Take the output so far
+    fn take(&mut self) -> Vec<u8> {
+        match self {
+            Encoder::Gzip(encoder) => std::mem::take(encoder.get_mut()),
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(encoder) => std::mem::take(encoder.get_mut()),
+        }
+    }
+
+    /// End the stream, returning the rest of the output
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// Reads a body compressed as it is read from its source
+struct Compressing {
+    source: Box<dyn Read + Send>,
+    input: Vec<u8>,
+    /// None once the source is exhausted
+    encoder: Option<Encoder>,
+    output: Vec<u8>,
+    position: usize,
+}
+
+impl Read for Compressing {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.output.len() {
+            let encoder = match &mut self.encoder {
+                Some(encoder) => encoder,
+                None => return Ok(0),
+            };
+            self.position = 0;
+            let read = match self.source.read(&mut self.input) {
+                Ok(read) => read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.output = match read {
+                0 => self.encoder.take().map_or(Ok(Vec::new()), Encoder::finish)?,
+                read => {
+                    encoder.write_all(&self.input[..read])?;
+                    encoder.take()
+                }
+            };
+        }
+        let copied = buffer.len().min(self.output.len() - self.position);
+        buffer[..copied].copy_from_slice(&self.output[self.position..self.position + copied]);
+        self.position += copied;
+        Ok(copied)
+    }
+}
@@ -0,0 +1,213 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::admin::AdminAddress;
+use crate::autoscale::Autoscale;
+use crate::client_limit::ClientLimit;
+use crate::dump::DebugDumps;
+use crate::faults::Fault;
+use crate::forwarded::{Cidr, ForwardedHeader};
+use crate::headers::{HeaderCasing, HeaderRule};
+use crate::log::{Level, LogFormat, Rotation};
+use crate::maintenance::Maintenance;
+use crate::record::Recording;
+use crate::redirect::HttpsRedirect;
+use crate::request::Limits;
+use crate::rewrite::Rewrite;
+use crate::shed::Shedding;
+use crate::socket::SocketOptions;
+#[cfg(feature = "otel")]
+use crate::trace::OtelConfig;
+
+/// Settings used when creating a Server
+pub struct Config {
+    /// Address the server listens on
+    pub address: String,
+    /// Number of worker threads handling connections
+    pub workers: usize,
+    /// Grow and shrink the pool of `workers`, which it starts out with,
+    /// between bounds as its load changes, or None to keep its size
+    pub autoscale: Option<Autoscale>,
+    /// Number of threads accepting connections and handing them to the
+    /// workers, so connections are accepted while every worker is busy
+    pub acceptors: usize,
+    /// Additional named worker pools and their sizes, which routes can
+    /// be assigned to with `Route::on_pool`
+    pub pools: Vec<(String, usize)>,
+    /// Path at which Prometheus metrics are served, or None to disable them
+    pub metrics_path: Option<String>,
+    /// Value of the Server header added to responses that do not set
+    /// one, or None to leave it out
+    pub server_name: Option<String>,
+    /// Changes made to the headers of every response once the handler
+    /// and middleware are done with it and the Server header is added,
+    /// in order, such as removing `X-Powered-By` or adding a header
+    /// naming the deployment
+    pub response_headers: Vec<HeaderRule>,
+    /// Maximum sizes of request heads
+    pub limits: Limits,
+    /// Tuning of the listening socket and accepted connections
+    pub socket: SocketOptions,
+    /// Size in bytes of the buffers a connection reads requests into and
+    /// writes response heads and chunks from
+    pub buffer_size: usize,
+    /// Most buffers kept for reuse once their connection closed, two of
+    /// which a connection takes; 0 allocates them for every connection
+    pub buffer_pool_capacity: usize,
+    /// When to answer 503 right away instead of queueing work, or None
+    /// to always queue it
+    pub shedding: Option<Shedding>,
+    /// How many requests of a single client are handled at once, or
+    /// None for no limit
+    pub client_limit: Option<ClientLimit>,
+    /// How requests are answered in maintenance, and whether the server
+    /// starts out in it
+    pub maintenance: Maintenance,
+    /// Park connections waiting for a request with epoll instead of
+    /// blocking a worker thread on each of them, so many idle keep-alive
+    /// connections can be held with few workers; Linux only
+    pub event_driven: bool,
+    /// How long a kept-alive connection may wait for its next request
+    /// before it is closed
+    pub keep_alive_timeout: Duration,
+    /// Most requests served on one connection, the last of which is
+    /// answered with `Connection: close`, or None for no limit
+    pub max_requests_per_connection: Option<u64>,
+    /// How the names of response headers are written
+    pub header_casing: HeaderCasing,
+    /// Header through which clients tell how long they will wait for a
+    /// response, such as `X-Request-Timeout` or `grpc-timeout`, which
+    /// moves the request's deadline earlier, or None to ignore clients'
+    /// timeouts; see `Request::deadline`
+    pub deadline_header: Option<String>,
+    /// Expect every connection to start with a PROXY protocol header,
+    /// as sent by HAProxy and most load balancers, and use the client
+    /// address from it instead of the balancer's. Connections without
+    /// a valid header are closed.
+    pub proxy_protocol: bool,
+    /// Close connections whose PROXY protocol header does not report a
+    /// client certificate the balancer verified, for balancers that
+    /// terminate TLS requiring one; needs `proxy_protocol`
+    pub require_client_cert: bool,
+    /// Proxies whose forwarding header is believed when working out
+    /// `Request::client_ip`
+    pub trusted_proxies: Vec<Cidr>,
+    /// The header the trusted proxies report clients in; the other one
+    /// is never read, see `forwarded::ForwardedHeader`
+    pub forwarded_header: ForwardedHeader,
+    /// Hosts requests may be addressed to, such as `example.com`,
+    /// `example.com:8080` or `*.example.com`; requests for any other
+    /// host are answered with 421. Empty allows every host.
+    pub allowed_hosts: Vec<String>,
+    /// Rules rewriting, redirecting or aliasing request paths, applied
+    /// in order before requests are routed; see `rewrite::Rewrite`
+    pub rewrites: Vec<Rewrite>,
+    /// Also listen for plain HTTP, e.g. on port 80, and redirect every
+    /// request there to the https origin with 301, for when TLS is
+    /// terminated in front of the server; not passed on in hot restarts
+    pub https_redirect: Option<HttpsRedirect>,
+    /// Account to switch to once the address is bound, so the server
+    /// can be started as root to listen on a privileged port
+    pub user: Option<String>,
+    /// Group to switch to together with `user`, instead of the primary
+    /// group of the account
+    pub group: Option<String>,
+    /// Directory to confine the process to before switching to `user`,
+    /// such as the document root
+    pub chroot: Option<PathBuf>,
+    /// Fork into the background once the address is bound, detaching
+    /// from the terminal; Unix only
+    pub daemonize: bool,
+    /// File the id of the server process is written to, which is
+    /// refused if it names a server that is still running
+    pub pid_file: Option<PathBuf>,
+    /// File standard output and error are appended to instead of the
+    /// terminal; when daemonized without one, they are discarded
+    pub log_file: Option<PathBuf>,
+    /// When the log file is rotated
+    pub log_rotation: Rotation,
+    /// Format of access and error log lines
+    pub log_format: LogFormat,
+    /// Least severe level of the events logged, which can be changed
+    /// through the admin endpoint while the server runs
+    pub log_level: Level,
+    /// Log a warning breaking down where the time went for every request
+    /// that takes at least this long to answer, or None to not single
+    /// out slow requests
+    pub slow_request_threshold: Option<Duration>,
+    /// Write some requests out in full to a debug log of their own, or
+    /// None to dump none
+    pub debug_dumps: Option<DebugDumps>,
+    /// Faults injected into some requests on purpose, for testing the
+    /// resilience of clients, before any middleware runs; none by
+    /// default, and never meant for serving real traffic
+    pub faults: Vec<Fault>,
+    /// Record every request, and optionally its response, to a file
+    /// that `Replay` can feed back through a router, or None to record
+    /// nothing
+    pub recording: Option<Recording>,
+    /// On SIGUSR2, start the binary again with the same arguments,
+    /// passing it the listening socket, then stop accepting and let open
+    /// connections finish, so a new version can be deployed without
+    /// refusing connections; Unix only, and not together with `chroot`
+    pub hot_restart: bool,
+    /// Where a local-only admin endpoint listens, for checking the status
+    /// of the server, draining it or shutting it down, or None to go
+    /// without one; see `AdminAddress`
+    pub admin: Option<AdminAddress>,
+    /// Where to export a span for every request, or None to disable
+    /// tracing
+    #[cfg(feature = "otel")]
+    pub otel: Option<OtelConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            address: String::from("127.0.0.1:7878"),
+            workers: 4,
+            autoscale: None,
+            acceptors: 1,
+            pools: Vec::new(),
+            metrics_path: None,
+            server_name: Some(format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))),
+            response_headers: Vec::new(),
+            limits: Limits::default(),
+            socket: SocketOptions::default(),
+            buffer_size: 8 * 1024,
+            buffer_pool_capacity: 256,
+            shedding: None,
+            client_limit: None,
+            maintenance: Maintenance::default(),
+            event_driven: false,
+            keep_alive_timeout: Duration::from_secs(5),
+            max_requests_per_connection: None,
+            header_casing: HeaderCasing::Preserve,
+            deadline_header: None,
+            proxy_protocol: false,
+            require_client_cert: false,
+            trusted_proxies: Vec::new(),
+            forwarded_header: ForwardedHeader::XForwardedFor,
+            allowed_hosts: Vec::new(),
+            rewrites: Vec::new(),
+            https_redirect: None,
+            user: None,
+            group: None,
+            chroot: None,
+            daemonize: false,
+            pid_file: None,
+            log_file: None,
+            log_rotation: Rotation::default(),
+            log_format: LogFormat::Text,
+            log_level: Level::Info,
+            slow_request_threshold: None,
+            debug_dumps: None,
+            faults: Vec::new(),
+            recording: None,
+            hot_restart: false,
+            admin: None,
+            #[cfg(feature = "otel")]
+            otel: None,
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use crate::auth::{constant_time_eq, random_secret};
+use crate::log;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+use crate::sha256;
+use crate::template::{escape_html, Context};
+use crate::trace_context;
+use crate::uri;
+
+/// A middleware protecting against cross-site request forgery with
+/// double-submitted cookies
+///
+/// A client without a valid token cookie gets one with the response.
+/// Requests with methods that change state, all but GET, HEAD, OPTIONS
+/// and TRACE, are rejected with 403 unless they submit the token from
+/// the cookie again, in a header as scripts do or in a field of a
+/// URL-encoded form. Another site can make a browser send the cookie,
+/// but cannot read it to submit it as well. Tokens are signed, so only
+/// those the server issued are accepted; they are signed with a random
+/// key unless one is given with `with_secret`, so they only last as
+/// long as the process.
+///
+/// Tokens are not bound to a session, so this does not cover a sibling
+/// subdomain that can set cookies for the whole domain: it can fetch a
+/// token from the server itself, plant it as the cookie and submit it
+/// along. Where subdomains are not trusted, name the cookie with the
+/// `__Host-` prefix and mark it Secure, as browsers refuse such cookies
+/// from other hosts.
+///
+/// Handlers find the token among the request's extensions, to put it
+/// into their forms. A token in a multipart form is not read, so for
+/// uploads scripts have to send the header.
+///
+/// ```
+/// use server::csrf::{Csrf, CsrfToken};
+/// use server::request::Request;
+/// use server::response::Response;
+/// use server::router::Router;
+///
+/// let mut router = Router::new();
+/// router.wrap(Csrf::new());
+/// router.get("/comment", |request: Request| {
+///     let field = request.extensions().get::<CsrfToken>().map(CsrfToken::form_field).unwrap_or_default();
+///     Response::html(200, format!("<form method=\"post\">{}<textarea name=\"text\"></textarea></form>", field))
+/// });
+/// ```
+pub struct Csrf {
+    cookie: String,
+    header: String,
+    field: String,
+    secure: bool,
+    exempt: Vec<String>,
+    secret: [u8; 32],
+}
+
+impl Csrf {
+    /// Create the middleware, with the token in the `csrf_token` cookie
+    /// and form field and the `X-CSRF-Token` header
+    pub fn new() -> Csrf {
+        Csrf {
+            cookie: String::from("csrf_token"),
+            header: String::from("X-CSRF-Token"),
+            field: String::from("csrf_token"),
+            secure: false,
+            exempt: Vec::new(),
+            secret: random_secret(),
+        }
+    }
+
+    /// Set the name of the cookie holding the token
+    pub fn with_cookie_name(mut self, name: &str) -> Csrf {
+        self.cookie = String::from(name);
+        self
+    }
+
+    /// Set the name of the header scripts submit the token in
+    pub fn with_header_name(mut self, name: &str) -> Csrf {
+        self.header = String::from(name);
+        self
+    }
+
+    /// Set the name of the form field the token is submitted in
+    pub fn with_field_name(mut self, name: &str) -> Csrf {
+        self.field = String::from(name);
+        self
+    }
+
+    /// Mark the cookie Secure, so browsers only send it over HTTPS
+    pub fn with_secure_cookie(mut self) -> Csrf {
+        self.secure = true;
+        self
+    }
+
+    /// Sign tokens with a key derived from a secret, so they stay valid
+    /// across restarts and on every server sharing the secret
+    pub fn with_secret(mut self, secret: &[u8]) -> Csrf {
+        self.secret = sha256::digest(secret);
+        self
+    }
+
+    /// Let requests to a path and everything below it through without
+    /// a token, such as webhooks called by other services
+    pub fn with_exempt_path(mut self, prefix: &str) -> Csrf {
+        self.exempt.push(String::from(prefix.trim_end_matches('/')));
+        self
+    }
+
+    /// Issue a token: a random id followed by its MAC
+    fn issue(&self) -> String {
+        let id = sha256::hex(&[trace_context::random_id(), trace_context::random_id()].concat());
+        format!("{}{}", id, self.mac(&id))
+    }
+
+    fn mac(&self, id: &str) -> String {
+        sha256::hex(&sha256::hmac(&self.secret, id.as_bytes())[..16])
+    }
+
+    /// Whether a token was issued by this middleware, or one with the
+    /// same secret
+    fn is_valid(&self, token: &str) -> bool {
+        if token.len() != 64 || !token.is_ascii() {
+            return false;
+        }
+        let (id, mac) = token.split_at(32);
+        constant_time_eq(mac.as_bytes(), self.mac(id).as_bytes())
+    }
+
+    fn is_exempt(&self, path: &str) -> bool {
+        self.exempt.iter().any(|prefix| {
+            path == prefix || (path.starts_with(prefix.as_str()) && path.as_bytes().get(prefix.len()) == Some(&b'/'))
+        })
+    }
+
+    /// The token a request submits, from the header or else from the
+    /// fields of a URL-encoded form
+    fn submitted(&self, request: &Request) -> Option<String> {
+        if let Some(token) = request.header(&self.header) {
+            return Some(String::from(token.trim()));
+        }
+        let form = request.content_type().is_some_and(|value| value.eq_ignore_ascii_case("application/x-www-form-urlencoded"));
+        if !form {
+            return None;
+        }
+        let body = std::str::from_utf8(request.body()).ok()?;
+        uri::parse_query(body).into_iter().find(|(name, _)| *name == self.field).map(|(_, value)| value)
+    }
+
+    fn set_cookie(&self, token: &str) -> String {
+        // Not HttpOnly, as scripts have to read the token to submit it
+        let mut cookie = format!("{}={}; Path=/; SameSite=Lax", self.cookie, token);
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        cookie
+    }
+}
+
+impl Default for Csrf {
+    fn default() -> Csrf {
+        Csrf::new()
+    }
+}
+
+impl Middleware for Csrf {
+    fn handle(&self, mut request: Request, next: &Next) -> Response {
+        let existing = cookie(&request, &self.cookie).filter(|token| self.is_valid(token)).map(String::from);
+        let changes_state = !matches!(request.method(), "GET" | "HEAD" | "OPTIONS" | "TRACE");
+        if changes_state && !self.is_exempt(request.path()) {
+            let valid = match (&existing, self.submitted(&request)) {
+                (Some(expected), Some(submitted)) => constant_time_eq(expected.as_bytes(), submitted.as_bytes()),
+                _ => false,
+            };
+            if !valid {
+                log::warn(&format!("Rejected {} {} without a valid CSRF token", request.method(), request.path()));
+                return Response::text(403, "Forbidden");
+            }
+        }
+
+        let issued = existing.is_none();
+        let token = existing.unwrap_or_else(|| self.issue());
+        request.extensions_mut().insert(CsrfToken { value: token.clone(), field: self.field.clone() });
+        let response = next.run(request);
+        if issued {
+            response.with_header("Set-Cookie", &self.set_cookie(&token))
+        } else {
+            response
+        }
+    }
+}
+
+/// The CSRF token of a request, which forms changing state have to
+/// submit, see `Csrf`
+#[derive(Clone, Debug)]
+pub struct CsrfToken {
+    value: String,
+    field: String,
+}
+
+impl CsrfToken {
+    /// The token itself, for scripts to send in the header
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// A hidden form field submitting the token
+    pub fn form_field(&self) -> String {
+        format!("<input type=\"hidden\" name=\"{}\" value=\"{}\">", escape_html(&self.field), escape_html(&self.value))
+    }
+
+    /// Make the token available to a template, as `{{ csrf_token }}`
+    /// and as the hidden form field `{{& csrf_field }}`
+    pub fn insert_into(&self, context: &mut Context) {
+        context.insert("csrf_token", &self.value);
+        context.insert("csrf_field", self.form_field());
+    }
+}
+
+/// The value of a cookie a request sends
+fn cookie<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .filter(|(header, _)| header.eq_ignore_ascii_case("Cookie"))
+        .flat_map(|(_, value)| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| value.trim_matches('"'))
+}
@@ -0,0 +1,188 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crate::log;
+use crate::restart;
+
+/// Detach the process from the terminal and continue in the background
+///
+/// The process forks twice, so it is neither a session leader nor the
+/// child of the shell that started it, and the original process exits.
+/// Standard input is redirected from /dev/null, as are standard output
+/// and error while they go to a terminal; use `log::log_to_file` first
+/// to keep them. The working directory is kept, as templates and static
+/// files are found relative to it.
+///
+/// Only the calling thread survives a fork, so this must be called
+/// before any threads are started.
+///
+/// # Errors
+///
+/// Returns an error if /dev/null cannot be opened or forking fails.
+#[cfg(unix)]
+pub fn daemonize() -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // Open everything before forking, so errors reach the terminal
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    io::stdout().flush()?;
+
+    sys::fork_and_exit_parent()?;
+    sys::setsid()?;
+    sys::fork_and_exit_parent()?;
+
+    sys::dup2(null.as_raw_fd(), 0)?;
+    for fd in 1..=2 {
+        if sys::is_terminal(fd) {
+            sys::dup2(null.as_raw_fd(), fd)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn daemonize() -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Daemonizing is not supported on this platform."))
+}
+
+/// A file holding the id of the running server process, removed again
+/// when dropped
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Claim a PID file for the current process
+    ///
+    /// A file left behind by a process that is no longer running is
+    /// replaced, as is the file of the process replaced by a hot restart.
+    ///
+    /// # Arguments
+    ///
+    /// path - Where to write the file, e.g. /var/run/server.pid.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `AlreadyExists` if the file names a
+    /// process that is still running, or the error of writing it.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<PidFile> {
+        let path = path.as_ref().to_path_buf();
+        if let Ok(contents) = fs::read_to_string(&path) {
+            if let Ok(pid) = contents.trim().parse::<u32>() {
+                let ours = pid == std::process::id() || Some(pid) == restart::restarted_from();
+                if !ours && is_running(pid) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("Server already running with PID {} according to {}.", pid, path.display()),
+                    ));
+                }
+            }
+            if restart::restarted_from().is_none() {
+                log::info(&format!("Replacing stale PID file {}", path.display()));
+            }
+        }
+
+        let pid_file = PidFile { path };
+        pid_file.update()?;
+        Ok(pid_file)
+    }
+
+    /// Write the id of the current process, e.g. after `daemonize`
+    /// replaced the process that created the file
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of writing the file.
+    pub fn update(&self) -> io::Result<()> {
+        let mut file = File::create(&self.path)?;
+        writeln!(file, "{}", std::process::id())
+    }
+
+    /// Where the file is written
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Only remove the file while it still names this process
+        let ours = fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|contents| contents.trim().parse::<u32>().ok())
+            == Some(std::process::id());
+        if ours {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Whether a process with the given id exists
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    sys::is_running(pid)
+}
+
+/// Without a way to check, a leftover file is assumed to be stale
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::io;
+    use std::os::raw::c_int;
+
+    const EPERM: i32 = 1;
+
+    extern "C" {
+        fn fork() -> c_int;
+        #[link_name = "setsid"]
+        fn c_setsid() -> c_int;
+        #[link_name = "dup2"]
+        fn c_dup2(old: c_int, new: c_int) -> c_int;
+        fn kill(pid: c_int, signal: c_int) -> c_int;
+        fn isatty(fd: c_int) -> c_int;
+        fn _exit(status: c_int) -> !;
+    }
+
+    fn check(result: c_int) -> io::Result<c_int> {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result)
+        }
+    }
+
+    /// Fork, ending the parent without running any destructors
+    pub fn fork_and_exit_parent() -> io::Result<()> {
+        if check(unsafe { fork() })? > 0 {
+            unsafe { _exit(0) }
+        }
+        Ok(())
+    }
+
+    pub fn setsid() -> io::Result<()> {
+        check(unsafe { c_setsid() }).map(|_| ())
+    }
+
+    pub fn dup2(old: c_int, new: c_int) -> io::Result<()> {
+        check(unsafe { c_dup2(old, new) }).map(|_| ())
+    }
+
+    pub fn is_terminal(fd: c_int) -> bool {
+        unsafe { isatty(fd) == 1 }
+    }
+
+    /// Probe with signal 0, which checks for the process without
+    /// disturbing it; a process of another user still counts
+    pub fn is_running(pid: u32) -> bool {
+        if unsafe { kill(pid as c_int, 0) } == 0 {
+            return true;
+        }
+        io::Error::last_os_error().raw_os_error() == Some(EPERM)
+    }
+}
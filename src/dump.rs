@@ -0,0 +1,221 @@
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::date;
+use crate::request::Request;
+use crate::trace_context;
+
+/// Which requests to write out in full to a debug log, for tracking
+/// down problems only some clients or requests run into
+///
+/// A request is dumped if it matches any of the filters, and otherwise
+/// with the probability of the sample rate. Each dump holds the request
+/// head, the start of its body, the response head as sent and where the
+/// time went. The values of the redacted headers are left out.
+///
+/// ```no_run
+/// use server::config::Config;
+/// use server::dump::{DebugDumps, DumpFilter};
+/// use server::server::Server;
+///
+/// let dumps = DebugDumps {
+///     filters: vec![DumpFilter::Header(String::from("User-Agent"), Some(String::from("BrokenClient/1.0")))],
+///     ..DebugDumps::new("dumps.log")
+/// };
+/// let server = Server::new(Config { debug_dumps: Some(dumps), ..Config::default() }).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct DebugDumps {
+    /// File the dumps are appended to, which is created if it does not
+    /// exist and never rotated
+    pub path: PathBuf,
+    /// Share of all requests dumped, from 0 for none to 1 for every one
+    pub sample_rate: f64,
+    /// Requests dumped whatever the sample rate
+    pub filters: Vec<DumpFilter>,
+    /// Most bytes of each request body written out
+    pub max_body: usize,
+    /// Headers of requests and responses whose values are not written
+    /// out, ignoring case
+    pub redact: Vec<String>,
+}
+
+impl DebugDumps {
+    /// Dump no requests but those matching filters yet to be added, to
+    /// a file, with up to 1 KiB of their bodies and credentials and
+    /// cookies redacted
+    pub fn new<P: Into<PathBuf>>(path: P) -> DebugDumps {
+        DebugDumps {
+            path: path.into(),
+            sample_rate: 0.0,
+            filters: Vec::new(),
+            max_body: 1024,
+            redact: ["Authorization", "Proxy-Authorization", "Cookie", "Set-Cookie"]
+                .iter()
+                .map(|&name| String::from(name))
+                .collect(),
+        }
+    }
+}
+
+/// Requests that get dumped whatever the sample rate
+#[derive(Clone, Debug)]
+pub enum DumpFilter {
+    /// Requests whose path starts with this
+    PathPrefix(String),
+    /// Requests with a header of this name, ignoring case, and this
+    /// exact value if one is given
+    Header(String, Option<String>),
+    /// Requests from this client, as `Request::client_ip` reports it
+    ClientIp(IpAddr),
+}
+
+impl DumpFilter {
+    fn matches(&self, request: &Request) -> bool {
+        match self {
+            DumpFilter::PathPrefix(prefix) => request.path().starts_with(prefix.as_str()),
+            DumpFilter::Header(name, value) => request
+                .header(name)
+                .is_some_and(|actual| value.as_ref().is_none_or(|value| actual.trim() == value)),
+            DumpFilter::ClientIp(ip) => request.client_ip() == Some(*ip),
+        }
+    }
+}
+
+/// What a dump holds of a request, taken before the handler gets it
+pub(crate) struct DumpedRequest {
+    head: String,
+    body: String,
+}
+
+/// Where the time answering a dumped request went
+pub(crate) struct DumpTiming {
+    pub(crate) total: Duration,
+    pub(crate) read: Duration,
+    pub(crate) queued: Duration,
+    pub(crate) handler: Duration,
+    pub(crate) write: Duration,
+}
+
+/// Writes the dumps of a running server
+pub(crate) struct Dumper {
+    config: DebugDumps,
+    file: Mutex<File>,
+}
+
+impl Dumper {
+    /// Open the dump file
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the sample rate is not between 0 and 1 or the
+    /// file cannot be opened.
+    pub(crate) fn open(config: DebugDumps) -> io::Result<Dumper> {
+        if !(0.0..=1.0).contains(&config.sample_rate) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The dump sample rate has to be between 0 and 1."));
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        Ok(Dumper { config, file: Mutex::new(file) })
+    }
+
+    /// Take what gets dumped of a request, if it is to be dumped
+    pub(crate) fn capture(&self, request: &Request, streamed: bool) -> Option<DumpedRequest> {
+        let sampled = self.config.sample_rate > 0.0 && trace_context::random_fraction() < self.config.sample_rate;
+        if !sampled && !self.config.filters.iter().any(|filter| filter.matches(request)) {
+            return None;
+        }
+        let mut head = format!("{} {} {}\r\n", request.method(), request.target(), request.version());
+        for (name, value) in request.headers() {
+            let _ = write!(head, "{}: {}\r\n", name, self.shown(name, value));
+        }
+        let body = if streamed {
+            String::from("(body streamed to the handler)\n")
+        } else if request.body().is_empty() {
+            String::new()
+        } else {
+            let shown = &request.body()[..request.body().len().min(self.config.max_body)];
+            format!("{}\n({} of {} bytes)\n", printable(shown), shown.len(), request.body().len())
+        };
+        Some(DumpedRequest { head, body })
+    }
+
+    /// Append the dump of an answered request
+    ///
+    /// # Arguments
+    ///
+    /// request_id - The id the request is logged with.
+    /// client_ip - The client the request came from.
+    /// request - What was taken of the request by `capture`.
+    /// response_head - The head of the response as it was sent.
+    /// timing - Where the time answering the request went.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the file mutex is in a poisoned state.
+    pub(crate) fn write(
+        &self,
+        request_id: &str,
+        client_ip: Option<IpAddr>,
+        request: &DumpedRequest,
+        response_head: &[u8],
+        timing: &DumpTiming,
+    ) {
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let mut dump = format!(
+            "=== {} {} from {}\n{}\r\n{}--- {:.1}ms (read {:.1}ms, queued {:.1}ms, handler {:.1}ms, write {:.1}ms)\n",
+            request_id,
+            date::rfc3339(SystemTime::now()),
+            client_ip.map(|ip| ip.to_string()).unwrap_or_else(|| String::from("-")),
+            request.head,
+            request.body,
+            ms(timing.total),
+            ms(timing.read),
+            ms(timing.queued),
+            ms(timing.handler),
+            ms(timing.write)
+        );
+        for line in String::from_utf8_lossy(response_head).split_inclusive("\r\n") {
+            match line.split_once(':') {
+                Some((name, value)) => {
+                    let _ = write!(dump, "{}: {}\r\n", name, self.shown(name, value.trim()));
+                }
+                None => dump.push_str(line),
+            }
+        }
+        dump.push('\n');
+        // One write per dump, so dumps of concurrent requests do not mix
+        if let Err(e) = self.file.lock().unwrap().write_all(dump.as_bytes()) {
+            let _ = writeln!(io::stderr(), "Failed to write debug dump to {}: {}", self.config.path.display(), e);
+        }
+    }
+
+    /// The value of a header as dumped
+    fn shown<'a>(&self, name: &str, value: &'a str) -> &'a str {
+        if self.config.redact.iter().any(|redacted| redacted.eq_ignore_ascii_case(name)) {
+            "[redacted]"
+        } else {
+            value
+        }
+    }
+}
+
+/// Bytes as text, escaping those that are not printable ASCII but for
+/// line breaks and tabs
+fn printable(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b'\n' | b'\t' | b' '..=b'~' => text.push(byte as char),
+            b'\r' => text.push_str("\\r"),
+            _ => {
+                let _ = write!(text, "\\x{:02x}", byte);
+            }
+        }
+    }
+    text
+}
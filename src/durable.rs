@@ -0,0 +1,304 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::json::{self, FromJson, ToJson, Value};
+use crate::log;
+use crate::PoolHandle;
+
+/// How often a job may fail before it is given up on, by default
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+type Handler = Box<dyn Fn(&Value) -> Result<(), String> + Send + Sync>;
+
+/// The kinds of jobs a durable queue can run, by name
+///
+/// Closures cannot be written to disk, so durable jobs are queued as the
+/// name of a handler registered here and a JSON payload it is run with.
+pub struct JobRegistry {
+    handlers: HashMap<String, Handler>,
+    max_attempts: u32,
+}
+
+impl JobRegistry {
+    /// Create a registry without any jobs, giving up on a job after it
+    /// failed five times
+    pub fn new() -> JobRegistry {
+        JobRegistry { handlers: HashMap::new(), max_attempts: DEFAULT_MAX_ATTEMPTS }
+    }
+
+    /// Register a kind of job, replacing one of the same name
+    ///
+    /// # Arguments
+    ///
+    /// name - What jobs of the kind are queued as. It has to stay the same
+    /// across releases for jobs queued by one to run in the next.
+    /// handler - Runs a job with its payload, or fails with a message to
+    /// have the job tried again.
+    pub fn with_job<T, F>(mut self, name: &str, handler: F) -> JobRegistry
+    where
+        T: FromJson,
+        F: Fn(T) -> Result<(), String> + Send + Sync + 'static,
+    {
+        let handler = move |payload: &Value| {
+            let payload = T::from_json(payload).map_err(|e| format!("Invalid payload: {}", e))?;
+            handler(payload)
+        };
+        self.handlers.insert(String::from(name), Box::new(handler));
+        self
+    }
+
+    /// Set how often a job may fail, including by panicking, before it
+    /// is dropped from the queue
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> JobRegistry {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+}
+
+impl Default for JobRegistry {
+    fn default() -> JobRegistry {
+        JobRegistry::new()
+    }
+}
+
+/// A queue of background jobs kept in a file, so jobs that did not
+/// finish before a crash or restart run when the queue is opened again
+///
+/// The file is a journal: every job is written to it before it is handed
+/// to the pool, and marked done once its handler returned. A job may
+/// therefore run more than once if the process stops while it runs, so
+/// handlers should tolerate that. A job that fails stays in the journal
+/// and is tried again the next time the queue is opened, until it failed
+/// as often as the registry allows. The journal is compacted to the
+/// unfinished jobs whenever it is opened.
+///
+/// Clones share the same journal.
+#[derive(Clone)]
+pub struct DurableQueue {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    registry: JobRegistry,
+    pool: PoolHandle,
+    journal: Mutex<Journal>,
+}
+
+struct Journal {
+    file: File,
+    next_id: u64,
+}
+
+/// A job read back from the journal that has not finished yet
+struct Pending {
+    job: String,
+    payload: Value,
+    failures: u32,
+}
+
+impl DurableQueue {
+    /// Open the journal at a path, creating it if it does not exist, and
+    /// hand the unfinished jobs in it to a pool
+    ///
+    /// Jobs with a name the registry does not know are kept in the
+    /// journal without running, so a release that dropped a kind of job
+    /// by mistake does not lose them.
+    ///
+    /// # Arguments
+    ///
+    /// path - The journal file.
+    /// registry - The kinds of jobs the queue runs.
+    /// pool - The pool the jobs run in.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the journal cannot be read or rewritten.
+    pub fn open<P: AsRef<Path>>(path: P, registry: JobRegistry, pool: PoolHandle) -> Result<DurableQueue, DurableQueueError> {
+        let path = path.as_ref();
+        let (mut pending, next_id) = read_journal(path)?;
+
+        pending.retain(|id, job| {
+            if job.failures < registry.max_attempts {
+                return true;
+            }
+            log::error(&format!("Giving up on durable job {} ({}) after {} failures", id, job.job, job.failures));
+            false
+        });
+
+        // The compacted journal replaces the old one in one step, so a
+        // crash while writing it loses nothing
+        let mut compacted = path.as_os_str().to_owned();
+        compacted.push(".tmp");
+        let mut file = File::create(&compacted)?;
+        for (id, job) in &pending {
+            writeln!(file, "{}", enqueue_record(*id, &job.job, &job.payload, job.failures))?;
+        }
+        file.sync_all()?;
+        fs::rename(&compacted, path)?;
+        let file = OpenOptions::new().append(true).open(path)?;
+
+        let queue = DurableQueue {
+            shared: Arc::new(Shared { registry, pool, journal: Mutex::new(Journal { file, next_id }) }),
+        };
+        for (id, job) in pending {
+            if queue.shared.registry.handlers.contains_key(&job.job) {
+                queue.submit(id, job.job, job.payload);
+            } else {
+                log::warn(&format!("Keeping durable job {} for unregistered job {}", id, job.job));
+            }
+        }
+        Ok(queue)
+    }
+
+    /// Queue a job, returning its id once it has been written to disk
+    ///
+    /// # Arguments
+    ///
+    /// job - The name the kind of job was registered with.
+    /// payload - What the job is run with.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no job of that name is registered or the
+    /// journal cannot be written.
+    pub fn enqueue<T: ToJson>(&self, job: &str, payload: &T) -> Result<u64, DurableQueueError> {
+        if !self.shared.registry.handlers.contains_key(job) {
+            return Err(DurableQueueError::new(&format!("No job named {} is registered.", job)));
+        }
+        let payload = payload.to_json();
+        let id = {
+            let mut journal = self.shared.journal.lock().unwrap();
+            let id = journal.next_id;
+            writeln!(journal.file, "{}", enqueue_record(id, job, &payload, 0))?;
+            journal.file.sync_data()?;
+            journal.next_id += 1;
+            id
+        };
+        self.submit(id, String::from(job), payload);
+        Ok(id)
+    }
+
+    fn submit(&self, id: u64, job: String, payload: Value) {
+        let shared = Arc::clone(&self.shared);
+        self.shared.pool.execute(move || {
+            let handler = &shared.registry.handlers[&job];
+            let op = match panic::catch_unwind(AssertUnwindSafe(|| handler(&payload))) {
+                Ok(Ok(())) => "done",
+                Ok(Err(e)) => {
+                    log::warn(&format!("Durable job {} ({}) failed: {}", id, job, e));
+                    "failed"
+                }
+                Err(_) => {
+                    log::error(&format!("Durable job {} ({}) panicked", id, job));
+                    "failed"
+                }
+            };
+            // Losing the record only means the job runs once more, so it
+            // is not synced like the job itself
+            let record = Value::object().with("op", op).with("id", id);
+            let mut journal = shared.journal.lock().unwrap();
+            if let Err(e) = writeln!(journal.file, "{}", record) {
+                log::error(&format!("Failed to record durable job {} as {}: {}", id, op, e));
+            }
+        });
+    }
+}
+
+fn enqueue_record(id: u64, job: &str, payload: &Value, failures: u32) -> Value {
+    Value::object()
+        .with("op", "enqueue")
+        .with("id", id)
+        .with("job", job)
+        .with("payload", payload)
+        .with("failures", failures)
+}
+
+/// Read the unfinished jobs from a journal, along with the id the next
+/// job gets
+fn read_journal(path: &Path) -> Result<(BTreeMap<u64, Pending>, u64), DurableQueueError> {
+    let mut pending = BTreeMap::new();
+    let mut next_id = 1;
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((pending, next_id)),
+        Err(e) => return Err(e.into()),
+    };
+
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // A line cut short by a crash while it was written is skipped
+        let record = match Value::parse(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                log::warn(&format!("Skipping line {} of {}: {}", number + 1, path.display(), e));
+                continue;
+            }
+        };
+        let (op, id) = match (json::field::<String>(&record, "op"), json::field::<u64>(&record, "id")) {
+            (Ok(op), Ok(id)) => (op, id),
+            _ => {
+                log::warn(&format!("Skipping line {} of {}: not a job record", number + 1, path.display()));
+                continue;
+            }
+        };
+        next_id = next_id.max(id + 1);
+        match op.as_str() {
+            "enqueue" => {
+                let job = json::field::<String>(&record, "job");
+                let failures = json::field::<Option<u32>>(&record, "failures");
+                if let (Ok(job), Ok(failures)) = (job, failures) {
+                    let payload = record.get("payload").cloned().unwrap_or(Value::Null);
+                    pending.insert(id, Pending { job, payload, failures: failures.unwrap_or(0) });
+                }
+            }
+            "done" => {
+                pending.remove(&id);
+            }
+            "failed" => {
+                if let Some(job) = pending.get_mut(&id) {
+                    job.failures += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok((pending, next_id))
+}
+
+#[derive(Debug)]
+pub struct DurableQueueError {
+    details: String,
+}
+
+impl DurableQueueError {
+    fn new(details: &str) -> DurableQueueError {
+        DurableQueueError{details: String::from(details)}
+    }
+}
+
+impl fmt::Display for DurableQueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for DurableQueueError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+impl From<io::Error> for DurableQueueError {
+    fn from(err: io::Error) -> DurableQueueError {
+        DurableQueueError{details: err.to_string()}
+    }
+}
@@ -0,0 +1,111 @@
+use std::fmt::Write as _;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+
+#[cfg(not(unix))]
+use crate::poll::RawFd;
+use crate::response::reason_phrase;
+
+/// Sends informational responses ahead of the final one, notably 103
+/// Early Hints that let browsers start loading what a page links to
+/// while the page is still being generated
+///
+/// Every request carries one, see `Request::early_hints`. Sending does
+/// nothing for HTTP/1.0 clients, which do not expect interim responses,
+/// for requests pipelined behind others, whose interim responses would
+/// arrive before the responses to the requests ahead of them, for
+/// connections that are not sockets, and once the final response is
+/// being written.
+///
+/// ```
+/// use server::request::Request;
+/// use server::response::Response;
+///
+/// fn page(request: Request) -> Response {
+///     request.early_hints().send(&[("Link", "</site.css>; rel=preload; as=style")]);
+///     Response::html(200, "<link rel=\"stylesheet\" href=\"/site.css\">")
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct EarlyHints {
+    /// The socket of the connection, until the final response is written
+    socket: Arc<Mutex<Option<RawFd>>>,
+}
+
+impl EarlyHints {
+    /// Send interim responses over a socket until `close` is called,
+    /// which has to happen before the socket can be closed
+    pub(crate) fn new(socket: RawFd) -> EarlyHints {
+        EarlyHints { socket: Arc::new(Mutex::new(Some(socket))) }
+    }
+
+    /// Stop sending, waiting for a response being sent to be done
+    ///
+    /// # Panics
+    ///
+    /// Panics if the socket mutex is in a poisoned state.
+    pub(crate) fn close(&self) {
+        self.socket.lock().unwrap().take();
+    }
+
+    /// Send a 103 Early Hints response with the given headers, usually
+    /// Link headers, returning whether it was sent
+    pub fn send(&self, headers: &[(&str, &str)]) -> bool {
+        self.send_status(103, headers)
+    }
+
+    /// Send an informational response, returning whether it was sent
+    ///
+    /// # Arguments
+    ///
+    /// status - A status from 100 to 199 but for 101, which a handler
+    /// switches protocols with through `Response::with_upgrade`.
+    /// headers - The names and values of the headers to send.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the status is not informational or is 101, or if the
+    /// socket mutex is in a poisoned state.
+    pub fn send_status(&self, status: u16, headers: &[(&str, &str)]) -> bool {
+        assert!((100..200).contains(&status) && status != 101, "{} is not an interim status", status);
+        let socket = self.socket.lock().unwrap();
+        let fd = match *socket {
+            Some(fd) => fd,
+            None => return false,
+        };
+        let mut head = format!("HTTP/1.1 {} {}\r\n", status, reason_phrase(status));
+        for (name, value) in headers {
+            let _ = write!(head, "{}: {}\r\n", name, value);
+        }
+        head.push_str("\r\n");
+        sys::write_all(fd, head.as_bytes()).is_ok()
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::io::{self, Write};
+    use std::mem::ManuallyDrop;
+    use std::net::TcpStream;
+    use std::os::unix::io::{FromRawFd, RawFd};
+
+    /// Write to a socket that stays open while the caller holds its
+    /// hints' lock
+    pub fn write_all(fd: RawFd, bytes: &[u8]) -> io::Result<()> {
+        // The connection owns the socket, so it is not closed here
+        let mut stream = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) });
+        stream.write_all(bytes)
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::io;
+
+    use crate::poll::RawFd;
+
+    pub fn write_all(_fd: RawFd, _bytes: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Interim responses are not supported on this platform."))
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "brotli")]
+use crate::brotli::BrotliEncoder;
+use crate::compression;
+use crate::etag;
+use crate::gzip::GzipEncoder;
+use crate::headers;
+use crate::mime::MimeTypes;
+use crate::negotiate;
+use crate::range;
+use crate::request::Request;
+use crate::response::Response;
+
+/// Content codings of precompressed variants with the extension of their
+/// files, in order of preference
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+/// A file compiled into the binary, see `Bundle`
+#[derive(Debug)]
+pub struct Asset {
+    /// The request path it is served at, e.g. `/css/site.css`
+    pub path: &'static str,
+    pub contents: &'static [u8],
+    /// Its strong entity tag, computed when the bundle was generated
+    pub etag: &'static str,
+    /// Its precompressed variants with their content coding, in order of
+    /// preference
+    pub variants: &'static [(&'static str, &'static [u8])],
+}
+
+/// The assets of a directory, gathered by a build script so they are
+/// compiled into the binary and deployments need no document root
+///
+/// Every file below the directory becomes an asset served at its path
+/// relative to the directory, except for hidden files. A `.br` or `.gz`
+/// file next to another one is embedded as its precompressed variant,
+/// as `StaticFiles` would serve it, and with compression enabled the
+/// files of compressible types without one get a gzip variant, and a
+/// Brotli one with the `brotli` feature, when it is smaller. The source
+/// written has to be included with `embedded_assets!`, and Cargo reruns
+/// the build script whenever something in the directory changes.
+///
+/// In `build.rs`, with this crate among the build dependencies:
+///
+/// ```no_run
+/// use server::embed::Bundle;
+///
+/// Bundle::new("assets").with_compression(true).write("assets.rs").unwrap();
+/// ```
+///
+/// In the crate itself:
+///
+/// ```ignore
+/// use server::embed::{Asset, EmbeddedFiles};
+/// use server::request::Request;
+/// use server::router::Router;
+///
+/// static ASSETS: &[Asset] = server::embedded_assets!("assets.rs");
+///
+/// let files = EmbeddedFiles::new(ASSETS);
+/// let mut router = Router::new();
+/// router.fallback(move |request: Request| files.handle(&request));
+/// ```
+pub struct Bundle {
+    root: PathBuf,
+    compression: bool,
+}
+
+impl Bundle {
+    /// Gather the files under a directory
+    ///
+    /// # Arguments
+    ///
+    /// root - The directory, relative to the crate being built.
+    pub fn new<P: AsRef<Path>>(root: P) -> Bundle {
+        Bundle { root: root.as_ref().to_path_buf(), compression: false }
+    }
+
+    /// Compress the files of compressible types that lack precompressed
+    /// variants, which makes the build slower and the binary larger in
+    /// exchange for smaller responses
+    pub fn with_compression(mut self, compression: bool) -> Bundle {
+        self.compression = compression;
+        self
+    }
+
+    /// Write the source of the assets to a file in the `OUT_DIR` of the
+    /// build, along with the variants compressed for them
+    ///
+    /// # Arguments
+    ///
+    /// name - The name of the file, which `embedded_assets!` is given.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if it is not run by a build script, the
+    /// directory cannot be read, a path in it is not valid UTF-8 or the
+    /// source cannot be written.
+    pub fn write(&self, name: &str) -> io::Result<()> {
+        let out_dir = env::var_os("OUT_DIR")
+            .map(PathBuf::from)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Bundles are written by build scripts, which have OUT_DIR set."))?;
+        let root = fs::canonicalize(&self.root)?;
+        let mut files = Vec::new();
+        walk(&root, Path::new(""), &mut files)?;
+
+        let compressed_dir = out_dir.join(format!("{}.compressed", name));
+        let mut source = String::from("&[\n");
+        for file in &files {
+            let relative = utf8(file)?;
+            let variant_of = PRECOMPRESSED
+                .iter()
+                .find_map(|(_, extension)| relative.strip_suffix(&format!(".{}", extension)))
+                .filter(|original| files.iter().any(|other| other.to_str() == Some(*original)));
+            if variant_of.is_some() {
+                continue;
+            }
+
+            let path = root.join(file);
+            let contents = fs::read(&path)?;
+            let mut variants: Vec<(&str, PathBuf)> = Vec::new();
+            for (encoding, extension) in PRECOMPRESSED {
+                let mut precompressed = path.as_os_str().to_owned();
+                precompressed.push(".");
+                precompressed.push(extension);
+                let precompressed = PathBuf::from(precompressed);
+                if precompressed.is_file() {
+                    variants.push((encoding, precompressed));
+                    continue;
+                }
+                if !self.compression || !is_compressible(file) {
+                    continue;
+                }
+                if let Some(compressed) = compress(encoding, &contents)?.filter(|compressed| compressed.len() < contents.len()) {
+                    let target = compressed_dir.join(format!("{}.{}", relative, extension));
+                    fs::create_dir_all(target.parent().unwrap_or(&compressed_dir))?;
+                    fs::write(&target, compressed)?;
+                    variants.push((encoding, target));
+                }
+            }
+
+            let _ = write!(
+                source,
+                "    Asset {{\n        path: {:?},\n        contents: include_bytes!({:?}),\n        etag: {:?},\n        variants: &[",
+                format!("/{}", relative),
+                utf8(&path)?,
+                etag::strong(&contents)
+            );
+            for (encoding, variant) in &variants {
+                let _ = write!(source, "({:?}, include_bytes!({:?})), ", encoding, utf8(variant)?);
+            }
+            source.push_str("],\n    },\n");
+        }
+        source.push_str("]\n");
+        fs::write(out_dir.join(name), source)?;
+        println!("cargo:rerun-if-changed={}", root.display());
+        Ok(())
+    }
+}
+
+/// Include the assets a build script wrote with `Bundle::write`, as a
+/// `&'static [Asset]`
+///
+/// # Arguments
+///
+/// The name the bundle was written with.
+#[macro_export]
+macro_rules! embedded_assets {
+    ($name:literal) => {{
+        use $crate::embed::Asset;
+        include!(concat!(env!("OUT_DIR"), "/", $name))
+    }};
+}
+
+/// A handler serving assets compiled into the binary the way
+/// `StaticFiles` serves files, see `Bundle`
+///
+/// Responses carry the ETag computed when the bundle was generated, so
+/// clients revalidating get a 304, and range requests are answered with
+/// the requested parts. A precompressed variant the client accepts is
+/// sent instead of the asset, with a tag of its own.
+pub struct EmbeddedFiles {
+    assets: HashMap<&'static str, &'static Asset>,
+    index_files: Vec<String>,
+    mime_types: MimeTypes,
+}
+
+impl EmbeddedFiles {
+    /// Create a handler serving a bundle
+    pub fn new(assets: &'static [Asset]) -> EmbeddedFiles {
+        EmbeddedFiles {
+            assets: assets.iter().map(|asset| (asset.path, asset)).collect(),
+            index_files: vec![String::from("index.html")],
+            mime_types: MimeTypes::new(),
+        }
+    }
+
+    /// Set the assets served for a request for a directory, tried in
+    /// order, by default only `index.html`
+    pub fn with_index_files(mut self, names: &[&str]) -> EmbeddedFiles {
+        self.index_files = names.iter().map(|name| String::from(*name)).collect();
+        self
+    }
+
+    /// Set the Content-Type assets are served with by their extension
+    pub fn with_mime_types(mut self, mime_types: MimeTypes) -> EmbeddedFiles {
+        self.mime_types = mime_types;
+        self
+    }
+
+    /// Serve the asset matching the request path
+    pub fn handle(&self, request: &Request) -> Response {
+        if request.method() != "GET" && request.method() != "HEAD" {
+            return Response::text(405, "Method Not Allowed").with_header("Allow", "GET, HEAD");
+        }
+        let asset = match self.find(request.path()) {
+            Some(asset) => asset,
+            None => return Response::text(404, "Not Found"),
+        };
+        let content_type = self.mime_types.get(Path::new(asset.path));
+        if asset.variants.is_empty() {
+            return range::bytes_response(request, asset.contents, &content_type).with_header("ETag", asset.etag);
+        }
+
+        let encodings: Vec<&str> = asset.variants.iter().map(|(encoding, _)| *encoding).collect();
+        let chosen = request
+            .header("Accept-Encoding")
+            .and_then(|accept| negotiate::preferred_encoding(accept, &encodings));
+        let response = match asset.variants.iter().find(|(encoding, _)| Some(*encoding) == chosen) {
+            Some((encoding, contents)) => {
+                let tag = format!("{}-{}\"", asset.etag.trim_end_matches('"'), encoding);
+                match range::bytes_response(request, *contents, &content_type) {
+                    response if matches!(response.status(), 200 | 206) => {
+                        response.with_header("Content-Encoding", encoding).with_header("ETag", &tag)
+                    }
+                    response => response,
+                }
+            }
+            None => range::bytes_response(request, asset.contents, &content_type).with_header("ETag", asset.etag),
+        };
+        response.with_header("Vary", "Accept-Encoding")
+    }
+
+    /// The asset at a path, or the first index file of the directory
+    /// there
+    fn find(&self, path: &str) -> Option<&'static Asset> {
+        if let Some(asset) = self.assets.get(path) {
+            return Some(asset);
+        }
+        let dir = path.trim_end_matches('/');
+        self.index_files.iter().find_map(|index| self.assets.get(format!("{}/{}", dir, index).as_str()).copied())
+    }
+}
+
+/// Collect the files below a directory, leaving out hidden ones, as
+/// paths relative to the root in a stable order
+fn walk(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(root.join(dir))?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = dir.join(&name);
+        if fs::metadata(entry.path())?.is_dir() {
+            walk(root, &path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// A path as a string with forward slashes, for the generated source
+fn utf8(path: &Path) -> io::Result<String> {
+    path.to_str()
+        .map(|path| path.replace('\\', "/"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{} is not valid UTF-8.", path.display())))
+}
+
+/// Whether compression helps a file, going by its extension
+fn is_compressible(file: &Path) -> bool {
+    compression::compresses_by_default(headers::media_type(&MimeTypes::new().get(file)))
+}
+
+/// Compress contents with a coding, or None if the coding is not
+/// available
+fn compress(encoding: &str, contents: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    match encoding {
+        "gzip" => {
+            let mut encoder = GzipEncoder::new(Vec::new(), 9);
+            encoder.write_all(contents)?;
+            encoder.finish().map(Some)
+        }
+        #[cfg(feature = "brotli")]
+        "br" => {
+            let mut encoder = BrotliEncoder::new(Vec::new(), 11);
+            encoder.write_all(contents)?;
+            encoder.finish().map(Some)
+        }
+        _ => Ok(None),
+    }
+}
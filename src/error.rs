@@ -0,0 +1,123 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::extract::IntoResponse;
+use crate::log;
+use crate::response::{reason_phrase, Response};
+
+type Source = Box<dyn Error + Send + Sync + 'static>;
+
+/// An error a handler can return instead of building the error response
+/// itself
+///
+/// The client is answered with the status and the message as plain text,
+/// while the source error is only logged, together with the id of the
+/// request. Any error converts into a 500 with `?`, so a handler returning
+/// `Result<Response, HttpError>` can pass failures on without matching
+/// them:
+///
+/// ```no_run
+/// use std::fs;
+/// use server::error::HttpError;
+/// use server::request::Request;
+/// use server::response::Response;
+/// use server::router::Router;
+///
+/// fn motd(request: Request) -> Result<Response, HttpError> {
+///     let name = request.param("name").ok_or_else(|| HttpError::bad_request("Missing name"))?;
+///     if name.contains('/') {
+///         return Err(HttpError::not_found("No such message"));
+///     }
+///     Ok(Response::text(200, fs::read(format!("motd/{}", name))?))
+/// }
+///
+/// let mut router = Router::new();
+/// router.get("/motd/:name", motd);
+/// ```
+///
+/// It does not implement `Error` itself, as that would conflict with the
+/// conversion from every error.
+#[derive(Debug)]
+pub struct HttpError {
+    status: u16,
+    message: String,
+    source: Option<Source>,
+}
+
+impl HttpError {
+    /// Create an error answered with a status and a message
+    pub fn new(status: u16, message: &str) -> HttpError {
+        HttpError { status, message: String::from(message), source: None }
+    }
+
+    /// A 400 error
+    pub fn bad_request(message: &str) -> HttpError {
+        HttpError::new(400, message)
+    }
+
+    /// A 403 error
+    pub fn forbidden(message: &str) -> HttpError {
+        HttpError::new(403, message)
+    }
+
+    /// A 404 error
+    pub fn not_found(message: &str) -> HttpError {
+        HttpError::new(404, message)
+    }
+
+    /// A 500 error caused by another error, answered without its details
+    pub fn internal<E: Into<Source>>(source: E) -> HttpError {
+        HttpError::new(500, reason_phrase(500)).with_source(source)
+    }
+
+    /// Set the error that caused this one, which is logged but not sent
+    /// to the client
+    pub fn with_source<E: Into<Source>>(mut self, source: E) -> HttpError {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The error that caused this one, if there is one
+    pub fn source(&self) -> Option<&(dyn Error + Send + Sync + 'static)> {
+        self.source.as_deref()
+    }
+}
+
+impl<E: Error + Send + Sync + 'static> From<E> for HttpError {
+    fn from(err: E) -> HttpError {
+        HttpError::internal(err)
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.status, self.message)?;
+        if let Some(source) = &self.source {
+            write!(f, ": {}", source)?;
+        }
+        Ok(())
+    }
+}
+
+/// Server errors are logged as errors and client errors as information,
+/// as only the former point at a problem with the server
+impl IntoResponse for HttpError {
+    fn into_response(self) -> Response {
+        let request = log::request_id().unwrap_or_else(|| String::from("-"));
+        let message = format!("Request {} failed with {}", request, self);
+        if self.status >= 500 {
+            log::error(&message);
+        } else {
+            log::info(&message);
+        }
+        Response::text(self.status, self.message)
+    }
+}
@@ -0,0 +1,120 @@
+use std::fmt::Display;
+
+use crate::response::Response;
+use crate::sha256;
+
+/// Headers a 304 keeps of the response it replaces; the others describe
+/// the body it does not have
+const KEPT: [&str; 8] =
+    ["Cache-Control", "Content-Location", "Date", "ETag", "Expires", "Last-Modified", "Set-Cookie", "Vary"];
+
+/// A strong entity tag for a body, from a hash of its bytes, changing
+/// whenever a single byte of it does
+///
+/// ```
+/// use server::etag;
+///
+/// assert_eq!(etag::strong(b"hello"), "\"2cf24dba5fb0a30e26e83b2ac5b9e29e\"");
+/// ```
+pub fn strong(content: &[u8]) -> String {
+    format!("\"{}\"", sha256::hex(&sha256::digest(content)[..16]))
+}
+
+/// A weak entity tag for a body, from a hash of its bytes, for bodies
+/// that are equivalent without being the same byte for byte, such as
+/// ones holding the time they were rendered
+pub fn weak(content: &[u8]) -> String {
+    format!("W/{}", strong(content))
+}
+
+/// A weak entity tag for a revision of a resource, such as a version
+/// column or the time it was last updated, so handlers need not render
+/// a body to tell whether the client's copy is still current
+///
+/// Revisions that cannot be written in a tag as they are, with quotes,
+/// spaces or control characters in them, are hashed.
+///
+/// ```
+/// use server::etag;
+///
+/// assert_eq!(etag::revision(42), "W/\"42\"");
+/// assert!(etag::revision("a b").starts_with("W/\""));
+/// ```
+pub fn revision<T: Display>(revision: T) -> String {
+    let revision = revision.to_string();
+    if !revision.is_empty() && revision.bytes().all(|byte| byte == 0x21 || (0x23..=0x7e).contains(&byte)) {
+        format!("W/\"{}\"", revision)
+    } else {
+        format!("W/\"{}\"", sha256::hex(&sha256::digest(revision.as_bytes())[..16]))
+    }
+}
+
+/// The weak form of an entity tag, which it already is if it starts
+/// with `W/`
+///
+/// Whatever changes the bytes of a body, such as compressing it, has to
+/// weaken its tag, as the strong tag of the original no longer
+/// identifies them.
+pub fn weaken(tag: &str) -> String {
+    if tag.starts_with("W/") {
+        String::from(tag)
+    } else {
+        format!("W/{}", tag)
+    }
+}
+
+/// Whether an If-None-Match header names an entity tag, comparing them
+/// weakly as If-None-Match is to, so `W/"1"` matches `"1"`
+///
+/// ```
+/// use server::etag;
+///
+/// assert!(etag::matches("\"a\", W/\"b\"", "\"b\""));
+/// assert!(etag::matches("*", "\"c\""));
+/// assert!(!etag::matches("\"a\"", "\"c\""));
+/// ```
+pub fn matches(if_none_match: &str, tag: &str) -> bool {
+    let opaque = |tag: &str| String::from(tag.trim().trim_start_matches("W/"));
+    let tag = opaque(tag);
+    if_none_match.trim() == "*" || split_tags(if_none_match).any(|candidate| opaque(candidate) == tag)
+}
+
+/// The entity tags of a list, which may hold commas within their quotes
+fn split_tags(list: &str) -> impl Iterator<Item = &str> {
+    let mut rest = list;
+    std::iter::from_fn(move || {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        if rest.is_empty() {
+            return None;
+        }
+        let open = rest.find('"')?;
+        let close = rest[open + 1..].find('"').map_or(rest.len(), |close| open + close + 2);
+        let (tag, remaining) = rest.split_at(close);
+        rest = remaining;
+        Some(tag)
+    })
+}
+
+/// Replace a response with a 304 if the request it answers is a GET or
+/// HEAD for a representation the client holds, going by the response's
+/// ETag and the request's If-None-Match
+///
+/// # Arguments
+///
+/// method - The method of the request.
+/// if_none_match - The If-None-Match header of the request.
+/// response - The response the handler produced.
+pub(crate) fn not_modified(method: &str, if_none_match: Option<&str>, response: Response) -> Response {
+    let fresh = match (if_none_match, response.header("ETag")) {
+        (Some(if_none_match), Some(tag)) => matches(if_none_match, tag),
+        _ => false,
+    };
+    if !fresh || response.status() != 200 || (method != "GET" && method != "HEAD") {
+        return response;
+    }
+    response
+        .headers()
+        .iter()
+        .filter(|(name, _)| KEPT.iter().any(|kept| kept.eq_ignore_ascii_case(name)))
+        .fold(Response::new(304), |not_modified, (name, value)| not_modified.with_header(name, value))
+}
@@ -0,0 +1,71 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A map holding at most one value of each type
+///
+/// Requests carry one, so middleware can hand structured data such as
+/// the authenticated user to the handlers after it:
+///
+/// ```
+/// use server::request::Request;
+///
+/// struct AuthUser {
+///     name: String,
+/// }
+///
+/// let mut request = Request::new("GET", "/");
+/// request.extensions_mut().insert(AuthUser { name: String::from("ada") });
+/// assert_eq!(request.extensions().get::<AuthUser>().map(|user| user.name.as_str()), Some("ada"));
+/// ```
+#[derive(Default)]
+pub struct Extensions {
+    values: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    /// Create an empty map
+    pub fn new() -> Extensions {
+        Extensions::default()
+    }
+
+    /// Add a value, returning the one of the same type it replaced
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.values
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    /// The value of a type, if there is one
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref())
+    }
+
+    /// The value of a type for changing it in place, if there is one
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.values.get_mut(&TypeId::of::<T>()).and_then(|value| value.downcast_mut())
+    }
+
+    /// Take out the value of a type, if there is one
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.values
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Extensions {{ {} values }}", self.values.len())
+    }
+}
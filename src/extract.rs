@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::json::{FromJson, ToJson, Value};
+use crate::request::Request;
+use crate::response::Response;
+
+/// A value that can be taken from a request before its handler runs
+///
+/// If extraction fails, the returned response is sent instead of
+/// calling the handler.
+pub trait FromRequest: Sized {
+    /// # Errors
+    ///
+    /// Returns the response to send if the request does not provide
+    /// the value.
+    fn from_request(request: &Request) -> Result<Self, Response>;
+}
+
+/// A value a handler can return
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        Response::text(200, self)
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self) -> Response {
+        Response::text(200, self)
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for (u16, T) {
+    fn into_response(self) -> Response {
+        self.1.into_response().with_status(self.0)
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(error) => error.into_response(),
+        }
+    }
+}
+
+/// A single path parameter captured by a `:name` segment
+pub trait FromParam: Sized {
+    fn from_param(value: &str) -> Option<Self>;
+}
+
+/// The path parameters of a route, converted as a whole
+pub trait FromParams: Sized {
+    fn from_params(params: &[(String, String)]) -> Option<Self>;
+}
+
+macro_rules! from_param {
+    ($($t:ty),*) => {
+        $(
+            impl FromParam for $t {
+                fn from_param(value: &str) -> Option<$t> {
+                    value.parse().ok()
+                }
+            }
+
+            impl FromParams for $t {
+                fn from_params(params: &[(String, String)]) -> Option<$t> {
+                    match params {
+                        [(_, value)] => <$t as FromParam>::from_param(value),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+from_param!(String, bool, char, u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, f32, f64);
+
+impl<A: FromParam, B: FromParam> FromParams for (A, B) {
+    fn from_params(params: &[(String, String)]) -> Option<(A, B)> {
+        match params {
+            [(_, a), (_, b)] => Some((A::from_param(a)?, B::from_param(b)?)),
+            _ => None,
+        }
+    }
+}
+
+impl<A: FromParam, B: FromParam, C: FromParam> FromParams for (A, B, C) {
+    fn from_params(params: &[(String, String)]) -> Option<(A, B, C)> {
+        match params {
+            [(_, a), (_, b), (_, c)] => Some((A::from_param(a)?, B::from_param(b)?, C::from_param(c)?)),
+            _ => None,
+        }
+    }
+}
+
+impl FromParams for HashMap<String, String> {
+    fn from_params(params: &[(String, String)]) -> Option<HashMap<String, String>> {
+        Some(params.iter().cloned().collect())
+    }
+}
+
+/// The parameters captured from the path by the matched route
+///
+/// A single parameter is extracted as a value, several of them as a
+/// tuple in pattern order. Responds with 404 if a parameter cannot be
+/// converted, as the path then names no existing resource.
+pub struct Path<T>(pub T);
+
+impl<T: FromParams> FromRequest for Path<T> {
+    fn from_request(request: &Request) -> Result<Path<T>, Response> {
+        T::from_params(request.params())
+            .map(Path)
+            .ok_or_else(|| Response::text(404, "Not Found"))
+    }
+}
+
+/// A type built from the decoded pairs of a query string
+pub trait FromQuery: Sized {
+    /// # Errors
+    ///
+    /// Returns a description of the problem if a required pair is
+    /// missing or malformed.
+    fn from_query(pairs: &[(String, String)]) -> Result<Self, String>;
+}
+
+impl FromQuery for HashMap<String, String> {
+    fn from_query(pairs: &[(String, String)]) -> Result<HashMap<String, String>, String> {
+        Ok(pairs.iter().cloned().collect())
+    }
+}
+
+impl FromQuery for Vec<(String, String)> {
+    fn from_query(pairs: &[(String, String)]) -> Result<Vec<(String, String)>, String> {
+        Ok(pairs.to_vec())
+    }
+}
+
+/// The query string of the request, responding with 400 if it cannot
+/// be converted
+pub struct Query<T>(pub T);
+
+impl<T: FromQuery> FromRequest for Query<T> {
+    fn from_request(request: &Request) -> Result<Query<T>, Response> {
+        T::from_query(&request.query_pairs())
+            .map(Query)
+            .map_err(|e| Response::text(400, e))
+    }
+}
+
+/// A JSON request body, or a JSON response when returned from a handler
+///
+/// Extraction responds with 415 unless the request is declared as
+/// `application/json`, with 400 if the body is not valid JSON and with
+/// 422 if it does not have the shape of `T`.
+pub struct Json<T>(pub T);
+
+impl<T: FromJson> FromRequest for Json<T> {
+    fn from_request(request: &Request) -> Result<Json<T>, Response> {
+        let is_json = request.content_type().is_some_and(|v| v.eq_ignore_ascii_case("application/json"));
+        if !is_json {
+            return Err(Response::text(415, "Expected an application/json body"));
+        }
+
+        let body = std::str::from_utf8(request.body()).map_err(|_| Response::text(400, "Body is not valid UTF-8"))?;
+        let value = Value::parse(body).map_err(|e| Response::text(400, e.to_string()))?;
+        T::from_json(&value)
+            .map(Json)
+            .map_err(|e| Response::text(422, e.to_string()))
+    }
+}
+
+impl<T: ToJson> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        Response::new(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(self.0.to_json().to_string())
+    }
+}
+
+/// The headers of the request
+pub struct Headers(pub Vec<(String, String)>);
+
+impl Headers {
+    /// Get the value of a header, ignoring the case of its name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl FromRequest for Headers {
+    fn from_request(request: &Request) -> Result<Headers, Response> {
+        Ok(Headers(request.headers().map(|(name, value)| (String::from(name), String::from(value))).collect()))
+    }
+}
+
+/// The address of the client that sent the request
+pub struct ClientAddr(pub SocketAddr);
+
+impl FromRequest for ClientAddr {
+    fn from_request(request: &Request) -> Result<ClientAddr, Response> {
+        request
+            .peer_addr()
+            .map(ClientAddr)
+            .ok_or_else(|| Response::text(500, "Client address unavailable"))
+    }
+}
+
+/// The IP address of the client behind any trusted proxies
+pub struct ClientIp(pub IpAddr);
+
+impl FromRequest for ClientIp {
+    fn from_request(request: &Request) -> Result<ClientIp, Response> {
+        request
+            .client_ip()
+            .map(ClientIp)
+            .ok_or_else(|| Response::text(500, "Client address unavailable"))
+    }
+}
+
+/// A value a middleware attached to the request's extensions
+///
+/// A missing value means the middleware providing it does not run for
+/// the route, which is answered with 500.
+pub struct Extension<T>(pub T);
+
+impl<T: Clone + Send + Sync + 'static> FromRequest for Extension<T> {
+    fn from_request(request: &Request) -> Result<Extension<T>, Response> {
+        request
+            .extensions()
+            .get::<T>()
+            .cloned()
+            .map(Extension)
+            .ok_or_else(|| Response::text(500, "Request extension unavailable"))
+    }
+}
+
+/// Extracts a value if the request provides it, instead of rejecting
+/// the request
+impl<T: FromRequest> FromRequest for Option<T> {
+    fn from_request(request: &Request) -> Result<Option<T>, Response> {
+        Ok(T::from_request(request).ok())
+    }
+}
+
+/// A function whose arguments are all extractors
+///
+/// Implemented for functions of up to four arguments. Use `handler`
+/// to register one with a router.
+pub trait Handler<Args>: Send + Sync + 'static {
+    fn call(&self, request: Request) -> Response;
+}
+
+impl<F, R> Handler<()> for F
+where
+    F: Fn() -> R + Send + Sync + 'static,
+    R: IntoResponse,
+{
+    fn call(&self, _request: Request) -> Response {
+        self().into_response()
+    }
+}
+
+macro_rules! handler {
+    ($($arg:ident),+) => {
+        impl<F, R, $($arg),+> Handler<($($arg,)+)> for F
+        where
+            F: Fn($($arg),+) -> R + Send + Sync + 'static,
+            R: IntoResponse,
+            $($arg: FromRequest),+
+        {
+            #[allow(non_snake_case)]
+            fn call(&self, request: Request) -> Response {
+                $(
+                    let $arg = match $arg::from_request(&request) {
+                        Ok(value) => value,
+                        Err(response) => return response,
+                    };
+                )+
+                self($($arg),+).into_response()
+            }
+        }
+    };
+}
+
+handler!(A);
+handler!(A, B);
+handler!(A, B, C);
+handler!(A, B, C, D);
+
+/// Turn a function taking extractors into a handler for a router
+///
+/// # Arguments
+///
+/// f - A function whose arguments implement `FromRequest` and whose
+/// return value implements `IntoResponse`.
+///
+/// ```no_run
+/// use server::extract::{handler, Json, Path};
+/// use server::json::Value;
+/// use server::router::Router;
+///
+/// fn rename(Path(id): Path<u32>, Json(body): Json<Value>) -> String {
+///     format!("Renamed {} to {}", id, body.get("name").and_then(Value::as_str).unwrap_or(""))
+/// }
+///
+/// let mut router = Router::new();
+/// router.put("/users/:id", handler(rename));
+/// ```
+pub fn handler<F, Args>(f: F) -> impl Fn(Request) -> Response + Send + Sync + 'static
+where
+    F: Handler<Args>,
+    Args: 'static,
+{
+    move |request| f.call(request)
+}
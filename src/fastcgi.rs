@@ -0,0 +1,307 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use crate::cgi::{environment, parse_output};
+use crate::log;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+use crate::static_files::sanitize;
+
+const VERSION: u8 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u16 = 1;
+const KEEP_CONN: u8 = 1;
+const REQUEST_COMPLETE: u8 = 0;
+const MAX_RECORD_CONTENT: usize = 65535;
+/// Connections kept open for reuse when idle
+const MAX_IDLE_CONNECTIONS: usize = 8;
+
+/// Where a FastCGI application listens
+pub enum Backend {
+    /// A TCP address such as 127.0.0.1:9000
+    Tcp(String),
+    /// A Unix domain socket such as /run/php/php-fpm.sock
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Forwards requests to a FastCGI application such as php-fpm
+///
+/// Used as a middleware, requests whose path matches the pattern are
+/// forwarded and all others passed on. Backend connections are kept
+/// open and reused for later requests; each of them carries one request
+/// at a time, as common applications do not multiplex.
+pub struct FastCgi {
+    backend: Backend,
+    root: PathBuf,
+    pattern: String,
+    timeout: Duration,
+    idle: Mutex<Vec<Stream>>,
+}
+
+impl FastCgi {
+    /// Create a client for an application serving scripts under a
+    /// document root, forwarding every request by default
+    ///
+    /// # Arguments
+    ///
+    /// backend - The socket the application listens on.
+    /// root - The document root as seen by the application, used for
+    /// SCRIPT_FILENAME and DOCUMENT_ROOT.
+    pub fn new<P: AsRef<Path>>(backend: Backend, root: P) -> FastCgi {
+        FastCgi {
+            backend,
+            root: root.as_ref().to_path_buf(),
+            pattern: String::from("*"),
+            timeout: Duration::from_secs(30),
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Only forward requests whose path matches a pattern, in which `*`
+    /// matches any run of characters, e.g. `*.php`
+    pub fn with_pattern(mut self, pattern: &str) -> FastCgi {
+        self.pattern = String::from(pattern);
+        self
+    }
+
+    /// Set how long reading from or writing to the application may
+    /// block before the request fails with 504, 30 seconds by default
+    pub fn with_timeout(mut self, timeout: Duration) -> FastCgi {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Whether a request path matches the pattern
+    pub fn matches(&self, path: &str) -> bool {
+        glob_match(&self.pattern, path)
+    }
+
+    /// Forward a request to the application
+    pub fn handle(&self, request: &Request) -> Response {
+        let relative = match sanitize(request.path()) {
+            Ok(relative) => relative,
+            Err(response) => return response,
+        };
+        let script = self.root.join(relative);
+        let mut params = environment(request, &script, request.path(), "");
+        params.push((String::from("DOCUMENT_ROOT"), self.root.display().to_string()));
+        params.push((String::from("REQUEST_URI"), String::from(request.target())));
+
+        // A kept-alive connection may have been closed by the
+        // application in the meantime, so retry once on a new one
+        let idle = self.idle.lock().unwrap().pop();
+        let result = match idle {
+            Some(stream) => match self.exchange(stream, &params, request.body()) {
+                Err(e) if e.kind() != io::ErrorKind::TimedOut && e.kind() != io::ErrorKind::WouldBlock => {
+                    self.connect().and_then(|stream| self.exchange(stream, &params, request.body()))
+                }
+                result => result,
+            },
+            None => self.connect().and_then(|stream| self.exchange(stream, &params, request.body())),
+        };
+
+        match result {
+            Ok(output) => parse_output(&output).unwrap_or_else(|| {
+                log::error(&format!("FastCGI application produced malformed output for {}", request.path()));
+                Response::text(502, "Bad Gateway")
+            }),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock => {
+                log::error(&format!("FastCGI application timed out for {}", request.path()));
+                Response::text(504, "Gateway Timeout")
+            }
+            Err(e) => {
+                log::error(&format!("FastCGI request for {} failed: {}", request.path(), e));
+                Response::text(502, "Bad Gateway")
+            }
+        }
+    }
+
+    fn connect(&self) -> io::Result<Stream> {
+        let stream = match &self.backend {
+            Backend::Tcp(address) => Stream::Tcp(TcpStream::connect(address)?),
+            #[cfg(unix)]
+            Backend::Unix(path) => Stream::Unix(UnixStream::connect(path)?),
+        };
+        stream.set_timeout(self.timeout)?;
+        Ok(stream)
+    }
+
+    /// Send a request over a connection and collect the application's
+    /// output, returning the connection to the idle list afterwards
+    fn exchange(&self, mut stream: Stream, params: &[(String, String)], body: &[u8]) -> io::Result<Vec<u8>> {
+        // Only one request is in flight per connection
+        let id = 1;
+        let mut message = Vec::new();
+        let begin = [(RESPONDER >> 8) as u8, RESPONDER as u8, KEEP_CONN, 0, 0, 0, 0, 0];
+        push_record(&mut message, BEGIN_REQUEST, id, &begin);
+
+        let mut encoded = Vec::new();
+        for (name, value) in params {
+            push_length(&mut encoded, name.len());
+            push_length(&mut encoded, value.len());
+            encoded.extend_from_slice(name.as_bytes());
+            encoded.extend_from_slice(value.as_bytes());
+        }
+        push_stream(&mut message, PARAMS, id, &encoded);
+        push_stream(&mut message, STDIN, id, body);
+        stream.write_all(&message)?;
+        stream.flush()?;
+
+        let mut output = Vec::new();
+        loop {
+            let mut header = [0; 8];
+            stream.read_exact(&mut header)?;
+            let kind = header[1];
+            let request_id = u16::from_be_bytes([header[2], header[3]]);
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            let padding = header[6] as usize;
+            let mut content = vec![0; length + padding];
+            stream.read_exact(&mut content)?;
+            content.truncate(length);
+
+            if request_id != id {
+                continue;
+            }
+            match kind {
+                STDOUT => output.extend_from_slice(&content),
+                STDERR => log::warn(&format!("FastCGI application: {}", String::from_utf8_lossy(&content).trim_end())),
+                END_REQUEST => {
+                    if content.get(4) != Some(&REQUEST_COMPLETE) {
+                        return Err(io::Error::other("Application rejected the request."));
+                    }
+                    let mut idle = self.idle.lock().unwrap();
+                    if idle.len() < MAX_IDLE_CONNECTIONS {
+                        idle.push(stream);
+                    }
+                    return Ok(output);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Middleware for FastCgi {
+    fn handle(&self, request: Request, next: &Next) -> Response {
+        if self.matches(request.path()) {
+            FastCgi::handle(self, &request)
+        } else {
+            next.run(request)
+        }
+    }
+}
+
+/// A connection to the application
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))
+            }
+            #[cfg(unix)]
+            Stream::Unix(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))
+            }
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+fn push_record(message: &mut Vec<u8>, kind: u8, id: u16, content: &[u8]) {
+    let padding = (8 - content.len() % 8) % 8;
+    message.extend_from_slice(&[VERSION, kind]);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    message.extend_from_slice(&[padding as u8, 0]);
+    message.extend_from_slice(content);
+    message.extend(std::iter::repeat_n(0, padding));
+}
+
+/// Write a stream as records of at most the maximum size, followed by
+/// the empty record ending it
+fn push_stream(message: &mut Vec<u8>, kind: u8, id: u16, data: &[u8]) {
+    for chunk in data.chunks(MAX_RECORD_CONTENT) {
+        push_record(message, kind, id, chunk);
+    }
+    push_record(message, kind, id, &[]);
+}
+
+/// Encode the length of a name or value of a name-value pair
+fn push_length(encoded: &mut Vec<u8>, length: usize) {
+    if length < 128 {
+        encoded.push(length as u8);
+    } else {
+        encoded.extend_from_slice(&(length as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+/// Match a path against a pattern in which `*` matches any run of
+/// characters
+fn glob_match(pattern: &str, path: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match path.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(found) => rest = &rest[found + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
@@ -0,0 +1,196 @@
+use std::io::{self, Read};
+use std::net::IpAddr;
+use std::thread;
+use std::time::Duration;
+
+use crate::log;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::{reason_phrase, Body, Response};
+use crate::trace_context;
+
+/// A fault injected into the handling of some requests, for testing how
+/// clients and the retries of whatever sits in front of the server cope
+/// with a server misbehaving, see `Config::faults`
+///
+/// A fault applies to a request matching all of its filters with the
+/// probability of its percentage, each fault being tried on its own:
+/// a request may be delayed by one fault and answered by another.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use server::config::Config;
+/// use server::faults::{Fault, FaultFilter, FaultKind};
+/// use server::server::Server;
+///
+/// let faults = vec![
+///     Fault::new(FaultKind::Delay(Duration::from_millis(500)), 10.0),
+///     Fault::new(FaultKind::Status(503), 5.0).with_filter(FaultFilter::PathPrefix(String::from("/api"))),
+///     Fault::new(FaultKind::Drop, 1.0).with_filter(FaultFilter::Method(String::from("POST"))),
+/// ];
+/// let server = Server::new(Config { faults, ..Config::default() }).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Fault {
+    /// What goes wrong
+    pub kind: FaultKind,
+    /// How many of every hundred matching requests it happens to
+    pub percent: f64,
+    /// What requests have to match for the fault to apply, all of them;
+    /// none means every request
+    pub filters: Vec<FaultFilter>,
+}
+
+/// What goes wrong with a request a fault applies to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FaultKind {
+    /// Wait this long before handling the request
+    Delay(Duration),
+    /// Answer with this error status instead of handling the request
+    Status(u16),
+    /// Close the connection without answering
+    Drop,
+    /// Send the head of the response and this many bytes of its body,
+    /// then close the connection; bodies written by a function are sent
+    /// in full
+    Truncate(u64),
+}
+
+/// Requests a fault applies to
+#[derive(Clone, Debug)]
+pub enum FaultFilter {
+    /// Requests whose path starts with this
+    PathPrefix(String),
+    /// Requests with this method
+    Method(String),
+    /// Requests with a header of this name, ignoring case, and this
+    /// exact value if one is given
+    Header(String, Option<String>),
+    /// Requests from this client, as `Request::client_ip` reports it
+    ClientIp(IpAddr),
+}
+
+impl Fault {
+    /// A fault applying to a share of all requests, until filters are
+    /// added
+    ///
+    /// # Arguments
+    ///
+    /// kind - What goes wrong.
+    /// percent - How many of every hundred requests it happens to.
+    pub fn new(kind: FaultKind, percent: f64) -> Fault {
+        Fault { kind, percent, filters: Vec::new() }
+    }
+
+    /// Only apply the fault to requests matching a filter as well
+    pub fn with_filter(mut self, filter: FaultFilter) -> Fault {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Whether the fault happens to a request
+    fn strikes(&self, request: &Request) -> bool {
+        self.filters.iter().all(|filter| filter.matches(request))
+            && self.percent > 0.0
+            && trace_context::random_fraction() * 100.0 < self.percent
+    }
+}
+
+impl FaultFilter {
+    fn matches(&self, request: &Request) -> bool {
+        match self {
+            FaultFilter::PathPrefix(prefix) => request.path().starts_with(prefix.as_str()),
+            FaultFilter::Method(method) => request.method().eq_ignore_ascii_case(method),
+            FaultFilter::Header(name, value) => request
+                .header(name)
+                .is_some_and(|actual| value.as_ref().is_none_or(|value| actual.trim() == value)),
+            FaultFilter::ClientIp(ip) => request.client_ip() == Some(*ip),
+        }
+    }
+}
+
+/// Check that faults are well formed
+///
+/// # Errors
+///
+/// Returns an error if a percentage is not between 0 and 100 or a
+/// status is not a 4xx or 5xx one.
+pub(crate) fn validate(faults: &[Fault]) -> io::Result<()> {
+    for fault in faults {
+        if !(0.0..=100.0).contains(&fault.percent) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Fault percentages have to be between 0 and 100."));
+        }
+        if let FaultKind::Status(status) = fault.kind {
+            if !(400..600).contains(&status) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Injected faults need a 4xx or 5xx status."));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs the configured faults around the handling of every request,
+/// before any other middleware
+pub(crate) struct FaultInjection {
+    faults: Vec<Fault>,
+}
+
+impl FaultInjection {
+    pub(crate) fn new(faults: Vec<Fault>) -> FaultInjection {
+        FaultInjection { faults }
+    }
+}
+
+impl Middleware for FaultInjection {
+    fn handle(&self, request: Request, next: &Next) -> Response {
+        let mut truncate: Option<u64> = None;
+        for fault in self.faults.iter().filter(|fault| fault.strikes(&request)) {
+            match fault.kind {
+                FaultKind::Delay(delay) => thread::sleep(delay),
+                FaultKind::Status(status) => {
+                    log::info(&format!("Injecting {} for {}", status, request.path()));
+                    return Response::text(status, reason_phrase(status));
+                }
+                FaultKind::Drop => {
+                    log::info(&format!("Injecting a dropped connection for {}", request.path()));
+                    return Response::aborted();
+                }
+                FaultKind::Truncate(sent) => truncate = Some(truncate.map_or(sent, |other| other.min(sent))),
+            }
+        }
+        let mut response = next.run(request);
+        let sent = match truncate {
+            Some(sent) if !matches!(response.body(), Body::Writer(_)) => sent,
+            _ => return response,
+        };
+        let (reader, length): (Box<dyn Read + Send>, Option<u64>) = match response.take_body() {
+            Body::Bytes(bytes) => {
+                let length = bytes.len() as u64;
+                (Box::new(io::Cursor::new(bytes)), Some(length))
+            }
+            Body::Reader(reader, length) => (reader, length),
+            Body::File(file, length) => (Box::new(file.take(length)), Some(length)),
+            Body::Writer(_) => unreachable!(),
+        };
+        response.with_stream(Truncated { reader, left: sent }, length)
+    }
+}
+
+/// A body that fails once a number of its bytes have been read, so the
+/// connection is closed in the middle of it
+struct Truncated {
+    reader: Box<dyn Read + Send>,
+    left: u64,
+}
+
+impl Read for Truncated {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.left == 0 {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Response truncated by an injected fault."));
+        }
+        let wanted = buf.len().min(self.left.min(usize::MAX as u64) as usize);
+        let read = self.reader.read(&mut buf[..wanted])?;
+        self.left -= read as u64;
+        Ok(read)
+    }
+}
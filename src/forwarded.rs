@@ -0,0 +1,202 @@
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use crate::request::Request;
+
+/// A block of IP addresses such as `10.0.0.0/8` or `2001:db8::/32`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parse a block in CIDR notation, or a single address
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the address or prefix length is invalid.
+    pub fn parse(input: &str) -> Result<Cidr, CidrError> {
+        let (address, prefix) = match input.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (input, None),
+        };
+        let network: IpAddr = address
+            .trim()
+            .parse()
+            .map_err(|_| CidrError::new(&format!("Invalid address in {}.", input)))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => match prefix.trim().parse::<u8>() {
+                Ok(prefix) if prefix <= max => prefix,
+                _ => return Err(CidrError::new(&format!("Invalid prefix length in {}.", input))),
+            },
+            None => max,
+        };
+        Ok(Cidr { network, prefix })
+    }
+
+    /// The block of addresses counted as one client, e.g. by a limit on
+    /// requests per client
+    ///
+    /// IPv6 hosts are commonly handed a whole /64, so counting each of
+    /// their addresses separately lets one client pass for many; with
+    /// a prefix length of 64 they are bucketed by their network
+    /// instead. IPv4 addresses, including mapped ones, and a prefix
+    /// length of 128 always give a block of a single address.
+    ///
+    /// # Arguments
+    ///
+    /// ip - The address of the client.
+    /// ipv6_prefix - The prefix length IPv6 addresses are bucketed by, at most 128.
+    pub fn client(ip: IpAddr, ipv6_prefix: u8) -> Cidr {
+        match ip.to_canonical() {
+            IpAddr::V4(v4) => Cidr { network: IpAddr::V4(v4), prefix: 32 },
+            IpAddr::V6(v6) => {
+                let prefix = ipv6_prefix.min(128);
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                let network = Ipv6Addr::from(u128::from(v6) & mask);
+                Cidr { network: IpAddr::V6(network), prefix }
+            }
+        }
+    }
+
+    /// Whether an address lies within the block
+    ///
+    /// IPv4 addresses mapped into IPv6 match IPv4 blocks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// The header trusted proxies report the addresses of clients in
+///
+/// Only the header the proxies are known to maintain may be read: a
+/// proxy passes on the other one as the client sent it, so reading
+/// that would let clients claim any address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For`, which most proxies, nginx among them, append
+    /// to
+    XForwardedFor,
+    /// The standard `Forwarded` header
+    Forwarded,
+}
+
+impl ForwardedHeader {
+    /// The name of the header
+    pub fn name(self) -> &'static str {
+        match self {
+            ForwardedHeader::XForwardedFor => "X-Forwarded-For",
+            ForwardedHeader::Forwarded => "Forwarded",
+        }
+    }
+}
+
+/// Work out the address of the client a request came from
+///
+/// The forwarding header is only believed while the hop that added to
+/// it is a trusted proxy: starting with the peer, the chain in it is
+/// followed from the nearest hop outwards until an untrusted or
+/// unreadable entry.
+///
+/// # Arguments
+///
+/// peer - The address of the connected socket.
+/// request - The request carrying the forwarding header.
+/// trusted - The addresses of the proxies in front of the server.
+/// header - The header the proxies report clients in.
+pub fn client_ip(peer: IpAddr, request: &Request, trusted: &[Cidr], header: ForwardedHeader) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+    if !is_trusted(peer) {
+        return peer;
+    }
+
+    let chain = forwarded_chain(request, header);
+    let mut client = peer;
+    for hop in chain.iter().rev() {
+        if !is_trusted(client) {
+            break;
+        }
+        match parse_node(hop) {
+            Some(ip) => client = ip,
+            None => break,
+        }
+    }
+    client
+}
+
+/// The client addresses listed by the proxies, nearest last
+fn forwarded_chain(request: &Request, header: ForwardedHeader) -> Vec<String> {
+    let values = request.headers().filter(|(name, _)| name.eq_ignore_ascii_case(header.name())).map(|(_, value)| value);
+    if header == ForwardedHeader::Forwarded {
+        return values
+            .flat_map(|value| value.split(','))
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                    .map(|(_, value)| String::from(value.trim().trim_matches('"')))
+                    .unwrap_or_default()
+            })
+            .collect();
+    }
+
+    values.flat_map(|value| value.split(',')).map(|hop| String::from(hop.trim())).collect()
+}
+
+/// Parse an address that may carry a port, e.g. `[2001:db8::1]:4711`
+///
+/// IPv4-mapped IPv6 addresses are turned into plain IPv4 ones.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| node.trim_start_matches('[').trim_end_matches(']').parse().ok())
+        .map(|ip| ip.to_canonical())
+}
+
+#[derive(Debug)]
+pub struct CidrError {
+    details: String,
+}
+
+impl CidrError {
+    fn new(details: &str) -> CidrError {
+        CidrError{details: String::from(details)}
+    }
+}
+
+impl fmt::Display for CidrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for CidrError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
@@ -0,0 +1,384 @@
+// gzip as specified in RFC 1952, compressing with DEFLATE as specified
+// in RFC 1951
+
+use std::io::{self, Write};
+
+use crate::huffman::{self, BitWriter};
+use crate::lz77::{Matcher, Params, Token};
+
+/// Input compressed into one DEFLATE block
+const BLOCK_SIZE: usize = 1 << 16;
+
+/// The farthest back DEFLATE can refer
+const WINDOW: usize = 1 << 15;
+
+/// Match lengths at which each length code starts
+pub(crate) const LENGTH_BASE: [u16; 29] =
+    [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+pub(crate) const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+
+/// Distances at which each distance code starts
+pub(crate) const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+pub(crate) const DISTANCE_EXTRA: [u8; 30] =
+    [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// The order code length code lengths are sent in
+pub(crate) const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+/// How long a chain of earlier positions each level searches for matches
+const CHAIN_LENGTHS: [usize; 10] = [0, 4, 8, 16, 32, 64, 128, 256, 1024, 4096];
+
+/// Compresses what is written to it into gzip written to another writer
+///
+/// Compressed data is written in blocks as enough input arrives. `flush`
+/// writes out everything written so far so it can be decompressed right
+/// away, at some cost in compression, and `finish` ends the stream; an
+/// encoder dropped without `finish` leaves it truncated.
+///
+/// ```
+/// use std::io::Write;
+/// use server::gzip::GzipEncoder;
+///
+/// let mut encoder = GzipEncoder::new(Vec::new(), 6);
+/// encoder.write_all("hello ".repeat(1000).as_bytes()).unwrap();
+/// let compressed = encoder.finish().unwrap();
+/// assert_eq!(compressed[..2], [0x1f, 0x8b]);
+/// assert!(compressed.len() < 100);
+/// ```
+pub struct GzipEncoder<W: Write> {
+    inner: W,
+    deflater: Deflater,
+    crc: u32,
+    size: u32,
+}
+
+impl<W: Write> GzipEncoder<W> {
+    /// Create an encoder
+    ///
+    /// # Arguments
+    ///
+    /// inner - The writer the compressed stream is written to.
+    /// level - From 0, storing the input without compressing it, to 9,
+    /// searching hardest for the smallest output; larger levels mean 9.
+    pub fn new(inner: W, level: u32) -> GzipEncoder<W> {
+        let level = level.min(9);
+        let mut deflater = Deflater::new(level);
+        // No name or time, and the operating system unknown
+        let extra_flags = match level {
+            9 => 2,
+            1 => 4,
+            _ => 0,
+        };
+        deflater.bits.write_bytes(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, extra_flags, 255]);
+        GzipEncoder { inner, deflater, crc: 0, size: 0 }
+    }
+
+    /// The writer the compressed stream is written to
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// The writer the compressed stream is written to, which must not
+    /// be written to directly
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Compress the rest of the input and end the stream, returning the
+    /// underlying writer
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing to the underlying writer fails.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.deflater.finish();
+        self.deflater.bits.write_bytes(&self.crc.to_le_bytes());
+        self.deflater.bits.write_bytes(&self.size.to_le_bytes());
+        self.deflater.bits.drain_to(&mut self.inner)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for GzipEncoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.crc = crc32(self.crc, data);
+        self.size = self.size.wrapping_add(data.len() as u32);
+        self.deflater.write(data);
+        self.deflater.bits.drain_to(&mut self.inner)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.deflater.flush();
+        self.deflater.bits.drain_to(&mut self.inner)?;
+        self.inner.flush()
+    }
+}
+
+/// Compresses input into DEFLATE blocks
+struct Deflater {
+    level: u32,
+    matcher: Matcher,
+    bits: BitWriter,
+}
+
+impl Deflater {
+    fn new(level: u32) -> Deflater {
+        let params = Params {
+            window: WINDOW,
+            min_length: 3,
+            max_length: 258,
+            max_chain: CHAIN_LENGTHS[level as usize],
+            lazy: level >= 4,
+        };
+        Deflater { level, matcher: Matcher::new(params), bits: BitWriter::default() }
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        for piece in data.chunks(BLOCK_SIZE) {
+            self.matcher.push(piece);
+            while self.matcher.pending() >= BLOCK_SIZE {
+                self.block(false);
+            }
+        }
+    }
+
+    /// Compress what is pending and align the output to a byte with an
+    /// empty stored block, so a decompressor gets all of the input
+    fn flush(&mut self) {
+        if self.matcher.pending() > 0 {
+            self.block(false);
+        }
+        write_stored(&mut self.bits, &[], false);
+    }
+
+    fn finish(&mut self) {
+        self.block(true);
+        self.bits.align();
+    }
+
+    /// Compress up to a block of pending input, as whichever kind of
+    /// block comes out smallest
+    fn block(&mut self, last: bool) {
+        let tokens = self.matcher.parse(BLOCK_SIZE);
+        let raw = self.matcher.block();
+        if self.level > 0 {
+            let mut dynamic = self.bits.scratch();
+            write_dynamic(&mut dynamic, &tokens, last);
+            let mut fixed = self.bits.scratch();
+            write_fixed(&mut fixed, &tokens, last);
+            let best = if fixed.len() < dynamic.len() { fixed } else { dynamic };
+            // A stored block takes the header, up to a byte of padding,
+            // the length and the data
+            let pieces = raw.len().div_ceil(usize::from(u16::MAX)).max(1) as u64;
+            if best.len() < pieces * 42 + raw.len() as u64 * 8 {
+                self.bits.append(best);
+                return;
+            }
+        }
+        write_stored(&mut self.bits, raw, last);
+    }
+}
+
+/// Write input uncompressed, in as many stored blocks as it takes
+fn write_stored(bits: &mut BitWriter, raw: &[u8], last: bool) {
+    let mut pieces = raw.chunks(usize::from(u16::MAX)).peekable();
+    if pieces.peek().is_none() {
+        write_stored_piece(bits, &[], last);
+    }
+    while let Some(piece) = pieces.next() {
+        write_stored_piece(bits, piece, last && pieces.peek().is_none());
+    }
+}
+
+fn write_stored_piece(bits: &mut BitWriter, piece: &[u8], last: bool) {
+    bits.write(u64::from(last), 3);
+    bits.align();
+    let length = piece.len() as u16;
+    bits.write_bytes(&length.to_le_bytes());
+    bits.write_bytes(&(!length).to_le_bytes());
+    bits.write_bytes(piece);
+}
+
+/// Write a block compressed with the fixed Huffman codes
+fn write_fixed(bits: &mut BitWriter, tokens: &[Token], last: bool) {
+    let mut literal_lengths = [8; 288];
+    literal_lengths[144..256].fill(9);
+    literal_lengths[256..280].fill(7);
+    let distance_lengths = [5; 30];
+    bits.write(u64::from(last) | 1 << 1, 3);
+    let literals = Code::new(&literal_lengths);
+    let distances = Code::new(&distance_lengths);
+    write_tokens(bits, tokens, &literals, &distances);
+}
+
+/// Write a block compressed with Huffman codes made for its tokens
+fn write_dynamic(bits: &mut BitWriter, tokens: &[Token], last: bool) {
+    let mut literal_frequencies = [0u32; 286];
+    let mut distance_frequencies = [0u32; 30];
+    literal_frequencies[256] = 1;
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => literal_frequencies[usize::from(byte)] += 1,
+            Token::Match { length, distance } => {
+                literal_frequencies[257 + length_code(length)] += 1;
+                distance_frequencies[distance_code(distance)] += 1;
+            }
+        }
+    }
+    let literal_lengths = complete_lengths(&mut literal_frequencies, 15);
+    let distance_lengths = complete_lengths(&mut distance_frequencies, 15);
+
+    let literal_count = literal_lengths.iter().rposition(|&length| length > 0).map_or(0, |i| i + 1).max(257);
+    let distance_count = distance_lengths.iter().rposition(|&length| length > 0).map_or(0, |i| i + 1).max(1);
+    let mut sequence = literal_lengths[..literal_count].to_vec();
+    sequence.extend_from_slice(&distance_lengths[..distance_count]);
+    let runs = run_lengths(&sequence);
+
+    let mut code_length_frequencies = [0u32; 19];
+    for (symbol, _) in &runs {
+        code_length_frequencies[usize::from(*symbol)] += 1;
+    }
+    let code_length_lengths = complete_lengths(&mut code_length_frequencies, 7);
+    let code_length_codes = huffman::codes(&code_length_lengths);
+    let code_length_count =
+        CODE_LENGTH_ORDER.iter().rposition(|&symbol| code_length_lengths[symbol] > 0).map_or(0, |i| i + 1).max(4);
+
+    bits.write(u64::from(last) | 2 << 1, 3);
+    bits.write(literal_count as u64 - 257, 5);
+    bits.write(distance_count as u64 - 1, 5);
+    bits.write(code_length_count as u64 - 4, 4);
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        bits.write(u64::from(code_length_lengths[symbol]), 3);
+    }
+    for &(symbol, extra) in &runs {
+        let symbol = usize::from(symbol);
+        bits.write(u64::from(code_length_codes[symbol]), u32::from(code_length_lengths[symbol]));
+        match symbol {
+            16 => bits.write(u64::from(extra), 2),
+            17 => bits.write(u64::from(extra), 3),
+            18 => bits.write(u64::from(extra), 7),
+            _ => {}
+        }
+    }
+
+    let literals = Code::new(&literal_lengths);
+    let distances = Code::new(&distance_lengths);
+    write_tokens(bits, tokens, &literals, &distances);
+}
+
+/// A prefix code as the lengths and bit-reversed codes of its symbols
+struct Code {
+    lengths: Vec<u8>,
+    codes: Vec<u32>,
+}
+
+impl Code {
+    fn new(lengths: &[u8]) -> Code {
+        Code { lengths: lengths.to_vec(), codes: huffman::codes(lengths) }
+    }
+
+    fn write(&self, bits: &mut BitWriter, symbol: usize) {
+        bits.write(u64::from(self.codes[symbol]), u32::from(self.lengths[symbol]));
+    }
+}
+
+/// Write the tokens of a block and the end of block code
+fn write_tokens(bits: &mut BitWriter, tokens: &[Token], literals: &Code, distances: &Code) {
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => literals.write(bits, usize::from(byte)),
+            Token::Match { length, distance } => {
+                let code = length_code(length);
+                literals.write(bits, 257 + code);
+                bits.write(u64::from(length - u32::from(LENGTH_BASE[code])), u32::from(LENGTH_EXTRA[code]));
+                let code = distance_code(distance);
+                distances.write(bits, code);
+                bits.write(u64::from(distance - u32::from(DISTANCE_BASE[code])), u32::from(DISTANCE_EXTRA[code]));
+            }
+        }
+    }
+    literals.write(bits, 256);
+}
+
+fn length_code(length: u32) -> usize {
+    LENGTH_BASE.partition_point(|&base| u32::from(base) <= length) - 1
+}
+
+fn distance_code(distance: u32) -> usize {
+    DISTANCE_BASE.partition_point(|&base| u32::from(base) <= distance) - 1
+}
+
+/// Code lengths for a complete code, which some decoders insist on, so
+/// at least two symbols are given lengths even if fewer are used
+fn complete_lengths(frequencies: &mut [u32], limit: u8) -> Vec<u8> {
+    let mut unused = 0;
+    while frequencies.iter().filter(|&&frequency| frequency > 0).count() < 2 {
+        while frequencies[unused] > 0 {
+            unused += 1;
+        }
+        frequencies[unused] = 1;
+    }
+    huffman::lengths(frequencies, limit)
+}
+
+/// Run-length encode code lengths with the code length alphabet: 16
+/// repeats the previous length 3 to 6 times, 17 and 18 stand for 3 to
+/// 10 and 11 to 138 zeros
+fn run_lengths(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let length = lengths[i];
+        let mut run = lengths[i..].iter().take_while(|&&l| l == length).count();
+        i += run;
+        if length == 0 {
+            while run >= 11 {
+                let repeated = run.min(138);
+                runs.push((18, (repeated - 11) as u8));
+                run -= repeated;
+            }
+            if run >= 3 {
+                runs.push((17, (run - 3) as u8));
+                run = 0;
+            }
+        } else {
+            runs.push((length, 0));
+            run -= 1;
+            while run >= 3 {
+                let repeated = run.min(6);
+                runs.push((16, (repeated - 3) as u8));
+                run -= repeated;
+            }
+        }
+        runs.extend(std::iter::repeat_n((length, 0), run));
+    }
+    runs
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Continue a CRC-32 over more data
+pub(crate) fn crc32(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, &byte| CRC_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8))
+}
@@ -0,0 +1,98 @@
+/// How the names of response headers are written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaderCasing {
+    /// As the handler or middleware set them
+    Preserve,
+    /// In canonical form, such as `Content-Type` for `content-type`,
+    /// for clients that compare names case-sensitively against the
+    /// usual spelling
+    Canonical,
+}
+
+/// Words of header names whose usual spelling is not a capital letter
+/// followed by lowercase ones
+const IRREGULAR: &[&str] = &["CSRF", "DNT", "ETag", "IP", "MD5", "TE", "WWW", "WebSocket", "XSS"];
+
+/// The canonical form of a header name, with each word between dashes
+/// capitalized, e.g. `Content-Type`, or spelled as usual where that
+/// differs, e.g. `WWW-Authenticate` and `Sec-WebSocket-Key`
+///
+/// ```
+/// use server::headers::canonical;
+///
+/// assert_eq!(canonical("content-type"), "Content-Type");
+/// assert_eq!(canonical("ETAG"), "ETag");
+/// assert_eq!(canonical("sec-websocket-accept"), "Sec-WebSocket-Accept");
+/// ```
+pub fn canonical(name: &str) -> String {
+    let mut canonical = String::with_capacity(name.len());
+    for (i, word) in name.split('-').enumerate() {
+        if i > 0 {
+            canonical.push('-');
+        }
+        match IRREGULAR.iter().find(|irregular| irregular.eq_ignore_ascii_case(word)) {
+            Some(irregular) => canonical.push_str(irregular),
+            None => {
+                let mut chars = word.chars();
+                canonical.extend(chars.next().map(|first| first.to_ascii_uppercase()));
+                canonical.extend(chars.map(|c| c.to_ascii_lowercase()));
+            }
+        }
+    }
+    canonical
+}
+
+/// The media type of a Content-Type value without its parameters, e.g.
+/// `text/html` for `text/html; charset=utf-8`
+pub fn media_type(value: &str) -> &str {
+    value.split(';').next().unwrap_or("").trim()
+}
+
+/// Whether a byte may appear in a header name
+pub(crate) fn is_token_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+/// A change made to the headers of a message, such as those of every
+/// response, see `Config::response_headers`, or of requests and
+/// responses passing through a proxy, see `proxy::Proxy::with_request_rule`
+#[derive(Clone, Debug)]
+pub enum HeaderRule {
+    /// Add a header, keeping any others of the same name
+    Add(String, String),
+    /// Add a header unless there is one of the name already
+    Default(String, String),
+    /// Set a header, replacing any of the same name
+    Set(String, String),
+    /// Replace a header if there is one of the name, without adding it
+    /// otherwise
+    Replace(String, String),
+    /// Remove every header of a name
+    Remove(String),
+}
+
+impl HeaderRule {
+    pub(crate) fn apply(&self, headers: &mut Vec<(String, String)>) {
+        let remove = |headers: &mut Vec<(String, String)>, name: &str| headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        let present = |headers: &[(String, String)], name: &str| headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(name));
+        match self {
+            HeaderRule::Add(name, value) => headers.push((name.clone(), value.clone())),
+            HeaderRule::Default(name, value) => {
+                if !present(headers, name) {
+                    headers.push((name.clone(), value.clone()));
+                }
+            }
+            HeaderRule::Set(name, value) => {
+                remove(headers, name);
+                headers.push((name.clone(), value.clone()));
+            }
+            HeaderRule::Replace(name, value) => {
+                if present(headers, name) {
+                    remove(headers, name);
+                    headers.push((name.clone(), value.clone()));
+                }
+            }
+            HeaderRule::Remove(name) => remove(headers, name),
+        }
+    }
+}
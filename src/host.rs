@@ -0,0 +1,84 @@
+use crate::request::Request;
+
+/// Split the value of a Host header into the host name and the port
+///
+/// IPv6 addresses keep their brackets, and names are returned as sent,
+/// without changing their case. Returns None if the value is not a
+/// valid host with an optional port.
+pub fn split_host(value: &str) -> Option<(&str, Option<u16>)> {
+    let (host, port) = if value.starts_with('[') {
+        let end = value.find(']')?;
+        let host = &value[..=end];
+        let address = &host[1..host.len() - 1];
+        if address.parse::<std::net::Ipv6Addr>().is_err() {
+            return None;
+        }
+        match &value[end + 1..] {
+            "" => (host, None),
+            rest => (host, Some(rest.strip_prefix(':')?)),
+        }
+    } else {
+        match value.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (value, None),
+        }
+    };
+
+    if !host.starts_with('[') && !host.bytes().all(is_name_byte) {
+        return None;
+    }
+    let port = match port {
+        None => None,
+        Some(port) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => Some(port.parse().ok()?),
+        Some(_) => return None,
+    };
+    Some((host, port))
+}
+
+/// Whether a byte may appear in a registered host name, which covers
+/// IPv4 addresses as well
+fn is_name_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"-._~%!$&'()*+,;=".contains(&byte)
+}
+
+/// Whether a request is addressed to one of the allowed hosts
+///
+/// Entries are host names such as `example.com`, which match on any
+/// port, names with a port such as `example.com:8080`, or wildcards
+/// such as `*.example.com`, which match any subdomain but not the
+/// domain itself. Names are compared case-insensitively, ignoring a
+/// trailing dot. A request without a Host header never matches.
+///
+/// # Arguments
+///
+/// request - The request to check.
+/// allowed - The allowlist entries.
+pub fn is_allowed(request: &Request, allowed: &[String]) -> bool {
+    let (host, port) = match request.header("Host").and_then(split_host) {
+        Some(host) => host,
+        None => return false,
+    };
+    let host = host.trim_end_matches('.');
+    if host.is_empty() {
+        return false;
+    }
+
+    allowed.iter().any(|entry| {
+        let (pattern, entry_port) = match split_host(entry.trim()) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        if entry_port.is_some() && entry_port != port {
+            return false;
+        }
+        let pattern = pattern.trim_end_matches('.');
+        match pattern.strip_prefix("*.") {
+            Some(domain) => {
+                host.len() > domain.len() + 1
+                    && host[host.len() - domain.len()..].eq_ignore_ascii_case(domain)
+                    && host.as_bytes()[host.len() - domain.len() - 1] == b'.'
+            }
+            None => host.eq_ignore_ascii_case(pattern),
+        }
+    })
+}
@@ -0,0 +1,157 @@
+// Canonical prefix codes and the bit packing shared by the DEFLATE and
+// Brotli encoders, which both write codes least significant bit first
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{self, Write};
+
+/// Collects values of a few bits each into bytes
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    /// Append the low `count` bits of a value, at most 32
+    pub(crate) fn write(&mut self, value: u64, count: u32) {
+        debug_assert!(count <= 32 && value >> count == 0);
+        self.bits |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.bytes.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Pad with zero bits up to the next byte boundary
+    pub(crate) fn align(&mut self) {
+        if self.count > 0 {
+            self.bytes.push(self.bits as u8);
+            self.bits = 0;
+            self.count = 0;
+        }
+    }
+
+    /// Append whole bytes, which must start on a byte boundary
+    pub(crate) fn write_bytes(&mut self, bytes: &[u8]) {
+        debug_assert_eq!(self.count, 0);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// The number of bits written since the writer was created
+    pub(crate) fn len(&self) -> u64 {
+        self.bytes.len() as u64 * 8 + u64::from(self.count)
+    }
+
+    /// An empty writer continuing at the same bit position, to try out
+    /// an encoding before committing to it with `append`
+    pub(crate) fn scratch(&self) -> BitWriter {
+        BitWriter { bytes: Vec::new(), bits: self.bits, count: self.count }
+    }
+
+    /// Take over what a scratch writer of this one wrote
+    pub(crate) fn append(&mut self, scratch: BitWriter) {
+        self.bytes.extend_from_slice(&scratch.bytes);
+        self.bits = scratch.bits;
+        self.count = scratch.count;
+    }
+
+    /// Write out the complete bytes, keeping the bits of a partial one
+    pub(crate) fn drain_to<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.bytes)?;
+        self.bytes.clear();
+        Ok(())
+    }
+}
+
+/// The code lengths of a Huffman code for symbols with the given
+/// frequencies, none longer than `limit` bits
+///
+/// Unused symbols get length 0. A single used symbol gets length 1,
+/// leaving the code incomplete.
+pub(crate) fn lengths(frequencies: &[u32], limit: u8) -> Vec<u8> {
+    let mut weights: Vec<u64> = frequencies.iter().map(|&frequency| u64::from(frequency)).collect();
+    loop {
+        let lengths = unlimited_lengths(&weights);
+        if lengths.iter().all(|&length| length <= limit) {
+            return lengths;
+        }
+        // Evening out the weights makes the tree shallower, and with all
+        // of them equal it is balanced
+        for weight in weights.iter_mut().filter(|weight| **weight > 0) {
+            *weight = (*weight >> 1).max(1);
+        }
+    }
+}
+
+fn unlimited_lengths(weights: &[u64]) -> Vec<u8> {
+    let mut lengths = vec![0; weights.len()];
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = BinaryHeap::new();
+    // Nodes are the symbols followed by the internal nodes as they are made
+    let mut parents: Vec<usize> = vec![usize::MAX; weights.len()];
+    for (symbol, &weight) in weights.iter().enumerate() {
+        if weight > 0 {
+            heap.push(Reverse((weight, symbol)));
+        }
+    }
+    if heap.len() == 1 {
+        if let Some(Reverse((_, symbol))) = heap.pop() {
+            lengths[symbol] = 1;
+        }
+        return lengths;
+    }
+    // The last node left is the root
+    while let Some(Reverse((a, first))) = heap.pop() {
+        let (b, second) = match heap.pop() {
+            Some(Reverse(node)) => node,
+            None => break,
+        };
+        let node = parents.len();
+        parents.push(usize::MAX);
+        parents[first] = node;
+        parents[second] = node;
+        heap.push(Reverse((a + b, node)));
+    }
+    for (symbol, &weight) in weights.iter().enumerate() {
+        if weight > 0 {
+            let mut depth = 0;
+            let mut node = symbol;
+            while parents[node] != usize::MAX {
+                node = parents[node];
+                depth += 1;
+            }
+            lengths[symbol] = depth;
+        }
+    }
+    lengths
+}
+
+/// The canonical codes for the given code lengths, with their bits
+/// reversed so they can be written least significant bit first
+pub(crate) fn codes(lengths: &[u8]) -> Vec<u32> {
+    let mut counts = [0u32; 16];
+    for &length in lengths {
+        counts[usize::from(length)] += 1;
+    }
+    counts[0] = 0;
+    let mut next = [0u32; 16];
+    let mut code = 0;
+    for bits in 1..16 {
+        code = (code + counts[bits - 1]) << 1;
+        next[bits] = code;
+    }
+    lengths
+        .iter()
+        .map(|&length| {
+            if length == 0 {
+                return 0;
+            }
+            let code = next[usize::from(length)];
+            next[usize::from(length)] += 1;
+            code.reverse_bits() >> (32 - u32::from(length))
+        })
+        .collect()
+}
@@ -0,0 +1,373 @@
+// Decompressing DEFLATE as specified in RFC 1951, wrapped in gzip as
+// specified in RFC 1952 or zlib as specified in RFC 1950
+
+use std::error::Error;
+use std::fmt;
+
+use crate::gzip::{self, CODE_LENGTH_ORDER, DISTANCE_BASE, DISTANCE_EXTRA, LENGTH_BASE, LENGTH_EXTRA};
+
+/// The longest code DEFLATE uses
+const MAX_CODE_LENGTH: usize = 15;
+
+/// Decompress gzip, of one or more members one after the other
+///
+/// ```
+/// use std::io::Write;
+/// use server::gzip::GzipEncoder;
+/// use server::inflate;
+///
+/// let mut encoder = GzipEncoder::new(Vec::new(), 6);
+/// encoder.write_all(b"hello hello hello").unwrap();
+/// let compressed = encoder.finish().unwrap();
+/// assert_eq!(inflate::gunzip(&compressed, 1024).unwrap(), b"hello hello hello");
+/// assert!(inflate::gunzip(&compressed, 5).unwrap_err().is_too_large());
+/// ```
+///
+/// # Arguments
+///
+/// data - The compressed bytes.
+/// max_size - The most bytes decompressed before giving up, which guards
+/// against small inputs expanding into huge outputs.
+///
+/// # Errors
+///
+/// Returns an error if the data is not well formed gzip, its checksum
+/// does not match or it decompresses to more than `max_size` bytes.
+pub fn gunzip(data: &[u8], max_size: usize) -> Result<Vec<u8>, InflateError> {
+    let mut output = Vec::new();
+    let mut rest = data;
+    loop {
+        let start = output.len();
+        rest = member(rest, &mut output, max_size)?;
+        let trailer = rest.get(..8).ok_or_else(|| InflateError::new("Truncated gzip trailer."))?;
+        let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+        if crc != gzip::crc32(0, &output[start..]) || size != (output.len() - start) as u32 {
+            return Err(InflateError::new("The gzip checksum does not match."));
+        }
+        rest = &rest[8..];
+        if rest.is_empty() {
+            return Ok(output);
+        }
+    }
+}
+
+/// Decompress what the `deflate` content coding names, which is zlib,
+/// or raw DEFLATE as some clients send instead
+///
+/// # Arguments
+///
+/// data - The compressed bytes.
+/// max_size - The most bytes decompressed before giving up.
+///
+/// # Errors
+///
+/// Returns an error if the data is not well formed, its checksum does
+/// not match or it decompresses to more than `max_size` bytes.
+pub fn inflate(data: &[u8], max_size: usize) -> Result<Vec<u8>, InflateError> {
+    // A zlib header names DEFLATE with a window of at most 32 KiB and
+    // makes a multiple of 31; one of raw DEFLATE hardly ever matches
+    let zlib = data.len() >= 2
+        && data[0] & 0x0f == 8
+        && data[0] >> 4 <= 7
+        && u16::from_be_bytes([data[0], data[1]]).is_multiple_of(31);
+    let mut output = Vec::new();
+    if !zlib {
+        Inflater::new(data).run(&mut output, max_size)?;
+        return Ok(output);
+    }
+    if data[1] & 0x20 != 0 {
+        return Err(InflateError::new("zlib preset dictionaries are not supported."));
+    }
+    let mut inflater = Inflater::new(&data[2..]);
+    inflater.run(&mut output, max_size)?;
+    let trailer = inflater.rest().get(..4).ok_or_else(|| InflateError::new("Truncated zlib trailer."))?;
+    if u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]) != adler32(&output) {
+        return Err(InflateError::new("The zlib checksum does not match."));
+    }
+    Ok(output)
+}
+
+/// Decompress one gzip member, returning what follows its compressed
+/// data
+fn member<'a>(data: &'a [u8], output: &mut Vec<u8>, max_size: usize) -> Result<&'a [u8], InflateError> {
+    let truncated = || InflateError::new("Truncated gzip header.");
+    if data.len() < 10 || data[..3] != [0x1f, 0x8b, 8] {
+        return Err(InflateError::new("Not gzip data."));
+    }
+    let flags = data[3];
+    let mut at = 10;
+    if flags & 0x04 != 0 {
+        let extra = data.get(at..at + 2).ok_or_else(truncated)?;
+        at += 2 + usize::from(u16::from_le_bytes([extra[0], extra[1]]));
+    }
+    // The file name and comment end with a NUL byte
+    for flag in [0x08, 0x10] {
+        if flags & flag != 0 {
+            at += data.get(at..).and_then(|rest| rest.iter().position(|&byte| byte == 0)).ok_or_else(truncated)? + 1;
+        }
+    }
+    if flags & 0x02 != 0 {
+        at += 2;
+    }
+    let mut inflater = Inflater::new(data.get(at..).ok_or_else(truncated)?);
+    inflater.run(output, max_size)?;
+    Ok(inflater.rest())
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // Sums of this many bytes cannot overflow before being reduced
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+/// A canonical Huffman code, as the number of codes of each length and
+/// the symbols in order of their codes
+struct Code {
+    counts: [u16; MAX_CODE_LENGTH + 1],
+    symbols: Vec<u16>,
+}
+
+impl Code {
+    /// The code with the given length for each symbol, zero for symbols
+    /// that do not occur
+    fn new(lengths: &[u8]) -> Result<Code, InflateError> {
+        let mut counts = [0u16; MAX_CODE_LENGTH + 1];
+        for &length in lengths {
+            counts[usize::from(length)] += 1;
+        }
+        counts[0] = 0;
+        // More codes of a length than there is room for cannot decode
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = left * 2 - i32::from(count);
+            if left < 0 {
+                return Err(InflateError::new("Over-subscribed Huffman code."));
+            }
+        }
+        let mut offsets = [0u16; MAX_CODE_LENGTH + 2];
+        for length in 1..=MAX_CODE_LENGTH {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; usize::from(offsets[MAX_CODE_LENGTH + 1])];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[usize::from(offsets[usize::from(length)])] = symbol as u16;
+                offsets[usize::from(length)] += 1;
+            }
+        }
+        Ok(Code { counts, symbols })
+    }
+}
+
+/// Reads DEFLATE blocks from a slice
+struct Inflater<'a> {
+    data: &'a [u8],
+    /// The next byte to take bits from
+    position: usize,
+    bits: u32,
+    count: u32,
+}
+
+impl<'a> Inflater<'a> {
+    fn new(data: &'a [u8]) -> Inflater<'a> {
+        Inflater { data, position: 0, bits: 0, count: 0 }
+    }
+
+    /// What follows the DEFLATE stream, which ends on a byte boundary
+    fn rest(&self) -> &'a [u8] {
+        &self.data[self.position..]
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32, InflateError> {
+        while self.count < count {
+            let byte = *self.data.get(self.position).ok_or_else(|| InflateError::new("Truncated DEFLATE stream."))?;
+            self.position += 1;
+            self.bits |= u32::from(byte) << self.count;
+            self.count += 8;
+        }
+        let value = self.bits & ((1u64 << count) - 1) as u32;
+        self.bits >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    /// Read a symbol, a bit at a time as codes are sent starting with
+    /// their most significant bit
+    fn symbol(&mut self, code: &Code) -> Result<u16, InflateError> {
+        let (mut value, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &code.counts[1..] {
+            value |= self.bits(1)? as i32;
+            let count = i32::from(count);
+            if value - first < count {
+                return Ok(code.symbols[(index + value - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            value <<= 1;
+        }
+        Err(InflateError::new("Invalid Huffman code."))
+    }
+
+    /// Decompress every block up to and including the last
+    fn run(&mut self, output: &mut Vec<u8>, max_size: usize) -> Result<(), InflateError> {
+        loop {
+            let last = self.bits(1)? == 1;
+            match self.bits(2)? {
+                0 => self.stored(output, max_size)?,
+                1 => {
+                    let mut lengths = [8u8; 288];
+                    lengths[144..256].fill(9);
+                    lengths[256..280].fill(7);
+                    self.codes(output, max_size, &Code::new(&lengths)?, &Code::new(&[5; 30])?)?;
+                }
+                2 => {
+                    let (literals, distances) = self.dynamic()?;
+                    self.codes(output, max_size, &literals, &distances)?;
+                }
+                _ => return Err(InflateError::new("Invalid DEFLATE block type.")),
+            }
+            if last {
+                // The stream ends on a byte boundary
+                self.bits = 0;
+                self.count = 0;
+                return Ok(());
+            }
+        }
+    }
+
+    fn stored(&mut self, output: &mut Vec<u8>, max_size: usize) -> Result<(), InflateError> {
+        self.bits = 0;
+        self.count = 0;
+        let header = self.data.get(self.position..self.position + 4).ok_or_else(|| InflateError::new("Truncated stored block."))?;
+        let length = usize::from(u16::from_le_bytes([header[0], header[1]]));
+        if length != usize::from(!u16::from_le_bytes([header[2], header[3]])) {
+            return Err(InflateError::new("Corrupt stored block length."));
+        }
+        self.position += 4;
+        let stored = self.data.get(self.position..self.position + length).ok_or_else(|| InflateError::new("Truncated stored block."))?;
+        if output.len() + length > max_size {
+            return Err(InflateError::too_large());
+        }
+        output.extend_from_slice(stored);
+        self.position += length;
+        Ok(())
+    }
+
+    /// Read the codes of a block with dynamic Huffman codes
+    fn dynamic(&mut self) -> Result<(Code, Code), InflateError> {
+        let literal_count = self.bits(5)? as usize + 257;
+        let distance_count = self.bits(5)? as usize + 1;
+        let length_count = self.bits(4)? as usize + 4;
+        if literal_count > 286 || distance_count > 30 {
+            return Err(InflateError::new("Too many codes in a DEFLATE block."));
+        }
+        let mut code_lengths = [0u8; 19];
+        for &symbol in &CODE_LENGTH_ORDER[..length_count] {
+            code_lengths[symbol] = self.bits(3)? as u8;
+        }
+        let code_length_code = Code::new(&code_lengths)?;
+
+        let mut lengths = vec![0u8; literal_count + distance_count];
+        let mut i = 0;
+        while i < lengths.len() {
+            let symbol = self.symbol(&code_length_code)?;
+            let (length, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 if i > 0 => (lengths[i - 1], 3 + self.bits(2)? as usize),
+                16 => return Err(InflateError::new("Repeated code length without a previous one.")),
+                17 => (0, 3 + self.bits(3)? as usize),
+                _ => (0, 11 + self.bits(7)? as usize),
+            };
+            if i + repeat > lengths.len() {
+                return Err(InflateError::new("Code lengths overflow the DEFLATE block."));
+            }
+            lengths[i..i + repeat].fill(length);
+            i += repeat;
+        }
+        if lengths[256] == 0 {
+            return Err(InflateError::new("DEFLATE block without an end code."));
+        }
+        Ok((Code::new(&lengths[..literal_count])?, Code::new(&lengths[literal_count..])?))
+    }
+
+    /// Decompress the symbols of a block with Huffman codes
+    fn codes(&mut self, output: &mut Vec<u8>, max_size: usize, literals: &Code, distances: &Code) -> Result<(), InflateError> {
+        loop {
+            let symbol = usize::from(self.symbol(literals)?);
+            if symbol < 256 {
+                if output.len() >= max_size {
+                    return Err(InflateError::too_large());
+                }
+                output.push(symbol as u8);
+                continue;
+            }
+            if symbol == 256 {
+                return Ok(());
+            }
+            let index = symbol - 257;
+            if index >= LENGTH_BASE.len() {
+                return Err(InflateError::new("Invalid length code."));
+            }
+            let length = usize::from(LENGTH_BASE[index]) + self.bits(u32::from(LENGTH_EXTRA[index]))? as usize;
+            let index = usize::from(self.symbol(distances)?);
+            if index >= DISTANCE_BASE.len() {
+                return Err(InflateError::new("Invalid distance code."));
+            }
+            let distance = usize::from(DISTANCE_BASE[index]) + self.bits(u32::from(DISTANCE_EXTRA[index]))? as usize;
+            if distance > output.len() {
+                return Err(InflateError::new("Distance reaches back before the start of the data."));
+            }
+            if output.len() + length > max_size {
+                return Err(InflateError::too_large());
+            }
+            // Copies may overlap what they are copying
+            let start = output.len() - distance;
+            for i in 0..length {
+                output.push(output[start + i]);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InflateError {
+    details: String,
+    too_large: bool,
+}
+
+impl InflateError {
+    fn new(msg: &str) -> InflateError {
+        InflateError { details: msg.to_string(), too_large: false }
+    }
+
+    fn too_large() -> InflateError {
+        InflateError { details: String::from("Decompressed data too large."), too_large: true }
+    }
+
+    /// Whether decompressing gave up as the output exceeded its limit,
+    /// rather than the data being malformed
+    pub fn is_too_large(&self) -> bool {
+        self.too_large
+    }
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for InflateError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
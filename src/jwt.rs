@@ -0,0 +1,324 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::auth::constant_time_eq;
+use crate::base64;
+use crate::json::Value;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+use crate::rsa;
+use crate::sha256;
+
+/// The claims of a verified token, which `JwtAuth` puts into the
+/// request extensions
+#[derive(Clone, Debug)]
+pub struct Claims(pub Value);
+
+impl Claims {
+    /// Get a claim
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.0.get(name)
+    }
+
+    /// Who the token was issued to, from the `sub` claim
+    pub fn subject(&self) -> Option<&str> {
+        self.get("sub").and_then(Value::as_str)
+    }
+}
+
+#[derive(Clone)]
+enum Secret {
+    Hmac(Vec<u8>),
+    Rsa(rsa::PublicKey),
+}
+
+/// A key tokens can be signed with
+#[derive(Clone)]
+pub struct Key {
+    id: Option<String>,
+    secret: Secret,
+}
+
+impl Key {
+    /// A shared secret for HS256 tokens
+    pub fn hmac(secret: &[u8]) -> Key {
+        Key { id: None, secret: Secret::Hmac(secret.to_vec()) }
+    }
+
+    /// An RSA public key for RS256 tokens
+    ///
+    /// # Arguments
+    ///
+    /// modulus - The modulus as big-endian bytes.
+    /// exponent - The public exponent as big-endian bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the modulus is shorter than 2048 bits or the
+    /// numbers do not make a valid key.
+    pub fn rsa(modulus: &[u8], exponent: &[u8]) -> Result<Key, JwtError> {
+        match rsa::PublicKey::new(modulus, exponent) {
+            Some(key) => Ok(Key { id: None, secret: Secret::Rsa(key) }),
+            None => Err(JwtError::new("Invalid RSA key, or one shorter than 2048 bits.")),
+        }
+    }
+
+    /// Set the key ID, so that only tokens naming it in their `kid`
+    /// header are checked against the key
+    pub fn with_id(mut self, id: &str) -> Key {
+        self.id = Some(String::from(id));
+        self
+    }
+
+    /// A key from a JSON Web Key, as specified in RFC 7517, of type
+    /// `RSA` or `oct`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key is of another type, is not meant for
+    /// signatures or is malformed.
+    pub fn from_jwk(jwk: &Value) -> Result<Key, JwtError> {
+        let member = |name: &str| {
+            jwk.get(name)
+                .and_then(Value::as_str)
+                .and_then(base64::decode_url)
+                .ok_or_else(|| JwtError::new(&format!("Missing or invalid key member {}.", name)))
+        };
+        if jwk.get("use").is_some_and(|usage| usage.as_str() != Some("sig")) {
+            return Err(JwtError::new("The key is not meant for signatures."));
+        }
+        let alg = jwk.get("alg").and_then(Value::as_str);
+        let key = match jwk.get("kty").and_then(Value::as_str) {
+            Some("RSA") if alg.is_none_or(|alg| alg == "RS256") => Key::rsa(&member("n")?, &member("e")?)?,
+            Some("oct") if alg.is_none_or(|alg| alg == "HS256") => Key::hmac(&member("k")?),
+            _ => return Err(JwtError::new("Unsupported key type or algorithm.")),
+        };
+        match jwk.get("kid").and_then(Value::as_str) {
+            Some(id) => Ok(key.with_id(id)),
+            None => Ok(key),
+        }
+    }
+
+    fn algorithm(&self) -> &'static str {
+        match self.secret {
+            Secret::Hmac(_) => "HS256",
+            Secret::Rsa(_) => "RS256",
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match &self.secret {
+            Secret::Hmac(secret) => constant_time_eq(&sha256::hmac(secret, message), signature),
+            Secret::Rsa(key) => key.verify(message, signature),
+        }
+    }
+}
+
+/// Read the keys of a JSON Web Key Set file, skipping keys of types
+/// that `Key::from_jwk` does not support
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read, is not a key set or
+/// has no supported keys.
+pub fn load_jwks<P: AsRef<Path>>(path: P) -> Result<Vec<Key>, JwtError> {
+    let contents = fs::read_to_string(path.as_ref())
+        .map_err(|e| JwtError::new(&format!("Cannot read {}: {}", path.as_ref().display(), e)))?;
+    let set = Value::parse(&contents).map_err(|e| JwtError::new(&format!("Invalid key set: {}", e)))?;
+    let keys: Vec<Key> = set
+        .get("keys")
+        .and_then(Value::as_array)
+        .ok_or_else(|| JwtError::new("The key set has no keys member."))?
+        .iter()
+        .filter_map(|jwk| Key::from_jwk(jwk).ok())
+        .collect();
+    if keys.is_empty() {
+        return Err(JwtError::new("The key set has no supported keys."));
+    }
+    Ok(keys)
+}
+
+/// A middleware requiring a JSON Web Token, as specified in RFC 7519,
+/// sent as a bearer token in the `Authorization` header
+///
+/// Tokens have to be signed with HS256 or RS256 by one of the keys;
+/// `none` and every other algorithm are refused, as is a token whose
+/// algorithm does not match the key. The `exp` and `nbf` claims are
+/// checked when present, allowing for the leeway in clock differences,
+/// and the `aud` claim has to name one of the audiences if any are
+/// set. The claims of a valid token are put into the request extensions
+/// as `Claims`; other requests are answered with a 401 telling the
+/// client what was wrong, as specified in RFC 6750.
+///
+/// ```no_run
+/// use server::jwt::{Claims, JwtAuth, Key};
+/// use server::request::Request;
+/// use server::router::Router;
+///
+/// let mut router = Router::new();
+/// router.wrap(JwtAuth::new(vec![Key::hmac(b"secret")]).with_audience("api"));
+/// router.get("/", |request: Request| {
+///     format!("Hello, {}", request.extensions().get::<Claims>().and_then(Claims::subject).unwrap_or(""))
+/// });
+/// ```
+pub struct JwtAuth {
+    keys: Vec<Key>,
+    audiences: Vec<String>,
+    leeway: Duration,
+}
+
+impl JwtAuth {
+    /// Create the middleware, accepting tokens signed with any of the
+    /// keys, with a leeway of a minute
+    pub fn new(keys: Vec<Key>) -> JwtAuth {
+        JwtAuth { keys, audiences: Vec::new(), leeway: Duration::from_secs(60) }
+    }
+
+    /// Create the middleware with the keys of a JSON Web Key Set file
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of `load_jwks`.
+    pub fn from_jwks_file<P: AsRef<Path>>(path: P) -> Result<JwtAuth, JwtError> {
+        Ok(JwtAuth::new(load_jwks(path)?))
+    }
+
+    /// Only accept tokens with this audience, or any of the audiences
+    /// when called more than once
+    pub fn with_audience(mut self, audience: &str) -> JwtAuth {
+        self.audiences.push(String::from(audience));
+        self
+    }
+
+    /// Set how long tokens are accepted after they expire or before
+    /// they become valid, to allow for clocks not agreeing
+    pub fn with_leeway(mut self, leeway: Duration) -> JwtAuth {
+        self.leeway = leeway;
+        self
+    }
+
+    /// The claims of a token, if it is valid
+    ///
+    /// # Errors
+    ///
+    /// Returns why the token is not valid.
+    pub fn verify(&self, token: &str) -> Result<Claims, JwtError> {
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            return Err(JwtError::new("The token is malformed."));
+        }
+        let header = decode_json(parts[0])?;
+        let alg = header.get("alg").and_then(Value::as_str).unwrap_or("");
+        if alg != "HS256" && alg != "RS256" {
+            return Err(JwtError::new("The token is signed with an unsupported algorithm."));
+        }
+        if header.get("crit").is_some() {
+            return Err(JwtError::new("The token has critical header parameters."));
+        }
+        let kid = header.get("kid").and_then(Value::as_str);
+        let signature = base64::decode_url(parts[2]).ok_or_else(|| JwtError::new("The token is malformed."))?;
+        let signed = &token[..parts[0].len() + 1 + parts[1].len()];
+        let verified = self
+            .keys
+            .iter()
+            .filter(|key| key.algorithm() == alg)
+            .filter(|key| match (kid, &key.id) {
+                (Some(kid), Some(id)) => kid == id,
+                _ => true,
+            })
+            .any(|key| key.verify(signed.as_bytes(), &signature));
+        if !verified {
+            return Err(JwtError::new("The token signature is invalid."));
+        }
+
+        let claims = decode_json(parts[1])?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs_f64()).unwrap_or(0.0);
+        let leeway = self.leeway.as_secs_f64();
+        match claims.get("exp").map(Value::as_f64) {
+            Some(Some(exp)) if now >= exp + leeway => return Err(JwtError::new("The token has expired.")),
+            Some(None) => return Err(JwtError::new("The token expiry is not a number.")),
+            _ => {}
+        }
+        match claims.get("nbf").map(Value::as_f64) {
+            Some(Some(nbf)) if now + leeway < nbf => return Err(JwtError::new("The token is not valid yet.")),
+            Some(None) => return Err(JwtError::new("The token start is not a number.")),
+            _ => {}
+        }
+        if !self.audiences.is_empty() {
+            let audiences: Vec<&str> = match claims.get("aud") {
+                Some(Value::String(audience)) => vec![audience],
+                Some(Value::Array(audiences)) => audiences.iter().filter_map(Value::as_str).collect(),
+                _ => Vec::new(),
+            };
+            if !audiences.iter().any(|audience| self.audiences.iter().any(|accepted| accepted == audience)) {
+                return Err(JwtError::new("The token is not meant for this audience."));
+            }
+        }
+        Ok(Claims(claims))
+    }
+}
+
+impl Middleware for JwtAuth {
+    fn handle(&self, mut request: Request, next: &Next) -> Response {
+        let token = request.header("Authorization").and_then(|authorization| {
+            let (scheme, token) = authorization.trim().split_once(' ')?;
+            if scheme.eq_ignore_ascii_case("Bearer") {
+                Some(String::from(token.trim()))
+            } else {
+                None
+            }
+        });
+        let token = match token {
+            Some(token) => token,
+            None => return Response::text(401, "Unauthorized").with_header("WWW-Authenticate", "Bearer"),
+        };
+        match self.verify(&token) {
+            Ok(claims) => {
+                request.extensions_mut().insert(claims);
+                next.run(request)
+            }
+            Err(e) => Response::text(401, "Unauthorized").with_header(
+                "WWW-Authenticate",
+                &format!("Bearer error=\"invalid_token\", error_description=\"{}\"", e.details),
+            ),
+        }
+    }
+}
+
+/// A base64url-encoded JSON object of a token
+fn decode_json(part: &str) -> Result<Value, JwtError> {
+    let value = base64::decode_url(part)
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .and_then(|decoded| Value::parse(&decoded).ok());
+    match value {
+        Some(value @ Value::Object(_)) => Ok(value),
+        _ => Err(JwtError::new("The token is malformed.")),
+    }
+}
+
+#[derive(Debug)]
+pub struct JwtError {
+    details: String,
+}
+
+impl JwtError {
+    pub fn new(details: &str) -> JwtError {
+        JwtError { details: String::from(details) }
+    }
+}
+
+impl fmt::Display for JwtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for JwtError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
@@ -1,13 +1,109 @@
+use std::cmp;
+use std::collections::{BTreeSet, BinaryHeap, HashSet, VecDeque};
 use std::error::Error;
 use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
 use std::thread;
-use std::sync::mpsc;
-use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+pub mod admin;
+pub mod auth;
+pub mod autoscale;
+pub mod base64;
+pub mod body;
+pub mod buffers;
+#[cfg(feature = "brotli")]
+pub mod brotli;
+pub mod cache;
+pub mod cancel;
+pub mod cgi;
+pub mod client_limit;
+pub mod compression;
+pub mod config;
+pub mod csrf;
+pub mod daemon;
+pub mod date;
+pub mod dump;
+pub mod durable;
+pub mod early_hints;
+pub mod embed;
+pub mod error;
+pub mod etag;
+pub mod extensions;
+pub mod extract;
+pub mod faults;
+pub mod fastcgi;
+pub mod forwarded;
+pub mod gzip;
+pub mod headers;
+pub mod host;
+mod huffman;
+pub mod inflate;
+pub mod json;
+#[cfg(feature = "jwt")]
+pub mod jwt;
+pub mod loadgen;
+pub mod log;
+pub mod maintenance;
+mod lz77;
+pub mod md5;
+pub mod metrics;
+pub mod middleware;
+pub mod mime;
+pub mod multipart;
+pub mod negotiate;
+pub mod pages;
+pub mod poll;
+pub mod pools;
+pub mod privileges;
+pub mod proxy;
+pub mod proxy_protocol;
+pub mod record;
+mod queue;
+pub mod range;
+pub mod recover;
+pub mod redirect;
+pub mod request;
+pub mod response;
+pub mod restart;
+pub mod rewrite;
+pub mod router;
+#[cfg(feature = "jwt")]
+mod rsa;
+pub mod security;
+pub mod sendfile;
+pub mod server;
+pub mod service;
+pub mod sha256;
+pub mod shed;
+pub mod socket;
+pub mod state;
+pub mod static_files;
+pub mod stats;
+pub mod task;
+pub mod template;
+pub mod testing;
+#[cfg(feature = "otel")]
+pub mod trace;
+pub mod trace_context;
+pub mod uri;
+#[cfg(feature = "watch")]
+pub mod watch;
+
+/// How long a job waits by default before it is treated as one priority
+/// level more urgent
+const DEFAULT_AGING: Duration = Duration::from_secs(1);
+/// Number of recent queue waits kept for computing their quantiles
+const QUEUE_WAIT_WINDOW: usize = 1024;
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
+    workers: Arc<Workers>,
+    sender: queue::Sender<Message>,
+    counters: Arc<Counters>,
+    scheduler: Arc<Scheduler>,
 }
 
 impl ThreadPool {
@@ -25,18 +121,59 @@ impl ThreadPool {
             return Err(PoolCreationError::new());
         }
         
-        let (sender, receiver) = mpsc::channel();
+        // Every worker learns of new jobs from the same receiver, and
+        // takes the most urgent one from the scheduler
+        let (sender, receiver) = queue::channel();
 
-        // We need to share ownership across multiple threads and allow the threads
-        // to mutate the value, hence receiver is Arc<Mutex<mpsc::receiver<Job>>>
-        let receiver = Arc::new(Mutex::new(receiver));
+        let counters = Arc::new(Counters::new(size));
+        let scheduler = Arc::new(Scheduler::new(DEFAULT_AGING, size));
 
-        let mut workers = Vec::with_capacity(size);
+        let mut threads = Vec::with_capacity(size);
 
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            threads.push(Worker::new(id, receiver.clone(), Arc::clone(&counters), Arc::clone(&scheduler)));
+        }
+        let workers = Arc::new(Workers {
+            receiver,
+            scheduler: Arc::clone(&scheduler),
+            threads: Mutex::new(threads),
+            next_id: AtomicUsize::new(size),
+            closed: AtomicBool::new(false),
+        });
+        Ok(ThreadPool{ workers, sender, counters, scheduler })
+    }
+
+    /// Set how long a job waits before it is treated as one priority level
+    /// more urgent, one second by default
+    ///
+    /// Aging lets low-priority jobs run eventually even under a steady
+    /// stream of high-priority ones: a `Low` job that waited twice this
+    /// long goes before a `High` job that was just queued. It applies to
+    /// jobs queued from then on; `Duration::MAX` keeps priorities strict.
+    pub fn with_aging(self, aging: Duration) -> ThreadPool {
+        self.scheduler.set_aging(aging);
+        self
+    }
+
+    /// Grow and shrink the pool with its load from now on, between the
+    /// bounds of the config, which the pool is resized into right away
+    ///
+    /// A thread of its own samples the load until the pool is dropped,
+    /// see `autoscale::Autoscale`. Resizing the pool by hand still works,
+    /// the samples going on from the new size.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the minimum is zero or above the maximum.
+    pub fn with_autoscaling(self, config: autoscale::Autoscale) -> ThreadPool {
+        assert!(config.min > 0 && config.min <= config.max, "Autoscaling needs a minimum from 1 up to the maximum.");
+        let size = self.monitor().size();
+        if size < config.min || size > config.max {
+            // Cannot fail, the size being positive and the pool alive
+            let _ = self.resize(size.clamp(config.min, config.max));
         }
-        Ok(ThreadPool{ workers, sender })
+        autoscale::spawn(self.handle(), config);
+        self
     }
 
 	/// Execute a job in the thread pool
@@ -53,9 +190,364 @@ impl ThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
+        self.execute_with_priority(Priority::Normal, f);
+    }
+
+    /// Execute a job in the thread pool once no more urgent job is
+    /// waiting, see `with_aging`
+    ///
+    /// # Arguments
+    ///
+    /// priority - How urgent the job is.
+    /// f - A closure the pool should run.
+    ///
+    /// # Panics
+    ///
+    /// Panics if all the threads in the pool have stopped.
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with(JobOptions::new().with_priority(priority), f);
+    }
+
+    /// Execute a job unless one with the same key is still waiting for a
+    /// worker, in which case the new one is dropped
+    ///
+    /// Returns whether the job was queued. Once a job has started, jobs
+    /// with its key are queued again, so a burst of refreshes submitted
+    /// while one is waiting results in a single run.
+    ///
+    /// # Arguments
+    ///
+    /// key - What identifies jobs doing the same work.
+    /// f - A closure the pool should run.
+    ///
+    /// # Panics
+    ///
+    /// Panics if all the threads in the pool have stopped.
+    pub fn execute_keyed<F>(&self, key: &str, f: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with(JobOptions::new().with_key(key), f)
+    }
+
+    /// Execute a job as part of a group that can be cancelled together,
+    /// see `cancel_tag`
+    ///
+    /// # Arguments
+    ///
+    /// tag - The group the job belongs to, such as the connection it is
+    /// done for.
+    /// f - A closure the pool should run.
+    ///
+    /// # Panics
+    ///
+    /// Panics if all the threads in the pool have stopped.
+    pub fn execute_tagged<F>(&self, tag: &str, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with(JobOptions::new().with_tag(tag), f);
+    }
+
+    /// Drop the jobs with a tag that are still waiting for a worker,
+    /// returning how many were dropped
+    ///
+    /// Jobs that already started run to completion.
+    pub fn cancel_tag(&self, tag: &str) -> usize {
+        cancel(&self.counters, &self.scheduler, tag)
+    }
+
+    /// Wait until every job submitted before the call has finished
+    ///
+    /// Jobs submitted meanwhile, by this or other threads, are not waited
+    /// for, and cancelled or coalesced jobs count as finished. A job must
+    /// not flush its own pool, as it would wait for itself.
+    pub fn flush(&self) {
+        self.scheduler.flush(None);
+    }
+
+    /// Wait until every job submitted before the call has finished, or
+    /// the timeout passed, returning whether they finished; see `flush`
+    pub fn flush_timeout(&self, timeout: Duration) -> bool {
+        self.scheduler.flush(Some(timeout))
+    }
+
+    /// Register a function called whenever a worker starts a job, with
+    /// how long the job waited for it
+    ///
+    /// Hooks run on the worker before the job, so they should return
+    /// quickly.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the hook mutex is in a poisoned state.
+    pub fn on_job_started<F>(&self, hook: F)
+    where
+        F: Fn(&JobStarted) + Send + Sync + 'static,
+    {
+        self.counters.hooks.lock().unwrap().push(Box::new(hook));
+    }
+
+    /// Execute a job that takes the capacity of several ordinary ones
+    ///
+    /// The pool has as many slots as it has threads, and a job only
+    /// starts once it got as many slots as its weight, so a few heavy
+    /// jobs cannot run at once and oversubscribe memory. Slots are handed
+    /// out in the order jobs start, so one waiting for several keeps
+    /// later jobs from taking them first; a weight beyond the size of the
+    /// pool takes all of it.
+    ///
+    /// # Arguments
+    ///
+    /// weight - How many slots the job takes, at least one.
+    /// f - A closure the pool should run.
+    ///
+    /// # Panics
+    ///
+    /// Panics if all the threads in the pool have stopped.
+    pub fn execute_weighted<F>(&self, weight: usize, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with(JobOptions::new().with_weight(weight), f);
+    }
+
+    /// Execute a job queued as the options say, returning whether it was
+    /// queued; see `JobOptions`
+    ///
+    /// # Panics
+    ///
+    /// Panics if all the threads in the pool have stopped.
+    pub fn execute_with<F>(&self, options: JobOptions, f: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        submit(&self.sender, &self.counters, &self.scheduler, options, Box::new(f))
+    }
+
+    /// Get a handle for observing the pool's counters from other threads
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor { counters: Arc::clone(&self.counters) }
+    }
+
+    /// Get a handle for submitting jobs to the pool from other threads,
+    /// including from jobs running in this or another pool
+    pub fn handle(&self) -> PoolHandle {
+        PoolHandle {
+            sender: self.sender.clone(),
+            counters: Arc::clone(&self.counters),
+            scheduler: Arc::clone(&self.scheduler),
+            workers: Arc::downgrade(&self.workers),
+        }
+    }
+
+    /// Change the number of worker threads while the pool runs
+    ///
+    /// New workers start right away. When shrinking, as many workers as
+    /// are too many stop once the jobs queued before the call have been
+    /// picked up, so no job is lost.
+    ///
+    /// Returns the previous number of workers.
+    ///
+    /// # Arguments
+    ///
+    /// size - The new number of threads in the pool.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if size is zero.
+    pub fn resize(&self, size: usize) -> Result<usize, PoolResizeError> {
+        self.workers.resize(size, &self.sender, &self.counters)
+    }
+}
+
+/// A cloneable handle submitting jobs to a ThreadPool
+///
+/// The handle does not keep the worker threads alive: once the pool is
+/// dropped, jobs submitted through the handle are no longer executed.
+#[derive(Clone)]
+pub struct PoolHandle {
+    sender: queue::Sender<Message>,
+    counters: Arc<Counters>,
+    scheduler: Arc<Scheduler>,
+    workers: Weak<Workers>,
+}
+
+impl PoolHandle {
+    /// Execute a job in the thread pool
+    ///
+    /// # Arguments
+    ///
+    /// f - A closure the pool should run.
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with_priority(Priority::Normal, f);
+    }
+
+    /// Execute a job in the thread pool once no more urgent job is
+    /// waiting, see `ThreadPool::with_aging`
+    ///
+    /// # Arguments
+    ///
+    /// priority - How urgent the job is.
+    /// f - A closure the pool should run.
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with(JobOptions::new().with_priority(priority), f);
+    }
+
+    /// Execute a job unless one with the same key is still waiting for a
+    /// worker, see `ThreadPool::execute_keyed`
+    ///
+    /// # Arguments
+    ///
+    /// key - What identifies jobs doing the same work.
+    /// f - A closure the pool should run.
+    pub fn execute_keyed<F>(&self, key: &str, f: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with(JobOptions::new().with_key(key), f)
+    }
+
+    /// Execute a job as part of a group that can be cancelled together,
+    /// see `ThreadPool::execute_tagged`
+    pub fn execute_tagged<F>(&self, tag: &str, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with(JobOptions::new().with_tag(tag), f);
+    }
+
+    /// Drop the jobs with a tag that are still waiting for a worker, see
+    /// `ThreadPool::cancel_tag`
+    pub fn cancel_tag(&self, tag: &str) -> usize {
+        cancel(&self.counters, &self.scheduler, tag)
+    }
+
+    /// Wait until every job submitted before the call has finished, see
+    /// `ThreadPool::flush`
+    pub fn flush(&self) {
+        self.scheduler.flush(None);
+    }
+
+    /// Wait until every job submitted before the call has finished, or
+    /// the timeout passed, see `ThreadPool::flush_timeout`
+    pub fn flush_timeout(&self, timeout: Duration) -> bool {
+        self.scheduler.flush(Some(timeout))
+    }
+
+    /// Register a function called whenever a worker starts a job, see
+    /// `ThreadPool::on_job_started`
+    ///
+    /// # Panics
+    ///
+    /// Panics if the hook mutex is in a poisoned state.
+    pub fn on_job_started<F>(&self, hook: F)
+    where
+        F: Fn(&JobStarted) + Send + Sync + 'static,
+    {
+        self.counters.hooks.lock().unwrap().push(Box::new(hook));
+    }
+
+    /// Execute a job that takes the capacity of several ordinary ones,
+    /// see `ThreadPool::execute_weighted`
+    pub fn execute_weighted<F>(&self, weight: usize, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with(JobOptions::new().with_weight(weight), f);
+    }
+
+    /// Execute a job queued as the options say, returning whether it was
+    /// queued, see `ThreadPool::execute_with`
+    ///
+    /// Jobs submitted once the pool has been dropped are not queued.
+    pub fn execute_with<F>(&self, options: JobOptions, f: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        submit(&self.sender, &self.counters, &self.scheduler, options, Box::new(f))
+    }
+
+    /// Get a handle for observing the pool's counters
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor { counters: Arc::clone(&self.counters) }
+    }
+
+    /// Change the number of worker threads of the pool, see
+    /// `ThreadPool::resize`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if size is zero or the pool has been dropped.
+    pub fn resize(&self, size: usize) -> Result<usize, PoolResizeError> {
+        match self.workers.upgrade() {
+            Some(workers) => workers.resize(size, &self.sender, &self.counters),
+            None => Err(PoolResizeError::new("The thread pool has been dropped.")),
+        }
+    }
+
+    /// Whether the pool has been dropped, so jobs are no longer executed
+    pub(crate) fn is_dropped(&self) -> bool {
+        self.workers.strong_count() == 0
+    }
+}
+
+/// The worker threads of a pool, shared with its handles so they can
+/// resize it
+struct Workers {
+    receiver: queue::Receiver<Message>,
+    scheduler: Arc<Scheduler>,
+    /// Every thread started and not yet joined, including the ones told
+    /// to stop by shrinking the pool
+    threads: Mutex<Vec<Worker>>,
+    next_id: AtomicUsize,
+    /// Set once the pool is dropped
+    closed: AtomicBool,
+}
+
+impl Workers {
+    fn resize(&self, size: usize, sender: &queue::Sender<Message>, counters: &Arc<Counters>) -> Result<usize, PoolResizeError> {
+        if size == 0 {
+            return Err(PoolResizeError::new("Cannot resize a thread pool to 0 threads."));
+        }
+        let mut threads = self.threads.lock().unwrap();
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(PoolResizeError::new("The thread pool has been dropped."));
+        }
+
+        // Join the workers that stopped after an earlier shrink
+        let (stopped, running): (Vec<Worker>, Vec<Worker>) = threads
+            .drain(..)
+            .partition(|worker| worker.thread.as_ref().is_none_or(|thread| thread.is_finished()));
+        *threads = running;
+        for mut worker in stopped {
+            if let Some(thread) = worker.thread.take() {
+                let _ = thread.join();
+            }
+        }
 
-        self.sender.send(Message::NewJob(job)).unwrap();
+        let previous = counters.size.swap(size, Ordering::SeqCst);
+        self.scheduler.permits.set_capacity(size);
+        if size > previous {
+            for _ in previous..size {
+                let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                threads.push(Worker::new(id, self.receiver.clone(), Arc::clone(counters), Arc::clone(&self.scheduler)));
+            }
+        } else {
+            for _ in size..previous {
+                let _ = sender.send(Message::Terminate);
+            }
+        }
+        Ok(previous)
     }
 }
 
@@ -66,14 +558,23 @@ impl Drop for ThreadPool {
 	/// 
 	/// Panics if the receiver goes out of scope or if thread joining fails.
     fn drop(&mut self) {
-        println!("Sending terminate message to all workers");
+        log::debug("Sending terminate message to all workers");
 
-        for _ in &self.workers {
+        // Taken out of the lock, so jobs resizing the pool while it is
+        // dropped get an error instead of waiting for it forever
+        let mut threads = {
+            let mut threads = self.workers.threads.lock().unwrap();
+            self.workers.closed.store(true, Ordering::SeqCst);
+            std::mem::take(&mut *threads)
+        };
+        // Workers that are already stopping got their message when the
+        // pool was shrunk
+        for _ in 0..self.counters.size.load(Ordering::SeqCst) {
             self.sender.send(Message::Terminate).unwrap();
         }
         
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+        for worker in threads.iter_mut() {
+            log::debug(&format!("Shutting down worker {}", worker.id));
             
             if let Some(thread) = worker.thread.take() {
                 thread.join().unwrap();
@@ -82,12 +583,559 @@ impl Drop for ThreadPool {
     }
 }
 
+/// Counters shared between the pool and its workers
+struct Counters {
+    size: AtomicUsize,
+    queued: AtomicUsize,
+    active: AtomicUsize,
+    completed: AtomicUsize,
+    /// Longest time a job waited for a worker, in nanoseconds
+    max_queue_wait: AtomicU64,
+    /// How long the most recent jobs waited for a worker
+    recent_waits: Mutex<VecDeque<Duration>>,
+    hooks: Mutex<Vec<JobHook>>,
+}
+
+type JobHook = Box<dyn Fn(&JobStarted) + Send + Sync + 'static>;
+
+impl Counters {
+    fn new(size: usize) -> Counters {
+        Counters {
+            size: AtomicUsize::new(size),
+            queued: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+            completed: AtomicUsize::new(0),
+            max_queue_wait: AtomicU64::new(0),
+            recent_waits: Mutex::new(VecDeque::with_capacity(QUEUE_WAIT_WINDOW)),
+            hooks: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Record that a worker started a job
+    fn started(&self, job: &JobStarted) {
+        let nanos = job.queue_wait.as_nanos().min(u64::MAX as u128) as u64;
+        self.max_queue_wait.fetch_max(nanos, Ordering::SeqCst);
+        {
+            let mut waits = self.recent_waits.lock().unwrap();
+            if waits.len() == QUEUE_WAIT_WINDOW {
+                waits.pop_front();
+            }
+            waits.push_back(job.queue_wait);
+        }
+        for hook in self.hooks.lock().unwrap().iter() {
+            hook(job);
+        }
+    }
+}
+
+/// A job a worker started, as passed to `ThreadPool::on_job_started`
+/// hooks
+#[derive(Debug, Clone)]
+pub struct JobStarted<'a> {
+    /// How long the job waited for a worker and its slots
+    pub queue_wait: Duration,
+    pub priority: Priority,
+    pub tag: Option<&'a str>,
+    pub weight: usize,
+}
+
+/// A cloneable, read-only view of a ThreadPool's internal counters
+#[derive(Clone)]
+pub struct PoolMonitor {
+    counters: Arc<Counters>,
+}
+
+impl PoolMonitor {
+    /// Number of worker threads in the pool
+    pub fn size(&self) -> usize {
+        self.counters.size.load(Ordering::SeqCst)
+    }
+
+    /// Number of jobs waiting for a free worker
+    pub fn queued_jobs(&self) -> usize {
+        self.counters.queued.load(Ordering::SeqCst)
+    }
+
+    /// Number of jobs currently being executed
+    pub fn active_jobs(&self) -> usize {
+        self.counters.active.load(Ordering::SeqCst)
+    }
+
+    /// Number of jobs that have finished executing
+    pub fn completed_jobs(&self) -> usize {
+        self.counters.completed.load(Ordering::SeqCst)
+    }
+
+    /// Longest time a job has waited for a worker so far
+    pub fn max_queue_wait(&self) -> Duration {
+        Duration::from_nanos(self.counters.max_queue_wait.load(Ordering::SeqCst))
+    }
+
+    /// How long recent jobs waited for a worker, as the quantile of the
+    /// waits of the last 1024 jobs, zero before any job started
+    ///
+    /// # Arguments
+    ///
+    /// q - The quantile, such as 0.95 for the wait 95% of the jobs did
+    /// not exceed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the queue wait mutex is in a poisoned state.
+    pub fn queue_wait_quantile(&self, q: f64) -> Duration {
+        let mut sorted: Vec<Duration> = self.counters.recent_waits.lock().unwrap().iter().copied().collect();
+        if sorted.is_empty() {
+            return Duration::ZERO;
+        }
+        sorted.sort();
+        let rank = (q.clamp(0.0, 1.0) * (sorted.len() - 1) as f64).round() as usize;
+        sorted[rank]
+    }
+
+    /// All the counters at once
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            size: self.size(),
+            queued_jobs: self.queued_jobs(),
+            active_jobs: self.active_jobs(),
+            completed_jobs: self.completed_jobs(),
+            p50_queue_wait: self.queue_wait_quantile(0.5),
+            p95_queue_wait: self.queue_wait_quantile(0.95),
+            max_queue_wait: self.max_queue_wait(),
+        }
+    }
+}
+
+/// The counters of a pool at one moment, or of several pools together,
+/// see `PoolMonitor` for what they count
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub size: usize,
+    pub queued_jobs: usize,
+    pub active_jobs: usize,
+    pub completed_jobs: usize,
+    /// Median wait of recent jobs, see `PoolMonitor::queue_wait_quantile`
+    pub p50_queue_wait: Duration,
+    /// Wait of recent jobs 95% of them did not exceed
+    pub p95_queue_wait: Duration,
+    pub max_queue_wait: Duration,
+}
+
+impl PoolStats {
+    /// Add the counters of another pool, keeping the longer waits
+    ///
+    /// Quantiles of different pools cannot be merged, so the combined
+    /// ones are those of the pool waiting longest, which is what sizing
+    /// decisions have to go by.
+    pub fn combine(self, other: PoolStats) -> PoolStats {
+        PoolStats {
+            size: self.size + other.size,
+            queued_jobs: self.queued_jobs + other.queued_jobs,
+            active_jobs: self.active_jobs + other.active_jobs,
+            completed_jobs: self.completed_jobs + other.completed_jobs,
+            p50_queue_wait: self.p50_queue_wait.max(other.p50_queue_wait),
+            p95_queue_wait: self.p95_queue_wait.max(other.p95_queue_wait),
+            max_queue_wait: self.max_queue_wait.max(other.max_queue_wait),
+        }
+    }
+}
+
+/// How urgent a job is, `Normal` for jobs submitted with `execute`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// How a job is queued, for `ThreadPool::execute_with`
+#[derive(Clone, Debug)]
+pub struct JobOptions {
+    priority: Priority,
+    key: Option<String>,
+    tag: Option<String>,
+    weight: usize,
+}
+
+impl JobOptions {
+    /// Queue a job at normal priority taking one slot, without a key or
+    /// tag
+    pub fn new() -> JobOptions {
+        JobOptions { priority: Priority::Normal, key: None, tag: None, weight: 1 }
+    }
+
+    /// Set how urgent the job is
+    pub fn with_priority(mut self, priority: Priority) -> JobOptions {
+        self.priority = priority;
+        self
+    }
+
+    /// Drop the job if one with the same key is still waiting for a
+    /// worker, see `ThreadPool::execute_keyed`
+    pub fn with_key(mut self, key: &str) -> JobOptions {
+        self.key = Some(String::from(key));
+        self
+    }
+
+    /// Put the job in a group that can be cancelled together, see
+    /// `ThreadPool::cancel_tag`
+    pub fn with_tag(mut self, tag: &str) -> JobOptions {
+        self.tag = Some(String::from(tag));
+        self
+    }
+
+    /// Set how many slots of the pool the job takes, see
+    /// `ThreadPool::execute_weighted`
+    pub fn with_weight(mut self, weight: usize) -> JobOptions {
+        self.weight = weight.max(1);
+        self
+    }
+}
+
+impl Default for JobOptions {
+    fn default() -> JobOptions {
+        JobOptions::new()
+    }
+}
+
+/// Queue a job and wake a worker for it, returning whether it was queued
+fn submit(
+    sender: &queue::Sender<Message>,
+    counters: &Counters,
+    scheduler: &Scheduler,
+    options: JobOptions,
+    job: Job,
+) -> bool {
+    // Counted first, so a worker taking the job right away never sees
+    // fewer queued jobs than it takes
+    counters.queued.fetch_add(1, Ordering::SeqCst);
+    let seq = match scheduler.push(options, job) {
+        Some(seq) => seq,
+        None => {
+            counters.queued.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+    };
+    // Every worker is gone once the pool has been dropped, so nothing
+    // would ever take the job
+    if sender.send(Message::NewJob).is_err() {
+        if scheduler.withdraw(seq) {
+            counters.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+        return false;
+    }
+    true
+}
+
+/// Drop the waiting jobs with a tag, returning how many were dropped
+fn cancel(counters: &Counters, scheduler: &Scheduler, tag: &str) -> usize {
+    // The workers woken for them find nothing to take and wait again
+    let cancelled = scheduler.cancel(tag);
+    counters.queued.fetch_sub(cancelled, Ordering::SeqCst);
+    cancelled
+}
+
+/// The jobs waiting for a worker, ordered by priority and age
+struct Scheduler {
+    /// What queue times are measured from
+    start: Instant,
+    state: Mutex<SchedulerState>,
+    /// Signalled when a job finishes while a thread is flushing
+    finished: Condvar,
+    /// The slots jobs take while they run
+    permits: Permits,
+}
+
+struct SchedulerState {
+    aging: Duration,
+    jobs: BinaryHeap<Queued>,
+    /// Keys of the waiting jobs that have one
+    keys: HashSet<String>,
+    /// Sequence numbers of the jobs that are waiting or running
+    unfinished: BTreeSet<u64>,
+    /// Threads waiting in `flush`
+    flushing: usize,
+    next_seq: u64,
+}
+
+/// A job waiting in the scheduler
+struct Queued {
+    /// When the job would have had to be queued to be as urgent at
+    /// normal priority, in nanoseconds since the scheduler started;
+    /// the job with the lowest rank runs first
+    rank: i128,
+    /// Breaks ties between equal ranks in submission order
+    seq: u64,
+    enqueued: Instant,
+    priority: Priority,
+    key: Option<String>,
+    tag: Option<String>,
+    weight: usize,
+    job: Job,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Queued) -> bool {
+        (self.rank, self.seq) == (other.rank, other.seq)
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Queued) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Queued) -> cmp::Ordering {
+        // BinaryHeap pops the greatest, which has to be the lowest rank
+        (other.rank, other.seq).cmp(&(self.rank, self.seq))
+    }
+}
+
+impl Scheduler {
+    fn new(aging: Duration, size: usize) -> Scheduler {
+        Scheduler {
+            start: Instant::now(),
+            state: Mutex::new(SchedulerState {
+                aging,
+                jobs: BinaryHeap::new(),
+                keys: HashSet::new(),
+                unfinished: BTreeSet::new(),
+                flushing: 0,
+                next_seq: 0,
+            }),
+            finished: Condvar::new(),
+            permits: Permits::new(size),
+        }
+    }
+
+    fn set_aging(&self, aging: Duration) {
+        self.state.lock().unwrap().aging = aging;
+    }
+
+    /// Queue a job, returning its sequence number, or None if one with
+    /// the same key is waiting
+    fn push(&self, options: JobOptions, job: Job) -> Option<u64> {
+        let enqueued = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if let Some(key) = &options.key {
+            if !state.keys.insert(key.clone()) {
+                return None;
+            }
+        }
+        // Every job ages at the same rate, so a job that is some levels
+        // more urgent ranks as if it had been queued that many aging
+        // periods earlier, and the order never has to be recomputed
+        let levels = options.priority as i128 - Priority::Normal as i128;
+        let rank = enqueued.duration_since(self.start).as_nanos() as i128 - levels * state.aging.as_nanos() as i128;
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.unfinished.insert(seq);
+        state.jobs.push(Queued {
+            rank,
+            seq,
+            enqueued,
+            priority: options.priority,
+            key: options.key,
+            tag: options.tag,
+            weight: options.weight,
+            job,
+        });
+        Some(seq)
+    }
+
+    /// Take the most urgent job, if any is left
+    fn pop(&self) -> Option<Queued> {
+        let mut state = self.state.lock().unwrap();
+        let queued = state.jobs.pop()?;
+        if let Some(key) = &queued.key {
+            state.keys.remove(key);
+        }
+        Some(queued)
+    }
+
+    /// Drop the waiting jobs with a tag, returning how many were dropped
+    fn cancel(&self, tag: &str) -> usize {
+        self.drop_waiting(|queued| queued.tag.as_deref() == Some(tag))
+    }
+
+    /// Drop a job if it is still waiting, returning whether it was
+    fn withdraw(&self, seq: u64) -> bool {
+        self.drop_waiting(|queued| queued.seq == seq) > 0
+    }
+
+    /// Drop the waiting jobs matching a predicate, returning how many
+    /// were dropped
+    fn drop_waiting<P: Fn(&Queued) -> bool>(&self, matches: P) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let before = state.jobs.len();
+        let keys = &mut state.keys;
+        let unfinished = &mut state.unfinished;
+        state.jobs.retain(|queued| {
+            if !matches(queued) {
+                return true;
+            }
+            if let Some(key) = &queued.key {
+                keys.remove(key);
+            }
+            unfinished.remove(&queued.seq);
+            false
+        });
+        if state.flushing > 0 {
+            self.finished.notify_all();
+        }
+        before - state.jobs.len()
+    }
+
+    /// Mark a job taken with `pop` as finished
+    fn finish(&self, seq: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.unfinished.remove(&seq);
+        if state.flushing > 0 {
+            self.finished.notify_all();
+        }
+    }
+
+    /// Wait until the jobs queued so far have finished, returning false
+    /// if the timeout passed first
+    fn flush(&self, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.state.lock().unwrap();
+        let target = state.next_seq;
+        state.flushing += 1;
+        let flushed = loop {
+            // Sequence numbers only grow, so the oldest unfinished job
+            // tells if any queued before the call is left
+            if state.unfinished.first().is_none_or(|&seq| seq >= target) {
+                break true;
+            }
+            state = match deadline {
+                None => self.finished.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break false;
+                    }
+                    self.finished.wait_timeout(state, deadline - now).unwrap().0
+                }
+            };
+        };
+        state.flushing -= 1;
+        flushed
+    }
+}
+
+/// Marks a running job as finished when dropped, even if it panics
+struct Running<'a> {
+    scheduler: &'a Scheduler,
+    seq: u64,
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.scheduler.finish(self.seq);
+    }
+}
+
+/// Counts a running job as completed when dropped
+struct Active<'a> {
+    counters: &'a Counters,
+}
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.counters.active.fetch_sub(1, Ordering::SeqCst);
+        self.counters.completed.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// A semaphore handing out the slots of a pool in the order they are
+/// asked for
+struct Permits {
+    state: Mutex<PermitState>,
+    released: Condvar,
+}
+
+struct PermitState {
+    capacity: usize,
+    in_use: usize,
+    /// The ticket of the next worker to ask for slots
+    next_ticket: u64,
+    /// The ticket of the worker whose turn it is
+    serving: u64,
+    waiting: usize,
+}
+
+impl Permits {
+    fn new(capacity: usize) -> Permits {
+        Permits {
+            state: Mutex::new(PermitState { capacity, in_use: 0, next_ticket: 0, serving: 0, waiting: 0 }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait for slots, which are given back when the guard is dropped;
+    /// fewer are taken than asked for if the pool is smaller than that
+    fn acquire(&self, weight: usize) -> PermitGuard<'_> {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        loop {
+            let weight = weight.min(state.capacity);
+            if state.serving == ticket && state.in_use + weight <= state.capacity {
+                state.in_use += weight;
+                state.serving += 1;
+                if state.waiting > 0 {
+                    // The next in line may fit as well
+                    self.released.notify_all();
+                }
+                return PermitGuard { permits: self, weight };
+            }
+            state.waiting += 1;
+            state = self.released.wait(state).unwrap();
+            state.waiting -= 1;
+        }
+    }
+
+    fn release(&self, weight: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.in_use -= weight;
+        if state.waiting > 0 {
+            self.released.notify_all();
+        }
+    }
+
+    /// Follow a resize of the pool; slots beyond a smaller capacity are
+    /// not handed out again once released
+    fn set_capacity(&self, capacity: usize) {
+        self.state.lock().unwrap().capacity = capacity;
+        self.released.notify_all();
+    }
+}
+
+/// Slots taken by a running job, given back even if the job panics
+struct PermitGuard<'a> {
+    permits: &'a Permits,
+    weight: usize,
+}
+
+impl Drop for PermitGuard<'_> {
+    fn drop(&mut self) {
+        self.permits.release(self.weight);
+    }
+}
+
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
 /// An enum containing the types of messages that Workers understand
 enum Message {
-	/// Message contains a new job
-    NewJob(Job),
+	/// A new job is waiting in the scheduler
+    NewJob,
 	/// Message to terminate
     Terminate,
 }
@@ -104,22 +1152,48 @@ impl Worker {
 	///
 	/// id - id of the worker
 	/// receiver - a shared mutable receiver used to receive jobs
+	/// counters - the pool's shared job counters
+	/// scheduler - the queue the jobs are taken from
 	///
 	/// # Panics
 	///
 	/// Panics if mutex is in a poisoned state, or if the sending side of the channel
 	/// has shut down 
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
+    fn new(id: usize, receiver: queue::Receiver<Message>, counters: Arc<Counters>, scheduler: Arc<Scheduler>) -> Worker {
         let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
+            let message = receiver.recv().unwrap();
             
             match message {
-                Message::NewJob(job) => {
-                    println!("Worker {} got a job: executing.", id);
-                    job();
+                Message::NewJob => {
+                    let queued = match scheduler.pop() {
+                        Some(queued) => queued,
+                        None => continue,
+                    };
+                    let job = queued.job;
+                    let running = Running { scheduler: &scheduler, seq: queued.seq };
+                    // Waiting for slots counts as waiting in the queue
+                    let permits = scheduler.permits.acquire(queued.weight);
+                    counters.started(&JobStarted {
+                        queue_wait: queued.enqueued.elapsed(),
+                        priority: queued.priority,
+                        tag: queued.tag.as_deref(),
+                        weight: queued.weight,
+                    });
+                    log::debug(&format!("Worker {} got a job: executing.", id));
+                    counters.queued.fetch_sub(1, Ordering::SeqCst);
+                    counters.active.fetch_add(1, Ordering::SeqCst);
+                    let active = Active { counters: &counters };
+                    // A panicking job must neither take the worker down
+                    // with it nor leave it counted as busy
+                    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                        log::error(&format!("Job on worker {} panicked: {}", id, recover::message(&*payload)));
+                    }
+                    drop(active);
+                    drop(permits);
+                    drop(running);
                 }
                 Message::Terminate => {
-                    println!("Worker {} was told to terminate", id);
+                    log::debug(&format!("Worker {} was told to terminate", id));
                     break;
                 }
             }
@@ -155,3 +1229,26 @@ impl Error for PoolCreationError {
     }
 }
 
+
+#[derive(Debug)]
+pub struct PoolResizeError {
+    details: String,
+}
+
+impl PoolResizeError {
+    fn new(details: &str) -> PoolResizeError {
+        PoolResizeError{details: String::from(details)}
+    }
+}
+
+impl fmt::Display for PoolResizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for PoolResizeError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
@@ -0,0 +1,330 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What load to generate against which server
+#[derive(Clone)]
+pub struct LoadConfig {
+    /// The `http://` URL every request is sent to
+    pub url: String,
+    /// The method of every request
+    pub method: String,
+    /// Additional headers sent with every request
+    pub headers: Vec<(String, String)>,
+    /// Number of connections kept open at the same time, each sending
+    /// its next request as soon as the previous response arrived
+    pub connections: usize,
+    /// How long requests are sent for
+    pub duration: Duration,
+    /// How long to wait for a response before counting it as an error
+    pub timeout: Duration,
+}
+
+impl Default for LoadConfig {
+    fn default() -> LoadConfig {
+        LoadConfig {
+            url: String::from("http://127.0.0.1:7878/"),
+            method: String::from("GET"),
+            headers: Vec::new(),
+            connections: 16,
+            duration: Duration::from_secs(10),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The outcome of a load test
+pub struct Report {
+    /// How long requests were sent for
+    pub elapsed: Duration,
+    /// Number of responses received
+    pub requests: u64,
+    /// Number of requests that failed without a response
+    pub errors: u64,
+    /// Number of responses per status code
+    pub statuses: BTreeMap<u16, u64>,
+    /// Bytes received, including heads
+    pub bytes: u64,
+    /// Time from sending each request until its response was read, sorted
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    /// Responses received per second
+    pub fn requests_per_second(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.requests as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    /// The latency that the given fraction of responses did not exceed,
+    /// e.g. 0.99 for the 99th percentile, or None without responses
+    pub fn percentile(&self, fraction: f64) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        let rank = (fraction.clamp(0.0, 1.0) * self.latencies.len() as f64).ceil() as usize;
+        Some(self.latencies[rank.saturating_sub(1)])
+    }
+
+    /// The mean latency, or None without responses
+    pub fn mean(&self) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        Some(self.latencies.iter().sum::<Duration>() / self.latencies.len() as u32)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |latency: Option<Duration>| latency.map_or(0.0, |l| l.as_secs_f64() * 1000.0);
+        writeln!(
+            f,
+            "{} requests in {:.2}s, {:.1} requests/s, {} errors, {} bytes read",
+            self.requests,
+            self.elapsed.as_secs_f64(),
+            self.requests_per_second(),
+            self.errors,
+            self.bytes
+        )?;
+        writeln!(
+            f,
+            "Latency: mean {:.2}ms, p50 {:.2}ms, p90 {:.2}ms, p99 {:.2}ms, p99.9 {:.2}ms, max {:.2}ms",
+            ms(self.mean()),
+            ms(self.percentile(0.5)),
+            ms(self.percentile(0.9)),
+            ms(self.percentile(0.99)),
+            ms(self.percentile(0.999)),
+            ms(self.percentile(1.0))
+        )?;
+        let statuses: Vec<String> = self.statuses.iter().map(|(status, n)| format!("{}: {}", status, n)).collect();
+        write!(f, "Statuses: {}", statuses.join(", "))
+    }
+}
+
+/// What a single connection measured
+#[derive(Default)]
+struct Tally {
+    errors: u64,
+    statuses: BTreeMap<u16, u64>,
+    bytes: u64,
+    latencies: Vec<Duration>,
+}
+
+/// Send requests over keep-alive connections for the configured
+/// duration and measure how fast responses arrive
+///
+/// Connections that fail or are closed by the server are opened again.
+///
+/// # Errors
+///
+/// Returns an error if the URL is not a valid `http://` URL or the
+/// number of connections is zero.
+pub fn run(config: &LoadConfig) -> io::Result<Report> {
+    let (address, request) = prepare(config)?;
+    if config.connections == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "At least one connection is needed."));
+    }
+
+    let start = Instant::now();
+    let deadline = start + config.duration;
+    let tallies: Vec<Tally> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..config.connections)
+            .map(|_| scope.spawn(|| drive(&address, &request, deadline, config.timeout)))
+            .collect();
+        workers.into_iter().map(|worker| worker.join().unwrap_or_default()).collect()
+    });
+    let elapsed = start.elapsed();
+
+    let mut report = Report {
+        elapsed,
+        requests: 0,
+        errors: 0,
+        statuses: BTreeMap::new(),
+        bytes: 0,
+        latencies: Vec::new(),
+    };
+    for tally in tallies {
+        report.errors += tally.errors;
+        report.bytes += tally.bytes;
+        for (status, n) in tally.statuses {
+            *report.statuses.entry(status).or_insert(0) += n;
+        }
+        report.latencies.extend(tally.latencies);
+    }
+    report.requests = report.latencies.len() as u64;
+    report.latencies.sort();
+    Ok(report)
+}
+
+/// Work out the address to connect to and the request to send
+fn prepare(config: &LoadConfig) -> io::Result<(String, Vec<u8>)> {
+    let rest = config
+        .url
+        .strip_prefix("http://")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Only http:// URLs are supported."))?;
+    let (authority, target) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "The URL has no host."));
+    }
+    // A bracketed IPv6 address contains colons without having a port
+    let has_port = authority.rsplit_once(':').is_some_and(|(_, port)| !port.contains(']'));
+    let address = if has_port { String::from(authority) } else { format!("{}:80", authority) };
+
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", config.method, target, authority);
+    for (name, value) in &config.headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    Ok((address, request.into_bytes()))
+}
+
+/// Keep one connection busy until the deadline
+fn drive(address: &str, request: &[u8], deadline: Instant, timeout: Duration) -> Tally {
+    let head = request.starts_with(b"HEAD ");
+    let mut tally = Tally::default();
+    let mut connection: Option<BufReader<TcpStream>> = None;
+
+    while Instant::now() < deadline {
+        let reader = match &mut connection {
+            Some(reader) => reader,
+            None => match connect(address, timeout) {
+                Ok(stream) => connection.insert(BufReader::new(stream)),
+                Err(_) => {
+                    tally.errors += 1;
+                    // Do not spin on a server that refuses connections
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+            },
+        };
+
+        let sent = Instant::now();
+        let result = reader.get_mut().write_all(request).and_then(|_| read_response(reader, head));
+        match result {
+            Ok((status, bytes, keep_alive)) => {
+                tally.latencies.push(sent.elapsed());
+                *tally.statuses.entry(status).or_insert(0) += 1;
+                tally.bytes += bytes;
+                if !keep_alive {
+                    connection = None;
+                }
+            }
+            Err(_) => {
+                tally.errors += 1;
+                connection = None;
+            }
+        }
+    }
+    tally
+}
+
+fn connect(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(address)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+/// Read a response, returning its status, its size in bytes and
+/// whether the connection can be used for another request
+///
+/// # Arguments
+///
+/// reader - The connection the request was sent on.
+/// head - Whether the request was a HEAD request, whose response has no body.
+fn read_response<R: BufRead>(reader: &mut R, head: bool) -> io::Result<(u16, u64, bool)> {
+    let invalid = |details: &str| io::Error::new(io::ErrorKind::InvalidData, details.to_string());
+
+    let mut line = String::new();
+    let mut bytes = read_line(reader, &mut line)?;
+    let status: u16 = line
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid("Malformed status line."))?;
+    let http_10 = line.starts_with("HTTP/1.0");
+
+    let mut length = None;
+    let mut chunked = false;
+    let mut close = http_10;
+    loop {
+        bytes += read_line(reader, &mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':').ok_or_else(|| invalid("Malformed header."))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("Content-Length") {
+            length = Some(value.parse::<u64>().map_err(|_| invalid("Invalid Content-Length."))?);
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        } else if name.eq_ignore_ascii_case("Connection") {
+            close = value.eq_ignore_ascii_case("close") || (http_10 && !value.eq_ignore_ascii_case("keep-alive"));
+        }
+    }
+
+    if status < 200 {
+        // An interim response is followed by the real one
+        let (status, more, keep_alive) = read_response(reader, head)?;
+        return Ok((status, bytes + more, keep_alive));
+    }
+    if head || status == 204 || status == 304 {
+        // No body, whatever the headers say
+    } else if chunked {
+        loop {
+            bytes += read_line(reader, &mut line)?;
+            let size = line.split(';').next().unwrap_or("").trim();
+            let size = u64::from_str_radix(size, 16).map_err(|_| invalid("Invalid chunk size."))?;
+            if size == 0 {
+                // Skip any trailers up to the empty line
+                loop {
+                    bytes += read_line(reader, &mut line)?;
+                    if line.trim_end().is_empty() {
+                        break;
+                    }
+                }
+                break;
+            }
+            bytes += skip(reader, size)?;
+            bytes += read_line(reader, &mut line)?;
+        }
+    } else if let Some(length) = length {
+        bytes += skip(reader, length)?;
+    } else {
+        // The body ends with the connection
+        bytes += io::copy(reader, &mut io::sink())?;
+        close = true;
+    }
+    Ok((status, bytes, !close))
+}
+
+/// Read a line into the buffer, failing at the end of input
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> io::Result<u64> {
+    line.clear();
+    match reader.read_line(line)? {
+        0 => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed.")),
+        read => Ok(read as u64),
+    }
+}
+
+/// Discard exactly `length` bytes
+fn skip<R: BufRead>(reader: &mut R, length: u64) -> io::Result<u64> {
+    let skipped = io::copy(&mut reader.take(length), &mut io::sink())?;
+    if skipped != length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed in the middle of a body."));
+    }
+    Ok(skipped)
+}
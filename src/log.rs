@@ -0,0 +1,463 @@
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::date;
+use crate::json::Value;
+use crate::trace_context::{self, SpanContext};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+/// The least severe level of the events that are logged
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static NEXT_REQUEST: AtomicU64 = AtomicU64::new(0);
+static SEED: OnceLock<u32> = OnceLock::new();
+static OUTPUT: Mutex<Option<Output>> = Mutex::new(None);
+/// Set from a signal handler when the log file should be reopened
+static REOPEN: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// The request being handled on this thread, included in the
+    /// events logged while handling it
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// How log lines are written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Plain messages, and access lines similar to the common log format
+    Text,
+    /// One JSON object per line, for log collectors such as Loki or
+    /// Elasticsearch
+    Json,
+}
+
+/// How severe a logged event is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// What the thread pool's workers are doing, too much for production
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+
+    /// Parse a level name such as `warn`, ignoring case
+    pub fn parse(name: &str) -> Option<Level> {
+        [Level::Debug, Level::Info, Level::Warn, Level::Error]
+            .iter()
+            .copied()
+            .find(|level| level.as_str().eq_ignore_ascii_case(name.trim()))
+    }
+}
+
+/// Set the format of all lines logged from now on, text by default
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::SeqCst);
+}
+
+/// The format lines are currently logged in
+pub fn format() -> LogFormat {
+    if JSON.load(Ordering::SeqCst) {
+        LogFormat::Json
+    } else {
+        LogFormat::Text
+    }
+}
+
+pub fn debug(message: &str) {
+    log(Level::Debug, message);
+}
+
+pub fn info(message: &str) {
+    log(Level::Info, message);
+}
+
+pub fn warn(message: &str) {
+    log(Level::Warn, message);
+}
+
+pub fn error(message: &str) {
+    log(Level::Error, message);
+}
+
+/// Set the least severe level of the events logged from now on, info
+/// by default
+///
+/// Access lines are logged whatever the level.
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::SeqCst);
+}
+
+/// The least severe level of the events that are logged
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::SeqCst) {
+        0 => Level::Debug,
+        1 => Level::Info,
+        2 => Level::Warn,
+        _ => Level::Error,
+    }
+}
+
+/// Log an event, tagged with the id and trace context of the request
+/// handled on the current thread if there is one
+pub fn log(level: Level, message: &str) {
+    if level < self::level() {
+        return;
+    }
+    match format() {
+        LogFormat::Text => write_line(message),
+        LogFormat::Json => {
+            let mut event = Value::object()
+                .with("timestamp", date::rfc3339(SystemTime::now()))
+                .with("level", level.as_str())
+                .with("message", message);
+            if let Some(id) = request_id() {
+                event = event.with("request_id", id);
+            }
+            if let Some(trace) = trace_context::current() {
+                event = event
+                    .with("trace_id", trace.span.trace_id_hex())
+                    .with("span_id", trace.span.span_id_hex());
+            }
+            write_line(&event.to_string());
+        }
+    }
+}
+
+/// A request that has been answered
+pub struct Access<'a> {
+    pub request_id: &'a str,
+    pub method: &'a str,
+    pub target: &'a str,
+    pub version: &'a str,
+    /// The pattern of the route that matched, if any
+    pub route: Option<&'a str>,
+    pub status: u16,
+    /// Bytes written for the response, including its head
+    pub bytes: u64,
+    pub duration: Duration,
+    pub client_ip: Option<IpAddr>,
+    /// The subject of the certificate the client presented
+    pub client_cert: Option<&'a str>,
+    /// The span the request was handled in
+    pub trace: Option<&'a SpanContext>,
+}
+
+/// Log an answered request
+pub fn access(record: &Access) {
+    let line = match format() {
+        LogFormat::Text => format!(
+            "{} - [{}] \"{} {} {}\" {} {} {:.1}ms{}",
+            record.client_ip.map(|ip| ip.to_string()).unwrap_or_else(|| String::from("-")),
+            date::rfc3339(SystemTime::now()),
+            record.method,
+            record.target,
+            record.version,
+            record.status,
+            record.bytes,
+            record.duration.as_secs_f64() * 1000.0,
+            record.client_cert.map(|subject| format!(" cert={:?}", subject)).unwrap_or_default()
+        ),
+        LogFormat::Json => Value::object()
+            .with("timestamp", date::rfc3339(SystemTime::now()))
+            .with("level", Level::Info.as_str())
+            .with("message", "request")
+            .with("request_id", record.request_id)
+            .with("method", record.method)
+            .with("target", record.target)
+            .with("version", record.version)
+            .with("route", record.route)
+            .with("status", record.status)
+            .with("bytes", record.bytes)
+            .with("duration_ms", record.duration.as_secs_f64() * 1000.0)
+            .with("client_ip", record.client_ip.map(|ip| ip.to_string()))
+            .with("client_cert", record.client_cert)
+            .with("trace_id", record.trace.map(SpanContext::trace_id_hex))
+            .with("span_id", record.trace.map(SpanContext::span_id_hex))
+            .to_string(),
+    };
+    write_line(&line);
+}
+
+/// A request that took longer to answer than the server's threshold,
+/// with where the time went
+pub struct SlowRequest<'a> {
+    pub request_id: &'a str,
+    pub method: &'a str,
+    pub target: &'a str,
+    /// The pattern of the route that matched, if any
+    pub route: Option<&'a str>,
+    pub status: u16,
+    /// From when the server started reading the request until the
+    /// response was written
+    pub duration: Duration,
+    /// Spent reading and parsing the request head, and the body unless
+    /// it was streamed to the handler
+    pub read: Duration,
+    /// Spent waiting for a worker to run the handler on
+    pub queued: Duration,
+    /// Spent in the handler, including middleware
+    pub handler: Duration,
+    /// Spent writing the response
+    pub write: Duration,
+    /// The span the request was handled in
+    pub trace: Option<&'a SpanContext>,
+}
+
+/// Log a warning about a slow request, if warnings are logged
+pub fn slow_request(record: &SlowRequest) {
+    if Level::Warn < level() {
+        return;
+    }
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    let line = match format() {
+        LogFormat::Text => format!(
+            "Slow request {} \"{} {}\" route {} status {}: {:.1}ms (read {:.1}ms, queued {:.1}ms, handler {:.1}ms, write {:.1}ms)",
+            record.request_id,
+            record.method,
+            record.target,
+            record.route.unwrap_or("-"),
+            record.status,
+            ms(record.duration),
+            ms(record.read),
+            ms(record.queued),
+            ms(record.handler),
+            ms(record.write)
+        ),
+        LogFormat::Json => Value::object()
+            .with("timestamp", date::rfc3339(SystemTime::now()))
+            .with("level", Level::Warn.as_str())
+            .with("message", "slow request")
+            .with("request_id", record.request_id)
+            .with("method", record.method)
+            .with("target", record.target)
+            .with("route", record.route)
+            .with("status", record.status)
+            .with("duration_ms", ms(record.duration))
+            .with("read_ms", ms(record.read))
+            .with("queued_ms", ms(record.queued))
+            .with("handler_ms", ms(record.handler))
+            .with("write_ms", ms(record.write))
+            .with("trace_id", record.trace.map(SpanContext::trace_id_hex))
+            .with("span_id", record.trace.map(SpanContext::span_id_hex))
+            .to_string(),
+    };
+    write_line(&line);
+}
+
+/// The id of the request handled on the current thread
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.with(|id| id.borrow().clone())
+}
+
+/// Create an id unique within this run of the server
+pub fn next_request_id() -> String {
+    // Distinguish restarts by mixing in the start time and process
+    let seed = *SEED.get_or_init(|| {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+        nanos ^ std::process::id().rotate_left(16)
+    });
+    let count = NEXT_REQUEST.fetch_add(1, Ordering::Relaxed);
+    format!("{:08x}{:08x}", seed, count as u32)
+}
+
+/// Run a function with a request id set for the events it logs
+pub(crate) fn with_request_id<T, F: FnOnce() -> T>(id: &str, f: F) -> T {
+    let previous = REQUEST_ID.with(|current| current.replace(Some(String::from(id))));
+    let result = f();
+    REQUEST_ID.with(|current| *current.borrow_mut() = previous);
+    result
+}
+
+/// When the log file is rotated
+#[derive(Clone, Debug)]
+pub struct Rotation {
+    /// Rotate once the file has grown to this many bytes
+    pub max_size: Option<u64>,
+    /// Rotate once the file has been written to for this long
+    pub interval: Option<Duration>,
+    /// Number of rotated files kept as `<file>.1`, `<file>.2` and so on,
+    /// where a higher number is older
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    /// Never rotate, keeping seven files once a limit is set
+    fn default() -> Rotation {
+        Rotation { max_size: None, interval: None, keep: 7 }
+    }
+}
+
+/// The file standard output and error are redirected to
+struct Output {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    opened: Instant,
+}
+
+/// Send standard output and error, and with them every log line, to a
+/// file instead of the terminal
+///
+/// The file is rotated according to the rotation settings, and reopened
+/// on SIGHUP or SIGUSR1 so external tools such as logrotate can move it
+/// away. Both happen when the next line is logged. Unix only.
+///
+/// # Arguments
+///
+/// path - The file to append to, which is created if it does not exist.
+/// rotation - When to move the file aside and start a new one.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be opened or the standard
+/// streams cannot be redirected to it.
+pub fn log_to_file<P: AsRef<Path>>(path: P, rotation: Rotation) -> io::Result<()> {
+    let path = path.as_ref().to_path_buf();
+    let file = open(&path)?;
+    sys::install_reopen_handler();
+    *OUTPUT.lock().unwrap() = Some(Output { path, rotation, file, opened: Instant::now() });
+    Ok(())
+}
+
+/// Reopen the log file when the next line is logged
+pub fn reopen() {
+    REOPEN.store(true, Ordering::SeqCst);
+}
+
+/// Open a log file for appending and redirect the standard streams to it
+fn open(path: &Path) -> io::Result<File> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    io::stdout().flush()?;
+    sys::redirect_stdio(&file)?;
+    Ok(file)
+}
+
+impl Output {
+    fn needs_rotation(&self) -> bool {
+        let too_large = self
+            .rotation
+            .max_size
+            .is_some_and(|max| self.file.metadata().map(|m| m.len() >= max).unwrap_or(false));
+        let too_old = self.rotation.interval.is_some_and(|interval| self.opened.elapsed() >= interval);
+        too_large || too_old
+    }
+
+    /// Shift the rotated files up by one, dropping the oldest, and start
+    /// a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        let numbered = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(numbered(self.rotation.keep));
+            for n in (1..self.rotation.keep).rev() {
+                let _ = fs::rename(numbered(n), numbered(n + 1));
+            }
+            fs::rename(&self.path, numbered(1))?;
+        }
+        self.reopen()
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.file = open(&self.path)?;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+fn write_line(line: &str) {
+    let mut output = OUTPUT.lock().unwrap();
+    if let Some(output) = output.as_mut() {
+        let result = if REOPEN.swap(false, Ordering::SeqCst) {
+            output.reopen()
+        } else if output.needs_rotation() {
+            output.rotate()
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            // Keep writing to the current file rather than losing lines
+            let _ = writeln!(io::stderr(), "Failed to rotate log file {}: {}", output.path.display(), e);
+        }
+    }
+
+    // A full disk must not take the server down, so errors are ignored
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let _ = writeln!(out, "{}", line);
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::raw::c_int;
+    use std::os::unix::io::AsRawFd;
+
+    const SIGHUP: c_int = 1;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const SIGUSR1: c_int = 10;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const SIGUSR1: c_int = 30;
+
+    extern "C" {
+        fn dup2(old: c_int, new: c_int) -> c_int;
+        fn signal(signal: c_int, handler: extern "C" fn(c_int)) -> usize;
+    }
+
+    pub fn redirect_stdio(file: &File) -> io::Result<()> {
+        for fd in 1..=2 {
+            if unsafe { dup2(file.as_raw_fd(), fd) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Only an atomic store is safe in a signal handler, so the file is
+    /// reopened by the next write
+    extern "C" fn on_signal(_signal: c_int) {
+        super::reopen();
+    }
+
+    pub fn install_reopen_handler() {
+        unsafe {
+            signal(SIGHUP, on_signal);
+            signal(SIGUSR1, on_signal);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::fs::File;
+    use std::io;
+
+    pub fn redirect_stdio(_file: &File) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Logging to a file is not supported on this platform."))
+    }
+
+    pub fn install_reopen_handler() {}
+}
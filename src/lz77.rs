@@ -0,0 +1,197 @@
+// LZ77 matching shared by the DEFLATE and Brotli encoders: input is
+// split into literals and copies of earlier input, found through hash
+// chains over a sliding window
+
+/// Bits of the hash of the bytes a match starts with
+const HASH_BITS: u32 = 15;
+
+/// How far back a match of the minimum length may reach; farther ones
+/// cost more to encode than the literals they replace
+const MAX_SHORT_DISTANCE: usize = 4096;
+
+/// A piece of parsed input
+#[derive(Clone, Copy)]
+pub(crate) enum Token {
+    Literal(u8),
+    /// A copy of `length` bytes starting `distance` bytes back
+    Match {
+        length: u32,
+        distance: u32,
+    },
+}
+
+/// What the format allows for matches and how hard to look for them
+pub(crate) struct Params {
+    /// The farthest a match may reach back
+    pub window: usize,
+    /// The shortest match, 3 or 4 bytes
+    pub min_length: usize,
+    pub max_length: usize,
+    /// How many earlier positions with the same hash are tried
+    pub max_chain: usize,
+    /// Whether to emit a literal instead of a match when a longer one
+    /// starts at the next byte
+    pub lazy: bool,
+}
+
+/// Parses input fed to it in pieces, keeping a window of what it already
+/// parsed for later matches to refer to
+pub(crate) struct Matcher {
+    params: Params,
+    data: Vec<u8>,
+    /// The last position with each hash, plus one, or 0
+    head: Vec<u32>,
+    /// The previous position with the same hash as each position, plus
+    /// one, or 0
+    prev: Vec<u32>,
+    /// Positions before this are in the hash chains
+    hashed: usize,
+    /// Positions before this have been parsed
+    parsed: usize,
+    /// Where the input last parsed starts
+    block_start: usize,
+}
+
+impl Matcher {
+    pub(crate) fn new(params: Params) -> Matcher {
+        debug_assert!((3..=4).contains(&params.min_length));
+        Matcher {
+            params,
+            data: Vec::new(),
+            head: vec![0; 1 << HASH_BITS],
+            prev: Vec::new(),
+            hashed: 0,
+            parsed: 0,
+            block_start: 0,
+        }
+    }
+
+    /// Add input to parse
+    pub(crate) fn push(&mut self, input: &[u8]) {
+        self.slide();
+        self.data.extend_from_slice(input);
+        self.prev.resize(self.data.len(), 0);
+    }
+
+    /// The number of bytes pushed but not yet parsed
+    pub(crate) fn pending(&self) -> usize {
+        self.data.len() - self.parsed
+    }
+
+    /// Parse pending input into tokens, stopping once at least `limit`
+    /// bytes are covered
+    pub(crate) fn parse(&mut self, limit: usize) -> Vec<Token> {
+        self.block_start = self.parsed;
+        let end = self.data.len().min(self.parsed.saturating_add(limit));
+        let mut tokens = Vec::new();
+        let mut position = self.parsed;
+        while position < end {
+            self.hash_until(position);
+            let found = self.find(position);
+            let found = match found {
+                Some((length, _)) if self.params.lazy && length < self.params.max_length => {
+                    self.hash_until(position + 1);
+                    match self.find(position + 1) {
+                        Some((longer, _)) if longer > length => None,
+                        _ => found,
+                    }
+                }
+                found => found,
+            };
+            match found {
+                Some((length, distance)) => {
+                    tokens.push(Token::Match { length: length as u32, distance: distance as u32 });
+                    position += length;
+                }
+                None => {
+                    tokens.push(Token::Literal(self.data[position]));
+                    position += 1;
+                }
+            }
+        }
+        self.parsed = position;
+        tokens
+    }
+
+    /// The input the last call to `parse` covered
+    pub(crate) fn block(&self) -> &[u8] {
+        &self.data[self.block_start..self.parsed]
+    }
+
+    /// The longest match at a position, as its length and distance
+    fn find(&self, position: usize) -> Option<(usize, usize)> {
+        let available = self.data.len() - position;
+        if available < self.params.min_length {
+            return None;
+        }
+        let max_length = available.min(self.params.max_length);
+        let (mut best_length, mut best_distance) = (0, 0);
+        let mut candidate = self.head[self.hash(position)] as usize;
+        let mut chain = self.params.max_chain;
+        while candidate > 0 && chain > 0 {
+            let start = candidate - 1;
+            let distance = position - start;
+            if start >= position || distance > self.params.window {
+                break;
+            }
+            if self.data[start + best_length] == self.data[position + best_length] {
+                let length = self.data[start..]
+                    .iter()
+                    .zip(&self.data[position..position + max_length])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best_length {
+                    best_length = length;
+                    best_distance = distance;
+                    if length == max_length {
+                        break;
+                    }
+                }
+            }
+            candidate = self.prev[start] as usize;
+            chain -= 1;
+        }
+        let long_enough = best_length > self.params.min_length
+            || (best_length == self.params.min_length && best_distance <= MAX_SHORT_DISTANCE);
+        if long_enough {
+            Some((best_length, best_distance))
+        } else {
+            None
+        }
+    }
+
+    /// Add the positions before `end` to the hash chains
+    fn hash_until(&mut self, end: usize) {
+        let end = end.min((self.data.len() + 1).saturating_sub(self.params.min_length));
+        while self.hashed < end {
+            let hash = self.hash(self.hashed);
+            self.prev[self.hashed] = self.head[hash];
+            self.head[hash] = self.hashed as u32 + 1;
+            self.hashed += 1;
+        }
+    }
+
+    fn hash(&self, position: usize) -> usize {
+        let bytes = &self.data[position..position + self.params.min_length];
+        let value = bytes.iter().fold(0u32, |value, &byte| value << 8 | u32::from(byte));
+        (value.wrapping_mul(0x1e35_a7bd) >> (32 - HASH_BITS)) as usize
+    }
+
+    /// Drop input that matches can no longer reach, once there is at
+    /// least a window of it
+    fn slide(&mut self) {
+        let dropped = self.parsed.saturating_sub(self.params.window);
+        if dropped < self.params.window {
+            return;
+        }
+        self.data.drain(..dropped);
+        self.prev.drain(..dropped);
+        let dropped = dropped as u32;
+        for position in self.head.iter_mut().chain(self.prev.iter_mut()) {
+            *position = position.saturating_sub(dropped);
+        }
+        self.hashed -= dropped as usize;
+        self.parsed -= dropped as usize;
+        self.block_start = self.parsed;
+    }
+}
@@ -0,0 +1,76 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::log;
+use crate::response::{reason_phrase, Response};
+
+/// How the server answers while it is in maintenance, which the admin
+/// endpoint's `PUT /maintenance` turns on and off without a restart
+///
+/// Requests for any path but the allowed ones and the metrics path are
+/// answered with 503 and a Retry-After header while it is on, so
+/// deploys and migrations can run behind a "be right back" page. The
+/// admin endpoint keeps working, as it listens on an address of its own.
+///
+/// ```no_run
+/// use server::config::Config;
+/// use server::maintenance::Maintenance;
+/// use server::server::Server;
+///
+/// let maintenance = Maintenance {
+///     page: Some("static/maintenance.html".into()),
+///     allowed: vec![String::from("/healthz")],
+///     ..Maintenance::default()
+/// };
+/// let server = Server::new(Config { maintenance, ..Config::default() }).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Maintenance {
+    /// Whether the server starts out in maintenance
+    pub enabled: bool,
+    /// HTML file sent as the body of the 503, read anew for every
+    /// response so it can be changed while in maintenance, or None for
+    /// a plain text one
+    pub page: Option<PathBuf>,
+    /// How long clients are asked to wait before trying again, sent as
+    /// a Retry-After header
+    pub retry_after: Duration,
+    /// Paths served as usual, such as health checks, each together with
+    /// the paths below it
+    pub allowed: Vec<String>,
+}
+
+impl Default for Maintenance {
+    fn default() -> Maintenance {
+        Maintenance { enabled: false, page: None, retry_after: Duration::from_secs(300), allowed: Vec::new() }
+    }
+}
+
+impl Maintenance {
+    /// Whether a request path is served as usual during maintenance
+    pub(crate) fn allows(&self, path: &str) -> bool {
+        self.allowed.iter().any(|allowed| {
+            let allowed = allowed.trim_end_matches('/');
+            path.strip_prefix(allowed).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+
+    /// The response to requests refused during maintenance
+    pub(crate) fn response(&self) -> Response {
+        // Retry-After counts whole seconds, and zero would invite an
+        // immediate retry
+        let seconds = self.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        let response = match &self.page {
+            Some(page) => match fs::read(page) {
+                Ok(html) => Response::html(503, html),
+                Err(e) => {
+                    log::error(&format!("Failed to read maintenance page {}: {}", page.display(), e));
+                    Response::text(503, reason_phrase(503))
+                }
+            },
+            None => Response::text(503, reason_phrase(503)),
+        };
+        response.with_header("Retry-After", &seconds.to_string()).with_header("Cache-Control", "no-store")
+    }
+}
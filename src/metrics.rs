@@ -0,0 +1,329 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::stats::StatsSnapshot;
+use crate::PoolMonitor;
+
+/// Number of recent request durations kept for computing latency quantiles
+const LATENCY_WINDOW: usize = 1024;
+
+/// Quantiles reported for the request duration summary
+const QUANTILES: [f64; 4] = [0.5, 0.9, 0.95, 0.99];
+
+/// Quantiles reported for the time jobs wait for a worker
+const QUEUE_WAIT_QUANTILES: [f64; 2] = [0.5, 0.95];
+
+/// Upper bounds in seconds of the per-route duration histogram buckets
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Route label of requests that matched no route
+const UNMATCHED: &str = "unmatched";
+
+/// Counters collected by the server and rendered in Prometheus text format
+pub struct Metrics {
+    requests: [AtomicU64; 5],
+    in_flight: AtomicUsize,
+    shed: AtomicU64,
+    latency: Mutex<Latency>,
+    routes: Mutex<BTreeMap<(String, String), RouteMetrics>>,
+}
+
+/// Durations and statuses of the requests matching one route
+#[derive(Clone, Default)]
+struct RouteMetrics {
+    /// Count per bucket of BUCKETS, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+    statuses: [u64; 5],
+}
+
+/// The recorded durations and statuses of the requests for one route
+#[derive(Clone, Debug)]
+pub struct RouteSnapshot {
+    pub method: String,
+    /// The route pattern, or "unmatched" for requests matching no route
+    pub route: String,
+    /// Number of requests with a duration of at most each bound in
+    /// seconds, as in a Prometheus histogram
+    pub buckets: Vec<(f64, u64)>,
+    /// Total duration of all requests in seconds
+    pub sum: f64,
+    pub count: u64,
+    /// Number of responses per status class, 1xx first
+    pub statuses: [u64; 5],
+}
+
+impl RouteSnapshot {
+    /// Mean request duration in seconds, NaN without requests
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+/// Recent request durations plus running totals
+struct Latency {
+    window: VecDeque<f64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    /// Create a new set of metrics with all counters at zero
+    pub fn new() -> Metrics {
+        Metrics {
+            requests: Default::default(),
+            in_flight: AtomicUsize::new(0),
+            shed: AtomicU64::new(0),
+            latency: Mutex::new(Latency {
+                window: VecDeque::with_capacity(LATENCY_WINDOW),
+                sum: 0.0,
+                count: 0,
+            }),
+            routes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record that a request has started being handled
+    pub fn request_started(&self) {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Number of requests currently being handled
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Record that a connection or request was refused with 503 because
+    /// the server was saturated
+    pub fn shed(&self) {
+        self.shed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Record that a request has finished
+    ///
+    /// # Arguments
+    ///
+    /// status - The status code that was sent to the client.
+    /// duration - Time taken from reading the request to writing the response.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the latency mutex is in a poisoned state.
+    pub fn request_finished(&self, status: u16, duration: Duration) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.observe(status, duration);
+    }
+
+    /// Record a response that was sent without a request being handled,
+    /// e.g. a 400 for a request that could not be parsed
+    ///
+    /// # Panics
+    ///
+    /// Panics if the latency mutex is in a poisoned state.
+    pub fn observe(&self, status: u16, duration: Duration) {
+        if let Some(counter) = status_class(status).and_then(|class| self.requests.get(class - 1)) {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let seconds = duration.as_secs_f64();
+        let mut latency = self.latency.lock().unwrap();
+        if latency.window.len() == LATENCY_WINDOW {
+            latency.window.pop_front();
+        }
+        latency.window.push_back(seconds);
+        latency.sum += seconds;
+        latency.count += 1;
+    }
+
+    /// Record the duration and status of a request by the route it
+    /// matched
+    ///
+    /// Routes are labelled by their pattern rather than the path, so
+    /// the number of series stays bounded.
+    ///
+    /// # Arguments
+    ///
+    /// method - The method of the request.
+    /// route - The pattern of the matched route, or None if none matched.
+    /// status - The status code that was sent to the client.
+    /// duration - Time taken from reading the request to writing the response.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the routes mutex is in a poisoned state.
+    pub fn observe_route(&self, method: &str, route: Option<&str>, status: u16, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let key = (String::from(method), String::from(route.unwrap_or(UNMATCHED)));
+        let mut routes = self.routes.lock().unwrap();
+        let metrics = routes.entry(key).or_default();
+
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            metrics.buckets[bucket] += 1;
+        }
+        metrics.sum += seconds;
+        metrics.count += 1;
+        if let Some(class) = status_class(status) {
+            metrics.statuses[class - 1] += 1;
+        }
+    }
+
+    /// The durations and statuses recorded for each route, ordered by
+    /// route and method
+    ///
+    /// # Panics
+    ///
+    /// Panics if the routes mutex is in a poisoned state.
+    pub fn routes(&self) -> Vec<RouteSnapshot> {
+        let mut snapshots: Vec<RouteSnapshot> = self
+            .routes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((method, route), metrics)| {
+                let mut cumulative = 0;
+                let buckets = BUCKETS
+                    .iter()
+                    .zip(metrics.buckets.iter())
+                    .map(|(bound, count)| {
+                        cumulative += count;
+                        (*bound, cumulative)
+                    })
+                    .collect();
+                RouteSnapshot {
+                    method: method.clone(),
+                    route: route.clone(),
+                    buckets,
+                    sum: metrics.sum,
+                    count: metrics.count,
+                    statuses: metrics.statuses,
+                }
+            })
+            .collect();
+        snapshots.sort_by(|a, b| (&a.route, &a.method).cmp(&(&b.route, &b.method)));
+        snapshots
+    }
+
+    /// Render all metrics in the Prometheus text exposition format
+    ///
+    /// # Arguments
+    ///
+    /// pool - The pool whose queue and worker counters should be included.
+    /// connections - The server's connection statistics.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the latency mutex is in a poisoned state.
+    pub fn render(&self, pool: &PoolMonitor, connections: &StatsSnapshot) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "http_requests_total", "counter", "Total number of HTTP responses sent, by status class.");
+        for (i, counter) in self.requests.iter().enumerate() {
+            let _ = writeln!(out, "http_requests_total{{class=\"{}xx\"}} {}", i + 1, counter.load(Ordering::SeqCst));
+        }
+
+        gauge(&mut out, "http_requests_in_flight", "Number of requests currently being handled.", self.in_flight.load(Ordering::SeqCst));
+        header(&mut out, "http_requests_shed_total", "counter", "Total number of connections and requests refused because the server was saturated.");
+        let _ = writeln!(out, "http_requests_shed_total {}", self.shed.load(Ordering::SeqCst));
+
+        header(&mut out, "http_connections_total", "counter", "Total number of accepted connections.");
+        let _ = writeln!(out, "http_connections_total {}", connections.accepted);
+        gauge(&mut out, "http_connections_open", "Number of currently open connections.", connections.open() as usize);
+        gauge(&mut out, "http_connections_idle", "Number of kept-alive connections waiting for a request.", connections.idle as usize);
+        header(&mut out, "http_received_bytes_total", "counter", "Total bytes read from clients.");
+        let _ = writeln!(out, "http_received_bytes_total {}", connections.bytes_in);
+        header(&mut out, "http_sent_bytes_total", "counter", "Total bytes written to clients.");
+        let _ = writeln!(out, "http_sent_bytes_total {}", connections.bytes_out);
+
+        gauge(&mut out, "threadpool_workers", "Number of worker threads in the pool.", pool.size());
+        gauge(&mut out, "threadpool_queue_depth", "Number of jobs waiting for a free worker.", pool.queued_jobs());
+        gauge(&mut out, "threadpool_active_jobs", "Number of jobs currently being executed.", pool.active_jobs());
+        header(&mut out, "threadpool_completed_jobs_total", "counter", "Total number of jobs the pool has finished.");
+        let _ = writeln!(out, "threadpool_completed_jobs_total {}", pool.completed_jobs());
+        header(&mut out, "threadpool_max_queue_wait_seconds", "gauge", "Longest time a job has waited for a free worker.");
+        let _ = writeln!(out, "threadpool_max_queue_wait_seconds {}", pool.max_queue_wait().as_secs_f64());
+        header(&mut out, "threadpool_queue_wait_seconds", "summary", "Time recent jobs waited for a free worker.");
+        for q in QUEUE_WAIT_QUANTILES.iter() {
+            let _ = writeln!(out, "threadpool_queue_wait_seconds{{quantile=\"{}\"}} {}", q, pool.queue_wait_quantile(*q).as_secs_f64());
+        }
+
+        let latency = self.latency.lock().unwrap();
+        let mut sorted: Vec<f64> = latency.window.iter().cloned().collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        header(&mut out, "http_request_duration_seconds", "summary", "Request duration over recent requests.");
+        for q in QUANTILES.iter() {
+            let _ = writeln!(out, "http_request_duration_seconds{{quantile=\"{}\"}} {}", q, quantile(&sorted, *q));
+        }
+        let _ = writeln!(out, "http_request_duration_seconds_sum {}", latency.sum);
+        let _ = writeln!(out, "http_request_duration_seconds_count {}", latency.count);
+        drop(latency);
+
+        let routes = self.routes();
+        header(&mut out, "http_route_requests_total", "counter", "Total number of HTTP responses sent, by route and status class.");
+        for route in &routes {
+            for (i, count) in route.statuses.iter().enumerate().filter(|(_, count)| **count > 0) {
+                let _ = writeln!(out, "http_route_requests_total{{{},class=\"{}xx\"}} {}", route_labels(route), i + 1, count);
+            }
+        }
+        header(&mut out, "http_route_request_duration_seconds", "histogram", "Request duration by route.");
+        for route in &routes {
+            let labels = route_labels(route);
+            for (bound, count) in &route.buckets {
+                let _ = writeln!(out, "http_route_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, count);
+            }
+            let _ = writeln!(out, "http_route_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, route.count);
+            let _ = writeln!(out, "http_route_request_duration_seconds_sum{{{}}} {}", labels, route.sum);
+            let _ = writeln!(out, "http_route_request_duration_seconds_count{{{}}} {}", labels, route.count);
+        }
+
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+/// Map a status code to its class, 1 for 1xx through 5 for 5xx
+fn status_class(status: u16) -> Option<usize> {
+    match status {
+        100..=599 => Some((status / 100) as usize),
+        _ => None,
+    }
+}
+
+/// Get the q-quantile of an already sorted slice, NaN if it is empty
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let rank = (q * (sorted.len() - 1) as f64).round() as usize;
+    sorted[rank]
+}
+
+/// The method and route labels of a route's series
+fn route_labels(route: &RouteSnapshot) -> String {
+    format!("method=\"{}\",route=\"{}\"", escape_label(&route.method), escape_label(&route.route))
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Write the HELP and TYPE lines for a metric
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Write a complete gauge metric
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
@@ -0,0 +1,44 @@
+use std::sync::Arc;
+
+use crate::request::Request;
+use crate::response::Response;
+
+/// Code that runs around the handling of every request
+///
+/// A middleware receives the request before the route's handler and
+/// decides whether to pass it on with `next.run`, which also gives it
+/// the response to inspect or change before it is sent. Closures of
+/// the form `|request: Request, next: &Next| -> Response` implement it.
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, request: Request, next: &Next) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(Request, &Next) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: Request, next: &Next) -> Response {
+        self(request, next)
+    }
+}
+
+/// The rest of the chain after a middleware, ending at the handler
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn Middleware>],
+    endpoint: &'a dyn Fn(Request) -> Response,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(middleware: &'a [Arc<dyn Middleware>], endpoint: &'a dyn Fn(Request) -> Response) -> Next<'a> {
+        Next { middleware, endpoint }
+    }
+
+    /// Pass the request on to the next middleware, or to the handler
+    /// if this was the last one
+    pub fn run(&self, request: Request) -> Response {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(request, &Next::new(rest, self.endpoint)),
+            None => (self.endpoint)(request),
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+/// The media types of file extensions known without any configuration
+const BUILT_IN: [(&str, &str); 16] = [
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("json", "application/json"),
+    ("txt", "text/plain"),
+    ("xml", "application/xml"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("ico", "image/x-icon"),
+    ("webp", "image/webp"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+];
+
+/// A mapping from file extensions to the Content-Type files are served
+/// with
+///
+/// It starts out with the types of common web files, which can be
+/// overridden and extended:
+///
+/// ```
+/// use std::path::Path;
+/// use server::mime::MimeTypes;
+///
+/// let types = MimeTypes::new().with("md", "text/markdown").with_default("text/plain");
+/// assert_eq!(types.get(Path::new("README.md")), "text/markdown; charset=utf-8");
+/// assert_eq!(types.get(Path::new("Makefile")), "text/plain; charset=utf-8");
+/// assert_eq!(types.get(Path::new("logo.PNG")), "image/png");
+/// ```
+#[derive(Clone, Debug)]
+pub struct MimeTypes {
+    types: HashMap<String, String>,
+    default: String,
+    charset: Option<String>,
+}
+
+impl MimeTypes {
+    /// The built-in mapping, with `application/octet-stream` for unknown
+    /// extensions and UTF-8 as the charset of text types
+    pub fn new() -> MimeTypes {
+        MimeTypes {
+            types: BUILT_IN.iter().map(|(extension, media_type)| (String::from(*extension), String::from(*media_type))).collect(),
+            default: String::from("application/octet-stream"),
+            charset: Some(String::from("utf-8")),
+        }
+    }
+
+    /// Map an extension to a media type, replacing its type if it had one
+    ///
+    /// # Arguments
+    ///
+    /// extension - The extension without the dot, compared
+    /// case-insensitively.
+    /// media_type - The Content-Type, which may carry parameters.
+    pub fn with(mut self, extension: &str, media_type: &str) -> MimeTypes {
+        self.types.insert(extension.trim_start_matches('.').to_ascii_lowercase(), String::from(media_type));
+        self
+    }
+
+    /// Set the type of files without an extension or with an unknown one
+    pub fn with_default(mut self, media_type: &str) -> MimeTypes {
+        self.default = String::from(media_type);
+        self
+    }
+
+    /// Set the charset parameter added to `text/*` types that do not
+    /// have one, or None to send them without
+    pub fn with_charset(mut self, charset: Option<&str>) -> MimeTypes {
+        self.charset = charset.map(String::from);
+        self
+    }
+
+    /// The Content-Type of a file
+    pub fn get(&self, path: &Path) -> String {
+        let media_type = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| self.types.get(&extension.to_ascii_lowercase()))
+            .unwrap_or(&self.default);
+        match &self.charset {
+            Some(charset) if is_text(media_type) && !media_type.to_ascii_lowercase().contains("charset=") => {
+                format!("{}; charset={}", media_type, charset)
+            }
+            _ => media_type.clone(),
+        }
+    }
+}
+
+impl Default for MimeTypes {
+    fn default() -> MimeTypes {
+        MimeTypes::new()
+    }
+}
+
+fn is_text(media_type: &str) -> bool {
+    media_type.get(..5).is_some_and(|prefix| prefix.eq_ignore_ascii_case("text/"))
+}
@@ -0,0 +1,504 @@
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::auth::random_secret;
+use crate::body::Body;
+use crate::headers;
+use crate::request::Request;
+
+/// The most bytes read from the body at once
+const READ_SIZE: usize = 64 * 1024;
+
+/// How a multipart/form-data body is read
+#[derive(Clone, Debug)]
+pub struct FormOptions {
+    /// The directory large file parts are written to
+    pub temp_dir: PathBuf,
+    /// Size in bytes above which a file part is written to a temporary
+    /// file instead of being kept in memory
+    pub spool_threshold: usize,
+    /// Largest part without a file name in bytes, which is always kept
+    /// in memory
+    pub max_field_size: usize,
+    /// Largest head of a part in bytes
+    pub max_head_size: usize,
+    /// Largest number of parts
+    pub max_parts: usize,
+}
+
+impl Default for FormOptions {
+    fn default() -> FormOptions {
+        FormOptions {
+            temp_dir: env::temp_dir(),
+            spool_threshold: 1024 * 1024,
+            max_field_size: 64 * 1024,
+            max_head_size: 8 * 1024,
+            max_parts: 128,
+        }
+    }
+}
+
+/// A multipart/form-data body, as sent by an HTML form with file inputs
+///
+/// The form is read as the body arrives, so together with
+/// `Route::stream_body` an upload of a file larger than the spool
+/// threshold is never held in memory as a whole:
+///
+/// ```no_run
+/// use server::multipart::{Form, FormOptions};
+/// use server::request::Request;
+/// use server::response::Response;
+/// use server::router::Router;
+///
+/// fn upload(mut request: Request) -> Response {
+///     let form = match Form::read(&mut request, &FormOptions::default()) {
+///         Ok(form) => form,
+///         Err(e) => return Response::text(e.status(), e.to_string()),
+///     };
+///     match form.part("file").map(|file| file.persist("uploads/latest")) {
+///         Some(Ok(())) => Response::text(201, "Stored"),
+///         Some(Err(e)) => Response::text(500, e.to_string()),
+///         None => Response::text(400, "No file"),
+///     }
+/// }
+///
+/// let mut router = Router::new();
+/// router.post("/upload", upload).stream_body();
+/// ```
+#[derive(Debug)]
+pub struct Form {
+    parts: Vec<Part>,
+}
+
+impl Form {
+    /// Read the body of a request as a form
+    ///
+    /// # Arguments
+    ///
+    /// request - A request with a multipart/form-data body, which is
+    /// taken out of it.
+    /// options - Where and from which size file parts are spooled to
+    /// disk, and the limits of the form.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request is not multipart/form-data, the
+    /// form is malformed or exceeds the limits, or the body or a
+    /// temporary file cannot be read or written. `MultipartError::status`
+    /// gives the status to respond with.
+    pub fn read(request: &mut Request, options: &FormOptions) -> Result<Form, MultipartError> {
+        let boundary = request
+            .header("Content-Type")
+            .filter(|value| headers::media_type(value).eq_ignore_ascii_case("multipart/form-data"))
+            .and_then(|value| parameter(value, "boundary"))
+            .ok_or_else(|| MultipartError::with_status(415, "Not a multipart/form-data request."))?;
+        if boundary.is_empty() || boundary.len() > 70 {
+            return Err(MultipartError::new("Invalid multipart boundary."));
+        }
+
+        // The leading line break lets the first delimiter be found like
+        // all the others
+        let mut scanner = Scanner { body: request.take_body(), buffer: b"\r\n".to_vec() };
+        let delimiter = format!("\r\n--{}", boundary).into_bytes();
+        scanner.skip_past(&delimiter)?;
+
+        let mut parts = Vec::new();
+        loop {
+            if scanner.starts_with(b"--")? {
+                return Ok(Form { parts });
+            }
+            if parts.len() == options.max_parts {
+                return Err(MultipartError::with_status(413, "Too many parts."));
+            }
+            scanner.skip_line()?;
+            let head = scanner.read_head(options.max_head_size)?;
+            let mut part = Part::parse(&head)?;
+            scanner.copy_until(&delimiter, |data| part.append(data, options))?;
+            parts.push(part);
+        }
+    }
+
+    /// The first part of a name
+    pub fn part(&self, name: &str) -> Option<&Part> {
+        self.parts.iter().find(|part| part.name == name)
+    }
+
+    /// The first value of a field without a file, as text
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.part(name).filter(|part| part.filename.is_none()).and_then(Part::text)
+    }
+
+    /// All parts in the order they were sent
+    pub fn parts(&self) -> &[Part] {
+        &self.parts
+    }
+}
+
+/// A field or file of a form
+#[derive(Debug)]
+pub struct Part {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    contents: Contents,
+}
+
+#[derive(Debug)]
+enum Contents {
+    Memory(Vec<u8>),
+    File(TempFile),
+}
+
+impl Part {
+    fn parse(head: &str) -> Result<Part, MultipartError> {
+        let mut disposition = None;
+        let mut content_type = None;
+        for line in head.split("\r\n").filter(|line| !line.is_empty()) {
+            let (name, value) = match line.find(':') {
+                Some(colon) => (line[..colon].trim(), line[colon + 1..].trim()),
+                None => return Err(MultipartError::new("Part header line without a colon.")),
+            };
+            if name.eq_ignore_ascii_case("Content-Disposition") {
+                disposition = Some(value);
+            } else if name.eq_ignore_ascii_case("Content-Type") {
+                content_type = Some(String::from(value));
+            }
+        }
+        let disposition = disposition
+            .filter(|value| value.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case("form-data"))
+            .ok_or_else(|| MultipartError::new("Part without a form-data Content-Disposition."))?;
+        let name = parameter(disposition, "name").ok_or_else(|| MultipartError::new("Part without a name."))?;
+        Ok(Part {
+            name,
+            filename: parameter(disposition, "filename"),
+            content_type,
+            contents: Contents::Memory(Vec::new()),
+        })
+    }
+
+    /// Add data to the part, moving a file part to a temporary file once
+    /// it grows beyond the threshold
+    fn append(&mut self, data: &[u8], options: &FormOptions) -> Result<(), MultipartError> {
+        match &mut self.contents {
+            Contents::File(file) => file.write(data)?,
+            Contents::Memory(contents) if self.filename.is_none() => {
+                if contents.len() + data.len() > options.max_field_size {
+                    return Err(MultipartError::with_status(413, &format!("Field {} too large.", self.name)));
+                }
+                contents.extend_from_slice(data);
+            }
+            Contents::Memory(contents) if contents.len() + data.len() > options.spool_threshold => {
+                let mut file = TempFile::create(&options.temp_dir)?;
+                file.write(contents)?;
+                file.write(data)?;
+                self.contents = Contents::File(file);
+            }
+            Contents::Memory(contents) => contents.extend_from_slice(data),
+        }
+        Ok(())
+    }
+
+    /// The name of the form field
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The file name the client gave, if the part is a file
+    ///
+    /// It is the client's choice, so it must not be used as a path
+    /// without checking it first.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    /// The media type of the part, if the client gave one
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// The size of the part in bytes
+    pub fn size(&self) -> u64 {
+        match &self.contents {
+            Contents::Memory(contents) => contents.len() as u64,
+            Contents::File(file) => file.size,
+        }
+    }
+
+    /// The contents if they are held in memory, which they are unless
+    /// the part is a file larger than the spool threshold
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match &self.contents {
+            Contents::Memory(contents) => Some(contents),
+            Contents::File(_) => None,
+        }
+    }
+
+    /// The contents as text, if they are held in memory and valid UTF-8
+    pub fn text(&self) -> Option<&str> {
+        self.bytes().and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    /// The temporary file holding the contents, if they were spooled to
+    /// disk
+    ///
+    /// The file is deleted when the form is dropped, unless the part is
+    /// persisted.
+    pub fn path(&self) -> Option<&Path> {
+        match &self.contents {
+            Contents::Memory(_) => None,
+            Contents::File(file) => Some(&file.path),
+        }
+    }
+
+    /// Open the contents for reading, wherever they are held
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the temporary file cannot be opened.
+    pub fn reader(&self) -> io::Result<Box<dyn Read + '_>> {
+        match &self.contents {
+            Contents::Memory(contents) => Ok(Box::new(contents.as_slice())),
+            Contents::File(file) => Ok(Box::new(File::open(&file.path)?)),
+        }
+    }
+
+    /// Store the contents at a path, moving the temporary file there if
+    /// they were spooled to disk
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the contents cannot be written to the path.
+    pub fn persist<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        match &self.contents {
+            Contents::Memory(contents) => fs::write(path, contents),
+            Contents::File(file) => {
+                // Renaming fails across file systems, where it is copied
+                if fs::rename(&file.path, path).is_err() {
+                    fs::copy(&file.path, path)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A file a part is spooled to, deleted when dropped
+#[derive(Debug)]
+struct TempFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl TempFile {
+    /// Create a file only the server's user can read, under a name no
+    /// one else can guess and create first, as the directory is usually
+    /// shared with other users
+    fn create(dir: &Path) -> io::Result<TempFile> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        loop {
+            let name: String = random_secret()[..16].iter().map(|byte| format!("{:02x}", byte)).collect();
+            let path = dir.join(format!("upload-{}", name));
+            match options.open(&path) {
+                Ok(file) => return Ok(TempFile { path, file, size: 0 }),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // It is gone already if the part was persisted by renaming it
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Reads a body in pieces, looking for delimiters across them
+struct Scanner {
+    body: Body,
+    /// Data read from the body but not consumed yet
+    buffer: Vec<u8>,
+}
+
+impl Scanner {
+    /// Read more of the body into the buffer, failing at its end
+    fn fill(&mut self) -> Result<(), MultipartError> {
+        let start = self.buffer.len();
+        self.buffer.resize(start + READ_SIZE, 0);
+        let read = self.body.read(&mut self.buffer[start..]);
+        self.buffer.truncate(start + *read.as_ref().unwrap_or(&0));
+        match read {
+            Ok(0) => Err(MultipartError::new("Form ended before its closing boundary.")),
+            Ok(_) => Ok(()),
+            // The client failed to send the body
+            Err(e) => Err(MultipartError::new(&e.to_string())),
+        }
+    }
+
+    /// Hand everything before a delimiter to `sink` and consume it along
+    /// with the delimiter
+    fn copy_until<F>(&mut self, delimiter: &[u8], mut sink: F) -> Result<(), MultipartError>
+    where
+        F: FnMut(&[u8]) -> Result<(), MultipartError>,
+    {
+        loop {
+            if let Some(position) = find(&self.buffer, delimiter) {
+                sink(&self.buffer[..position])?;
+                self.buffer.drain(..position + delimiter.len());
+                return Ok(());
+            }
+            // The end of the buffer may be the start of the delimiter
+            let keep = delimiter.len() - 1;
+            if self.buffer.len() > keep {
+                let end = self.buffer.len() - keep;
+                sink(&self.buffer[..end])?;
+                self.buffer.drain(..end);
+            }
+            self.fill()?;
+        }
+    }
+
+    /// Consume everything up to and including a delimiter
+    fn skip_past(&mut self, delimiter: &[u8]) -> Result<(), MultipartError> {
+        self.copy_until(delimiter, |_| Ok(()))
+    }
+
+    /// Whether the data after the last delimiter starts with a prefix
+    fn starts_with(&mut self, prefix: &[u8]) -> Result<bool, MultipartError> {
+        while self.buffer.len() < prefix.len() {
+            self.fill()?;
+        }
+        Ok(self.buffer.starts_with(prefix))
+    }
+
+    /// Consume the rest of the delimiter line
+    fn skip_line(&mut self) -> Result<(), MultipartError> {
+        loop {
+            if let Some(end) = find(&self.buffer, b"\r\n") {
+                if self.buffer[..end].iter().any(|&b| b != b' ' && b != b'\t') {
+                    return Err(MultipartError::new("Malformed multipart boundary line."));
+                }
+                self.buffer.drain(..end + 2);
+                return Ok(());
+            }
+            if self.buffer.len() > 1024 {
+                return Err(MultipartError::new("Malformed multipart boundary line."));
+            }
+            self.fill()?;
+        }
+    }
+
+    /// Read and consume the head of a part, up to the empty line ending it
+    fn read_head(&mut self, limit: usize) -> Result<String, MultipartError> {
+        loop {
+            // A part without headers starts with the empty line
+            if self.buffer.starts_with(b"\r\n") {
+                self.buffer.drain(..2);
+                return Ok(String::new());
+            }
+            if let Some(end) = find(&self.buffer, b"\r\n\r\n") {
+                let head = String::from_utf8(self.buffer[..end].to_vec())
+                    .map_err(|_| MultipartError::new("Part head is not valid UTF-8."))?;
+                self.buffer.drain(..end + 4);
+                return Ok(head);
+            }
+            if self.buffer.len() > limit {
+                return Err(MultipartError::with_status(431, "Part head too large."));
+            }
+            self.fill()?;
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// The value of a parameter of a header value like
+/// `form-data; name="file"`, unquoted
+fn parameter(value: &str, name: &str) -> Option<String> {
+    let mut rest = value.split_once(';')?.1;
+    loop {
+        let (key, after) = rest.split_once('=')?;
+        let after = after.trim_start();
+        let (parsed, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut parsed = String::new();
+                let mut chars = quoted.char_indices();
+                let end = loop {
+                    match chars.next()? {
+                        (_, '\\') => parsed.push(chars.next()?.1),
+                        (i, '"') => break i + 1,
+                        (_, c) => parsed.push(c),
+                    }
+                };
+                (parsed, &quoted[end..])
+            }
+            None => {
+                let end = after.find(';').unwrap_or(after.len());
+                (String::from(after[..end].trim()), &after[end..])
+            }
+        };
+        if key.trim().eq_ignore_ascii_case(name) {
+            return Some(parsed);
+        }
+        rest = after.split_once(';')?.1;
+    }
+}
+
+#[derive(Debug)]
+pub struct MultipartError {
+    details: String,
+    status: u16,
+}
+
+impl MultipartError {
+    fn new(details: &str) -> MultipartError {
+        MultipartError::with_status(400, details)
+    }
+
+    fn with_status(status: u16, details: &str) -> MultipartError {
+        MultipartError{details: String::from(details), status}
+    }
+
+    /// The status code to respond to the request with
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for MultipartError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+impl From<io::Error> for MultipartError {
+    fn from(err: io::Error) -> MultipartError {
+        MultipartError{details: err.to_string(), status: 500}
+    }
+}
@@ -0,0 +1,134 @@
+use crate::request::Request;
+use crate::response::Response;
+
+/// Pick the representation of a resource the client prefers
+///
+/// The media types are matched against the Accept header of the
+/// request, honoring q-values and preferring `text/html` over `text/*`
+/// over `*/*` when ranges overlap. Ties go to the type listed first,
+/// as does a request without an Accept header.
+///
+/// # Arguments
+///
+/// request - The request to negotiate for.
+/// available - The media types the handler can produce, in order of
+/// the handler's own preference.
+///
+/// # Errors
+///
+/// Returns a 406 response if the client accepts none of the types, so
+/// handlers returning a `Result` can use `?`.
+pub fn negotiate<'a>(request: &Request, available: &[&'a str]) -> Result<&'a str, Response> {
+    let chosen = match request.header("Accept") {
+        Some(accept) => preferred(accept, available),
+        None => available.first().copied(),
+    };
+    chosen.ok_or_else(|| {
+        Response::text(406, format!("Not Acceptable, available: {}", available.join(", ")))
+    })
+}
+
+/// Pick the media type an Accept header value prefers, or None if it
+/// accepts none of them
+pub fn preferred<'a>(accept: &str, available: &[&'a str]) -> Option<&'a str> {
+    let ranges = parse_accept(accept);
+    let mut best: Option<(&'a str, f32)> = None;
+    for media_type in available {
+        let quality = quality(&ranges, essence(media_type));
+        if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+            best = Some((media_type, quality));
+        }
+    }
+    best.map(|(media_type, _)| media_type)
+}
+
+/// Pick the content coding an Accept-Encoding header value prefers, or
+/// None if it prefers the representation without any
+///
+/// Codings the header does not name get the q-value of `*` if there is
+/// one. The unencoded representation is acceptable unless excluded with
+/// `identity;q=0` or `*;q=0`, and loses ties with the codings, of which
+/// the one listed first wins.
+///
+/// # Arguments
+///
+/// accept_encoding - The value of the Accept-Encoding header.
+/// available - The codings the handler can produce, such as `br` and
+/// `gzip`, in order of the handler's own preference.
+pub fn preferred_encoding<'a>(accept_encoding: &str, available: &[&'a str]) -> Option<&'a str> {
+    let codings = parse_accept(accept_encoding);
+    let quality_of = |coding: &str| {
+        let named = codings
+            .iter()
+            .find(|c| c.range == coding || (coding == "gzip" && c.range == "x-gzip"))
+            .or_else(|| codings.iter().find(|c| c.range == "*"));
+        named.map(|c| c.quality)
+    };
+
+    let identity = quality_of("identity").unwrap_or(1.0);
+    let mut best: Option<(&'a str, f32)> = None;
+    for coding in available {
+        let quality = quality_of(&coding.to_ascii_lowercase()).unwrap_or(0.0);
+        if quality > 0.0 && quality >= identity && best.is_none_or(|(_, q)| quality > q) {
+            best = Some((coding, quality));
+        }
+    }
+    best.map(|(coding, _)| coding)
+}
+
+/// A media range of an Accept header with its q-value
+struct MediaRange {
+    range: String,
+    quality: f32,
+}
+
+impl MediaRange {
+    /// How specific the range is, or None if it does not match the type
+    fn specificity(&self, media_type: &str) -> Option<u8> {
+        if self.range == "*/*" {
+            return Some(0);
+        }
+        if let Some(prefix) = self.range.strip_suffix("/*") {
+            let matches = media_type.split('/').next() == Some(prefix);
+            return if matches { Some(1) } else { None };
+        }
+        if self.range == media_type {
+            Some(2)
+        } else {
+            None
+        }
+    }
+}
+
+fn parse_accept(accept: &str) -> Vec<MediaRange> {
+    accept
+        .split(',')
+        .filter_map(|part| {
+            let mut params = part.split(';');
+            let range = params.next()?.trim().to_ascii_lowercase();
+            if range.is_empty() {
+                return None;
+            }
+            let quality = params
+                .filter_map(|p| p.trim().strip_prefix("q=").or_else(|| p.trim().strip_prefix("Q=")))
+                .next()
+                .map(|q| q.trim().parse::<f32>().unwrap_or(0.0).clamp(0.0, 1.0))
+                .unwrap_or(1.0);
+            Some(MediaRange { range, quality })
+        })
+        .collect()
+}
+
+/// The q-value of the most specific range matching a media type
+fn quality(ranges: &[MediaRange], media_type: String) -> f32 {
+    ranges
+        .iter()
+        .filter_map(|r| r.specificity(&media_type).map(|s| (s, r.quality)))
+        .max_by_key(|(specificity, _)| *specificity)
+        .map_or(0.0, |(_, quality)| quality)
+}
+
+/// The type and subtype of a media type without its parameters
+fn essence(media_type: &str) -> String {
+    media_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase()
+}
@@ -0,0 +1,197 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::extract::IntoResponse;
+use crate::log;
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::{self, Router};
+use crate::static_files::StaticFiles;
+use crate::template::{Context, TemplateCache};
+
+/// A route table built from the layout of a directory, for sites that
+/// are mostly files with a few dynamic pages
+///
+/// Folders become path segments and files pages, by these conventions:
+///
+/// `index.html` - the page of its folder, e.g. `users/index.html` is `/users`.
+/// `about.html` - a page of its own, `/about`.
+/// `[id].html` or a folder `[id]` - a segment captured as the parameter `id`.
+/// `[...path].html` - the rest of the path, captured as `path`.
+/// `name.rs` - a page answered by the handler registered for `name`.
+///
+/// HTML pages are templates, rendered with the captured parameters and
+/// recompiled when their file changes; they can include the templates of
+/// the directory, see `template::Template`. Other files are served as
+/// they are, and files and folders whose names start with `_` or `.`,
+/// such as `_layout.html`, are not served at all.
+///
+/// ```no_run
+/// use server::pages::Pages;
+/// use server::request::Request;
+/// use server::response::Response;
+/// use server::router::Router;
+///
+/// fn update_user(request: Request) -> Response {
+///     Response::text(200, format!("Updated {}", request.param("id").unwrap_or("")))
+/// }
+///
+/// let pages = Pages::new("routes").with_handler("POST", "users/[id]", update_user);
+/// let mut router = Router::new();
+/// router.nest("/", pages.into_router().unwrap());
+/// ```
+pub struct Pages {
+    root: PathBuf,
+    handlers: Router,
+}
+
+impl Pages {
+    /// Build routes from the files under a directory
+    pub fn new<P: AsRef<Path>>(root: P) -> Pages {
+        Pages { root: root.as_ref().to_path_buf(), handlers: Router::new() }
+    }
+
+    /// Register a handler for a page, at the path its name maps to
+    ///
+    /// # Arguments
+    ///
+    /// method - The request method, e.g. GET.
+    /// name - The page as it is named in the directory, relative to it
+    /// and with or without its `.rs` extension, e.g. `users/[id]`.
+    /// handler - The function producing the response.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a bracketed name is not made of letters, digits and
+    /// underscores or a `[...name]` segment is not the last one.
+    pub fn with_handler<H, R>(mut self, method: &str, name: &str, handler: H) -> Pages
+    where
+        H: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        let pattern = match pattern(name.strip_suffix(".rs").unwrap_or(name)) {
+            Some(pattern) => pattern,
+            None => panic!("{} is not a valid page name", name),
+        };
+        self.handlers.route(method, &pattern, handler);
+        self
+    }
+
+    /// Walk the directory and build the router serving it
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the directory cannot be read, a file name
+    /// does not map to a valid path, two files map to the same one or a
+    /// `.rs` page has no handler registered.
+    pub fn into_router(self) -> io::Result<Router> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let mut files = Vec::new();
+        walk(&self.root, Path::new(""), &mut files)?;
+
+        let mut router = self.handlers;
+        let templates = Arc::new(TemplateCache::new(&self.root));
+        let assets = Arc::new(StaticFiles::new(&self.root));
+        let mut served: Vec<String> = Vec::new();
+        for file in files {
+            let name = file.to_string_lossy().replace('\\', "/");
+            let (stem, extension) = match name.rsplit_once('.') {
+                Some((stem, extension)) if !stem.ends_with('/') => (stem, extension),
+                _ => (name.as_str(), ""),
+            };
+            let page = match extension {
+                "html" | "htm" | "rs" => stem,
+                _ => name.as_str(),
+            };
+            let pattern = pattern(page).ok_or_else(|| invalid(format!("{} does not map to a valid path.", name)))?;
+            if extension == "rs" {
+                if !router.routes().iter().any(|route| route.pattern == pattern) {
+                    return Err(invalid(format!("No handler is registered for {}.", name)));
+                }
+                continue;
+            }
+            if served.contains(&pattern) {
+                return Err(invalid(format!("{} maps to {}, which another file does too.", name, pattern)));
+            }
+            served.push(pattern.clone());
+
+            if page == stem {
+                let templates = Arc::clone(&templates);
+                router.get(&pattern, move |request: Request| render(&templates, &name, &request));
+            } else {
+                let assets = Arc::clone(&assets);
+                router.get(&pattern, move |_| assets.serve(&name));
+            }
+        }
+        Ok(router)
+    }
+}
+
+/// Render an HTML page with the parameters its route captured
+fn render(templates: &TemplateCache, name: &str, request: &Request) -> Response {
+    let mut context = Context::new();
+    for (param, value) in request.params() {
+        context.insert(param, value);
+    }
+    match templates.render(name, &context) {
+        Ok(html) => Response::html(200, html),
+        Err(e) => {
+            log::error(&format!("Failed to render page {}: {}", name, e));
+            Response::text(500, "Internal Server Error")
+        }
+    }
+}
+
+/// Collect the served files below a directory, as paths relative to
+/// the root, in a stable order
+fn walk(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(root.join(dir))?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with(['_', '.']) {
+            continue;
+        }
+        let path = dir.join(&name);
+        if entry.file_type()?.is_dir() {
+            walk(root, &path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The route pattern of a page named as in the directory, without its
+/// extension, e.g. `/users/:id` for `users/[id]`, or None if it maps to
+/// no valid pattern
+fn pattern(page: &str) -> Option<String> {
+    let mut segments: Vec<&str> = page.split('/').filter(|segment| !segment.is_empty()).collect();
+    if segments.last() == Some(&"index") {
+        segments.pop();
+    }
+    let mut pattern = String::new();
+    for segment in segments {
+        pattern.push('/');
+        match segment.strip_prefix('[').and_then(|segment| segment.strip_suffix(']')) {
+            Some(name) => match name.strip_prefix("...") {
+                Some(name) => {
+                    pattern.push('*');
+                    pattern.push_str(name);
+                }
+                None => {
+                    pattern.push(':');
+                    pattern.push_str(name);
+                }
+            },
+            None if segment.starts_with([':', '*']) => return None,
+            None => pattern.push_str(segment),
+        }
+    }
+    if pattern.is_empty() {
+        pattern.push('/');
+    }
+    Some(pattern).filter(|pattern| router::is_valid_pattern(pattern))
+}
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+
+/// Placeholder so the poller's signatures exist on every platform
+#[cfg(not(unix))]
+pub type RawFd = i32;
+
+/// Keeps idle sockets out of the worker threads until they become
+/// readable
+///
+/// Parked items are registered for a single readiness notification
+/// with epoll. `run` hands every item whose socket became readable, or
+/// was closed by the peer, back to the caller, and gives up on items
+/// that stayed idle for longer than the timeout. Only available on
+/// Linux and Android; `new` fails elsewhere.
+pub struct Poller<T> {
+    poll: sys::Poll,
+    parked: Mutex<Parked<T>>,
+}
+
+struct Parked<T> {
+    items: HashMap<u64, (RawFd, Instant, T)>,
+    next_token: u64,
+}
+
+impl<T: Send> Poller<T> {
+    /// Create a poller
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the platform has no supported readiness API
+    /// or the kernel refuses to create an instance.
+    pub fn new() -> io::Result<Poller<T>> {
+        Ok(Poller {
+            poll: sys::Poll::new()?,
+            parked: Mutex::new(Parked { items: HashMap::new(), next_token: 0 }),
+        })
+    }
+
+    /// Park an item until its socket becomes readable
+    ///
+    /// # Arguments
+    ///
+    /// fd - The socket to wait on, which must stay open while parked.
+    /// item - The value handed back once the socket is ready.
+    ///
+    /// # Errors
+    ///
+    /// Returns the item back if the socket cannot be registered.
+    pub fn park(&self, fd: RawFd, item: T) -> Result<(), T> {
+        let token = {
+            let mut parked = self.parked.lock().unwrap();
+            let token = parked.next_token;
+            parked.next_token += 1;
+            // Insert before registering, as the socket may already be
+            // readable and reported by `run` right away
+            parked.items.insert(token, (fd, Instant::now(), item));
+            token
+        };
+
+        match self.poll.add(fd, token) {
+            Ok(()) => Ok(()),
+            Err(_) => {
+                let (_, _, item) = self.parked.lock().unwrap().items.remove(&token).unwrap();
+                Err(item)
+            }
+        }
+    }
+
+    /// Number of items currently parked
+    pub fn len(&self) -> usize {
+        self.parked.lock().unwrap().items.len()
+    }
+
+    /// Whether no items are parked
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Wait for parked sockets forever, passing each ready item to
+    /// `ready` and each item idle for longer than `timeout` to `expired`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if waiting for events fails.
+    pub fn run<R, E>(&self, timeout: Duration, ready: R, expired: E) -> io::Result<()>
+    where
+        R: Fn(T),
+        E: Fn(T),
+    {
+        let mut tokens = Vec::new();
+        loop {
+            tokens.clear();
+            self.poll.wait(&mut tokens, Duration::from_secs(1))?;
+
+            let mut ready_items = Vec::with_capacity(tokens.len());
+            let mut expired_items = Vec::new();
+            {
+                let mut parked = self.parked.lock().unwrap();
+                for token in &tokens {
+                    if let Some(entry) = parked.items.remove(token) {
+                        ready_items.push(entry);
+                    }
+                }
+
+                let now = Instant::now();
+                let stale: Vec<u64> = parked
+                    .items
+                    .iter()
+                    .filter(|(_, (_, since, _))| now.duration_since(*since) > timeout)
+                    .map(|(token, _)| *token)
+                    .collect();
+                for token in stale {
+                    expired_items.extend(parked.items.remove(&token));
+                }
+            }
+
+            for (fd, _, item) in ready_items {
+                self.poll.remove(fd);
+                ready(item);
+            }
+            for (fd, _, item) in expired_items {
+                self.poll.remove(fd);
+                expired(item);
+            }
+        }
+    }
+}
+
+/// Wait for at most the timeout until a socket is readable, such as a
+/// listener with a connection to accept
+///
+/// # Errors
+///
+/// Returns an error if waiting fails for a reason other than being
+/// interrupted by a signal.
+#[cfg(unix)]
+pub(crate) fn wait_readable(fd: RawFd, timeout: Duration) -> io::Result<bool> {
+    use std::os::raw::{c_int, c_short};
+
+    const POLLIN: c_short = 0x1;
+
+    #[repr(C)]
+    struct PollFd {
+        fd: c_int,
+        events: c_short,
+        revents: c_short,
+    }
+
+    extern "C" {
+        fn poll(fds: *mut PollFd, count: std::os::raw::c_ulong, timeout: c_int) -> c_int;
+    }
+
+    let mut descriptor = PollFd { fd, events: POLLIN, revents: 0 };
+    match unsafe { poll(&mut descriptor, 1, timeout.as_millis() as c_int) } {
+        ready if ready > 0 => Ok(true),
+        0 => Ok(false),
+        _ => {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            Err(error)
+        }
+    }
+}
+
+/// Without poll, report the socket as readable so the caller blocks on it
+#[cfg(not(unix))]
+pub(crate) fn wait_readable(_fd: RawFd, _timeout: Duration) -> io::Result<bool> {
+    Ok(true)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use std::io;
+    use std::os::raw::c_int;
+    use std::os::unix::io::RawFd;
+    use std::time::Duration;
+
+    const EPOLL_CLOEXEC: c_int = 0o2000000;
+    const EPOLL_CTL_ADD: c_int = 1;
+    const EPOLL_CTL_DEL: c_int = 2;
+    const EPOLLIN: u32 = 0x1;
+    const EPOLLRDHUP: u32 = 0x2000;
+    const EPOLLONESHOT: u32 = 1 << 30;
+    const MAX_EVENTS: usize = 256;
+
+    #[cfg_attr(target_arch = "x86_64", repr(C, packed))]
+    #[cfg_attr(not(target_arch = "x86_64"), repr(C))]
+    #[derive(Clone, Copy)]
+    struct EpollEvent {
+        events: u32,
+        data: u64,
+    }
+
+    extern "C" {
+        fn epoll_create1(flags: c_int) -> c_int;
+        fn epoll_ctl(epfd: c_int, op: c_int, fd: c_int, event: *mut EpollEvent) -> c_int;
+        fn epoll_wait(epfd: c_int, events: *mut EpollEvent, maxevents: c_int, timeout: c_int) -> c_int;
+        fn close(fd: c_int) -> c_int;
+    }
+
+    pub struct Poll {
+        epfd: c_int,
+    }
+
+    impl Poll {
+        pub fn new() -> io::Result<Poll> {
+            let epfd = unsafe { epoll_create1(EPOLL_CLOEXEC) };
+            if epfd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Poll { epfd })
+        }
+
+        /// Register a socket for a single notification once it is readable
+        pub fn add(&self, fd: RawFd, token: u64) -> io::Result<()> {
+            let mut event = EpollEvent { events: EPOLLIN | EPOLLRDHUP | EPOLLONESHOT, data: token };
+            if unsafe { epoll_ctl(self.epfd, EPOLL_CTL_ADD, fd, &mut event) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        pub fn remove(&self, fd: RawFd) {
+            let mut event = EpollEvent { events: 0, data: 0 };
+            unsafe {
+                epoll_ctl(self.epfd, EPOLL_CTL_DEL, fd, &mut event);
+            }
+        }
+
+        /// Wait for at most the timeout, appending the tokens of the
+        /// sockets that became ready
+        pub fn wait(&self, tokens: &mut Vec<u64>, timeout: Duration) -> io::Result<()> {
+            let mut events = [EpollEvent { events: 0, data: 0 }; MAX_EVENTS];
+            let count = unsafe {
+                epoll_wait(self.epfd, events.as_mut_ptr(), MAX_EVENTS as c_int, timeout.as_millis() as c_int)
+            };
+            if count < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    return Ok(());
+                }
+                return Err(error);
+            }
+            tokens.extend(events[..count as usize].iter().map(|event| event.data));
+            Ok(())
+        }
+    }
+
+    impl Drop for Poll {
+        fn drop(&mut self) {
+            unsafe {
+                close(self.epfd);
+            }
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+mod sys {
+    use std::io;
+    use std::time::Duration;
+
+    use super::RawFd;
+
+    pub struct Poll;
+
+    impl Poll {
+        pub fn new() -> io::Result<Poll> {
+            Err(io::Error::other("Event-driven mode is not supported on this platform."))
+        }
+
+        pub fn add(&self, _fd: RawFd, _token: u64) -> io::Result<()> {
+            unreachable!()
+        }
+
+        pub fn remove(&self, _fd: RawFd) {}
+
+        pub fn wait(&self, _tokens: &mut Vec<u64>, _timeout: Duration) -> io::Result<()> {
+            unreachable!()
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use crate::log;
+use crate::{PoolHandle, PoolStats, ThreadPool};
+
+/// A set of named thread pools that are shut down together, in the
+/// reverse of the order they were added
+///
+/// Pools others hand work to should be added first, such as a
+/// "blocking" pool before the "background" pool using it and both before
+/// the "http" pool, so each pool is only shut down once the pools that
+/// could still submit to it have finished their jobs.
+pub struct PoolManager {
+    pools: Vec<(String, ThreadPool)>,
+}
+
+impl PoolManager {
+    /// Create a manager without any pools
+    pub fn new() -> PoolManager {
+        PoolManager { pools: Vec::new() }
+    }
+
+    /// Add a pool, replacing and shutting down one of the same name
+    ///
+    /// The replacing pool takes the place of the old one in the shutdown
+    /// order.
+    ///
+    /// # Arguments
+    ///
+    /// name - What the pool is looked up by.
+    /// pool - The pool, owned by the manager from then on.
+    pub fn with_pool(mut self, name: &str, pool: ThreadPool) -> PoolManager {
+        match self.pools.iter_mut().find(|(n, _)| n == name) {
+            Some((_, existing)) => drop(std::mem::replace(existing, pool)),
+            None => self.pools.push((String::from(name), pool)),
+        }
+        self
+    }
+
+    /// The pool of a name
+    pub fn get(&self, name: &str) -> Option<&ThreadPool> {
+        self.pools.iter().find(|(n, _)| n == name).map(|(_, pool)| pool)
+    }
+
+    /// A handle for submitting jobs to the pool of a name, which can be
+    /// passed to other threads
+    pub fn handle(&self, name: &str) -> Option<PoolHandle> {
+        self.get(name).map(ThreadPool::handle)
+    }
+
+    /// The names of the pools, in the order they were added
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.pools.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// The counters of the pool of a name
+    pub fn stats(&self, name: &str) -> Option<PoolStats> {
+        self.get(name).map(|pool| pool.monitor().stats())
+    }
+
+    /// The counters of all the pools together
+    pub fn total(&self) -> PoolStats {
+        self.pools
+            .iter()
+            .map(|(_, pool)| pool.monitor().stats())
+            .fold(PoolStats::default(), PoolStats::combine)
+    }
+
+    /// Shut the pools down, each after letting it finish its queued jobs,
+    /// in the reverse of the order they were added
+    ///
+    /// Dropping the manager does the same.
+    pub fn shutdown(self) {}
+}
+
+impl Default for PoolManager {
+    fn default() -> PoolManager {
+        PoolManager::new()
+    }
+}
+
+impl Drop for PoolManager {
+    fn drop(&mut self) {
+        while let Some((name, pool)) = self.pools.pop() {
+            log::info(&format!("Shutting down pool {}", name));
+            drop(pool);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::io;
+use std::path::Path;
+
+/// Switch the process to an unprivileged account, optionally confining
+/// it to a directory first
+///
+/// Meant to be called once the listening socket is bound, so a server
+/// started as root can use port 80 without running as root. The names
+/// are looked up before changing the root directory, as the account
+/// database is usually not reachable from inside it. Afterwards, paths
+/// such as the template directory are resolved within the new root.
+///
+/// # Arguments
+///
+/// user - The name of the account to run as.
+/// group - The name of the group to run as, or None for the primary
+/// group of the account.
+/// root - The directory to change the root to, or None to keep it.
+///
+/// # Errors
+///
+/// Returns an error if the account or group does not exist, if the
+/// process lacks the permission to switch to them, or if root
+/// privileges could be regained afterwards.
+#[cfg(unix)]
+pub fn drop_privileges(user: &str, group: Option<&str>, root: Option<&Path>) -> io::Result<()> {
+    let (uid, primary_gid) = sys::user_ids(user)?;
+    let gid = match group {
+        Some(group) => sys::group_id(group)?,
+        None => primary_gid,
+    };
+
+    // Already switched, e.g. in the process started by a hot restart
+    if uid != 0 && sys::geteuid() == uid && sys::getegid() == gid {
+        return Ok(());
+    }
+
+    if let Some(root) = root {
+        sys::chroot(root)?;
+        std::env::set_current_dir("/")?;
+    }
+
+    // Groups first, as changing them needs the privileges setuid gives up
+    sys::initgroups(user, gid)?;
+    sys::setgid(gid)?;
+    sys::setuid(uid)?;
+
+    if uid != 0 && sys::setuid(0).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Root privileges could be regained after dropping them."));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(_user: &str, _group: Option<&str>, _root: Option<&Path>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Dropping privileges is not supported on this platform."))
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::raw::{c_char, c_int};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    /// The leading fields of `struct passwd`, which are laid out the
+    /// same way on all supported platforms
+    #[repr(C)]
+    struct Passwd {
+        _pw_name: *mut c_char,
+        _pw_passwd: *mut c_char,
+        pw_uid: u32,
+        pw_gid: u32,
+    }
+
+    /// The leading fields of `struct group`
+    #[repr(C)]
+    struct Group {
+        _gr_name: *mut c_char,
+        _gr_passwd: *mut c_char,
+        gr_gid: u32,
+    }
+
+    extern "C" {
+        fn getpwnam(name: *const c_char) -> *mut Passwd;
+        fn getgrnam(name: *const c_char) -> *mut Group;
+        #[link_name = "initgroups"]
+        fn c_initgroups(user: *const c_char, group: u32) -> c_int;
+        #[link_name = "setgid"]
+        fn c_setgid(gid: u32) -> c_int;
+        #[link_name = "setuid"]
+        fn c_setuid(uid: u32) -> c_int;
+        #[link_name = "geteuid"]
+        fn c_geteuid() -> u32;
+        #[link_name = "getegid"]
+        fn c_getegid() -> u32;
+        #[link_name = "chroot"]
+        fn c_chroot(path: *const c_char) -> c_int;
+    }
+
+    fn c_string(value: &[u8]) -> io::Result<CString> {
+        CString::new(value).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Name contains a NUL byte."))
+    }
+
+    fn check(result: c_int) -> io::Result<()> {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The user and primary group ids of an account
+    pub fn user_ids(user: &str) -> io::Result<(u32, u32)> {
+        let name = c_string(user.as_bytes())?;
+        let entry = unsafe { getpwnam(name.as_ptr()) };
+        if entry.is_null() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("Unknown user {}.", user)));
+        }
+        let entry = unsafe { &*entry };
+        Ok((entry.pw_uid, entry.pw_gid))
+    }
+
+    pub fn group_id(group: &str) -> io::Result<u32> {
+        let name = c_string(group.as_bytes())?;
+        let entry = unsafe { getgrnam(name.as_ptr()) };
+        if entry.is_null() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("Unknown group {}.", group)));
+        }
+        Ok(unsafe { (*entry).gr_gid })
+    }
+
+    pub fn initgroups(user: &str, gid: u32) -> io::Result<()> {
+        let name = c_string(user.as_bytes())?;
+        check(unsafe { c_initgroups(name.as_ptr(), gid) })
+    }
+
+    pub fn setgid(gid: u32) -> io::Result<()> {
+        check(unsafe { c_setgid(gid) })
+    }
+
+    pub fn setuid(uid: u32) -> io::Result<()> {
+        check(unsafe { c_setuid(uid) })
+    }
+
+    pub fn geteuid() -> u32 {
+        unsafe { c_geteuid() }
+    }
+
+    pub fn getegid() -> u32 {
+        unsafe { c_getegid() }
+    }
+
+    pub fn chroot(root: &Path) -> io::Result<()> {
+        let path = c_string(root.as_os_str().as_bytes())?;
+        check(unsafe { c_chroot(path.as_ptr()) })
+    }
+}
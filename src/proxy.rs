@@ -0,0 +1,1554 @@
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{IpAddr, Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub use crate::headers::HeaderRule;
+use crate::json::Value;
+use crate::log;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::{Body, Response};
+use crate::trace_context;
+use crate::{PoolHandle, Priority};
+
+/// Headers that only apply to a single connection, which are not passed
+/// on, along with any the Connection header names; Upgrade is for
+/// WebSocket
+const HOP_BY_HOP: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+/// Largest response head accepted from an upstream
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+/// Consecutive failures after which an upstream is taken out of use
+const MAX_FAILS: u32 = 3;
+/// How long an upstream that failed too often is left alone
+const FAIL_TIMEOUT: Duration = Duration::from_secs(10);
+/// Retries that can be made in a row before requests have to earn more
+const RETRY_BURST: f64 = 10.0;
+/// The number of parts the window of a circuit breaker is counted in,
+/// the oldest of which is dropped at a time
+const WINDOW_BUCKETS: usize = 10;
+
+/// The upstreams of every proxy, for the admin endpoint
+static UPSTREAMS: Mutex<Vec<Weak<Upstream>>> = Mutex::new(Vec::new());
+
+/// Active health checks of the upstreams of a proxy, see
+/// `Proxy::with_health_check`
+#[derive(Clone, Debug)]
+pub struct HealthCheck {
+    /// The path requested from each upstream, which passes the check by
+    /// answering with a 2xx or 3xx status
+    pub path: String,
+    /// How long to wait between two rounds of checks
+    pub interval: Duration,
+    /// How long connecting and answering may take before the check fails
+    pub timeout: Duration,
+    /// Checks in a row an upstream out of rotation has to pass to be
+    /// put back
+    pub healthy_threshold: u32,
+    /// Checks in a row an upstream has to fail to be taken out of
+    /// rotation
+    pub unhealthy_threshold: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> HealthCheck {
+        HealthCheck {
+            path: String::from("/"),
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+        }
+    }
+}
+
+/// When the circuit of an upstream opens, see
+/// `Proxy::with_circuit_breaker`
+///
+/// The outcomes of the requests sent to each upstream are counted over
+/// a sliding window. Once enough of them failed, or took longer than the
+/// slow threshold, the circuit opens: for a while no requests are sent
+/// to the upstream, and those it would have gotten go elsewhere or are
+/// answered with 503 right away. Then the circuit is half open, letting
+/// a few probe requests through; it closes again if they all succeed
+/// and opens once more if one of them fails.
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+    /// How far back the outcomes of requests are counted
+    pub window: Duration,
+    /// Requests within the window below which the circuit stays closed
+    /// whatever their outcomes
+    pub min_requests: u32,
+    /// Share of the requests within the window that may fail, by the
+    /// upstream not answering or answering with a 5xx status, before
+    /// the circuit opens
+    pub max_error_rate: f64,
+    /// How long an upstream may take to answer before the request
+    /// counts as slow, or None to not judge latency
+    pub slow_threshold: Option<Duration>,
+    /// Share of the requests within the window that may be slow before
+    /// the circuit opens
+    pub max_slow_rate: f64,
+    /// How long the circuit stays open before probe requests are let
+    /// through
+    pub open_for: Duration,
+    /// Probe requests let through at a time while the circuit is half
+    /// open, all of which have to succeed for it to close
+    pub probes: u32,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> CircuitBreaker {
+        CircuitBreaker {
+            window: Duration::from_secs(30),
+            min_requests: 20,
+            max_error_rate: 0.5,
+            slow_threshold: None,
+            max_slow_rate: 0.5,
+            open_for: Duration::from_secs(30),
+            probes: 3,
+        }
+    }
+}
+
+/// How a proxy picks which of its upstreams a request goes to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Balance {
+    /// Take turns, each upstream getting a share of the requests in
+    /// proportion to its weight
+    #[default]
+    RoundRobin,
+    /// The upstream with the fewest requests in flight for its weight,
+    /// which suits requests taking very different times
+    LeastConnections,
+    /// The same upstream for every request from a client address, for
+    /// as long as it is up, which keeps sessions held by the upstream;
+    /// requests without a client address take turns
+    IpHash,
+}
+
+/// Forwards requests to HTTP/1.1 servers, as a reverse proxy
+///
+/// Used as a middleware, requests under the prefix are forwarded and
+/// all others passed on. With more than one upstream, each request goes
+/// to one of them picked as the `Balance` says, leaving out those that
+/// are down. Connections to the upstreams are kept open and
+/// reused for later requests, up to a number of idle ones and for as
+/// long as the idle timeout allows; each of them carries one request at
+/// a time. A reused connection that turns out to have been closed by
+/// the upstream is replaced by a new one for idempotent requests.
+///
+/// Headers that only apply to one connection are dropped in both
+/// directions, the trace context is passed on as a child of the
+/// request's, and response bodies are streamed to the client as they
+/// arrive. A Location header pointing at the upstream's own address is
+/// made to point at the same path on this server instead, and other
+/// prefixes can be rewritten with `with_location_rewrite`; beyond that,
+/// headers can be changed by rules in either direction.
+///
+/// WebSocket upgrades are passed on: once the upstream switches
+/// protocols, bytes are relayed between it and the client on the
+/// thread serving the connection until both have closed, and the
+/// connection to the upstream is not reused. An upstream that fails to connect or answer several times
+/// in a row is not tried again for a few seconds, during which requests
+/// are answered with 502 right away.
+///
+/// A request that fails on one upstream is retried on another, as long
+/// as doing so is safe: when connecting failed, so nothing was sent, or
+/// for idempotent methods also when the upstream did not answer or
+/// answered 502 or 504. The retries of a request are limited, and so
+/// are those of all requests together by a budget, so that retrying
+/// cannot multiply the load on upstreams that are struggling. Health checks can also find out
+/// about an upstream being down without a request failing, see
+/// `with_health_check`, and circuit breakers hold off upstreams whose
+/// requests fail or are slow too often, see `with_circuit_breaker`. The state of every upstream is shown by the
+/// admin endpoint's `GET /status`.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use server::proxy::Proxy;
+/// use server::router::Router;
+///
+/// let mut router = Router::new();
+/// router.wrap(Proxy::new("127.0.0.1:8080").with_prefix("/api").with_idle_timeout(Duration::from_secs(30)));
+/// ```
+///
+/// Balancing between two instances, one of which takes twice the load:
+///
+/// ```no_run
+/// use server::proxy::{Balance, Proxy};
+/// use server::router::Router;
+///
+/// let mut router = Router::new();
+/// router.wrap(Proxy::balanced(&[("10.0.0.1:8080", 2), ("10.0.0.2:8080", 1)]).with_balance(Balance::LeastConnections));
+/// ```
+///
+/// Telling the upstream who the client is and authenticating to it:
+///
+/// ```no_run
+/// use server::proxy::{HeaderRule, Proxy};
+/// use server::router::Router;
+///
+/// let mut router = Router::new();
+/// router.wrap(
+///     Proxy::new("127.0.0.1:8080")
+///         .with_forwarded_headers()
+///         .with_request_rule(HeaderRule::Set(String::from("Authorization"), String::from("Bearer secret")))
+///         .with_response_rule(HeaderRule::Remove(String::from("Server"))),
+/// );
+/// ```
+pub struct Proxy {
+    upstreams: Vec<Arc<Upstream>>,
+    balance: Balance,
+    /// The current weights of the upstreams for round-robin
+    rotation: Mutex<Vec<i64>>,
+    prefix: String,
+    timeout: Duration,
+    max_idle: usize,
+    idle_timeout: Duration,
+    retries: usize,
+    budget: RetryBudget,
+    forwarded_headers: bool,
+    request_rules: Vec<HeaderRule>,
+    response_rules: Vec<HeaderRule>,
+    location_rewrites: Vec<(String, String)>,
+    mirror: Option<Mirror>,
+}
+
+impl Proxy {
+    /// Create a proxy forwarding every request to a server
+    ///
+    /// # Arguments
+    ///
+    /// upstream - The address of the server, such as `127.0.0.1:8080`.
+    pub fn new(upstream: &str) -> Proxy {
+        Proxy::balanced(&[(upstream, 1)])
+    }
+
+    /// Create a proxy balancing requests between servers, round-robin
+    /// unless `with_balance` says otherwise
+    ///
+    /// # Arguments
+    ///
+    /// upstreams - The address of each server, with its weight: a server
+    /// with twice the weight of another is sent twice as many requests.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no upstreams or one has a weight of zero.
+    pub fn balanced(upstreams: &[(&str, u32)]) -> Proxy {
+        assert!(!upstreams.is_empty(), "A proxy needs an upstream.");
+        assert!(upstreams.iter().all(|&(_, weight)| weight > 0), "Upstream weights have to be positive.");
+        let upstreams: Vec<Arc<Upstream>> =
+            upstreams.iter().map(|&(address, weight)| Arc::new(Upstream::new(address, weight))).collect();
+        UPSTREAMS.lock().unwrap().extend(upstreams.iter().map(Arc::downgrade));
+        Proxy {
+            rotation: Mutex::new(vec![0; upstreams.len()]),
+            upstreams,
+            balance: Balance::default(),
+            prefix: String::new(),
+            timeout: Duration::from_secs(30),
+            max_idle: 16,
+            idle_timeout: Duration::from_secs(60),
+            retries: 1,
+            budget: RetryBudget::new(0.2),
+            forwarded_headers: false,
+            request_rules: Vec::new(),
+            response_rules: Vec::new(),
+            location_rewrites: Vec::new(),
+            mirror: None,
+        }
+    }
+
+    /// Set how requests are balanced between the upstreams
+    pub fn with_balance(mut self, balance: Balance) -> Proxy {
+        self.balance = balance;
+        self
+    }
+
+    /// Check the health of the upstreams on a thread of their own, which
+    /// runs until the proxy is dropped, taking those failing the checks
+    /// out of rotation until they pass again
+    ///
+    /// Upstreams count as healthy until checked. Changes are logged.
+    pub fn with_health_check(self, check: HealthCheck) -> Proxy {
+        let upstreams: Vec<Weak<Upstream>> = self.upstreams.iter().map(Arc::downgrade).collect();
+        thread::spawn(move || loop {
+            for upstream in &upstreams {
+                let upstream = match upstream.upgrade() {
+                    Some(upstream) => upstream,
+                    None => return,
+                };
+                let result = match upstream.probe(&check) {
+                    Ok(status) if (200..400).contains(&status) => Ok(()),
+                    Ok(status) => Err(format!("answered {}", status)),
+                    Err(e) => Err(e.to_string()),
+                };
+                upstream.checked(result, &check);
+            }
+            thread::sleep(check.interval);
+        });
+        self
+    }
+
+    /// Open the circuit of an upstream whose requests fail or are slow
+    /// too often, sending it no requests for a while
+    ///
+    /// Requests are answered with 503 and a Retry-After header while the
+    /// circuits of all upstreams are open. An upstream that health checks
+    /// take out of rotation has its circuit opened too, and while it is
+    /// open a passing check lets the probe requests through right away.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the window is zero or no probes are let through.
+    pub fn with_circuit_breaker(self, breaker: CircuitBreaker) -> Proxy {
+        assert!(!breaker.window.is_zero() && breaker.probes > 0, "A circuit breaker needs a window and probes.");
+        for upstream in &self.upstreams {
+            upstream.health.lock().unwrap().circuit = Some(Circuit::new(breaker.clone()));
+        }
+        self
+    }
+
+    /// Only forward requests whose path is the prefix or below it, such
+    /// as `/api` for `/api` and `/api/users` but not `/apis`
+    pub fn with_prefix(mut self, prefix: &str) -> Proxy {
+        self.prefix = String::from(prefix.trim_end_matches('/'));
+        self
+    }
+
+    /// Set how long connecting to, writing to or reading from the
+    /// upstream may block before the request fails with 504, 30 seconds
+    /// by default
+    pub fn with_timeout(mut self, timeout: Duration) -> Proxy {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set how many idle connections to each upstream are kept for
+    /// reuse, 16 by default; zero opens one for every request
+    pub fn with_max_idle(mut self, max_idle: usize) -> Proxy {
+        self.max_idle = max_idle;
+        self
+    }
+
+    /// Set how long an idle connection is kept before it is closed, 60
+    /// seconds by default, which should be shorter than the upstream's
+    /// own keep-alive timeout
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Proxy {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Set on how many other upstreams a failed request is tried at
+    /// most, one by default; zero turns retries off
+    pub fn with_retries(mut self, retries: usize) -> Proxy {
+        self.retries = retries;
+        self
+    }
+
+    /// Set the share of requests that may be retried, 0.2 by default, on
+    /// top of a few retries that are always allowed in a row
+    pub fn with_retry_budget(mut self, ratio: f64) -> Proxy {
+        self.budget = RetryBudget::new(ratio);
+        self
+    }
+
+    /// Tell the upstream about the client with X-Forwarded-For, to which
+    /// the peer's address is added, and X-Forwarded-Proto and
+    /// X-Forwarded-Host
+    ///
+    /// The latter two are kept as sent when the request came through a
+    /// trusted proxy, which would have set them, and are set to `http`
+    /// and the Host header otherwise.
+    pub fn with_forwarded_headers(mut self) -> Proxy {
+        self.forwarded_headers = true;
+        self
+    }
+
+    /// Change the headers of requests sent to the upstream, such as to
+    /// add credentials for it; rules apply in the order they are added
+    pub fn with_request_rule(mut self, rule: HeaderRule) -> Proxy {
+        self.request_rules.push(rule);
+        self
+    }
+
+    /// Change the headers of responses passed on from the upstream;
+    /// rules apply in the order they are added
+    pub fn with_response_rule(mut self, rule: HeaderRule) -> Proxy {
+        self.response_rules.push(rule);
+        self
+    }
+
+    /// Rewrite the Location header of responses that starts with a
+    /// prefix, such as the public address of the upstream, to start
+    /// with another instead
+    ///
+    /// # Arguments
+    ///
+    /// from - The prefix as sent by the upstream, e.g.
+    /// `http://internal:8080/app`.
+    /// to - What the prefix is replaced with, e.g.
+    /// `https://example.com/app`, or empty for a path on this server.
+    pub fn with_location_rewrite(mut self, from: &str, to: &str) -> Proxy {
+        self.location_rewrites.push((String::from(from), String::from(to)));
+        self
+    }
+
+    /// Copy a share of the forwarded requests to another server as
+    /// well, see `Mirror`
+    pub fn with_mirror(mut self, mirror: Mirror) -> Proxy {
+        self.mirror = Some(mirror);
+        self
+    }
+
+    /// Whether a request path is under the prefix
+    pub fn matches(&self, path: &str) -> bool {
+        match path.strip_prefix(&self.prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || self.prefix.is_empty(),
+            None => false,
+        }
+    }
+
+    /// Forward a request to one of the upstreams, and to others if that
+    /// fails
+    pub fn handle(&self, request: &Request) -> Response {
+        let idempotent = matches!(request.method(), "GET" | "HEAD" | "OPTIONS" | "PUT" | "DELETE" | "TRACE");
+        if let Some(mirror) = &self.mirror {
+            mirror.send(request);
+        }
+        self.budget.deposit();
+        let mut tried: Vec<usize> = Vec::new();
+        let mut last: Option<Response> = None;
+        loop {
+            if request.time_remaining().is_some_and(|left| left.is_zero()) {
+                return last.unwrap_or_else(|| {
+                    log::warn(&format!("Deadline of {} passed before it could be forwarded", request.path()));
+                    Response::text(504, "Gateway Timeout")
+                });
+            }
+            let upstream = match self.choose(request, &tried) {
+                Some(chosen) => {
+                    tried.push(chosen);
+                    let upstream = &self.upstreams[chosen];
+                    if last.is_some() {
+                        log::info(&format!("Retrying {} on upstream {}", request.path(), upstream.address));
+                    }
+                    upstream
+                }
+                None => return last.unwrap_or_else(|| self.unavailable(request)),
+            };
+            upstream.started();
+            let started = Instant::now();
+            let (response, retryable) = match self.forward(upstream, request, idempotent) {
+                Ok(response) if idempotent && matches!(response.status(), 502 | 504) => {
+                    log::warn(&format!("Upstream {} answered {} for {}", upstream.address, response.status(), request.path()));
+                    upstream.failed();
+                    (response, true)
+                }
+                Ok(response) => {
+                    upstream.succeeded(started.elapsed(), response.status() >= 500);
+                    return response;
+                }
+                Err(failure) => (failed(upstream, request, failure.error), idempotent || !failure.sent),
+            };
+            if !retryable || tried.len() > self.retries || tried.len() == self.upstreams.len() || !self.budget.withdraw() {
+                return response;
+            }
+            last = Some(response);
+        }
+    }
+
+    /// The response to a request no upstream can take, a 503 when it is
+    /// because circuit breakers hold them off
+    fn unavailable(&self, request: &Request) -> Response {
+        match self.upstreams.iter().filter_map(|upstream| upstream.circuit_wait()).min() {
+            Some(wait) => {
+                log::warn(&format!("Circuits of all upstreams are open, not forwarding {}", request.path()));
+                // Rounded up, so clients do not come back too early
+                let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                Response::text(503, "Service Unavailable").with_header("Retry-After", &seconds.max(1).to_string())
+            }
+            None => {
+                log::warn(&format!("No upstream is up, not forwarding {}", request.path()));
+                Response::text(502, "Bad Gateway")
+            }
+        }
+    }
+
+    /// Send a request to an upstream, over an idle connection if there
+    /// is one
+    fn forward(&self, upstream: &Arc<Upstream>, request: &Request, idempotent: bool) -> Result<Response, Failure> {
+        let _in_flight = InFlight::new(upstream);
+        let head = self.request_head(request, &upstream.address);
+        // The request's deadline bounds waiting for the upstream too; a
+        // zero timeout would mean none
+        let timeout = match request.time_remaining() {
+            Some(left) => left.min(self.timeout).max(Duration::from_millis(1)),
+            None => self.timeout,
+        };
+        let mut reused = upstream.checkout(self.idle_timeout);
+        loop {
+            let retry = reused.is_some() && idempotent;
+            let connection = match reused.take() {
+                Some(connection) => connection,
+                None => upstream.connect(timeout).map_err(|error| Failure { error, sent: false })?,
+            };
+            let stream = connection.get_ref();
+            if let Err(error) = stream.set_read_timeout(Some(timeout)).and_then(|_| stream.set_write_timeout(Some(timeout))) {
+                return Err(Failure { error, sent: false });
+            }
+            match self.exchange(upstream, connection, &head, request) {
+                Ok(response) => return Ok(response),
+                // A kept-alive connection may have been closed by the
+                // upstream in the meantime
+                Err(e) if retry && !is_timeout(&e) => continue,
+                Err(error) => return Err(Failure { error, sent: true }),
+            }
+        }
+    }
+
+    /// The upstream a request goes to, out of those that are up and
+    /// have not been tried for it yet
+    fn choose(&self, request: &Request, tried: &[usize]) -> Option<usize> {
+        let available: Vec<usize> = (0..self.upstreams.len())
+            .filter(|i| !tried.contains(i))
+            .filter(|&i| self.upstreams[i].is_available())
+            .collect();
+        let chosen = match (self.balance, request.client_ip()) {
+            (Balance::LeastConnections, _) => available.iter().copied().min_by(|&a, &b| {
+                // Requests in flight for the weight, compared without
+                // dividing: x / v < y / w when x * w < y * v
+                let load = |i: usize| self.upstreams[i].in_flight.load(Ordering::SeqCst) as u64;
+                let weight = |i: usize| u64::from(self.upstreams[i].weight);
+                (load(a) * weight(b)).cmp(&(load(b) * weight(a)))
+            }),
+            (Balance::IpHash, Some(ip)) => available
+                .iter()
+                .copied()
+                .max_by(|&a, &b| self.upstreams[a].score(ip).total_cmp(&self.upstreams[b].score(ip))),
+            _ => self.rotate(&available),
+        };
+        chosen
+    }
+
+    /// The next of the available upstreams by smooth weighted
+    /// round-robin, which spreads the turns of heavier upstreams out
+    /// instead of giving them several in a row
+    fn rotate(&self, available: &[usize]) -> Option<usize> {
+        let mut current = self.rotation.lock().unwrap();
+        let mut chosen: Option<usize> = None;
+        for &i in available {
+            current[i] += i64::from(self.upstreams[i].weight);
+            if chosen.is_none_or(|chosen| current[i] > current[chosen]) {
+                chosen = Some(i);
+            }
+        }
+        let chosen = chosen?;
+        current[chosen] -= available.iter().map(|&i| i64::from(self.upstreams[i].weight)).sum::<i64>();
+        Some(chosen)
+    }
+
+    /// Send a request over a connection and read the head of the
+    /// response, whose body is streamed from the connection afterwards
+    fn exchange(
+        &self,
+        upstream: &Arc<Upstream>,
+        mut connection: BufReader<TcpStream>,
+        head: &[u8],
+        request: &Request,
+    ) -> io::Result<Response> {
+        let stream = connection.get_mut();
+        stream.write_all(head)?;
+        stream.write_all(request.body())?;
+        stream.flush()?;
+
+        let websocket = is_websocket(request);
+        let Head { status, headers, close } = loop {
+            let head = read_head(&mut connection)?;
+            // Interim responses are not passed on, the final one follows,
+            // unless the upstream switches to WebSocket as asked
+            if !(100..200).contains(&head.status) || (head.status == 101 && websocket) {
+                break head;
+            }
+        };
+
+        let header = |name: &str| headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str());
+        let listed = connection_tokens(headers.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+        let mut passed: Vec<(String, String)> = headers
+            .iter()
+            .filter(|(name, _)| !is_hop_by_hop(name, &listed) && !name.eq_ignore_ascii_case("Content-Length"))
+            .cloned()
+            .collect();
+        for (name, value) in passed.iter_mut() {
+            if name.eq_ignore_ascii_case("Location") {
+                if let Some(location) = self.rewrite_location(value, &upstream.address) {
+                    *value = location;
+                }
+            }
+        }
+        for rule in &self.response_rules {
+            rule.apply(&mut passed);
+        }
+        let mut response = Response::new(status);
+        for (name, value) in &passed {
+            response = response.with_header(name, value);
+        }
+        if status == 304 {
+            if let Some(length) = header("Content-Length") {
+                response.set_header("Content-Length", length);
+            }
+        }
+        if status == 101 {
+            // Upgrade is hop-by-hop, but names the protocol switched to
+            if let Some(protocol) = header("Upgrade") {
+                response.set_header("Upgrade", protocol);
+            }
+            let in_flight = InFlight::new(upstream);
+            return Ok(response.with_upgrade(move |client, early| {
+                if let Err(e) = tunnel(client, &early, connection) {
+                    log::warn(&format!("WebSocket connection to {} failed: {}", in_flight.0.address, e));
+                }
+            }));
+        }
+
+        let framing = if request.method() == "HEAD" || status == 204 || status == 304 {
+            Framing::Length(0)
+        } else if header("Transfer-Encoding").is_some_and(is_chunked) {
+            Framing::Chunked(0)
+        } else if let Some(length) = header("Content-Length") {
+            Framing::Length(length.trim().parse().map_err(|_| invalid("Invalid Content-Length from upstream."))?)
+        } else {
+            Framing::UntilClose
+        };
+        let reusable = !close && !matches!(framing, Framing::UntilClose);
+        let length = match framing {
+            Framing::Length(length) => Some(length),
+            _ => None,
+        };
+        let mut body = UpstreamBody {
+            connection: Some(connection),
+            framing,
+            reusable,
+            in_flight: InFlight::new(upstream),
+            max_idle: self.max_idle,
+        };
+        if length == Some(0) {
+            body.finish();
+            return Ok(response);
+        }
+        Ok(response.with_stream(body, length))
+    }
+
+}
+
+impl Middleware for Proxy {
+    fn handle(&self, request: Request, next: &Next) -> Response {
+        if self.matches(request.path()) {
+            Proxy::handle(self, &request)
+        } else {
+            next.run(request)
+        }
+    }
+}
+
+/// Copies a share of requests to a secondary server, such as a new build
+/// of a backend to be tried on live traffic, ignoring its responses
+///
+/// The copies are sent by a background pool, so the requests themselves
+/// neither wait for the mirror nor notice it failing. Requests whose
+/// bodies are streamed to their handlers and WebSocket upgrades are not
+/// copied.
+///
+/// ```no_run
+/// use server::proxy::{Mirror, Proxy};
+/// use server::router::Router;
+/// use server::ThreadPool;
+///
+/// let background = ThreadPool::new(2).unwrap();
+/// let mut router = Router::new();
+/// router.wrap(Proxy::new("127.0.0.1:8080").with_mirror(Mirror::new(
+///     Proxy::new("127.0.0.1:9090"),
+///     10.0,
+///     background.handle(),
+/// )));
+/// ```
+pub struct Mirror {
+    proxy: Arc<Proxy>,
+    percent: f64,
+    pool: PoolHandle,
+    max_queued: usize,
+}
+
+impl Mirror {
+    /// Copy a share of requests to the upstreams of a proxy
+    ///
+    /// # Arguments
+    ///
+    /// proxy - The proxy the copies are sent through, whose timeouts,
+    /// retries and header rules apply to them but not its prefix.
+    /// percent - How many of every hundred requests are copied.
+    /// pool - The pool sending the copies, which should not be the one
+    /// handling requests.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the percentage is not between 0 and 100.
+    pub fn new(proxy: Proxy, percent: f64, pool: PoolHandle) -> Mirror {
+        assert!((0.0..=100.0).contains(&percent), "The mirrored percentage has to be between 0 and 100.");
+        Mirror { proxy: Arc::new(proxy), percent, pool, max_queued: 64 }
+    }
+
+    /// Set how many copies may wait for the pool at most, 64 by default;
+    /// requests arriving while the pool has as many jobs waiting are not
+    /// copied, so a slow mirror cannot pile them up
+    pub fn with_max_queued(mut self, max_queued: usize) -> Mirror {
+        self.max_queued = max_queued;
+        self
+    }
+
+    /// Send a copy of a request to the mirror if it is among the share
+    /// copied, without waiting for it to be answered
+    pub fn send(&self, request: &Request) {
+        if self.percent == 0.0 || trace_context::random_fraction() * 100.0 >= self.percent || is_websocket(request) {
+            return;
+        }
+        // Not logged, as it happens to every request while the mirror is
+        // falling behind
+        let copy = match request.duplicate() {
+            Some(copy) if self.pool.monitor().queued_jobs() < self.max_queued => copy,
+            _ => return,
+        };
+        let proxy = Arc::clone(&self.proxy);
+        self.pool.execute_with_priority(Priority::Low, move || {
+            let mut response = proxy.handle(&copy);
+            // Read to the end, so the connection can be reused
+            if let Body::Reader(mut body, _) = response.take_body() {
+                let _ = io::copy(&mut body, &mut io::sink());
+            }
+        });
+    }
+}
+
+impl Middleware for Mirror {
+    fn handle(&self, request: Request, next: &Next) -> Response {
+        self.send(&request);
+        next.run(request)
+    }
+}
+
+impl Proxy {
+    /// The request line and headers of a request as sent to an upstream
+    fn request_head(&self, request: &Request, upstream: &str) -> Vec<u8> {
+        let listed = connection_tokens(request.headers());
+        let websocket = is_websocket(request);
+        // The body has been read already, so the framing is set anew and
+        // there is nothing left to expect
+        let mut headers: Vec<(String, String)> = request
+            .headers()
+            .filter(|(name, _)| {
+                (!is_hop_by_hop(name, &listed) || (websocket && name.eq_ignore_ascii_case("Upgrade")))
+                    && !["Content-Length", "Expect", "traceparent", "tracestate"].iter().any(|n| n.eq_ignore_ascii_case(name))
+            })
+            .map(|(name, value)| (String::from(name), String::from(value)))
+            .collect();
+        if websocket {
+            headers.push((String::from("Connection"), String::from("Upgrade")));
+        }
+        if request.header("Host").is_none() {
+            headers.push((String::from("Host"), String::from(upstream)));
+        }
+        if let Some(trace) = trace_context::current() {
+            headers.extend(trace.child().headers().into_iter().map(|(name, value)| (String::from(name), value)));
+        }
+        if self.forwarded_headers {
+            add_forwarded_headers(request, &mut headers);
+        }
+        for rule in &self.request_rules {
+            rule.apply(&mut headers);
+        }
+
+        let mut head = format!("{} {} HTTP/1.1\r\n", request.method(), request.origin_target());
+        for (name, value) in &headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        let framed = request.header("Content-Length").is_some() || request.header("Transfer-Encoding").is_some();
+        if framed || !request.body().is_empty() {
+            head.push_str(&format!("Content-Length: {}\r\n", request.body().len()));
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+
+    /// A Location header rewritten by the first prefix it starts with,
+    /// or None to leave it as it is
+    fn rewrite_location(&self, location: &str, upstream: &str) -> Option<String> {
+        for (from, to) in &self.location_rewrites {
+            if let Some(rest) = location.strip_prefix(from.as_str()) {
+                return Some(format!("{}{}", to, rest));
+            }
+        }
+        // The upstream naming itself, which clients cannot reach
+        let lowercase = location.to_ascii_lowercase();
+        let rest = ["http://", "https://"]
+            .iter()
+            .find_map(|scheme| lowercase.starts_with(scheme).then(|| &location[scheme.len()..]))?
+            .strip_prefix(upstream)?;
+        match rest.chars().next() {
+            None => Some(String::from("/")),
+            Some('/') => Some(String::from(rest)),
+            Some('?') | Some('#') => Some(format!("/{}", rest)),
+            _ => None,
+        }
+    }
+}
+
+/// Add the peer to X-Forwarded-For, and set X-Forwarded-Proto and
+/// X-Forwarded-Host unless a trusted proxy did
+fn add_forwarded_headers(request: &Request, headers: &mut Vec<(String, String)>) {
+    let peer = request.peer_addr().map(|peer| peer.ip().to_canonical());
+    // The client address differs from the peer's when forwarding
+    // headers of a trusted proxy named it
+    let proxied = peer.is_some() && request.client_ip() != peer;
+
+    let mut chain: Vec<String> = Vec::new();
+    headers.retain(|(name, value)| {
+        let forwarded = name.eq_ignore_ascii_case("X-Forwarded-For");
+        if forwarded {
+            chain.push(value.clone());
+        }
+        !forwarded
+    });
+    chain.extend(peer.map(|peer| peer.to_string()));
+    if !chain.is_empty() {
+        headers.push((String::from("X-Forwarded-For"), chain.join(", ")));
+    }
+    for (name, value) in [("X-Forwarded-Proto", Some("http")), ("X-Forwarded-Host", request.header("Host"))] {
+        if proxied && headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            continue;
+        }
+        headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        if let Some(value) = value {
+            headers.push((String::from(name), String::from(value)));
+        }
+    }
+}
+
+/// Why a request could not be forwarded to an upstream
+struct Failure {
+    error: io::Error,
+    /// Whether any of the request may have reached the upstream
+    sent: bool,
+}
+
+/// How many retries may be made, earned by requests
+struct RetryBudget {
+    /// Retries earned by each request
+    ratio: f64,
+    tokens: Mutex<f64>,
+}
+
+impl RetryBudget {
+    fn new(ratio: f64) -> RetryBudget {
+        RetryBudget { ratio, tokens: Mutex::new(RETRY_BURST) }
+    }
+
+    fn deposit(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens + self.ratio).min(RETRY_BURST);
+    }
+
+    /// Take a retry out of the budget, if there is one left
+    fn withdraw(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+/// The state of the upstreams of every proxy in use, for the admin
+/// endpoint's `GET /status`
+pub(crate) fn status() -> Value {
+    let mut upstreams = UPSTREAMS.lock().unwrap();
+    upstreams.retain(|upstream| upstream.strong_count() > 0);
+    let upstreams: Vec<Value> = upstreams.iter().filter_map(Weak::upgrade).map(|upstream| upstream.status()).collect();
+    Value::Array(upstreams)
+}
+
+/// The response to a request that could not be forwarded
+fn failed(upstream: &Upstream, request: &Request, e: io::Error) -> Response {
+    // Running out of the request's own time is not the upstream's fault
+    if is_timeout(&e) && request.time_remaining().is_some_and(|left| left.is_zero()) {
+        log::warn(&format!("Deadline of {} passed waiting for upstream {}", request.path(), upstream.address));
+        upstream.abandoned();
+        return Response::text(504, "Gateway Timeout");
+    }
+    upstream.failed();
+    if is_timeout(&e) {
+        log::error(&format!("Upstream {} timed out for {}", upstream.address, request.path()));
+        Response::text(504, "Gateway Timeout")
+    } else {
+        log::error(&format!("Forwarding {} to {} failed: {}", request.path(), upstream.address, e));
+        Response::text(502, "Bad Gateway")
+    }
+}
+
+/// A server requests are forwarded to, with its idle connections
+struct Upstream {
+    address: String,
+    weight: u32,
+    /// Requests sent to the upstream whose response has not been read
+    /// in full yet
+    in_flight: AtomicUsize,
+    idle: Mutex<Vec<(BufReader<TcpStream>, Instant)>>,
+    health: Mutex<Health>,
+}
+
+#[derive(Default)]
+struct Health {
+    /// Failures since the last request that succeeded
+    failures: u32,
+    /// Until when the upstream is not tried, after failing too often
+    down_until: Option<Instant>,
+    /// Whether health checks took the upstream out of rotation
+    unhealthy: bool,
+    /// Health checks in a row disagreeing with `unhealthy`
+    streak: u32,
+    circuit: Option<Circuit>,
+}
+
+impl Upstream {
+    fn new(address: &str, weight: u32) -> Upstream {
+        Upstream {
+            address: String::from(address),
+            weight,
+            in_flight: AtomicUsize::new(0),
+            idle: Mutex::new(Vec::new()),
+            health: Mutex::new(Health::default()),
+        }
+    }
+
+    /// How strongly a client address prefers the upstream, by weighted
+    /// rendezvous hashing: the client goes to the upstream it scores
+    /// highest with, so only the clients of an upstream that goes down
+    /// move elsewhere
+    fn score(&self, ip: IpAddr) -> f64 {
+        let hash = hash(format!("{} {}", ip, self.address).as_bytes());
+        // A number in (0, 1], from the top 53 bits
+        let unit = ((hash >> 11) + 1) as f64 / (1u64 << 53) as f64;
+        -f64::from(self.weight) / unit.ln()
+    }
+
+    fn connect(&self, timeout: Duration) -> io::Result<BufReader<TcpStream>> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "Upstream address does not resolve.");
+        for address in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(timeout))?;
+                    stream.set_write_timeout(Some(timeout))?;
+                    stream.set_nodelay(true)?;
+                    return Ok(BufReader::new(stream));
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// The most recently used idle connection that is still open,
+    /// closing those idle for longer than the timeout
+    fn checkout(&self, idle_timeout: Duration) -> Option<BufReader<TcpStream>> {
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain(|(_, since)| since.elapsed() < idle_timeout);
+        while let Some((connection, _)) = idle.pop() {
+            if is_open(connection.get_ref()) {
+                return Some(connection);
+            }
+        }
+        None
+    }
+
+    /// Keep a connection whose last response was read in full for reuse
+    fn checkin(&self, connection: BufReader<TcpStream>, max_idle: usize) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < max_idle {
+            idle.push((connection, Instant::now()));
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        let mut health = self.health.lock().unwrap();
+        if health.unhealthy {
+            return false;
+        }
+        if let Some(circuit) = &mut health.circuit {
+            if !circuit.allows(&self.address) {
+                return false;
+            }
+        }
+        match health.down_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                // Let requests try it again, and take it out once more
+                // if the next one fails
+                health.down_until = None;
+                health.failures = MAX_FAILS - 1;
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Request the health check path, for its status
+    fn probe(&self, check: &HealthCheck) -> io::Result<u16> {
+        let mut connection = self.connect(check.timeout)?;
+        let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", check.path, self.address);
+        connection.get_mut().write_all(request.as_bytes())?;
+        Ok(read_head(&mut connection)?.status)
+    }
+
+    /// Take the upstream out of rotation or put it back once enough
+    /// health checks in a row say so
+    fn checked(&self, result: Result<(), String>, check: &HealthCheck) {
+        let mut health = self.health.lock().unwrap();
+        if let (Ok(()), Some(circuit)) = (&result, &mut health.circuit) {
+            circuit.probe_now();
+        }
+        if result.is_ok() != health.unhealthy {
+            health.streak = 0;
+            return;
+        }
+        health.streak += 1;
+        match result {
+            Ok(()) if health.streak >= check.healthy_threshold => {
+                log::info(&format!("Upstream {} passed {} health checks, putting it back into rotation", self.address, health.streak));
+                *health = Health { circuit: health.circuit.take(), ..Health::default() };
+            }
+            Err(e) if health.streak >= check.unhealthy_threshold => {
+                log::warn(&format!(
+                    "Upstream {} failed {} health checks, taking it out of rotation: {}",
+                    self.address, health.streak, e
+                ));
+                health.unhealthy = true;
+                health.streak = 0;
+                if let Some(circuit) = &mut health.circuit {
+                    circuit.open(&self.address, "it failed its health checks");
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn status(&self) -> Value {
+        let health = self.health.lock().unwrap();
+        Value::object()
+            .with("address", self.address.as_str())
+            .with("weight", self.weight)
+            .with("healthy", !health.unhealthy)
+            .with("down", health.down_until.is_some_and(|until| Instant::now() < until))
+            .with("failures", health.failures)
+            .with("in_flight", self.in_flight.load(Ordering::SeqCst))
+            .with("circuit", health.circuit.as_ref().map(Circuit::status))
+    }
+
+    /// Note that a request is being sent to the upstream, which may be
+    /// one of the probes of a half-open circuit
+    fn started(&self) {
+        if let Some(circuit) = &mut self.health.lock().unwrap().circuit {
+            circuit.started();
+        }
+    }
+
+    /// Note that the upstream answered a request
+    ///
+    /// # Arguments
+    ///
+    /// latency - How long it took to answer.
+    /// error - Whether it answered with a server error, which counts
+    /// against its circuit though not as a failure to answer.
+    fn succeeded(&self, latency: Duration, error: bool) {
+        let mut health = self.health.lock().unwrap();
+        health.failures = 0;
+        if let Some(circuit) = &mut health.circuit {
+            circuit.record(&self.address, error, Some(latency));
+        }
+    }
+
+    /// Note that a request was given up on for reasons of its own, which
+    /// count neither for nor against the upstream
+    fn abandoned(&self) {
+        if let Some(circuit) = &mut self.health.lock().unwrap().circuit {
+            circuit.abandoned();
+        }
+    }
+
+    /// How long until requests may be sent to the upstream again, if
+    /// its circuit holds them off
+    fn circuit_wait(&self) -> Option<Duration> {
+        self.health.lock().unwrap().circuit.as_ref().and_then(Circuit::wait)
+    }
+
+    fn failed(&self) {
+        let mut health = self.health.lock().unwrap();
+        if let Some(circuit) = &mut health.circuit {
+            circuit.record(&self.address, true, None);
+        }
+        health.failures += 1;
+        if health.failures >= MAX_FAILS && health.down_until.is_none() {
+            log::warn(&format!("Upstream {} failed {} times in a row, taking it out of use", self.address, health.failures));
+            health.down_until = Some(Instant::now() + FAIL_TIMEOUT);
+        }
+    }
+}
+
+/// The circuit breaker of an upstream
+struct Circuit {
+    config: CircuitBreaker,
+    state: CircuitState,
+    /// When counting the window started, which the buckets are numbered
+    /// from
+    epoch: Instant,
+    /// The outcomes counted in each part of the window, with the number
+    /// of the part they were counted in
+    buckets: [(u64, Outcomes); WINDOW_BUCKETS],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CircuitState {
+    Closed,
+    /// No requests are sent until then
+    Open(Instant),
+    HalfOpen {
+        /// Probe requests in flight
+        probing: u32,
+        /// Probe requests that succeeded
+        passed: u32,
+    },
+}
+
+#[derive(Clone, Copy, Default)]
+struct Outcomes {
+    requests: u32,
+    failures: u32,
+    slow: u32,
+}
+
+impl Circuit {
+    fn new(config: CircuitBreaker) -> Circuit {
+        Circuit {
+            config,
+            state: CircuitState::Closed,
+            epoch: Instant::now(),
+            buckets: [(0, Outcomes::default()); WINDOW_BUCKETS],
+        }
+    }
+
+    /// The number of the part of the window it is now
+    fn bucket(&self) -> u64 {
+        let length = self.config.window.as_nanos() / WINDOW_BUCKETS as u128;
+        (self.epoch.elapsed().as_nanos() / length.max(1)) as u64
+    }
+
+    /// The outcomes counted within the window
+    fn totals(&self) -> Outcomes {
+        let current = self.bucket();
+        let mut totals = Outcomes::default();
+        for (number, outcomes) in &self.buckets {
+            if current - number < WINDOW_BUCKETS as u64 {
+                totals.requests += outcomes.requests;
+                totals.failures += outcomes.failures;
+                totals.slow += outcomes.slow;
+            }
+        }
+        totals
+    }
+
+    /// Whether a request may be sent, moving an open circuit whose time
+    /// is up to half open
+    fn allows(&mut self, address: &str) -> bool {
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::Open(until) if Instant::now() < until => false,
+            CircuitState::Open(_) => {
+                log::info(&format!("Circuit of upstream {} is half open, letting probe requests through", address));
+                self.state = CircuitState::HalfOpen { probing: 0, passed: 0 };
+                true
+            }
+            CircuitState::HalfOpen { probing, .. } => probing < self.config.probes,
+        }
+    }
+
+    fn started(&mut self) {
+        if let CircuitState::HalfOpen { probing, .. } = &mut self.state {
+            *probing += 1;
+        }
+    }
+
+    fn abandoned(&mut self) {
+        if let CircuitState::HalfOpen { probing, .. } = &mut self.state {
+            *probing = probing.saturating_sub(1);
+        }
+    }
+
+    /// Count the outcome of a request, opening or closing the circuit if
+    /// it tips the balance
+    ///
+    /// # Arguments
+    ///
+    /// address - The address of the upstream, for logging.
+    /// failed - Whether the request failed.
+    /// latency - How long the upstream took to answer, if it did.
+    fn record(&mut self, address: &str, failed: bool, latency: Option<Duration>) {
+        let slow = self.config.slow_threshold.is_some_and(|threshold| latency.is_some_and(|latency| latency > threshold));
+        match self.state {
+            // Requests sent before the circuit opened
+            CircuitState::Open(_) => {}
+            CircuitState::HalfOpen { .. } if failed || slow => {
+                self.open(address, if failed { "a probe request failed" } else { "a probe request was slow" });
+            }
+            CircuitState::HalfOpen { passed, .. } if passed + 1 >= self.config.probes => {
+                log::info(&format!("Circuit of upstream {} closed, its probe requests succeeded", address));
+                self.state = CircuitState::Closed;
+            }
+            CircuitState::HalfOpen { probing, passed } => {
+                self.state = CircuitState::HalfOpen { probing: probing.saturating_sub(1), passed: passed + 1 };
+            }
+            CircuitState::Closed => {
+                let current = self.bucket();
+                let (number, outcomes) = &mut self.buckets[(current % WINDOW_BUCKETS as u64) as usize];
+                if *number != current {
+                    *number = current;
+                    *outcomes = Outcomes::default();
+                }
+                outcomes.requests += 1;
+                outcomes.failures += u32::from(failed);
+                outcomes.slow += u32::from(slow);
+
+                let totals = self.totals();
+                if totals.requests < self.config.min_requests.max(1) {
+                    return;
+                }
+                let rate = |count: u32| f64::from(count) / f64::from(totals.requests);
+                if rate(totals.failures) > self.config.max_error_rate {
+                    self.open(address, &format!("{} of {} requests failed", totals.failures, totals.requests));
+                } else if rate(totals.slow) > self.config.max_slow_rate {
+                    self.open(address, &format!("{} of {} requests were slow", totals.slow, totals.requests));
+                }
+            }
+        }
+    }
+
+    /// Stop sending requests for the configured time, forgetting the
+    /// outcomes counted so far
+    fn open(&mut self, address: &str, reason: &str) {
+        if !matches!(self.state, CircuitState::Open(_)) {
+            log::warn(&format!("Circuit of upstream {} opened, as {}", address, reason));
+        }
+        self.state = CircuitState::Open(Instant::now() + self.config.open_for);
+        self.buckets = [(0, Outcomes::default()); WINDOW_BUCKETS];
+        self.epoch = Instant::now();
+    }
+
+    /// Let probe requests through without waiting out the open time
+    fn probe_now(&mut self) {
+        if matches!(self.state, CircuitState::Open(_)) {
+            self.state = CircuitState::Open(Instant::now());
+        }
+    }
+
+    /// How long until a request may be sent, if none may be now
+    fn wait(&self) -> Option<Duration> {
+        match self.state {
+            CircuitState::Closed => None,
+            CircuitState::Open(until) => Some(until.saturating_duration_since(Instant::now())),
+            CircuitState::HalfOpen { probing, .. } if probing >= self.config.probes => Some(Duration::ZERO),
+            CircuitState::HalfOpen { .. } => None,
+        }
+    }
+
+    fn status(&self) -> Value {
+        let state = match self.state {
+            CircuitState::Closed => "closed",
+            CircuitState::Open(_) => "open",
+            CircuitState::HalfOpen { .. } => "half_open",
+        };
+        let totals = self.totals();
+        Value::object()
+            .with("state", state)
+            .with("requests", totals.requests)
+            .with("failures", totals.failures)
+            .with("slow", totals.slow)
+            .with("retry_after_secs", self.wait().map(|wait| wait.as_secs_f64()))
+    }
+}
+
+/// How the end of a response body from an upstream is recognized
+enum Framing {
+    /// This many bytes are left
+    Length(u64),
+    /// Chunked, with this many bytes left of the current chunk
+    Chunked(u64),
+    /// The body ends when the upstream closes the connection
+    UntilClose,
+    /// All of the body was read
+    Done,
+}
+
+/// The body of a response from an upstream, read from its connection as
+/// the client is sent it; the connection is reused once it is read in
+/// full, or closed if the body is dropped before
+struct UpstreamBody {
+    connection: Option<BufReader<TcpStream>>,
+    framing: Framing,
+    reusable: bool,
+    in_flight: InFlight,
+    max_idle: usize,
+}
+
+impl UpstreamBody {
+    fn finish(&mut self) {
+        self.framing = Framing::Done;
+        if let Some(connection) = self.connection.take() {
+            if self.reusable {
+                self.in_flight.0.checkin(connection, self.max_idle);
+            }
+        }
+    }
+}
+
+impl Read for UpstreamBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => return Ok(0),
+        };
+        let read = match self.framing {
+            Framing::Done => return Ok(0),
+            Framing::UntilClose => connection.read(buf)?,
+            Framing::Length(left) => {
+                let wanted = buf.len().min(left.min(usize::MAX as u64) as usize);
+                let read = connection.read(&mut buf[..wanted])?;
+                if read == 0 {
+                    return Err(invalid("Upstream closed the connection in the middle of the body."));
+                }
+                self.framing = Framing::Length(left - read as u64);
+                read
+            }
+            Framing::Chunked(0) => {
+                let size = read_chunk_size(connection)?;
+                if size == 0 {
+                    // Trailers are not passed on
+                    while !read_line(connection)?.trim_end().is_empty() {}
+                    self.finish();
+                    return Ok(0);
+                }
+                self.framing = Framing::Chunked(size);
+                return self.read(buf);
+            }
+            Framing::Chunked(left) => {
+                let wanted = buf.len().min(left.min(usize::MAX as u64) as usize);
+                let read = connection.read(&mut buf[..wanted])?;
+                if read == 0 {
+                    return Err(invalid("Upstream closed the connection in the middle of the body."));
+                }
+                if read as u64 == left && !read_line(connection)?.trim_end().is_empty() {
+                    return Err(invalid("Chunk from upstream not followed by a line ending."));
+                }
+                self.framing = Framing::Chunked(left - read as u64);
+                read
+            }
+        };
+        if matches!(self.framing, Framing::Length(0)) || (read == 0 && matches!(self.framing, Framing::UntilClose)) {
+            self.finish();
+        }
+        Ok(read)
+    }
+}
+
+/// Counts a request as in flight to an upstream for as long as it lives
+struct InFlight(Arc<Upstream>);
+
+impl InFlight {
+    fn new(upstream: &Arc<Upstream>) -> InFlight {
+        upstream.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(Arc::clone(upstream))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The status line and headers of a response from an upstream
+struct Head {
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Whether the upstream closes the connection after the response
+    close: bool,
+}
+
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<Head> {
+    let mut size = 0;
+    let line = read_line(reader)?;
+    if line.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Upstream closed the connection."));
+    }
+    size += line.len();
+    let mut parts = line.trim_end().splitn(3, ' ');
+    let version = parts.next().unwrap_or("");
+    let status = parts
+        .next()
+        .and_then(|status| status.parse::<u16>().ok())
+        .filter(|status| (100..600).contains(status) && version.starts_with("HTTP/1."))
+        .ok_or_else(|| invalid("Malformed status line from upstream."))?;
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        size += line.len();
+        if size > MAX_HEAD_SIZE {
+            return Err(invalid("Response head from upstream too large."));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("Malformed header from upstream."))?;
+        headers.push((String::from(name.trim()), String::from(value.trim())));
+    }
+
+    let tokens = connection_tokens(headers.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+    let close = if version == "HTTP/1.0" {
+        !tokens.iter().any(|token| token == "keep-alive")
+    } else {
+        tokens.iter().any(|token| token == "close")
+    };
+    Ok(Head { status, headers, close })
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = Vec::new();
+    (&mut *reader).take(MAX_HEAD_SIZE as u64 + 1).read_until(b'\n', &mut line)?;
+    if line.len() > MAX_HEAD_SIZE {
+        return Err(invalid("Line from upstream too long."));
+    }
+    String::from_utf8(line).map_err(|_| invalid("Response head from upstream is not valid UTF-8."))
+}
+
+fn read_chunk_size<R: BufRead>(reader: &mut R) -> io::Result<u64> {
+    let line = read_line(reader)?;
+    let size = line.split(';').next().unwrap_or("").trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid("Invalid chunk size from upstream."));
+    }
+    u64::from_str_radix(size, 16).map_err(|_| invalid("Invalid chunk size from upstream."))
+}
+
+/// The lowercased options of the Connection headers
+fn connection_tokens<'a, I: Iterator<Item = (&'a str, &'a str)>>(headers: I) -> Vec<String> {
+    headers
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+/// Whether a request asks to switch the connection to WebSocket
+fn is_websocket(request: &Request) -> bool {
+    request.header("Upgrade").is_some_and(|protocol| protocol.trim().eq_ignore_ascii_case("websocket"))
+        && connection_tokens(request.headers()).iter().any(|token| token == "upgrade")
+}
+
+/// Relay bytes both ways between a client and an upstream that
+/// switched protocols, until both have closed their side
+fn tunnel(client: TcpStream, early: &[u8], upstream: BufReader<TcpStream>) -> io::Result<()> {
+    let pending = upstream.buffer().to_vec();
+    let upstream = upstream.into_inner();
+    // Either side may stay quiet for as long as it likes
+    upstream.set_read_timeout(None)?;
+    (&upstream).write_all(early)?;
+    (&client).write_all(&pending)?;
+    thread::scope(|scope| {
+        scope.spawn(|| relay(&client, &upstream));
+        relay(&upstream, &client);
+    });
+    Ok(())
+}
+
+/// Copy from one socket to another until the first closes its side,
+/// then close the sending side of the other
+fn relay(mut from: &TcpStream, mut to: &TcpStream) {
+    if io::copy(&mut from, &mut to).is_err() {
+        // A broken side ends the tunnel in both directions
+        let _ = from.shutdown(Shutdown::Both);
+        let _ = to.shutdown(Shutdown::Both);
+    } else {
+        let _ = to.shutdown(Shutdown::Write);
+    }
+}
+
+fn is_hop_by_hop(name: &str, listed: &[String]) -> bool {
+    HOP_BY_HOP.iter().any(|n| n.eq_ignore_ascii_case(name)) || listed.iter().any(|n| n.eq_ignore_ascii_case(name))
+}
+
+/// Whether chunked is the last transfer coding, which is the only case
+/// where the body is delimited by chunks
+fn is_chunked(codings: &str) -> bool {
+    codings.rsplit(',').next().is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// Whether an idle connection is still open, as far as can be told
+/// without waiting: a closed one reads as the end of input
+fn is_open(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let open = match stream.peek(&mut [0]) {
+        Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+        // Nothing may arrive before a request is sent
+        Ok(_) => false,
+    };
+    stream.set_nonblocking(false).is_ok() && open
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock
+}
+
+fn invalid(details: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, details)
+}
+
+/// FNV-1a, with the bits mixed afterwards so that inputs differing in
+/// their last bytes still hash far apart
+fn hash(data: &[u8]) -> u64 {
+    let mut hash = data.iter().fold(0xcbf29ce484222325u64, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3));
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ hash >> 33
+}
@@ -0,0 +1,170 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest possible version 1 header, including the line ending
+const V1_MAX_LENGTH: usize = 107;
+
+/// The TLV describing the TLS connection of the client
+const PP2_TYPE_SSL: u8 = 0x20;
+/// The common name of the client certificate, within `PP2_TYPE_SSL`
+const PP2_SUBTYPE_SSL_CN: u8 = 0x22;
+/// Client flags: a certificate was presented on this connection, or on
+/// the TLS session it resumed
+const PP2_CLIENT_CERT_CONN: u8 = 0x02;
+const PP2_CLIENT_CERT_SESS: u8 = 0x04;
+
+/// What the TLS connection of the client to the load balancer says
+/// about its certificate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientCert {
+    /// Whether the balancer verified the certificate against its CA
+    /// bundle
+    pub verified: bool,
+    /// The common name of the subject, if the balancer sends it
+    pub subject: Option<String>,
+}
+
+/// What a PROXY protocol header says about the original client
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Header {
+    /// The address of the client, None for connections of the balancer
+    /// itself such as health checks
+    pub source: Option<SocketAddr>,
+    /// The certificate the client presented when the balancer
+    /// terminated TLS, which only version 2 headers can carry
+    pub client_cert: Option<ClientCert>,
+}
+
+/// Read the PROXY protocol header a load balancer sends at the start of
+/// a connection, returning the address of the original client
+///
+/// Both the text (version 1) and binary (version 2) formats are
+/// accepted. Returns None if the header does not carry an address, as
+/// for health checks sent by the balancer itself.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidData` if the connection does not
+/// start with a valid header, or the error of the underlying reader.
+pub fn read_header<R: BufRead>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    read(reader).map(|header| header.source)
+}
+
+/// Read the PROXY protocol header a load balancer sends at the start of
+/// a connection, with the client certificate of version 2 headers
+///
+/// # Errors
+///
+/// The same as for `read_header`.
+pub fn read<R: BufRead>(reader: &mut R) -> io::Result<Header> {
+    // Both formats are at least this long
+    let mut start = [0; 12];
+    reader.read_exact(&mut start)?;
+
+    if &start == V2_SIGNATURE {
+        read_v2(reader)
+    } else if start.starts_with(b"PROXY ") {
+        let source = read_v1(reader, &start)?;
+        Ok(Header { source, client_cert: None })
+    } else {
+        Err(invalid("Connection does not start with a PROXY protocol header."))
+    }
+}
+
+fn read_v1<R: BufRead>(reader: &mut R, start: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LENGTH {
+            return Err(invalid("PROXY protocol header too long."));
+        }
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("Invalid PROXY protocol header."))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", source, _, port, _] | ["PROXY", "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("Invalid source address in PROXY protocol header."))?;
+            let port: u16 = port.parse().map_err(|_| invalid("Invalid source port in PROXY protocol header."))?;
+            let matches_family = if fields[1] == "TCP4" { ip.is_ipv4() } else { ip.is_ipv6() };
+            if !matches_family {
+                return Err(invalid("Address family mismatch in PROXY protocol header."));
+            }
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("Invalid PROXY protocol header.")),
+    }
+}
+
+fn read_v2<R: BufRead>(reader: &mut R) -> io::Result<Header> {
+    let mut fixed = [0; 4];
+    reader.read_exact(&mut fixed)?;
+    let (version_command, family) = (fixed[0], fixed[1]);
+    let length = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload)?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version."));
+    }
+    match version_command & 0x0F {
+        // LOCAL: the balancer's own connection, e.g. a health check
+        0 => return Ok(Header::default()),
+        1 => {}
+        _ => return Err(invalid("Unsupported PROXY protocol command.")),
+    }
+
+    let (source, address_length) = match family >> 4 {
+        1 if payload.len() >= 12 => {
+            let ip = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            (Some(SocketAddr::new(IpAddr::V4(ip), port)), 12)
+        }
+        2 if payload.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&payload[..16]);
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            (Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)), 36)
+        }
+        // Unix sockets and unspecified families carry no usable address
+        0 => (None, 0),
+        3 => (None, payload.len().min(216)),
+        _ => return Err(invalid("Invalid PROXY protocol address block.")),
+    };
+
+    // Of the TLVs after the address, only the TLS ones are used
+    let client_cert = tlvs(&payload[address_length..]).find(|&(kind, _)| kind == PP2_TYPE_SSL).and_then(|(_, ssl)| {
+        if ssl.len() < 5 || ssl[0] & (PP2_CLIENT_CERT_CONN | PP2_CLIENT_CERT_SESS) == 0 {
+            return None;
+        }
+        let verify = u32::from_be_bytes([ssl[1], ssl[2], ssl[3], ssl[4]]);
+        let subject = tlvs(&ssl[5..])
+            .find(|&(kind, _)| kind == PP2_SUBTYPE_SSL_CN)
+            .map(|(_, name)| String::from_utf8_lossy(name).into_owned());
+        Some(ClientCert { verified: verify == 0, subject })
+    });
+    Ok(Header { source, client_cert })
+}
+
+/// The type and value of each complete TLV in a block
+fn tlvs(mut block: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || {
+        if block.len() < 3 {
+            return None;
+        }
+        let length = u16::from_be_bytes([block[1], block[2]]) as usize;
+        let tlv = (block[0], block.get(3..3 + length)?);
+        block = &block[3 + length..];
+        Some(tlv)
+    })
+}
+
+fn invalid(details: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, details)
+}
@@ -0,0 +1,146 @@
+// The channel the workers of a pool learn about new jobs through
+//
+// By default this is a `std::sync::mpsc` channel whose receiver the
+// workers share behind a mutex, which the waiting worker holds for as
+// long as `recv` blocks. With the `mpmc` feature the workers share a
+// queue instead whose lock is only held to take a message out, while
+// idle workers wait on a condition variable, so waking workers up does
+// not serialize them on one lock.
+
+#[cfg(not(feature = "mpmc"))]
+pub(crate) use self::std_channel::{channel, Receiver, Sender};
+
+#[cfg(feature = "mpmc")]
+pub(crate) use self::shared_queue::{channel, Receiver, Sender};
+
+#[cfg(not(feature = "mpmc"))]
+mod std_channel {
+    use std::sync::mpsc::{self, RecvError};
+    use std::sync::{Arc, Mutex};
+
+    pub(crate) type Sender<T> = mpsc::Sender<T>;
+
+    /// A receiver shared by several workers
+    pub(crate) struct Receiver<T> {
+        inner: Arc<Mutex<mpsc::Receiver<T>>>,
+    }
+
+    impl<T> Clone for Receiver<T> {
+        fn clone(&self) -> Receiver<T> {
+            Receiver { inner: Arc::clone(&self.inner) }
+        }
+    }
+
+    impl<T> Receiver<T> {
+        /// Wait for the next message, failing once every sender is gone
+        ///
+        /// # Panics
+        ///
+        /// Panics if another worker panicked while receiving.
+        pub(crate) fn recv(&self) -> Result<T, RecvError> {
+            self.inner.lock().unwrap().recv()
+        }
+    }
+
+    pub(crate) fn channel<T>() -> (Sender<T>, Receiver<T>) {
+        let (sender, receiver) = mpsc::channel();
+        (sender, Receiver { inner: Arc::new(Mutex::new(receiver)) })
+    }
+}
+
+#[cfg(feature = "mpmc")]
+mod shared_queue {
+    use std::collections::VecDeque;
+    use std::sync::mpsc::{RecvError, SendError};
+    use std::sync::{Arc, Condvar, Mutex};
+
+    struct State<T> {
+        messages: VecDeque<T>,
+        senders: usize,
+        receivers: usize,
+    }
+
+    struct Shared<T> {
+        state: Mutex<State<T>>,
+        available: Condvar,
+    }
+
+    pub(crate) struct Sender<T> {
+        shared: Arc<Shared<T>>,
+    }
+
+    pub(crate) struct Receiver<T> {
+        shared: Arc<Shared<T>>,
+    }
+
+    impl<T> Sender<T> {
+        /// Queue a message, failing once every receiver is gone
+        pub(crate) fn send(&self, message: T) -> Result<(), SendError<T>> {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.receivers == 0 {
+                return Err(SendError(message));
+            }
+            state.messages.push_back(message);
+            drop(state);
+            self.shared.available.notify_one();
+            Ok(())
+        }
+    }
+
+    impl<T> Receiver<T> {
+        /// Wait for the next message, failing once every sender is gone
+        /// and the queue is empty
+        pub(crate) fn recv(&self) -> Result<T, RecvError> {
+            let mut state = self.shared.state.lock().unwrap();
+            loop {
+                if let Some(message) = state.messages.pop_front() {
+                    return Ok(message);
+                }
+                if state.senders == 0 {
+                    return Err(RecvError);
+                }
+                state = self.shared.available.wait(state).unwrap();
+            }
+        }
+    }
+
+    impl<T> Clone for Sender<T> {
+        fn clone(&self) -> Sender<T> {
+            self.shared.state.lock().unwrap().senders += 1;
+            Sender { shared: Arc::clone(&self.shared) }
+        }
+    }
+
+    impl<T> Clone for Receiver<T> {
+        fn clone(&self) -> Receiver<T> {
+            self.shared.state.lock().unwrap().receivers += 1;
+            Receiver { shared: Arc::clone(&self.shared) }
+        }
+    }
+
+    impl<T> Drop for Sender<T> {
+        fn drop(&mut self) {
+            let mut state = self.shared.state.lock().unwrap();
+            state.senders -= 1;
+            if state.senders == 0 {
+                drop(state);
+                // Waiting receivers have to notice there is nothing more to come
+                self.shared.available.notify_all();
+            }
+        }
+    }
+
+    impl<T> Drop for Receiver<T> {
+        fn drop(&mut self) {
+            self.shared.state.lock().unwrap().receivers -= 1;
+        }
+    }
+
+    pub(crate) fn channel<T>() -> (Sender<T>, Receiver<T>) {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State { messages: VecDeque::new(), senders: 1, receivers: 1 }),
+            available: Condvar::new(),
+        });
+        (Sender { shared: Arc::clone(&shared) }, Receiver { shared })
+    }
+}
@@ -0,0 +1,224 @@
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::request::Request;
+use crate::response::{Response, ResponseBodyWriter};
+
+/// Most ranges served for one request; a Range header asking for more is
+/// ignored and the whole representation sent, so a client cannot make
+/// the server seek around a file for tiny pieces
+const MAX_RANGES: usize = 16;
+
+/// Distinguishes the boundaries of multipart responses
+static NEXT_BOUNDARY: AtomicU64 = AtomicU64::new(0);
+
+/// A range of bytes of a representation, both ends included
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    /// The number of bytes in the range
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// Whether the range holds no bytes, which no parsed range does
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The value of the Content-Range header for the range
+    fn content_range(&self, length: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, length)
+    }
+}
+
+/// Parse a Range header against the length of a representation
+///
+/// Ranges that overlap or touch are coalesced, and the ranges are then
+/// served in ascending order.
+///
+/// Returns None if the header is to be ignored and the whole
+/// representation sent, because it is malformed, does not ask for bytes
+/// or asks for too many ranges. Returns an empty list if none of the
+/// ranges can be satisfied, to be answered with 416.
+///
+/// ```
+/// use server::range::{self, ByteRange};
+///
+/// assert_eq!(range::parse("bytes=0-9,-5", 100), Some(vec![ByteRange { start: 0, end: 9 }, ByteRange { start: 95, end: 99 }]));
+/// assert_eq!(range::parse("bytes=200-", 100), Some(Vec::new()));
+/// assert_eq!(range::parse("lines=1-2", 100), None);
+/// ```
+pub fn parse(header: &str, length: u64) -> Option<Vec<ByteRange>> {
+    let (unit, set) = header.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let specs: Vec<&str> = set.split(',').map(str::trim).filter(|spec| !spec.is_empty()).collect();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let (first, last) = spec.split_once('-')?;
+        let range = match (first.trim(), last.trim()) {
+            ("", suffix) => {
+                let suffix: u64 = suffix.parse().ok()?;
+                if suffix == 0 || length == 0 {
+                    continue;
+                }
+                ByteRange { start: length.saturating_sub(suffix), end: length - 1 }
+            }
+            (first, last) => {
+                let start: u64 = first.parse().ok()?;
+                let end = match last {
+                    "" => u64::MAX,
+                    last => last.parse().ok()?,
+                };
+                if end < start {
+                    return None;
+                }
+                if start >= length {
+                    continue;
+                }
+                ByteRange { start, end: end.min(length - 1) }
+            }
+        };
+        ranges.push(range);
+    }
+
+    ranges.sort_by_key(|range| range.start);
+    let mut coalesced: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match coalesced.last_mut() {
+            Some(previous) if range.start <= previous.end.saturating_add(1) => previous.end = previous.end.max(range.end),
+            _ => coalesced.push(range),
+        }
+    }
+    Some(coalesced)
+}
+
+/// Respond to a request with a file, or with the ranges of it the
+/// request asks for
+///
+/// A single range is answered with a 206 carrying only that range, and
+/// several with a 206 whose `multipart/byteranges` body holds each of
+/// them with its own Content-Range. Ranges none of which lie within the
+/// file are answered with 416. All responses announce with
+/// Accept-Ranges that ranges are supported. As responses carry no
+/// validator yet, a request with an If-Range header always gets the
+/// whole file.
+///
+/// # Arguments
+///
+/// request - The request whose Range header is honored, if it is a GET.
+/// file - The file to send, positioned at its start.
+/// content_type - The value of the Content-Type header of the file.
+///
+/// # Errors
+///
+/// Returns an error if the file metadata cannot be read or the file
+/// cannot be positioned at the start of a range.
+pub fn file_response(request: &Request, mut file: File, content_type: &str) -> io::Result<Response> {
+    let length = file.metadata()?.len();
+    let response = match requested(request, length).as_deref() {
+        None => Response::from_file(file, content_type)?,
+        Some([]) => Response::text(416, "Range Not Satisfiable").with_header("Content-Range", &format!("bytes */{}", length)),
+        Some([range]) => {
+            file.seek(SeekFrom::Start(range.start))?;
+            Response::new(206)
+                .with_header("Content-Type", content_type)
+                .with_header("Content-Range", &range.content_range(length))
+                .with_file(file, range.len())
+        }
+        Some(ranges) => byteranges(content_type, length, ranges.to_vec(), move |writer, range| {
+            file.seek(SeekFrom::Start(range.start))?;
+            io::copy(&mut (&mut file).take(range.len()), writer).map(|_| ())
+        }),
+    };
+    Ok(response.with_header("Accept-Ranges", "bytes"))
+}
+
+/// Respond to a request with contents held in memory, or with the
+/// ranges of them the request asks for, the same way `file_response`
+/// does with a file
+///
+/// # Arguments
+///
+/// request - The request whose Range header is honored, if it is a GET.
+/// contents - The contents to send, such as an `Arc<[u8]>` shared
+/// rather than copied, or bytes compiled into the binary.
+/// content_type - The value of the Content-Type header of the contents.
+pub fn bytes_response<C>(request: &Request, contents: C, content_type: &str) -> Response
+where
+    C: AsRef<[u8]> + Send + 'static,
+{
+    let length = contents.as_ref().len() as u64;
+    let response = match requested(request, length).as_deref() {
+        None => Response::new(200).with_header("Content-Type", content_type).with_stream(Cursor::new(contents), Some(length)),
+        Some([]) => Response::text(416, "Range Not Satisfiable").with_header("Content-Range", &format!("bytes */{}", length)),
+        Some([range]) => {
+            let mut reader = Cursor::new(contents);
+            reader.set_position(range.start);
+            Response::new(206)
+                .with_header("Content-Type", content_type)
+                .with_header("Content-Range", &range.content_range(length))
+                .with_stream(reader.take(range.len()), Some(range.len()))
+        }
+        Some(ranges) => byteranges(content_type, length, ranges.to_vec(), move |writer, range| {
+            writer.write_all(&contents.as_ref()[range.start as usize..=range.end as usize])
+        }),
+    };
+    response.with_header("Accept-Ranges", "bytes")
+}
+
+/// The ranges a request asks for, if its Range header is honored
+fn requested(request: &Request, length: u64) -> Option<Vec<ByteRange>> {
+    match request.header("Range") {
+        Some(header) if request.method() == "GET" && request.header("If-Range").is_none() => parse(header, length),
+        _ => None,
+    }
+}
+
+/// A 206 response with several ranges of a representation in a
+/// multipart body, each written by a function
+fn byteranges<F>(content_type: &str, length: u64, ranges: Vec<ByteRange>, mut write_range: F) -> Response
+where
+    F: FnMut(&mut ResponseBodyWriter, &ByteRange) -> io::Result<()> + Send + 'static,
+{
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.subsec_nanos());
+    let boundary = format!("{:08x}{:016x}", nanos, NEXT_BOUNDARY.fetch_add(1, Ordering::Relaxed));
+    let heads: Vec<String> = ranges
+        .iter()
+        .map(|range| {
+            format!(
+                "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                boundary,
+                content_type,
+                range.content_range(length)
+            )
+        })
+        .collect();
+    let end = format!("\r\n--{}--\r\n", boundary);
+    let size = heads.iter().map(|head| head.len() as u64).sum::<u64>()
+        + ranges.iter().map(ByteRange::len).sum::<u64>()
+        + end.len() as u64;
+
+    let multipart = format!("multipart/byteranges; boundary={}", boundary);
+    Response::from_writer(&multipart, move |writer| {
+        for (head, range) in heads.iter().zip(&ranges) {
+            writer.write_all(head.as_bytes())?;
+            write_range(writer, range)?;
+        }
+        writer.write_all(end.as_bytes())
+    })
+    .with_status(206)
+    .with_header("Content-Length", &size.to_string())
+}
@@ -0,0 +1,392 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::base64;
+use crate::json::Value;
+use crate::log;
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::Router;
+use crate::state::AppState;
+
+/// Where to record the requests a server receives, so they can be fed
+/// back through a router with `Replay`
+///
+/// Every request is appended to the file as a JSON object on a line of
+/// its own once it has been answered, together with when it arrived.
+/// Bodies are recorded in full, except for those streamed to their
+/// handler, which are recorded as missing. Recordings hold whatever
+/// clients sent, credentials included, so they should be kept as safe
+/// as the server's secrets.
+#[derive(Clone, Debug)]
+pub struct Recording {
+    /// File the requests are appended to, which is created if it does
+    /// not exist
+    pub path: PathBuf,
+    /// Also record the status and headers of every response, and its
+    /// body if it was held in memory, so a replay can tell which
+    /// answers changed
+    pub responses: bool,
+}
+
+/// Writes the recording of a running server
+pub(crate) struct Recorder {
+    responses: bool,
+    file: Mutex<File>,
+    started: Instant,
+}
+
+impl Recorder {
+    /// Open the recording file
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be opened.
+    pub(crate) fn open(recording: &Recording) -> io::Result<Recorder> {
+        let file = OpenOptions::new().create(true).append(true).open(&recording.path)?;
+        Ok(Recorder { responses: recording.responses, file: Mutex::new(file), started: Instant::now() })
+    }
+
+    /// Take what is recorded of a request, before the handler gets it
+    ///
+    /// # Arguments
+    ///
+    /// request - The request, with its body unless it is streamed.
+    /// arrived - When the server started reading the request.
+    /// streamed - Whether the body is streamed to the handler.
+    pub(crate) fn capture(&self, request: &Request, arrived: Instant, streamed: bool) -> Value {
+        let offset = arrived.saturating_duration_since(self.started);
+        Value::object()
+            .with("offset_ms", offset.as_secs_f64() * 1000.0)
+            .with("method", request.method())
+            .with("target", request.target())
+            .with("version", request.version())
+            .with("headers", fields(request.headers()))
+            .with("client_ip", request.client_ip().map(|ip| ip.to_string()))
+            .with("body", Some(base64::encode(request.body())).filter(|_| !streamed))
+    }
+
+    /// What is recorded of a response, if responses are, taken before
+    /// it is sent
+    pub(crate) fn response(&self, response: &Response) -> Option<Value> {
+        if !self.responses {
+            return None;
+        }
+        let headers = response.headers().iter().map(|(name, value)| (name.as_str(), value.as_str()));
+        Some(
+            Value::object()
+                .with("status", response.status())
+                .with("headers", fields(headers))
+                .with("body", response.body().as_bytes().map(base64::encode)),
+        )
+    }
+
+    /// Append an answered request
+    ///
+    /// # Panics
+    ///
+    /// Panics if the file mutex is in a poisoned state.
+    pub(crate) fn write(&self, request: Value, response: Option<Value>) {
+        let mut line = match response {
+            Some(response) => request.with("response", response),
+            None => request,
+        }
+        .to_string();
+        line.push('\n');
+        // One write per line, so the lines of concurrent requests do not
+        // mix
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            log::error(&format!("Failed to record request: {}", e));
+        }
+    }
+}
+
+/// Header fields as an array of name and value pairs, which keeps their
+/// order and repeated names
+fn fields<'a, I: Iterator<Item = (&'a str, &'a str)>>(fields: I) -> Vec<Value> {
+    fields
+        .map(|(name, value)| Value::Array(vec![Value::String(String::from(name)), Value::String(String::from(value))]))
+        .collect()
+}
+
+/// A request read back from a recording
+pub struct Recorded {
+    /// When the request arrived, counted from the start of the recording
+    pub offset: Duration,
+    pub request: Request,
+    /// The status the request was answered with, if responses were
+    /// recorded
+    pub status: Option<u16>,
+    /// The body the request was answered with, if responses were
+    /// recorded and the body was held in memory
+    pub response_body: Option<Vec<u8>>,
+}
+
+/// Read the requests of a recording, in the order they arrived
+///
+/// Requests whose body was streamed to their handler come back with an
+/// empty body.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read or a line is not a
+/// recorded request.
+pub fn read<P: Into<PathBuf>>(path: P) -> Result<Vec<Recorded>, RecordError> {
+    let path = path.into();
+    let contents = fs::read_to_string(&path)
+        .map_err(|e| RecordError::new(&format!("Cannot read {}: {}", path.display(), e)))?;
+    let mut recorded = Vec::new();
+    for (number, line) in contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let entry = Value::parse(line)
+            .ok()
+            .and_then(|entry| parse_entry(&entry))
+            .ok_or_else(|| RecordError::new(&format!("Line {} is not a recorded request.", number + 1)))?;
+        recorded.push(entry);
+    }
+    // Lines are written as requests are answered, which is not always
+    // the order they arrived in
+    recorded.sort_by_key(|entry| entry.offset);
+    Ok(recorded)
+}
+
+fn parse_entry(entry: &Value) -> Option<Recorded> {
+    let offset = Duration::try_from_secs_f64(entry.get("offset_ms")?.as_f64()? / 1000.0).ok()?;
+    let mut request = Request::new(entry.get("method")?.as_str()?, entry.get("target")?.as_str()?);
+    for field in entry.get("headers")?.as_array()? {
+        match field.as_array()? {
+            [name, value] => request = request.with_header(name.as_str()?, value.as_str()?),
+            _ => return None,
+        }
+    }
+    if let Some(body) = entry.get("body").and_then(Value::as_str) {
+        request = request.with_body(base64::decode(body)?);
+    }
+    if let Some(ip) = entry.get("client_ip").and_then(Value::as_str) {
+        request.set_client_ip(ip.parse::<IpAddr>().ok()?);
+    }
+    let response = entry.get("response");
+    let status = match response.and_then(|response| response.get("status")) {
+        Some(status) => Some(status.as_f64().filter(|status| (100.0..1000.0).contains(status))? as u16),
+        None => None,
+    };
+    let response_body = match response.and_then(|response| response.get("body")).and_then(Value::as_str) {
+        Some(body) => Some(base64::decode(body)?),
+        None => None,
+    };
+    Some(Recorded { offset, request, status, response_body })
+}
+
+/// Feeds a recording back through a router, at the pace the requests
+/// arrived or faster, and reports how the answers compare to the
+/// recorded ones
+///
+/// Requests are handled on worker threads of the replay's own, each
+/// dispatched when it is due; when every worker is busy, requests wait
+/// and fall behind the recorded pace. Only the router sees the
+/// requests, not the server, so connection handling, limits and
+/// anything else the server does around the router are not replayed.
+///
+/// ```no_run
+/// use server::record::Replay;
+/// use server::router::Router;
+///
+/// let mut router = Router::new();
+/// router.get("/", |_| "Hello");
+/// let report = Replay::new("traffic.jsonl").with_speed(10.0).run(&router).unwrap();
+/// println!("{}", report);
+/// ```
+pub struct Replay {
+    path: PathBuf,
+    speed: f64,
+    concurrency: usize,
+    state: Arc<AppState>,
+}
+
+impl Replay {
+    /// Replay a recording at the pace it was recorded, handling up to 16
+    /// requests at once
+    pub fn new<P: Into<PathBuf>>(path: P) -> Replay {
+        Replay { path: path.into(), speed: 1.0, concurrency: 16, state: Arc::new(AppState::new()) }
+    }
+
+    /// Set how many times faster than recorded the requests are sent,
+    /// such as 2.0 for twice as fast, or `f64::INFINITY` to send each as
+    /// soon as a worker is free
+    pub fn with_speed(mut self, speed: f64) -> Replay {
+        self.speed = speed;
+        self
+    }
+
+    /// Set the most requests handled at once
+    pub fn with_concurrency(mut self, concurrency: usize) -> Replay {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Share values with every handler, as `Server::with_state` does
+    pub fn with_state(mut self, state: Arc<AppState>) -> Replay {
+        self.state = state;
+        self
+    }
+
+    /// Replay the recording through a router
+    ///
+    /// Responses answered differently than recorded are logged as
+    /// warnings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the speed is not positive, the concurrency is
+    /// zero or the recording cannot be read.
+    pub fn run(&self, router: &Router) -> Result<ReplayReport, RecordError> {
+        if self.speed.is_nan() || self.speed <= 0.0 {
+            return Err(RecordError::new("The replay speed has to be positive."));
+        }
+        if self.concurrency == 0 {
+            return Err(RecordError::new("At least one request has to be replayed at a time."));
+        }
+        let recorded = read(&self.path)?;
+        // The replay starts with the first request rather than with the
+        // recording server
+        let first = recorded.first().map_or(Duration::ZERO, |entry| entry.offset);
+
+        let start = Instant::now();
+        let (sender, receiver) = mpsc::sync_channel::<Recorded>(0);
+        let receiver = Mutex::new(receiver);
+        let mut report = thread::scope(|scope| {
+            let workers: Vec<_> = (0..self.concurrency)
+                .map(|_| scope.spawn(|| self.work(router, &receiver)))
+                .collect();
+            for entry in recorded {
+                let due = start + (entry.offset - first).div_f64(self.speed);
+                if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+                if sender.send(entry).is_err() {
+                    break;
+                }
+            }
+            drop(sender);
+            let mut report = ReplayReport::default();
+            for worker in workers {
+                report.add(worker.join().unwrap_or_default());
+            }
+            report
+        });
+        report.elapsed = start.elapsed();
+        Ok(report)
+    }
+
+    /// Handle requests until the recording runs out
+    fn work(&self, router: &Router, receiver: &Mutex<mpsc::Receiver<Recorded>>) -> ReplayReport {
+        let mut report = ReplayReport::default();
+        loop {
+            // The lock is released before the request is handled
+            let next = receiver.lock().unwrap().recv();
+            let Recorded { mut request, status, response_body, .. } = match next {
+                Ok(entry) => entry,
+                Err(_) => return report,
+            };
+            let method = String::from(request.method());
+            let target = String::from(request.target());
+            request.set_state(Arc::clone(&self.state));
+            report.requests += 1;
+            let response = match panic::catch_unwind(AssertUnwindSafe(|| router.handle(request))) {
+                Ok(response) => response,
+                Err(_) => {
+                    report.panics += 1;
+                    continue;
+                }
+            };
+            *report.statuses.entry(response.status()).or_insert(0) += 1;
+            if let Some(status) = status.filter(|&status| status != response.status()) {
+                report.status_mismatches += 1;
+                log::warn(&format!("Replayed {} {} answered {} instead of {}", method, target, response.status(), status));
+            } else if let (Some(recorded), Some(body)) = (&response_body, response.body().as_bytes()) {
+                if recorded.as_slice() != body {
+                    report.body_mismatches += 1;
+                    log::warn(&format!("Replayed {} {} answered with a different body", method, target));
+                }
+            }
+        }
+    }
+}
+
+/// The outcome of a replay
+#[derive(Default)]
+pub struct ReplayReport {
+    /// How long the replay took
+    pub elapsed: Duration,
+    /// Number of requests replayed
+    pub requests: u64,
+    /// Number of responses per status code
+    pub statuses: BTreeMap<u16, u64>,
+    /// Number of responses with another status than recorded
+    pub status_mismatches: u64,
+    /// Number of responses with the recorded status but another body,
+    /// counting only bodies held in memory both times
+    pub body_mismatches: u64,
+    /// Number of requests whose handler panicked
+    pub panics: u64,
+}
+
+impl ReplayReport {
+    fn add(&mut self, other: ReplayReport) {
+        self.requests += other.requests;
+        self.status_mismatches += other.status_mismatches;
+        self.body_mismatches += other.body_mismatches;
+        self.panics += other.panics;
+        for (status, count) in other.statuses {
+            *self.statuses.entry(status).or_insert(0) += count;
+        }
+    }
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} requests replayed in {:.2}s, {} with another status, {} with another body, {} panicked",
+            self.requests,
+            self.elapsed.as_secs_f64(),
+            self.status_mismatches,
+            self.body_mismatches,
+            self.panics
+        )?;
+        let statuses: Vec<String> = self.statuses.iter().map(|(status, n)| format!("{}: {}", status, n)).collect();
+        write!(f, "Statuses: {}", statuses.join(", "))
+    }
+}
+
+#[derive(Debug)]
+pub struct RecordError {
+    details: String,
+}
+
+impl RecordError {
+    pub fn new(details: &str) -> RecordError {
+        RecordError { details: String::from(details) }
+    }
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for RecordError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
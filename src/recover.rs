@@ -0,0 +1,104 @@
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+
+use crate::log;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::{reason_phrase, Response};
+
+thread_local! {
+    /// Whether a panic on this thread happens below a `CatchPanic`
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+    /// Where the last panic caught on this thread happened, and how it
+    /// got there
+    static CAUGHT: RefCell<Option<(String, Backtrace)>> = const { RefCell::new(None) };
+}
+
+static HOOK: Once = Once::new();
+
+/// A middleware answering requests whose handler panicked with a 500
+///
+/// Without it, a panic unwinds through the worker thread running the
+/// request, which drops the connection without a response and leaves
+/// the pool a worker short. With it, the panic is logged with its
+/// message, location, backtrace and the request id, the client gets a
+/// plain 500 and the connection stays usable.
+///
+/// It should be the outermost middleware, registered with `Router::wrap`
+/// before any other, so panics in middleware are caught as well:
+///
+/// ```no_run
+/// use server::recover::CatchPanic;
+/// use server::request::Request;
+/// use server::router::Router;
+///
+/// let mut router = Router::new();
+/// router.wrap(CatchPanic::new());
+/// router.get("/boom", |_request: Request| -> &'static str { panic!("boom") });
+/// ```
+///
+/// Panics below it are reported by it alone instead of the panic hook
+/// that was installed before, which still reports all others.
+pub struct CatchPanic {
+    _private: (),
+}
+
+impl CatchPanic {
+    pub fn new() -> CatchPanic {
+        HOOK.call_once(|| {
+            let previous = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                if CATCHING.with(Cell::get) {
+                    let location = info.location().map(ToString::to_string).unwrap_or_else(|| String::from("unknown location"));
+                    CAUGHT.with(|caught| *caught.borrow_mut() = Some((location, Backtrace::force_capture())));
+                } else {
+                    previous(info);
+                }
+            }));
+        });
+        CatchPanic { _private: () }
+    }
+}
+
+impl Default for CatchPanic {
+    fn default() -> CatchPanic {
+        CatchPanic::new()
+    }
+}
+
+impl Middleware for CatchPanic {
+    fn handle(&self, request: Request, next: &Next) -> Response {
+        let outer = CATCHING.with(|catching| catching.replace(true));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| next.run(request)));
+        CATCHING.with(|catching| catching.set(outer));
+
+        let payload = match result {
+            Ok(response) => return response,
+            Err(payload) => payload,
+        };
+        let (location, backtrace) = CAUGHT
+            .with(|caught| caught.borrow_mut().take())
+            .map(|(location, backtrace)| (location, backtrace.to_string()))
+            .unwrap_or_else(|| (String::from("unknown location"), String::new()));
+        let request = log::request_id().unwrap_or_else(|| String::from("-"));
+        log::error(&format!(
+            "Request {} panicked at {}: {}\n{}",
+            request,
+            location,
+            message(payload.as_ref()),
+            backtrace
+        ));
+        Response::text(500, reason_phrase(500))
+    }
+}
+
+/// The message a panic was started with
+pub(crate) fn message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&'static str>() {
+        Some(message) => message,
+        None => payload.downcast_ref::<String>().map(String::as_str).unwrap_or("Box<dyn Any>"),
+    }
+}
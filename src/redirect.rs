@@ -0,0 +1,141 @@
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use crate::host;
+use crate::log;
+use crate::request::{Limits, Request};
+use crate::response::{reason_phrase, Response};
+use crate::PoolHandle;
+
+/// How long a client of the redirect listener may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where ACME HTTP-01 challenges are requested, as specified in RFC 8555
+const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// A plain HTTP listener that sends every client to the https origin
+#[derive(Clone)]
+pub struct HttpsRedirect {
+    /// Address the plain HTTP listener is bound to
+    pub address: String,
+    /// Port of the https origin; left out of the redirects when it is
+    /// the default 443
+    pub https_port: u16,
+    /// Directory an ACME client such as certbot in webroot mode writes
+    /// its HTTP-01 challenges to, under `.well-known/acme-challenge`;
+    /// requests for them are answered from there instead of redirected,
+    /// so certificates for the https origin can be issued and renewed.
+    /// After a chroot it is looked up inside the new root.
+    pub acme_webroot: Option<PathBuf>,
+}
+
+impl Default for HttpsRedirect {
+    fn default() -> HttpsRedirect {
+        HttpsRedirect {
+            address: String::from("0.0.0.0:80"),
+            https_port: 443,
+            acme_webroot: None,
+        }
+    }
+}
+
+/// A bound redirect listener, not accepting yet
+pub(crate) struct RedirectListener {
+    listener: TcpListener,
+    https_port: u16,
+    acme_webroot: Option<PathBuf>,
+}
+
+impl RedirectListener {
+    /// Bind the plain HTTP listener, which has to happen before
+    /// privileges are dropped if it uses port 80
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the address cannot be bound.
+    pub(crate) fn bind(config: &HttpsRedirect) -> io::Result<RedirectListener> {
+        Ok(RedirectListener {
+            listener: TcpListener::bind(&config.address)?,
+            https_port: config.https_port,
+            acme_webroot: config.acme_webroot.clone(),
+        })
+    }
+
+    /// Accept connections on a thread of its own, answering them in a pool
+    pub(crate) fn spawn(self, pool: PoolHandle, limits: Limits) {
+        thread::spawn(move || {
+            for stream in self.listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let limits = limits.clone();
+                        let https_port = self.https_port;
+                        let acme_webroot = self.acme_webroot.clone();
+                        pool.execute(move || redirect(stream, &limits, https_port, acme_webroot));
+                    }
+                    Err(e) => log::error(&format!("Failed to accept connection to redirect: {}", e)),
+                }
+            }
+        });
+    }
+}
+
+/// Answer one request with a redirect to the same path and query on the
+/// https origin, or with an ACME challenge, and close the connection
+fn redirect(stream: TcpStream, limits: &Limits, https_port: u16, acme_webroot: Option<PathBuf>) {
+    if let Err(e) = stream.set_read_timeout(Some(REQUEST_TIMEOUT)) {
+        log::warn(&format!("Failed to set read timeout: {}", e));
+    }
+    let mut reader = BufReader::new(&stream);
+    let mut response = match Request::parse_with_limits(&mut reader, limits) {
+        Ok(request) => match (acme_webroot, request.path().strip_prefix(ACME_CHALLENGE_PREFIX)) {
+            (Some(webroot), Some(token)) => acme_challenge(&webroot, token),
+            _ => match location(&request, https_port) {
+                Some(location) => Response::new(301).with_header("Location", &location),
+                None => Response::text(400, reason_phrase(400)),
+            },
+        },
+        Err(e) => Response::text(e.status(), reason_phrase(e.status())),
+    };
+    response.set_header("Connection", "close");
+    let mut stream = &stream;
+    if let Err(e) = response.write_to(&mut stream) {
+        log::warn(&format!("Failed to send redirect: {}", e));
+    }
+}
+
+/// The key authorization an ACME client left for a challenge token
+fn acme_challenge(webroot: &std::path::Path, token: &str) -> Response {
+    // Tokens are base64url, which also keeps them from leaving the directory
+    let valid = !token.is_empty() && token.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    let file = if valid {
+        File::open(webroot.join(&ACME_CHALLENGE_PREFIX[1..]).join(token)).ok()
+    } else {
+        None
+    };
+    match file.map(|file| Response::from_file(file, "application/octet-stream")) {
+        Some(Ok(response)) => response,
+        Some(Err(e)) => {
+            log::warn(&format!("Failed to read ACME challenge {}: {}", token, e));
+            Response::text(500, reason_phrase(500))
+        }
+        None => Response::text(404, reason_phrase(404)),
+    }
+}
+
+/// The https URL for a request, or None if it does not name a host
+fn location(request: &Request, https_port: u16) -> Option<String> {
+    let (host, _) = host::split_host(request.header("Host")?)?;
+    if host.is_empty() {
+        return None;
+    }
+    let target = request.origin_target();
+    Some(match https_port {
+        443 => format!("https://{}{}", host, target),
+        port => format!("https://{}:{}{}", host, port, target),
+    })
+}
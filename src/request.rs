@@ -0,0 +1,1250 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::body::Body;
+use crate::cancel::CancellationToken;
+use crate::early_hints::EarlyHints;
+use crate::extensions::Extensions;
+use crate::headers;
+use crate::host;
+use crate::inflate;
+use crate::state::AppState;
+use crate::uri;
+
+/// The most bytes of a body read from the connection at once
+const BODY_PIECE: usize = 64 * 1024;
+
+/// A parsed HTTP request
+pub struct Request {
+    method: String,
+    target: String,
+    path: String,
+    query: Option<String>,
+    version: String,
+    headers: Fields,
+    body: Vec<u8>,
+    stream: Option<Body>,
+    trailers: Fields,
+    peer_addr: Option<SocketAddr>,
+    client_ip: Option<IpAddr>,
+    params: Vec<(String, String)>,
+    state: Option<Arc<AppState>>,
+    extensions: Extensions,
+    cancellation: CancellationToken,
+    deadline: Option<Instant>,
+    early_hints: EarlyHints,
+}
+
+impl Request {
+    /// Create a request without reading it from a connection
+    ///
+    /// # Arguments
+    ///
+    /// method - The request method, e.g. GET.
+    /// target - The request target, i.e. the path with an optional query
+    /// string, or an absolute URL such as `http://example.com/path`.
+    ///
+    /// If the path contains invalid %-escapes it is kept undecoded.
+    pub fn new(method: &str, target: &str) -> Request {
+        let (raw_path, query) = split_target(absolute_form(target).map_or(target, |(_, rest)| rest));
+        let path = uri::percent_decode(&raw_path).unwrap_or(raw_path);
+        Request {
+            method: String::from(method),
+            target: String::from(target),
+            path,
+            query,
+            version: String::from("HTTP/1.1"),
+            headers: Fields::default(),
+            body: Vec::new(),
+            stream: None,
+            trailers: Fields::default(),
+            peer_addr: None,
+            client_ip: None,
+            params: Vec::new(),
+            state: None,
+            extensions: Extensions::new(),
+            cancellation: CancellationToken::new(),
+            deadline: None,
+            early_hints: EarlyHints::default(),
+        }
+    }
+
+    /// Read and parse a request from a reader with the default limits
+    ///
+    /// # Arguments
+    ///
+    /// reader - A buffered reader positioned at the start of a request.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request is malformed, exceeds the limits
+    /// or the connection fails before a complete request has been read.
+    pub fn parse<R: BufRead>(reader: &mut R) -> Result<Request, ParseError> {
+        Request::parse_with_limits(reader, &Limits::default())
+    }
+
+    /// Read and parse a request from a reader
+    ///
+    /// No more of the head than the limits allow is ever buffered, so a
+    /// client cannot make the server hold arbitrary amounts of input.
+    /// The head is copied into a single buffer that header names and
+    /// values are slices of, rather than into strings of their own.
+    ///
+    /// # Arguments
+    ///
+    /// reader - A buffered reader positioned at the start of a request.
+    /// limits - The maximum sizes of the request head.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request is malformed, exceeds the limits
+    /// or the connection fails before a complete request has been read.
+    /// `ParseError::status` gives the status to respond with.
+    pub fn parse_with_limits<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Request, ParseError> {
+        let (mut request, head) = Request::parse_head(reader, limits)?;
+        request.read_body(reader, limits, head)?;
+        Ok(request)
+    }
+
+    /// Read and parse the head of a request, up to its body
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the head is malformed, exceeds the limits or
+    /// the connection fails before all of it has been read.
+    pub(crate) fn parse_head<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<(Request, Head), ParseError> {
+        // What is buffered usually holds the whole head, so the buffer
+        // rarely has to grow
+        let mut head = Vec::with_capacity(reader.fill_buf()?.len().min(limits.max_head_size));
+        let mut head_size = match read_line(reader, &mut head, limits.max_request_line)? {
+            Some(read) => read,
+            None => return Err(ParseError::new("Connection closed before a request was received.")),
+        };
+        if head_size > limits.max_request_line {
+            return Err(ParseError::with_status(414, "Request line too long."));
+        }
+        check_ending(&head, limits)?;
+        let line = as_str(&head)?;
+
+        let mut parts = line.trim_end().split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version), None) if !method.is_empty() && !target.is_empty() => {
+                (method, target, version)
+            }
+            _ => return Err(ParseError::new("Malformed request line.")),
+        };
+        if !version.starts_with("HTTP/") {
+            return Err(ParseError::new("Unsupported protocol version."));
+        }
+
+        let absolute = absolute_form(target);
+        let (raw_path, _) = split_target(absolute.map_or(target, |(_, rest)| rest));
+        let path = uri::percent_decode(&raw_path).map_err(|e| ParseError::new(&e.to_string()))?;
+
+        let mut request = Request::new(method, target);
+        request.path = path;
+        request.version = String::from(version);
+
+        if let Some((authority, _)) = absolute {
+            let authority = String::from(authority);
+            request.headers = read_fields(reader, limits, &mut head_size, head)?;
+            // The authority of an absolute-form target takes the place of
+            // the Host header, so both cannot disagree later on
+            request.headers.retain(|name, _| !name.eq_ignore_ascii_case("Host"));
+            request.headers.push("Host", &authority);
+        } else {
+            request.headers = read_fields(reader, limits, &mut head_size, head)?;
+        }
+        let hosts: Vec<&str> = request
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Host"))
+            .map(|(_, value)| value)
+            .collect();
+        match hosts.as_slice() {
+            [] if request.version != "HTTP/1.0" => return Err(ParseError::new("Missing Host header.")),
+            [] => {}
+            [value] if value.is_empty() || host::split_host(value).is_some() => {}
+            [_] => return Err(ParseError::new("Invalid Host header.")),
+            _ => return Err(ParseError::new("Multiple Host headers.")),
+        }
+
+        let framing = framing(&request, limits)?;
+        Ok((request, Head { framing, size: head_size }))
+    }
+
+    /// Read the body announced by the head of the request into memory
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the body is malformed, larger than what is
+    /// left of `max_buffered` after the head, or the connection fails
+    /// before all of it has been read.
+    pub(crate) fn read_body<R: BufRead>(&mut self, reader: &mut R, limits: &Limits, head: Head) -> Result<(), ParseError> {
+        let framing = match head.framing {
+            Some(framing) => framing,
+            None => return Ok(()),
+        };
+        let budget = limits.max_buffered.saturating_sub(head.size) as u64;
+        let mut body = Vec::new();
+        self.trailers = read_body(reader, limits, framing, budget, |piece| body.extend_from_slice(&piece))?;
+        self.body = body;
+        self.decode_body(limits)
+    }
+
+    /// Undo the gzip and deflate content codings of the body, so the
+    /// handler gets what the client compressed, with Content-Length
+    /// set to its decompressed size
+    fn decode_body(&mut self, limits: &Limits) -> Result<(), ParseError> {
+        let codings: Vec<String> = self
+            .header_values("Content-Encoding")
+            .flat_map(|value| value.split(','))
+            .map(|coding| coding.trim().to_ascii_lowercase())
+            .filter(|coding| !coding.is_empty() && coding != "identity")
+            .collect();
+        if codings.is_empty() || self.body.is_empty() {
+            return Ok(());
+        }
+        let mut body = std::mem::take(&mut self.body);
+        // Codings are listed in the order they were applied
+        for coding in codings.iter().rev() {
+            let decoded = match coding.as_str() {
+                "gzip" | "x-gzip" => inflate::gunzip(&body, limits.max_decompressed_body),
+                "deflate" => inflate::inflate(&body, limits.max_decompressed_body),
+                _ => return Err(ParseError::with_status(415, &format!("Unsupported request content coding {}.", coding))),
+            };
+            body = decoded.map_err(|e| {
+                if e.is_too_large() {
+                    ParseError::with_status(413, "Decompressed request body too large.")
+                } else {
+                    ParseError::new(&format!("Malformed compressed request body: {}", e))
+                }
+            })?;
+        }
+        self.headers.retain(|name, _| !name.eq_ignore_ascii_case("Content-Encoding") && !name.eq_ignore_ascii_case("Content-Length"));
+        self.headers.push("Content-Length", &body.len().to_string());
+        self.body = body;
+        Ok(())
+    }
+
+    /// The request method
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// The request target exactly as it was sent
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// The request target as a path with an optional query string, with
+    /// the scheme and authority of an absolute-form target removed
+    pub fn origin_target(&self) -> String {
+        match absolute_form(&self.target) {
+            Some((_, rest)) if rest.starts_with('/') => String::from(rest),
+            Some((_, rest)) => format!("/{}", rest),
+            None => self.target.clone(),
+        }
+    }
+
+    /// The path part of the request target, with %-escapes decoded
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The query string, without the leading question mark
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    /// The protocol version, e.g. HTTP/1.1
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Get the value of the first header with the given name
+    ///
+    /// Header names are compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// All headers as names and values, in the order they were received
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter()
+    }
+
+    /// The values of all headers with the given name, compared
+    /// case-insensitively, in the order they were received
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.headers.iter().filter(move |(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, value)| value)
+    }
+
+    /// The media type of the body without its parameters, e.g.
+    /// `application/json`, to be compared case-insensitively
+    pub fn content_type(&self) -> Option<&str> {
+        self.header("Content-Type").map(headers::media_type)
+    }
+
+    /// The length of the body as the client announced it, if it did
+    pub fn content_length(&self) -> Option<u64> {
+        self.header("Content-Length").and_then(|length| length.trim().parse().ok())
+    }
+
+    /// The software the client says it is
+    pub fn user_agent(&self) -> Option<&str> {
+        self.header("User-Agent")
+    }
+
+    /// The page the request was made from, if the client tells
+    pub fn referer(&self) -> Option<&str> {
+        self.header("Referer")
+    }
+
+    /// The request body
+    ///
+    /// Empty if the route streams the body, see `take_body`.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Take the body out of the request to read it in chunks
+    ///
+    /// For a route set to `Route::stream_body`, this is the only way to
+    /// get at the body, which is then still being received. Otherwise it
+    /// holds the body that was read before the handler was called, which
+    /// `body` is empty afterwards. Trailers of a streamed body are not
+    /// available.
+    pub fn take_body(&mut self) -> Body {
+        match self.stream.take() {
+            Some(stream) => stream,
+            None => Body::buffered(std::mem::take(&mut self.body)),
+        }
+    }
+
+    /// Have the body be read in chunks from a stream
+    pub(crate) fn set_body_stream(&mut self, stream: Body) {
+        self.stream = Some(stream);
+    }
+
+    /// Get the value of the first trailer field with the given name
+    ///
+    /// Trailers follow a chunked body, so they are only present if the
+    /// client sent one. Names are compared case-insensitively.
+    pub fn trailer(&self, name: &str) -> Option<&str> {
+        self.trailers.get(name)
+    }
+
+    /// All trailer fields as names and values, in the order they were
+    /// received
+    pub fn trailers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.trailers.iter()
+    }
+
+    /// Address of the client that sent the request, if known
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// IP address of the client the request originates from
+    ///
+    /// Behind trusted proxies this is taken from the forwarding headers,
+    /// otherwise it is the address of the peer.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.client_ip.or_else(|| self.peer_addr.map(|addr| addr.ip()))
+    }
+
+    /// Get a parameter captured from the path by the matched route,
+    /// e.g. `id` for the pattern `/users/:id`
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All parameters captured from the path, in pattern order
+    pub fn params(&self) -> &[(String, String)] {
+        &self.params
+    }
+
+    /// The query string parsed into decoded name/value pairs
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        self.query.as_deref().map(uri::parse_query).unwrap_or_default()
+    }
+
+    /// Add a header to the request
+    pub fn with_header(mut self, name: &str, value: &str) -> Request {
+        self.headers.push(name, value);
+        self
+    }
+
+    /// Replace the request body
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Request {
+        self.body = body.into();
+        self
+    }
+
+    /// A copy of the request as it was read, body included, for sending
+    /// it elsewhere too, or None if its body is still to be streamed; the
+    /// copy has its own extensions, and no state, deadline or cancellation
+    pub(crate) fn duplicate(&self) -> Option<Request> {
+        if self.stream.is_some() {
+            return None;
+        }
+        Some(Request {
+            method: self.method.clone(),
+            target: self.target.clone(),
+            path: self.path.clone(),
+            query: self.query.clone(),
+            version: self.version.clone(),
+            headers: self.headers.clone(),
+            body: self.body.clone(),
+            stream: None,
+            trailers: self.trailers.clone(),
+            peer_addr: self.peer_addr,
+            client_ip: self.client_ip,
+            params: self.params.clone(),
+            state: None,
+            extensions: Extensions::new(),
+            cancellation: CancellationToken::new(),
+            deadline: None,
+            early_hints: EarlyHints::default(),
+        })
+    }
+
+    /// Replace the decoded path, e.g. after normalizing or rewriting it
+    pub fn set_path(&mut self, path: &str) {
+        self.path = String::from(path);
+    }
+
+    /// Replace the query string, e.g. after rewriting the path
+    pub fn set_query(&mut self, query: Option<&str>) {
+        self.query = query.map(String::from);
+    }
+
+    /// Replace the parameters captured from the path
+    pub fn set_params(&mut self, params: Vec<(String, String)>) {
+        self.params = params;
+    }
+
+    /// Set the address of the client that sent the request
+    pub fn set_peer_addr(&mut self, addr: SocketAddr) {
+        self.peer_addr = Some(addr);
+    }
+
+    /// Set the IP address of the client behind any proxies
+    pub fn set_client_ip(&mut self, ip: IpAddr) {
+        self.client_ip = Some(ip);
+    }
+
+    /// The application state of the server handling the request, which
+    /// is empty if the server has none
+    pub fn state(&self) -> &AppState {
+        match &self.state {
+            Some(state) => state,
+            None => empty_state(),
+        }
+    }
+
+    /// A reference to the state that outlives the request, for handlers
+    /// that take the request by value
+    pub(crate) fn shared_state(&self) -> Arc<AppState> {
+        match &self.state {
+            Some(state) => Arc::clone(state),
+            None => Arc::clone(empty_state()),
+        }
+    }
+
+    /// Set the application state, see `Server::with_state`
+    pub fn set_state(&mut self, state: Arc<AppState>) {
+        self.state = Some(state);
+    }
+
+    /// Values attached to the request by middleware, see `Extensions`
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Values attached to the request, for adding or changing them
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// The token cancelled once the response to the request is no longer
+    /// wanted, as when its route's timeout has passed
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancellation
+    }
+
+    /// When the response to the request is due, if it has a deadline
+    ///
+    /// The server sets it from the timeout of the request's route and
+    /// from the timeout the client sent, see `Config::deadline_header`,
+    /// whichever ends first. Handlers should bound their own calls to
+    /// other services by it, as `Proxy` does.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// How long is left until the deadline, zero once it has passed, or
+    /// None if the request has no deadline
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Where informational responses such as 103 Early Hints are sent
+    /// ahead of the final response
+    pub fn early_hints(&self) -> &EarlyHints {
+        &self.early_hints
+    }
+
+    pub(crate) fn set_early_hints(&mut self, early_hints: EarlyHints) {
+        self.early_hints = early_hints;
+    }
+
+    /// Set the deadline, or move it earlier if the request has an
+    /// earlier one already
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(self.deadline.map_or(deadline, |current| current.min(deadline)));
+    }
+}
+
+/// How the body of a request is framed, from all of its Content-Length
+/// and Transfer-Encoding fields, rejecting requests that framing could
+/// be read differently for by another server in front of this one
+fn framing(request: &Request, limits: &Limits) -> Result<Option<Framing>, ParseError> {
+    let fields = |name: &'static str| {
+        request
+            .headers
+            .iter()
+            .filter(move |(field, _)| field.eq_ignore_ascii_case(name))
+            .flat_map(|(_, value)| value.split(','))
+            .map(str::trim)
+    };
+    let lengths: Vec<&str> = fields("Content-Length").collect();
+    let length = match lengths.split_first() {
+        None => None,
+        Some((first, rest)) => {
+            // Digits only, as parsing would accept a sign
+            if first.is_empty() || !first.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseError::new("Invalid Content-Length header."));
+            }
+            if rest.iter().any(|other| other != first) {
+                return Err(ParseError::new("Conflicting Content-Length values."));
+            }
+            if !rest.is_empty() && limits.strictness == Strictness::Strict {
+                return Err(ParseError::new("Repeated Content-Length value."));
+            }
+            Some(first.parse().map_err(|_| ParseError::new("Invalid Content-Length header."))?)
+        }
+    };
+
+    if request.headers.get("Transfer-Encoding").is_none() {
+        return Ok(length.map(Framing::Length));
+    }
+    // Only chunked is supported, and it has to come last for the end of
+    // the body to be recognizable
+    let codings: Vec<&str> = fields("Transfer-Encoding").filter(|coding| !coding.is_empty()).collect();
+    if !matches!(codings.as_slice(), [coding] if coding.eq_ignore_ascii_case("chunked")) {
+        return Err(ParseError::with_status(501, "Unsupported Transfer-Encoding."));
+    }
+    if length.is_some() {
+        return Err(ParseError::new("Both Content-Length and Transfer-Encoding are set."));
+    }
+    // HTTP/1.0 has no transfer codings, so a server in front may have
+    // ignored it
+    if request.version == "HTTP/1.0" && limits.strictness == Strictness::Strict {
+        return Err(ParseError::new("Transfer-Encoding in an HTTP/1.0 request."));
+    }
+    Ok(Some(Framing::Chunked))
+}
+
+/// Reject a line ending in a bare LF unless the limits allow it
+fn check_ending(line: &[u8], limits: &Limits) -> Result<(), ParseError> {
+    if limits.strictness == Strictness::Strict && line.ends_with(b"\n") && !line.ends_with(b"\r\n") {
+        return Err(ParseError::new("Line ends in a bare LF."));
+    }
+    Ok(())
+}
+
+fn empty_state() -> &'static Arc<AppState> {
+    static EMPTY: OnceLock<Arc<AppState>> = OnceLock::new();
+    EMPTY.get_or_init(|| Arc::new(AppState::new()))
+}
+
+/// Maximum sizes of a request, beyond which parsing stops, and how
+/// strictly its framing is checked
+#[derive(Clone)]
+pub struct Limits {
+    /// Longest request line in bytes, answered with 414 when exceeded
+    pub max_request_line: usize,
+    /// Largest number of header fields, answered with 431 when exceeded
+    pub max_headers: usize,
+    /// Largest head in bytes, including the request line, answered with
+    /// 431 when exceeded
+    pub max_head_size: usize,
+    /// Most bytes held in memory for one connection at a time: the
+    /// head and body of a request, answered with 413 when exceeded,
+    /// and the bodies of responses waiting to be sent, which are
+    /// replaced with a 500 when they exceed what is left. The
+    /// connection is closed in both cases.
+    pub max_buffered: usize,
+    /// Largest body in bytes of a request streamed to its handler, see
+    /// `Route::stream_body`. Reading the body fails once it is exceeded
+    /// and the connection is closed afterwards.
+    pub max_streamed_body: u64,
+    /// Largest body in bytes a gzip or deflate compressed request body
+    /// may decompress to, answered with 413 when exceeded; the handler
+    /// gets the decompressed body. Bodies streamed to their handler are
+    /// left compressed.
+    pub max_decompressed_body: usize,
+    /// How requests that parsers may disagree about are treated, which
+    /// are answered with 400 when rejected
+    pub strictness: Strictness,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_request_line: 8 * 1024,
+            max_headers: 64,
+            max_head_size: 64 * 1024,
+            max_buffered: 16 * 1024 * 1024,
+            max_streamed_body: 1024 * 1024 * 1024,
+            max_decompressed_body: 16 * 1024 * 1024,
+            strictness: Strictness::Strict,
+        }
+    }
+}
+
+/// How strictly requests are held to RFC 9112 where a server in front
+/// of this one could read them differently, letting a client smuggle a
+/// request past it
+///
+/// Either way requests are rejected that have both Content-Length and
+/// Transfer-Encoding, Content-Length values that differ, whitespace
+/// between a field name and its colon, a CR within a line or a folded
+/// first field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strictness {
+    /// Also reject lines ending in a bare LF, folded field lines,
+    /// repeated Content-Length values even when they are the same, and
+    /// Transfer-Encoding in HTTP/1.0 requests
+    Strict,
+    /// Accept what old clients send and RFC 9112 lets a server accept:
+    /// lines ending in a bare LF, folded lines joined to the previous
+    /// field with spaces, the same Content-Length given more than once
+    /// and Transfer-Encoding in HTTP/1.0 requests
+    Lenient,
+}
+
+/// Header or trailer fields kept in one buffer, as the ranges of it
+/// their names and values take up
+#[derive(Clone, Default)]
+pub(crate) struct Fields {
+    text: String,
+    fields: Vec<(Range<usize>, Range<usize>)>,
+}
+
+impl Fields {
+    /// The value of the first field with the name, compared
+    /// case-insensitively
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, value)| value)
+    }
+
+    /// The names and values of the fields in order
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(move |(name, value)| (&self.text[name.clone()], &self.text[value.clone()]))
+    }
+
+    /// Append a field
+    pub(crate) fn push(&mut self, name: &str, value: &str) {
+        let start = self.text.len();
+        self.text.push_str(name);
+        self.text.push_str(value);
+        self.fields.push((start..start + name.len(), start + name.len()..self.text.len()));
+    }
+
+    /// Keep only the fields a function returns true for
+    pub(crate) fn retain<F: FnMut(&str, &str) -> bool>(&mut self, mut keep: F) {
+        let text = &self.text;
+        self.fields.retain(|(name, value)| keep(&text[name.clone()], &text[value.clone()]));
+    }
+}
+
+/// Read header or trailer fields up to the empty line ending them
+///
+/// # Arguments
+///
+/// reader - A buffered reader positioned at the first field.
+/// limits - The limits on the number and total size of the fields.
+/// head_size - The size of the head read so far, updated as fields are read.
+/// text - The buffer the fields are read into, which may already hold
+/// the request line.
+fn read_fields<R: BufRead>(
+    reader: &mut R,
+    limits: &Limits,
+    head_size: &mut usize,
+    mut text: Vec<u8>,
+) -> Result<Fields, ParseError> {
+    let mut fields: Vec<(Range<usize>, Range<usize>)> = Vec::new();
+    loop {
+        let start = text.len();
+        let remaining = limits.max_head_size.saturating_sub(*head_size);
+        match read_line(reader, &mut text, remaining)? {
+            Some(read) => *head_size += read,
+            None => return Err(ParseError::new("Connection closed in the middle of the request head.")),
+        }
+        if *head_size > limits.max_head_size {
+            return Err(ParseError::with_status(431, "Request head too large."));
+        }
+        check_ending(&text[start..], limits)?;
+        let mut end = text.len();
+        if text[start..].ends_with(b"\r\n") {
+            end -= 2;
+        } else if text[start..].ends_with(b"\n") {
+            end -= 1;
+        }
+        if end == start {
+            text.truncate(start);
+            break;
+        }
+        if text[start..end].contains(&b'\r') {
+            return Err(ParseError::new("Header line with a bare CR."));
+        }
+        if matches!(text[start], b' ' | b'\t') {
+            match (limits.strictness, fields.last_mut()) {
+                (Strictness::Lenient, Some((_, value))) => {
+                    // The folding becomes spaces in place, joining the line
+                    // to the value of the previous field
+                    let folded = trim(&text, start..end);
+                    if !folded.is_empty() {
+                        text[value.end..folded.start].fill(b' ');
+                        value.end = folded.end;
+                    }
+                    continue;
+                }
+                _ => return Err(ParseError::new("Folded header line.")),
+            }
+        }
+        if fields.len() == limits.max_headers {
+            return Err(ParseError::with_status(431, "Too many headers."));
+        }
+        let colon = match text[start..end].iter().position(|&b| b == b':') {
+            Some(colon) => start + colon,
+            None => return Err(ParseError::new("Header line without a colon.")),
+        };
+        let name = trim(&text, start..colon);
+        if name.is_empty() {
+            return Err(ParseError::new("Header with an empty name."));
+        }
+        if name.end != colon {
+            return Err(ParseError::new("Whitespace between a header name and its colon."));
+        }
+        // Such as a vertical tab, which some servers trim, so that the
+        // field would pass for another one there
+        if !text[name.clone()].iter().all(|&b| headers::is_token_byte(b)) {
+            return Err(ParseError::new("Invalid character in a header name."));
+        }
+        fields.push((name, trim(&text, colon + 1..end)));
+    }
+    // The ranges start and end next to ASCII bytes, so they lie on
+    // character boundaries of valid text
+    Ok(Fields { text: into_string(text)?, fields })
+}
+
+/// A range of a buffer without the whitespace around it
+fn trim(text: &[u8], mut range: Range<usize>) -> Range<usize> {
+    while range.start < range.end && text[range.start].is_ascii_whitespace() {
+        range.start += 1;
+    }
+    while range.end > range.start && text[range.end - 1].is_ascii_whitespace() {
+        range.end -= 1;
+    }
+    range
+}
+
+/// How the end of a request body is recognized
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Framing {
+    /// The body is as long as its Content-Length header says
+    Length(u64),
+    /// The body is sent with chunked transfer encoding
+    Chunked,
+}
+
+/// What the head of a request says about the body following it
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Head {
+    /// How the body is framed, None if the request has no body
+    pub(crate) framing: Option<Framing>,
+    /// The size of the head in bytes
+    pub(crate) size: usize,
+}
+
+/// Read a request body, handing it to `emit` in pieces of at most
+/// `BODY_PIECE` bytes, and return the trailers after a chunked one
+///
+/// Chunk extensions are ignored. Fails with 413 as soon as the body
+/// grows beyond `budget` bytes.
+pub(crate) fn read_body<R, F>(reader: &mut R, limits: &Limits, framing: Framing, budget: u64, mut emit: F) -> Result<Fields, ParseError>
+where
+    R: BufRead,
+    F: FnMut(Vec<u8>),
+{
+    let length = match framing {
+        Framing::Length(length) => length,
+        Framing::Chunked => return read_chunked(reader, limits, budget, emit),
+    };
+    if length > budget {
+        return Err(ParseError::with_status(413, "Request body too large."));
+    }
+    read_pieces(reader, length, &mut emit)?;
+    Ok(Fields::default())
+}
+
+fn read_chunked<R, F>(reader: &mut R, limits: &Limits, budget: u64, mut emit: F) -> Result<Fields, ParseError>
+where
+    R: BufRead,
+    F: FnMut(Vec<u8>),
+{
+    let mut read = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let line = match read_line(reader, &mut line, limits.max_request_line)? {
+            Some(read) if read <= limits.max_request_line => &line,
+            Some(_) => return Err(ParseError::new("Chunk size line too long.")),
+            None => return Err(ParseError::new("Connection closed in the middle of the request body.")),
+        };
+        let size = chunk_size(line, limits)?;
+        if size == 0 {
+            // Trailers count towards the head limits, but separately
+            // from the header fields
+            let mut trailer_size = 0;
+            return read_fields(reader, limits, &mut trailer_size, Vec::new());
+        }
+        if size > budget - read {
+            return Err(ParseError::with_status(413, "Request body too large."));
+        }
+        read += size;
+        read_pieces(reader, size, &mut emit)?;
+        chunk_end(reader, limits)?;
+    }
+}
+
+/// The size of a chunk from the line announcing it, ignoring extensions
+fn chunk_size(line: &[u8], limits: &Limits) -> Result<u64, ParseError> {
+    check_ending(line, limits)?;
+    let size = as_str(line)?.split(';').next().unwrap_or("").trim();
+    if !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ParseError::new("Invalid chunk size."));
+    }
+    u64::from_str_radix(size, 16).map_err(|_| ParseError::new("Invalid chunk size."))
+}
+
+/// Read the line ending after the data of a chunk
+fn chunk_end<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<(), ParseError> {
+    let mut end = Vec::new();
+    match read_line(reader, &mut end, 2)? {
+        Some(_) if end == b"\r\n" || (end == b"\n" && limits.strictness == Strictness::Lenient) => Ok(()),
+        _ => Err(ParseError::new("Chunk not followed by a line ending.")),
+    }
+}
+
+/// Read exactly `length` bytes in pieces of at most `BODY_PIECE` bytes
+fn read_pieces<R: BufRead, F: FnMut(Vec<u8>)>(reader: &mut R, mut length: u64, emit: &mut F) -> Result<(), ParseError> {
+    while length > 0 {
+        let size = length.min(BODY_PIECE as u64);
+        // Read instead of allocating up front, as the length is chosen
+        // by the client
+        let mut piece = Vec::new();
+        if reader.take(size).read_to_end(&mut piece)? as u64 != size {
+            return Err(ParseError::new("Connection closed in the middle of the request body."));
+        }
+        length -= size;
+        emit(piece);
+    }
+    Ok(())
+}
+
+/// Append a line including its line ending to a buffer, returning its
+/// length, or None at the end of input
+///
+/// Stops once more than `limit` bytes have been read without a line
+/// ending, leaving the partial line so the caller can reject it.
+fn read_line<R: BufRead>(reader: &mut R, line: &mut Vec<u8>, limit: usize) -> Result<Option<usize>, ParseError> {
+    let mut read = 0;
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            if read == 0 {
+                return Ok(None);
+            }
+            return Err(ParseError::new("Connection closed in the middle of a line."));
+        }
+
+        let (used, done) = match find_newline(buffer) {
+            Some(i) => (i + 1, true),
+            None => (buffer.len(), false),
+        };
+        let used = used.min(limit + 1 - read);
+        line.extend_from_slice(&buffer[..used]);
+        reader.consume(used);
+        read += used;
+        if done || read > limit {
+            return Ok(Some(read));
+        }
+    }
+}
+
+/// The position of the first line feed in a buffer
+#[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+fn find_newline(buffer: &[u8]) -> Option<usize> {
+    buffer.iter().position(|b| *b == b'\n')
+}
+
+/// The position of the first line feed in a buffer, comparing 16 bytes
+/// at a time with SSE2, which every x86-64 processor has
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+fn find_newline(buffer: &[u8]) -> Option<usize> {
+    use std::arch::x86_64::{__m128i, _mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8, _mm_set1_epi8};
+
+    let mut blocks = buffer.chunks_exact(16);
+    let mut offset = 0;
+    for block in &mut blocks {
+        // The unaligned load reads exactly the 16 bytes of the block
+        let found = unsafe {
+            let bytes = _mm_loadu_si128(block.as_ptr() as *const __m128i);
+            _mm_movemask_epi8(_mm_cmpeq_epi8(bytes, _mm_set1_epi8(b'\n' as i8)))
+        };
+        if found != 0 {
+            return Some(offset + found.trailing_zeros() as usize);
+        }
+        offset += 16;
+    }
+    blocks.remainder().iter().position(|b| *b == b'\n').map(|i| offset + i)
+}
+
+fn as_str(line: &[u8]) -> Result<&str, ParseError> {
+    std::str::from_utf8(line).map_err(|_| ParseError::new("Request head is not valid UTF-8."))
+}
+
+fn into_string(text: Vec<u8>) -> Result<String, ParseError> {
+    String::from_utf8(text).map_err(|_| ParseError::new("Request head is not valid UTF-8."))
+}
+
+/// Split an absolute-form request target such as
+/// `http://example.com/path?query` into its authority and the rest
+fn absolute_form(target: &str) -> Option<(&str, &str)> {
+    let scheme_end = target.find("://")?;
+    let scheme = &target[..scheme_end];
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return None;
+    }
+    let rest = &target[scheme_end + 3..];
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, rest) = rest.split_at(end);
+    // User info is deprecated and never names the host
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    Some((authority, rest))
+}
+
+/// Split a request target into its path and query string
+///
+/// An empty path, as in `http://example.com?query`, is the root.
+fn split_target(target: &str) -> (String, Option<String>) {
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], Some(String::from(&target[i + 1..]))),
+        None => (target, None),
+    };
+    (String::from(if path.is_empty() { "/" } else { path }), query)
+}
+
+/// An HTTP request parser that is handed input as it arrives instead of
+/// reading it from a connection, for event loops and for testing how
+/// requests split at any point are parsed
+///
+/// Each call to `advance` adds the bytes to what the parser holds and
+/// returns the next event. As one piece of input may hold several
+/// events, `advance` should be called again with no bytes until it
+/// returns `NeedMore`. A request yields its head, then the pieces of its
+/// body as they arrive, then its end, after which the next pipelined
+/// request is parsed. The head is checked exactly as `Request::parse`
+/// checks it; bodies are limited by `max_streamed_body`, as they are
+/// handed out rather than held.
+///
+/// ```
+/// use server::request::{ParseEvent, Parser};
+///
+/// let mut parser = Parser::new();
+/// assert!(matches!(parser.advance(b"POST /upload HTTP/1.1\r\nHost: exa"), ParseEvent::NeedMore));
+/// let request = match parser.advance(b"mple.com\r\nContent-Length: 5\r\n\r\nhel") {
+///     ParseEvent::Head(request) => request,
+///     _ => unreachable!(),
+/// };
+/// assert_eq!(request.header("Host"), Some("example.com"));
+/// assert!(matches!(parser.advance(b""), ParseEvent::Body(body) if body == b"hel"));
+/// assert!(matches!(parser.advance(b""), ParseEvent::NeedMore));
+/// assert!(matches!(parser.advance(b"lo"), ParseEvent::Body(body) if body == b"lo"));
+/// assert!(matches!(parser.advance(b""), ParseEvent::End(trailers) if trailers.is_empty()));
+/// ```
+pub struct Parser {
+    limits: Limits,
+    /// Input not consumed yet
+    buffer: Vec<u8>,
+    state: ParserState,
+}
+
+/// What a `Parser` reports after being handed input
+pub enum ParseEvent {
+    /// Nothing more can be parsed before more input arrives
+    NeedMore,
+    /// The head of a request, whose body follows as `Body` events,
+    /// boxed as requests are large
+    Head(Box<Request>),
+    /// A piece of the body of the current request
+    Body(Vec<u8>),
+    /// The end of the current request, with the trailers after a
+    /// chunked body
+    End(Vec<(String, String)>),
+    /// The input is malformed or exceeds the limits; the parser reports
+    /// this for all input that follows
+    Error(ParseError),
+}
+
+#[derive(Clone, Copy)]
+enum ParserState {
+    Head,
+    /// The remaining bytes of a body with a Content-Length
+    Body(u64),
+    /// A chunk size line, after the bytes of the body so far
+    ChunkSize(u64),
+    /// The remaining bytes of a chunk, and of the body so far
+    Chunk(u64, u64),
+    /// The line ending after a chunk
+    ChunkEnd(u64),
+    Trailers,
+    Failed(u16),
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser::new()
+    }
+}
+
+impl Parser {
+    /// Create a parser with the default limits
+    pub fn new() -> Parser {
+        Parser::with_limits(Limits::default())
+    }
+
+    /// Create a parser with the limits requests are checked against
+    pub fn with_limits(limits: Limits) -> Parser {
+        Parser { limits, buffer: Vec::new(), state: ParserState::Head }
+    }
+
+    /// The number of bytes handed to the parser and not consumed yet
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Add input and parse the next event from what the parser holds
+    pub fn advance(&mut self, bytes: &[u8]) -> ParseEvent {
+        self.buffer.extend_from_slice(bytes);
+        let event = match self.next_event() {
+            Ok(event) => event,
+            Err(e) => {
+                self.state = ParserState::Failed(e.status());
+                return ParseEvent::Error(e);
+            }
+        };
+        event.unwrap_or(ParseEvent::NeedMore)
+    }
+
+    /// The next event, if the input holds enough for one
+    fn next_event(&mut self) -> Result<Option<ParseEvent>, ParseError> {
+        let limits = &self.limits;
+        match self.state {
+            ParserState::Head => {
+                let (request, head) = match attempt(&mut self.buffer, |input| Request::parse_head(input, limits))? {
+                    Some(parsed) => parsed,
+                    None => return Ok(None),
+                };
+                self.state = match head.framing {
+                    None => ParserState::Body(0),
+                    Some(Framing::Length(length)) if length > limits.max_streamed_body => {
+                        return Err(ParseError::with_status(413, "Request body too large."));
+                    }
+                    Some(Framing::Length(length)) => ParserState::Body(length),
+                    Some(Framing::Chunked) => ParserState::ChunkSize(0),
+                };
+                Ok(Some(ParseEvent::Head(Box::new(request))))
+            }
+            ParserState::Body(0) => {
+                self.state = ParserState::Head;
+                Ok(Some(ParseEvent::End(Vec::new())))
+            }
+            ParserState::Body(remaining) => Ok(self.take_piece(remaining).map(|(piece, remaining)| {
+                self.state = ParserState::Body(remaining);
+                ParseEvent::Body(piece)
+            })),
+            ParserState::ChunkSize(read) => {
+                let size = attempt(&mut self.buffer, |input| {
+                    let mut line = Vec::new();
+                    match read_line(input, &mut line, limits.max_request_line)? {
+                        Some(length) if length <= limits.max_request_line => chunk_size(&line, limits),
+                        _ => Err(ParseError::new("Chunk size line too long.")),
+                    }
+                })?;
+                match size {
+                    None => return Ok(None),
+                    Some(0) => self.state = ParserState::Trailers,
+                    Some(size) if size > limits.max_streamed_body - read => {
+                        return Err(ParseError::with_status(413, "Request body too large."));
+                    }
+                    Some(size) => self.state = ParserState::Chunk(size, read + size),
+                }
+                self.next_event()
+            }
+            ParserState::Chunk(0, read) => {
+                self.state = ParserState::ChunkEnd(read);
+                self.next_event()
+            }
+            ParserState::Chunk(remaining, read) => Ok(self.take_piece(remaining).map(|(piece, remaining)| {
+                self.state = ParserState::Chunk(remaining, read);
+                ParseEvent::Body(piece)
+            })),
+            ParserState::ChunkEnd(read) => {
+                let ended = attempt(&mut self.buffer, |input| chunk_end(input, limits))?;
+                if ended.is_none() {
+                    return Ok(None);
+                }
+                self.state = ParserState::ChunkSize(read);
+                self.next_event()
+            }
+            ParserState::Trailers => {
+                let trailers = attempt(&mut self.buffer, |input| read_fields(input, limits, &mut 0, Vec::new()))?;
+                Ok(trailers.map(|trailers| {
+                    self.state = ParserState::Head;
+                    ParseEvent::End(trailers.iter().map(|(name, value)| (String::from(name), String::from(value))).collect())
+                }))
+            }
+            ParserState::Failed(status) => Err(ParseError::with_status(status, "The request could not be parsed.")),
+        }
+    }
+
+    /// Take up to `remaining` bytes of a body from the input, if it holds
+    /// any, returning them with the number of bytes still to come
+    fn take_piece(&mut self, remaining: u64) -> Option<(Vec<u8>, u64)> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let size = (remaining.min(BODY_PIECE as u64) as usize).min(self.buffer.len());
+        let piece = self.buffer.drain(..size).collect();
+        Some((piece, remaining - size as u64))
+    }
+}
+
+/// Run a parsing function over the input a parser holds, consuming what
+/// it read if it succeeds, or returning None if it ran out of input
+fn attempt<T, F>(buffer: &mut Vec<u8>, parse: F) -> Result<Option<T>, ParseError>
+where
+    F: FnOnce(&mut Partial) -> Result<T, ParseError>,
+{
+    let mut input = Partial { input: buffer, position: 0, ran_out: false };
+    match parse(&mut input) {
+        Ok(parsed) => {
+            let used = input.position;
+            buffer.drain(..used);
+            Ok(Some(parsed))
+        }
+        // Running out fails the read, which fails the parsing
+        Err(_) if input.ran_out => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The input of a parser read as a stream, which fails rather than
+/// ending when it runs out, so the parsing can be tried again once more
+/// input arrived
+struct Partial<'a> {
+    input: &'a [u8],
+    position: usize,
+    ran_out: bool,
+}
+
+impl Read for Partial<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = {
+            let mut available = self.fill_buf()?;
+            available.read(buf)?
+        };
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl BufRead for Partial<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.position == self.input.len() {
+            self.ran_out = true;
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "More input is needed."));
+        }
+        Ok(&self.input[self.position..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position = (self.position + amount).min(self.input.len());
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    details: String,
+    status: u16,
+}
+
+impl ParseError {
+    fn new(details: &str) -> ParseError {
+        ParseError::with_status(400, details)
+    }
+
+    fn with_status(status: u16, details: &str) -> ParseError {
+        ParseError{details: String::from(details), status}
+    }
+
+    /// The status code to respond to the malformed request with
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for ParseError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+impl From<std::io::Error> for ParseError {
+    fn from(err: std::io::Error) -> ParseError {
+        ParseError{details: err.to_string(), status: 400}
+    }
+}
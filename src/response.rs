@@ -0,0 +1,1021 @@
+use std::fs::File;
+use std::io::{self, IoSlice};
+use std::io::prelude::*;
+use std::net::TcpStream;
+
+use crate::date;
+use crate::headers;
+use crate::log;
+use crate::sendfile::{self, Socket};
+use crate::template::{self, Context};
+
+/// Size of the chunks a streamed body is read and written in
+const STREAM_CHUNK_SIZE: usize = 8192;
+
+/// The body of a response
+pub enum Body {
+    /// A body held entirely in memory
+    Bytes(Vec<u8>),
+    /// A body streamed from a reader, with its length if known in advance
+    Reader(Box<dyn Read + Send>, Option<u64>),
+    /// A file sent from its current position, with the number of bytes to send
+    File(File, u64),
+    /// A body written incrementally by a function once the head is sent
+    Writer(WriteFn),
+}
+
+/// Writes a body through a `ResponseBodyWriter`
+pub type WriteFn = Box<dyn FnOnce(&mut ResponseBodyWriter) -> io::Result<()> + Send>;
+
+impl Body {
+    /// The body contents if they are held in memory
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes),
+            Body::Reader(..) | Body::File(..) | Body::Writer(_) => None,
+        }
+    }
+
+    /// The length of the body if it is known in advance
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Reader(_, length) => *length,
+            Body::File(_, length) => Some(*length),
+            Body::Writer(_) => None,
+        }
+    }
+
+    /// Whether the body is known to be empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+}
+
+/// Takes over a connection after a 101 response, given its socket and
+/// the bytes the client sent after the request that were read already
+pub type UpgradeFn = Box<dyn FnOnce(TcpStream, Vec<u8>) + Send>;
+
+/// Computes trailer fields once the body has been sent
+type TrailerFn = Box<dyn FnOnce() -> Vec<(String, String)> + Send>;
+
+/// Trailer fields sent after a chunked body
+#[derive(Default)]
+struct Trailers {
+    /// Fields whose values are known up front
+    fields: Vec<(String, String)>,
+    /// Names of the fields computed once the body has been sent
+    deferred: Vec<String>,
+    /// The function computing the deferred fields
+    compute: Option<TrailerFn>,
+}
+
+/// An HTTP response produced by a handler
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Body,
+    /// Boxed since almost no response has any
+    trailers: Option<Box<Trailers>>,
+    upgrade: Option<UpgradeFn>,
+    /// Whether the connection is closed instead of sending the response
+    aborted: bool,
+    /// Whether only the head is sent, as the response answers a HEAD
+    /// request
+    head_only: bool,
+    /// Whether the body must not be chunked, as the request was HTTP/1.0
+    unchunked: bool,
+}
+
+impl Response {
+    /// Create an empty response with the given status code
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Bytes(Vec::new()),
+            trailers: None,
+            upgrade: None,
+            aborted: false,
+            head_only: false,
+            unchunked: false,
+        }
+    }
+
+    /// Create a response with an HTML body
+    ///
+    /// # Arguments
+    ///
+    /// status - The status code of the response.
+    /// contents - The HTML document to send.
+    pub fn html<B: Into<Vec<u8>>>(status: u16, contents: B) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(contents)
+    }
+
+    /// Create a 200 response that streams its body from a reader
+    ///
+    /// The body is sent in fixed-size chunks using chunked transfer
+    /// encoding, so it never has to be held in memory as a whole. HTTP/1.0
+    /// clients do not understand chunks, so for them the body ends when
+    /// the connection is closed instead.
+    ///
+    /// # Arguments
+    ///
+    /// reader - The source of the body.
+    /// content_type - The value of the Content-Type header.
+    pub fn from_reader<R: Read + Send + 'static>(reader: R, content_type: &str) -> Response {
+        Response::new(200)
+            .with_header("Content-Type", content_type)
+            .with_stream(reader, None)
+    }
+
+    /// Create a 200 response that streams the contents of a file
+    ///
+    /// Unlike `from_reader` the length of the file is known up front, so
+    /// it is sent with a Content-Length header instead of being chunked,
+    /// and when sent to a socket the kernel can copy it without going
+    /// through userspace.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file metadata cannot be read.
+    pub fn from_file(file: File, content_type: &str) -> io::Result<Response> {
+        let length = file.metadata()?.len();
+        let mut response = Response::new(200).with_header("Content-Type", content_type);
+        response.body = Body::File(file, length);
+        Ok(response)
+    }
+
+    /// Create a 200 response whose body is written incrementally once
+    /// the head has been sent
+    ///
+    /// The function gets a writer that sends what is written in chunks,
+    /// so output such as a long export never has to be held in memory
+    /// as a whole; flushing the writer sends what was written so far
+    /// to the client right away. HTTP/1.0 clients get the body unchunked,
+    /// ended by closing the connection. If the function fails, the connection
+    /// is closed without ending the body, so the client can tell that
+    /// the response is incomplete.
+    ///
+    /// # Arguments
+    ///
+    /// content_type - The value of the Content-Type header.
+    /// f - The function writing the body.
+    pub fn from_writer<F>(content_type: &str, f: F) -> Response
+    where
+        F: FnOnce(&mut ResponseBodyWriter) -> io::Result<()> + Send + 'static,
+    {
+        let mut response = Response::new(200).with_header("Content-Type", content_type);
+        response.body = Body::Writer(Box::new(f));
+        response
+    }
+
+    /// Render a template into a 200 response with an HTML body
+    ///
+    /// Templates are looked up relative to the working directory and
+    /// are compiled once, then cached until their file changes. If the
+    /// template cannot be rendered a 500 response is returned instead.
+    ///
+    /// # Arguments
+    ///
+    /// name - Path of the template file.
+    /// context - Values for the variables used in the template.
+    pub fn render(name: &str, context: &Context) -> Response {
+        match template::render(name, context) {
+            Ok(contents) => Response::html(200, contents),
+            Err(e) => {
+                log::error(&format!("Failed to render template {}: {}", name, e));
+                Response::text(500, "Internal Server Error")
+            }
+        }
+    }
+
+    /// Replace the status code of the response
+    pub fn with_status(mut self, status: u16) -> Response {
+        self.status = status;
+        self
+    }
+
+    /// Create a response with a plain text body
+    pub fn text<B: Into<Vec<u8>>>(status: u16, contents: B) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(contents)
+    }
+
+    /// Add a header to the response
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((String::from(name), String::from(value)));
+        self
+    }
+
+    /// Set a header, replacing any headers with the same name
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.remove_header(name);
+        self.headers.push((String::from(name), String::from(value)));
+    }
+
+    /// Remove all headers with the given name
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    /// Replace the response body
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+        self.body = Body::Bytes(body.into());
+        self
+    }
+
+    /// Replace the response body with one streamed from a reader
+    ///
+    /// # Arguments
+    ///
+    /// reader - The source of the body.
+    /// length - The exact number of bytes the reader produces, if known.
+    pub fn with_stream<R: Read + Send + 'static>(mut self, reader: R, length: Option<u64>) -> Response {
+        self.body = Body::Reader(Box::new(reader), length);
+        self
+    }
+
+    /// Replace the response body with part of a file
+    ///
+    /// # Arguments
+    ///
+    /// file - The file, positioned where the body starts.
+    /// length - The number of bytes of the file to send, which it has
+    /// to hold from its position on.
+    pub fn with_file(mut self, file: File, length: u64) -> Response {
+        self.body = Body::File(file, length);
+        self
+    }
+
+    /// Add a trailer field, sent after the body
+    ///
+    /// A response with trailers is always sent with chunked transfer
+    /// encoding, and the names of its trailers are announced in a
+    /// Trailer header. Clients are free to ignore trailers, so they
+    /// should only carry information the response is usable without;
+    /// HTTP/1.0 clients never get them.
+    pub fn with_trailer(mut self, name: &str, value: &str) -> Response {
+        let trailers = self.trailers.get_or_insert_with(Box::default);
+        trailers.fields.push((String::from(name), String::from(value)));
+        self
+    }
+
+    /// Compute trailer fields once the body has been sent, e.g. a
+    /// checksum of a streamed body or the time it took to produce
+    ///
+    /// The function runs after the last chunk is written. Fields it
+    /// returns that were not declared, or that are not allowed in
+    /// trailers, are dropped.
+    ///
+    /// # Arguments
+    ///
+    /// names - The names of the fields the function returns, announced in the Trailer header.
+    /// f - The function producing the fields.
+    pub fn with_trailers<F>(mut self, names: &[&str], f: F) -> Response
+    where
+        F: FnOnce() -> Vec<(String, String)> + Send + 'static,
+    {
+        let trailers = self.trailers.get_or_insert_with(Box::default);
+        trailers.deferred = names.iter().map(|name| String::from(*name)).collect();
+        trailers.compute = Some(Box::new(f));
+        self
+    }
+
+    /// Take over the connection once this 101 response is sent, to
+    /// speak the protocol switched to, such as WebSocket
+    ///
+    /// The function runs on the thread serving the connection and the
+    /// connection is closed when it returns; the socket has no read
+    /// timeout by then. Connections that are not sockets cannot be
+    /// handed over, so such clients are answered with 500 instead.
+    ///
+    /// # Arguments
+    ///
+    /// f - The function given the socket and the bytes the client sent
+    /// after the request that were read from it already.
+    pub fn with_upgrade<F>(mut self, f: F) -> Response
+    where
+        F: FnOnce(TcpStream, Vec<u8>) + Send + 'static,
+    {
+        self.upgrade = Some(Box::new(f));
+        self
+    }
+
+    /// Take the function given to `with_upgrade` out of the response
+    pub(crate) fn take_upgrade(&mut self) -> Option<UpgradeFn> {
+        self.upgrade.take()
+    }
+
+    /// A response that is never sent, the connection being closed
+    /// instead, as if the server had gone away
+    pub(crate) fn aborted() -> Response {
+        let mut response = Response::new(500);
+        response.aborted = true;
+        response
+    }
+
+    /// Whether the connection is to be closed instead of sending the
+    /// response
+    pub(crate) fn is_aborted(&self) -> bool {
+        self.aborted
+    }
+
+    /// The status code of the response
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Get the value of the first header with the given name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All headers of the response
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// The media type of the body without its parameters, e.g.
+    /// `text/html`, to be compared case-insensitively
+    pub fn content_type(&self) -> Option<&str> {
+        self.header("Content-Type").map(headers::media_type)
+    }
+
+    /// The headers of the response, for changing them in place
+    pub(crate) fn headers_mut(&mut self) -> &mut Vec<(String, String)> {
+        &mut self.headers
+    }
+
+    /// Write the names of headers and trailers in canonical form, see
+    /// `headers::canonical`
+    pub(crate) fn canonicalize_headers(&mut self) {
+        for (name, _) in &mut self.headers {
+            *name = headers::canonical(name);
+        }
+        if let Some(trailers) = &mut self.trailers {
+            for name in trailers.fields.iter_mut().map(|(name, _)| name).chain(&mut trailers.deferred) {
+                *name = headers::canonical(name);
+            }
+        }
+    }
+
+    /// The response body
+    pub fn body(&self) -> &Body {
+        &self.body
+    }
+
+    /// Take the body out of the response, e.g. to replace it with a
+    /// transformed one, leaving it empty
+    pub fn take_body(&mut self) -> Body {
+        std::mem::replace(&mut self.body, Body::Bytes(Vec::new()))
+    }
+
+    /// The trailer fields added with `with_trailer`
+    ///
+    /// Fields computed by a function given to `with_trailers` are not
+    /// known until the body has been sent.
+    pub fn trailers(&self) -> &[(String, String)] {
+        self.trailers.as_ref().map_or(&[], |trailers| &trailers.fields)
+    }
+
+    /// Whether any trailers are sent after the body
+    fn has_trailers(&self) -> bool {
+        self.trailers.is_some()
+    }
+
+    /// Check that the headers of the response describe a body that can
+    /// be framed correctly
+    ///
+    /// The response is rejected if its Content-Length headers disagree
+    /// with each other or with the body, if it sets both Content-Length
+    /// and Transfer-Encoding, uses a transfer coding other than chunked,
+    /// carries a body or trailers although its status forbids them, or
+    /// if a header or trailer name or value would break the message
+    /// apart or a trailer is one that must be sent in the head.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` describing the problem.
+    pub fn validate(&self) -> io::Result<()> {
+        self.framing().map(|_| ())
+    }
+
+    /// Serialize the response to a writer
+    ///
+    /// Date and Content-Length headers are added if the response does
+    /// not set them, and bodies of unknown length or followed by
+    /// trailers are sent with chunked transfer encoding. A streamed
+    /// body is consumed in the process.
+    /// Returns the number of bytes written.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the response fails `validate`, in which case
+    /// nothing is written, or if reading the body or writing to the
+    /// underlying writer fails.
+    pub fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<u64> {
+        self.write_with(writer, &mut Vec::new())
+    }
+
+    /// Serialize the response to a writer like `write_to`, putting the
+    /// head and chunks together in a buffer that is reused afterwards
+    pub(crate) fn write_with<W: Write>(&mut self, writer: &mut W, buffer: &mut Vec<u8>) -> io::Result<u64> {
+        let framing = self.write_head(buffer)?;
+        if self.head_only {
+            return self.write_head_only(writer, buffer);
+        }
+        let written = self.write_rest(writer, buffer, framing)?;
+        writer.flush()?;
+        Ok(written)
+    }
+
+    /// Serialize the response to a socket
+    ///
+    /// Works like `write_to`, except that file bodies are handed to the
+    /// kernel with sendfile where the platform supports it instead of
+    /// being copied through a userspace buffer.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the response fails `validate` or if reading
+    /// the body or writing to the socket fails.
+    pub fn send<W: Socket>(&mut self, socket: &mut W) -> io::Result<u64> {
+        self.send_with(socket, &mut Vec::new())
+    }
+
+    /// Serialize the response to a socket like `send`, putting the head
+    /// and chunks together in a buffer that is reused afterwards
+    pub(crate) fn send_with<W: Socket>(&mut self, socket: &mut W, buffer: &mut Vec<u8>) -> io::Result<u64> {
+        let framing = self.write_head(buffer)?;
+        if self.head_only {
+            return self.write_head_only(socket, buffer);
+        }
+
+        let written = match (&mut self.body, framing) {
+            (Body::File(file, length), Framing::Length(_)) => {
+                socket.write_all(buffer)?;
+                sendfile::copy_file(file, socket, *length)?;
+                buffer.len() as u64 + *length
+            }
+            _ => self.write_rest(socket, buffer, framing)?,
+        };
+        socket.flush()?;
+        Ok(written)
+    }
+
+    /// Send only the head of the response, which keeps the framing
+    /// headers of its body, held serialized in a buffer
+    ///
+    /// The body is left unread, so a streamed one is never produced.
+    fn write_head_only(&self, writer: &mut dyn Write, buffer: &[u8]) -> io::Result<u64> {
+        writer.write_all(buffer)?;
+        writer.flush()?;
+        Ok(buffer.len() as u64)
+    }
+
+    /// Send the head only, without the body, as the answer to a HEAD
+    /// request has to
+    pub(crate) fn omit_body(&mut self) {
+        self.head_only = true;
+    }
+
+    /// Send a body of unknown length unchunked and ended by closing the
+    /// connection, and drop the trailers, as an HTTP/1.0 client cannot
+    /// read chunks
+    pub(crate) fn forbid_chunking(&mut self) {
+        self.unchunked = true;
+        self.trailers = None;
+    }
+
+    /// Whether the end of the body is only marked by closing the
+    /// connection, see `forbid_chunking`
+    pub(crate) fn is_close_delimited(&self) -> bool {
+        matches!(self.framing(), Ok(Framing::UntilClose))
+    }
+
+    /// Write the serialized head held in a buffer, the body and the end
+    /// of a chunked body, returning the number of bytes written
+    ///
+    /// Small pieces are collected in the buffer and go out together
+    /// with the next larger one in a single vectored write, so that the
+    /// head and a body held in memory take one system call. Only before
+    /// reading a streamed body is what was collected sent on its own, as
+    /// the stream may take a while.
+    fn write_rest(&mut self, writer: &mut dyn Write, buffer: &mut Vec<u8>, framing: Framing) -> io::Result<u64> {
+        let mut out = Coalescer::new(writer, buffer);
+        write_body(&mut self.body, framing, &mut out)?;
+        if let Framing::Chunked = framing {
+            self.write_last_chunk(&mut out)?;
+        }
+        out.flush()?;
+        Ok(out.written)
+    }
+
+    /// Work out how the body is delimited, checking the framing headers
+    /// set by the handler
+    fn framing(&self) -> io::Result<Framing> {
+        let invalid = |details: &str| Err(io::Error::new(io::ErrorKind::InvalidData, details));
+
+        for (name, value) in &self.headers {
+            if name.is_empty() || !name.bytes().all(headers::is_token_byte) {
+                return invalid("Invalid response header name.");
+            }
+            if value.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0) {
+                return invalid("Invalid response header value.");
+            }
+        }
+        if let Some(trailers) = &self.trailers {
+            let fields = trailers.fields.iter().map(|(n, v)| (n.as_str(), v.as_str()));
+            let deferred = trailers.deferred.iter().map(|n| (n.as_str(), ""));
+            if !fields.chain(deferred).all(|(name, value)| is_valid_trailer(name, value)) {
+                return invalid("Invalid response trailer.");
+            }
+        }
+
+        let mut declared = None;
+        for (_, value) in self.headers.iter().filter(|(n, _)| n.eq_ignore_ascii_case("Content-Length")) {
+            let length = match value.trim().parse::<u64>() {
+                Ok(length) => length,
+                Err(_) => return invalid("Invalid Content-Length header."),
+            };
+            if declared.is_some_and(|d| d != length) {
+                return invalid("Conflicting Content-Length headers.");
+            }
+            declared = Some(length);
+        }
+
+        let chunked = match self.header("Transfer-Encoding") {
+            None => false,
+            Some(coding) if coding.trim().eq_ignore_ascii_case("chunked") => true,
+            Some(_) => return invalid("Unsupported Transfer-Encoding."),
+        };
+        if chunked && declared.is_some() {
+            return invalid("Both Content-Length and Transfer-Encoding are set.");
+        }
+
+        // 1xx, 204 and 304 responses never have a body; a 304 may still
+        // state the length of the representation it refers to
+        if self.status < 200 || self.status == 204 || self.status == 304 {
+            if self.body.len() != Some(0) {
+                return invalid("Status does not allow a body.");
+            }
+            if chunked || (declared.is_some() && self.status != 304) {
+                return invalid("Status does not allow framing headers.");
+            }
+            if self.has_trailers() {
+                return invalid("Status does not allow trailers.");
+            }
+            return Ok(Framing::Empty);
+        }
+
+        match (declared, self.body.len()) {
+            (Some(declared), Some(length)) if self.unchunked && declared != length => {
+                invalid("Content-Length does not match the body.")
+            }
+            (Some(length), _) | (None, Some(length)) if self.unchunked => Ok(Framing::Length(length)),
+            (None, None) if self.unchunked => Ok(Framing::UntilClose),
+            _ if chunked => Ok(Framing::Chunked),
+            (Some(declared), Some(length)) if declared != length => {
+                invalid("Content-Length does not match the body.")
+            }
+            // Trailers can only follow a chunked body
+            _ if self.has_trailers() => Ok(Framing::Chunked),
+            (Some(length), _) | (None, Some(length)) => Ok(Framing::Length(length)),
+            (None, None) => Ok(Framing::Chunked),
+        }
+    }
+
+    /// The head as it would be sent, for dumping it
+    pub(crate) fn head_bytes(&self) -> Vec<u8> {
+        let mut head = Vec::new();
+        let _ = self.write_head(&mut head);
+        head
+    }
+
+    /// Serialize the head into a buffer, returning how the body is
+    /// framed
+    fn write_head(&self, head: &mut Vec<u8>) -> io::Result<Framing> {
+        let framing = self.framing()?;
+
+        head.clear();
+        write!(head, "HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status))?;
+        if self.header("Date").is_none() {
+            write!(head, "Date: {}\r\n", date::now())?;
+        }
+        for (name, value) in &self.headers {
+            // The framing headers are written below, once
+            if name.eq_ignore_ascii_case("Content-Length")
+                || name.eq_ignore_ascii_case("Transfer-Encoding")
+                || (name.eq_ignore_ascii_case("Trailer") && self.has_trailers())
+            {
+                continue;
+            }
+            write!(head, "{}: {}\r\n", name, value)?;
+        }
+        if let Some(trailers) = &self.trailers {
+            let mut names: Vec<&str> = Vec::new();
+            for name in trailers.fields.iter().map(|(n, _)| n).chain(&trailers.deferred) {
+                if !names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+                    names.push(name);
+                }
+            }
+            if !names.is_empty() {
+                write!(head, "Trailer: {}\r\n", names.join(", "))?;
+            }
+        }
+        match framing {
+            Framing::Length(length) => write!(head, "Content-Length: {}\r\n", length)?,
+            Framing::Chunked => head.extend_from_slice(b"Transfer-Encoding: chunked\r\n"),
+            Framing::UntilClose => {}
+            Framing::Empty => {
+                if let Some(length) = self.header("Content-Length") {
+                    write!(head, "Content-Length: {}\r\n", length.trim())?;
+                }
+            }
+        }
+        head.extend_from_slice(b"\r\n");
+        Ok(framing)
+    }
+
+    /// End a chunked body with the last chunk and any trailers
+    fn write_last_chunk(&mut self, out: &mut Coalescer) -> io::Result<()> {
+        out.push(b"0\r\n");
+        if let Some(trailers) = &mut self.trailers {
+            for (name, value) in &trailers.fields {
+                out.push_fmt(format_args!("{}: {}\r\n", name, value))?;
+            }
+        }
+        let compute = self.trailers.as_mut().and_then(|trailers| trailers.compute.take());
+        if let Some(f) = compute {
+            for (name, value) in f() {
+                let deferred = self.trailers.as_ref().map_or(&[][..], |trailers| &trailers.deferred);
+                let declared = deferred.iter().any(|n| n.eq_ignore_ascii_case(&name));
+                if declared && is_valid_trailer(&name, &value) {
+                    out.push_fmt(format_args!("{}: {}\r\n", name, value))?;
+                } else {
+                    log::warn(&format!("Dropping trailer {} that was not declared or is not allowed.", name));
+                }
+            }
+        }
+        out.push(b"\r\n");
+        Ok(())
+    }
+}
+
+/// How the end of a response body is recognized by the client
+#[derive(Clone, Copy)]
+enum Framing {
+    /// The status does not allow a body
+    Empty,
+    /// The body is exactly this many bytes long
+    Length(u64),
+    /// The body is sent in chunks, followed by an empty chunk and the
+    /// trailers
+    Chunked,
+    /// The body ends when the connection is closed
+    UntilClose,
+}
+
+/// Whether a field may be sent as a trailer: it has to be well formed and
+/// must not be needed to frame, route or authenticate the message
+fn is_valid_trailer(name: &str, value: &str) -> bool {
+    const HEAD_ONLY: [&str; 9] = [
+        "Content-Length",
+        "Transfer-Encoding",
+        "Trailer",
+        "Content-Type",
+        "Content-Encoding",
+        "Content-Range",
+        "Cache-Control",
+        "Set-Cookie",
+        "Authorization",
+    ];
+    !name.is_empty()
+        && name.bytes().all(headers::is_token_byte)
+        && !HEAD_ONLY.iter().any(|n| n.eq_ignore_ascii_case(name))
+        && !value.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0)
+}
+
+/// Write a body that is held in memory or streamed from a reader
+fn write_body(body: &mut Body, framing: Framing, out: &mut Coalescer) -> io::Result<()> {
+    match (framing, body) {
+        (Framing::Empty, _) => Ok(()),
+        (Framing::Length(_), Body::Bytes(bytes)) => out.write(bytes),
+        (Framing::Length(length), Body::Reader(reader, _)) => {
+            out.flush()?;
+            copy_exact(reader, out, length, true)
+        }
+        (Framing::Length(length), Body::File(file, _)) => copy_exact(file, out, length, false),
+        // All of it is at hand, so it is sent as a single chunk
+        (Framing::Chunked, Body::Bytes(bytes)) => {
+            if !bytes.is_empty() {
+                out.push_fmt(format_args!("{:X}\r\n", bytes.len()))?;
+                out.write(bytes)?;
+                out.push(b"\r\n");
+            }
+            Ok(())
+        }
+        (Framing::Chunked, Body::Reader(reader, _)) => {
+            out.flush()?;
+            write_chunked(reader, out, true)
+        }
+        (Framing::Chunked, Body::File(file, length)) => write_chunked(&mut file.take(*length), out, false),
+        (Framing::UntilClose, Body::Bytes(bytes)) => out.write(bytes),
+        (Framing::UntilClose, Body::Reader(reader, _)) => {
+            out.flush()?;
+            copy_to_end(reader, out)
+        }
+        (Framing::UntilClose, Body::File(file, length)) => copy_exact(file, out, *length, false),
+        (framing, body @ Body::Writer(_)) => {
+            let f = match std::mem::replace(body, Body::Bytes(Vec::new())) {
+                Body::Writer(f) => f,
+                _ => unreachable!(),
+            };
+            let remaining = match framing {
+                Framing::Length(length) => Some(length),
+                _ => None,
+            };
+            let inner = Coalescer { writer: &mut *out.writer, pending: &mut *out.pending, written: 0 };
+            let chunked = matches!(framing, Framing::Chunked);
+            let mut body_writer = ResponseBodyWriter { out: inner, buffer: Vec::new(), remaining, chunked };
+            f(&mut body_writer)?;
+            out.written += body_writer.finish()?;
+            Ok(())
+        }
+    }
+}
+
+/// Collects the small pieces of a response, such as the head and the
+/// size lines of chunks, in a buffer, and writes them together with the
+/// next larger piece in a single vectored write
+pub(crate) struct Coalescer<'a> {
+    writer: &'a mut dyn Write,
+    pending: &'a mut Vec<u8>,
+    /// Bytes written or collected, including what the buffer held at
+    /// the start
+    written: u64,
+}
+
+impl<'a> Coalescer<'a> {
+    fn new(writer: &'a mut dyn Write, pending: &'a mut Vec<u8>) -> Coalescer<'a> {
+        let written = pending.len() as u64;
+        Coalescer { writer, pending, written }
+    }
+
+    /// Collect bytes to be sent with the next write
+    fn push(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+        self.written += bytes.len() as u64;
+    }
+
+    /// Collect formatted text to be sent with the next write
+    fn push_fmt(&mut self, args: std::fmt::Arguments) -> io::Result<()> {
+        let before = self.pending.len();
+        self.pending.write_fmt(args)?;
+        self.written += (self.pending.len() - before) as u64;
+        Ok(())
+    }
+
+    /// Send data after what was collected, or collect it too if both
+    /// still fit into a chunk
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.pending.len() + data.len() <= STREAM_CHUNK_SIZE {
+            self.push(data);
+            return Ok(());
+        }
+        self.send(&[data])
+    }
+
+    /// Send what was collected followed by the parts right away
+    fn send(&mut self, parts: &[&[u8]]) -> io::Result<()> {
+        let mut slices = [IoSlice::new(&[]); 4];
+        slices[0] = IoSlice::new(self.pending);
+        for (slice, part) in slices[1..].iter_mut().zip(parts) {
+            *slice = IoSlice::new(part);
+            self.written += part.len() as u64;
+        }
+        write_all_vectored(self.writer, &mut slices[..parts.len() + 1])?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Send what was collected
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            self.writer.write_all(self.pending)?;
+            self.pending.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Write all of several buffers, in as few system calls as the writer
+/// allows
+fn write_all_vectored(writer: &mut dyn Write, mut slices: &mut [IoSlice]) -> io::Result<()> {
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "Failed to write the whole response.")),
+            Ok(written) => IoSlice::advance_slices(&mut slices, written),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Sends what a handler writes as the chunks of a response body
+///
+/// Small writes are collected until a chunk is full or the writer is
+/// flushed, so the body is not sent in tiny pieces. If the handler set
+/// a Content-Length header, the body is sent unchunked instead and must
+/// be exactly that long. The head goes out together with the first
+/// chunk, so a handler that has the head sent before it has anything to
+/// write flushes the writer.
+pub struct ResponseBodyWriter<'a> {
+    out: Coalescer<'a>,
+    buffer: Vec<u8>,
+    /// Bytes still to be written when the length was declared
+    remaining: Option<u64>,
+    /// Whether the body is sent in chunks, rather than with its length
+    /// or until the connection is closed
+    chunked: bool,
+}
+
+impl<'a> ResponseBodyWriter<'a> {
+    /// Pass on what has been collected, leaving it to be sent with the
+    /// end of the response, and return the number of bytes of the body
+    fn finish(mut self) -> io::Result<u64> {
+        self.write_chunk()?;
+        if self.remaining.is_some_and(|remaining| remaining > 0) {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Response body ended early."));
+        }
+        Ok(self.out.written)
+    }
+
+    /// Pass on what has been collected as one chunk
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() || !self.chunked {
+            return Ok(());
+        }
+        self.out.push_fmt(format_args!("{:X}\r\n", self.buffer.len()))?;
+        self.out.write(&self.buffer)?;
+        self.out.push(b"\r\n");
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl<'a> Write for ResponseBodyWriter<'a> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if let Some(remaining) = &mut self.remaining {
+            if data.len() as u64 > *remaining {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Response body longer than its Content-Length."));
+            }
+            self.out.write(data)?;
+            *remaining -= data.len() as u64;
+            return Ok(data.len());
+        }
+        if !self.chunked {
+            self.out.write(data)?;
+            return Ok(data.len());
+        }
+        let room = STREAM_CHUNK_SIZE - self.buffer.len();
+        let taken = data.len().min(room);
+        self.buffer.extend_from_slice(&data[..taken]);
+        if self.buffer.len() == STREAM_CHUNK_SIZE {
+            self.write_chunk()?;
+        }
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.out.flush()?;
+        self.out.writer.flush()
+    }
+}
+
+/// Copy exactly `length` bytes from a reader to the response
+///
+/// # Arguments
+///
+/// reader - The body.
+/// out - Where the body is written.
+/// length - The length of the body.
+/// streamed - Whether reading may take a while, so each piece is sent
+/// right away rather than collected with the following ones.
+fn copy_exact<R: Read + ?Sized>(reader: &mut R, out: &mut Coalescer, length: u64, streamed: bool) -> io::Result<()> {
+    let mut piece = [0; STREAM_CHUNK_SIZE];
+    let mut left = length;
+    while left > 0 {
+        let read = match reader.read(&mut piece[..left.min(STREAM_CHUNK_SIZE as u64) as usize]) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Response body ended early.")),
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if streamed {
+            out.send(&[&piece[..read]])?;
+        } else {
+            out.write(&piece[..read])?;
+        }
+        left -= read as u64;
+    }
+    Ok(())
+}
+
+/// Copy all of a streamed reader to the response unchunked, sending
+/// each piece right away
+fn copy_to_end<R: Read + ?Sized>(reader: &mut R, out: &mut Coalescer) -> io::Result<()> {
+    let mut piece = [0; STREAM_CHUNK_SIZE];
+    loop {
+        let read = match reader.read(&mut piece) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        out.send(&[&piece[..read]])?;
+    }
+}
+
+/// Copy a reader to the response as chunks, leaving the last chunk to
+/// `Response::write_last_chunk`
+///
+/// # Arguments
+///
+/// reader - The body.
+/// out - Where the chunks are written.
+/// streamed - Whether reading may take a while, so each chunk is sent
+/// right away rather than collected with the following ones.
+fn write_chunked<R: Read + ?Sized>(reader: &mut R, out: &mut Coalescer, streamed: bool) -> io::Result<()> {
+    let mut piece = [0; STREAM_CHUNK_SIZE];
+    loop {
+        let read = match reader.read(&mut piece) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        out.push_fmt(format_args!("{:X}\r\n", read))?;
+        if streamed {
+            out.send(&[&piece[..read], b"\r\n"])?;
+        } else {
+            out.write(&piece[..read])?;
+            out.push(b"\r\n");
+        }
+    }
+}
+
+/// Get the standard reason phrase for a status code
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        103 => "Early Hints",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        207 => "Multi-Status",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        421 => "Misdirected Request",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
@@ -0,0 +1,141 @@
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Environment variable passing the listening socket to a new process
+const LISTEN_FD: &str = "SERVER_LISTEN_FD";
+/// Environment variable naming the process being replaced
+const RESTARTED_FROM: &str = "SERVER_RESTARTED_FROM";
+
+/// Set from a signal handler or `request` when a restart is wanted
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Ask a server with hot restarts enabled to hand its listener to a
+/// newly started copy of its binary and drain, as on SIGUSR2
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Whether a restart was requested, clearing the request
+pub(crate) fn take_request() -> bool {
+    REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// The id of the process this one replaces in a hot restart, if any
+pub fn restarted_from() -> Option<u32> {
+    env::var(RESTARTED_FROM).ok().and_then(|pid| pid.parse().ok())
+}
+
+/// Take over the listening socket passed on by the process this one
+/// replaces
+///
+/// # Errors
+///
+/// Returns an error if the passed descriptor is invalid.
+#[cfg(unix)]
+pub(crate) fn inherited_listener() -> io::Result<Option<TcpListener>> {
+    use std::os::unix::io::FromRawFd;
+
+    let fd = match env::var(LISTEN_FD) {
+        Ok(fd) => fd,
+        Err(_) => return Ok(None),
+    };
+    // Processes started by this one must not take the socket again
+    env::remove_var(LISTEN_FD);
+    let fd = fd
+        .parse::<i32>()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid {}.", LISTEN_FD)))?;
+    if !sys::is_open(fd) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} {} is not open.", LISTEN_FD, fd)));
+    }
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    listener.local_addr()?;
+    Ok(Some(listener))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn inherited_listener() -> io::Result<Option<TcpListener>> {
+    Ok(None)
+}
+
+/// Request a restart on SIGUSR2
+#[cfg(unix)]
+pub(crate) fn install_handler() {
+    sys::install_handler();
+}
+
+#[cfg(not(unix))]
+pub(crate) fn install_handler() {}
+
+/// Start the current binary again with the same arguments, passing it
+/// the listening socket
+///
+/// # Errors
+///
+/// Returns an error if the binary cannot be found or started.
+#[cfg(unix)]
+pub(crate) fn spawn_successor(listener: &TcpListener) -> io::Result<Child> {
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let fd = listener.as_raw_fd();
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(env::args_os().skip(1))
+        .env(LISTEN_FD, fd.to_string())
+        .env(RESTARTED_FROM, std::process::id().to_string());
+    unsafe {
+        // Sockets are opened close-on-exec, so keep this one open in the
+        // new binary; only async-signal-safe calls are allowed here
+        command.pre_exec(move || sys::clear_cloexec(fd));
+    }
+    command.spawn()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn spawn_successor(_listener: &TcpListener) -> io::Result<Child> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Hot restarts are not supported on this platform."))
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::io;
+    use std::os::raw::c_int;
+
+    const F_GETFD: c_int = 1;
+    const F_SETFD: c_int = 2;
+    const FD_CLOEXEC: c_int = 1;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const SIGUSR2: c_int = 12;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const SIGUSR2: c_int = 31;
+
+    extern "C" {
+        fn fcntl(fd: c_int, command: c_int, ...) -> c_int;
+        fn signal(signal: c_int, handler: extern "C" fn(c_int)) -> usize;
+    }
+
+    pub fn is_open(fd: c_int) -> bool {
+        unsafe { fcntl(fd, F_GETFD) >= 0 }
+    }
+
+    pub fn clear_cloexec(fd: c_int) -> io::Result<()> {
+        let flags = unsafe { fcntl(fd, F_GETFD) };
+        if flags < 0 || unsafe { fcntl(fd, F_SETFD, flags & !FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    extern "C" fn on_signal(_signal: c_int) {
+        super::request();
+    }
+
+    pub fn install_handler() {
+        unsafe {
+            signal(SIGUSR2, on_signal);
+        }
+    }
+}
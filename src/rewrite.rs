@@ -0,0 +1,281 @@
+use std::io;
+use std::path::PathBuf;
+
+use crate::log;
+use crate::request::Request;
+use crate::response::Response;
+use crate::static_files::StaticFiles;
+use crate::uri;
+
+/// Most times `Flag::Last` may send a request back to the first rule,
+/// after which rules rewriting one another's paths are taken to loop
+const MAX_RESTARTS: usize = 10;
+
+/// A rule rewriting the paths of requests before they are routed, see
+/// `Config::rewrites`
+///
+/// The pattern is matched against the whole decoded path, and every `*`
+/// in it matches any run of characters, slashes included; the earlier
+/// ones match as little as they can. `$1` to `$9` in the replacement
+/// stand for what they matched and `$0` for the whole path. A query in
+/// the replacement replaces the one of the request, which is kept
+/// otherwise.
+///
+/// ```no_run
+/// use server::config::Config;
+/// use server::rewrite::{Flag, Rewrite};
+/// use server::server::Server;
+///
+/// let rewrites = vec![
+///     Rewrite::redirect("/old-blog/*", "/blog/$1", 301),
+///     Rewrite::internal("/u/*", "/users/$1").with_flag(Flag::Last),
+///     Rewrite::alias("/media/", "/srv/media"),
+/// ];
+/// let server = Server::new(Config { rewrites, ..Config::default() }).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Rewrite {
+    /// The paths the rule applies to
+    pub pattern: String,
+    /// What the path is replaced with
+    pub replacement: String,
+    /// What the rule does with a matching request
+    pub action: Action,
+    /// Which rules are applied after this one, if it rewrites
+    pub flag: Flag,
+}
+
+/// What a rewrite rule does with a request it matches
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Route the request by the replaced path, which the client never
+    /// learns of
+    Rewrite,
+    /// Send the client to the replacement, which may be a URL of another
+    /// origin, with this 3xx status
+    Redirect(u16),
+    /// Answer the request with the file at the replaced path below this
+    /// directory, without routing it
+    Alias(PathBuf),
+}
+
+/// Which rules follow a rule that rewrote a path
+///
+/// Redirects and aliases answer the request, so no rule follows them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flag {
+    /// Go on with the next rule
+    Continue,
+    /// Start over at the first rule with the new path, until no rule
+    /// matches or one breaks
+    Last,
+    /// Apply no more rules
+    Break,
+}
+
+impl Rewrite {
+    /// Route requests for matching paths by the replacement instead
+    pub fn internal(pattern: &str, replacement: &str) -> Rewrite {
+        Rewrite {
+            pattern: String::from(pattern),
+            replacement: String::from(replacement),
+            action: Action::Rewrite,
+            flag: Flag::Continue,
+        }
+    }
+
+    /// Redirect requests for matching paths to the replacement
+    ///
+    /// What the pattern matched is percent-encoded as it is put into
+    /// the replacement, so the Location is a valid URI whatever the path
+    /// held; the rest of the replacement has to be one already.
+    ///
+    /// # Arguments
+    ///
+    /// pattern - The paths redirected.
+    /// replacement - The path or URL clients are sent to.
+    /// status - The status of the redirect, e.g. 301 or 308.
+    pub fn redirect(pattern: &str, replacement: &str, status: u16) -> Rewrite {
+        Rewrite {
+            pattern: String::from(pattern),
+            replacement: String::from(replacement),
+            action: Action::Redirect(status),
+            flag: Flag::Break,
+        }
+    }
+
+    /// Serve the files of a directory below a path prefix, e.g. those in
+    /// `/srv/media` below `/media/`
+    pub fn alias<P: Into<PathBuf>>(prefix: &str, root: P) -> Rewrite {
+        Rewrite {
+            pattern: format!("{}/*", prefix.trim_end_matches('/')),
+            replacement: String::from("/$1"),
+            action: Action::Alias(root.into()),
+            flag: Flag::Break,
+        }
+    }
+
+    /// Set which rules follow this one if it rewrites a path
+    pub fn with_flag(mut self, flag: Flag) -> Rewrite {
+        self.flag = flag;
+        self
+    }
+
+    /// The replacement for a path the rule matches, or None if it does
+    /// not match
+    ///
+    /// # Arguments
+    ///
+    /// path - The decoded path of the request.
+    /// encode - Whether what the pattern matched is percent-encoded, for a URI rather than a path.
+    fn replace(&self, path: &str, encode: bool) -> Option<String> {
+        let captures = captures(&self.pattern, path)?;
+        let substitute = |replaced: &mut String, captured: &str| {
+            if encode {
+                replaced.push_str(&uri::percent_encode_path(captured));
+            } else {
+                replaced.push_str(captured);
+            }
+        };
+        let mut replaced = String::with_capacity(self.replacement.len());
+        let mut rest = self.replacement.as_str();
+        while let Some(dollar) = rest.find('$') {
+            replaced.push_str(&rest[..dollar]);
+            let reference = rest[dollar + 1..].chars().next();
+            match reference {
+                Some('$') => replaced.push('$'),
+                Some('0') => substitute(&mut replaced, path),
+                Some(digit @ '1'..='9') => {
+                    substitute(&mut replaced, captures.get(digit as usize - '1' as usize).copied().unwrap_or(""));
+                }
+                _ => {
+                    replaced.push('$');
+                    rest = &rest[dollar + 1..];
+                    continue;
+                }
+            }
+            rest = &rest[dollar + 2..];
+        }
+        replaced.push_str(rest);
+        Some(replaced)
+    }
+}
+
+/// Check that rewrite rules are well formed
+///
+/// # Errors
+///
+/// Returns an error if a pattern does not start with a slash or a
+/// redirect status is not a 3xx one.
+pub(crate) fn validate(rules: &[Rewrite]) -> io::Result<()> {
+    for rule in rules {
+        if !rule.pattern.starts_with('/') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("The rewrite pattern {} has to start with a slash.", rule.pattern),
+            ));
+        }
+        if let Action::Redirect(status) = rule.action {
+            if !(300..400).contains(&status) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Rewrite redirects need a 3xx status."));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How a request the rules answer is answered, kept in its extensions
+/// until the handler would run
+pub(crate) enum Answer {
+    Redirect(u16, String),
+    File(PathBuf),
+    /// `Flag::Last` sent the request back more than `MAX_RESTARTS` times
+    Looped,
+}
+
+impl Answer {
+    /// The response to a request the rules answer
+    pub(crate) fn respond(self, request: &Request) -> Response {
+        match self {
+            Answer::Redirect(status, location) => Response::new(status).with_header("Location", &location),
+            Answer::File(root) => StaticFiles::new(root).handle(request),
+            Answer::Looped => Response::text(500, "Internal Server Error"),
+        }
+    }
+}
+
+/// Apply rewrite rules to a request, rewriting its path and query
+///
+/// # Errors
+///
+/// Returns how to answer the request if a rule redirects or aliases it,
+/// or if the rules loop.
+pub(crate) fn apply(rules: &[Rewrite], request: &mut Request) -> Result<(), Answer> {
+    let mut restarts = 0;
+    let mut index = 0;
+    while let Some(rule) = rules.get(index) {
+        index += 1;
+        let redirect = matches!(rule.action, Action::Redirect(_));
+        let replaced = match rule.replace(request.path(), redirect) {
+            Some(replaced) => replaced,
+            None => continue,
+        };
+        let (path, query) = match replaced.split_once('?') {
+            Some((path, query)) => (String::from(path), Some(String::from(query))),
+            None => (replaced, request.query().map(String::from)),
+        };
+        match &rule.action {
+            Action::Redirect(status) => {
+                let mut location = path;
+                if let Some(query) = query {
+                    location.push('?');
+                    location.push_str(&query);
+                }
+                return Err(Answer::Redirect(*status, location));
+            }
+            Action::Alias(root) => {
+                request.set_path(&path);
+                return Err(Answer::File(root.clone()));
+            }
+            Action::Rewrite => {
+                request.set_path(&path);
+                request.set_query(query.as_deref());
+            }
+        }
+        match rule.flag {
+            Flag::Continue => {}
+            Flag::Break => break,
+            Flag::Last if restarts == MAX_RESTARTS => {
+                log::error(&format!("Rewrite rules keep rewriting {}", request.target()));
+                return Err(Answer::Looped);
+            }
+            Flag::Last => {
+                restarts += 1;
+                index = 0;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What the `*` of a pattern matched in a path, or None if the path does
+/// not match
+fn captures<'a>(pattern: &str, path: &'a str) -> Option<Vec<&'a str>> {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if parts.len() == 1 {
+        return Some(Vec::new()).filter(|_| path == pattern);
+    }
+    if path.len() < first.len() + last.len() || !path.starts_with(first) || !path.ends_with(last) {
+        return None;
+    }
+    let mut rest = &path[first.len()..path.len() - last.len()];
+    let mut captures = Vec::with_capacity(parts.len() - 1);
+    for part in &parts[1..parts.len() - 1] {
+        let at = rest.find(part)?;
+        captures.push(&rest[..at]);
+        rest = &rest[at + part.len()..];
+    }
+    captures.push(rest);
+    Some(captures)
+}
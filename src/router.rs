@@ -0,0 +1,861 @@
+use std::any;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::extract::{IntoResponse, Json};
+use crate::json::{ToJson, Value};
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+use crate::uri;
+
+type BoxedHandler = Box<dyn Fn(Request) -> Response + Send + Sync + 'static>;
+
+/// How the router treats a trailing slash on request paths
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TrailingSlash {
+    /// Match the path exactly as it was requested
+    Preserve,
+    /// Remove a trailing slash, so `/a/` matches the route `/a`
+    Trim,
+    /// Add a trailing slash, so `/a` matches the route `/a/`
+    Append,
+}
+
+/// The normalizations a router applies to request paths before matching
+#[derive(Clone, Copy, Debug)]
+pub struct Normalization {
+    /// Resolve `.` and `..` segments, e.g. `/a/./b/../c` becomes `/a/c`
+    pub dot_segments: bool,
+    /// Collapse duplicate slashes, e.g. `/a//b` becomes `/a/b`
+    pub merge_slashes: bool,
+    /// What to do with trailing slashes
+    pub trailing_slash: TrailingSlash,
+    /// Answer with a 301 redirect to the normalized path instead of
+    /// routing the request internally
+    pub redirect: bool,
+}
+
+impl Normalization {
+    /// A policy that leaves paths exactly as they were requested
+    pub fn none() -> Normalization {
+        Normalization {
+            dot_segments: false,
+            merge_slashes: false,
+            trailing_slash: TrailingSlash::Preserve,
+            redirect: false,
+        }
+    }
+
+    /// Apply the policy to a path
+    pub fn apply(&self, path: &str) -> String {
+        let mut path = String::from(path);
+        if self.merge_slashes {
+            path = uri::merge_slashes(&path);
+        }
+        if self.dot_segments {
+            path = uri::remove_dot_segments(&path);
+        }
+        match self.trailing_slash {
+            TrailingSlash::Preserve => {}
+            TrailingSlash::Trim => {
+                while path.len() > 1 && path.ends_with('/') {
+                    path.pop();
+                }
+            }
+            TrailingSlash::Append => {
+                if !path.ends_with('/') {
+                    path.push('/');
+                }
+            }
+        }
+        path
+    }
+}
+
+impl Default for Normalization {
+    /// Resolve dot segments and duplicate slashes, keep trailing slashes
+    fn default() -> Normalization {
+        Normalization {
+            dot_segments: true,
+            merge_slashes: true,
+            trailing_slash: TrailingSlash::Preserve,
+            redirect: false,
+        }
+    }
+}
+
+/// A single registered route
+pub struct Route {
+    method: String,
+    pattern: String,
+    handler: BoxedHandler,
+    /// The type name of the handler
+    handler_name: &'static str,
+    pool: Option<String>,
+    streams_body: bool,
+    timeout: Option<Duration>,
+    /// Middleware of the groups and nested routers the route was added
+    /// through, run after the router's own
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl Route {
+    /// Run the route's handler on the named worker pool instead of the
+    /// pool that accepted the connection
+    ///
+    /// The pool has to be configured on the server; slow endpoints can
+    /// be given a separate, smaller pool so they cannot starve fast ones.
+    pub fn on_pool(&mut self, pool: &str) -> &mut Route {
+        self.pool = Some(String::from(pool));
+        self
+    }
+
+    /// Run a middleware for this route only
+    ///
+    /// Route middleware runs after the middleware of the router and of
+    /// the groups and nested routers the route was added through, in the
+    /// order it was attached, so the first layer sees the request first
+    /// among them and the response last. It runs once the route has
+    /// been chosen, with the captured parameters set on the request.
+    pub fn layer<M: Middleware>(&mut self, middleware: M) -> &mut Route {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Hand the request body to the handler while it is still being
+    /// received, instead of reading all of it first
+    ///
+    /// The handler reads it with `Request::take_body`. Streamed bodies
+    /// are limited by `Limits::max_streamed_body` rather than by what
+    /// the connection may buffer.
+    pub fn stream_body(&mut self) -> &mut Route {
+        self.streams_body = true;
+        self
+    }
+
+    /// Whether the request body is streamed to the handler
+    pub fn streams_body(&self) -> bool {
+        self.streams_body
+    }
+
+    /// Answer with 504 if the handler has not responded within a time,
+    /// measured from when it starts running
+    ///
+    /// The server enforces the timeout, which covers the middleware of
+    /// the router as well; `Router::handle` called directly ignores it.
+    /// A handler that runs out of time cannot be stopped, so it keeps
+    /// running on a thread of its own, its response discarded, and its
+    /// request's cancellation token is cancelled to tell it to give up.
+    /// While 256 such threads are running, requests to routes with a
+    /// timeout are answered with 503 instead.
+    /// The request's deadline is set to match, see `Request::deadline`.
+    pub fn timeout_after(&mut self, timeout: Duration) -> &mut Route {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// How long the handler may take, if it is limited
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// The name of the pool the route runs on, if it has one
+    pub fn pool(&self) -> Option<&str> {
+        self.pool.as_deref()
+    }
+
+    /// The type name of the route's handler, e.g. `app::users::show` for
+    /// a function, or ending in `{{closure}}` for a closure
+    pub fn handler_name(&self) -> &'static str {
+        self.handler_name
+    }
+
+    /// The method the route responds to
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// The path the route matches
+    ///
+    /// Segments starting with `:` match any single segment and are
+    /// captured as a parameter of that name, e.g. `/users/:id`. A `*`
+    /// segment matches any single segment without capturing it, and a
+    /// last segment `*name` matches the rest of the path, which is
+    /// captured without its leading slash, e.g. `css/site.css` for
+    /// `/static/css/site.css` and `/static/*path`. The rest may be empty,
+    /// as for `/static/`, but `/static` does not match.
+    ///
+    /// When several routes match a path, the most specific one wins:
+    /// segments are compared from the left, and a literal segment beats
+    /// a `:name`, which beats a `*`, which beats a `*name`. Routes that
+    /// are equally specific go by registration order.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Match a path against the route's pattern, returning the captured
+    /// parameters if it matches
+    fn captures(&self, path: &str) -> Option<Vec<(String, String)>> {
+        if !self.pattern.contains(':') && !self.pattern.contains('*') {
+            return if self.pattern == path { Some(Vec::new()) } else { None };
+        }
+
+        let mut segments = path.split('/');
+        let mut params = Vec::new();
+        for expected in self.pattern.split('/') {
+            match Segment::parse(expected) {
+                Segment::CatchAll(name) => {
+                    let rest: Vec<&str> = segments.collect();
+                    if rest.is_empty() {
+                        return None;
+                    }
+                    params.push((String::from(name), rest.join("/")));
+                    return Some(params);
+                }
+                Segment::Wildcard => {
+                    if segments.next()?.is_empty() {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let segment = segments.next()?;
+                    if segment.is_empty() {
+                        return None;
+                    }
+                    params.push((String::from(name), String::from(segment)));
+                }
+                Segment::Literal(literal) => {
+                    if segments.next()? != literal {
+                        return None;
+                    }
+                }
+            }
+        }
+        match segments.next() {
+            Some(_) => None,
+            None => Some(params),
+        }
+    }
+
+    /// How specific the pattern is, compared between routes matching the
+    /// same path, see `pattern`
+    fn specificity(&self) -> Vec<u8> {
+        self.pattern.split('/').map(|segment| Segment::parse(segment).rank()).collect()
+    }
+
+    fn matches_method(&self, method: &str) -> bool {
+        self.method == method || (method == "HEAD" && self.method == "GET")
+    }
+
+    fn run(&self, request: Request) -> Response {
+        Next::new(&self.middleware, &*self.handler).run(request)
+    }
+}
+
+/// A description of a registered route, see `Router::routes`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteInfo {
+    pub method: String,
+    /// The full pattern, including the prefixes of groups and nested
+    /// routers
+    pub pattern: String,
+    /// The type name of the handler, see `Route::handler_name`
+    pub handler: &'static str,
+    /// The named pool the route runs on, if any
+    pub pool: Option<String>,
+    /// How long the handler may take, if it is limited
+    pub timeout: Option<Duration>,
+    /// Number of middleware run for the route only, besides the ones
+    /// of the router
+    pub middleware: usize,
+}
+
+impl ToJson for RouteInfo {
+    fn to_json(&self) -> Value {
+        Value::object()
+            .with("method", self.method.as_str())
+            .with("pattern", self.pattern.as_str())
+            .with("handler", self.handler)
+            .with("pool", self.pool.as_deref())
+            .with("timeout_ms", self.timeout.map(|timeout| timeout.as_millis() as u64))
+            .with("middleware", self.middleware)
+    }
+}
+
+/// A route matching a request, with the parameters it captured
+struct Match<'a> {
+    route: &'a Route,
+    params: Vec<(String, String)>,
+}
+
+/// A segment of a route pattern
+enum Segment<'a> {
+    Literal(&'a str),
+    Param(&'a str),
+    Wildcard,
+    CatchAll(&'a str),
+}
+
+impl Segment<'_> {
+    fn parse(segment: &str) -> Segment<'_> {
+        if segment == "*" {
+            Segment::Wildcard
+        } else if let Some(name) = segment.strip_prefix('*') {
+            Segment::CatchAll(name)
+        } else if let Some(name) = segment.strip_prefix(':') {
+            Segment::Param(name)
+        } else {
+            Segment::Literal(segment)
+        }
+    }
+
+    /// Higher for segments matching fewer paths
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Literal(_) => 3,
+            Segment::Param(_) => 2,
+            Segment::Wildcard => 1,
+            Segment::CatchAll(_) => 0,
+        }
+    }
+}
+
+/// A handler along with its type name
+struct NamedHandler {
+    handler: BoxedHandler,
+    name: &'static str,
+}
+
+impl NamedHandler {
+    fn new<H, R>(handler: H) -> NamedHandler
+    where
+        H: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        NamedHandler { handler: Box::new(move |request| handler(request).into_response()), name: any::type_name::<H>() }
+    }
+}
+
+/// A handler for requests matching no route under a prefix
+struct Fallback {
+    /// The path the fallback covers along with everything below it,
+    /// empty for the router's own
+    prefix: String,
+    handler: BoxedHandler,
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl Fallback {
+    fn covers(&self, path: &str) -> bool {
+        self.prefix.is_empty()
+            || path == self.prefix
+            || (path.starts_with(self.prefix.as_str()) && path.as_bytes().get(self.prefix.len()) == Some(&b'/'))
+    }
+}
+
+/// A pattern below a prefix, e.g. `/users` below `/api` is `/api/users`
+/// and `/` below `/api` is `/api`
+fn join(prefix: &str, pattern: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.is_empty() {
+        return String::from(pattern);
+    }
+    match pattern {
+        "" | "/" => String::from(prefix),
+        pattern if pattern.starts_with('/') => format!("{}{}", prefix, pattern),
+        pattern => format!("{}/{}", prefix, pattern),
+    }
+}
+
+/// Dispatches requests to handlers by method and path
+pub struct Router {
+    routes: Vec<Route>,
+    normalization: Normalization,
+    /// The router's own fallback and those of nested routers
+    fallbacks: Vec<Fallback>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    /// Where the route table is served, see `expose_routes`
+    routes_path: Option<String>,
+}
+
+impl Router {
+    /// Create a router without any routes
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            normalization: Normalization::default(),
+            fallbacks: Vec::new(),
+            middleware: Vec::new(),
+            routes_path: None,
+        }
+    }
+
+    /// Set a handler for requests whose path matches no route
+    ///
+    /// The fallback replaces the default 404 response, e.g. to serve
+    /// the index page of a single-page application or a custom
+    /// not-found page. It receives the request with its normalized path.
+    pub fn fallback<H, R>(&mut self, handler: H) -> &mut Router
+    where
+        H: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.fallbacks.retain(|fallback| !fallback.prefix.is_empty());
+        let handler: BoxedHandler = Box::new(move |request| handler(request).into_response());
+        self.fallbacks.push(Fallback { prefix: String::new(), handler, middleware: Vec::new() });
+        self
+    }
+
+    /// Run a middleware around the handling of every request
+    ///
+    /// Middleware runs in registration order, so the first one added
+    /// sees the request first and the response last. It runs after the
+    /// path has been normalized and covers the fallback and the 404 and
+    /// 405 responses as well. Middleware of single routes and groups
+    /// runs inside it, see `Route::layer`.
+    pub fn wrap<M: Middleware>(&mut self, middleware: M) -> &mut Router {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Run a middleware around the handling of every request before all
+    /// the others, so it sees the request first and the response last
+    pub(crate) fn wrap_outermost<M: Middleware>(&mut self, middleware: M) {
+        self.middleware.insert(0, Arc::new(middleware));
+    }
+
+    /// Add the routes of another router below a prefix
+    ///
+    /// A route `/users` of the nested router becomes `/api/v1/users`
+    /// when nested at `/api/v1`, and its route `/` becomes `/api/v1`.
+    /// Middleware of the nested router runs for its routes and its
+    /// fallback only, after the middleware of this router; its fallback
+    /// answers requests below the prefix that match no route. Its
+    /// normalization is replaced by the one of this router.
+    ///
+    /// # Arguments
+    ///
+    /// prefix - The path the routes are added below.
+    /// router - The router whose routes are added.
+    pub fn nest(&mut self, prefix: &str, router: Router) -> &mut Router {
+        let Router { routes, fallbacks, middleware, .. } = router;
+        for mut route in routes {
+            route.pattern = join(prefix, &route.pattern);
+            route.middleware.splice(0..0, middleware.iter().cloned());
+            self.routes.push(route);
+        }
+        for mut fallback in fallbacks {
+            fallback.prefix = join(prefix, &fallback.prefix).trim_end_matches('/').to_string();
+            fallback.middleware.splice(0..0, middleware.iter().cloned());
+            // A nested fallback replaces one nested at the same prefix
+            self.fallbacks.retain(|existing| existing.prefix != fallback.prefix);
+            self.fallbacks.push(fallback);
+        }
+        self
+    }
+
+    /// Add routes below a prefix through a group, which can run its own
+    /// middleware for them
+    ///
+    /// ```
+    /// use server::middleware::Next;
+    /// use server::request::Request;
+    /// use server::response::Response;
+    /// use server::router::Router;
+    ///
+    /// let mut router = Router::new();
+    /// let mut admin = router.group("/admin").middleware(|request: Request, next: &Next| {
+    ///     match request.header("Authorization") {
+    ///         Some(_) => next.run(request),
+    ///         None => Response::text(401, "Unauthorized"),
+    ///     }
+    /// });
+    /// admin.get("/users", |_| Response::text(200, "ada, grace"));
+    /// assert_eq!(router.routes()[0].pattern, "/admin/users");
+    /// ```
+    pub fn group(&mut self, prefix: &str) -> Group<'_> {
+        Group { router: self, prefix: String::from(prefix), middleware: Vec::new(), timeout: None }
+    }
+
+    /// Set how request paths are normalized before they are matched
+    pub fn normalization(&mut self, normalization: Normalization) -> &mut Router {
+        self.normalization = normalization;
+        self
+    }
+
+    /// Register a handler for a method and path
+    ///
+    /// # Arguments
+    ///
+    /// method - The request method, e.g. GET.
+    /// pattern - The path to match, which may capture `:name` segments
+    /// and contain wildcards, see `Route::pattern`.
+    /// handler - The function producing the response.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a `*name` segment is not the last one of the pattern.
+    pub fn route<H, R>(&mut self, method: &str, pattern: &str, handler: H) -> &mut Route
+    where
+        H: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.add(method, String::from(pattern), NamedHandler::new(handler), Vec::new())
+    }
+
+    fn add(&mut self, method: &str, pattern: String, handler: NamedHandler, middleware: Vec<Arc<dyn Middleware>>) -> &mut Route {
+        let segments: Vec<&str> = pattern.split('/').collect();
+        if let Some(position) = segments.iter().position(|segment| matches!(Segment::parse(segment), Segment::CatchAll(_))) {
+            assert!(position == segments.len() - 1, "The catch-all segment of {} has to be its last one", pattern);
+        }
+        self.routes.push(Route {
+            method: method.to_ascii_uppercase(),
+            pattern,
+            handler: handler.handler,
+            handler_name: handler.name,
+            pool: None,
+            streams_body: false,
+            timeout: None,
+            middleware,
+        });
+        self.routes.last_mut().unwrap()
+    }
+
+    /// Register a handler for GET (and HEAD) requests
+    pub fn get<H, R>(&mut self, pattern: &str, handler: H) -> &mut Route
+    where
+        H: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route("GET", pattern, handler)
+    }
+
+    /// Register a handler for POST requests
+    pub fn post<H, R>(&mut self, pattern: &str, handler: H) -> &mut Route
+    where
+        H: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route("POST", pattern, handler)
+    }
+
+    /// Register a handler for PUT requests
+    pub fn put<H, R>(&mut self, pattern: &str, handler: H) -> &mut Route
+    where
+        H: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route("PUT", pattern, handler)
+    }
+
+    /// Register a handler for PATCH requests
+    pub fn patch<H, R>(&mut self, pattern: &str, handler: H) -> &mut Route
+    where
+        H: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route("PATCH", pattern, handler)
+    }
+
+    /// Register a handler for DELETE requests
+    pub fn delete<H, R>(&mut self, pattern: &str, handler: H) -> &mut Route
+    where
+        H: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route("DELETE", pattern, handler)
+    }
+
+    /// The registered routes in registration order
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        self.routes
+            .iter()
+            .map(|route| RouteInfo {
+                method: route.method.clone(),
+                pattern: route.pattern.clone(),
+                handler: route.handler_name,
+                pool: route.pool.clone(),
+                timeout: route.timeout,
+                middleware: route.middleware.len(),
+            })
+            .collect()
+    }
+
+    /// Answer GET requests for a path with the route table as JSON, for
+    /// debugging or generating documentation
+    ///
+    /// The table goes through the router's middleware like any route,
+    /// so it can be protected the same way. It should not be exposed
+    /// publicly, as handler names tell about the code.
+    pub fn expose_routes(&mut self, path: &str) -> &mut Router {
+        self.routes_path = Some(String::from(path));
+        self
+    }
+
+    /// Find the route a request would be dispatched to
+    pub fn route_for(&self, request: &Request) -> Option<&Route> {
+        let path = self.normalization.apply(request.path());
+        self.find(request.method(), &path).ok().map(|found| found.route)
+    }
+
+    /// The most specific route matching a method and path, or the
+    /// methods of the routes matching the path if none matches the method
+    fn find(&self, method: &str, path: &str) -> Result<Match<'_>, Vec<&str>> {
+        let mut best: Option<(Match, Vec<u8>)> = None;
+        let mut allowed: Vec<&str> = Vec::new();
+        for route in &self.routes {
+            let params = match route.captures(path) {
+                Some(params) => params,
+                None => continue,
+            };
+            if !route.matches_method(method) {
+                allowed.push(&route.method);
+                continue;
+            }
+            let specificity = route.specificity();
+            if best.as_ref().is_none_or(|(_, best)| specificity > *best) {
+                best = Some((Match { route, params }, specificity));
+            }
+        }
+        best.map(|(found, _)| found).ok_or(allowed)
+    }
+
+    /// Dispatch a request to the matching route
+    ///
+    /// Runs the fallback handler, or responds with 404 if there is none,
+    /// when no route matches the path. Responds with 405 if routes match
+    /// the path but none of them the method.
+    pub fn handle(&self, mut request: Request) -> Response {
+        let path = self.normalization.apply(request.path());
+        if path != request.path() {
+            if self.normalization.redirect {
+                let mut location = uri::percent_encode_path(&path);
+                if let Some(query) = request.query() {
+                    location.push('?');
+                    location.push_str(query);
+                }
+                return Response::new(301).with_header("Location", &location);
+            }
+            request.set_path(&path);
+        }
+
+        let dispatch = |request| self.dispatch(request);
+        Next::new(&self.middleware, &dispatch).run(request)
+    }
+
+    /// Run the route matching the (possibly rewritten) request path
+    fn dispatch(&self, mut request: Request) -> Response {
+        let path = String::from(request.path());
+        if self.routes_path.as_deref() == Some(path.as_str()) && matches!(request.method(), "GET" | "HEAD") {
+            return Json(self.routes()).into_response();
+        }
+        let mut allowed = match self.find(request.method(), &path) {
+            Ok(found) => {
+                request.set_params(found.params);
+                return found.route.run(request);
+            }
+            Err(allowed) => allowed,
+        };
+
+        if allowed.is_empty() {
+            let fallback = self
+                .fallbacks
+                .iter()
+                .filter(|fallback| fallback.covers(&path))
+                .max_by_key(|fallback| fallback.prefix.len());
+            match fallback {
+                Some(fallback) => Next::new(&fallback.middleware, &*fallback.handler).run(request),
+                None => Response::text(404, "Not Found"),
+            }
+        } else {
+            if allowed.contains(&"GET") {
+                allowed.push("HEAD");
+            }
+            Response::text(405, "Method Not Allowed").with_header("Allow", &allowed.join(", "))
+        }
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+/// Whether a route pattern is well formed, for checking patterns at
+/// compile time
+///
+/// A pattern starts with a slash, its `:name` and `*name` segments have
+/// names of letters, digits and underscores, and a `*name` segment can
+/// only be the last one.
+pub const fn is_valid_pattern(pattern: &str) -> bool {
+    let bytes = pattern.as_bytes();
+    if bytes.is_empty() || bytes[0] != b'/' {
+        return false;
+    }
+    let mut start = 1;
+    while start <= bytes.len() {
+        let mut end = start;
+        while end < bytes.len() && bytes[end] != b'/' {
+            end += 1;
+        }
+        if start < end && (bytes[start] == b':' || bytes[start] == b'*') {
+            let wildcard = bytes[start] == b'*' && end == start + 1;
+            if !wildcard {
+                if end == start + 1 {
+                    return false;
+                }
+                let mut i = start + 1;
+                while i < end {
+                    if !(bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                        return false;
+                    }
+                    i += 1;
+                }
+                if bytes[start] == b'*' && end != bytes.len() {
+                    return false;
+                }
+            }
+        }
+        start = end + 1;
+    }
+    true
+}
+
+/// Build a router from a table of routes
+///
+/// Every line is a method, a pattern and a handler, and each pattern is
+/// checked with `is_valid_pattern` when the program is compiled.
+///
+/// ```
+/// use server::request::Request;
+/// use server::response::Response;
+/// use server::routes;
+///
+/// fn index(_request: Request) -> Response {
+///     Response::text(200, "Hello")
+/// }
+///
+/// fn update_item(request: Request) -> Response {
+///     Response::text(200, format!("Updated {}", request.param("id").unwrap_or("")))
+/// }
+///
+/// let router = routes! {
+///     GET "/" => index,
+///     POST "/items/:id" => update_item,
+/// };
+/// assert_eq!(router.routes().len(), 2);
+/// ```
+///
+/// A malformed pattern fails to compile:
+///
+/// ```compile_fail
+/// # use server::response::Response;
+/// let router = server::routes! {
+///     GET "/files/*path/meta" => |_| Response::new(200),
+/// };
+/// ```
+#[macro_export]
+macro_rules! routes {
+    ($($method:ident $pattern:literal => $handler:expr),* $(,)?) => {{
+        let mut router = $crate::router::Router::new();
+        $(
+            const _: () = assert!($crate::router::is_valid_pattern($pattern), concat!("Invalid route pattern ", $pattern));
+            router.route(stringify!($method), $pattern, $handler);
+        )*
+        router
+    }};
+}
+
+/// Routes added below a common prefix, see `Router::group`
+pub struct Group<'a> {
+    router: &'a mut Router,
+    prefix: String,
+    middleware: Vec<Arc<dyn Middleware>>,
+    timeout: Option<Duration>,
+}
+
+impl Group<'_> {
+    /// Run a middleware for the routes added through the group from now
+    /// on, after the middleware of the router and of enclosing groups
+    pub fn middleware<M: Middleware>(mut self, middleware: M) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Limit how long the handlers of the routes added through the group
+    /// from now on may take, see `Route::timeout_after`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// A group below a prefix of this one, running its middleware too
+    pub fn group(&mut self, prefix: &str) -> Group<'_> {
+        Group {
+            prefix: join(&self.prefix, prefix),
+            middleware: self.middleware.clone(),
+            timeout: self.timeout,
+            router: self.router,
+        }
+    }
+
+    /// Register a handler for a method and a path below the prefix, see
+    /// `Router::route`
+    pub fn route<H, R>(&mut self, method: &str, pattern: &str, handler: H) -> &mut Route
+    where
+        H: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        let pattern = join(&self.prefix, pattern);
+        let route = self.router.add(method, pattern, NamedHandler::new(handler), self.middleware.clone());
+        route.timeout = self.timeout;
+        route
+    }
+
+    /// Register a handler for GET (and HEAD) requests
+    pub fn get<H, R>(&mut self, pattern: &str, handler: H) -> &mut Route
+    where
+        H: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route("GET", pattern, handler)
+    }
+
+    /// Register a handler for POST requests
+    pub fn post<H, R>(&mut self, pattern: &str, handler: H) -> &mut Route
+    where
+        H: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route("POST", pattern, handler)
+    }
+
+    /// Register a handler for PUT requests
+    pub fn put<H, R>(&mut self, pattern: &str, handler: H) -> &mut Route
+    where
+        H: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route("PUT", pattern, handler)
+    }
+
+    /// Register a handler for PATCH requests
+    pub fn patch<H, R>(&mut self, pattern: &str, handler: H) -> &mut Route
+    where
+        H: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route("PATCH", pattern, handler)
+    }
+
+    /// Register a handler for DELETE requests
+    pub fn delete<H, R>(&mut self, pattern: &str, handler: H) -> &mut Route
+    where
+        H: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route("DELETE", pattern, handler)
+    }
+}
@@ -0,0 +1,180 @@
+// RSASSA-PKCS1-v1_5 signature verification with SHA-256, as specified in
+// RFC 8017, which is what RS256 JSON Web Tokens are signed with. Only
+// public keys are involved, so nothing here needs to run in constant
+// time.
+
+use crate::sha256;
+
+/// The DER encoding of the DigestInfo of a SHA-256 hash, which precedes
+/// the hash itself in a signature
+const SHA256_PREFIX: [u8; 19] =
+    [0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00, 0x04, 0x20];
+
+/// The smallest modulus accepted, in bits
+const MIN_BITS: usize = 2048;
+
+/// An RSA public key
+#[derive(Clone)]
+pub(crate) struct PublicKey {
+    /// The modulus, least significant limb first
+    modulus: Vec<u32>,
+    /// The length of the modulus in bytes, which signatures have
+    length: usize,
+    exponent: Vec<u8>,
+    /// The negated inverse of the lowest limb of the modulus, modulo 2^32
+    inverse: u32,
+    /// R^2 modulo the modulus, where R is 2 to the power of the bits in
+    /// the limbs of the modulus, for moving numbers into Montgomery form
+    r_squared: Vec<u32>,
+}
+
+impl PublicKey {
+    /// A key from its big-endian modulus and exponent, or None if it is
+    /// not a usable key
+    pub(crate) fn new(modulus: &[u8], exponent: &[u8]) -> Option<PublicKey> {
+        let modulus = strip_zeros(modulus);
+        let exponent = strip_zeros(exponent);
+        let length = modulus.len();
+        if length * 8 < MIN_BITS || modulus[length - 1] & 1 == 0 || exponent.is_empty() || exponent.len() > 8 {
+            return None;
+        }
+        let modulus = limbs(modulus, length.div_ceil(4));
+        let inverse = (0..5).fold(1u32, |inverse, _| {
+            inverse.wrapping_mul(2u32.wrapping_sub(modulus[0].wrapping_mul(inverse)))
+        });
+        let r_squared = r_squared(&modulus);
+        Some(PublicKey { modulus, length, exponent: exponent.to_vec(), inverse: inverse.wrapping_neg(), r_squared })
+    }
+
+    /// Whether a signature of a message was made with the private key
+    pub(crate) fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        if signature.len() != self.length {
+            return false;
+        }
+        let signature = limbs(signature, self.modulus.len());
+        if compare(&signature, &self.modulus) != std::cmp::Ordering::Less {
+            return false;
+        }
+        let decrypted = self.power(&signature);
+
+        let mut expected = vec![0xff; self.length];
+        expected[0] = 0;
+        expected[1] = 1;
+        let digest_start = self.length - 32;
+        let prefix_start = digest_start - SHA256_PREFIX.len();
+        expected[prefix_start - 1] = 0;
+        expected[prefix_start..digest_start].copy_from_slice(&SHA256_PREFIX);
+        expected[digest_start..].copy_from_slice(&sha256::digest(message));
+        bytes(&decrypted, self.length) == expected
+    }
+
+    /// A number, smaller than the modulus, to the power of the exponent
+    /// modulo the modulus
+    fn power(&self, base: &[u32]) -> Vec<u32> {
+        let mut one = vec![0; self.modulus.len()];
+        one[0] = 1;
+        let base = self.multiply(base, &self.r_squared);
+        let mut result = self.multiply(&one, &self.r_squared);
+        for byte in &self.exponent {
+            for bit in (0..8).rev() {
+                result = self.multiply(&result, &result);
+                if byte >> bit & 1 == 1 {
+                    result = self.multiply(&result, &base);
+                }
+            }
+        }
+        self.multiply(&result, &one)
+    }
+
+    /// The Montgomery product of two numbers smaller than the modulus,
+    /// a * b / R modulo the modulus
+    fn multiply(&self, a: &[u32], b: &[u32]) -> Vec<u32> {
+        let n = &self.modulus;
+        let size = n.len();
+        let mut t = vec![0u32; size + 2];
+        for &digit in b {
+            let mut carry = 0u64;
+            for j in 0..size {
+                let sum = u64::from(t[j]) + u64::from(a[j]) * u64::from(digit) + carry;
+                t[j] = sum as u32;
+                carry = sum >> 32;
+            }
+            let sum = u64::from(t[size]) + carry;
+            t[size] = sum as u32;
+            t[size + 1] = (sum >> 32) as u32;
+
+            // Adding a multiple of the modulus makes the lowest limb zero,
+            // so everything can be shifted down a limb
+            let m = t[0].wrapping_mul(self.inverse);
+            let mut carry = (u64::from(t[0]) + u64::from(m) * u64::from(n[0])) >> 32;
+            for j in 1..size {
+                let sum = u64::from(t[j]) + u64::from(m) * u64::from(n[j]) + carry;
+                t[j - 1] = sum as u32;
+                carry = sum >> 32;
+            }
+            let sum = u64::from(t[size]) + carry;
+            t[size - 1] = sum as u32;
+            t[size] = t[size + 1] + (sum >> 32) as u32;
+            t[size + 1] = 0;
+        }
+        if t[size] != 0 || compare(&t[..size], n) != std::cmp::Ordering::Less {
+            subtract(&mut t[..=size], n);
+        }
+        t.truncate(size);
+        t
+    }
+}
+
+fn strip_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|&byte| byte != 0).unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+/// A big-endian number as `count` limbs, least significant first
+fn limbs(bytes: &[u8], count: usize) -> Vec<u32> {
+    let mut limbs = vec![0u32; count];
+    for (i, &byte) in bytes.iter().rev().enumerate() {
+        limbs[i / 4] |= u32::from(byte) << (8 * (i % 4));
+    }
+    limbs
+}
+
+/// A number as `length` big-endian bytes
+fn bytes(limbs: &[u32], length: usize) -> Vec<u8> {
+    (0..length).rev().map(|i| (limbs[i / 4] >> (8 * (i % 4))) as u8).collect()
+}
+
+fn compare(a: &[u32], b: &[u32]) -> std::cmp::Ordering {
+    a.iter().rev().cmp(b.iter().rev())
+}
+
+/// Subtract `b` from `a`, which is at least as large and may have more
+/// limbs
+fn subtract(a: &mut [u32], b: &[u32]) {
+    let mut borrow = 0i64;
+    for (i, limb) in a.iter_mut().enumerate() {
+        let difference = i64::from(*limb) - i64::from(*b.get(i).unwrap_or(&0)) + borrow;
+        *limb = difference as u32;
+        borrow = difference >> 32;
+    }
+}
+
+/// R^2 modulo a modulus, by doubling 1 as many times as R^2 has bits
+fn r_squared(modulus: &[u32]) -> Vec<u32> {
+    let size = modulus.len();
+    let mut value = vec![0u32; size + 1];
+    value[0] = 1;
+    for _ in 0..64 * size {
+        let mut carry = 0;
+        for limb in value.iter_mut() {
+            let shifted = *limb >> 31;
+            *limb = *limb << 1 | carry;
+            carry = shifted;
+        }
+        if value[size] != 0 || compare(&value[..size], modulus) != std::cmp::Ordering::Less {
+            subtract(&mut value, modulus);
+        }
+    }
+    value.truncate(size);
+    value
+}
@@ -0,0 +1,134 @@
+use std::time::Duration;
+
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+
+/// A middleware adding security headers to every response
+///
+/// By default responses get Strict-Transport-Security for a year,
+/// `X-Content-Type-Options: nosniff`, `X-Frame-Options: DENY` and
+/// `Referrer-Policy: strict-origin-when-cross-origin`; a
+/// Content-Security-Policy is only sent once one is configured, as no
+/// policy fits every site. Headers a response sets itself are left
+/// alone, so a handler can override any of them for itself, and whole
+/// parts of the site can be given a different set with `for_path`.
+#[derive(Clone)]
+pub struct SecurityHeaders {
+    headers: Vec<(String, String)>,
+    overrides: Vec<(String, SecurityHeaders)>,
+}
+
+impl SecurityHeaders {
+    /// Create the middleware with the default headers
+    pub fn new() -> SecurityHeaders {
+        SecurityHeaders::empty()
+            .with_hsts(Duration::from_secs(365 * 24 * 60 * 60), false)
+            .with_header("X-Content-Type-Options", "nosniff")
+            .with_header("X-Frame-Options", "DENY")
+            .with_header("Referrer-Policy", "strict-origin-when-cross-origin")
+    }
+
+    /// Create the middleware without any headers, to add only the ones
+    /// wanted
+    pub fn empty() -> SecurityHeaders {
+        SecurityHeaders {
+            headers: Vec::new(),
+            overrides: Vec::new(),
+        }
+    }
+
+    /// Add a header, replacing one of the same name
+    pub fn with_header(mut self, name: &str, value: &str) -> SecurityHeaders {
+        self = self.without(name);
+        self.headers.push((String::from(name), String::from(value)));
+        self
+    }
+
+    /// Stop adding a header
+    pub fn without(mut self, name: &str) -> SecurityHeaders {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self
+    }
+
+    /// Tell browsers to only use https for the site for some time
+    ///
+    /// Browsers ignore the header on plain HTTP responses.
+    ///
+    /// # Arguments
+    ///
+    /// max_age - How long browsers remember to use https.
+    /// include_subdomains - Whether all subdomains only use https as well.
+    pub fn with_hsts(self, max_age: Duration, include_subdomains: bool) -> SecurityHeaders {
+        let mut value = format!("max-age={}", max_age.as_secs());
+        if include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        self.with_header("Strict-Transport-Security", &value)
+    }
+
+    /// Restrict where pages may be embedded, e.g. `DENY` or `SAMEORIGIN`
+    pub fn with_frame_options(self, value: &str) -> SecurityHeaders {
+        self.with_header("X-Frame-Options", value)
+    }
+
+    /// Set how much of the URL is sent as the referrer, e.g. `no-referrer`
+    pub fn with_referrer_policy(self, value: &str) -> SecurityHeaders {
+        self.with_header("Referrer-Policy", value)
+    }
+
+    /// Restrict the sources a page may load content from, e.g.
+    /// `default-src 'self'`
+    pub fn with_content_security_policy(self, policy: &str) -> SecurityHeaders {
+        self.with_header("Content-Security-Policy", policy)
+    }
+
+    /// Send a different set of headers for a part of the site
+    ///
+    /// The prefix matches its path and everything below it, so `/embed`
+    /// covers `/embed` and `/embed/video` but not `/embedded`. When
+    /// several prefixes match, the longest one wins.
+    ///
+    /// # Arguments
+    ///
+    /// prefix - The path the headers apply under.
+    /// headers - The headers sent there instead of the ones of this middleware.
+    pub fn for_path(mut self, prefix: &str, headers: SecurityHeaders) -> SecurityHeaders {
+        let prefix = prefix.trim_end_matches('/');
+        self.overrides.retain(|(p, _)| p != prefix);
+        self.overrides.push((String::from(prefix), headers));
+        self
+    }
+
+    /// The headers that apply to a path
+    fn headers_for(&self, path: &str) -> &[(String, String)] {
+        let matching = self.overrides.iter().filter(|(prefix, _)| {
+            prefix.is_empty()
+                || path == prefix
+                || (path.starts_with(prefix.as_str()) && path.as_bytes().get(prefix.len()) == Some(&b'/'))
+        });
+        match matching.max_by_key(|(prefix, _)| prefix.len()) {
+            Some((_, headers)) => headers.headers_for(path),
+            None => &self.headers,
+        }
+    }
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> SecurityHeaders {
+        SecurityHeaders::new()
+    }
+}
+
+impl Middleware for SecurityHeaders {
+    fn handle(&self, request: Request, next: &Next) -> Response {
+        let path = String::from(request.path());
+        let mut response = next.run(request);
+        for (name, value) in self.headers_for(&path) {
+            if response.header(name).is_none() {
+                response.set_header(name, value);
+            }
+        }
+        response
+    }
+}
@@ -0,0 +1,156 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A writer file bodies can be transmitted to
+///
+/// On unix this is any writer backed by a file descriptor, which lets
+/// the kernel copy file contents straight to the socket.
+#[cfg(unix)]
+pub trait Socket: Write + AsRawFd {}
+
+#[cfg(unix)]
+impl<T: Write + AsRawFd> Socket for T {}
+
+/// A writer file bodies can be transmitted to
+#[cfg(not(unix))]
+pub trait Socket: Write {}
+
+#[cfg(not(unix))]
+impl<T: Write> Socket for T {}
+
+/// Set once the kernel has reported that sendfile cannot be used, so
+/// later transfers go straight to the portable copy
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+static UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Largest number of bytes handed to a single sendfile call
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+const MAX_CHUNK: u64 = 1 << 30;
+
+/// Copy `length` bytes from the current position of a file to a socket
+///
+/// Uses sendfile where the platform supports it and falls back to
+/// reading and writing through a userspace buffer otherwise, or when
+/// the kernel rejects the pair of descriptors at runtime.
+///
+/// # Errors
+///
+/// Returns an error if either side fails, or if the file ends before
+/// `length` bytes have been sent.
+pub fn copy_file<W: Socket>(file: &mut File, socket: &mut W, length: u64) -> io::Result<()> {
+    let sent = zero_copy(file, socket, length)?;
+    if sent < length {
+        let copied = io::copy(&mut file.take(length - sent), socket)?;
+        if sent + copied != length {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File ended before its full length was sent."));
+        }
+    }
+    Ok(())
+}
+
+/// Send as much of the file as possible without copying through userspace,
+/// returning the number of bytes sent
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+fn zero_copy<W: Socket>(file: &mut File, socket: &mut W, length: u64) -> io::Result<u64> {
+    if UNSUPPORTED.load(Ordering::Relaxed) {
+        return Ok(0);
+    }
+    // Anything buffered by the writer has to reach the socket first
+    socket.flush()?;
+
+    let mut sent = 0;
+    while sent < length {
+        match sys::send(file, socket.as_raw_fd(), (length - sent).min(MAX_CHUNK)) {
+            Ok(0) => break,
+            Ok(n) => sent += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                if sent == 0 && sys::is_unsupported(&e) {
+                    UNSUPPORTED.store(true, Ordering::Relaxed);
+                    break;
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(sent)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn zero_copy<W: Socket>(_file: &mut File, _socket: &mut W, _length: u64) -> io::Result<u64> {
+    Ok(0)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::raw::c_int;
+    use std::os::unix::io::AsRawFd;
+
+    extern "C" {
+        fn sendfile(out_fd: c_int, in_fd: c_int, offset: *mut i64, count: usize) -> isize;
+    }
+
+    const EINVAL: i32 = 22;
+    const ENOSYS: i32 = 38;
+
+    /// Send up to `count` bytes from the file's current position, advancing it
+    pub fn send(file: &File, socket: c_int, count: u64) -> io::Result<u64> {
+        // A null offset makes the kernel read from and update the file position
+        let sent = unsafe { sendfile(socket, file.as_raw_fd(), std::ptr::null_mut(), count as usize) };
+        if sent < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(sent as u64)
+        }
+    }
+
+    pub fn is_unsupported(error: &io::Error) -> bool {
+        matches!(error.raw_os_error(), Some(EINVAL) | Some(ENOSYS))
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::io::{Seek, SeekFrom};
+    use std::os::raw::{c_int, c_void};
+    use std::os::unix::io::AsRawFd;
+
+    extern "C" {
+        fn sendfile(fd: c_int, s: c_int, offset: i64, len: *mut i64, hdtr: *mut c_void, flags: c_int) -> c_int;
+    }
+
+    const EINTR: i32 = 4;
+    const EAGAIN: i32 = 35;
+    const ENOTSUP: i32 = 45;
+    const ENOTSOCK: i32 = 38;
+
+    /// Send up to `count` bytes from the file's current position, advancing it
+    pub fn send(file: &File, socket: c_int, count: u64) -> io::Result<u64> {
+        let mut file = file;
+        let offset = file.stream_position()?;
+        let mut len = count as i64;
+        let result = unsafe { sendfile(file.as_raw_fd(), socket, offset as i64, &mut len, std::ptr::null_mut(), 0) };
+        // On EAGAIN and EINTR len still holds the number of bytes that were sent
+        if result < 0 {
+            let error = io::Error::last_os_error();
+            if len == 0 || !matches!(error.raw_os_error(), Some(EAGAIN) | Some(EINTR)) {
+                return Err(error);
+            }
+        }
+        file.seek(SeekFrom::Start(offset + len as u64))?;
+        Ok(len as u64)
+    }
+
+    pub fn is_unsupported(error: &io::Error) -> bool {
+        matches!(error.raw_os_error(), Some(ENOTSUP) | Some(ENOTSOCK))
+    }
+}
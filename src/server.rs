@@ -0,0 +1,1747 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::*;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+
+use crate::admin::{AdminListener, Control};
+use crate::body::Body;
+use crate::buffers::{BufferPool, PooledReader};
+use crate::client_limit::{ClientLimiter, ClientSlot};
+use crate::config::Config;
+use crate::daemon::{self, PidFile};
+use crate::dump::{DumpTiming, DumpedRequest, Dumper};
+use crate::early_hints::EarlyHints;
+use crate::etag;
+use crate::extract::IntoResponse;
+use crate::faults::{self, Fault, FaultInjection};
+use crate::forwarded::{self, Cidr, ForwardedHeader};
+use crate::headers::{HeaderCasing, HeaderRule};
+use crate::host;
+use crate::json::Value;
+use crate::log::{self, Access, SlowRequest};
+use crate::maintenance::Maintenance;
+use crate::metrics::Metrics;
+use crate::poll::{self, Poller};
+use crate::privileges;
+use crate::proxy;
+use crate::proxy_protocol::{self, ClientCert};
+use crate::record::Recorder;
+use crate::redirect::RedirectListener;
+use crate::request::{self, Framing, Limits, Request};
+use crate::restart;
+use crate::rewrite::{self, Answer, Rewrite};
+use crate::shed::{Shedder, Shedding};
+use crate::response::{reason_phrase, Response, UpgradeFn};
+use crate::router::{Normalization, Route, Router};
+use crate::socket::{self, SocketOptions};
+use crate::state::AppState;
+use crate::stats::{ConnectionEvent, Stats};
+use crate::trace_context::{self, TraceContext};
+#[cfg(feature = "otel")]
+use crate::trace::{Span, SpanKind, Tracer};
+use crate::{PoolHandle, ThreadPool};
+
+/// How long a server that stopped accepting, because it was replaced
+/// by a hot restart or shut down, waits for its open connections to
+/// finish
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// Most pipelined requests handled concurrently on one connection
+const MAX_PIPELINED: usize = 16;
+/// Most chunks of a streamed request body read ahead of its handler
+const STREAMED_CHUNKS: usize = 4;
+/// How often the acceptors check whether the server stopped accepting,
+/// and restart requests are checked for
+const RESTART_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How long a connection the server closes keeps being read from, so
+/// that what the client still sends does not make the kernel reset it
+/// before the last response arrives
+const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+/// Most bytes read from a connection the server closes
+const LINGER_MAX_BYTES: usize = 1024 * 1024;
+/// Longest timeout a client's deadline header is taken at; longer ones
+/// are cut to it, so no client can push a deadline out of range
+const MAX_CLIENT_TIMEOUT: Duration = Duration::from_secs(300);
+/// Most handlers of routes with a timeout running at once, including
+/// those that ran out of time and have not returned yet
+const MAX_TIMED_HANDLERS: usize = 256;
+
+/// A multithreaded HTTP server
+pub struct Server {
+    listener: TcpListener,
+    pool: ThreadPool,
+    pools: HashMap<String, ThreadPool>,
+    metrics: Arc<Metrics>,
+    stats: Arc<Stats>,
+    metrics_path: Option<String>,
+    server_name: Option<String>,
+    response_headers: Vec<HeaderRule>,
+    limits: Limits,
+    slow_request_threshold: Option<Duration>,
+    dumper: Option<Arc<Dumper>>,
+    recorder: Option<Arc<Recorder>>,
+    socket: SocketOptions,
+    buffers: Arc<BufferPool>,
+    shedding: Option<Shedding>,
+    client_limiter: Option<Arc<ClientLimiter>>,
+    maintenance: Maintenance,
+    poller: Option<Arc<Poller<Connection>>>,
+    keep_alive_timeout: Duration,
+    deadline_header: Option<String>,
+    header_casing: HeaderCasing,
+    max_requests_per_connection: Option<u64>,
+    proxy_protocol: bool,
+    require_client_cert: bool,
+    trusted_proxies: Vec<Cidr>,
+    forwarded_header: ForwardedHeader,
+    allowed_hosts: Vec<String>,
+    rewrites: Vec<Rewrite>,
+    faults: Vec<Fault>,
+    redirect: Option<RedirectListener>,
+    admin: Option<AdminListener>,
+    /// Removed when the server is dropped
+    _pid_file: Option<PidFile>,
+    hot_restart: bool,
+    acceptors: usize,
+    /// Set once the server was asked to stop accepting, by the admin
+    /// endpoint or a `ShutdownHandle`
+    stopping: Arc<AtomicBool>,
+    state: Arc<AppState>,
+    #[cfg(feature = "otel")]
+    tracer: Option<Arc<Tracer>>,
+}
+
+impl Server {
+    /// Bind a new server according to the configuration
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the address cannot be bound, the socket options
+    /// of the listener are rejected, the configured number of workers of
+    /// any pool is zero, the PID file is held by a running server, the
+    /// log file cannot be opened, or the server cannot daemonize or drop
+    /// privileges to the configured user. Client certificates can only
+    /// be required together with the PROXY protocol, and the keep-alive
+    /// timeout, the maximum of requests per connection, the buffer size
+    /// and the number of acceptors cannot be zero.
+    pub fn new(config: Config) -> io::Result<Server> {
+        if config.keep_alive_timeout.is_zero() || config.max_requests_per_connection == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The keep-alive timeout and the requests per connection cannot be zero.",
+            ));
+        }
+        if config.buffer_size == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The buffer size cannot be zero."));
+        }
+        if config.acceptors == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "At least one acceptor thread is needed."));
+        }
+        if config.require_client_cert && !config.proxy_protocol {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Client certificates can only be required with the PROXY protocol.",
+            ));
+        }
+        rewrite::validate(&config.rewrites)?;
+        if config.client_limit.as_ref().is_some_and(|limit| limit.max_in_flight == 0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "A client limit has to allow a request in flight."));
+        }
+        faults::validate(&config.faults)?;
+        log::set_format(config.log_format);
+        log::set_level(config.log_level);
+        let listener = match restart::inherited_listener()? {
+            Some(listener) => listener,
+            None => socket::bind(&config.address, &config.socket)?,
+        };
+        socket::configure_listener(&listener, &config.socket)?;
+        let redirect = match &config.https_redirect {
+            Some(redirect) => Some(RedirectListener::bind(redirect)?),
+            None => None,
+        };
+        let admin = match &config.admin {
+            Some(address) => Some(AdminListener::bind(address, &config)?),
+            None => None,
+        };
+
+        // Forking only keeps the calling thread, so the pools are
+        // started afterwards
+        let pid_file = match &config.pid_file {
+            Some(path) => Some(PidFile::create(path)?),
+            None => None,
+        };
+        if let Some(path) = &config.log_file {
+            log::log_to_file(path, config.log_rotation.clone())?;
+        }
+        let dumper = match &config.debug_dumps {
+            Some(dumps) => Some(Arc::new(Dumper::open(dumps.clone())?)),
+            None => None,
+        };
+        let recorder = match &config.recording {
+            Some(recording) => Some(Arc::new(Recorder::open(recording)?)),
+            None => None,
+        };
+        if config.daemonize {
+            daemon::daemonize()?;
+            if let Some(pid_file) = &pid_file {
+                pid_file.update()?;
+            }
+        }
+
+        match &config.user {
+            Some(user) => privileges::drop_privileges(user, config.group.as_deref(), config.chroot.as_deref())?,
+            None if config.group.is_some() || config.chroot.is_some() => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "A group or chroot requires a user to switch to."));
+            }
+            None => {}
+        }
+
+        let pool = ThreadPool::new(config.workers)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let pool = match &config.autoscale {
+            Some(autoscale) if autoscale.min == 0 || autoscale.min > autoscale.max => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Autoscaling needs a minimum from 1 up to the maximum.",
+                ));
+            }
+            Some(autoscale) => pool.with_autoscaling(autoscale.clone()),
+            None => pool,
+        };
+        let mut pools = HashMap::new();
+        for (name, size) in &config.pools {
+            let named = ThreadPool::new(*size)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Pool {}: {}", name, e)))?;
+            pools.insert(name.clone(), named);
+        }
+        let poller = if config.event_driven {
+            match Poller::new() {
+                Ok(poller) => Some(Arc::new(poller)),
+                Err(e) => {
+                    log::warn(&format!("Event-driven mode unavailable, idle connections will block workers: {}", e));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        Ok(Server {
+            listener,
+            pool,
+            pools,
+            metrics: Arc::new(Metrics::new()),
+            stats: Arc::new(Stats::new()),
+            metrics_path: config.metrics_path,
+            server_name: config.server_name,
+            response_headers: config.response_headers,
+            limits: config.limits,
+            slow_request_threshold: config.slow_request_threshold,
+            dumper,
+            recorder,
+            socket: config.socket,
+            buffers: Arc::new(BufferPool::new(config.buffer_size, config.buffer_pool_capacity)),
+            shedding: config.shedding,
+            client_limiter: config.client_limit.map(|limit| Arc::new(ClientLimiter::new(limit))),
+            maintenance: config.maintenance,
+            poller,
+            keep_alive_timeout: config.keep_alive_timeout,
+            deadline_header: config.deadline_header.clone(),
+            header_casing: config.header_casing,
+            max_requests_per_connection: config.max_requests_per_connection,
+            proxy_protocol: config.proxy_protocol,
+            require_client_cert: config.require_client_cert,
+            trusted_proxies: config.trusted_proxies,
+            forwarded_header: config.forwarded_header,
+            allowed_hosts: config.allowed_hosts,
+            rewrites: config.rewrites,
+            faults: config.faults,
+            redirect,
+            admin,
+            _pid_file: pid_file,
+            hot_restart: config.hot_restart,
+            acceptors: config.acceptors,
+            stopping: Arc::new(AtomicBool::new(false)),
+            state: Arc::new(AppState::new()),
+            #[cfg(feature = "otel")]
+            tracer: config.otel.map(Tracer::new),
+        })
+    }
+
+    /// Share values with every handler, see `AppState`
+    pub fn with_state(mut self, state: Arc<AppState>) -> Server {
+        self.state = state;
+        self
+    }
+
+    /// The address the server is listening on
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// A handle for stopping the server from another thread once it is
+    /// serving
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the address of the listening socket cannot be
+    /// found out.
+    pub fn shutdown_handle(&self) -> io::Result<ShutdownHandle> {
+        let mut address = self.listener.local_addr()?;
+        // A server listening on every interface is reached over loopback
+        if address.ip().is_unspecified() {
+            match address {
+                SocketAddr::V4(_) => address.set_ip(IpAddr::from([127, 0, 0, 1])),
+                SocketAddr::V6(_) => address.set_ip(IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1])),
+            }
+        }
+        Ok(ShutdownHandle { stopping: Arc::clone(&self.stopping), address, acceptors: self.acceptors })
+    }
+
+    /// The metrics collected by the server
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// The live connection statistics of the server
+    ///
+    /// The returned handle stays valid while the server runs, so it can
+    /// be moved into a handler, e.g. for a status page, and queried with
+    /// `snapshot`.
+    pub fn stats(&self) -> Arc<Stats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Register a function called whenever a connection is accepted,
+    /// becomes idle or active, or is closed
+    pub fn on_connection_event<F>(&self, hook: F)
+    where
+        F: Fn(&ConnectionEvent) + Send + Sync + 'static,
+    {
+        self.stats.on_event(hook);
+    }
+
+    /// Accept connections and handle each of them in the thread pool
+    ///
+    /// A handler needing the state of the server can be turned into one
+    /// for this with `state::stateful`.
+    ///
+    /// # Arguments
+    ///
+    /// handler - A function that produces a response for every request.
+    pub fn run<H, R>(self, handler: H)
+    where
+        H: Fn(Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        let mut router = Router::new();
+        router.normalization(Normalization::none()).fallback(handler);
+        self.serve(router);
+    }
+
+    /// Accept connections and dispatch their requests with a router
+    ///
+    /// Connections are handled in the default pool. Requests for routes
+    /// assigned to a named pool are handed over to that pool together
+    /// with their connection, which returns to the default pool once
+    /// the response has been written.
+    ///
+    /// In event-driven mode, connections waiting for a request are
+    /// parked with a poller thread instead and only handed to the
+    /// default pool once they have something to read.
+    ///
+    /// With hot restarts enabled, this returns once the server has been
+    /// replaced and its connections have finished.
+    pub fn serve(mut self, router: Router) {
+        for route in router.routes() {
+            if let Some(pool) = &route.pool {
+                if !self.pools.contains_key(pool) {
+                    log::warn(&format!("Route {} {} uses unknown pool {}, it will run on the default pool", route.method, route.pattern, pool));
+                }
+            }
+        }
+
+        let pools = self.pools.iter().map(|(name, pool)| (name.clone(), pool.handle())).collect();
+        let shared = self.shared(router, pools);
+
+        let shedder = self.shedding.as_ref().map(|shedding| Shedder::spawn(shedding, &self.limits));
+        if let Some(redirect) = self.redirect.take() {
+            redirect.spawn(self.pool.handle(), self.limits.clone());
+        }
+        if let Some(admin) = self.admin.take() {
+            admin.spawn(Arc::clone(&shared) as Arc<dyn Control>, self.limits.clone());
+        }
+        if let Some(poller) = &self.poller {
+            let poller = Arc::clone(poller);
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || {
+                let ready = |connection| {
+                    let shared = Arc::clone(&shared);
+                    shared.default_pool.clone().execute(move || serve_connection(connection, shared));
+                };
+                let expired = |connection: Connection| connection.close(&shared);
+                if let Err(e) = poller.run(shared.keep_alive_timeout, ready, expired) {
+                    log::error(&format!("Poller stopped: {}", e));
+                }
+            });
+        }
+
+        // Accepting without blocking lets the acceptors notice restarts
+        // and shutdowns, and the replacing process take connections from
+        // the same socket
+        let polling = self.hot_restart || shared.admin;
+        if self.hot_restart {
+            restart::install_handler();
+        }
+        if polling {
+            if let Err(e) = self.listener.set_nonblocking(true) {
+                log::error(&format!("Failed to make listener non-blocking: {}", e));
+            }
+        }
+
+        // The acceptor threads only accept and hand connections over, so
+        // accepting goes on while every worker is busy
+        thread::scope(|scope| {
+            for _ in 0..self.acceptors {
+                scope.spawn(|| self.accept_connections(&shared, shedder.as_ref(), polling));
+            }
+            if polling {
+                self.supervise(&shared);
+            }
+        });
+
+        self.drain(&shared);
+    }
+
+    /// Accept connections and hand them to the default pool, or the
+    /// poller, until the server stops accepting
+    fn accept_connections(&self, shared: &Arc<Shared>, shedder: Option<&Shedder>, polling: bool) {
+        loop {
+            if polling && !self.wait_for_connection(shared) {
+                return;
+            }
+            let stream = match self.listener.accept() {
+                // A shutdown handle connects to wake the acceptor
+                Ok(_) if shared.stopping.load(Ordering::SeqCst) => {
+                    shared.draining.store(true, Ordering::SeqCst);
+                    return;
+                }
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => {
+                    log::error(&format!("Failed to accept connection: {}", e));
+                    continue;
+                }
+            };
+            // Accepted sockets inherit the listener's mode on some platforms
+            if polling {
+                if let Err(e) = stream.set_nonblocking(false) {
+                    log::warn(&format!("Failed to make connection blocking: {}", e));
+                }
+            }
+            if let Err(e) = socket::configure(&stream, &self.socket) {
+                log::warn(&format!("Failed to set socket options: {}", e));
+            }
+            if let (Some(shedder), Some(shedding)) = (shedder, &self.shedding) {
+                if self.pool.monitor().queued_jobs() >= shedding.max_queued {
+                    shared.metrics.shed();
+                    shedder.refuse(stream);
+                    continue;
+                }
+            }
+
+            let connection = Connection::accept(stream, shared);
+            if let Some(connection) = shared.park(connection) {
+                let shared = Arc::clone(shared);
+                self.pool.execute(move || serve_connection(connection, shared));
+            }
+        }
+    }
+
+    /// Watch for restarts and shutdowns while the acceptors run, telling
+    /// them to stop once a restart has handed the listener to a new
+    /// process or the server was asked to shut down
+    fn supervise(&self, shared: &Shared) {
+        while !shared.stopping.load(Ordering::SeqCst) {
+            if restart::take_request() {
+                match restart::spawn_successor(&self.listener) {
+                    Ok(child) => {
+                        log::info(&format!("Started process {} to replace this one, draining connections", child.id()));
+                        shared.draining.store(true, Ordering::SeqCst);
+                        shared.stopping.store(true, Ordering::SeqCst);
+                        return;
+                    }
+                    Err(e) => log::error(&format!("Failed to start a replacement process: {}", e)),
+                }
+            }
+            thread::sleep(RESTART_POLL_INTERVAL);
+        }
+    }
+
+    /// Wait until a connection can be accepted, returning false once the
+    /// server stops accepting
+    fn wait_for_connection(&self, shared: &Shared) -> bool {
+        loop {
+            if shared.stopping.load(Ordering::SeqCst) {
+                return false;
+            }
+            match poll::wait_readable(self.listener_fd(), RESTART_POLL_INTERVAL) {
+                Ok(true) => return true,
+                Ok(false) => {}
+                Err(e) => {
+                    log::error(&format!("Failed to wait for connections: {}", e));
+                    return true;
+                }
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn listener_fd(&self) -> std::os::unix::io::RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    #[cfg(not(unix))]
+    fn listener_fd(&self) -> crate::poll::RawFd {
+        0
+    }
+
+    /// Let the open connections finish after the server stopped
+    /// accepting, giving up after the drain timeout
+    fn drain(&self, shared: &Shared) {
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        let mut open = shared.stats.snapshot().open();
+        while open > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(100));
+            open = shared.stats.snapshot().open();
+        }
+        if open > 0 {
+            log::warn(&format!("Stopping with {} connections still open", open));
+        }
+    }
+}
+
+impl Server {
+    /// Serve a single connection over an arbitrary stream on the current
+    /// thread, returning once either side closes it
+    ///
+    /// This runs the same connection handling as `serve`, so it can be
+    /// exercised with a `testing::MockStream` instead of a socket. Routes
+    /// assigned to named pools run on the current thread as well.
+    pub fn serve_stream<S>(&self, router: Router, stream: S)
+    where
+        S: Read + Write + Send + 'static,
+    {
+        let shared = self.shared(router, HashMap::new());
+        shared.stats.accepted(None);
+        serve_connection(Connection::new(Transport::Stream(Box::new(stream)), None, &shared), shared);
+    }
+
+    fn shared(&self, mut router: Router, pools: HashMap<String, PoolHandle>) -> Arc<Shared> {
+        if !self.faults.is_empty() {
+            log::warn(&format!("Fault injection is on, with {} faults configured", self.faults.len()));
+            router.wrap_outermost(FaultInjection::new(self.faults.clone()));
+        }
+        Arc::new(Shared {
+            router: Arc::new(router),
+            metrics: Arc::clone(&self.metrics),
+            stats: Arc::clone(&self.stats),
+            metrics_path: self.metrics_path.clone(),
+            server_name: self.server_name.clone(),
+            response_headers: self.response_headers.clone(),
+            limits: self.limits.clone(),
+            slow_request_threshold: self.slow_request_threshold,
+            dumper: self.dumper.clone(),
+            recorder: self.recorder.clone(),
+            buffers: Arc::clone(&self.buffers),
+            poller: self.poller.clone(),
+            keep_alive_timeout: self.keep_alive_timeout,
+            deadline_header: self.deadline_header.clone(),
+            header_casing: self.header_casing,
+            max_requests_per_connection: self.max_requests_per_connection,
+            lingering_close: self.socket.linger != Some(Duration::ZERO),
+            proxy_protocol: self.proxy_protocol,
+            require_client_cert: self.require_client_cert,
+            trusted_proxies: self.trusted_proxies.clone(),
+            forwarded_header: self.forwarded_header,
+            shedding: self.shedding.clone(),
+            client_limiter: self.client_limiter.clone(),
+            in_maintenance: AtomicBool::new(self.maintenance.enabled),
+            maintenance: self.maintenance.clone(),
+            allowed_hosts: self.allowed_hosts.clone(),
+            rewrites: self.rewrites.clone(),
+            draining: AtomicBool::new(false),
+            timed_handlers: Arc::new(AtomicUsize::new(0)),
+            stopping: Arc::clone(&self.stopping),
+            admin: self.admin.is_some(),
+            started: Instant::now(),
+            #[cfg(feature = "otel")]
+            tracer: self.tracer.clone(),
+            default_pool: self.pool.handle(),
+            pools,
+            state: Arc::clone(&self.state),
+        })
+    }
+}
+
+/// Stops a server from another thread, see `Server::shutdown_handle`
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    stopping: Arc<AtomicBool>,
+    address: SocketAddr,
+    acceptors: usize,
+}
+
+impl ShutdownHandle {
+    /// Stop accepting connections and let the open ones finish their
+    /// current request, after which `Server::serve` returns
+    ///
+    /// Connections kept alive without a request in progress are only
+    /// closed once their keep-alive timeout runs out.
+    pub fn shutdown(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        // Wake the acceptors, which may be blocked waiting for a
+        // connection and each take one
+        for _ in 0..self.acceptors {
+            let _ = TcpStream::connect_timeout(&self.address, Duration::from_secs(1));
+        }
+    }
+}
+
+/// State shared by all connections of a running server
+struct Shared {
+    /// Shared with the threads of handlers that may run out of time
+    router: Arc<Router>,
+    metrics: Arc<Metrics>,
+    stats: Arc<Stats>,
+    metrics_path: Option<String>,
+    server_name: Option<String>,
+    response_headers: Vec<HeaderRule>,
+    limits: Limits,
+    slow_request_threshold: Option<Duration>,
+    dumper: Option<Arc<Dumper>>,
+    recorder: Option<Arc<Recorder>>,
+    buffers: Arc<BufferPool>,
+    poller: Option<Arc<Poller<Connection>>>,
+    keep_alive_timeout: Duration,
+    deadline_header: Option<String>,
+    header_casing: HeaderCasing,
+    max_requests_per_connection: Option<u64>,
+    /// False when the socket options ask for connections to be reset
+    /// on close, which lingering would defeat
+    lingering_close: bool,
+    proxy_protocol: bool,
+    require_client_cert: bool,
+    trusted_proxies: Vec<Cidr>,
+    forwarded_header: ForwardedHeader,
+    shedding: Option<Shedding>,
+    client_limiter: Option<Arc<ClientLimiter>>,
+    maintenance: Maintenance,
+    /// Set while requests not allowed in maintenance are answered with
+    /// 503
+    in_maintenance: AtomicBool,
+    allowed_hosts: Vec<String>,
+    rewrites: Vec<Rewrite>,
+    /// Set once the server stopped accepting, so connections are closed
+    /// after their current request
+    draining: AtomicBool,
+    /// Handlers running on threads of their own for a route timeout,
+    /// see `handle_within`
+    timed_handlers: Arc<AtomicUsize>,
+    /// Set once the server was asked to stop accepting
+    stopping: Arc<AtomicBool>,
+    /// Whether an admin endpoint can ask the server to stop
+    admin: bool,
+    started: Instant,
+    #[cfg(feature = "otel")]
+    tracer: Option<Arc<Tracer>>,
+    default_pool: PoolHandle,
+    pools: HashMap<String, PoolHandle>,
+    state: Arc<AppState>,
+}
+
+impl Control for Shared {
+    fn status(&self) -> Value {
+        let pool = |name: &str, pool: &PoolHandle| {
+            let monitor = pool.monitor();
+            Value::object()
+                .with("name", name)
+                .with("size", monitor.size())
+                .with("queued", monitor.queued_jobs())
+                .with("active", monitor.active_jobs())
+                .with("completed", monitor.completed_jobs())
+                .with("p50_queue_wait_ms", monitor.queue_wait_quantile(0.5).as_secs_f64() * 1000.0)
+                .with("p95_queue_wait_ms", monitor.queue_wait_quantile(0.95).as_secs_f64() * 1000.0)
+                .with("max_queue_wait_ms", monitor.max_queue_wait().as_secs_f64() * 1000.0)
+        };
+        let mut named: Vec<(&String, &PoolHandle)> = self.pools.iter().collect();
+        named.sort_by_key(|(name, _)| *name);
+        let mut pools = vec![pool("default", &self.default_pool)];
+        pools.extend(named.into_iter().map(|(name, handle)| pool(name, handle)));
+
+        let connections = self.stats.snapshot();
+        Value::object()
+            .with("pid", std::process::id())
+            .with("version", env!("CARGO_PKG_VERSION"))
+            .with("uptime_secs", self.started.elapsed().as_secs_f64())
+            .with("draining", self.draining.load(Ordering::SeqCst))
+            .with("maintenance", self.in_maintenance.load(Ordering::SeqCst))
+            .with("in_flight", self.metrics.in_flight())
+            .with(
+                "connections",
+                Value::object()
+                    .with("open", connections.open())
+                    .with("active", connections.active)
+                    .with("idle", connections.idle)
+                    .with("accepted", connections.accepted)
+                    .with("closed", connections.closed),
+            )
+            .with("pools", pools)
+            .with(
+                "buffers",
+                Value::object().with("size", self.buffers.buffer_size()).with("idle", self.buffers.idle()),
+            )
+            .with("upstreams", proxy::status())
+    }
+
+    fn pool(&self, name: &str) -> Option<PoolHandle> {
+        match name {
+            "default" => Some(self.default_pool.clone()),
+            name => self.pools.get(name).cloned(),
+        }
+    }
+
+    fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    fn maintenance(&self) -> bool {
+        self.in_maintenance.load(Ordering::SeqCst)
+    }
+
+    fn set_maintenance(&self, on: bool) {
+        self.in_maintenance.store(on, Ordering::SeqCst);
+    }
+
+    fn shutdown(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Shared {
+    /// Whether a request is refused because the server is in
+    /// maintenance; the metrics stay available
+    fn refuses_for_maintenance(&self, request: &Request) -> bool {
+        self.in_maintenance.load(Ordering::SeqCst)
+            && !self.maintenance.allows(request.path())
+            && self.metrics_path.as_deref() != Some(request.path())
+    }
+
+    /// The named pool a request should be handled on, if any
+    fn pool_for(&self, request: &Request) -> Option<&PoolHandle> {
+        self.router
+            .route_for(request)
+            .and_then(|route| route.pool())
+            .and_then(|name| self.pools.get(name))
+    }
+
+    /// Hand a connection waiting for a request to the poller, or give
+    /// it back if the server is not event-driven
+    fn park(&self, connection: Connection) -> Option<Connection> {
+        match (&self.poller, connection.raw_fd()) {
+            (Some(poller), Some(fd)) => poller.park(fd, connection).err(),
+            _ => Some(connection),
+        }
+    }
+
+    /// Add the Server header and replace responses whose framing is
+    /// invalid with a 500, before they are written
+    fn finalize(&self, response: Response) -> Response {
+        let mut response = match response.validate() {
+            Ok(()) => response,
+            Err(e) => {
+                log::error(&format!("Refusing to send invalid response: {}", e));
+                Response::text(500, "Internal Server Error")
+            }
+        };
+        if let Some(name) = &self.server_name {
+            if response.header("Server").is_none() {
+                response.set_header("Server", name);
+            }
+        }
+        for rule in &self.response_headers {
+            rule.apply(response.headers_mut());
+        }
+        if self.header_casing == HeaderCasing::Canonical {
+            response.canonicalize_headers();
+        }
+        response
+    }
+
+    /// Replace a response whose body would take the connection over its
+    /// memory budget with a 500, closing the connection
+    ///
+    /// # Arguments
+    ///
+    /// response - The response about to be sent.
+    /// held - Bytes of the responses produced before it that were held at the same time.
+    fn within_budget(&self, response: Response, held: usize) -> Response {
+        let size = buffered_size(&response);
+        if size.saturating_add(held) <= self.limits.max_buffered {
+            return response;
+        }
+        log::error(&format!(
+            "Response of {} bytes with {} more bytes held exceeds the connection budget of {} bytes",
+            size, held, self.limits.max_buffered
+        ));
+        Response::text(500, reason_phrase(500)).with_header("Connection", "close")
+    }
+
+    /// The response to a request that is refused before it reaches the
+    /// router, after which the connection is closed
+    fn reject(&self, status: u16, start: Instant) -> Response {
+        let mut response = self.finalize(Response::text(status, reason_phrase(status)));
+        response.set_header("Connection", "close");
+        self.metrics.observe(response.status(), start.elapsed());
+        response
+    }
+
+    /// Start the span of a request, continuing the trace of the caller
+    #[cfg(feature = "otel")]
+    fn request_span(&self, request: &Request, route: Option<&str>, trace: &TraceContext) -> Option<Span> {
+        let tracer = self.tracer.as_ref()?;
+        let name = match route {
+            Some(route) => format!("{} {}", request.method(), route),
+            None => String::from(request.method()),
+        };
+
+        let mut span = tracer.start_in(&name, SpanKind::Server, trace);
+        span.set_attribute("http.request.method", request.method());
+        span.set_attribute("url.path", request.path());
+        span.set_attribute("network.protocol.version", request.version().trim_start_matches("HTTP/"));
+        if let Some(route) = route {
+            span.set_attribute("http.route", route);
+        }
+        if let Some(ip) = request.client_ip() {
+            span.set_attribute("client.address", ip.to_string());
+        }
+        Some(span)
+    }
+}
+
+/// An accepted connection together with its buffers, so it can be
+/// moved between the threads of different pools
+///
+/// The buffers are checked out of the server's pool and returned when
+/// the connection is dropped.
+struct Connection {
+    reader: PooledReader<CountingReader>,
+    /// Where response heads and chunks are put together before sending
+    writes: Vec<u8>,
+    buffers: Arc<BufferPool>,
+    peer: Option<SocketAddr>,
+    /// The certificate the client presented to the balancer that
+    /// terminated TLS, from the PROXY protocol header
+    client_cert: Option<ClientCert>,
+    requests: u64,
+    bytes_out: u64,
+    idle: bool,
+    /// Whether the last response told the client the connection closes,
+    /// which requires a lingering close
+    closing: bool,
+}
+
+impl Connection {
+    /// Set up an accepted stream, recording it in the statistics
+    fn accept(stream: TcpStream, shared: &Shared) -> Connection {
+        let peer = stream.peer_addr().ok().map(socket::canonical_peer);
+        shared.stats.accepted(peer);
+
+        if let Err(e) = stream.set_read_timeout(Some(shared.keep_alive_timeout)) {
+            log::warn(&format!("Failed to set read timeout: {}", e));
+        }
+        Connection::new(Transport::Tcp(stream), peer, shared)
+    }
+
+    fn new(transport: Transport, peer: Option<SocketAddr>, shared: &Shared) -> Connection {
+        let counting = CountingReader { transport, stats: Arc::clone(&shared.stats), count: 0 };
+        Connection {
+            reader: PooledReader::new(counting, shared.buffers.take()),
+            writes: shared.buffers.take(),
+            buffers: Arc::clone(&shared.buffers),
+            peer,
+            client_cert: None,
+            requests: 0,
+            bytes_out: 0,
+            idle: false,
+            closing: false,
+        }
+    }
+
+    /// The socket of the connection, if it has one that can be polled
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        match &self.reader.get_ref().transport {
+            Transport::Tcp(stream) => Some(stream.as_raw_fd()),
+            Transport::Stream(_) => None,
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn raw_fd(&self) -> Option<crate::poll::RawFd> {
+        None
+    }
+
+    /// The socket of the connection, if it is one
+    fn raw_stream(&self) -> Option<&TcpStream> {
+        match &self.reader.get_ref().transport {
+            Transport::Tcp(stream) => Some(stream),
+            Transport::Stream(_) => None,
+        }
+    }
+
+    /// Hand the connection over to the function of a 101 response
+    fn upgrade(&mut self, upgrade: UpgradeFn) {
+        let early = self.reader.buffer().to_vec();
+        self.reader.consume(early.len());
+        let stream = match self.raw_stream().map(TcpStream::try_clone) {
+            Some(Ok(stream)) => stream,
+            Some(Err(e)) => {
+                log::warn(&format!("Failed to hand over upgraded connection: {}", e));
+                return;
+            }
+            None => return,
+        };
+        if let Err(e) = stream.set_read_timeout(None) {
+            log::warn(&format!("Failed to clear read timeout: {}", e));
+        }
+        upgrade(stream, early);
+    }
+
+    /// Record that the connection is waiting for its next request
+    fn set_idle(&mut self, stats: &Stats) {
+        if !self.idle {
+            stats.idle(self.peer);
+            self.idle = true;
+        }
+    }
+
+    /// Wait for the next request on a kept-alive connection, returning
+    /// false if the client closed it or the idle timeout expired
+    fn wait_for_request(&mut self, stats: &Stats) -> bool {
+        self.set_idle(stats);
+        match self.reader.fill_buf() {
+            Ok(buffer) if !buffer.is_empty() => {
+                stats.active(self.peer);
+                self.idle = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Write a response, returning false if the connection broke
+    fn send(&mut self, response: &mut Response, stats: &Stats) -> bool {
+        let written = match &mut self.reader.get_mut().transport {
+            Transport::Tcp(stream) => response.send_with(stream, &mut self.writes),
+            Transport::Stream(stream) => response.write_with(stream, &mut self.writes),
+        };
+        match written {
+            Ok(written) => {
+                self.bytes_out += written;
+                stats.add_bytes_out(written);
+                true
+            }
+            Err(e) => {
+                log::warn(&format!("Failed to write response: {}", e));
+                false
+            }
+        }
+    }
+
+    fn close(mut self, shared: &Shared) {
+        if self.closing && shared.lingering_close {
+            self.linger();
+        }
+        let bytes_in = self.reader.get_ref().count;
+        shared.stats.closed(self.peer, self.idle, self.requests, bytes_in, self.bytes_out);
+    }
+
+    /// Stop writing and read what the client still sends until it
+    /// closes its side too, or for a short while
+    ///
+    /// Closing a socket with unread data resets the connection, which
+    /// can discard the last response before the client has read it, as
+    /// when a request is rejected before its body was read.
+    fn linger(&mut self) {
+        match &self.reader.get_ref().transport {
+            Transport::Tcp(stream) => {
+                if stream.shutdown(Shutdown::Write).is_err() {
+                    return;
+                }
+            }
+            Transport::Stream(_) => return,
+        }
+        let deadline = Instant::now() + LINGER_TIMEOUT;
+        let mut drained = self.reader.buffer().len();
+        self.reader.consume(drained);
+        let mut discarded = [0; 8192];
+        while drained < LINGER_MAX_BYTES {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            let counting = self.reader.get_mut();
+            if let Transport::Tcp(stream) = &counting.transport {
+                if stream.set_read_timeout(Some(left)).is_err() {
+                    break;
+                }
+            }
+            match counting.read(&mut discarded) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => drained += read,
+            }
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.buffers.give(self.reader.take_buffer());
+        self.buffers.give(std::mem::take(&mut self.writes));
+    }
+}
+
+/// Serve requests on a connection until either side closes it, or in
+/// event-driven mode until it has to wait for the next request
+fn serve_connection(mut connection: Connection, shared: Arc<Shared>) {
+    if connection.requests == 0 && shared.proxy_protocol {
+        match proxy_protocol::read(&mut connection.reader) {
+            Ok(header) => {
+                if let Some(client) = header.source {
+                    connection.peer = Some(socket::canonical_peer(client));
+                }
+                connection.client_cert = header.client_cert;
+            }
+            Err(e) => {
+                log::warn(&format!("Failed to read PROXY protocol header: {}", e));
+                connection.close(&shared);
+                return;
+            }
+        }
+        if shared.require_client_cert && !connection.client_cert.as_ref().is_some_and(|cert| cert.verified) {
+            log::warn("Closing connection without a verified client certificate");
+            connection.close(&shared);
+            return;
+        }
+    }
+
+    // A request read while collecting pipelined ones that cannot join them
+    let mut pending = None;
+    loop {
+        let next = match pending.take() {
+            Some(next) => next,
+            None => {
+                if connection.requests > 0 {
+                    // A connection resumed by the poller is still marked idle and
+                    // has data waiting; pipelined requests may already be buffered
+                    if shared.poller.is_some() && !connection.idle && connection.reader.buffer().is_empty() {
+                        connection.set_idle(&shared.stats);
+                        connection = match shared.park(connection) {
+                            Some(connection) => connection,
+                            None => return,
+                        };
+                    }
+                    if !connection.wait_for_request(&shared.stats) {
+                        break;
+                    }
+                }
+                read_request(&mut connection, &shared)
+            }
+        };
+        let (request, exchange) = match next {
+            Ok(next) => next,
+            Err(mut response) => {
+                connection.requests += 1;
+                connection.closing = connection.send(&mut response, &shared.stats);
+                break;
+            }
+        };
+
+        if let Some(pool) = shared.pool_for(&request) {
+            let pool = pool.clone();
+            #[cfg(feature = "otel")]
+            let mut job = exchange.span.as_ref().map(|span| {
+                let name = shared.router.route_for(&request).and_then(|route| route.pool()).unwrap_or_default();
+                let mut job = span.child(&format!("pool {}", name), SpanKind::Internal);
+                job.set_attribute("pool.name", name);
+                job
+            });
+            #[cfg(feature = "otel")]
+            let queued = Instant::now();
+            pool.execute(move || {
+                #[cfg(feature = "otel")]
+                if let Some(job) = &mut job {
+                    job.set_attribute("pool.queue_wait_ms", queued.elapsed().as_secs_f64() * 1000.0);
+                }
+                let kept_alive = finish_request(&mut connection, request, exchange, &shared);
+                #[cfg(feature = "otel")]
+                if let Some(job) = job {
+                    job.end();
+                }
+                if kept_alive {
+                    let default_pool = shared.default_pool.clone();
+                    default_pool.execute(move || serve_connection(connection, shared));
+                } else {
+                    connection.close(&shared);
+                }
+            });
+            return;
+        }
+
+        if runs_concurrently(&request, &exchange, &shared) && !connection.reader.buffer().is_empty() {
+            let (keep_open, leftover) = serve_pipelined(&mut connection, (request, exchange), &shared);
+            if !keep_open {
+                break;
+            }
+            pending = leftover;
+            continue;
+        }
+
+        if !finish_request(&mut connection, request, exchange, &shared) {
+            break;
+        }
+    }
+
+    connection.close(&shared);
+}
+
+/// A request read from a connection, or the response to send for a
+/// malformed one
+type Incoming = Result<(Request, Exchange), Response>;
+
+/// Read the next request from a connection
+///
+/// # Errors
+///
+/// Returns the response to send before closing the connection if the
+/// request is malformed.
+fn read_request(connection: &mut Connection, shared: &Shared) -> Incoming {
+    let start = Instant::now();
+    let parsed = Request::parse_head(&mut connection.reader, &shared.limits).and_then(|(mut request, head)| {
+        // Requests the rules answer never reach a route
+        let answered = match rewrite::apply(&shared.rewrites, &mut request) {
+            Ok(()) => false,
+            Err(answer) => {
+                request.extensions_mut().insert(answer);
+                true
+            }
+        };
+        let streams =
+            !answered && head.framing.is_some() && shared.router.route_for(&request).is_some_and(Route::streams_body);
+        if streams {
+            return Ok((request, head.framing));
+        }
+        request.read_body(&mut connection.reader, &shared.limits, head)?;
+        Ok((request, None))
+    });
+    let (mut request, streamed) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            log::warn(&format!("Failed to parse request: {}", e));
+            return Err(shared.reject(e.status(), start));
+        }
+    };
+    if !shared.allowed_hosts.is_empty() && !host::is_allowed(&request, &shared.allowed_hosts) {
+        log::warn(&format!("Refusing request for host {}", request.header("Host").unwrap_or("-")));
+        return Err(shared.reject(421, start));
+    }
+    if let Some(shedding) = &shared.shedding {
+        if shedding.max_in_flight.is_some_and(|max| shared.metrics.in_flight() >= max) {
+            shared.metrics.shed();
+            let response = shared.finalize(shedding.response());
+            shared.metrics.observe(response.status(), start.elapsed());
+            return Err(response);
+        }
+    }
+    if let Some(addr) = connection.peer {
+        request.set_peer_addr(addr);
+        if !shared.trusted_proxies.is_empty() {
+            let client = forwarded::client_ip(addr.ip(), &request, &shared.trusted_proxies, shared.forwarded_header);
+            request.set_client_ip(client);
+        }
+    }
+    if let Some(cert) = &connection.client_cert {
+        request.extensions_mut().insert(cert.clone());
+    }
+    let client_slot = match (&shared.client_limiter, request.client_ip()) {
+        (Some(limiter), Some(ip)) => match ClientLimiter::acquire(limiter, ip) {
+            Some(slot) => slot,
+            None => {
+                let response = shared.finalize(limiter.response());
+                shared.metrics.observe(response.status(), start.elapsed());
+                return Err(response);
+            }
+        },
+        _ => None,
+    };
+
+    let mut exchange = Exchange::new(&request, shared, start);
+    exchange._client_slot = client_slot;
+    exchange.dump = shared.dumper.as_ref().and_then(|dumper| dumper.capture(&request, streamed.is_some()));
+    exchange.recorded = shared.recorder.as_ref().map(|recorder| recorder.capture(&request, start, streamed.is_some()));
+    exchange.streamed = streamed;
+    Ok((request, exchange))
+}
+
+/// Whether a request may be handled while other requests pipelined on
+/// the same connection are, which only holds for safe methods on the
+/// default pool whose body has already been read, and not when asking
+/// to switch protocols, as what follows may not be HTTP
+fn runs_concurrently(request: &Request, exchange: &Exchange, shared: &Shared) -> bool {
+    matches!(request.method(), "GET" | "HEAD" | "OPTIONS")
+        && exchange.streamed.is_none()
+        && shared.pool_for(request).is_none()
+        && request.header("Upgrade").is_none()
+}
+
+/// Handle a run of pipelined requests concurrently on the default pool
+/// and write their responses in the order the requests arrived
+///
+/// Requests are collected while more of them are already buffered.
+/// Each is offered to the pool, and the connection's own thread runs
+/// those no worker has taken yet by the time their turn comes, so the
+/// batch finishes even when every worker is busy with batches of its
+/// own.
+/// Returns whether the connection stays open, together with a request
+/// that was read but cannot run concurrently, or the response to a
+/// malformed one, to be dealt with next.
+fn serve_pipelined(
+    connection: &mut Connection,
+    first: (Request, Exchange),
+    shared: &Arc<Shared>,
+) -> (bool, Option<Incoming>) {
+    let mut batch = vec![first];
+    let mut leftover = None;
+    while batch.len() < MAX_PIPELINED
+        && batch.last().is_some_and(|(_, exchange)| exchange.keep_alive)
+        && shared.max_requests_per_connection.is_none_or(|max| connection.requests + (batch.len() as u64) < max)
+        && !connection.reader.buffer().is_empty()
+    {
+        match read_request(connection, shared) {
+            Ok((request, exchange)) if runs_concurrently(&request, &exchange, shared) => batch.push((request, exchange)),
+            other => {
+                leftover = Some(other);
+                break;
+            }
+        }
+    }
+
+    // Whoever takes a request out of its slot first answers it
+    let answer = |(request, mut exchange): (Request, Exchange), shared: &Shared| {
+        let (response, handling) = respond(request, &exchange, shared);
+        exchange.handling = handling;
+        (response, exchange)
+    };
+    let mut slots = Vec::with_capacity(batch.len());
+    for (i, pipelined) in batch.into_iter().enumerate() {
+        let slot = Arc::new(Mutex::new(Some(pipelined)));
+        let (sender, receiver) = mpsc::sync_channel(1);
+        // The first is answered right away by this thread
+        if i > 0 {
+            let (slot, shared) = (Arc::clone(&slot), Arc::clone(shared));
+            shared.default_pool.clone().execute(move || {
+                let taken = slot.lock().unwrap().take();
+                if let Some(pipelined) = taken {
+                    let _ = sender.send(answer(pipelined, &shared));
+                }
+            });
+        }
+        slots.push((slot, receiver));
+    }
+    let total = slots.len();
+    let mut answered = Vec::with_capacity(total);
+    for (slot, receiver) in slots {
+        let taken = slot.lock().unwrap().take();
+        match taken {
+            Some(pipelined) => answered.push(answer(pipelined, shared)),
+            None => match receiver.recv() {
+                Ok(response) => answered.push(response),
+                // The worker panicked outside the handler, so the
+                // responses after it cannot be sent in order
+                Err(_) => break,
+            },
+        }
+    }
+
+    // The whole batch was held in memory at once, so the responses
+    // count towards the budget together, in order
+    let complete = answered.len() == total;
+    let mut held = 0;
+    let mut keep_open = true;
+    for (response, exchange) in answered {
+        if keep_open {
+            let size = buffered_size(&response);
+            let response = shared.within_budget(response, held);
+            held += size;
+            keep_open = write_response(connection, response, exchange, shared);
+        } else {
+            // The connection closed before this response could be sent
+            shared.metrics.request_finished(response.status(), exchange.start.elapsed());
+        }
+    }
+    let keep_open = keep_open && complete;
+    (keep_open, leftover.filter(|_| keep_open))
+}
+
+/// Produce and write the response to a request, returning whether the
+/// connection should be kept open afterwards
+fn finish_request(connection: &mut Connection, mut request: Request, mut exchange: Exchange, shared: &Shared) -> bool {
+    let early_hints = match connection.raw_fd() {
+        Some(fd) if request.version() != "HTTP/1.0" => EarlyHints::new(fd),
+        _ => EarlyHints::default(),
+    };
+    request.set_early_hints(early_hints.clone());
+    let (response, handling) = match exchange.streamed.take() {
+        Some(framing) => respond_streaming(connection, request, &exchange, framing, shared),
+        None => respond(request, &exchange, shared),
+    };
+    // A handler that ran out of time may still be running, and must not
+    // write to the connection from now on
+    early_hints.close();
+    exchange.handling = handling;
+    let response = shared.within_budget(response, 0);
+    write_response(connection, response, exchange, shared)
+}
+
+/// Run the handler for a request whose body is streamed to it, reading
+/// the body from the connection in the meantime
+///
+/// The connection is closed after the response if the body could not
+/// be read in full, as the next request cannot be found then.
+fn respond_streaming(
+    connection: &mut Connection,
+    mut request: Request,
+    exchange: &Exchange,
+    framing: Framing,
+    shared: &Shared,
+) -> (Response, Handling) {
+    let (sender, body) = Body::channel(STREAMED_CHUNKS);
+    request.set_body_stream(body);
+    // A scoped thread runs the handler, as this one has to keep reading
+    // the connection for it
+    thread::scope(|scope| {
+        let handler = scope.spawn(move || respond(request, exchange, shared));
+        let limits = &shared.limits;
+        // Chunks the handler no longer takes are discarded
+        let read = request::read_body(&mut connection.reader, limits, framing, limits.max_streamed_body, |chunk| {
+            let _ = sender.send(Ok(chunk));
+        });
+        let complete = match read {
+            Ok(_) => true,
+            Err(e) => {
+                log::warn(&format!("Failed to read streamed request body: {}", e));
+                let _ = sender.send(Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())));
+                false
+            }
+        };
+        drop(sender);
+        let (mut response, handling) = handler
+            .join()
+            .unwrap_or_else(|_| (Response::text(500, "Internal Server Error"), Handling::default()));
+        if !complete {
+            response.set_header("Connection", "close");
+        }
+        (response, handling)
+    })
+}
+
+/// A request being handled, with what is needed to account for it and
+/// log it once it has been answered
+struct Exchange {
+    request_id: String,
+    method: String,
+    target: String,
+    version: String,
+    route: Option<String>,
+    client_ip: Option<IpAddr>,
+    /// The subject of the client certificate
+    client_cert: Option<String>,
+    keep_alive: bool,
+    start: Instant,
+    /// How long reading the request took
+    read: Duration,
+    /// How long the request waited for and spent in its handler
+    handling: Handling,
+    trace: TraceContext,
+    /// How the body is framed if it is streamed to the handler and has
+    /// not been read yet
+    streamed: Option<Framing>,
+    /// What is dumped of the request, if it is
+    dump: Option<DumpedRequest>,
+    /// What is recorded of the request, if traffic is
+    recorded: Option<Value>,
+    /// Counts the request against the limit of its client until it has
+    /// been answered
+    _client_slot: Option<ClientSlot>,
+    #[cfg(feature = "otel")]
+    span: Option<Span>,
+}
+
+impl Exchange {
+    fn new(request: &Request, shared: &Shared, start: Instant) -> Exchange {
+        let route = shared.router.route_for(request).map(|route| String::from(route.pattern()));
+        let trace = TraceContext::from_request(request);
+        Exchange {
+            request_id: log::next_request_id(),
+            method: String::from(request.method()),
+            target: String::from(request.target()),
+            version: String::from(request.version()),
+            #[cfg(feature = "otel")]
+            span: shared.request_span(request, route.as_deref(), &trace),
+            route,
+            client_ip: request.client_ip(),
+            client_cert: request.extensions().get::<ClientCert>().and_then(|cert| cert.subject.clone()),
+            keep_alive: wants_keep_alive(request),
+            start,
+            read: start.elapsed(),
+            handling: Handling::default(),
+            trace,
+            streamed: None,
+            dump: None,
+            recorded: None,
+            _client_slot: None,
+        }
+    }
+}
+
+/// Run the handler for a request
+fn respond(mut request: Request, exchange: &Exchange, shared: &Shared) -> (Response, Handling) {
+    let started = Instant::now();
+    let queued = started.saturating_duration_since(exchange.start + exchange.read);
+    shared.metrics.request_started();
+    request.set_state(Arc::clone(&shared.state));
+    let timeout = shared.router.route_for(&request).and_then(Route::timeout);
+    if let Some(timeout) = timeout {
+        request.set_deadline(started + timeout);
+    }
+    // Clients count their timeout from sending the request. The
+    // keep-alive timeout bounds the wait for the next request rather
+    // than the handling of this one, so it plays no part.
+    let waiting = shared.deadline_header.as_ref().and_then(|name| request.header(name)).and_then(parse_timeout);
+    if let Some(deadline) = waiting.and_then(|waiting| exchange.start.checked_add(waiting)) {
+        request.set_deadline(deadline);
+    }
+    let if_none_match = request.header("If-None-Match").map(String::from);
+
+    let answer = request.extensions_mut().remove::<Answer>();
+    let handle = || match (answer, &shared.metrics_path) {
+        _ if shared.refuses_for_maintenance(&request) => shared.maintenance.response(),
+        (Some(answer), _) => answer.respond(&request),
+        (None, Some(path)) if request.path() == path => {
+            let connections = shared.stats.snapshot();
+            Response::new(200)
+                .with_header("Content-Type", "text/plain; version=0.0.4")
+                .with_body(shared.metrics.render(&shared.default_pool.monitor(), &connections))
+        }
+        _ => match timeout {
+            Some(timeout) => handle_within(request, timeout, exchange, shared),
+            None => shared.router.handle(request),
+        },
+    };
+    #[cfg(feature = "otel")]
+    let handle = || match &exchange.span {
+        Some(span) => span.enter(handle),
+        None => handle(),
+    };
+    // A panicking handler must not take the worker down with it, so it
+    // keeps serving other connections and the connection is accounted for
+    let answered = panic::catch_unwind(AssertUnwindSafe(|| {
+        log::with_request_id(&exchange.request_id, || trace_context::with_current(&exchange.trace, handle))
+    }));
+    let response = answered.unwrap_or_else(|_| {
+        log::error(&format!("Handler for {} {} panicked", exchange.method, exchange.target));
+        Response::text(500, reason_phrase(500)).with_header("Connection", "close")
+    });
+    let response = etag::not_modified(&exchange.method, if_none_match.as_deref(), response);
+    (response, Handling { queued, handler: started.elapsed() })
+}
+
+/// Parse a timeout sent by a client, either in seconds such as `1.5` or
+/// as gRPC sends it, up to eight digits and a unit such as `1500m`, cut
+/// to `MAX_CLIENT_TIMEOUT`
+fn parse_timeout(value: &str) -> Option<Duration> {
+    parse_duration(value).map(|timeout| timeout.min(MAX_CLIENT_TIMEOUT))
+}
+
+/// Parse a timeout in either of the forms `parse_timeout` takes
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let unit = value.chars().last()?;
+    let digits = &value[..value.len() - unit.len_utf8()];
+    if !unit.is_ascii_digit() && !digits.is_empty() && digits.len() <= 8 && digits.bytes().all(|b| b.is_ascii_digit()) {
+        let amount: u64 = digits.parse().ok()?;
+        return match unit {
+            'H' => Some(Duration::from_secs(amount * 3600)),
+            'M' => Some(Duration::from_secs(amount * 60)),
+            'S' => Some(Duration::from_secs(amount)),
+            'm' => Some(Duration::from_millis(amount)),
+            'u' => Some(Duration::from_micros(amount)),
+            'n' => Some(Duration::from_nanos(amount)),
+            _ => None,
+        };
+    }
+    value.parse::<f64>().ok().and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+}
+
+/// Run the router on a thread of its own, answering 504 if it does not
+/// respond within the timeout of the request's route
+///
+/// A handler that runs out of time keeps its thread until it returns,
+/// as it cannot be stopped; its cancellation token tells it to give up.
+/// So that handlers which never return cannot pile up threads, requests
+/// are answered with 503 while `MAX_TIMED_HANDLERS` are running.
+fn handle_within(request: Request, timeout: Duration, exchange: &Exchange, shared: &Shared) -> Response {
+    let running = TimedHandler::start(&shared.timed_handlers);
+    let running = match running {
+        Some(running) => running,
+        None => {
+            log::warn(&format!(
+                "Refused {} {}, as {} handlers with a timeout are still running",
+                exchange.method, exchange.target, MAX_TIMED_HANDLERS
+            ));
+            return Response::text(503, reason_phrase(503)).with_header("Retry-After", "1");
+        }
+    };
+    let cancellation = request.cancellation().clone();
+    let router = Arc::clone(&shared.router);
+    let request_id = exchange.request_id.clone();
+    let trace = exchange.trace.clone();
+    let (sender, receiver) = mpsc::sync_channel(1);
+    let spawned = thread::Builder::new().spawn(move || {
+        let _running = running;
+        let response = log::with_request_id(&request_id, || trace_context::with_current(&trace, || router.handle(request)));
+        let _ = sender.send(response);
+    });
+    if let Err(e) = spawned {
+        log::error(&format!("Failed to start a thread for a handler with a timeout: {}", e));
+        return Response::text(500, reason_phrase(500));
+    }
+    match receiver.recv_timeout(timeout) {
+        Ok(response) => response,
+        Err(RecvTimeoutError::Timeout) => {
+            cancellation.cancel();
+            log::warn(&format!(
+                "Handler for {} {} did not respond within {}ms",
+                exchange.method,
+                exchange.route.as_deref().unwrap_or(&exchange.target),
+                timeout.as_millis()
+            ));
+            Response::text(504, reason_phrase(504))
+        }
+        // The handler panicked
+        Err(RecvTimeoutError::Disconnected) => Response::text(500, reason_phrase(500)),
+    }
+}
+
+/// Counts a handler of a route with a timeout as running until dropped,
+/// even if it panics
+struct TimedHandler {
+    running: Arc<AtomicUsize>,
+}
+
+impl TimedHandler {
+    /// Count another handler in, unless `MAX_TIMED_HANDLERS` are running
+    fn start(running: &Arc<AtomicUsize>) -> Option<TimedHandler> {
+        running
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| (count < MAX_TIMED_HANDLERS).then_some(count + 1))
+            .ok()?;
+        Some(TimedHandler { running: Arc::clone(running) })
+    }
+}
+
+impl Drop for TimedHandler {
+    fn drop(&mut self) {
+        self.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Where the time answering a request went between reading it and
+/// writing the response
+#[derive(Clone, Copy, Default)]
+struct Handling {
+    /// Waiting for a worker to run the handler on
+    queued: Duration,
+    /// Running the handler
+    handler: Duration,
+}
+
+/// Write the response to a request and log it, returning whether the
+/// connection should be kept open afterwards
+fn write_response(connection: &mut Connection, response: Response, exchange: Exchange, shared: &Shared) -> bool {
+    if response.is_aborted() {
+        // Not counted by status, as there is none
+        shared.metrics.request_finished(0, exchange.start.elapsed());
+        connection.closing = false;
+        return false;
+    }
+    let mut response = shared.finalize(response);
+    let upgrade = response.take_upgrade().filter(|_| response.status() == 101);
+    if upgrade.is_some() && connection.raw_stream().is_none() {
+        log::error("Cannot switch protocols on a connection that is not a socket");
+        response = shared.finalize(Response::text(500, reason_phrase(500)));
+    }
+    shared.metrics.request_finished(response.status(), exchange.start.elapsed());
+    shared.metrics.observe_route(&exchange.method, exchange.route.as_deref(), response.status(), exchange.start.elapsed());
+    if exchange.version == "HTTP/1.0" {
+        response.forbid_chunking();
+    }
+
+    // A body of unknown length to an HTTP/1.0 client ends with the
+    // connection
+    let keep_alive = exchange.keep_alive
+        && upgrade.is_none()
+        && !response.is_close_delimited()
+        && !shared.draining.load(Ordering::SeqCst)
+        && shared.max_requests_per_connection.is_none_or(|max| connection.requests + 1 < max)
+        && !response
+            .header("Connection")
+            .is_some_and(|v| v.eq_ignore_ascii_case("close"));
+    if upgrade.is_some() && response.status() == 101 {
+        response.set_header("Connection", "Upgrade");
+    } else {
+        response.set_header("Connection", if keep_alive { "keep-alive" } else { "close" });
+    }
+    connection.requests += 1;
+    if exchange.method == "HEAD" {
+        response.omit_body();
+    }
+
+    // The head is taken before sending, which may consume the body
+    let dumped_head = exchange.dump.as_ref().map(|_| response.head_bytes());
+    let recorded_response = shared.recorder.as_ref().and_then(|recorder| recorder.response(&response));
+    let bytes_before = connection.bytes_out;
+    let writing = Instant::now();
+    let sent = connection.send(&mut response, &shared.stats);
+    let write = writing.elapsed();
+    let duration = exchange.start.elapsed();
+    log::access(&Access {
+        request_id: &exchange.request_id,
+        method: &exchange.method,
+        target: &exchange.target,
+        version: &exchange.version,
+        route: exchange.route.as_deref(),
+        status: response.status(),
+        bytes: connection.bytes_out - bytes_before,
+        duration,
+        client_ip: exchange.client_ip,
+        client_cert: exchange.client_cert.as_deref(),
+        trace: Some(&exchange.trace.span),
+    });
+    if shared.slow_request_threshold.is_some_and(|threshold| duration >= threshold) {
+        log::slow_request(&SlowRequest {
+            request_id: &exchange.request_id,
+            method: &exchange.method,
+            target: &exchange.target,
+            route: exchange.route.as_deref(),
+            status: response.status(),
+            duration,
+            read: exchange.read,
+            queued: exchange.handling.queued,
+            handler: exchange.handling.handler,
+            write,
+            trace: Some(&exchange.trace.span),
+        });
+    }
+    if let (Some(dumper), Some(dump), Some(head)) = (&shared.dumper, &exchange.dump, &dumped_head) {
+        let timing = DumpTiming {
+            total: duration,
+            read: exchange.read,
+            queued: exchange.handling.queued,
+            handler: exchange.handling.handler,
+            write,
+        };
+        dumper.write(&exchange.request_id, exchange.client_ip, dump, head, &timing);
+    }
+    if let (Some(recorder), Some(recorded)) = (&shared.recorder, exchange.recorded) {
+        recorder.write(recorded, recorded_response);
+    }
+
+    #[cfg(feature = "otel")]
+    if let Some(mut span) = exchange.span {
+        span.set_attribute("http.response.status_code", i64::from(response.status()));
+        if response.status() >= 500 {
+            span.set_error();
+        }
+        span.end();
+    }
+
+    connection.closing = sent && !keep_alive;
+    if let Some(upgrade) = upgrade.filter(|_| sent && response.status() == 101) {
+        // The other protocol ends the connection itself
+        connection.closing = false;
+        connection.upgrade(upgrade);
+    }
+    sent && keep_alive
+}
+
+/// Bytes of a response body held in memory
+fn buffered_size(response: &Response) -> usize {
+    response.body().as_bytes().map_or(0, <[u8]>::len)
+}
+
+/// Whether the client asked for the connection to stay open
+fn wants_keep_alive(request: &Request) -> bool {
+    let connection = request.header("Connection").map(|v| v.to_ascii_lowercase());
+    let has = |token: &str| {
+        connection
+            .as_deref()
+            .is_some_and(|v| v.split(',').any(|t| t.trim() == token))
+    };
+    if request.version() == "HTTP/1.0" {
+        has("keep-alive")
+    } else {
+        !has("close")
+    }
+}
+
+/// The stream a connection reads from and writes to
+enum Transport {
+    /// A socket, to which files can be sent with sendfile
+    Tcp(TcpStream),
+    /// Any other stream, such as a mock stream in tests
+    Stream(Box<dyn ReadWrite>),
+}
+
+trait ReadWrite: Read + Write + Send {}
+
+impl<T: Read + Write + Send> ReadWrite for T {}
+
+/// Counts the bytes read from a connection, both for the connection
+/// itself and in the server-wide statistics
+struct CountingReader {
+    transport: Transport,
+    stats: Arc<Stats>,
+    count: u64,
+}
+
+impl Read for CountingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = match &mut self.transport {
+            Transport::Tcp(stream) => stream.read(buf)?,
+            Transport::Stream(stream) => stream.read(buf)?,
+        };
+        self.count += read as u64;
+        self.stats.add_bytes_in(read as u64);
+        Ok(read)
+    }
+}
@@ -0,0 +1,84 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::future::{self, Future, Ready};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use crate::log;
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::Router;
+use crate::task::block_on;
+
+/// An asynchronous function from a request to a response
+///
+/// The trait has the same shape as `tower::Service`, so services and
+/// middleware written against tower can be bridged to it with a
+/// forwarding implementation, and the other way around.
+pub trait Service<R> {
+    type Response;
+    type Error;
+    type Future: Future<Output = Result<Self::Response, Self::Error>>;
+
+    /// Wait until the service is able to accept a request
+    fn poll_ready(&mut self, context: &mut Context<'_>) -> Poll<Result<(), Self::Error>>;
+
+    /// Process a request, after `poll_ready` returned `Ready(Ok(()))`
+    fn call(&mut self, request: R) -> Self::Future;
+}
+
+/// Mount a service as a handler for a router
+///
+/// The service is cloned for every request, as tower services usually
+/// are, and driven to completion on the worker thread. Errors of the
+/// service are logged and answered with 500.
+///
+/// # Arguments
+///
+/// service - The service producing the responses.
+pub fn service_handler<S>(service: S) -> impl Fn(Request) -> Response + Send + Sync + 'static
+where
+    S: Service<Request, Response = Response> + Clone + Send + Sync + 'static,
+    S::Error: fmt::Display,
+{
+    move |request| {
+        let mut service = service.clone();
+        let result = block_on(async move {
+            future::poll_fn(|context| service.poll_ready(context)).await?;
+            service.call(request).await
+        });
+        result.unwrap_or_else(|e| {
+            log::error(&format!("Service failed: {}", e));
+            Response::text(500, "Internal Server Error")
+        })
+    }
+}
+
+/// A router exposed as a service
+///
+/// Cloning the service is cheap, all clones dispatch with the same
+/// router.
+#[derive(Clone)]
+pub struct RouterService {
+    router: Arc<Router>,
+}
+
+impl RouterService {
+    pub fn new(router: Router) -> RouterService {
+        RouterService { router: Arc::new(router) }
+    }
+}
+
+impl Service<Request> for RouterService {
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Ready<Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, _context: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        future::ready(Ok(self.router.handle(request)))
+    }
+}
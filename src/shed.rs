@@ -0,0 +1,141 @@
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread;
+use std::time::Duration;
+
+use crate::log;
+use crate::request::{Limits, Request};
+use crate::response::{reason_phrase, Response};
+use crate::socket;
+
+/// How long a refused client may take to send its request before it is
+/// dropped without an answer
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+/// Refused connections waiting for their answer, beyond which they are
+/// dropped without one
+const MAX_WAITING: usize = 256;
+/// Most bytes read and discarded from a connection answered before its
+/// request was read
+const MAX_DISCARDED: u64 = 64 * 1024;
+
+/// When the server answers 503 right away instead of letting requests
+/// wait for a worker
+#[derive(Clone)]
+pub struct Shedding {
+    /// Connections waiting for a worker of the default pool beyond which
+    /// new connections are refused
+    pub max_queued: usize,
+    /// Requests handled at once beyond which further requests are
+    /// refused, or None for no limit
+    pub max_in_flight: Option<usize>,
+    /// How long clients are asked to wait before trying again, sent as
+    /// a Retry-After header
+    pub retry_after: Duration,
+    /// How connections beyond `max_queued` are turned away
+    pub overflow: Overflow,
+}
+
+impl Default for Shedding {
+    fn default() -> Shedding {
+        Shedding {
+            max_queued: 64,
+            max_in_flight: None,
+            retry_after: Duration::from_secs(1),
+            overflow: Overflow::Answer,
+        }
+    }
+}
+
+/// How a connection refused because too many are waiting for a worker
+/// is turned away
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Read the request and answer it with 503, so the client can tell
+    /// the server is overloaded and retry later
+    Answer,
+    /// Answer with 503 as soon as the connection is accepted, without
+    /// waiting for the request, which clients that send it slowly get
+    /// sooner
+    Immediate,
+    /// Reset the connection right away, which costs the server least
+    /// but leaves the client guessing
+    Reset,
+}
+
+impl Shedding {
+    /// The response sent to refused requests
+    pub(crate) fn response(&self) -> Response {
+        // Retry-After counts whole seconds, and zero would invite an
+        // immediate retry
+        let seconds = self.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        Response::text(503, reason_phrase(503))
+            .with_header("Retry-After", &seconds.to_string())
+            .with_header("Connection", "close")
+    }
+}
+
+/// Answers refused connections on a thread of its own, so the workers
+/// they were refused for are not needed to do it
+pub(crate) struct Shedder {
+    /// Refused connections, and whether they have been answered already
+    sender: SyncSender<(TcpStream, bool)>,
+    overflow: Overflow,
+    /// The answer written right away, serialized once
+    answer: Vec<u8>,
+}
+
+impl Shedder {
+    pub(crate) fn spawn(shedding: &Shedding, limits: &Limits) -> Shedder {
+        let (sender, receiver) = mpsc::sync_channel::<(TcpStream, bool)>(MAX_WAITING);
+        let mut response = shedding.response();
+        let mut answer = Vec::new();
+        if let Err(e) = shedding.response().write_to(&mut answer) {
+            log::error(&format!("Failed to serialize the overload response: {}", e));
+        }
+        let limits = limits.clone();
+        thread::spawn(move || {
+            for (stream, answered) in receiver {
+                // The request is read first, as closing a connection with
+                // unread input would reset it and lose the answer
+                if stream.set_read_timeout(Some(REQUEST_TIMEOUT)).is_err() {
+                    continue;
+                }
+                if answered {
+                    let _ = io::copy(&mut (&stream).take(MAX_DISCARDED), &mut io::sink());
+                    continue;
+                }
+                if Request::parse_with_limits(&mut BufReader::new(&stream), &limits).is_err() {
+                    continue;
+                }
+                let mut stream = &stream;
+                if let Err(e) = response.write_to(&mut stream) {
+                    log::warn(&format!("Failed to refuse connection: {}", e));
+                }
+            }
+        });
+        Shedder { sender, overflow: shedding.overflow, answer }
+    }
+
+    /// Turn a connection away as configured, or drop it if too many are
+    /// waiting for their answer already
+    pub(crate) fn refuse(&self, stream: TcpStream) {
+        // Dropping the stream closes the connection
+        match self.overflow {
+            Overflow::Answer => {
+                let _ = self.sender.try_send((stream, false));
+            }
+            Overflow::Immediate => {
+                // A fresh connection has room for the answer in its send
+                // buffer, so this does not block
+                if (&stream).write_all(&self.answer).is_err() || stream.shutdown(Shutdown::Write).is_err() {
+                    return;
+                }
+                // What the client sends is still read before closing, for
+                // the same reason as when answering its request
+                let _ = self.sender.try_send((stream, true));
+            }
+            Overflow::Reset => socket::reset(stream),
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Tuning of the listening socket and accepted connections
+///
+/// Options left at None keep the operating system defaults.
+#[derive(Clone)]
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm, so small responses are sent at once
+    /// instead of waiting to be coalesced with more data
+    pub nodelay: bool,
+    /// Probe idle connections to detect peers that went away
+    pub keepalive: Option<Keepalive>,
+    /// Length of the queue of connections waiting to be accepted; the
+    /// kernel may cap it, e.g. at net.core.somaxconn on Linux
+    pub backlog: u32,
+    /// Size of the kernel send buffer of each connection in bytes
+    pub send_buffer_size: Option<usize>,
+    /// Size of the kernel receive buffer of each connection in bytes
+    pub recv_buffer_size: Option<usize>,
+    /// How long closing a connection waits for unsent data to be
+    /// delivered; zero discards it and resets the connection instead
+    pub linger: Option<Duration>,
+    /// Whether a listener bound to an IPv6 address such as `[::]:7878`
+    /// accepts only IPv6 connections, or IPv4 ones as well; None keeps
+    /// the system default, which is dual-stack on most systems
+    pub ipv6_only: Option<bool>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> SocketOptions {
+        SocketOptions {
+            nodelay: true,
+            keepalive: None,
+            backlog: 128,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            linger: None,
+            ipv6_only: None,
+        }
+    }
+}
+
+/// When TCP keep-alive probes are sent
+#[derive(Clone)]
+pub struct Keepalive {
+    /// How long a connection is idle before the first probe
+    pub idle: Duration,
+    /// Time between unanswered probes
+    pub interval: Duration,
+    /// Unanswered probes after which the connection is dropped
+    pub retries: u32,
+}
+
+impl Default for Keepalive {
+    fn default() -> Keepalive {
+        Keepalive {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+            retries: 6,
+        }
+    }
+}
+
+/// Bind a listener to the first address the given one resolves to that
+/// can be bound
+///
+/// # Errors
+///
+/// Returns an error if the address cannot be resolved, or the error of
+/// the last address tried if none can be bound.
+pub(crate) fn bind(address: &str, options: &SocketOptions) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    for addr in address.to_socket_addrs()? {
+        let result = match (addr, options.ipv6_only) {
+            // The option has to be set before binding, which the
+            // standard library offers no way to do
+            (SocketAddr::V6(addr), Some(only)) => sys::bind_v6(&addr, only, options.backlog),
+            (addr, _) => TcpListener::bind(addr),
+        };
+        match result {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{} did not resolve to any address.", address))
+    }))
+}
+
+/// The address a peer connected from, with IPv4 addresses that reached a
+/// dual-stack listener as IPv4-mapped IPv6 addresses turned back into
+/// plain IPv4 ones
+pub(crate) fn canonical_peer(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Apply the options that belong to the listening socket
+///
+/// Buffer sizes are set here as well as on every connection, since the
+/// receive buffer of the listener decides the window offered while the
+/// connection is established.
+///
+/// # Errors
+///
+/// Returns an error if the operating system rejects an option.
+pub(crate) fn configure_listener(listener: &TcpListener, options: &SocketOptions) -> io::Result<()> {
+    sys::set_backlog(listener, options.backlog)?;
+    sys::set_buffer_sizes(listener, options.send_buffer_size, options.recv_buffer_size)
+}
+
+/// Apply the options to an accepted connection
+///
+/// # Errors
+///
+/// Returns an error if the operating system rejects an option.
+pub(crate) fn configure(stream: &TcpStream, options: &SocketOptions) -> io::Result<()> {
+    stream.set_nodelay(options.nodelay)?;
+    if let Some(keepalive) = &options.keepalive {
+        sys::set_keepalive(stream, keepalive)?;
+    }
+    sys::set_buffer_sizes(stream, options.send_buffer_size, options.recv_buffer_size)?;
+    if let Some(linger) = options.linger {
+        sys::set_linger(stream, linger)?;
+    }
+    Ok(())
+}
+
+/// Close a connection by resetting it rather than with the usual
+/// handshake, discarding anything not sent yet
+pub(crate) fn reset(stream: TcpStream) {
+    // Closing without lingering sends a reset; where that cannot be set
+    // the connection is closed as usual
+    let _ = sys::set_linger(&stream, Duration::ZERO);
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::io;
+    use std::mem;
+    use std::net::{SocketAddrV6, TcpListener};
+    use std::os::raw::{c_int, c_void};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::time::Duration;
+
+    use super::Keepalive;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    mod consts {
+        use std::os::raw::c_int;
+
+        pub const AF_INET6: c_int = 10;
+        pub const IPV6_V6ONLY: Option<c_int> = Some(26);
+        pub const SOL_SOCKET: c_int = 1;
+        pub const SO_REUSEADDR: c_int = 2;
+        pub const SO_SNDBUF: c_int = 7;
+        pub const SO_RCVBUF: c_int = 8;
+        pub const SO_KEEPALIVE: c_int = 9;
+        pub const SO_LINGER: c_int = 13;
+        pub const TCP_KEEPIDLE: Option<c_int> = Some(4);
+        pub const TCP_KEEPINTVL: Option<c_int> = Some(5);
+        pub const TCP_KEEPCNT: Option<c_int> = Some(6);
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    mod consts {
+        use std::os::raw::c_int;
+
+        pub const AF_INET6: c_int = 30;
+        pub const IPV6_V6ONLY: Option<c_int> = Some(27);
+        pub const SOL_SOCKET: c_int = 0xffff;
+        pub const SO_REUSEADDR: c_int = 0x4;
+        pub const SO_SNDBUF: c_int = 0x1001;
+        pub const SO_RCVBUF: c_int = 0x1002;
+        pub const SO_KEEPALIVE: c_int = 0x8;
+        // SO_LINGER counts clock ticks here, SO_LINGER_SEC seconds
+        pub const SO_LINGER: c_int = 0x1080;
+        pub const TCP_KEEPIDLE: Option<c_int> = Some(0x10);
+        pub const TCP_KEEPINTVL: Option<c_int> = Some(0x101);
+        pub const TCP_KEEPCNT: Option<c_int> = Some(0x102);
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios")))]
+    mod consts {
+        use std::os::raw::c_int;
+
+        pub const AF_INET6: c_int = 28;
+        pub const IPV6_V6ONLY: Option<c_int> = Some(27);
+        pub const SOL_SOCKET: c_int = 0xffff;
+        pub const SO_REUSEADDR: c_int = 0x4;
+        pub const SO_SNDBUF: c_int = 0x1001;
+        pub const SO_RCVBUF: c_int = 0x1002;
+        pub const SO_KEEPALIVE: c_int = 0x8;
+        pub const SO_LINGER: c_int = 0x80;
+        pub const TCP_KEEPIDLE: Option<c_int> = None;
+        pub const TCP_KEEPINTVL: Option<c_int> = None;
+        pub const TCP_KEEPCNT: Option<c_int> = None;
+    }
+
+    use consts::*;
+
+    const IPPROTO_TCP: c_int = 6;
+    const IPPROTO_IPV6: c_int = 41;
+    const SOCK_STREAM: c_int = 1;
+    const F_SETFD: c_int = 2;
+    const FD_CLOEXEC: c_int = 1;
+
+    /// sockaddr_in6, which has a leading length byte on the BSDs
+    #[repr(C)]
+    struct SockaddrIn6 {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        family: u16,
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        length: u8,
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        family: u8,
+        port: u16,
+        flowinfo: u32,
+        addr: [u8; 16],
+        scope_id: u32,
+    }
+
+    #[repr(C)]
+    struct Linger {
+        onoff: c_int,
+        linger: c_int,
+    }
+
+    extern "C" {
+        fn setsockopt(fd: c_int, level: c_int, name: c_int, value: *const c_void, length: u32) -> c_int;
+        fn listen(fd: c_int, backlog: c_int) -> c_int;
+        fn socket(domain: c_int, kind: c_int, protocol: c_int) -> c_int;
+        fn bind(fd: c_int, addr: *const SockaddrIn6, length: u32) -> c_int;
+        fn fcntl(fd: c_int, command: c_int, ...) -> c_int;
+    }
+
+    pub fn bind_v6(addr: &SocketAddrV6, only: bool, backlog: u32) -> io::Result<TcpListener> {
+        let only_option = IPV6_V6ONLY.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Unsupported, "IPV6_V6ONLY cannot be set on this platform.")
+        })?;
+        let fd = unsafe { socket(AF_INET6, SOCK_STREAM, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Owned from here on, so the socket is closed on every error
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        if unsafe { fcntl(fd, F_SETFD, FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // As the standard library does, so restarts can bind at once
+        set(&listener, SOL_SOCKET, SO_REUSEADDR, 1 as c_int)?;
+        set(&listener, IPPROTO_IPV6, only_option, only as c_int)?;
+
+        let sockaddr = SockaddrIn6 {
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            length: mem::size_of::<SockaddrIn6>() as u8,
+            family: AF_INET6 as _,
+            port: addr.port().to_be(),
+            flowinfo: addr.flowinfo(),
+            addr: addr.ip().octets(),
+            scope_id: addr.scope_id(),
+        };
+        if unsafe { bind(fd, &sockaddr, mem::size_of::<SockaddrIn6>() as u32) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        set_backlog(&listener, backlog)?;
+        Ok(listener)
+    }
+
+    fn set<S: AsRawFd, T>(socket: &S, level: c_int, name: c_int, value: T) -> io::Result<()> {
+        let length = mem::size_of::<T>() as u32;
+        let result = unsafe { setsockopt(socket.as_raw_fd(), level, name, &value as *const T as *const c_void, length) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn seconds(duration: Duration) -> c_int {
+        duration.as_secs().clamp(1, c_int::MAX as u64) as c_int
+    }
+
+    pub fn set_backlog<S: AsRawFd>(listener: &S, backlog: u32) -> io::Result<()> {
+        // Listening again on a listening socket only changes its backlog
+        let backlog = backlog.min(c_int::MAX as u32) as c_int;
+        if unsafe { listen(listener.as_raw_fd(), backlog) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn set_buffer_sizes<S: AsRawFd>(socket: &S, send: Option<usize>, recv: Option<usize>) -> io::Result<()> {
+        if let Some(size) = send {
+            set(socket, SOL_SOCKET, SO_SNDBUF, size.min(c_int::MAX as usize) as c_int)?;
+        }
+        if let Some(size) = recv {
+            set(socket, SOL_SOCKET, SO_RCVBUF, size.min(c_int::MAX as usize) as c_int)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_keepalive<S: AsRawFd>(socket: &S, keepalive: &Keepalive) -> io::Result<()> {
+        set(socket, SOL_SOCKET, SO_KEEPALIVE, 1 as c_int)?;
+        let tuning = [
+            (TCP_KEEPIDLE, seconds(keepalive.idle)),
+            (TCP_KEEPINTVL, seconds(keepalive.interval)),
+            (TCP_KEEPCNT, keepalive.retries.clamp(1, c_int::MAX as u32) as c_int),
+        ];
+        for (name, value) in tuning.iter() {
+            match name {
+                Some(name) => set(socket, IPPROTO_TCP, *name, *value)?,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "Keep-alive intervals cannot be set on this platform.",
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_linger<S: AsRawFd>(socket: &S, linger: Duration) -> io::Result<()> {
+        let linger = Linger { onoff: 1, linger: linger.as_secs().min(c_int::MAX as u64) as c_int };
+        set(socket, SOL_SOCKET, SO_LINGER, linger)
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::io;
+    use std::net::{SocketAddrV6, TcpListener};
+    use std::time::Duration;
+
+    use super::Keepalive;
+
+    pub fn bind_v6(addr: &SocketAddrV6, _only: bool, _backlog: u32) -> io::Result<TcpListener> {
+        TcpListener::bind(addr)
+    }
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, "Socket options are not supported on this platform.")
+    }
+
+    pub fn set_backlog<S>(_listener: &S, _backlog: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn set_buffer_sizes<S>(_socket: &S, send: Option<usize>, recv: Option<usize>) -> io::Result<()> {
+        match (send, recv) {
+            (None, None) => Ok(()),
+            _ => Err(unsupported()),
+        }
+    }
+
+    pub fn set_keepalive<S>(_socket: &S, _keepalive: &Keepalive) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn set_linger<S>(_socket: &S, _linger: Duration) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
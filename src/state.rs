@@ -0,0 +1,87 @@
+use crate::extensions::Extensions;
+use crate::request::Request;
+use crate::response::Response;
+
+/// Values shared by all handlers of a server, such as a database pool or
+/// configuration, looked up by their type
+///
+/// The state is set on the server with `Server::with_state` and reaches
+/// handlers through `Request::state`, or as an argument of a `Handler`.
+/// Values are shared between all worker threads, so ones that change
+/// need their own synchronization, like a `Mutex` or an atomic.
+///
+/// ```no_run
+/// use std::sync::atomic::{AtomicU64, Ordering};
+/// use std::sync::Arc;
+/// use server::config::Config;
+/// use server::request::Request;
+/// use server::response::Response;
+/// use server::router::Router;
+/// use server::server::Server;
+/// use server::state::{stateful, AppState};
+///
+/// struct Hits(AtomicU64);
+///
+/// fn hits(_request: Request, state: &AppState) -> Response {
+///     let hits = state.get::<Hits>().unwrap();
+///     Response::text(200, (hits.0.fetch_add(1, Ordering::SeqCst) + 1).to_string())
+/// }
+///
+/// let mut router = Router::new();
+/// router.get("/hits", stateful(hits));
+/// let state = AppState::new().with(Hits(AtomicU64::new(0)));
+/// Server::new(Config::default()).unwrap().with_state(Arc::new(state)).serve(router);
+/// ```
+#[derive(Debug, Default)]
+pub struct AppState {
+    values: Extensions,
+}
+
+impl AppState {
+    /// Create a state without any values
+    pub fn new() -> AppState {
+        AppState::default()
+    }
+
+    /// Add a value, replacing one of the same type
+    pub fn with<T: Send + Sync + 'static>(mut self, value: T) -> AppState {
+        self.values.insert(value);
+        self
+    }
+
+    /// The value of a type, if one was added
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.values.get()
+    }
+}
+
+/// A handler that is given the application state along with the request
+///
+/// Implemented for functions and closures taking a `Request` and an
+/// `&AppState`. Use `stateful` to register one with a router.
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, request: Request, state: &AppState) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(Request, &AppState) -> Response + Send + Sync + 'static,
+{
+    fn call(&self, request: Request, state: &AppState) -> Response {
+        self(request, state)
+    }
+}
+
+/// Turn a handler taking the application state into a handler for a
+/// router
+///
+/// # Arguments
+///
+/// handler - A function taking the request and the state of the server
+/// serving it, which is empty if the server has none.
+pub fn stateful<H: Handler>(handler: H) -> impl Fn(Request) -> Response + Send + Sync + 'static {
+    move |request| {
+        let state = request.shared_state();
+        handler.call(request, &state)
+    }
+}
@@ -0,0 +1,1101 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::auth::User;
+use crate::base64;
+use crate::date;
+use crate::extract::{IntoResponse, Json};
+use crate::json::Value;
+use crate::log;
+use crate::mime::MimeTypes;
+use crate::negotiate;
+use crate::range;
+use crate::request::Request;
+use crate::response::{reason_phrase, Response};
+use crate::sha256::{self, Sha256};
+use crate::template;
+use crate::uri;
+#[cfg(feature = "watch")]
+use crate::watch::Watcher;
+
+/// Content codings of precompressed variants with the extension of their
+/// files, in order of preference
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+/// Most request paths a `FileCache` remembers the resolution of
+const MAX_RESOLUTIONS: usize = 4096;
+
+/// Files being received through PUT, told apart in their temporary names
+static UPLOADS: AtomicUsize = AtomicUsize::new(0);
+
+/// A handler serving files from a document root
+pub struct StaticFiles {
+    root: PathBuf,
+    symlinks: Symlinks,
+    index_files: Vec<String>,
+    listings: bool,
+    mime_types: MimeTypes,
+    digests: Option<DigestCache>,
+    cache: Option<FileCache>,
+    webdav: Option<WebDav>,
+}
+
+/// What a request path resolves to
+enum Target {
+    File(PathBuf),
+    /// A directory without an index file, to be listed
+    Directory(PathBuf),
+}
+
+/// An entry of a directory listing
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+/// How symbolic links below the document root are treated
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Symlinks {
+    /// Follow links wherever they point, even outside the document root
+    Follow,
+    /// Follow links only if their target lies inside the document root,
+    /// answering others with 403
+    WithinRoot,
+    /// Answer requests for paths going through any link with 403
+    Refuse,
+}
+
+/// Which WebDAV methods a handler answers besides GET and HEAD, see
+/// `StaticFiles::with_webdav`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WebDav {
+    /// OPTIONS and PROPFIND, so clients can browse the files
+    ReadOnly,
+    /// PUT, DELETE and MKCOL as well, for requests of authenticated users
+    ReadWrite,
+}
+
+impl StaticFiles {
+    /// Create a handler serving the files under a directory
+    ///
+    /// # Arguments
+    ///
+    /// root - The document root. Requests can never reach files outside of
+    /// it, unless symlinks are followed freely.
+    pub fn new<P: AsRef<Path>>(root: P) -> StaticFiles {
+        StaticFiles {
+            root: root.as_ref().to_path_buf(),
+            symlinks: Symlinks::WithinRoot,
+            index_files: vec![String::from("index.html")],
+            listings: false,
+            mime_types: MimeTypes::new(),
+            digests: None,
+            cache: None,
+            webdav: None,
+        }
+    }
+
+    /// Set how symbolic links below the document root are treated, by
+    /// default `Symlinks::WithinRoot`
+    ///
+    /// The document root itself may always be a link.
+    pub fn with_symlinks(mut self, symlinks: Symlinks) -> StaticFiles {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// Set the files served for a request for a directory, tried in
+    /// order, by default only `index.html`
+    ///
+    /// A directory containing none of them is listed if listings are
+    /// enabled and answered with 404 otherwise.
+    pub fn with_index_files(mut self, names: &[&str]) -> StaticFiles {
+        self.index_files = names.iter().map(|name| String::from(*name)).collect();
+        self
+    }
+
+    /// List the entries of directories without an index file instead of
+    /// answering with 404
+    ///
+    /// Listings are HTML pages, or JSON objects with the path and an
+    /// array of entries with their `name`, `is_dir`, `size` and `mtime`
+    /// for requests preferring `application/json` or with the query
+    /// parameter `format=json`. Hidden files are not listed.
+    pub fn with_listings(mut self, listings: bool) -> StaticFiles {
+        self.listings = listings;
+        self
+    }
+
+    /// Set the Content-Type files are served with by their extension,
+    /// e.g. to add types or change the one of unknown extensions
+    pub fn with_mime_types(mut self, mime_types: MimeTypes) -> StaticFiles {
+        self.mime_types = mime_types;
+        self
+    }
+
+    /// Send the SHA-256 digest of every file served in a Repr-Digest
+    /// header, and in a Content-Digest header when all of it is sent,
+    /// so clients can verify downloads
+    ///
+    /// A digest is computed when a file is first served and again after
+    /// it changed, which takes as long as reading the file.
+    ///
+    /// # Arguments
+    ///
+    /// digests - Where computed digests are kept.
+    pub fn with_digests(mut self, digests: DigestCache) -> StaticFiles {
+        self.digests = Some(digests);
+        self
+    }
+
+    /// Serve files from memory once they have been read, see `FileCache`
+    pub fn with_cache(mut self, cache: FileCache) -> StaticFiles {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Answer WebDAV requests as specified in RFC 4918, so the files can
+    /// be mounted as a network drive
+    ///
+    /// PROPFIND lists a file or a directory and, with Depth 1, its
+    /// entries, leaving out hidden files; Depth infinity is refused with
+    /// 403. Writing mounts store PUT bodies in a temporary file renamed
+    /// over the target once complete, delete files and whole directories
+    /// and create directories. Writes are only accepted from requests a
+    /// `BasicAuth` or `DigestAuth` middleware authenticated and answered
+    /// with 403 otherwise; they never go through symlinks, whatever the
+    /// policy for reads, nor touch the document root itself. Other
+    /// methods are answered with 405.
+    ///
+    /// The router has to hand requests of every method to the handler,
+    /// as a fallback does. Bodies are read into memory before the
+    /// handler runs, up to `Limits::max_buffered`; see `receive` for
+    /// larger files.
+    ///
+    /// ```no_run
+    /// use server::auth::{BasicAuth, Users};
+    /// use server::request::Request;
+    /// use server::router::Router;
+    /// use server::static_files::{StaticFiles, WebDav};
+    ///
+    /// let files = StaticFiles::new("/srv/share").with_listings(true).with_webdav(WebDav::ReadWrite);
+    /// let mut router = Router::new();
+    /// router.wrap(BasicAuth::new("share", Users::new().with_user("ada", "secret")));
+    /// router.fallback(move |request: Request| files.handle(&request));
+    /// ```
+    pub fn with_webdav(mut self, webdav: WebDav) -> StaticFiles {
+        self.webdav = Some(webdav);
+        self
+    }
+
+    /// Serve the file matching the request path
+    ///
+    /// Range requests are answered with the requested parts of the file,
+    /// see `range::file_response`. If a precompressed variant such as
+    /// `app.js.br` or `app.js.gz` lies next to the file and the client
+    /// accepts its encoding, the variant is sent instead, with the
+    /// Content-Type of the file itself.
+    pub fn handle(&self, request: &Request) -> Response {
+        match (request.method(), self.webdav) {
+            ("GET" | "HEAD", _) => {}
+            (_, Some(webdav)) => return self.dav(request, webdav, &mut request.body()),
+            (_, None) => return Response::text(405, "Method Not Allowed").with_header("Allow", "GET, HEAD"),
+        }
+        let held = self.cache.as_ref().map(|cache| cache.resolution(request.path()));
+        let (path, variants) = match held {
+            Some(Ok(resolution)) => resolution,
+            held => {
+                let path = match self.resolve(request.path()) {
+                    Ok(Target::File(path)) => path,
+                    Ok(Target::Directory(dir)) => return self.listing(Some(request), &dir, request.path()),
+                    Err(response) => return response,
+                };
+                let variants = self.variants(&path);
+                if let (Some(cache), Some(Err(changes))) = (&self.cache, held) {
+                    cache.resolved(request.path(), changes, &path, &variants);
+                }
+                (path, variants)
+            }
+        };
+
+        if variants.is_empty() {
+            return self.open(Some(request), &path, &path);
+        }
+        let encodings: Vec<&str> = variants.iter().map(|(encoding, _)| *encoding).collect();
+        let chosen = request
+            .header("Accept-Encoding")
+            .and_then(|accept| negotiate::preferred_encoding(accept, &encodings));
+        // Caches must not hand one variant to clients asking for another
+        let response = match variants.iter().find(|(encoding, _)| Some(*encoding) == chosen) {
+            Some((encoding, variant)) => match self.open(Some(request), variant, &path) {
+                response if matches!(response.status(), 200 | 206) => response.with_header("Content-Encoding", encoding),
+                response => response,
+            },
+            None => self.open(Some(request), &path, &path),
+        };
+        response.with_header("Vary", "Accept-Encoding")
+    }
+
+    /// Answer a request like `handle`, reading the body of a WebDAV PUT
+    /// as it is received
+    ///
+    /// Routes set to `Route::stream_body` that pass their requests here
+    /// accept files up to `Limits::max_streamed_body` rather than what
+    /// the connection may buffer. A route for PUT alone would have the
+    /// router answer the other methods with 405, so every method gets one.
+    ///
+    /// ```no_run
+    /// use server::request::Request;
+    /// use server::router::Router;
+    /// use server::static_files::{StaticFiles, WebDav};
+    /// use std::sync::Arc;
+    ///
+    /// let files = Arc::new(StaticFiles::new("/srv/share").with_webdav(WebDav::ReadWrite));
+    /// let mut router = Router::new();
+    /// for method in ["GET", "PUT", "DELETE", "MKCOL", "PROPFIND", "OPTIONS"] {
+    ///     let files = Arc::clone(&files);
+    ///     router.route(method, "/*path", move |request: Request| files.receive(request)).stream_body();
+    /// }
+    /// ```
+    pub fn receive(&self, mut request: Request) -> Response {
+        match (request.method(), self.webdav) {
+            ("GET" | "HEAD", _) | (_, None) => self.handle(&request),
+            (_, Some(webdav)) => {
+                let mut body = request.take_body();
+                self.dav(&request, webdav, &mut body)
+            }
+        }
+    }
+
+    /// Serve the file at a path relative to the document root
+    ///
+    /// Paths containing NUL bytes are rejected with 400, and paths that
+    /// would leave the document root through `..` segments or encoded
+    /// dots are rejected with 403, as are symlinks the policy forbids.
+    pub fn serve(&self, path: &str) -> Response {
+        let file = match self.resolve(path) {
+            Ok(Target::File(file)) => file,
+            Ok(Target::Directory(dir)) => return self.listing(None, &dir, path),
+            Err(response) => return response,
+        };
+
+        self.open(None, &file, &file)
+    }
+
+    /// Respond with a file, or the ranges of it a request asks for
+    ///
+    /// # Arguments
+    ///
+    /// request - The request whose Range header is honored, if any.
+    /// file - The file to send.
+    /// path - The path the Content-Type is guessed from, which differs from
+    /// the file for precompressed variants.
+    fn open(&self, request: Option<&Request>, file: &Path, path: &Path) -> Response {
+        let cached = self.cache.as_ref().and_then(|cache| cache.get(file));
+        let opened = match cached {
+            Some(contents) => Ok(match request {
+                Some(request) => range::bytes_response(request, contents, &self.mime_types.get(path)),
+                None => {
+                    let length = contents.len() as u64;
+                    Response::new(200)
+                        .with_header("Content-Type", &self.mime_types.get(path))
+                        .with_stream(io::Cursor::new(contents), Some(length))
+                }
+            }),
+            None => File::open(file).and_then(|opened| match request {
+                Some(request) => range::file_response(request, opened, &self.mime_types.get(path)),
+                None => Response::from_file(opened, &self.mime_types.get(path)),
+            }),
+        };
+        let mut response = match opened {
+            Ok(response) => response,
+            Err(e) => return error_response(&e),
+        };
+        if let (Some(digests), 200 | 206) = (&self.digests, response.status()) {
+            match digests.digest(file) {
+                Ok(digest) => {
+                    let value = format!("sha-256=:{}:", base64::encode(&digest));
+                    // The content is only the whole representation if no
+                    // range of it was asked for
+                    if response.status() == 200 {
+                        response.set_header("Content-Digest", &value);
+                    }
+                    response.set_header("Repr-Digest", &value);
+                }
+                Err(e) => log::warn(&format!("Failed to compute the digest of {}: {}", file.display(), e)),
+            }
+        }
+        response
+    }
+
+    /// Map a request path to a file under the document root, or to a
+    /// directory to list
+    fn resolve(&self, path: &str) -> Result<Target, Response> {
+        let relative = sanitize(path)?;
+
+        let root = fs::canonicalize(&self.root).map_err(|e| error_response(&e))?;
+        let resolved = self.check(&root, &root.join(relative))?;
+        if !resolved.is_dir() {
+            return Ok(Target::File(resolved));
+        }
+        for index in &self.index_files {
+            match self.check(&root, &resolved.join(index)) {
+                Err(response) if response.status() == 404 => continue,
+                result => return result.map(Target::File),
+            }
+        }
+        if self.listings {
+            return Ok(Target::Directory(resolved));
+        }
+        Err(Response::text(404, "Not Found"))
+    }
+
+    /// List the entries of a directory, as HTML or, if the request asks
+    /// for it, as JSON
+    ///
+    /// # Arguments
+    ///
+    /// request - The request whose Accept header and `format` query
+    /// parameter choose the format, if any.
+    /// dir - The resolved directory.
+    /// path - The requested path of the directory, which links to its
+    /// entries are relative to.
+    fn listing(&self, request: Option<&Request>, dir: &Path, path: &str) -> Response {
+        let root = match fs::canonicalize(&self.root) {
+            Ok(root) => root,
+            Err(e) => return error_response(&e),
+        };
+        let entries = match self.entries(&root, dir) {
+            Ok(entries) => entries,
+            Err(e) => return error_response(&e),
+        };
+
+        let base = if path.ends_with('/') { String::from(path) } else { format!("{}/", path) };
+        let json = request.is_some_and(|request| {
+            request.query_pairs().iter().any(|(name, value)| name == "format" && value == "json")
+                || request
+                    .header("Accept")
+                    .and_then(|accept| negotiate::preferred(accept, &["text/html", "application/json"]))
+                    == Some("application/json")
+        });
+        let response = if json {
+            let entries: Vec<Value> = entries
+                .iter()
+                .map(|entry| {
+                    Value::object()
+                        .with("name", entry.name.as_str())
+                        .with("is_dir", entry.is_dir)
+                        .with("size", entry.size)
+                        .with("mtime", entry.modified.map(date::rfc3339))
+                })
+                .collect();
+            Json(Value::object().with("path", base.as_str()).with("entries", entries)).into_response()
+        } else {
+            let mut html = format!(
+                "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n",
+                template::escape_html(&base)
+            );
+            if let Some((parent, _)) = base.trim_end_matches('/').rsplit_once('/') {
+                html.push_str(&format!("<li><a href=\"{}/\">../</a></li>\n", uri::percent_encode_path(parent)));
+            }
+            for entry in &entries {
+                let suffix = if entry.is_dir { "/" } else { "" };
+                html.push_str(&format!(
+                    "<li><a href=\"{}{}\">{}{}</a></li>\n",
+                    uri::percent_encode_path(&format!("{}{}", base, entry.name)),
+                    suffix,
+                    template::escape_html(&entry.name),
+                    suffix
+                ));
+            }
+            html.push_str("</ul>\n</body>\n</html>\n");
+            Response::html(200, html)
+        };
+        match request {
+            Some(_) => response.with_header("Vary", "Accept"),
+            None => response,
+        }
+    }
+
+    /// The entries of a directory, directories first, each sorted by name
+    ///
+    /// Hidden files and entries the symlink policy forbids are left out,
+    /// as are names that are not valid UTF-8.
+    fn entries(&self, root: &Path, dir: &Path) -> io::Result<Vec<Entry>> {
+        let mut entries: Vec<Entry> = fs::read_dir(dir)?
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                if name.starts_with('.') {
+                    return None;
+                }
+                let metadata = fs::metadata(self.check(root, &dir.join(&name)).ok()?).ok()?;
+                Some(Entry { name, is_dir: metadata.is_dir(), size: metadata.len(), modified: metadata.modified().ok() })
+            })
+            .collect();
+        entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+        Ok(entries)
+    }
+
+    /// Answer a WebDAV request
+    ///
+    /// # Arguments
+    ///
+    /// request - The request, of any method but GET and HEAD.
+    /// webdav - Which methods are answered.
+    /// body - The request body, stored by PUT.
+    fn dav(&self, request: &Request, webdav: WebDav, body: &mut dyn Read) -> Response {
+        let allow = match webdav {
+            WebDav::ReadOnly => "OPTIONS, GET, HEAD, PROPFIND",
+            WebDav::ReadWrite => "OPTIONS, GET, HEAD, PROPFIND, PUT, DELETE, MKCOL",
+        };
+        match request.method() {
+            "OPTIONS" => return Response::new(200).with_header("DAV", "1").with_header("Allow", allow),
+            "PROPFIND" => return self.propfind(request),
+            "PUT" | "DELETE" | "MKCOL" if webdav == WebDav::ReadWrite => {}
+            _ => return Response::text(405, "Method Not Allowed").with_header("Allow", allow),
+        }
+        let user = match request.extensions().get::<User>() {
+            Some(user) => user.name.clone(),
+            None => return forbidden(),
+        };
+
+        let relative = match sanitize(request.path()) {
+            Ok(relative) => relative,
+            Err(response) => return response,
+        };
+        let (parent, name) = match (relative.parent(), relative.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return forbidden(),
+        };
+        let root = match fs::canonicalize(&self.root) {
+            Ok(root) => root,
+            Err(e) => return error_response(&e),
+        };
+        // Whatever is written needs its parent directory to exist already,
+        // and is never written through a link, whatever reads follow
+        let parent = match without_links(&root, &root.join(parent)) {
+            Ok(parent) if parent.is_dir() => parent,
+            Ok(_) => return Response::text(409, reason_phrase(409)),
+            Err(response) if response.status() == 404 => return Response::text(409, reason_phrase(409)),
+            Err(response) => return response,
+        };
+        let target = parent.join(name);
+        let existing = match fs::symlink_metadata(&target) {
+            Ok(metadata) if metadata.file_type().is_symlink() => return forbidden(),
+            Ok(metadata) => Some(metadata),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return error_response(&e),
+        };
+
+        let written = match (request.method(), existing) {
+            ("PUT", Some(metadata)) if metadata.is_dir() => {
+                return Response::text(405, "Method Not Allowed").with_header("Allow", allow);
+            }
+            ("PUT", existing) => store(&target, body).map(|_| if existing.is_some() { 204 } else { 201 }),
+            ("DELETE", None) => return Response::text(404, "Not Found"),
+            ("DELETE", Some(metadata)) if metadata.is_dir() => fs::remove_dir_all(&target).map(|_| 204),
+            ("DELETE", Some(_)) => fs::remove_file(&target).map(|_| 204),
+            ("MKCOL", _) if body.read(&mut [0]).map_or(true, |read| read > 0) => {
+                return Response::text(415, reason_phrase(415));
+            }
+            ("MKCOL", Some(_)) => return Response::text(405, "Method Not Allowed").with_header("Allow", allow),
+            (_, _) => fs::create_dir(&target).map(|_| 201),
+        };
+        match written {
+            Ok(status) => {
+                log::info(&format!("{} {} by {}", request.method(), request.path(), user));
+                Response::new(status)
+            }
+            Err(e) => {
+                log::warn(&format!("Failed to {} {}: {}", request.method(), target.display(), e));
+                error_response(&e)
+            }
+        }
+    }
+
+    /// Answer a PROPFIND request with the properties of the requested
+    /// file or directory and, with Depth 1, of its entries
+    ///
+    /// The properties asked for in the body are not looked at, as every
+    /// one of them is cheap to tell.
+    fn propfind(&self, request: &Request) -> Response {
+        // A missing Depth header means infinity
+        let depth_one = match request.header("Depth").map(str::trim) {
+            Some("0") => false,
+            Some("1") => true,
+            _ => {
+                return Response::new(403).with_header("Content-Type", "application/xml; charset=utf-8").with_body(
+                    "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>\n",
+                );
+            }
+        };
+        let relative = match sanitize(request.path()) {
+            Ok(relative) => relative,
+            Err(response) => return response,
+        };
+        let root = match fs::canonicalize(&self.root) {
+            Ok(root) => root,
+            Err(e) => return error_response(&e),
+        };
+        let resolved = match self.check(&root, &root.join(relative)) {
+            Ok(resolved) => resolved,
+            Err(response) => return response,
+        };
+        let metadata = match fs::metadata(&resolved) {
+            Ok(metadata) => metadata,
+            Err(e) => return error_response(&e),
+        };
+
+        let name = resolved.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+        let requested = Entry { name, is_dir: metadata.is_dir(), size: metadata.len(), modified: metadata.modified().ok() };
+        let base = match (requested.is_dir, request.path().ends_with('/')) {
+            (true, false) => format!("{}/", request.path()),
+            _ => String::from(request.path()),
+        };
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n");
+        self.dav_properties(&mut xml, &base, &requested);
+        if depth_one && requested.is_dir {
+            let entries = match self.entries(&root, &resolved) {
+                Ok(entries) => entries,
+                Err(e) => return error_response(&e),
+            };
+            for entry in &entries {
+                let suffix = if entry.is_dir { "/" } else { "" };
+                self.dav_properties(&mut xml, &format!("{}{}{}", base, entry.name, suffix), entry);
+            }
+        }
+        xml.push_str("</D:multistatus>\n");
+        Response::new(207).with_header("Content-Type", "application/xml; charset=utf-8").with_body(xml)
+    }
+
+    /// Add the `response` element of a file or directory to a multistatus
+    /// body
+    fn dav_properties(&self, xml: &mut String, path: &str, entry: &Entry) {
+        xml.push_str(&format!(
+            "<D:response>\n<D:href>{}</D:href>\n<D:propstat>\n<D:prop>\n<D:displayname>{}</D:displayname>\n",
+            template::escape_html(&uri::percent_encode_path(path)),
+            template::escape_html(&entry.name)
+        ));
+        if entry.is_dir {
+            xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>\n");
+        } else {
+            xml.push_str(&format!(
+                "<D:resourcetype/>\n<D:getcontentlength>{}</D:getcontentlength>\n<D:getcontenttype>{}</D:getcontenttype>\n",
+                entry.size,
+                template::escape_html(&self.mime_types.get(Path::new(&entry.name)))
+            ));
+        }
+        if let Some(modified) = entry.modified {
+            xml.push_str(&format!("<D:getlastmodified>{}</D:getlastmodified>\n", date::http_date(modified)));
+        }
+        xml.push_str("</D:prop>\n<D:status>HTTP/1.1 200 OK</D:status>\n</D:propstat>\n</D:response>\n");
+    }
+
+    /// Apply the symlink policy to a path below the document root,
+    /// returning the path to open
+    fn check(&self, root: &Path, path: &Path) -> Result<PathBuf, Response> {
+        match self.symlinks {
+            Symlinks::Follow => fs::canonicalize(path).map_err(|e| error_response(&e)),
+            Symlinks::WithinRoot => {
+                let resolved = fs::canonicalize(path).map_err(|e| error_response(&e))?;
+                if !resolved.starts_with(root) {
+                    return Err(forbidden());
+                }
+                Ok(resolved)
+            }
+            Symlinks::Refuse => without_links(root, path),
+        }
+    }
+
+    /// The precompressed variants of a resolved file that the symlink
+    /// policy allows, with their content coding
+    fn variants(&self, path: &Path) -> Vec<(&'static str, PathBuf)> {
+        let root = match fs::canonicalize(&self.root) {
+            Ok(root) => root,
+            Err(_) => return Vec::new(),
+        };
+        PRECOMPRESSED
+            .iter()
+            .filter_map(|(encoding, extension)| {
+                let mut name = path.as_os_str().to_owned();
+                name.push(".");
+                name.push(extension);
+                let variant = self.check(&root, Path::new(&name)).ok()?;
+                if !variant.is_file() {
+                    return None;
+                }
+                Some((*encoding, variant))
+            })
+            .collect()
+    }
+}
+
+/// SHA-256 digests of files, each kept until its file is modified
+pub struct DigestCache {
+    entries: Arc<Mutex<HashMap<PathBuf, Digest>>>,
+    dir: Option<PathBuf>,
+    /// Whether a watcher drops the digests of changed files, so they do
+    /// not have to be checked
+    watched: bool,
+}
+
+/// The digest of a version of a file
+#[derive(Clone, Copy)]
+struct Digest {
+    modified: SystemTime,
+    length: u64,
+    hash: [u8; 32],
+}
+
+impl DigestCache {
+    /// A cache holding the digests in memory only
+    pub fn in_memory() -> DigestCache {
+        DigestCache { entries: Arc::new(Mutex::new(HashMap::new())), dir: None, watched: false }
+    }
+
+    /// A cache that also stores the digests in files in a directory, so
+    /// they outlive restarts of the server
+    ///
+    /// # Arguments
+    ///
+    /// dir - The directory, which is created when the first digest is
+    /// stored if it does not exist.
+    pub fn on_disk<P: AsRef<Path>>(dir: P) -> DigestCache {
+        DigestCache { entries: Arc::new(Mutex::new(HashMap::new())), dir: Some(dir.as_ref().to_path_buf()), watched: false }
+    }
+
+    /// Drop the digests of files as a watcher reports them changing, and
+    /// use the ones held without first checking whether their file
+    /// changed
+    ///
+    /// Only files below the watched directory should be served then, as
+    /// changes elsewhere go unnoticed; the document root is watched with
+    /// `Watcher::new` on the same directory.
+    #[cfg(feature = "watch")]
+    pub fn watched(mut self, watcher: &Watcher) -> DigestCache {
+        let entries = Arc::clone(&self.entries);
+        watcher.on_change(move |changed| entries.lock().unwrap().retain(|path, _| !path.starts_with(changed)));
+        self.watched = true;
+        self
+    }
+
+    /// The SHA-256 digest of the current contents of a file
+    ///
+    /// A file counts as changed when its modification time or length do,
+    /// or when a watcher says so.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read.
+    pub fn digest(&self, path: &Path) -> io::Result<[u8; 32]> {
+        if self.watched {
+            if let Some(digest) = self.entries.lock().unwrap().get(path) {
+                return Ok(digest.hash);
+            }
+        }
+        let metadata = fs::metadata(path)?;
+        let (modified, length) = (metadata.modified()?, metadata.len());
+        let current = |digest: &Digest| digest.modified == modified && digest.length == length;
+
+        if let Some(digest) = self.entries.lock().unwrap().get(path).filter(|digest| current(digest)) {
+            return Ok(digest.hash);
+        }
+        let stored = self.dir.as_ref().map(|dir| dir.join(sha256::hex(&sha256::digest(path.as_os_str().as_encoded_bytes()))));
+        let digest = match stored.as_deref().and_then(read_digest).filter(current) {
+            Some(digest) => digest,
+            None => {
+                let mut hasher = Sha256::new();
+                let mut file = File::open(path)?;
+                let mut buffer = vec![0; 64 * 1024];
+                loop {
+                    match file.read(&mut buffer)? {
+                        0 => break,
+                        read => hasher.update(&buffer[..read]),
+                    }
+                }
+                let digest = Digest { modified, length, hash: hasher.finish() };
+                if let Some(stored) = &stored {
+                    if let Err(e) = write_digest(stored, &digest) {
+                        log::warn(&format!("Failed to store the digest of {}: {}", path.display(), e));
+                    }
+                }
+                digest
+            }
+        };
+        self.entries.lock().unwrap().insert(path.to_path_buf(), digest);
+        Ok(digest.hash)
+    }
+}
+
+/// Read a digest stored as its modification time in nanoseconds, its
+/// length and its hash in hexadecimal
+fn read_digest(path: &Path) -> Option<Digest> {
+    let contents = fs::read_to_string(path).ok()?;
+    let mut fields = contents.split_whitespace();
+    let nanos: u64 = fields.next()?.parse().ok()?;
+    let length = fields.next()?.parse().ok()?;
+    let hex = fields.next()?;
+    let mut hash = [0; 32];
+    if hex.len() != 64 {
+        return None;
+    }
+    for (byte, digits) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(Digest { modified: UNIX_EPOCH + Duration::from_nanos(nanos), length, hash })
+}
+
+fn write_digest(path: &Path, digest: &Digest) -> io::Result<()> {
+    let nanos = digest.modified.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos());
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, format!("{} {} {}\n", nanos, digest.length, sha256::hex(&digest.hash)))
+}
+
+/// The contents of files kept in memory, so requests for files served
+/// over and over are answered without reading them again
+///
+/// A file is read whole the first time it is served, if it is not
+/// larger than the largest file held, and kept until it changes. When
+/// the files held take more than the budget, the least recently served
+/// are dropped. Each request still checks whether its file changed,
+/// which takes a single `stat`, unless a watcher reports the changes;
+/// then the paths requests resolve to are remembered as well, so a
+/// request for a file held makes no system calls for it at all.
+///
+/// Files are read rather than mapped into memory, as a mapped file
+/// truncated while being sent would crash the server.
+///
+/// ```no_run
+/// use server::static_files::{FileCache, StaticFiles};
+///
+/// let files = StaticFiles::new("public").with_cache(FileCache::new(64 * 1024 * 1024));
+/// ```
+pub struct FileCache {
+    state: Arc<Mutex<FileCacheState>>,
+    max_size: usize,
+    max_file_size: usize,
+    /// Whether a watcher drops changed files, so they do not have to be
+    /// checked
+    watched: bool,
+}
+
+/// A resolved file and its precompressed variants
+type Resolution = (PathBuf, Vec<(&'static str, PathBuf)>);
+
+struct FileCacheState {
+    files: HashMap<PathBuf, CachedFile>,
+    /// The file and precompressed variants request paths resolved to,
+    /// only kept while watched
+    resolutions: HashMap<String, Resolution>,
+    size: usize,
+    clock: u64,
+    /// Counts the changes reported, so a file read while it changed is
+    /// not kept
+    changes: u64,
+}
+
+/// The contents of a version of a file
+struct CachedFile {
+    contents: Arc<[u8]>,
+    modified: SystemTime,
+    last_used: u64,
+}
+
+impl FileCache {
+    /// A cache holding files of up to 1 MiB each
+    ///
+    /// # Arguments
+    ///
+    /// max_size - The most bytes of files held at once.
+    pub fn new(max_size: usize) -> FileCache {
+        FileCache {
+            state: Arc::new(Mutex::new(FileCacheState {
+                files: HashMap::new(),
+                resolutions: HashMap::new(),
+                size: 0,
+                clock: 0,
+                changes: 0,
+            })),
+            max_size,
+            max_file_size: 1024 * 1024,
+            watched: false,
+        }
+    }
+
+    /// Set the size of the largest file held, larger ones are read from
+    /// disk on every request
+    pub fn with_max_file_size(mut self, max_file_size: usize) -> FileCache {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Drop files as a watcher reports them changing, and serve the ones
+    /// held and the paths resolved without first checking the disk
+    ///
+    /// Only files below the watched directory should be served then, as
+    /// changes elsewhere go unnoticed; the document root is watched with
+    /// `Watcher::new` on the same directory.
+    #[cfg(feature = "watch")]
+    pub fn watched(mut self, watcher: &Watcher) -> FileCache {
+        let state = Arc::clone(&self.state);
+        watcher.on_change(move |changed| {
+            let mut state = state.lock().unwrap();
+            let state = &mut *state;
+            state.changes += 1;
+            // A new file can change what any path resolves to
+            state.resolutions.clear();
+            let size = &mut state.size;
+            state.files.retain(|path, file| {
+                let keep = !path.starts_with(changed);
+                if !keep {
+                    *size -= file.contents.len();
+                }
+                keep
+            });
+        });
+        self.watched = true;
+        self
+    }
+
+    /// The number of files held
+    ///
+    /// # Panics
+    ///
+    /// Panics if the cache mutex is in a poisoned state.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().files.len()
+    }
+
+    /// Whether no files are held
+    ///
+    /// # Panics
+    ///
+    /// Panics if the cache mutex is in a poisoned state.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of bytes of the files held
+    ///
+    /// # Panics
+    ///
+    /// Panics if the cache mutex is in a poisoned state.
+    pub fn size(&self) -> usize {
+        self.state.lock().unwrap().size
+    }
+
+    /// Drop all files held
+    ///
+    /// # Panics
+    ///
+    /// Panics if the cache mutex is in a poisoned state.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.files.clear();
+        state.resolutions.clear();
+        state.size = 0;
+    }
+
+    /// The current contents of a file, read into the cache if they fit,
+    /// or None if the file is to be read from disk
+    fn get(&self, path: &Path) -> Option<Arc<[u8]>> {
+        let changes = {
+            let mut state = self.state.lock().unwrap();
+            state.clock += 1;
+            let clock = state.clock;
+            if self.watched {
+                if let Some(file) = state.files.get_mut(path) {
+                    file.last_used = clock;
+                    return Some(Arc::clone(&file.contents));
+                }
+            }
+            state.changes
+        };
+        let metadata = fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?;
+        if !metadata.is_file() || metadata.len() > self.max_file_size as u64 {
+            return None;
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            let clock = state.clock;
+            let current = state
+                .files
+                .get_mut(path)
+                .filter(|file| file.modified == modified && file.contents.len() as u64 == metadata.len());
+            if let Some(file) = current {
+                file.last_used = clock;
+                return Some(Arc::clone(&file.contents));
+            }
+        }
+
+        let contents: Arc<[u8]> = fs::read(path).ok()?.into();
+        // The file changed while it was read
+        if contents.len() as u64 != metadata.len() {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.changes != changes || contents.len() > self.max_size {
+            return Some(contents);
+        }
+        let clock = state.clock;
+        let file = CachedFile { contents: Arc::clone(&contents), modified, last_used: clock };
+        if let Some(replaced) = state.files.insert(path.to_path_buf(), file) {
+            state.size -= replaced.contents.len();
+        }
+        state.size += contents.len();
+        while state.size > self.max_size {
+            let oldest = state.files.iter().min_by_key(|(_, file)| file.last_used).map(|(path, _)| path.clone());
+            if let Some(removed) = oldest.and_then(|oldest| state.files.remove(&oldest)) {
+                state.size -= removed.contents.len();
+            }
+        }
+        Some(contents)
+    }
+
+    /// What a request path resolved to before, if that is known to
+    /// still hold, or else the number of changes seen so far to pass to
+    /// `resolved`
+    fn resolution(&self, path: &str) -> Result<Resolution, u64> {
+        let state = self.state.lock().unwrap();
+        match state.resolutions.get(path) {
+            Some(resolution) if self.watched => Ok(resolution.clone()),
+            _ => Err(state.changes),
+        }
+    }
+
+    /// Remember what a request path resolved to, while watched and if
+    /// nothing changed since looking it up
+    fn resolved(&self, path: &str, changes: u64, file: &Path, variants: &[(&'static str, PathBuf)]) {
+        let mut state = self.state.lock().unwrap();
+        if !self.watched || state.changes != changes {
+            return;
+        }
+        // Many request paths can name the same file, so they are
+        // forgotten rather than piling up
+        if state.resolutions.len() >= MAX_RESOLUTIONS {
+            state.resolutions.clear();
+        }
+        state.resolutions.insert(String::from(path), (file.to_path_buf(), variants.to_vec()));
+    }
+}
+
+/// Check that no component of a path below the document root is a
+/// symbolic link, returning the path
+fn without_links(root: &Path, path: &Path) -> Result<PathBuf, Response> {
+    let relative = path.strip_prefix(root).map_err(|_| forbidden())?;
+    let mut current = root.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        let metadata = fs::symlink_metadata(&current).map_err(|e| error_response(&e))?;
+        if metadata.file_type().is_symlink() {
+            return Err(forbidden());
+        }
+    }
+    Ok(current)
+}
+
+/// Write a body to a file, replacing it only once all of the body has
+/// been received, so readers never see half a file
+fn store(target: &Path, body: &mut dyn Read) -> io::Result<()> {
+    let mut name = OsString::from(".");
+    name.push(target.file_name().unwrap_or_default());
+    name.push(format!(".{}-{}.upload", process::id(), UPLOADS.fetch_add(1, Ordering::Relaxed)));
+    let temporary = target.with_file_name(name);
+    let written = File::create(&temporary).and_then(|mut file| {
+        io::copy(body, &mut file)?;
+        file.sync_all()
+    });
+    let result = written.and_then(|_| fs::rename(&temporary, target));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+/// Turn a request path into a relative path free of traversal
+///
+/// Percent-encoded dots, slashes and backslashes are decoded before
+/// checking so that `%2e%2e/` is treated the same as `../`.
+pub(crate) fn sanitize(path: &str) -> Result<PathBuf, Response> {
+    if path.contains('\0') || contains_ignore_case(path, "%00") {
+        return Err(Response::text(400, "Bad Request"));
+    }
+
+    let decoded = decode_separators(path);
+    let mut relative = PathBuf::new();
+    for segment in decoded.split('/') {
+        if segment.contains('\\') {
+            return Err(forbidden());
+        }
+        match segment {
+            "" | "." => continue,
+            ".." => return Err(forbidden()),
+            _ => {}
+        }
+        let mut components = Path::new(segment).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(part)), None) => relative.push(part),
+            _ => return Err(forbidden()),
+        }
+    }
+    Ok(relative)
+}
+
+/// Decode the escapes that could be used to smuggle path separators or dots
+fn decode_separators(path: &str) -> String {
+    let mut decoded = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(i) = rest.find('%') {
+        decoded.push_str(&rest[..i]);
+        let escape = rest.get(i..i + 3).unwrap_or("");
+        match escape.to_ascii_lowercase().as_str() {
+            "%2e" => decoded.push('.'),
+            "%2f" => decoded.push('/'),
+            "%5c" => decoded.push('\\'),
+            _ => {
+                decoded.push('%');
+                rest = &rest[i + 1..];
+                continue;
+            }
+        }
+        rest = &rest[i + 3..];
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    haystack.to_ascii_lowercase().contains(needle)
+}
+
+pub(crate) fn forbidden() -> Response {
+    Response::text(403, "Forbidden")
+}
+
+/// Map a filesystem error to a response
+pub(crate) fn error_response(error: &io::Error) -> Response {
+    match error.kind() {
+        io::ErrorKind::NotFound => Response::text(404, "Not Found"),
+        io::ErrorKind::PermissionDenied => forbidden(),
+        _ => Response::text(500, "Internal Server Error"),
+    }
+}
+
+/// Guess the Content-Type of a file from its extension with the
+/// built-in mapping, see `MimeTypes`
+pub fn content_type(path: &Path) -> String {
+    MimeTypes::new().get(path)
+}
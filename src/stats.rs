@@ -0,0 +1,138 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+type Hook = Box<dyn Fn(&ConnectionEvent) + Send + Sync + 'static>;
+
+/// Something that happened to a connection
+#[derive(Debug, Clone)]
+pub enum ConnectionEvent {
+    /// A connection was accepted
+    Accepted { peer: Option<SocketAddr> },
+    /// A kept-alive connection finished a request and is waiting for the next one
+    Idle { peer: Option<SocketAddr> },
+    /// An idle connection started sending another request
+    Active { peer: Option<SocketAddr> },
+    /// A connection was closed
+    Closed {
+        peer: Option<SocketAddr>,
+        requests: u64,
+        bytes_in: u64,
+        bytes_out: u64,
+    },
+}
+
+/// A point-in-time copy of the connection counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Total number of connections accepted
+    pub accepted: u64,
+    /// Connections currently reading a request or writing a response
+    pub active: u64,
+    /// Kept-alive connections waiting for their next request
+    pub idle: u64,
+    /// Total number of connections closed
+    pub closed: u64,
+    /// Total bytes read from clients
+    pub bytes_in: u64,
+    /// Total bytes written to clients
+    pub bytes_out: u64,
+}
+
+impl StatsSnapshot {
+    /// Number of connections currently open
+    pub fn open(&self) -> u64 {
+        self.active + self.idle
+    }
+}
+
+/// Live connection statistics shared by all connections of a server
+#[derive(Default)]
+pub struct Stats {
+    accepted: AtomicU64,
+    active: AtomicU64,
+    idle: AtomicU64,
+    closed: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    hooks: Mutex<Vec<Hook>>,
+}
+
+impl Stats {
+    /// Create a new set of statistics with all counters at zero
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    /// Take a snapshot of the current counters
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            accepted: self.accepted.load(Ordering::SeqCst),
+            active: self.active.load(Ordering::SeqCst),
+            idle: self.idle.load(Ordering::SeqCst),
+            closed: self.closed.load(Ordering::SeqCst),
+            bytes_in: self.bytes_in.load(Ordering::SeqCst),
+            bytes_out: self.bytes_out.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Register a function called for every connection event
+    ///
+    /// Hooks run on the thread handling the connection, so they should
+    /// return quickly.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the hook mutex is in a poisoned state.
+    pub fn on_event<F>(&self, hook: F)
+    where
+        F: Fn(&ConnectionEvent) + Send + Sync + 'static,
+    {
+        self.hooks.lock().unwrap().push(Box::new(hook));
+    }
+
+    pub(crate) fn accepted(&self, peer: Option<SocketAddr>) {
+        self.accepted.fetch_add(1, Ordering::SeqCst);
+        self.active.fetch_add(1, Ordering::SeqCst);
+        self.emit(&ConnectionEvent::Accepted { peer });
+    }
+
+    pub(crate) fn idle(&self, peer: Option<SocketAddr>) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        self.idle.fetch_add(1, Ordering::SeqCst);
+        self.emit(&ConnectionEvent::Idle { peer });
+    }
+
+    pub(crate) fn active(&self, peer: Option<SocketAddr>) {
+        self.idle.fetch_sub(1, Ordering::SeqCst);
+        self.active.fetch_add(1, Ordering::SeqCst);
+        self.emit(&ConnectionEvent::Active { peer });
+    }
+
+    /// Record that a connection closed
+    ///
+    /// idle - Whether the connection was idle rather than active when it closed.
+    pub(crate) fn closed(&self, peer: Option<SocketAddr>, idle: bool, requests: u64, bytes_in: u64, bytes_out: u64) {
+        if idle {
+            self.idle.fetch_sub(1, Ordering::SeqCst);
+        } else {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.closed.fetch_add(1, Ordering::SeqCst);
+        self.emit(&ConnectionEvent::Closed { peer, requests, bytes_in, bytes_out });
+    }
+
+    pub(crate) fn add_bytes_in(&self, bytes: u64) {
+        self.bytes_in.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub(crate) fn add_bytes_out(&self, bytes: u64) {
+        self.bytes_out.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    fn emit(&self, event: &ConnectionEvent) {
+        for hook in self.hooks.lock().unwrap().iter() {
+            hook(event);
+        }
+    }
+}
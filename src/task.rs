@@ -0,0 +1,128 @@
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::PoolHandle;
+#[cfg(feature = "otel")]
+use crate::trace;
+
+/// Turn an async function into a handler for a router
+///
+/// The future runs to completion on the worker thread that received
+/// the request, which sleeps whenever the future is waiting, so async
+/// code can be called from handlers without an async runtime. Work
+/// that blocks for long should be moved to another pool with
+/// `spawn_blocking`.
+///
+/// # Arguments
+///
+/// f - An async function producing the response for a request.
+pub fn async_handler<F, Fut>(f: F) -> impl Fn(Request) -> Response + Send + Sync + 'static
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response>,
+{
+    move |request| block_on(f(request))
+}
+
+/// Run a future to completion on the current thread
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run a blocking function in a thread pool, returning a future that
+/// completes with its result
+///
+/// The pool should not be the one the awaiting handler runs on: if all
+/// of its workers are waiting for blocking work, none is left to do it.
+///
+/// # Arguments
+///
+/// pool - The pool to run the function in.
+/// f - The blocking function.
+///
+/// # Panics
+///
+/// Awaiting the future panics if the function panicked.
+pub fn spawn_blocking<F, T>(pool: &PoolHandle, f: F) -> Blocking<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let state = Arc::new(Mutex::new(BlockingState { result: None, waker: None }));
+    let job_state = Arc::clone(&state);
+    #[cfg(feature = "otel")]
+    let span = trace::current_child("blocking job", trace::SpanKind::Internal);
+    #[cfg(feature = "otel")]
+    let queued = std::time::Instant::now();
+    pool.execute(move || {
+        #[cfg(feature = "otel")]
+        let result = match span {
+            Some(mut span) => {
+                span.set_attribute("pool.queue_wait_ms", queued.elapsed().as_secs_f64() * 1000.0);
+                let result = span.enter(|| panic::catch_unwind(AssertUnwindSafe(f)));
+                if result.is_err() {
+                    span.set_error();
+                }
+                span.end();
+                result
+            }
+            None => panic::catch_unwind(AssertUnwindSafe(f)),
+        };
+        #[cfg(not(feature = "otel"))]
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        let mut state = job_state.lock().unwrap();
+        state.result = Some(result);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    });
+    Blocking { state }
+}
+
+/// The result of `spawn_blocking`, available once the function returned
+pub struct Blocking<T> {
+    state: Arc<Mutex<BlockingState<T>>>,
+}
+
+struct BlockingState<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+impl<T> Future for Blocking<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<T> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(Ok(value)) => Poll::Ready(value),
+            Some(Err(panic)) => panic::resume_unwind(panic),
+            None => {
+                state.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
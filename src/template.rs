@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+/// Maximum depth of nested includes, guarding against include cycles
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Values available to a template while it is rendered
+#[derive(Default)]
+pub struct Context {
+    values: HashMap<String, String>,
+}
+
+impl Context {
+    /// Create an empty context
+    pub fn new() -> Context {
+        Context::default()
+    }
+
+    /// Set a variable, replacing any previous value
+    pub fn insert<V: ToString>(&mut self, name: &str, value: V) -> &mut Context {
+        self.values.insert(String::from(name), value.to_string());
+        self
+    }
+
+    /// Get the value of a variable
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(|v| v.as_str())
+    }
+}
+
+/// A compiled template
+///
+/// Supported syntax:
+///
+/// `{{ name }}` - the HTML-escaped value of a variable.
+/// `{{& name }}` - the raw value of a variable.
+/// `{{> file.html }}` - the rendered contents of another template file.
+///
+/// Undefined variables render as an empty string.
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+enum Node {
+    Text(String),
+    Variable { name: String, escape: bool },
+    Include(String),
+}
+
+impl Template {
+    /// Compile a template from its source
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a tag is left unclosed or is empty.
+    pub fn compile(source: &str) -> Result<Template, TemplateError> {
+        let mut nodes = Vec::new();
+        let mut rest = source;
+
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                nodes.push(Node::Text(String::from(&rest[..start])));
+            }
+            let after = &rest[start + 2..];
+            let end = after
+                .find("}}")
+                .ok_or_else(|| TemplateError::new("Unclosed template tag."))?;
+            let tag = after[..end].trim();
+
+            let node = if let Some(name) = tag.strip_prefix('&') {
+                Node::Variable { name: String::from(name.trim()), escape: false }
+            } else if let Some(file) = tag.strip_prefix('>') {
+                Node::Include(String::from(file.trim()))
+            } else {
+                Node::Variable { name: String::from(tag), escape: true }
+            };
+            match &node {
+                Node::Variable { name, .. } | Node::Include(name) if name.is_empty() => {
+                    return Err(TemplateError::new("Empty template tag."));
+                }
+                _ => nodes.push(node),
+            }
+
+            rest = &after[end + 2..];
+        }
+        if !rest.is_empty() {
+            nodes.push(Node::Text(String::from(rest)));
+        }
+
+        Ok(Template { nodes })
+    }
+
+    /// Render the template without support for includes
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the template contains an include.
+    pub fn render(&self, context: &Context) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        self.render_into(&mut out, context, None, 0)?;
+        Ok(out)
+    }
+
+    fn render_into(
+        &self,
+        out: &mut String,
+        context: &Context,
+        cache: Option<&TemplateCache>,
+        depth: usize,
+    ) -> Result<(), TemplateError> {
+        for node in &self.nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Variable { name, escape } => {
+                    let value = context.get(name).unwrap_or("");
+                    if *escape {
+                        out.push_str(&escape_html(value));
+                    } else {
+                        out.push_str(value);
+                    }
+                }
+                Node::Include(file) => {
+                    let cache = cache.ok_or_else(|| TemplateError::new("Includes require a template cache."))?;
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        return Err(TemplateError::new("Templates are included too deeply."));
+                    }
+                    let template = cache.get(file)?;
+                    template.render_into(out, context, Some(cache), depth + 1)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compiled templates loaded from a directory
+///
+/// Templates are compiled the first time they are used and recompiled
+/// when the modification time of their file changes.
+pub struct TemplateCache {
+    root: PathBuf,
+    templates: Mutex<HashMap<PathBuf, CachedTemplate>>,
+}
+
+/// A compiled template with the modification time of its source file
+type CachedTemplate = (Option<SystemTime>, Arc<Template>);
+
+impl TemplateCache {
+    /// Create a cache for the templates under a directory
+    pub fn new<P: AsRef<Path>>(root: P) -> TemplateCache {
+        TemplateCache {
+            root: root.as_ref().to_path_buf(),
+            templates: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get a compiled template, loading or recompiling it if needed
+    ///
+    /// Names are relative to the root of the cache, with `/` between
+    /// directories, and a leading one is ignored.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the name could lead out of the root, or if
+    /// the file cannot be read or fails to compile.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the cache mutex is in a poisoned state.
+    pub fn get(&self, name: &str) -> Result<Arc<Template>, TemplateError> {
+        let path = self.root.join(relative_path(name)?);
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+
+        if let Some((cached_modified, template)) = self.templates.lock().unwrap().get(&path) {
+            if *cached_modified == modified && modified.is_some() {
+                return Ok(Arc::clone(template));
+            }
+        }
+
+        let source = fs::read_to_string(&path)
+            .map_err(|e| TemplateError::new(&format!("Cannot read template {}: {}", path.display(), e)))?;
+        let template = Arc::new(Template::compile(&source)?);
+        self.templates
+            .lock()
+            .unwrap()
+            .insert(path, (modified, Arc::clone(&template)));
+        Ok(template)
+    }
+
+    /// Render a template by name
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the template or one of its includes
+    /// cannot be loaded or rendered.
+    pub fn render(&self, name: &str, context: &Context) -> Result<String, TemplateError> {
+        let template = self.get(name)?;
+        let mut out = String::new();
+        template.render_into(&mut out, context, Some(self), 0)?;
+        Ok(out)
+    }
+}
+
+/// The path below the root a template name stands for, refusing names
+/// that could lead out of it, such as absolute paths on Windows or `..`
+fn relative_path(name: &str) -> Result<PathBuf, TemplateError> {
+    let refused = || TemplateError::new(&format!("Invalid template name {}.", name));
+    if name.contains('\0') {
+        return Err(refused());
+    }
+    let mut relative = PathBuf::new();
+    for segment in name.split('/') {
+        if segment.contains('\\') {
+            return Err(refused());
+        }
+        match segment {
+            "" | "." => continue,
+            ".." => return Err(refused()),
+            _ => {}
+        }
+        let mut components = Path::new(segment).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(part)), None) => relative.push(part),
+            _ => return Err(refused()),
+        }
+    }
+    if relative.as_os_str().is_empty() {
+        return Err(refused());
+    }
+    Ok(relative)
+}
+
+/// The cache used by `render`, rooted at the working directory
+fn default_cache() -> &'static TemplateCache {
+    static CACHE: OnceLock<TemplateCache> = OnceLock::new();
+    CACHE.get_or_init(|| TemplateCache::new("."))
+}
+
+/// Render a template file relative to the working directory
+///
+/// # Errors
+///
+/// Returns an error if the template cannot be loaded or rendered.
+pub fn render(name: &str, context: &Context) -> Result<String, TemplateError> {
+    default_cache().render(name, context)
+}
+
+/// Escape the characters that have a special meaning in HTML
+pub fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[derive(Debug)]
+pub struct TemplateError {
+    details: String,
+}
+
+impl TemplateError {
+    fn new(details: &str) -> TemplateError {
+        TemplateError{details: String::from(details)}
+    }
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for TemplateError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
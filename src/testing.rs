@@ -0,0 +1,423 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::request::Request;
+use crate::router::Router;
+use crate::server::{Server, ShutdownHandle};
+
+/// How long a `TestServer` request may take before it fails
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Drives a router directly, without a server or sockets, for testing
+/// handlers and middleware
+///
+/// Responses are serialized exactly as they would be on a connection
+/// and parsed back, so framing and headers are exercised as well.
+pub struct TestClient {
+    router: Router,
+}
+
+impl TestClient {
+    pub fn new(router: Router) -> TestClient {
+        TestClient { router }
+    }
+
+    /// Dispatch a request and parse the response
+    ///
+    /// A request without a client address gets 127.0.0.1.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the response cannot be serialized, e.g. because its
+    /// framing headers are invalid.
+    pub fn send(&self, mut request: Request) -> TestResponse {
+        if request.peer_addr().is_none() {
+            request.set_peer_addr(SocketAddr::from(([127, 0, 0, 1], 0)));
+        }
+
+        let mut response = self.router.handle(request);
+        let mut output = Vec::new();
+        if let Err(e) = response.write_to(&mut output) {
+            panic!("Failed to write response: {}", e);
+        }
+        match parse_response(&output, false) {
+            Some((response, _)) => response,
+            None => panic!("Malformed response: {}", String::from_utf8_lossy(&output)),
+        }
+    }
+
+    /// Send a GET request for a target
+    pub fn get(&self, target: &str) -> TestResponse {
+        self.send(Request::new("GET", target))
+    }
+
+    /// Send a POST request with a body
+    pub fn post<B: Into<Vec<u8>>>(&self, target: &str, content_type: &str, body: B) -> TestResponse {
+        self.send(Request::new("POST", target).with_header("Content-Type", content_type).with_body(body))
+    }
+}
+
+/// Start a server for a router on a free port of 127.0.0.1, for testing
+/// it over real connections
+///
+/// The server runs the full connection handling on a thread and pool of
+/// its own, and is shut down when the returned `TestServer` is dropped.
+///
+/// ```
+/// use server::request::Request;
+/// use server::router::Router;
+/// use server::testing::test_server;
+///
+/// let mut router = Router::new();
+/// router.get("/hello", |_: Request| "Hello");
+/// let server = test_server(router);
+/// assert_eq!(server.get("/hello").text(), "Hello");
+/// ```
+///
+/// # Panics
+///
+/// Panics if the server cannot be started.
+pub fn test_server(router: Router) -> TestServer {
+    let config = Config { address: String::from("127.0.0.1:0"), workers: 2, ..Config::default() };
+    match TestServer::start(config, router) {
+        Ok(server) => server,
+        Err(e) => panic!("Failed to start test server: {}", e),
+    }
+}
+
+/// A server running in the background for a test, see `test_server`
+pub struct TestServer {
+    address: SocketAddr,
+    shutdown: ShutdownHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    /// Start a server with a configuration of its own, such as one
+    /// listening on `127.0.0.1:0` with limits to test
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of `Server::new`.
+    pub fn start(config: Config, router: Router) -> io::Result<TestServer> {
+        let server = Server::new(config)?;
+        let address = server.local_addr()?;
+        let shutdown = server.shutdown_handle()?;
+        let thread = thread::spawn(move || server.serve(router));
+        Ok(TestServer { address, shutdown, thread: Some(thread) })
+    }
+
+    /// The address the server listens on
+    pub fn addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The `http://` URL of a path on the server
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.address, path)
+    }
+
+    /// Send a request over a new connection and read the response
+    ///
+    /// The request gets a Host header if it has none, a Content-Length
+    /// for its body and `Connection: close`, so the server closes the
+    /// connection after answering.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the connection fails, the server does not answer within
+    /// 30 seconds or the response is malformed, including when more is
+    /// sent after it than it is framed with, such as a body after the
+    /// response to a HEAD request.
+    pub fn send(&self, request: Request) -> TestResponse {
+        let output = match self.exchange(&request) {
+            Ok(output) => output,
+            Err(e) => panic!("Request to test server failed: {}", e),
+        };
+        match parse_response(&output, request.method() == "HEAD") {
+            // The connection is the upgraded protocol's after a 101
+            Some((response, used)) if used == output.len() || response.status == 101 => response,
+            Some((_, used)) => panic!("Unexpected bytes after response: {}", String::from_utf8_lossy(&output[used..])),
+            None => panic!("Malformed response: {}", String::from_utf8_lossy(&output)),
+        }
+    }
+
+    /// Send a GET request for a target
+    pub fn get(&self, target: &str) -> TestResponse {
+        self.send(Request::new("GET", target))
+    }
+
+    /// Send a POST request with a body
+    pub fn post<B: Into<Vec<u8>>>(&self, target: &str, content_type: &str, body: B) -> TestResponse {
+        self.send(Request::new("POST", target).with_header("Content-Type", content_type).with_body(body))
+    }
+
+    /// Write a request and read everything the server sends until it
+    /// closes the connection
+    fn exchange(&self, request: &Request) -> io::Result<Vec<u8>> {
+        let mut stream = TcpStream::connect(self.address)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+        let mut head = format!("{} {} HTTP/1.1\r\n", request.method(), request.target());
+        if request.header("Host").is_none() {
+            head.push_str(&format!("Host: {}\r\n", self.address));
+        }
+        for (name, value) in request.headers() {
+            if !name.eq_ignore_ascii_case("Connection") && !name.eq_ignore_ascii_case("Content-Length") {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        if !request.body().is_empty() || request.header("Content-Length").is_some() {
+            head.push_str(&format!("Content-Length: {}\r\n", request.body().len()));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(request.body())?;
+
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output)?;
+        Ok(output)
+    }
+}
+
+impl Drop for TestServer {
+    /// Shut the server down and wait for it to stop
+    fn drop(&mut self) {
+        self.shutdown.shutdown();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A response as a client received it
+pub struct TestResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    trailers: Vec<(String, String)>,
+    interim: Vec<TestResponse>,
+}
+
+impl TestResponse {
+    /// The status code of the response
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Get the value of the first header with the given name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All headers of the response
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// The body, with any chunked transfer encoding removed
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Get the value of the first trailer field with the given name
+    pub fn trailer(&self, name: &str) -> Option<&str> {
+        self.trailers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All trailer fields sent after a chunked body
+    pub fn trailers(&self) -> &[(String, String)] {
+        &self.trailers
+    }
+
+    /// The body as text, with invalid UTF-8 replaced
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    /// The informational responses that came before this one, such as
+    /// 103 Early Hints
+    pub fn interim(&self) -> &[TestResponse] {
+        &self.interim
+    }
+}
+
+/// An in-memory stream standing in for a client connection
+///
+/// Reads return the input the stream was created with, in pieces of at
+/// most the configured read size, and then the end of the stream. What
+/// is written is collected and can be inspected through any clone of
+/// the stream, so a clone can be passed to `Server::serve_stream`.
+#[derive(Clone)]
+pub struct MockStream {
+    inner: Arc<Mutex<MockInner>>,
+}
+
+struct MockInner {
+    input: Vec<u8>,
+    position: usize,
+    read_size: usize,
+    output: Vec<u8>,
+}
+
+impl MockStream {
+    /// Create a stream from the bytes the client sends
+    pub fn new<B: Into<Vec<u8>>>(input: B) -> MockStream {
+        MockStream {
+            inner: Arc::new(Mutex::new(MockInner {
+                input: input.into(),
+                position: 0,
+                read_size: usize::MAX,
+                output: Vec::new(),
+            })),
+        }
+    }
+
+    /// Return at most this many bytes from each read, to exercise the
+    /// handling of input arriving in pieces
+    pub fn with_read_size(self, read_size: usize) -> MockStream {
+        self.inner.lock().unwrap().read_size = read_size.max(1);
+        self
+    }
+
+    /// Everything written to the stream so far
+    pub fn output(&self) -> Vec<u8> {
+        self.inner.lock().unwrap().output.clone()
+    }
+
+    /// The responses written to the stream so far
+    ///
+    /// # Panics
+    ///
+    /// Panics if the output does not consist of complete responses.
+    pub fn responses(&self) -> Vec<TestResponse> {
+        let output = self.output();
+        let mut responses = Vec::new();
+        let mut rest = &output[..];
+        while !rest.is_empty() {
+            match parse_response(rest, false) {
+                Some((response, used)) => {
+                    responses.push(response);
+                    rest = &rest[used..];
+                }
+                None => panic!("Malformed response: {}", String::from_utf8_lossy(rest)),
+            }
+        }
+        responses
+    }
+}
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        let start = inner.position;
+        let length = buf.len().min(inner.read_size).min(inner.input.len() - start);
+        buf[..length].copy_from_slice(&inner.input[start..start + length]);
+        inner.position += length;
+        Ok(length)
+    }
+}
+
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.lock().unwrap().output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Parse a complete response from the start of the input, along with
+/// the informational responses before it, returning it together with
+/// the number of bytes they took up
+///
+/// The response to a HEAD request is bodiless whatever its headers say.
+fn parse_response(input: &[u8], bodiless: bool) -> Option<(TestResponse, usize)> {
+    let mut interim = Vec::new();
+    let mut used = 0;
+    loop {
+        let (mut response, size) = parse_message(&input[used..], bodiless)?;
+        used += size;
+        if (100..200).contains(&response.status) && response.status != 101 {
+            interim.push(response);
+            continue;
+        }
+        response.interim = interim;
+        return Some((response, used));
+    }
+}
+
+/// Parse a single response from the start of the input, see
+/// `parse_response`
+fn parse_message(input: &[u8], bodiless: bool) -> Option<(TestResponse, usize)> {
+    let head_end = input.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&input[..head_end]).ok()?;
+    let mut lines = head.split("\r\n");
+
+    let status_line = lines.next()?;
+    let status = status_line.split(' ').nth(1)?.parse().ok()?;
+    let mut headers = Vec::new();
+    for line in lines {
+        let colon = line.find(':')?;
+        headers.push((String::from(&line[..colon]), String::from(line[colon + 1..].trim())));
+    }
+    let mut response = TestResponse { status, headers, body: Vec::new(), trailers: Vec::new(), interim: Vec::new() };
+
+    let rest = &input[head_end + 4..];
+    let used = if bodiless {
+        0
+    } else if response.header("Transfer-Encoding").is_some() {
+        decode_chunked(rest, &mut response)?
+    } else if let Some(length) = response.header("Content-Length") {
+        let length: usize = length.parse().ok()?;
+        if status < 200 || status == 204 || status == 304 {
+            0
+        } else {
+            response.body = rest.get(..length)?.to_vec();
+            length
+        }
+    } else {
+        0
+    };
+    Some((response, head_end + 4 + used))
+}
+
+/// Decode a chunked body and its trailers into the response, returning
+/// the number of bytes used
+fn decode_chunked(input: &[u8], response: &mut TestResponse) -> Option<usize> {
+    let mut position = 0;
+    loop {
+        let line_end = position + input[position..].windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&input[position..line_end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        position = line_end + 2;
+        if size == 0 {
+            loop {
+                let line_end = position + input[position..].windows(2).position(|w| w == b"\r\n")?;
+                let line = std::str::from_utf8(&input[position..line_end]).ok()?;
+                position = line_end + 2;
+                if line.is_empty() {
+                    return Some(position);
+                }
+                let colon = line.find(':')?;
+                response.trailers.push((String::from(&line[..colon]), String::from(line[colon + 1..].trim())));
+            }
+        }
+        response.body.extend_from_slice(input.get(position..position + size)?);
+        position += size + 2;
+    }
+}
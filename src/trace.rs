@@ -0,0 +1,344 @@
+use std::cell::RefCell;
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::json::Value;
+use crate::log;
+use crate::trace_context::{hex, random_id, TraceContext};
+
+pub use crate::trace_context::SpanContext;
+
+/// Spans sent in one export request at most
+const MAX_BATCH: usize = 512;
+/// How long finished spans wait for more to fill a batch
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+thread_local! {
+    /// The span whose work runs on this thread
+    static CURRENT: RefCell<Option<(Arc<Tracer>, SpanContext)>> = const { RefCell::new(None) };
+}
+
+/// Where and as what spans are exported
+pub struct OtelConfig {
+    /// URL of the OTLP/HTTP traces endpoint of a collector, plain HTTP only
+    pub endpoint: String,
+    /// Value of the `service.name` resource attribute
+    pub service_name: String,
+}
+
+impl Default for OtelConfig {
+    fn default() -> OtelConfig {
+        OtelConfig {
+            endpoint: String::from("http://127.0.0.1:4318/v1/traces"),
+            service_name: String::from(env!("CARGO_PKG_NAME")),
+        }
+    }
+}
+
+/// The role of a span in a trace, numbered as in OTLP
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+/// A value of a span attribute
+#[derive(Clone, Debug, PartialEq)]
+pub enum Attribute {
+    String(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl From<&str> for Attribute {
+    fn from(value: &str) -> Attribute {
+        Attribute::String(String::from(value))
+    }
+}
+
+impl From<String> for Attribute {
+    fn from(value: String) -> Attribute {
+        Attribute::String(value)
+    }
+}
+
+impl From<i64> for Attribute {
+    fn from(value: i64) -> Attribute {
+        Attribute::Int(value)
+    }
+}
+
+impl From<f64> for Attribute {
+    fn from(value: f64) -> Attribute {
+        Attribute::Float(value)
+    }
+}
+
+impl From<bool> for Attribute {
+    fn from(value: bool) -> Attribute {
+        Attribute::Bool(value)
+    }
+}
+
+/// An operation being timed, exported when it ends
+///
+/// Spans of traces that are not sampled are timed all the same, but
+/// dropped instead of exported.
+pub struct Span {
+    tracer: Arc<Tracer>,
+    context: SpanContext,
+    parent: Option<[u8; 8]>,
+    data: SpanData,
+}
+
+/// A finished or running span as exported
+struct SpanData {
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(String, Attribute)>,
+    error: bool,
+}
+
+impl Span {
+    /// The identity of the span, to pass on to other services
+    pub fn context(&self) -> SpanContext {
+        self.context
+    }
+
+    pub fn set_attribute<V: Into<Attribute>>(&mut self, key: &str, value: V) {
+        self.data.attributes.push((String::from(key), value.into()));
+    }
+
+    /// Start the span earlier than when it was created, e.g. when a job
+    /// was queued
+    pub fn set_start(&mut self, start: SystemTime) {
+        self.data.start = start;
+    }
+
+    /// Mark the operation as failed
+    pub fn set_error(&mut self) {
+        self.data.error = true;
+    }
+
+    /// Start a span for part of this operation
+    pub fn child(&self, name: &str, kind: SpanKind) -> Span {
+        self.tracer.start(name, kind, Some(self.context))
+    }
+
+    /// Run a function as part of the span, so `current` returns the span
+    /// and `current_child` starts spans below it
+    pub fn enter<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        let entered = Some((Arc::clone(&self.tracer), self.context));
+        let previous = CURRENT.with(|current| current.replace(entered));
+        let result = f();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+        result
+    }
+
+    /// End the span and queue it for export
+    pub fn end(mut self) {
+        self.data.end = SystemTime::now();
+        if self.context.sampled {
+            let _ = self.tracer.sender.lock().unwrap().send(Finished {
+                trace_id: self.context.trace_id,
+                span_id: self.context.span_id,
+                parent: self.parent,
+                data: self.data,
+            });
+        }
+    }
+}
+
+/// A span waiting to be exported
+struct Finished {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent: Option<[u8; 8]>,
+    data: SpanData,
+}
+
+/// Creates spans and exports them in batches to an OpenTelemetry
+/// collector over OTLP/HTTP with JSON encoding
+pub struct Tracer {
+    sender: Mutex<mpsc::Sender<Finished>>,
+}
+
+impl Tracer {
+    /// Create a tracer and start its export thread
+    pub fn new(config: OtelConfig) -> Arc<Tracer> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || export_loop(&config, &receiver));
+        Arc::new(Tracer { sender: Mutex::new(sender) })
+    }
+
+    /// Start a span, continuing the trace of a parent or starting a new,
+    /// sampled one
+    pub fn start(self: &Arc<Self>, name: &str, kind: SpanKind, parent: Option<SpanContext>) -> Span {
+        let (trace_id, sampled) = match parent {
+            Some(parent) => (parent.trace_id, parent.sampled),
+            None => {
+                let root = TraceContext::root().span;
+                (root.trace_id, root.sampled)
+            }
+        };
+        let context = SpanContext { trace_id, span_id: random_id(), sampled };
+        self.span(name, kind, context, parent.map(|parent| parent.span_id))
+    }
+
+    /// Start the span a trace context names, so the exported span has
+    /// the ids that were logged and passed on for it
+    pub fn start_in(self: &Arc<Self>, name: &str, kind: SpanKind, context: &TraceContext) -> Span {
+        self.span(name, kind, context.span, context.parent_span_id)
+    }
+
+    fn span(self: &Arc<Self>, name: &str, kind: SpanKind, context: SpanContext, parent: Option<[u8; 8]>) -> Span {
+        Span {
+            tracer: Arc::clone(self),
+            context,
+            parent,
+            data: SpanData {
+                name: String::from(name),
+                kind,
+                start: SystemTime::now(),
+                end: SystemTime::now(),
+                attributes: Vec::new(),
+                error: false,
+            },
+        }
+    }
+}
+
+/// The span whose work runs on the current thread, see `Span::enter`
+pub fn current() -> Option<SpanContext> {
+    CURRENT.with(|current| current.borrow().as_ref().map(|(_, context)| *context))
+}
+
+/// Start a span below the one whose work runs on the current thread
+///
+/// Returns None outside of any span, such as when tracing is disabled.
+pub fn current_child(name: &str, kind: SpanKind) -> Option<Span> {
+    CURRENT.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .map(|(tracer, context)| tracer.start(name, kind, Some(*context)))
+    })
+}
+
+fn export_loop(config: &OtelConfig, receiver: &mpsc::Receiver<Finished>) {
+    let mut batch = Vec::new();
+    loop {
+        let disconnected = match receiver.recv_timeout(EXPORT_INTERVAL) {
+            Ok(span) => {
+                batch.push(span);
+                if batch.len() < MAX_BATCH {
+                    continue;
+                }
+                false
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => false,
+            Err(mpsc::RecvTimeoutError::Disconnected) => true,
+        };
+
+        if !batch.is_empty() {
+            let body = encode(config, &batch).to_string();
+            if let Err(e) = post(&config.endpoint, &body) {
+                log::warn(&format!("Failed to export {} spans to {}: {}", batch.len(), config.endpoint, e));
+            }
+            batch.clear();
+        }
+        if disconnected {
+            return;
+        }
+    }
+}
+
+/// Build an OTLP `ExportTraceServiceRequest` in its JSON mapping
+fn encode(config: &OtelConfig, batch: &[Finished]) -> Value {
+    let spans: Vec<Value> = batch
+        .iter()
+        .map(|span| {
+            let mut encoded = Value::object()
+                .with("traceId", hex(&span.trace_id))
+                .with("spanId", hex(&span.span_id))
+                .with("name", span.data.name.as_str())
+                .with("kind", span.data.kind as u8)
+                .with("startTimeUnixNano", unix_nanos(span.data.start))
+                .with("endTimeUnixNano", unix_nanos(span.data.end))
+                .with("attributes", Value::Array(span.data.attributes.iter().map(|(k, v)| attribute(k, v)).collect()))
+                // Unset, or error
+                .with("status", Value::object().with("code", if span.data.error { 2 } else { 0 }));
+            if let Some(parent) = &span.parent {
+                encoded = encoded.with("parentSpanId", hex(parent));
+            }
+            encoded
+        })
+        .collect();
+
+    let resource = Value::object().with(
+        "attributes",
+        Value::Array(vec![attribute("service.name", &Attribute::String(config.service_name.clone()))]),
+    );
+    let scope = Value::object()
+        .with("name", env!("CARGO_PKG_NAME"))
+        .with("version", env!("CARGO_PKG_VERSION"));
+    let scope_spans = Value::object().with("scope", scope).with("spans", Value::Array(spans));
+    let resource_spans = Value::object()
+        .with("resource", resource)
+        .with("scopeSpans", Value::Array(vec![scope_spans]));
+    Value::object().with("resourceSpans", Value::Array(vec![resource_spans]))
+}
+
+fn attribute(key: &str, value: &Attribute) -> Value {
+    // 64-bit integers are strings in the JSON mapping of protobuf
+    let value = match value {
+        Attribute::String(s) => Value::object().with("stringValue", s.as_str()),
+        Attribute::Int(i) => Value::object().with("intValue", i.to_string()),
+        Attribute::Float(f) => Value::object().with("doubleValue", *f),
+        Attribute::Bool(b) => Value::object().with("boolValue", *b),
+    };
+    Value::object().with("key", key).with("value", value)
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+/// Send a JSON document to an `http://` URL
+fn post(endpoint: &str, body: &str) -> io::Result<()> {
+    let rest = endpoint
+        .strip_prefix("http://")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Only http:// endpoints are supported."))?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    let address = if authority.contains(':') { String::from(authority) } else { format!("{}:80", authority) };
+
+    let mut stream = TcpStream::connect(&address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        authority,
+        body.len(),
+        body
+    )?;
+
+    let mut status_line = String::new();
+    io::BufReader::new(&mut stream).read_line(&mut status_line)?;
+    match status_line.split(' ').nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!("Collector responded with {}", status_line.trim_end()))),
+    }
+}
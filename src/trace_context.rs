@@ -0,0 +1,211 @@
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::fmt::Write as _;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use crate::request::Request;
+
+/// Longest `tracestate` value passed on; longer ones are dropped, as
+/// the specification allows
+const MAX_TRACE_STATE: usize = 512;
+
+thread_local! {
+    /// The trace context of the request being handled on this thread
+    static CURRENT: RefCell<Option<TraceContext>> = const { RefCell::new(None) };
+}
+
+/// The identity of a span, as carried between services in the W3C
+/// `traceparent` header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    /// Whether the trace is recorded
+    pub sampled: bool,
+}
+
+impl SpanContext {
+    /// Parse a `traceparent` header value such as
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`
+    ///
+    /// Returns None if the value is malformed or either id is all zeros.
+    pub fn parse_traceparent(value: &str) -> Option<SpanContext> {
+        let parts: Vec<&str> = value.trim().split('-').collect();
+        let (version, trace_id, span_id, flags) = match parts.as_slice() {
+            [version, trace_id, span_id, flags] => (*version, *trace_id, *span_id, *flags),
+            // Later versions may append fields
+            [version, trace_id, span_id, flags, ..] if *version != "00" => (*version, *trace_id, *span_id, *flags),
+            _ => return None,
+        };
+        if version.len() != 2 || version == "ff" || flags.len() != 2 {
+            return None;
+        }
+        u8::from_str_radix(version, 16).ok()?;
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+
+        let mut context = SpanContext { trace_id: [0; 16], span_id: [0; 8], sampled: flags & 1 == 1 };
+        decode_hex(trace_id, &mut context.trace_id)?;
+        decode_hex(span_id, &mut context.span_id)?;
+        if context.trace_id == [0; 16] || context.span_id == [0; 8] {
+            return None;
+        }
+        Some(context)
+    }
+
+    /// Format the context as a `traceparent` header value, for passing it
+    /// on to the services a handler calls
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", hex(&self.trace_id), hex(&self.span_id), self.sampled as u8)
+    }
+
+    pub fn trace_id_hex(&self) -> String {
+        hex(&self.trace_id)
+    }
+
+    pub fn span_id_hex(&self) -> String {
+        hex(&self.span_id)
+    }
+}
+
+/// Where a request sits in a distributed trace: the span this server
+/// handles it in, the span of the caller and the vendor state passed
+/// along with them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    /// The span of this server
+    pub span: SpanContext,
+    /// The span of the caller, or None if the trace starts here
+    pub parent_span_id: Option<[u8; 8]>,
+    /// The `tracestate` header, passed on unchanged
+    pub trace_state: Option<String>,
+}
+
+impl TraceContext {
+    /// Continue the trace of the caller of a request in a new span, or
+    /// start a new, sampled trace if the request has no valid
+    /// `traceparent` header
+    ///
+    /// A request with several `traceparent` headers is treated as having
+    /// none, and `tracestate` is only kept along with a valid parent.
+    pub fn from_request(request: &Request) -> TraceContext {
+        let mut values = request
+            .headers()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("traceparent"))
+            .map(|(_, value)| value);
+        let parent = match (values.next(), values.next()) {
+            (Some(value), None) => SpanContext::parse_traceparent(value),
+            _ => None,
+        };
+
+        match parent {
+            Some(parent) => TraceContext {
+                span: SpanContext { trace_id: parent.trace_id, span_id: random_id(), sampled: parent.sampled },
+                parent_span_id: Some(parent.span_id),
+                trace_state: trace_state(request),
+            },
+            None => TraceContext::root(),
+        }
+    }
+
+    /// Start a new, sampled trace
+    pub fn root() -> TraceContext {
+        let mut trace_id = [0; 16];
+        trace_id[..8].copy_from_slice(&random_id());
+        trace_id[8..].copy_from_slice(&random_id());
+        TraceContext {
+            span: SpanContext { trace_id, span_id: random_id(), sampled: true },
+            parent_span_id: None,
+            trace_state: None,
+        }
+    }
+
+    /// The context of a call made as part of this span, such as a request
+    /// forwarded to a backend, with a span id of its own
+    pub fn child(&self) -> TraceContext {
+        TraceContext {
+            span: SpanContext { span_id: random_id(), ..self.span },
+            parent_span_id: Some(self.span.span_id),
+            trace_state: self.trace_state.clone(),
+        }
+    }
+
+    /// The `traceparent` and, if there is one, `tracestate` header that
+    /// pass the context on to a service
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![("traceparent", self.span.traceparent())];
+        if let Some(state) = &self.trace_state {
+            headers.push(("tracestate", state.clone()));
+        }
+        headers
+    }
+}
+
+/// The combined `tracestate` headers of a request, or None if there are
+/// none or they are too long
+fn trace_state(request: &Request) -> Option<String> {
+    let members: Vec<&str> = request
+        .headers()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("tracestate"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim)
+        .filter(|member| !member.is_empty())
+        .collect();
+    let state = members.join(",");
+    if state.is_empty() || state.len() > MAX_TRACE_STATE || !state.bytes().all(|b| (b' '..=b'~').contains(&b)) {
+        return None;
+    }
+    Some(state)
+}
+
+/// The trace context of the request handled on the current thread
+///
+/// A handler calling other services passes on `current().child()`, so
+/// the trace continues through them.
+pub fn current() -> Option<TraceContext> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Run a function with a trace context set for it, see `current`
+pub(crate) fn with_current<T, F: FnOnce() -> T>(context: &TraceContext, f: F) -> T {
+    let previous = CURRENT.with(|current| current.replace(Some(context.clone())));
+    let result = f();
+    CURRENT.with(|current| *current.borrow_mut() = previous);
+    result
+}
+
+/// A random, non-zero id
+pub(crate) fn random_id() -> [u8; 8] {
+    // The standard library seeds its hashers randomly, which is good
+    // enough for ids that only need to be unique
+    static STATE: OnceLock<RandomState> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = STATE.get_or_init(RandomState::new).build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish().max(1).to_be_bytes()
+}
+
+/// A random number from 0 up to but not including 1
+pub(crate) fn random_fraction() -> f64 {
+    // The top 53 bits fill the mantissa of a double exactly
+    (u64::from_be_bytes(random_id()) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(out, "{:02x}", byte);
+    }
+    out
+}
+
+fn decode_hex(input: &str, out: &mut [u8]) -> Option<()> {
+    if input.len() != out.len() * 2 || !input.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+        return None;
+    }
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&input[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(())
+}
@@ -0,0 +1,145 @@
+use std::error::Error;
+use std::fmt;
+
+/// Decode the %-escapes in a string
+///
+/// # Errors
+///
+/// Returns an error if an escape is truncated, is not followed by two
+/// hex digits, or if the decoded bytes are not valid UTF-8.
+pub fn percent_decode(input: &str) -> Result<String, DecodeError> {
+    if !input.contains('%') {
+        return Ok(String::from(input));
+    }
+
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let high = bytes.get(i + 1).and_then(|b| hex_value(*b));
+            let low = bytes.get(i + 2).and_then(|b| hex_value(*b));
+            match (high, low) {
+                (Some(high), Some(low)) => decoded.push(high << 4 | low),
+                _ => return Err(DecodeError::new("Invalid percent-encoded sequence.")),
+            }
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| DecodeError::new("Percent-encoded bytes are not valid UTF-8."))
+}
+
+/// Parse an `application/x-www-form-urlencoded` string, such as a query
+/// string, into its name/value pairs
+///
+/// `+` is decoded as a space. Pairs whose escapes cannot be decoded are
+/// kept undecoded.
+pub fn parse_query(query: &str) -> Vec<(String, String)> {
+    let decode = |s: &str| {
+        let s = s.replace('+', " ");
+        percent_decode(&s).unwrap_or(s)
+    };
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.find('=') {
+            Some(i) => (decode(&pair[..i]), decode(&pair[i + 1..])),
+            None => (decode(pair), String::new()),
+        })
+        .collect()
+}
+
+/// Encode the characters of a path that may not appear unescaped in a URI
+pub fn percent_encode_path(input: &str) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Remove `.` and `..` segments from an absolute path as described in
+/// RFC 3986 section 5.2.4
+///
+/// A `..` segment at the root is dropped, so the result never leaves it.
+pub fn remove_dot_segments(path: &str) -> String {
+    if !path.starts_with('/') {
+        return String::from(path);
+    }
+
+    let mut output: Vec<&str> = Vec::new();
+    let segments: Vec<&str> = path.split('/').collect();
+    let last = segments.len() - 1;
+
+    for (i, segment) in segments.iter().enumerate().skip(1) {
+        match *segment {
+            "." | ".." => {
+                if *segment == ".." {
+                    output.pop();
+                }
+                // A trailing dot segment still refers to a directory
+                if i == last {
+                    output.push("");
+                }
+            }
+            _ => output.push(segment),
+        }
+    }
+
+    format!("/{}", output.join("/"))
+}
+
+/// Collapse runs of consecutive slashes into a single slash
+pub fn merge_slashes(path: &str) -> String {
+    let mut merged = String::with_capacity(path.len());
+    let mut previous_slash = false;
+    for c in path.chars() {
+        if c == '/' && previous_slash {
+            continue;
+        }
+        previous_slash = c == '/';
+        merged.push(c);
+    }
+    merged
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+pub struct DecodeError {
+    details: String,
+}
+
+impl DecodeError {
+    fn new(details: &str) -> DecodeError {
+        DecodeError{details: String::from(details)}
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for DecodeError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
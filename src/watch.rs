@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::log;
+
+type Hook = Box<dyn Fn(&Path) + Send + Sync + 'static>;
+
+/// Watches a directory tree for changes to the files in it, so caches
+/// can drop what they hold for a file when it changes instead of
+/// checking it on every request
+///
+/// Every function registered with `on_change` is called with the path
+/// of each file or directory that was modified, created, removed or
+/// renamed, on a thread of the watcher's own, which stops once every
+/// clone of the watcher has been dropped. When the kernel could not
+/// keep up and dropped events, the root is reported, as anything below
+/// it may have changed. Directories created later are watched too;
+/// symbolic links are not followed. Uses inotify, so only available on
+/// Linux and Android; `new` fails elsewhere.
+///
+/// ```no_run
+/// use server::static_files::{DigestCache, StaticFiles};
+/// use server::watch::Watcher;
+///
+/// let watcher = Watcher::new("public").unwrap();
+/// let files = StaticFiles::new("public").with_digests(DigestCache::in_memory().watched(&watcher));
+/// ```
+#[derive(Clone)]
+pub struct Watcher {
+    root: PathBuf,
+    hooks: Arc<Mutex<Vec<Hook>>>,
+}
+
+impl Watcher {
+    /// Start watching a directory and everything below it
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the platform has no supported API, the
+    /// directory cannot be read or the kernel refuses to watch it, such
+    /// as when the limit on watches is reached.
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<Watcher> {
+        let root = fs::canonicalize(root.as_ref())?;
+        let mut tree = Tree { inotify: sys::Inotify::new()?, dirs: HashMap::new() };
+        tree.add(&root)?;
+
+        let hooks: Arc<Mutex<Vec<Hook>>> = Arc::new(Mutex::new(Vec::new()));
+        let watching = Arc::downgrade(&hooks);
+        let watched = root.clone();
+        thread::spawn(move || {
+            if let Err(e) = watch(&mut tree, &watched, &watching) {
+                log::error(&format!("Stopped watching {} for changes: {}", watched.display(), e));
+            }
+        });
+        Ok(Watcher { root, hooks })
+    }
+
+    /// The watched directory, with symbolic links resolved as in the
+    /// paths reported
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Register a function called with the path of everything that
+    /// changes
+    ///
+    /// Hooks run on the watcher's thread, so they should return quickly.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the hook mutex is in a poisoned state.
+    pub fn on_change<F>(&self, hook: F)
+    where
+        F: Fn(&Path) + Send + Sync + 'static,
+    {
+        self.hooks.lock().unwrap().push(Box::new(hook));
+    }
+}
+
+/// The watched directories by their watch descriptor
+struct Tree {
+    inotify: sys::Inotify,
+    dirs: HashMap<i32, PathBuf>,
+}
+
+impl Tree {
+    /// Watch a directory and the directories below it
+    fn add(&mut self, dir: &Path) -> io::Result<()> {
+        let descriptor = self.inotify.add(dir)?;
+        self.dirs.insert(descriptor, dir.to_path_buf());
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                self.add(&entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Report changes until the watcher is dropped
+fn watch(tree: &mut Tree, root: &Path, hooks: &Weak<Mutex<Vec<Hook>>>) -> io::Result<()> {
+    let mut events = Vec::new();
+    while let Some(hooks) = hooks.upgrade() {
+        events.clear();
+        tree.inotify.read(&mut events, Duration::from_secs(1))?;
+        let mut changed: Vec<PathBuf> = Vec::new();
+        for event in &events {
+            if event.overflowed {
+                changed.push(root.to_path_buf());
+                continue;
+            }
+            let dir = match tree.dirs.get(&event.descriptor) {
+                Some(dir) => dir.clone(),
+                None => continue,
+            };
+            if event.removed {
+                tree.dirs.remove(&event.descriptor);
+            }
+            let path = match &event.name {
+                Some(name) => dir.join(name),
+                None => dir,
+            };
+            if event.new_dir {
+                if let Err(e) = tree.add(&path) {
+                    log::warn(&format!("Failed to watch {}: {}", path.display(), e));
+                }
+            }
+            if !changed.contains(&path) {
+                changed.push(path);
+            }
+        }
+        let hooks = hooks.lock().unwrap();
+        for path in &changed {
+            for hook in hooks.iter() {
+                hook(path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Something that happened in a watched directory
+struct Event {
+    descriptor: i32,
+    /// The entry of the directory it happened to, or None if it
+    /// happened to the directory itself
+    name: Option<std::ffi::OsString>,
+    /// Whether a directory was created or moved into the directory
+    new_dir: bool,
+    /// Whether the watch ended, as the directory is gone
+    removed: bool,
+    /// Whether events were dropped
+    overflowed: bool,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use std::ffi::{CString, OsStr};
+    use std::io;
+    use std::os::raw::{c_char, c_int, c_void};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use super::Event;
+    use crate::poll;
+
+    const IN_CLOEXEC: c_int = 0o2000000;
+    const IN_NONBLOCK: c_int = 0o4000;
+    const IN_MODIFY: u32 = 0x2;
+    const IN_ATTRIB: u32 = 0x4;
+    const IN_CLOSE_WRITE: u32 = 0x8;
+    const IN_MOVED_FROM: u32 = 0x40;
+    const IN_MOVED_TO: u32 = 0x80;
+    const IN_CREATE: u32 = 0x100;
+    const IN_DELETE: u32 = 0x200;
+    const IN_DELETE_SELF: u32 = 0x400;
+    const IN_MOVE_SELF: u32 = 0x800;
+    const IN_Q_OVERFLOW: u32 = 0x4000;
+    const IN_IGNORED: u32 = 0x8000;
+    const IN_ONLYDIR: u32 = 0x1000000;
+    const IN_DONT_FOLLOW: u32 = 0x2000000;
+    const IN_ISDIR: u32 = 0x40000000;
+    /// The size of the fixed part of an event, before its name
+    const EVENT_SIZE: usize = 16;
+
+    extern "C" {
+        fn inotify_init1(flags: c_int) -> c_int;
+        fn inotify_add_watch(fd: c_int, pathname: *const c_char, mask: u32) -> c_int;
+        fn read(fd: c_int, buf: *mut c_void, count: usize) -> isize;
+        fn close(fd: c_int) -> c_int;
+    }
+
+    pub struct Inotify {
+        fd: c_int,
+        buffer: Vec<u8>,
+    }
+
+    impl Inotify {
+        pub fn new() -> io::Result<Inotify> {
+            let fd = unsafe { inotify_init1(IN_CLOEXEC | IN_NONBLOCK) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Inotify { fd, buffer: vec![0; 64 * 1024] })
+        }
+
+        /// Watch a directory, returning the watch descriptor events for
+        /// it carry
+        pub fn add(&self, dir: &Path) -> io::Result<i32> {
+            let path = CString::new(dir.as_os_str().as_bytes())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Path contains a NUL byte."))?;
+            let mask = IN_MODIFY
+                | IN_ATTRIB
+                | IN_CLOSE_WRITE
+                | IN_MOVED_FROM
+                | IN_MOVED_TO
+                | IN_CREATE
+                | IN_DELETE
+                | IN_DELETE_SELF
+                | IN_MOVE_SELF
+                | IN_ONLYDIR
+                | IN_DONT_FOLLOW;
+            let descriptor = unsafe { inotify_add_watch(self.fd, path.as_ptr(), mask) };
+            if descriptor < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(descriptor)
+        }
+
+        /// Wait for at most the timeout, appending the events that
+        /// happened
+        pub fn read(&mut self, events: &mut Vec<Event>, timeout: Duration) -> io::Result<()> {
+            if !poll::wait_readable(self.fd, timeout)? {
+                return Ok(());
+            }
+            let read = unsafe { read(self.fd, self.buffer.as_mut_ptr() as *mut c_void, self.buffer.len()) };
+            if read < 0 {
+                let error = io::Error::last_os_error();
+                if matches!(error.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock) {
+                    return Ok(());
+                }
+                return Err(error);
+            }
+            let data = &self.buffer[..read as usize];
+            let mut offset = 0;
+            while offset + EVENT_SIZE <= data.len() {
+                let field = |at: usize| u32::from_ne_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+                let descriptor = field(offset) as i32;
+                let mask = field(offset + 4);
+                let length = field(offset + 12) as usize;
+                let name = data.get(offset + EVENT_SIZE..offset + EVENT_SIZE + length).unwrap_or_default();
+                // The name is padded with NUL bytes
+                let end = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+                let name = Some(&name[..end]).filter(|name| !name.is_empty()).map(|name| OsStr::from_bytes(name).to_owned());
+                events.push(Event {
+                    descriptor,
+                    name,
+                    new_dir: mask & IN_ISDIR != 0 && mask & (IN_CREATE | IN_MOVED_TO) != 0,
+                    removed: mask & IN_IGNORED != 0,
+                    overflowed: mask & IN_Q_OVERFLOW != 0,
+                });
+                offset += EVENT_SIZE + length;
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for Inotify {
+        fn drop(&mut self) {
+            unsafe {
+                close(self.fd);
+            }
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+mod sys {
+    use std::io;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use super::Event;
+
+    pub struct Inotify;
+
+    impl Inotify {
+        pub fn new() -> io::Result<Inotify> {
+            Err(io::Error::other("Watching files is not supported on this platform."))
+        }
+
+        pub fn add(&self, _dir: &Path) -> io::Result<i32> {
+            unreachable!()
+        }
+
+        pub fn read(&mut self, _events: &mut Vec<Event>, _timeout: Duration) -> io::Result<()> {
+            unreachable!()
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+
+use server::admin::{self, AdminAddress};
+use server::config::Config;
+use server::router::Router;
+use server::testing::TestServer;
+
+/// A server with an admin endpoint on a free loopback port, and the
+/// address of the endpoint
+fn start() -> (TestServer, String) {
+    let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let config = Config {
+        address: String::from("127.0.0.1:0"),
+        workers: 2,
+        admin: Some(AdminAddress::Tcp(address.clone())),
+        ..Config::default()
+    };
+    (TestServer::start(config, Router::new()).unwrap(), address)
+}
+
+/// Send a raw request to the endpoint and return the status it answers
+/// with
+fn status(address: &str, head: &str) -> u16 {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(head.as_bytes()).unwrap();
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).unwrap();
+    answer.split(' ').nth(1).unwrap().parse().unwrap()
+}
+
+#[test]
+fn local_clients_are_answered() {
+    let (_server, address) = start();
+    assert!(admin::send(&AdminAddress::Tcp(address.clone()), "GET", "/status", "").is_ok());
+    let port = address.rsplit(':').next().unwrap();
+    let head = format!("GET /log-level HTTP/1.1\r\nHost: localhost:{}\r\nConnection: close\r\n\r\n", port);
+    assert_eq!(status(&address, &head), 200);
+}
+
+#[test]
+fn browsers_and_foreign_hosts_are_refused() {
+    let (_server, address) = start();
+    let rebound = "POST /drain HTTP/1.1\r\nHost: attacker.example:7879\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    assert_eq!(status(&address, rebound), 403);
+    let cross_origin = format!(
+        "POST /shutdown HTTP/1.1\r\nHost: {}\r\nOrigin: https://attacker.example\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        address
+    );
+    assert_eq!(status(&address, &cross_origin), 403);
+    let without_host = "GET /status HTTP/1.0\r\n\r\n";
+    assert_eq!(status(&address, without_host), 403);
+    // Nothing was shut down
+    assert!(admin::send(&AdminAddress::Tcp(address), "GET", "/status", "").is_ok());
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_sockets_are_private_from_the_start() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("admin-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("admin.sock");
+    let config = Config {
+        address: String::from("127.0.0.1:0"),
+        workers: 2,
+        admin: Some(AdminAddress::Unix(path.clone())),
+        ..Config::default()
+    };
+    let server = TestServer::start(config, Router::new()).unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    // Only the socket is left behind, not the directory it was bound in
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    assert!(admin::send(&AdminAddress::Unix(path), "GET", "/status", "").is_ok());
+    drop(server);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,421 @@
+#![cfg(feature = "brotli")]
+
+use std::io::Write;
+
+use server::brotli::BrotliEncoder;
+
+/// Reads bits from the low end of each byte first, as RFC 7932 packs them
+struct Bits<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Bits<'a> {
+    fn read(&mut self, count: u32) -> usize {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self.data[self.position / 8];
+            value |= usize::from(byte >> (self.position % 8) & 1) << i;
+            self.position += 1;
+        }
+        value
+    }
+
+    /// Skip to the next byte, whose padding has to be zero
+    fn align(&mut self) {
+        let padding = (8 - self.position % 8) as u32 % 8;
+        assert_eq!(self.read(padding), 0, "padding bits are set");
+    }
+
+    fn byte(&mut self) -> u8 {
+        assert_eq!(self.position % 8, 0);
+        self.position += 8;
+        self.data[self.position / 8 - 1]
+    }
+}
+
+/// A canonical prefix code, decoded a bit at a time
+struct Code {
+    /// The number of codes of each length
+    counts: [u16; 16],
+    /// The symbols in code order
+    symbols: Vec<u16>,
+}
+
+impl Code {
+    fn new(lengths: &[u8]) -> Code {
+        let mut counts = [0; 16];
+        for &length in lengths {
+            counts[usize::from(length)] += 1;
+        }
+        counts[0] = 0;
+        let mut symbols: Vec<u16> = (0..lengths.len() as u16).filter(|&s| lengths[usize::from(s)] > 0).collect();
+        symbols.sort_by_key(|&s| lengths[usize::from(s)]);
+        Code { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut Bits) -> usize {
+        // A code of a single symbol takes no bits
+        if self.symbols.len() == 1 {
+            return usize::from(self.symbols[0]);
+        }
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for length in 1..16 {
+            code |= bits.read(1);
+            let count = usize::from(self.counts[length]);
+            if code < first + count {
+                return usize::from(self.symbols[index + code - first]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        panic!("no symbol has the code");
+    }
+}
+
+/// The order code length code lengths are sent in
+const CODE_LENGTH_ORDER: [usize; 18] = [1, 2, 3, 4, 0, 5, 17, 6, 16, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+fn prefix_code(bits: &mut Bits, alphabet: usize) -> Code {
+    let mut lengths = vec![0u8; alphabet];
+    let kind = bits.read(2);
+    if kind == 1 {
+        let alphabet_bits = usize::BITS - (alphabet - 1).leading_zeros();
+        let count = bits.read(2) + 1;
+        let symbols: Vec<usize> = (0..count).map(|_| bits.read(alphabet_bits)).collect();
+        let shape: &[u8] = match count {
+            // Given a length to tell it from unused symbols, though it
+            // takes no bits
+            1 => &[1],
+            2 => &[1, 1],
+            3 => &[1, 2, 2],
+            _ if bits.read(1) == 0 => &[2, 2, 2, 2],
+            _ => &[1, 2, 3, 3],
+        };
+        for (&symbol, &length) in symbols.iter().zip(shape) {
+            assert!(symbol < alphabet && lengths[symbol] == 0, "bad simple code");
+            lengths[symbol] = length;
+        }
+        return Code::new(&lengths);
+    }
+
+    // The code length code, its lengths sent with a static code
+    let mut code_length_lengths = [0u8; 18];
+    let mut space = 32;
+    for &symbol in &CODE_LENGTH_ORDER[kind..] {
+        let length = match bits.read(2) {
+            0 => 0,
+            1 => 4,
+            2 => 3,
+            _ if bits.read(1) == 0 => 2,
+            _ if bits.read(1) == 0 => 1,
+            _ => 5,
+        };
+        code_length_lengths[symbol] = length;
+        if length > 0 {
+            space -= 32 >> length;
+            if space <= 0 {
+                break;
+            }
+        }
+    }
+    let code_length_code = Code::new(&code_length_lengths);
+
+    let mut space = 32768;
+    let mut symbol = 0;
+    let mut previous = 8;
+    let (mut repeat, mut repeat_length) = (0, 0);
+    while symbol < alphabet && space > 0 {
+        let code = code_length_code.decode(bits);
+        if code < 16 {
+            lengths[symbol] = code as u8;
+            symbol += 1;
+            if code > 0 {
+                previous = code as u8;
+                space -= 32768 >> code;
+            }
+            repeat = 0;
+            continue;
+        }
+        let (length, extra_bits) = if code == 16 { (previous, 2) } else { (0, 3) };
+        if repeat_length != length {
+            repeat = 0;
+            repeat_length = length;
+        }
+        let old = repeat;
+        if repeat > 0 {
+            repeat = (repeat - 2) << extra_bits;
+        }
+        repeat += bits.read(extra_bits) + 3;
+        for _ in old..repeat {
+            assert!(symbol < alphabet, "code lengths run past the alphabet");
+            lengths[symbol] = length;
+            symbol += 1;
+            if length > 0 {
+                space -= 32768 >> length;
+            }
+        }
+    }
+    assert_eq!(space, 0, "incomplete prefix code");
+    Code::new(&lengths)
+}
+
+/// A count of block types or prefix codes, from 1 to 256
+fn count(bits: &mut Bits) -> usize {
+    if bits.read(1) == 0 {
+        return 1;
+    }
+    match bits.read(3) {
+        0 => 2,
+        n => (1 << n) + bits.read(n as u32) + 1,
+    }
+}
+
+const INSERT_BASE: [usize; 24] =
+    [0, 1, 2, 3, 4, 5, 6, 8, 10, 14, 18, 26, 34, 50, 66, 98, 130, 194, 322, 578, 1090, 2114, 6210, 22594];
+const INSERT_EXTRA: [u32; 24] = [0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 7, 8, 9, 10, 12, 14, 24];
+const COPY_BASE: [usize; 24] =
+    [2, 3, 4, 5, 6, 7, 8, 9, 10, 12, 14, 18, 22, 30, 38, 54, 70, 102, 134, 198, 326, 582, 1094, 2118];
+const COPY_EXTRA: [u32; 24] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 7, 8, 9, 10, 24];
+
+/// Where the insert and copy length codes of each 64 command symbols
+/// start
+const CELLS: [(usize, usize); 11] =
+    [(0, 0), (0, 8), (0, 0), (0, 8), (8, 0), (8, 8), (0, 16), (16, 0), (8, 16), (16, 8), (16, 16)];
+
+/// Decompress a stream that uses neither block switching, context
+/// modelling nor the static dictionary, panicking on anything invalid
+///
+/// It is written from RFC 7932 rather than from the encoder, so the two
+/// check each other.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut bits = Bits { data, position: 0 };
+    let window_bits = match bits.read(1) {
+        0 => 16,
+        _ => match bits.read(3) {
+            0 => match bits.read(3) {
+                0 => 17,
+                1 => panic!("large windows are not part of RFC 7932"),
+                n => 8 + n,
+            },
+            n => 17 + n,
+        },
+    };
+    let window = (1 << window_bits) - 16;
+    let mut output = Vec::new();
+    // The last distances, the latest first
+    let mut distances = [4, 11, 15, 16];
+    loop {
+        let last = bits.read(1) == 1;
+        if last && bits.read(1) == 1 {
+            break;
+        }
+        let nibbles = bits.read(2);
+        if nibbles == 3 {
+            assert!(!last, "the last meta-block is metadata");
+            assert_eq!(bits.read(1), 0, "reserved bit is set");
+            let skip_bytes = bits.read(2) as u32;
+            let skip = if skip_bytes == 0 { 0 } else { bits.read(skip_bytes * 8) + 1 };
+            bits.align();
+            for _ in 0..skip {
+                bits.byte();
+            }
+            continue;
+        }
+        let length = bits.read((nibbles as u32 + 4) * 4) + 1;
+        let end = output.len() + length;
+        if !last && bits.read(1) == 1 {
+            bits.align();
+            for _ in 0..length {
+                output.push(bits.byte());
+            }
+            continue;
+        }
+
+        for _ in 0..3 {
+            assert_eq!(count(&mut bits), 1, "block switching is not implemented");
+        }
+        let postfix_bits = bits.read(2) as u32;
+        let direct = bits.read(4) << postfix_bits;
+        bits.read(2);
+        for _ in 0..2 {
+            assert_eq!(count(&mut bits), 1, "context modelling is not implemented");
+        }
+        let literals = prefix_code(&mut bits, 256);
+        let commands = prefix_code(&mut bits, 704);
+        let distance_codes = prefix_code(&mut bits, 16 + direct + (48 << postfix_bits));
+
+        while output.len() < end {
+            let symbol = commands.decode(&mut bits);
+            let (insert_start, copy_start) = CELLS[symbol >> 6];
+            let insert_code = insert_start + (symbol >> 3 & 7);
+            let copy_code = copy_start + (symbol & 7);
+            let insert = INSERT_BASE[insert_code] + bits.read(INSERT_EXTRA[insert_code]);
+            let copy = COPY_BASE[copy_code] + bits.read(COPY_EXTRA[copy_code]);
+            for _ in 0..insert {
+                output.push(literals.decode(&mut bits) as u8);
+            }
+            assert!(output.len() <= end, "literals run past the meta-block");
+            if output.len() == end {
+                break;
+            }
+
+            let code = if symbol < 128 { 0 } else { distance_codes.decode(&mut bits) };
+            let distance = match code {
+                0..=3 => distances[code],
+                4..=15 => {
+                    const DELTAS: [isize; 6] = [-1, 1, -2, 2, -3, 3];
+                    let base = distances[if code < 10 { 0 } else { 1 }] as isize;
+                    let distance = base + DELTAS[(code - 4) % 6];
+                    assert!(distance > 0, "distance below one");
+                    distance as usize
+                }
+                _ if code < 16 + direct => code - 15,
+                _ => {
+                    let code = code - direct - 16;
+                    let extra_bits = 1 + (code >> (postfix_bits + 1)) as u32;
+                    let high = code >> postfix_bits;
+                    let low = code & ((1 << postfix_bits) - 1);
+                    let offset = ((2 + (high & 1)) << extra_bits) - 4;
+                    ((offset + bits.read(extra_bits)) << postfix_bits) + low + direct + 1
+                }
+            };
+            assert!(distance <= output.len().min(window), "static dictionary references are not implemented");
+            if code != 0 {
+                distances = [distance, distances[0], distances[1], distances[2]];
+            }
+            assert!(output.len() + copy <= end, "copy runs past the meta-block");
+            for _ in 0..copy {
+                output.push(output[output.len() - distance]);
+            }
+        }
+        if last {
+            break;
+        }
+    }
+    bits.align();
+    assert_eq!(bits.position, data.len() * 8, "data after the end of the stream");
+    output
+}
+
+fn compress(data: &[u8], quality: u32) -> Vec<u8> {
+    let mut encoder = BrotliEncoder::new(Vec::new(), quality);
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Bytes from a fixed linear congruential generator
+fn noise(length: usize) -> Vec<u8> {
+    let mut seed = 1u32;
+    (0..length)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect()
+}
+
+/// Text that repeats at all sorts of distances
+fn text() -> Vec<u8> {
+    let mut text = String::new();
+    for i in 0..4000 {
+        text.push_str(&format!("line {} of {}: {}\n", i, i * 7 % 1000, "ab".repeat(i % 13)));
+    }
+    text.into_bytes()
+}
+
+
+fn hex(text: &str) -> Vec<u8> {
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+}
+
+#[test]
+fn streams_from_the_reference_encoder_decode() {
+    // Made by the reference encoder at qualities 0, 1 and 4, with an
+    // 18 bit window
+    let fox = "The quick brown fox jumps over the lazy dog. ".repeat(3);
+    let vectors = [
+        (
+            "0343000080aaaaaaeaff74e5c3496f273d5c36d16513dd6df9bfabfe3df756f78ceaf23686bbe2c5c2c2c2c6c6aa5e66540\
+             71f3efc180c06e338935535180c0683c1600e7546a4a2340a87857692bd27f86e65208c134d869efc34387e914eed0b7c6\
+             962074251ff07cb5e20c043ba01",
+            fox.clone(),
+        ),
+        (
+            "0343000080aaaaaaeaff74a5c3496f273d5c4c5545375335315333b50d0ee0e2579a4851890dc63c23a8b5593866f12b6e\
+             e8b311f5a5cebe9fe82d832b0b6df93f8226c3b034",
+            fox,
+        ),
+        (
+            "832b010080aaaaaaeaff74a5fb8500f840000c0770f12b55aa196c30e6d851669d5a8101",
+            "hello ".repeat(100),
+        ),
+        ("135702000436c6d6f51fa12114b5c581c1ac03", "hello ".repeat(100)),
+    ];
+    for (compressed, expected) in &vectors {
+        assert_eq!(decompress(&hex(compressed)), expected.as_bytes());
+    }
+}
+
+#[test]
+fn short_inputs_match_the_reference_encoder() {
+    for quality in 0..12 {
+        assert_eq!(compress(b"", quality), [0x33]);
+        assert_eq!(compress(b"a", quality), [0x03, 0x00, 0x80, 0x61, 0x03]);
+    }
+}
+
+#[test]
+fn output_decodes_at_every_quality() {
+    let mut distant = noise(100_000);
+    distant.extend_from_within(..50_000);
+    let inputs = [
+        b"hello ".repeat(1000),
+        noise(100_000),
+        text(),
+        vec![0; 200_000],
+        // A copy from further back than a meta-block
+        distant,
+    ];
+    for quality in 0..12 {
+        for input in &inputs {
+            assert!(decompress(&compress(input, quality)) == *input, "quality {}", quality);
+        }
+    }
+}
+
+#[test]
+fn output_gets_smaller() {
+    let text = text();
+    assert!(compress(&text, 0).len() < text.len() / 3);
+    assert!(compress(&text, 11).len() < compress(&text, 0).len());
+    // Noise is stored, which costs a few bytes a meta-block
+    let noise = noise(200_000);
+    assert!(compress(&noise, 11).len() < noise.len() + 20);
+}
+
+#[test]
+fn flushing_makes_everything_written_decodable() {
+    let text = text();
+    let mut encoder = BrotliEncoder::new(Vec::new(), 5);
+    encoder.write_all(&text[..1000]).unwrap();
+    encoder.flush().unwrap();
+    // Ending the stream where it was flushed, with an empty last
+    // meta-block, gets back what had been written
+    let mut flushed = encoder.get_ref().clone();
+    flushed.push(0x03);
+    assert_eq!(decompress(&flushed), &text[..1000]);
+
+    // Writes in pieces of all sizes, with flushes among them, decode to
+    // the whole input
+    for (i, piece) in text.chunks(7777).enumerate() {
+        encoder.write_all(piece).unwrap();
+        if i % 3 == 0 {
+            encoder.flush().unwrap();
+        }
+    }
+    let mut expected = text[..1000].to_vec();
+    expected.extend_from_slice(&text);
+    assert!(decompress(&encoder.finish().unwrap()) == expected);
+}
@@ -0,0 +1,62 @@
+#![cfg(unix)]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use server::cgi::Cgi;
+use server::request::Request;
+use server::router::Router;
+use server::testing::{test_server, TestServer};
+
+/// A directory of scripts, each a shell snippet
+fn scripts(name: &str, scripts: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cgi-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for (script, body) in scripts {
+        let path = dir.join(script);
+        fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    dir
+}
+
+fn start(cgi: Cgi) -> TestServer {
+    let mut router = Router::new();
+    router.fallback(move |request: Request| cgi.handle(&request));
+    test_server(router)
+}
+
+#[test]
+fn output_beyond_the_limit_is_refused() {
+    let dir = scripts(
+        "limit",
+        &[
+            ("small", "printf 'Content-Type: text/plain\\r\\n\\r\\nhello'"),
+            ("large", "printf 'Content-Type: text/plain\\r\\n\\r\\n'; head -c 5000 /dev/zero"),
+            ("endless", "printf 'Content-Type: text/plain\\r\\n\\r\\n'; yes"),
+        ],
+    );
+    let server = start(Cgi::new(&dir, "/cgi-bin").with_max_output(1000).with_timeout(Duration::from_secs(10)));
+    let small = server.get("/cgi-bin/small");
+    assert_eq!((small.status(), small.text()), (200, String::from("hello")));
+    assert_eq!(server.get("/cgi-bin/large").status(), 502);
+    let started = Instant::now();
+    assert_eq!(server.get("/cgi-bin/endless").status(), 502);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn output_held_open_by_other_processes_times_out() {
+    // The script exits at once, but the process it leaves behind keeps
+    // its output open
+    let dir = scripts("orphan", &[("orphan", "printf 'Content-Type: text/plain\\r\\n\\r\\nhi'; sleep 20 &")]);
+    let server = start(Cgi::new(&dir, "/cgi-bin").with_timeout(Duration::from_millis(500)));
+    let started = Instant::now();
+    assert_eq!(server.get("/cgi-bin/orphan").status(), 504);
+    assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,64 @@
+use server::csrf::{Csrf, CsrfToken};
+use server::request::Request;
+use server::router::Router;
+use server::testing::{test_server, TestServer};
+
+fn start(csrf: Csrf) -> TestServer {
+    let mut router = Router::new();
+    router.wrap(csrf);
+    router.get("/form", |request: Request| {
+        request.extensions().get::<CsrfToken>().map(|token| String::from(token.value())).unwrap_or_default()
+    });
+    router.post("/comment", |_: Request| "saved");
+    test_server(router)
+}
+
+/// The token the server hands out, from the cookie it sets
+fn token(server: &TestServer, cookie: &str) -> String {
+    let response = server.get("/form");
+    let set_cookie = response.header("Set-Cookie").unwrap();
+    let value = set_cookie.strip_prefix(&format!("{}=", cookie)).unwrap().split(';').next().unwrap();
+    assert_eq!(value, response.text());
+    String::from(value)
+}
+
+fn post(server: &TestServer, cookie: &str, header: Option<&str>) -> u16 {
+    let mut request = Request::new("POST", "/comment").with_header("Cookie", cookie);
+    if let Some(token) = header {
+        request = request.with_header("X-CSRF-Token", token);
+    }
+    server.send(request).status()
+}
+
+#[test]
+fn tokens_have_to_be_submitted_again() {
+    let server = start(Csrf::new());
+    let token = token(&server, "csrf_token");
+    let cookie = format!("csrf_token={}", token);
+    assert_eq!(post(&server, &cookie, Some(&token)), 200);
+    assert_eq!(post(&server, &cookie, None), 403);
+    assert_eq!(post(&server, "", Some(&token)), 403);
+    // A token the server never signed is refused even when both match
+    let forged = "0".repeat(64);
+    assert_eq!(post(&server, &format!("csrf_token={}", forged), Some(&forged)), 403);
+}
+
+#[test]
+fn tokens_are_not_bound_to_a_session() {
+    // Anyone who got a token from the server can plant it for another
+    // client, which is what the `__Host-` prefix guards against
+    let server = start(Csrf::new());
+    let planted = token(&server, "csrf_token");
+    assert_eq!(post(&server, &format!("csrf_token={}", planted), Some(&planted)), 200);
+}
+
+#[test]
+fn host_prefixed_cookies_can_be_used() {
+    let server = start(Csrf::new().with_cookie_name("__Host-csrf").with_secure_cookie());
+    let response = server.get("/form");
+    let set_cookie = response.header("Set-Cookie").unwrap();
+    assert!(set_cookie.contains("; Path=/") && set_cookie.ends_with("; Secure"), "{}", set_cookie);
+    assert!(!set_cookie.contains("Domain"), "{}", set_cookie);
+    let token = token(&server, "__Host-csrf");
+    assert_eq!(post(&server, &format!("__Host-csrf={}", token), Some(&token)), 200);
+}
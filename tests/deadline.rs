@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use server::config::Config;
+use server::request::Request;
+use server::router::Router;
+use server::testing::TestServer;
+
+/// A server answering with the milliseconds left until the deadline
+fn start() -> TestServer {
+    let mut router = Router::new();
+    router.get("/remaining", |request: Request| match request.time_remaining() {
+        Some(remaining) => remaining.as_millis().to_string(),
+        None => String::from("none"),
+    });
+    let config = Config {
+        address: String::from("127.0.0.1:0"),
+        workers: 2,
+        deadline_header: Some(String::from("X-Request-Timeout")),
+        ..Config::default()
+    };
+    TestServer::start(config, router).unwrap()
+}
+
+fn remaining(server: &TestServer, timeout: &str) -> String {
+    let response = server.send(Request::new("GET", "/remaining").with_header("X-Request-Timeout", timeout));
+    assert_eq!(response.status(), 200);
+    response.text()
+}
+
+#[test]
+fn client_timeouts_set_the_deadline() {
+    let server = start();
+    let seconds: u128 = remaining(&server, "2").parse().unwrap();
+    assert!(seconds > 1000 && seconds <= 2000, "{}", seconds);
+    let grpc: u128 = remaining(&server, "1500m").parse().unwrap();
+    assert!(grpc > 500 && grpc <= 1500, "{}", grpc);
+    assert_eq!(server.get("/remaining").text(), "none");
+}
+
+#[test]
+fn huge_client_timeouts_are_cut_short() {
+    let server = start();
+    for timeout in ["10000000000000000000", "99999999H"] {
+        let left: u128 = remaining(&server, timeout).parse().unwrap();
+        assert!(left <= Duration::from_secs(300).as_millis(), "{} gave {}", timeout, left);
+    }
+    // The workers survived
+    assert_eq!(server.get("/remaining").status(), 200);
+}
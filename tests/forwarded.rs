@@ -0,0 +1,39 @@
+use std::net::IpAddr;
+
+use server::forwarded::{client_ip, Cidr, ForwardedHeader};
+use server::request::Request;
+
+fn ip(address: &str) -> IpAddr {
+    address.parse().unwrap()
+}
+
+fn trusted() -> Vec<Cidr> {
+    vec![Cidr::parse("10.0.0.0/8").unwrap()]
+}
+
+#[test]
+fn only_the_configured_header_is_read() {
+    // The proxy appended to X-Forwarded-For and passed Forwarded on as
+    // the client sent it
+    let request = Request::new("GET", "/")
+        .with_header("X-Forwarded-For", "203.0.113.7")
+        .with_header("Forwarded", "for=1.2.3.4");
+    let peer = ip("10.0.0.1");
+    assert_eq!(client_ip(peer, &request, &trusted(), ForwardedHeader::XForwardedFor), ip("203.0.113.7"));
+    assert_eq!(client_ip(peer, &request, &trusted(), ForwardedHeader::Forwarded), ip("1.2.3.4"));
+
+    let forwarded_only = Request::new("GET", "/").with_header("Forwarded", "for=1.2.3.4");
+    assert_eq!(client_ip(peer, &forwarded_only, &trusted(), ForwardedHeader::XForwardedFor), peer);
+}
+
+#[test]
+fn chains_are_followed_through_trusted_hops_only() {
+    let request = Request::new("GET", "/").with_header("X-Forwarded-For", "1.2.3.4, 203.0.113.7, 10.0.0.2");
+    let header = ForwardedHeader::XForwardedFor;
+    assert_eq!(client_ip(ip("10.0.0.1"), &request, &trusted(), header), ip("203.0.113.7"));
+    // An untrusted peer's headers are not believed at all
+    assert_eq!(client_ip(ip("198.51.100.1"), &request, &trusted(), header), ip("198.51.100.1"));
+
+    let forwarded = Request::new("GET", "/").with_header("Forwarded", "for=1.2.3.4, for=\"[2001:db8::1]:4711\"");
+    assert_eq!(client_ip(ip("10.0.0.1"), &forwarded, &trusted(), ForwardedHeader::Forwarded), ip("2001:db8::1"));
+}
@@ -0,0 +1,110 @@
+use std::io::Write;
+
+use server::gzip::GzipEncoder;
+use server::inflate;
+
+fn compress(data: &[u8], level: u32) -> Vec<u8> {
+    let mut encoder = GzipEncoder::new(Vec::new(), level);
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn hex(text: &str) -> Vec<u8> {
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+}
+
+/// Bytes from a fixed linear congruential generator
+fn noise(length: usize) -> Vec<u8> {
+    let mut seed = 1u32;
+    (0..length)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect()
+}
+
+fn lines(count: usize) -> Vec<u8> {
+    (0..count).map(|i| format!("line {} of {}\n", i, i * 7 % 100)).collect::<String>().into_bytes()
+}
+
+#[test]
+fn output_matches_zlib() {
+    // zlib at levels 6 and 9 makes the same DEFLATE data, and the
+    // trailer has the CRC-32 check value of the input
+    let body = "33343236313533b7b00400";
+    let trailer = "2639f4cb09000000";
+    assert_eq!(compress(b"123456789", 6), hex(&format!("1f8b08000000000000ff{}{}", body, trailer)));
+    assert_eq!(compress(b"123456789", 9), hex(&format!("1f8b08000000000002ff{}{}", body, trailer)));
+    // Level 0 stores the input
+    assert_eq!(compress(b"123456789", 0), hex(&format!("1f8b08000000000000ff010900f6ff{}{}", "313233343536373839", trailer)));
+}
+
+#[test]
+fn streams_from_zlib_decompress() {
+    let vectors = [
+        (
+            "1f8b08000000000002030bc94855282ccd4cce56482aca2fcf5348cbaf50c82acd2d2856c82f4b2d5228014ae72456552aa4e4\
+             a7eb2984d04c310058001e0087000000",
+            b"The quick brown fox jumps over the lazy dog. ".repeat(3),
+        ),
+        (
+            "1f8b080000000000020335d1bb0dc3400c83e1de53780453bce7400910c048f6ef025174c7bfba0fbafbf37d9dd7f97b9fd771\
+             e746ee593b72a355302350d114aba267b0578c8c161553b12b56461f153b63d06f0a301f41116c8010cb0848b1ad8018db0cc8\
+             6106e4801d1004864092b004a2d09410a59912a2b4e71ca274534294614a88324c0951a62d21cbb2256459b6842cdb96288b0f\
+             2e0a4ca1283085a2c4f335a2d0148a4253284a3385a27453284a3785a20c5328cae4f1076abce97a22020000",
+            lines(40),
+        ),
+    ];
+    for (compressed, expected) in &vectors {
+        assert_eq!(inflate::gunzip(&hex(compressed), 1 << 20).unwrap(), *expected);
+    }
+}
+
+#[test]
+fn output_decodes_at_every_level() {
+    let mut distant = noise(50_000);
+    distant.extend_from_within(20_000..45_000);
+    let inputs = [
+        Vec::new(),
+        b"hello ".repeat(1000),
+        noise(100_000),
+        lines(10_000),
+        vec![0; 200_000],
+        // Copies from as far back as the window reaches
+        distant,
+    ];
+    for level in 0..10 {
+        for input in &inputs {
+            assert!(inflate::gunzip(&compress(input, level), 1 << 20).unwrap() == *input, "level {}", level);
+        }
+    }
+}
+
+#[test]
+fn output_gets_smaller() {
+    let lines = lines(10_000);
+    assert!(compress(&lines, 1).len() < lines.len() / 3);
+    assert!(compress(&lines, 9).len() < compress(&lines, 1).len());
+    let noise = noise(200_000);
+    assert!(compress(&noise, 9).len() < noise.len() + 100);
+}
+
+#[test]
+fn flushing_makes_everything_written_decodable() {
+    let lines = lines(10_000);
+    let mut encoder = GzipEncoder::new(Vec::new(), 6);
+    for (i, piece) in lines.chunks(7777).enumerate() {
+        encoder.write_all(piece).unwrap();
+        if i % 3 == 0 {
+            encoder.flush().unwrap();
+            // Ending the DEFLATE data where it was flushed, with an empty
+            // last block, gets back what had been written
+            let mut flushed = encoder.get_ref()[10..].to_vec();
+            flushed.extend_from_slice(&[0x03, 0x00]);
+            let expected = lines.len().min((i + 1) * 7777);
+            assert!(inflate::inflate(&flushed, 1 << 20).unwrap() == lines[..expected]);
+        }
+    }
+    assert!(inflate::gunzip(&encoder.finish().unwrap(), 1 << 20).unwrap() == lines);
+}
@@ -0,0 +1,60 @@
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use server::config::Config;
+use server::request::Request;
+use server::response::Response;
+use server::router::Router;
+use server::server::Server;
+use server::testing::{test_server, MockStream};
+
+/// A body recording whether it was ever read
+struct Watched(Arc<AtomicBool>);
+
+impl Read for Watched {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        self.0.store(true, Ordering::SeqCst);
+        Ok(0)
+    }
+}
+
+fn router(read: Arc<AtomicBool>) -> Router {
+    let mut router = Router::new();
+    router.get("/hello", |_: Request| "hello-body");
+    router.get("/stream", move |_: Request| Response::from_reader(Watched(Arc::clone(&read)), "text/plain"));
+    router
+}
+
+#[test]
+fn head_responses_keep_the_headers_but_not_the_body() {
+    let server = test_server(router(Arc::default()));
+    let response = server.send(Request::new("HEAD", "/hello"));
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.header("Content-Length"), Some("10"));
+    assert!(response.body().is_empty());
+}
+
+#[test]
+fn head_responses_leave_keep_alive_connections_in_sync() {
+    let server = Server::new(Config { address: String::from("127.0.0.1:0"), ..Config::default() }).unwrap();
+    let stream = MockStream::new(
+        "HEAD /hello HTTP/1.1\r\nHost: a\r\n\r\nGET /hello HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n",
+    );
+    server.serve_stream(router(Arc::default()), stream.clone());
+    let output = String::from_utf8(stream.output()).unwrap();
+    assert_eq!(output.matches("hello-body").count(), 1, "{}", output);
+    let second = output.find("\r\n\r\nHTTP/1.1 200").expect("the second response follows the first head");
+    assert!(output[..second].contains("Content-Length: 10"));
+    assert!(output.ends_with("\r\n\r\nhello-body"));
+}
+
+#[test]
+fn head_requests_do_not_read_streamed_bodies() {
+    let read = Arc::new(AtomicBool::new(false));
+    let server = test_server(router(Arc::clone(&read)));
+    assert_eq!(server.send(Request::new("HEAD", "/stream")).status(), 200);
+    assert!(!read.load(Ordering::SeqCst));
+    assert_eq!(server.get("/stream").status(), 200);
+    assert!(read.load(Ordering::SeqCst));
+}
@@ -0,0 +1,32 @@
+use server::config::Config;
+use server::request::Request;
+use server::router::Router;
+use server::server::Server;
+use server::testing::MockStream;
+
+/// The status the server answers a raw request with
+fn status(head: &str) -> u16 {
+    let server = Server::new(Config { address: String::from("127.0.0.1:0"), ..Config::default() }).unwrap();
+    let mut router = Router::new();
+    router.get("/", |_: Request| "ok");
+    router.post("/", |_: Request| "ok");
+    let stream = MockStream::new(head);
+    server.serve_stream(router, stream.clone());
+    let output = String::from_utf8(stream.output()).unwrap();
+    output.split(' ').nth(1).unwrap().parse().unwrap()
+}
+
+#[test]
+fn header_names_must_be_tokens() {
+    assert_eq!(status("POST / HTTP/1.1\r\nHost: a\r\nContent-Length\x0b: 5\r\nContent-Length: 0\r\n\r\n"), 400);
+    assert_eq!(status("GET / HTTP/1.1\r\nHost: a\r\nX(Y): 1\r\n\r\n"), 400);
+    assert_eq!(status("GET / HTTP/1.1\r\nHost: a\r\nX\u{e9}: 1\r\n\r\n"), 400);
+    assert_eq!(status("GET / HTTP/1.1\r\nHost: a\r\nX-Custom_Name.1~: 1\r\nConnection: close\r\n\r\n"), 200);
+}
+
+#[test]
+fn trailer_names_must_be_tokens() {
+    let chunked = "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n1\r\nx\r\n0\r\n";
+    assert_eq!(status(&format!("{}X-Sum\x0b: 1\r\n\r\n", chunked)), 400);
+    assert_eq!(status(&format!("{}X-Sum: 1\r\n\r\n", chunked)), 200);
+}
@@ -0,0 +1,58 @@
+use std::io::{Cursor, Write};
+
+use server::config::Config;
+use server::request::Request;
+use server::response::Response;
+use server::router::Router;
+use server::server::Server;
+use server::testing::MockStream;
+
+fn router() -> Router {
+    let mut router = Router::new();
+    router.get("/reader", |_: Request| Response::from_reader(Cursor::new("hello world"), "text/plain"));
+    router.get("/writer", |_: Request| Response::from_writer("text/plain", |out| out.write_all(b"written")));
+    router.get("/trailers", |_: Request| Response::text(200, "body").with_trailer("X-Checksum", "abc"));
+    router.get("/fixed", |_: Request| "fixed");
+    router
+}
+
+/// Send raw requests over one connection and return everything the
+/// server answered
+fn exchange(requests: &str) -> String {
+    let server = Server::new(Config { address: String::from("127.0.0.1:0"), ..Config::default() }).unwrap();
+    let stream = MockStream::new(requests);
+    server.serve_stream(router(), stream.clone());
+    String::from_utf8(stream.output()).unwrap()
+}
+
+#[test]
+fn http_10_clients_never_get_chunks() {
+    for (path, body) in [("/reader", "hello world"), ("/writer", "written")] {
+        let output = exchange(&format!("GET {} HTTP/1.0\r\nConnection: keep-alive\r\n\r\n", path));
+        assert!(!output.contains("Transfer-Encoding"), "{}", output);
+        assert!(output.contains("Connection: close\r\n"), "{}", output);
+        assert!(output.ends_with(&format!("\r\n\r\n{}", body)), "{}", output);
+    }
+}
+
+#[test]
+fn http_10_clients_get_no_trailers() {
+    let output = exchange("GET /trailers HTTP/1.0\r\n\r\n");
+    assert!(!output.contains("Trailer") && !output.contains("X-Checksum"), "{}", output);
+    assert!(output.contains("Content-Length: 4\r\n"), "{}", output);
+    assert!(output.ends_with("\r\n\r\nbody"), "{}", output);
+}
+
+#[test]
+fn http_10_keep_alive_works_for_known_lengths() {
+    let output = exchange("GET /fixed HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /reader HTTP/1.0\r\n\r\n");
+    assert!(output.contains("Connection: keep-alive\r\n"), "{}", output);
+    assert!(output.ends_with("\r\n\r\nhello world"), "{}", output);
+}
+
+#[test]
+fn http_11_clients_still_get_chunks() {
+    let output = exchange("GET /reader HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert!(output.contains("Transfer-Encoding: chunked\r\n"), "{}", output);
+    assert!(output.ends_with("B\r\nhello world\r\n0\r\n\r\n"), "{}", output);
+}
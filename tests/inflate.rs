@@ -0,0 +1,83 @@
+use std::io::Write;
+
+use server::config::Config;
+use server::gzip::GzipEncoder;
+use server::inflate;
+use server::request::{Limits, Request};
+use server::router::Router;
+use server::testing::TestServer;
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzipEncoder::new(Vec::new(), 9);
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[test]
+fn output_is_limited() {
+    let data = b"hello ".repeat(1000);
+    let compressed = gzip(&data);
+    assert_eq!(inflate::gunzip(&compressed, data.len()).unwrap(), data);
+    assert!(inflate::gunzip(&compressed, data.len() - 1).unwrap_err().is_too_large());
+    // Stored blocks are limited too
+    let stored = {
+        let mut encoder = GzipEncoder::new(Vec::new(), 0);
+        encoder.write_all(&data).unwrap();
+        encoder.finish().unwrap()
+    };
+    assert!(inflate::gunzip(&stored, data.len() - 1).unwrap_err().is_too_large());
+
+    // The limit covers all the members together
+    let mut members = compressed.clone();
+    members.extend_from_slice(&compressed);
+    assert_eq!(inflate::gunzip(&members, 2 * data.len()).unwrap().len(), 2 * data.len());
+    assert!(inflate::gunzip(&members, 2 * data.len() - 1).unwrap_err().is_too_large());
+}
+
+#[test]
+fn bombs_are_stopped_at_the_limit() {
+    // 20 MiB of zeros in about 20 KiB
+    let mut encoder = GzipEncoder::new(Vec::new(), 1);
+    let zeros = vec![0; 1 << 20];
+    for _ in 0..20 {
+        encoder.write_all(&zeros).unwrap();
+    }
+    let bomb = encoder.finish().unwrap();
+    assert!(bomb.len() < 40 * 1024);
+    assert!(inflate::gunzip(&bomb, 1 << 20).unwrap_err().is_too_large());
+
+    // And so is the raw DEFLATE inside it
+    let raw = &bomb[10..bomb.len() - 8];
+    assert!(inflate::inflate(raw, 1 << 20).unwrap_err().is_too_large());
+}
+
+#[test]
+fn malformed_data_is_not_too_large() {
+    let mut compressed = gzip(&b"hello ".repeat(1000));
+    let end = compressed.len();
+    compressed[end - 8] ^= 1;
+    let error = inflate::gunzip(&compressed, 1 << 20).unwrap_err();
+    assert!(!error.is_too_large());
+    assert_eq!(error.to_string(), "The gzip checksum does not match.");
+    assert!(!inflate::gunzip(&compressed[..20], 1 << 20).unwrap_err().is_too_large());
+}
+
+#[test]
+fn request_bodies_over_the_limit_are_refused() {
+    let mut router = Router::new();
+    router.post("/", |request: Request| request.body().len().to_string());
+    let config = Config {
+        address: String::from("127.0.0.1:0"),
+        workers: 2,
+        limits: Limits { max_decompressed_body: 10_000, ..Limits::default() },
+        ..Config::default()
+    };
+    let server = TestServer::start(config, router).unwrap();
+    let post = |data: &[u8]| {
+        server.send(Request::new("POST", "/").with_header("Content-Encoding", "gzip").with_body(gzip(data)))
+    };
+    let response = post(&[b'a'; 10_000]);
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text(), "10000");
+    assert_eq!(post(&[b'a'; 10_001]).status(), 413);
+}
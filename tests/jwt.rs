@@ -0,0 +1,162 @@
+#![cfg(feature = "jwt")]
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use server::base64;
+use server::json::Value;
+use server::jwt::{Claims, JwtAuth, Key};
+use server::request::Request;
+use server::router::Router;
+use server::sha256;
+use server::testing::test_server;
+
+/// The public half of a 2048 bit key the tokens below were signed with
+const MODULUS: &str = "yEj-uMvQ7272U8vg1KzEYfgC0Ymsuh9EwliEN1ZVLY47oCvMUOkLcRq9sb_0Zbk83A8M2hN_kTOeKmNYONzoj1IKf8yiEsaR1g2C7oD_\
+                       CpuEAoOjwpHPShO44HCMd0IddxqCMQCRaxnXGxj5UazFB4JRcpPbYdbed03IlVLYPTFZi2uK_0qBTDCox0ZT20Q9lEuGOhbn9mrG9_4Y\
+                       uQJPTyXYnIkQUKyQgKggFWq1lW3ZLO7R5zqEC-2ky0ubv5JLv8HArKJqPdrRxsbFXHXmWW0YW4thJAWTXPz9abLMJWT8Jz6t_bD3CQ9P\
+                       ZwguHrGKzszPoelV9FKUauwL7UncFQ";
+
+/// `{"alg":"RS256","typ":"JWT","kid":"test"}` and
+/// `{"sub":"alice","aud":"api","exp":4102444800}`
+const VALID: &str = "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCIsImtpZCI6InRlc3QifQ.\
+                     eyJzdWIiOiJhbGljZSIsImF1ZCI6ImFwaSIsImV4cCI6NDEwMjQ0NDgwMH0.\
+                     Bz8Q5OWVpbjM2Dq1Sl7PLRwVAyH-0Q11BJcxh003KHlStt81KKA2qEvLmohxV3ebbecDtZZDSQ4yaVm1FMM4JN5Z2H0JQkRpa_ud6Rev\
+                     sge8I2GtyD384VwJNj9vNK6zsnGhxBTSjV-LqnLkBOd-ZdavdxuaIocsDmuSQyJ-90FNTzz0DXeUBAr_Tx95Uhst0dr96BY_hs9GtXT2\
+                     JSxdgmr5kF6BbGbGGHqO5KooGzd3iMUw4SF4NwwWDaxpAamnyLPuk6EszduDvNHMo9KDI4OOi0X05rw2kTzHaqthVvFjTQK-TIJ4Vn5s\
+                     vaGMmJtf14z3gSAFJRZJWgF7syrczQ";
+
+/// `{"alg":"RS256","typ":"JWT"}` and `{"sub":"alice","exp":1000000000}`
+const EXPIRED: &str = "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9.eyJzdWIiOiJhbGljZSIsImV4cCI6MTAwMDAwMDAwMH0.\
+                       FSNUUs4u2yD-8x3SL0q0f_pX0424mgmHX0XaFp9N3r8RxnEY90fknIynjUYIcwgHizcmhMcb_6Fwg6ID6SZcVDl191vOGN555jElak\
+                       LWINuy-BJPpxYSkvjzoj6SKqtPj9AF-eaezAzuttnMNd85VgNd_Be4Cog32W7Q8XO0-JXUReiQNW1G-eYwkCNyUWMXbdLQQKPsCl1L\
+                       e3vlqiIeBX_W6pONiqJx6X9Md-2Gi-P2_nOjFFJC9sIa_-zRyyWY-S7eYt_8ARy9tBDj1wwb4s9KzNClp_ZgV1lUDq9Wpmm8kcW_Mi\
+                       auoVLaUx3InOf92kN2_JYD7QgmMV0K_HdQhg";
+
+/// `{"alg":"RS256","typ":"JWT"}` and `{"sub":"alice","nbf":4102444800}`
+const NOT_YET_VALID: &str = "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9.eyJzdWIiOiJhbGljZSIsIm5iZiI6NDEwMjQ0NDgwMH0.\
+                             Jp-GQyjmDGIAQJeHvd7_ZWcKnZ3F5ByI4ewrehrigH5F6aUB183AlU7zPAbjSqvq3L_5hzRMD820iOZCV2_tajEbhL5290HNO6\
+                             7_CAXiIQf_FXs8j95WaCkAPOgUlK6pfMdWTMEwaBDo-mHiD0byPt1sLtculHjNRUcosowVaVlr_UvWfejKUqd06GxNt4bP2chra\
+                             FZeGPL3I-HmMNluhBGfeC5bjrWRb5jKLnugt7F0I-7VD0TB85wncdCla7RLO2ZuQCzsZ_PnNKYTnzfXUeGBSt55RpsihHj_xYF0q\
+                             -cxOwNnTiwYEPKQdk9s6GzjflLDN8n8DvuLsD22n29Ylg";
+
+fn rsa_key() -> Key {
+    let jwk = format!(r#"{{"kty":"RSA","use":"sig","kid":"test","n":"{}","e":"AQAB"}}"#, MODULUS);
+    Key::from_jwk(&Value::parse(&jwk).unwrap()).unwrap()
+}
+
+fn encode(data: &[u8]) -> String {
+    base64::encode(data).replace('+', "-").replace('/', "_").trim_end_matches('=').to_string()
+}
+
+/// An HS256 token with the claims, signed with the secret
+fn hs256(secret: &[u8], claims: &str) -> String {
+    let signed = format!("{}.{}", encode(br#"{"alg":"HS256","typ":"JWT"}"#), encode(claims.as_bytes()));
+    format!("{}.{}", signed, encode(&sha256::hmac(secret, signed.as_bytes())))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn error(auth: &JwtAuth, token: &str) -> String {
+    auth.verify(token).map(|_| ()).unwrap_err().to_string()
+}
+
+#[test]
+fn rs256_tokens_are_verified() {
+    let auth = JwtAuth::new(vec![rsa_key()]).with_audience("api");
+    let claims = auth.verify(VALID).unwrap();
+    assert_eq!(claims.subject(), Some("alice"));
+    assert_eq!(claims.get("aud").and_then(Value::as_str), Some("api"));
+
+    // A key with another ID is not tried
+    let modulus = base64::decode_url(MODULUS).unwrap();
+    let other = Key::rsa(&modulus, &[1, 0, 1]).unwrap().with_id("other");
+    assert_eq!(error(&JwtAuth::new(vec![other]), VALID), "The token signature is invalid.");
+}
+
+#[test]
+fn tampered_tokens_are_refused() {
+    let auth = JwtAuth::new(vec![rsa_key()]);
+    let (signed, signature) = VALID.rsplit_once('.').unwrap();
+
+    let mut bytes = base64::decode_url(signature).unwrap();
+    bytes[100] ^= 1;
+    assert_eq!(error(&auth, &format!("{}.{}", signed, encode(&bytes))), "The token signature is invalid.");
+
+    // Claims changed after signing
+    let (header, _) = signed.split_once('.').unwrap();
+    let claims = encode(br#"{"sub":"mallory","aud":"api","exp":4102444800}"#);
+    assert_eq!(error(&auth, &format!("{}.{}.{}", header, claims, signature)), "The token signature is invalid.");
+
+    assert_eq!(error(&auth, signed), "The token is malformed.");
+    let none = format!("{}.{}.", encode(br#"{"alg":"none"}"#), claims);
+    assert_eq!(error(&auth, &none), "The token is signed with an unsupported algorithm.");
+}
+
+#[test]
+fn hs256_tokens_are_not_checked_against_rsa_keys() {
+    // Signed with the public key as the HMAC secret, which anyone can
+    // do if the algorithm in the token picked how the key is used
+    let modulus = base64::decode_url(MODULUS).unwrap();
+    let forged = hs256(&modulus, r#"{"sub":"mallory"}"#);
+    let auth = JwtAuth::new(vec![rsa_key()]);
+    assert_eq!(error(&auth, &forged), "The token signature is invalid.");
+    // Nor RS256 tokens against HMAC keys
+    assert_eq!(error(&JwtAuth::new(vec![Key::hmac(&modulus)]), VALID), "The token signature is invalid.");
+
+    assert!(JwtAuth::new(vec![Key::hmac(&modulus)]).verify(&forged).is_ok());
+}
+
+#[test]
+fn expiry_and_start_are_checked_with_leeway() {
+    let auth = JwtAuth::new(vec![rsa_key()]);
+    assert_eq!(error(&auth, EXPIRED), "The token has expired.");
+    assert_eq!(error(&auth, NOT_YET_VALID), "The token is not valid yet.");
+    let lenient = JwtAuth::new(vec![rsa_key()]).with_leeway(Duration::from_secs(10_000_000_000));
+    assert!(lenient.verify(EXPIRED).is_ok());
+    assert!(lenient.verify(NOT_YET_VALID).is_ok());
+
+    // A minute of leeway by default
+    let auth = JwtAuth::new(vec![Key::hmac(b"secret")]);
+    let token = |claims: String| hs256(b"secret", &claims);
+    assert!(auth.verify(&token(format!(r#"{{"exp":{}}}"#, now() - 30))).is_ok());
+    assert!(auth.verify(&token(format!(r#"{{"exp":{}}}"#, now() - 90))).is_err());
+    assert!(auth.verify(&token(format!(r#"{{"nbf":{}}}"#, now() + 30))).is_ok());
+    assert!(auth.verify(&token(format!(r#"{{"nbf":{}}}"#, now() + 90))).is_err());
+    let strict = JwtAuth::new(vec![Key::hmac(b"secret")]).with_leeway(Duration::from_secs(0));
+    assert_eq!(error(&strict, &token(format!(r#"{{"exp":{}}}"#, now() - 30))), "The token has expired.");
+    assert_eq!(error(&strict, &token(format!(r#"{{"exp":"{}"}}"#, now() + 30))), "The token expiry is not a number.");
+}
+
+#[test]
+fn audiences_are_checked() {
+    let auth = JwtAuth::new(vec![Key::hmac(b"secret")]).with_audience("api").with_audience("admin");
+    let token = |claims: &str| hs256(b"secret", claims);
+    assert!(auth.verify(&token(r#"{"aud":"admin"}"#)).is_ok());
+    assert!(auth.verify(&token(r#"{"aud":["web","api"]}"#)).is_ok());
+    assert_eq!(error(&auth, &token(r#"{"aud":"web"}"#)), "The token is not meant for this audience.");
+    assert_eq!(error(&auth, &token(r#"{"sub":"alice"}"#)), "The token is not meant for this audience.");
+    // The RS256 token is for "api"
+    assert_eq!(error(&JwtAuth::new(vec![rsa_key()]).with_audience("web"), VALID), "The token is not meant for this audience.");
+}
+
+#[test]
+fn claims_reach_handlers() {
+    let mut router = Router::new();
+    router.wrap(JwtAuth::new(vec![rsa_key()]));
+    router.get("/", |request: Request| {
+        String::from(request.extensions().get::<Claims>().and_then(Claims::subject).unwrap_or(""))
+    });
+    let server = test_server(router);
+    let response = server.send(Request::new("GET", "/").with_header("Authorization", &format!("Bearer {}", VALID)));
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text(), "alice");
+
+    let response = server.send(Request::new("GET", "/").with_header("Authorization", &format!("Bearer {}", EXPIRED)));
+    assert_eq!(response.status(), 401);
+    let challenge = response.header("WWW-Authenticate").unwrap();
+    assert_eq!(challenge, "Bearer error=\"invalid_token\", error_description=\"The token has expired.\"");
+    let response = server.get("/");
+    assert_eq!((response.status(), response.header("WWW-Authenticate")), (401, Some("Bearer")));
+}
@@ -0,0 +1,52 @@
+use std::env;
+use std::process::Command;
+
+use server::json::Value;
+use server::log::{self, Level, LogFormat};
+use server::ThreadPool;
+
+/// Run the pool in a child process, whose standard output can be
+/// looked at, and return what it printed
+fn child_output(level: &str) -> String {
+    let output = Command::new(env::current_exe().unwrap())
+        .args(["--exact", "pool_in_child", "--ignored", "--nocapture", "--test-threads", "1"])
+        .env("POOL_LOG_LEVEL", level)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+#[ignore = "run by the other tests in a child process"]
+fn pool_in_child() {
+    let level = match env::var("POOL_LOG_LEVEL") {
+        Ok(level) => level,
+        Err(_) => return,
+    };
+    log::set_format(LogFormat::Json);
+    log::set_level(Level::parse(&level).unwrap());
+    let pool = ThreadPool::new(2).unwrap();
+    for _ in 0..4 {
+        pool.execute(|| log::info("job ran"));
+    }
+    drop(pool);
+}
+
+#[test]
+fn workers_log_nothing_by_default() {
+    let output = child_output("info");
+    assert_eq!(output.matches("job ran").count(), 4, "{}", output);
+    assert!(!output.contains("Worker") && !output.contains("terminate"), "{}", output);
+}
+
+#[test]
+fn worker_events_are_json_lines_at_debug_level() {
+    let output = child_output("debug");
+    let events: Vec<&str> = output.lines().filter(|line| line.contains("Worker")).collect();
+    assert!(!events.is_empty(), "{}", output);
+    for event in events {
+        let event = Value::parse(event).unwrap_or_else(|_| panic!("not JSON: {}", event));
+        assert!(event.to_string().contains("\"level\":\"debug\""), "{}", event);
+    }
+}
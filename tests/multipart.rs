@@ -0,0 +1,45 @@
+use std::fs;
+
+use server::multipart::{Form, FormOptions};
+use server::request::Request;
+
+fn upload(contents: &str) -> Request {
+    let body = format!(
+        "--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\n{}\r\n--b--\r\n",
+        contents
+    );
+    Request::new("POST", "/upload").with_header("Content-Type", "multipart/form-data; boundary=b").with_body(body)
+}
+
+#[test]
+fn spooled_uploads_are_private_and_unpredictable() {
+    let dir = std::env::temp_dir().join(format!("multipart-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let options = FormOptions { temp_dir: dir.clone(), spool_threshold: 4, ..FormOptions::default() };
+
+    let first = Form::read(&mut upload("secret contents"), &options).unwrap();
+    let second = Form::read(&mut upload("more contents"), &options).unwrap();
+    let (first, second) = (first.part("file").unwrap().path().unwrap(), second.part("file").unwrap().path().unwrap());
+    assert_ne!(first, second);
+    let name = first.file_name().unwrap().to_str().unwrap();
+    assert!(!name.contains(&std::process::id().to_string()), "{}", name);
+    assert_eq!(fs::read_to_string(first).unwrap(), "secret contents");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(first).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn spooled_uploads_are_removed_with_the_form() {
+    let dir = std::env::temp_dir().join(format!("multipart-drop-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let options = FormOptions { temp_dir: dir.clone(), spool_threshold: 4, ..FormOptions::default() };
+    let form = Form::read(&mut upload("spooled to disk"), &options).unwrap();
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    drop(form);
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,28 @@
+use std::time::{Duration, Instant};
+
+use server::config::Config;
+use server::request::Request;
+use server::router::Router;
+use server::testing::TestServer;
+
+#[test]
+fn panicking_handlers_leave_the_worker_healthy() {
+    let mut router = Router::new();
+    router.get("/panic", |_: Request| -> &'static str { panic!("handler failed") });
+    router.get("/ok", |_: Request| "ok");
+    // A single worker, so a lost one would leave nothing to answer
+    let config = Config { address: String::from("127.0.0.1:0"), workers: 1, ..Config::default() };
+    let server = TestServer::start(config, router).unwrap();
+
+    for _ in 0..3 {
+        let response = server.get("/panic");
+        assert_eq!(response.status(), 500);
+        assert_eq!(response.header("Connection"), Some("close"));
+        assert_eq!(server.get("/ok").text(), "ok");
+    }
+
+    // No connection is left counted as open, which shutdown would wait for
+    let stopping = Instant::now();
+    drop(server);
+    assert!(stopping.elapsed() < Duration::from_secs(10));
+}
@@ -0,0 +1,58 @@
+use std::collections::HashSet;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use server::config::Config;
+use server::request::Request;
+use server::router::Router;
+use server::testing::TestServer;
+
+/// A server whose `/sleep?ms` handler waits that long and answers with
+/// the time, recording the threads handlers ran on
+fn start(workers: usize, threads: Arc<Mutex<HashSet<thread::ThreadId>>>) -> TestServer {
+    let mut router = Router::new();
+    router.get("/sleep", move |request: Request| {
+        threads.lock().unwrap().insert(thread::current().id());
+        let ms = request.query().unwrap_or("0").to_string();
+        thread::sleep(Duration::from_millis(ms.parse().unwrap()));
+        ms
+    });
+    let config = Config { address: String::from("127.0.0.1:0"), workers, ..Config::default() };
+    TestServer::start(config, router).unwrap()
+}
+
+/// Send the requests in one write and read the bodies of the answers,
+/// in the order they come
+fn pipeline(server: &TestServer, sleeps: &[u64]) -> Vec<String> {
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    let mut requests = String::new();
+    for (i, ms) in sleeps.iter().enumerate() {
+        let close = if i + 1 == sleeps.len() { "Connection: close\r\n" } else { "" };
+        requests.push_str(&format!("GET /sleep?{} HTTP/1.1\r\nHost: a\r\n{}\r\n", ms, close));
+    }
+    stream.write_all(requests.as_bytes()).unwrap();
+    let mut output = String::new();
+    stream.read_to_string(&mut output).unwrap();
+    output.split("HTTP/1.1 ").skip(1).map(|response| String::from(response.rsplit("\r\n\r\n").next().unwrap())).collect()
+}
+
+#[test]
+fn pipelined_requests_run_concurrently_on_the_pool() {
+    let threads = Arc::default();
+    let server = start(4, Arc::clone(&threads));
+    let started = Instant::now();
+    assert_eq!(pipeline(&server, &[300, 100, 200, 0]), ["300", "100", "200", "0"]);
+    assert!(started.elapsed() < Duration::from_millis(550), "{:?}", started.elapsed());
+}
+
+#[test]
+fn pipelined_requests_stay_on_the_workers() {
+    let threads = Arc::new(Mutex::new(HashSet::new()));
+    let server = start(1, Arc::clone(&threads));
+    assert_eq!(pipeline(&server, &[20, 10, 0, 5, 0]), ["20", "10", "0", "5", "0"]);
+    // The only worker answered all of them itself rather than waiting
+    assert_eq!(threads.lock().unwrap().len(), 1);
+}
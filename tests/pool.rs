@@ -0,0 +1,45 @@
+use std::sync::mpsc;
+use std::time::Duration;
+
+use server::{JobOptions, ThreadPool};
+
+#[test]
+fn handles_run_jobs_while_the_pool_lives() {
+    let pool = ThreadPool::new(2).unwrap();
+    let handle = pool.handle();
+    let (done, finished) = mpsc::channel();
+    assert!(handle.execute_with(JobOptions::new(), move || done.send(()).unwrap()));
+    assert!(finished.recv_timeout(Duration::from_secs(5)).is_ok());
+}
+
+#[test]
+fn jobs_submitted_after_the_pool_is_dropped_are_refused() {
+    let pool = ThreadPool::new(2).unwrap();
+    let handle = pool.handle();
+    let monitor = handle.monitor();
+    drop(pool);
+    assert!(!handle.execute_with(JobOptions::new(), || {}));
+    assert!(!handle.execute_keyed("refresh", || {}));
+    handle.execute(|| {});
+    assert_eq!(monitor.queued_jobs(), 0);
+    // The refused jobs are not waited for either
+    assert!(handle.flush_timeout(Duration::from_secs(1)));
+}
+
+#[test]
+fn panicking_jobs_leave_the_workers_running() {
+    let pool = ThreadPool::new(2).unwrap();
+    let monitor = pool.monitor();
+    for _ in 0..4 {
+        pool.execute(|| panic!("job failed"));
+    }
+    let (done, finished) = mpsc::channel();
+    pool.execute(move || done.send(()).unwrap());
+    assert!(finished.recv_timeout(Duration::from_secs(5)).is_ok());
+    assert!(pool.flush_timeout(Duration::from_secs(5)));
+    assert_eq!(monitor.size(), 2);
+    assert_eq!(monitor.active_jobs(), 0);
+    assert_eq!(monitor.completed_jobs(), 5);
+    // Joining the workers does not find one that died
+    drop(pool);
+}
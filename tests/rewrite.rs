@@ -0,0 +1,42 @@
+use server::config::Config;
+use server::request::Request;
+use server::rewrite::Rewrite;
+use server::router::Router;
+use server::testing::TestServer;
+
+fn start() -> TestServer {
+    let rewrites = vec![
+        Rewrite::redirect("/moved/*", "https://new.example/docs/$1", 301),
+        Rewrite::redirect("/old-blog/*", "/blog/$1", 308),
+        Rewrite::internal("/u/*", "/users/$1"),
+    ];
+    let mut router = Router::new();
+    router.get("/users/*", |request: Request| String::from(request.path()));
+    let config = Config { address: String::from("127.0.0.1:0"), workers: 2, rewrites, ..Config::default() };
+    TestServer::start(config, router).unwrap()
+}
+
+fn location(server: &TestServer, target: &str) -> (u16, String) {
+    let response = server.get(target);
+    (response.status(), String::from(response.header("Location").unwrap_or_default()))
+}
+
+#[test]
+fn captures_are_encoded_into_redirects() {
+    let server = start();
+    assert_eq!(
+        location(&server, "/moved/x%0d%0aSet-Cookie:%20a=b"),
+        (301, String::from("https://new.example/docs/x%0D%0ASet-Cookie%3A%20a%3Db"))
+    );
+    assert_eq!(location(&server, "/moved/a%20b?page=2"), (301, String::from("https://new.example/docs/a%20b?page=2")));
+    // A matched question mark stays part of the path
+    assert_eq!(location(&server, "/moved/what%3F"), (301, String::from("https://new.example/docs/what%3F")));
+    assert_eq!(location(&server, "/old-blog/caf%C3%A9/2024"), (308, String::from("/blog/caf%C3%A9/2024")));
+}
+
+#[test]
+fn internal_rewrites_keep_the_decoded_path() {
+    let server = start();
+    let response = server.get("/u/a%20b");
+    assert_eq!((response.status(), response.text()), (200, String::from("/users/a b")));
+}
@@ -0,0 +1,61 @@
+use server::sha256::{self, Sha256};
+
+#[test]
+fn digests_match_fips_180_examples() {
+    let vectors: [(&[u8], &str); 3] = [
+        (b"", "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+        (b"abc", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+        (
+            b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+        ),
+    ];
+    for (input, digest) in &vectors {
+        assert_eq!(sha256::hex(&sha256::digest(input)), *digest);
+    }
+    assert_eq!(
+        sha256::hex(&sha256::digest(&[b'a'; 1_000_000])),
+        "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+    );
+}
+
+#[test]
+fn input_can_be_fed_in_pieces() {
+    let input: Vec<u8> = (0..1000u32).map(|i| (i * 31 % 251) as u8).collect();
+    // Pieces that end short of, at and across block boundaries, and
+    // lengths whose padding takes another block
+    for size in [1, 7, 55, 56, 63, 64, 65, 200] {
+        for length in [0, 55, 56, 64, 119, 120, 1000] {
+            let mut hasher = Sha256::new();
+            for piece in input[..length].chunks(size) {
+                hasher.update(piece);
+            }
+            assert_eq!(hasher.finish(), sha256::digest(&input[..length]), "pieces of {}, {} bytes", size, length);
+        }
+    }
+}
+
+#[test]
+fn hmacs_match_rfc_4231_examples() {
+    let long_key = [0xaa; 131];
+    let vectors: [(&[u8], &[u8], &str); 5] = [
+        (&[0x0b; 20], b"Hi There", "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"),
+        (b"Jefe", b"what do ya want for nothing?", "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"),
+        (&[0xaa; 20], &[0xdd; 50], "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe"),
+        // Keys longer than a block are hashed first
+        (
+            &long_key,
+            b"Test Using Larger Than Block-Size Key - Hash Key First",
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+        ),
+        (
+            &long_key,
+            b"This is a test using a larger than block-size key and a larger than block-size data. The key needs to \
+              be hashed before being used by the HMAC algorithm.",
+            "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+        ),
+    ];
+    for (key, data, mac) in &vectors {
+        assert_eq!(sha256::hex(&sha256::hmac(key, data)), *mac);
+    }
+}
@@ -0,0 +1,28 @@
+use std::fs;
+
+use server::template::{Context, TemplateCache};
+
+#[test]
+fn templates_cannot_be_loaded_from_outside_the_root() {
+    let base = std::env::temp_dir().join(format!("template-test-{}", std::process::id()));
+    let root = base.join("templates");
+    fs::create_dir_all(root.join("partials")).unwrap();
+    fs::write(base.join("secret.txt"), "top secret").unwrap();
+    fs::write(root.join("partials").join("name.html"), "<b>{{ name }}</b>").unwrap();
+    fs::write(root.join("page.html"), "Hello {{> partials/name.html}}").unwrap();
+    fs::write(root.join("escape.html"), "{{> ../secret.txt}}").unwrap();
+    let cache = TemplateCache::new(&root);
+    let mut context = Context::new();
+    context.insert("name", "Ada");
+
+    assert_eq!(cache.render("page.html", &context).unwrap(), "Hello <b>Ada</b>");
+    assert_eq!(cache.render("/partials/name.html", &context).unwrap(), "<b>Ada</b>");
+    assert!(cache.render("escape.html", &context).is_err());
+    assert!(cache.get("../secret.txt").is_err());
+    assert!(cache.get("partials/../../secret.txt").is_err());
+    // Absolute names are taken relative to the root as well
+    let absolute = base.join("secret.txt");
+    assert!(cache.get(absolute.to_str().unwrap()).is_err());
+    assert!(cache.get("").is_err());
+    let _ = fs::remove_dir_all(&base);
+}
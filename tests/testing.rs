@@ -0,0 +1,45 @@
+use server::config::Config;
+use server::request::Request;
+use server::response::Response;
+use server::router::Router;
+use server::server::Server;
+use server::testing::{test_server, MockStream, TestClient};
+
+fn router() -> Router {
+    let mut router = Router::new();
+    router.get("/hello", |_: Request| "Hello");
+    router.post("/echo", |request: Request| Response::text(200, request.body().to_vec()));
+    router
+}
+
+#[test]
+fn test_client_dispatches_without_sockets() {
+    let client = TestClient::new(router());
+    let response = client.get("/hello");
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text(), "Hello");
+    assert_eq!(client.get("/missing").status(), 404);
+}
+
+#[test]
+fn test_server_answers_over_connections() {
+    let server = test_server(router());
+    assert_eq!(server.get("/hello").text(), "Hello");
+    let response = server.post("/echo", "text/plain", "ping");
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text(), "ping");
+}
+
+#[test]
+fn mock_stream_collects_pipelined_responses() {
+    let server = Server::new(Config { address: String::from("127.0.0.1:0"), ..Config::default() }).unwrap();
+    let stream = MockStream::new(
+        "GET /hello HTTP/1.1\r\nHost: a\r\n\r\nPOST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\nConnection: close\r\n\r\npong",
+    )
+    .with_read_size(7);
+    server.serve_stream(router(), stream.clone());
+    let responses = stream.responses();
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[0].text(), "Hello");
+    assert_eq!(responses[1].text(), "pong");
+}
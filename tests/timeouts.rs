@@ -0,0 +1,49 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use server::config::Config;
+use server::request::Request;
+use server::router::Router;
+use server::testing::TestServer;
+
+/// Handlers block until the gate opens
+type Gate = Arc<(Mutex<bool>, Condvar)>;
+
+fn start(gate: Gate) -> TestServer {
+    let mut router = Router::new();
+    router
+        .get("/stuck", move |_: Request| {
+            let (open, opened) = &*gate;
+            let _open = opened.wait_while(open.lock().unwrap(), |open| !*open).unwrap();
+            "late"
+        })
+        .timeout_after(Duration::from_millis(5));
+    router.get("/quick", |_: Request| "quick").timeout_after(Duration::from_secs(5));
+    let config = Config { address: String::from("127.0.0.1:0"), workers: 4, ..Config::default() };
+    TestServer::start(config, router).unwrap()
+}
+
+#[test]
+fn handlers_that_never_return_cannot_pile_up_threads() {
+    let gate: Gate = Arc::default();
+    let server = start(Arc::clone(&gate));
+    for _ in 0..256 {
+        assert_eq!(server.get("/stuck").status(), 504);
+    }
+    let refused = server.get("/quick");
+    assert_eq!(refused.status(), 503);
+    assert_eq!(refused.header("Retry-After"), Some("1"));
+
+    // Once the handlers returned their threads are counted out again
+    *gate.0.lock().unwrap() = true;
+    gate.1.notify_all();
+    let mut status = 503;
+    for _ in 0..100 {
+        status = server.get("/quick").status();
+        if status != 503 {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(status, 200);
+}
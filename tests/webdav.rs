@@ -0,0 +1,73 @@
+#![cfg(unix)]
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::PathBuf;
+
+use server::auth::{BasicAuth, Users};
+use server::request::Request;
+use server::router::Router;
+use server::static_files::{StaticFiles, Symlinks, WebDav};
+use server::testing::{test_server, TestServer};
+
+/// A document root with a directory inside, a link to it and a link to
+/// a directory outside, next to that outside directory
+fn layout(name: &str) -> (PathBuf, PathBuf) {
+    let base = std::env::temp_dir().join(format!("webdav-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&base);
+    let (root, outside) = (base.join("root"), base.join("outside"));
+    fs::create_dir_all(root.join("inside")).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+    fs::write(outside.join("victim"), "keep me").unwrap();
+    fs::write(root.join("inside").join("victim"), "keep me").unwrap();
+    symlink(&outside, root.join("out")).unwrap();
+    symlink(root.join("inside"), root.join("in")).unwrap();
+    (root, outside)
+}
+
+fn start(root: &PathBuf, symlinks: Symlinks) -> TestServer {
+    let files = StaticFiles::new(root).with_symlinks(symlinks).with_webdav(WebDav::ReadWrite);
+    let mut router = Router::new();
+    router.wrap(BasicAuth::new("share", Users::new().with_user("ada", "secret")));
+    router.fallback(move |request: Request| files.handle(&request));
+    test_server(router)
+}
+
+fn send(server: &TestServer, method: &str, path: &str, body: &str) -> u16 {
+    let request = Request::new(method, path).with_header("Authorization", "Basic YWRhOnNlY3JldA==").with_body(body);
+    server.send(request).status()
+}
+
+#[test]
+fn writes_never_go_through_links() {
+    for (name, symlinks) in [("follow", Symlinks::Follow), ("within", Symlinks::WithinRoot)] {
+        let (root, outside) = layout(name);
+        let server = start(&root, symlinks);
+        for link in ["/out", "/in"] {
+            assert_eq!(send(&server, "PUT", &format!("{}/planted", link), "x"), 403, "{:?} {}", symlinks, link);
+            assert_eq!(send(&server, "MKCOL", &format!("{}/made", link), ""), 403, "{:?} {}", symlinks, link);
+            assert_eq!(send(&server, "DELETE", &format!("{}/victim", link), ""), 403, "{:?} {}", symlinks, link);
+            assert_eq!(send(&server, "DELETE", link, ""), 403, "{:?} {}", symlinks, link);
+        }
+        assert!(!outside.join("planted").exists() && !outside.join("made").exists());
+        assert_eq!(fs::read_to_string(outside.join("victim")).unwrap(), "keep me");
+        assert_eq!(fs::read_to_string(root.join("inside").join("victim")).unwrap(), "keep me");
+        let _ = fs::remove_dir_all(root.parent().unwrap());
+    }
+}
+
+#[test]
+fn writes_below_the_root_go_through() {
+    let (root, _) = layout("plain");
+    let server = start(&root, Symlinks::Follow);
+    assert_eq!(send(&server, "PUT", "/inside/new.txt", "hello"), 201);
+    assert_eq!(send(&server, "PUT", "/inside/new.txt", "again"), 204);
+    assert_eq!(fs::read_to_string(root.join("inside").join("new.txt")).unwrap(), "again");
+    assert_eq!(send(&server, "MKCOL", "/inside/sub", ""), 201);
+    assert_eq!(send(&server, "PUT", "/missing/new.txt", "x"), 409);
+    assert_eq!(send(&server, "DELETE", "/inside/victim", ""), 204);
+    assert_eq!(send(&server, "DELETE", "/", ""), 403);
+    // Reads still follow the link
+    assert_eq!(send(&server, "GET", "/out/victim", ""), 200);
+    let _ = fs::remove_dir_all(root.parent().unwrap());
+}